@@ -0,0 +1,139 @@
+//! Records the telemetry of two agents driving through a bundled OSM JSON
+//! file for a few simulated seconds, without a window, and checks the CSV
+//! that would be saved: its header, that every sample has a row for both
+//! agents, and that the values of every row are valid.
+//!
+//! Run with `cargo run --example agent_telemetry`. Exits with a failure if
+//! any of the checks fails.
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{agent_app, build_graph, center_offset, data_bounds, load, FRAME_TIME, SMALL_TOWN};
+
+use city_visualizer::common::StatusEvent;
+use city_visualizer::data::geography::{GeoLocation, WorldScale};
+use city_visualizer::earth::agent::{create_agents, update_agents, AgentSettings};
+use city_visualizer::earth::telemetry::{update_agent_telemetry, AgentTelemetry};
+
+use bevy::prelude::*;
+
+use std::collections::BTreeSet;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+/// The number of updates, 4 simulated seconds.
+const UPDATES: usize = 40;
+/// The time between samples, in simulated seconds.
+const SAMPLE_INTERVAL: f32 = 0.5;
+const HEADER: &str = "time_s,agent_id,agent_type,latitude,longitude,road_type,speed_m_s";
+
+fn main() -> ExitCode {
+    let data = match load(SMALL_TOWN) {
+        Ok(data) => data,
+        Err(message) => {
+            eprintln!("could not load {}: {}", SMALL_TOWN, message);
+            return ExitCode::FAILURE;
+        }
+    };
+    let offset = center_offset(&data);
+    let graph = Arc::new(build_graph(&data, &offset, &data_bounds(&data, &offset)));
+
+    // some random starts have no path, so more seeds are tried
+    let settings = AgentSettings::default();
+    let agents: Vec<_> = (0..100)
+        .flat_map(|seed| create_agents(1, Arc::clone(&graph), seed, settings, WorldScale::default()))
+        .take(2)
+        .collect();
+    if agents.len() < 2 {
+        eprintln!("could not create two agents with a path");
+        return ExitCode::FAILURE;
+    }
+
+    let mut app = agent_app(Arc::unwrap_or_clone(graph));
+    app.insert_resource(offset)
+        .insert_resource(settings)
+        .init_resource::<AgentTelemetry>()
+        .add_event::<StatusEvent>()
+        .add_systems(Update, update_agent_telemetry.after(update_agents));
+    for (location, agent) in agents {
+        app.world.spawn((Transform::from_translation(location), agent));
+    }
+
+    {
+        let mut telemetry = app.world.resource_mut::<AgentTelemetry>();
+        telemetry.interval = SAMPLE_INTERVAL;
+        telemetry.start();
+    }
+    for _ in 0..UPDATES {
+        app.update();
+    }
+    app.world.resource_mut::<AgentTelemetry>().stop();
+
+    let csv = app.world.resource::<AgentTelemetry>().to_csv();
+    let mut failed = false;
+    let mut lines = csv.lines();
+    if lines.next() != Some(HEADER) {
+        eprintln!("the CSV does not start with the header");
+        failed = true;
+    }
+
+    let center = GeoLocation::unproject(Vec2::ZERO, &offset);
+    let mut agent_ids = BTreeSet::new();
+    let mut times: Vec<(String, usize)> = Vec::new();
+    let mut rows = 0;
+    for line in lines {
+        rows += 1;
+        let fields: Vec<_> = line.split(',').collect();
+        if fields.len() != 7 {
+            eprintln!("row {} has {} fields instead of 7: {}", rows, fields.len(), line);
+            failed = true;
+            continue;
+        }
+        match times.last_mut() {
+            Some((time, count)) if time == fields[0] => *count += 1,
+            _ => times.push((fields[0].to_owned(), 1)),
+        }
+        agent_ids.insert(fields[1].to_owned());
+        if !["car", "pedestrian", "bicycle"].contains(&fields[2]) {
+            eprintln!("row {} has an unknown agent type: {}", rows, line);
+            failed = true;
+        }
+        // the fixture is a small town, so all agents are close to its center
+        let near_center = |value: &str, center: f64| {
+            value.parse::<f64>().is_ok_and(|value| (value - center).abs() < 0.1)
+        };
+        if !near_center(fields[3], center.latitude) || !near_center(fields[4], center.longitude) {
+            eprintln!("row {} is not in the town: {}", rows, line);
+            failed = true;
+        }
+        if !fields[6].is_empty() && !fields[6].parse::<f32>().is_ok_and(|speed| speed >= 0.0) {
+            eprintln!("row {} has an invalid speed: {}", rows, line);
+            failed = true;
+        }
+    }
+
+    println!("rows: {}", rows);
+    println!("samples: {}", times.len());
+    if agent_ids.len() != 2 {
+        eprintln!("found {} agents instead of 2", agent_ids.len());
+        failed = true;
+    }
+    if let Some((time, count)) = times.iter().find(|(_, count)| *count != 2) {
+        eprintln!("the sample at {} s has {} rows instead of 2", time, count);
+        failed = true;
+    }
+    // the first sample is taken right away
+    let expected_samples = (UPDATES as f32 * FRAME_TIME.as_secs_f32() / SAMPLE_INTERVAL) as usize;
+    if times.len() < expected_samples {
+        eprintln!("found {} samples, expected at least {}", times.len(), expected_samples);
+        failed = true;
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        println!("all checks passed");
+        ExitCode::SUCCESS
+    }
+}
@@ -0,0 +1,66 @@
+//! Compares how long it takes to load an OSM JSON file by parsing and
+//! converting it, and to load the same data from a `.citybin` file, and
+//! checks that both give the same data.
+//!
+//! Exits with a failure if the data differs or loading the saved data is not
+//! faster.
+//!
+//! Run with `cargo run --release --example city_bin_bench [file]`, with an
+//! OSM JSON file, by default the small town fixture.
+
+use city_visualizer::data::city_bin::{decode_geo_data, encode_geo_data};
+use city_visualizer::data::geography::convert_osm_json;
+
+use std::process::ExitCode;
+use std::time::Instant;
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/fixtures/small_town.json");
+/// How often both are loaded, to even out small files.
+const ROUNDS: u32 = 20;
+
+fn main() -> ExitCode {
+    let path = std::env::args().nth(1).unwrap_or_else(|| FIXTURE.to_owned());
+    let json = match std::fs::read_to_string(&path) {
+        Ok(json) => json,
+        Err(error) => {
+            eprintln!("could not read {}: {}", path, error);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let start = Instant::now();
+    let mut data = None;
+    for _ in 0..ROUNDS {
+        let value = serde_json::from_str(&json).expect("the file is not valid JSON");
+        data = Some(convert_osm_json(value).expect("the file is not valid OSM JSON"));
+    }
+    let json_time = start.elapsed() / ROUNDS;
+    let data = data.unwrap();
+
+    let bytes = encode_geo_data(&data).expect("the data could not be encoded");
+    let start = Instant::now();
+    let mut decoded = None;
+    for _ in 0..ROUNDS {
+        decoded = Some(decode_geo_data(&bytes).expect("the data could not be decoded"));
+    }
+    let bin_time = start.elapsed() / ROUNDS;
+
+    println!(
+        "{:.1} MB of JSON in {:.2} ms, {:.1} MB saved in {:.2} ms ({:.1}x faster)",
+        json.len() as f64 / 1_000_000.0,
+        json_time.as_secs_f64() * 1000.0,
+        bytes.len() as f64 / 1_000_000.0,
+        bin_time.as_secs_f64() * 1000.0,
+        json_time.as_secs_f64() / bin_time.as_secs_f64(),
+    );
+
+    if decoded.as_ref() != Some(&data) {
+        eprintln!("the saved data differs from the converted data");
+        return ExitCode::FAILURE;
+    }
+    if bin_time >= json_time {
+        eprintln!("loading the saved data is not faster than converting the JSON");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
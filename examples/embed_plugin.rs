@@ -0,0 +1,56 @@
+//! Embeds `CityVisualizerPlugin` into a custom Bevy app: agents are disabled,
+//! a bundled OSM JSON file is loaded at startup and the app exits on its own
+//! after a few seconds.
+//!
+//! Run with `cargo run --example embed_plugin`.
+
+use city_visualizer::common::DataFormat;
+use city_visualizer::data::loading::DataQueryEvent;
+use city_visualizer::data::query::DataQuery;
+use city_visualizer::earth::agent::AgentSettings;
+use city_visualizer::earth::GeoFeature;
+use city_visualizer::plugin::CityVisualizerPlugin;
+
+use bevy::app::AppExit;
+use bevy::asset::AssetMetaCheck;
+use bevy::prelude::*;
+use bevy_egui::EguiPlugin;
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/fixtures/small_town.json");
+const RUN_SECONDS: f32 = 5.0; // How long the app runs before exiting
+
+fn main() {
+    App::new()
+        .insert_resource(AssetMetaCheck::Never)
+        // Inserted before the plugin, so it is not replaced by the default
+        .insert_resource(AgentSettings {
+            enabled: false,
+            ..default()
+        })
+        .add_plugins(DefaultPlugins)
+        .add_plugins(CityVisualizerPlugin)
+        .add_plugins(EguiPlugin)
+        .add_systems(Startup, load_fixture)
+        .add_systems(Update, exit_after_timeout)
+        .run();
+}
+
+fn load_fixture(mut data_query_events: EventWriter<DataQueryEvent>) {
+    data_query_events.send(DataQueryEvent {
+        query: DataQuery::File {
+            format: Some(DataFormat::OsmJson),
+            file_path: FIXTURE.into(),
+        },
+    });
+}
+
+fn exit_after_timeout(
+    time: Res<Time>,
+    features: Query<(), With<GeoFeature>>,
+    mut exit_events: EventWriter<AppExit>,
+) {
+    if time.elapsed_seconds() >= RUN_SECONDS {
+        println!("{} geographic features in the world", features.iter().count());
+        exit_events.send(AppExit);
+    }
+}
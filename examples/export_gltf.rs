@@ -0,0 +1,116 @@
+//! Converts a bundled OSM JSON file to building and road meshes and writes
+//! them to a glTF file, without running a Bevy app.
+//!
+//! Run with `cargo run --example export_gltf [input.json] [output.gltf]`.
+//! The binary buffer is written next to the output file, or into it if the
+//! output is a `.glb` file.
+
+use city_visualizer::data::geography::{
+    convert_osm_json, GeoData, GeoLocation, LoadedBounds, Offset, WorldScale,
+};
+use city_visualizer::earth::assets::AssetCache;
+use city_visualizer::earth::buildings::create_building_data;
+use city_visualizer::earth::generation_settings::GenerationSettings;
+use city_visualizer::earth::gltf_export::GltfWriter;
+use city_visualizer::earth::mesh_builder::MeshBuilder;
+use city_visualizer::earth::roads::create_road_data;
+
+use bevy::transform::components::Transform;
+
+use std::path::Path;
+use std::process::ExitCode;
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/fixtures/small_town.json");
+
+fn main() -> ExitCode {
+    let input = std::env::args().nth(1).unwrap_or_else(|| FIXTURE.to_owned());
+    let output = std::env::args().nth(2).unwrap_or_else(|| "city.gltf".to_owned());
+
+    let data = match load(&input) {
+        Ok(data) => data,
+        Err(message) => {
+            eprintln!("could not load {}: {}", input, message);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Mesh generation only needs the layout of the texture atlases
+    let asset_cache = AssetCache::without_assets();
+    let offset = center_offset(&data);
+    let mut bounds = LoadedBounds::default();
+    for location in data.node_locations.values() {
+        let point = location.project(&offset);
+        bounds.extend(point, point);
+    }
+
+    // Combine the meshes of all chunks
+    let mut buildings = MeshBuilder::new();
+    let mut roads = MeshBuilder::new();
+    for chunk in data.chunks.values() {
+        let (building_meshes, _, _) = create_building_data(
+            &data.node_locations,
+            &chunk.building_features,
+            &chunk.land_use_features,
+            &asset_cache,
+            &offset,
+            &GenerationSettings::default(),
+        );
+        for (_, building_mesh) in &building_meshes {
+            buildings.add_mesh(&building_mesh.detailed, Transform::IDENTITY);
+        }
+
+        let (road_mesh, lit_road_mesh, _, _) = create_road_data(
+            &data.node_locations,
+            &chunk.road_features,
+            &asset_cache,
+            &offset,
+            &bounds,
+        );
+        roads.add_mesh(&road_mesh.detailed, Transform::IDENTITY);
+        roads.add_mesh(&lit_road_mesh.detailed, Transform::IDENTITY);
+    }
+
+    let mut writer = GltfWriter::default();
+    writer.add_mesh("buildings", &buildings.into_mesh());
+    writer.add_mesh("roads", &roads.into_mesh());
+    let path = Path::new(&output);
+    let written = if path.extension().is_some_and(|extension| extension == "glb") {
+        std::fs::write(path, writer.to_glb())
+    } else {
+        writer.write_gltf(path)
+    };
+    match written {
+        Ok(()) => {
+            println!("wrote {}", output);
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("could not write {}: {}", output, error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn load(path: &str) -> Result<GeoData, String> {
+    let contents = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let json = serde_json::from_str(&contents).map_err(|error| error.to_string())?;
+    convert_osm_json(json).map_err(|error| error.to_string())
+}
+
+/// Returns the offset that puts the average of all nodes at the origin.
+fn center_offset(data: &GeoData) -> Offset {
+    let count = data.node_locations.len().max(1) as f64;
+    let (sum_longitude, sum_latitude) = data
+        .node_locations
+        .values()
+        .fold((0.0, 0.0), |(longitude, latitude), location| {
+            (longitude + location.longitude, latitude + location.latitude)
+        });
+    Offset::centered_on(
+        &GeoLocation {
+            longitude: sum_longitude / count,
+            latitude: sum_latitude / count,
+        },
+        WorldScale::default(),
+    )
+}
@@ -0,0 +1,74 @@
+//! Runs the headless batch mode on a bundled OSM JSON file, the same way as
+//! `city_visualizer --headless --file <file> --export-gltf <file>`: the data
+//! is loaded and generated without a window, the world is exported to a GLB
+//! file and the app exits. Then the GLB file is checked.
+//!
+//! Run with `cargo run --example headless_batch`. Exits with a failure if
+//! loading, exporting or any of the checks fails.
+
+use city_visualizer::startup::{headless_app, parse_args};
+
+use std::process::ExitCode;
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/fixtures/small_town.json");
+
+fn main() -> ExitCode {
+    let output = std::env::temp_dir().join("city_visualizer_headless_batch.glb");
+    // a file of an earlier run must not pass the checks
+    let _ = std::fs::remove_file(&output);
+
+    let args = [
+        "--headless",
+        "--file",
+        FIXTURE,
+        "--export-gltf",
+        output.to_str().expect("the temporary directory is not valid UTF-8"),
+    ];
+    let startup_args = match parse_args(args.map(String::from)) {
+        Ok(startup_args) => startup_args,
+        Err(error) => {
+            eprintln!("invalid arguments: {:?}", error);
+            return ExitCode::FAILURE;
+        }
+    };
+    // exits the process with a failure if loading or exporting fails
+    headless_app(startup_args).run();
+
+    let glb = match std::fs::read(&output) {
+        Ok(glb) => glb,
+        Err(error) => {
+            eprintln!("could not read {}: {}", output.display(), error);
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut failed = false;
+    if glb.len() < 20 || &glb[0..4] != b"glTF" {
+        eprintln!("{} is not a GLB file", output.display());
+        return ExitCode::FAILURE;
+    }
+    let length = u32::from_le_bytes([glb[8], glb[9], glb[10], glb[11]]) as usize;
+    if length != glb.len() {
+        eprintln!("the GLB header gives a length of {}, but the file has {} bytes", length, glb.len());
+        failed = true;
+    }
+
+    // the JSON chunk follows the header, and names the nodes after their layer
+    let json_length = u32::from_le_bytes([glb[12], glb[13], glb[14], glb[15]]) as usize;
+    let json = String::from_utf8_lossy(&glb[20..(20 + json_length).min(glb.len())]);
+    for layer in ["Buildings", "Roads"] {
+        if json.contains(&format!("\"{} ", layer)) {
+            println!("exported {}", layer.to_lowercase());
+        } else {
+            eprintln!("no {} were exported", layer.to_lowercase());
+            failed = true;
+        }
+    }
+
+    let _ = std::fs::remove_file(&output);
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        println!("all checks passed");
+        ExitCode::SUCCESS
+    }
+}
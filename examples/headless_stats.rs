@@ -0,0 +1,110 @@
+//! Loads a bundled OSM JSON file without opening a window and prints some
+//! statistics about the data and the traffic graph built from it.
+//!
+//! Run with `cargo run --example headless_stats [path/to/osm.json]`. The
+//! `missing_nodes.json` fixture contains features of which some nodes are
+//! missing from the data, which are still loaded without those nodes, and a
+//! road that is missing most of its nodes, which is skipped.
+
+use city_visualizer::data::geography::{
+    convert_osm_json, GeoData, GeoLocation, LoadedBounds, Offset, WorldScale,
+};
+use city_visualizer::data::traffic_graph::{update_traffic_graph, TrafficGraph};
+
+use std::process::ExitCode;
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/fixtures/small_town.json");
+
+fn main() -> ExitCode {
+    let path = std::env::args().nth(1).unwrap_or_else(|| FIXTURE.to_owned());
+    let data = match load(&path) {
+        Ok(data) => data,
+        Err(message) => {
+            eprintln!("could not load {}: {}", path, message);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut buildings = 0;
+    let mut roads = 0;
+    let mut land_uses = 0;
+    let mut lakes = 0;
+    let mut rivers = 0;
+    for chunk in data.chunks.values() {
+        buildings += chunk.building_features.len();
+        roads += chunk.road_features.len();
+        land_uses += chunk.land_use_features.len();
+        lakes += chunk.lake_features.len();
+        rivers += chunk.river_features.len();
+    }
+
+    println!("file:      {}", path);
+    println!("snapshot:  {}", data.snapshot_timestamp.as_deref().unwrap_or("unknown"));
+    println!("nodes:     {}", data.node_locations.len());
+    println!("ways:      {}", data.report.ways);
+    println!("chunks:    {}", data.chunks.len());
+    println!("buildings: {}", buildings);
+    println!("roads:     {}", roads);
+    println!("land uses: {}", land_uses);
+    println!("lakes:     {}", lakes);
+    println!("rivers:    {}", rivers);
+    println!("missing nodes:    {}", data.report.missing_nodes);
+    println!("dropped features: {}", data.report.dropped_features);
+    println!("clamped tags:     {}", data.report.warnings.len());
+    for warning in &data.report.warnings {
+        println!("  {}", warning);
+    }
+    println!("oversized features: {:?}", data.report.oversized_features);
+    println!("skipped relations:  {}", data.report.skipped_relations);
+
+    // Build the traffic graph the same way the world does, centered on the
+    // average of all nodes
+    let offset = center_offset(&data);
+    let mut bounds = LoadedBounds::default();
+    for location in data.node_locations.values() {
+        let point = location.project(&offset);
+        bounds.extend(point, point);
+    }
+    let mut graph = TrafficGraph::default();
+    let mut chunk_indices: Vec<_> = data.chunks.keys().collect();
+    chunk_indices.sort();
+    for index in chunk_indices {
+        let chunk = &data.chunks[index];
+        update_traffic_graph(
+            &data.node_locations,
+            &chunk.road_features,
+            index,
+            &mut graph,
+            &offset,
+            &bounds,
+        );
+    }
+    println!("traffic graph nodes: {}", graph.get_size());
+    println!("traffic graph edges: {}", graph.get_edge_count());
+
+    ExitCode::SUCCESS
+}
+
+fn load(path: &str) -> Result<GeoData, String> {
+    let contents = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let json = serde_json::from_str(&contents).map_err(|error| error.to_string())?;
+    convert_osm_json(json).map_err(|error| error.to_string())
+}
+
+/// Returns the offset that puts the average of all nodes at the origin.
+fn center_offset(data: &GeoData) -> Offset {
+    let count = data.node_locations.len().max(1) as f64;
+    let (sum_longitude, sum_latitude) = data
+        .node_locations
+        .values()
+        .fold((0.0, 0.0), |(longitude, latitude), location| {
+            (longitude + location.longitude, latitude + location.latitude)
+        });
+    Offset::centered_on(
+        &GeoLocation {
+            longitude: sum_longitude / count,
+            latitude: sum_latitude / count,
+        },
+        WorldScale::default(),
+    )
+}
@@ -0,0 +1,57 @@
+//! Builds geographic data in code with `GeoDataBuilder` and adds it to the
+//! world by sending a `GeoDataEvent`, without loading a file or querying an
+//! API.
+//!
+//! Run with `cargo run --example inject_geodata`.
+
+use city_visualizer::data::geography::GeoDataBuilder;
+use city_visualizer::earth::GeoDataEvent;
+use city_visualizer::plugin::CityVisualizerPlugin;
+
+use bevy::asset::AssetMetaCheck;
+use bevy::prelude::*;
+use bevy_egui::EguiPlugin;
+
+const LATITUDE: f64 = 51.4484; // Center of the generated block
+const LONGITUDE: f64 = 5.4907;
+const SPACING: f64 = 0.0002; // Distance between buildings in degrees
+
+fn main() {
+    App::new()
+        .insert_resource(AssetMetaCheck::Never)
+        .add_plugins(DefaultPlugins)
+        .add_plugins(CityVisualizerPlugin)
+        .add_plugins(EguiPlugin)
+        .add_systems(Startup, inject_data)
+        .run();
+}
+
+fn inject_data(mut geo_data_events: EventWriter<GeoDataEvent>) {
+    let mut builder = GeoDataBuilder::new();
+
+    // A row of square buildings of increasing height
+    let size = SPACING / 2.0;
+    for i in 0..5u64 {
+        let longitude = LONGITUDE + i as f64 * SPACING;
+        let first_node = i * 4;
+        builder
+            .add_node(first_node, LATITUDE, longitude)
+            .add_node(first_node + 1, LATITUDE, longitude + size)
+            .add_node(first_node + 2, LATITUDE + size, longitude + size)
+            .add_node(first_node + 3, LATITUDE + size, longitude);
+        let levels = (i + 1).to_string();
+        builder.add_building(
+            100 + i,
+            vec![first_node, first_node + 1, first_node + 2, first_node + 3, first_node],
+            [("building:levels", levels.as_str())],
+        );
+    }
+
+    // A street in front of the buildings
+    builder
+        .add_node(1000, LATITUDE - size, LONGITUDE - SPACING)
+        .add_node(1001, LATITUDE - size, LONGITUDE + 5.0 * SPACING)
+        .add_road(200, vec![1000, 1001], "residential", [("name", "Builder Street")]);
+
+    geo_data_events.send(GeoDataEvent::new(builder.build()));
+}
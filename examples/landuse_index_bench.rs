@@ -0,0 +1,127 @@
+//! Checks that `LandUseIndex::find` gives exactly the same land use as
+//! testing every land use polygon with `point_in_polygon_check`, on random
+//! land use areas and points, and compares how long both take.
+//!
+//! Exits with a failure if the results differ.
+//!
+//! Run with `cargo run --release --example landuse_index_bench [points]`.
+
+use bevy::math::Vec2;
+use city_visualizer::data::building_type::BuildingLandUseType;
+use city_visualizer::data::geography::WorldScale;
+use city_visualizer::earth::buildings::{point_in_polygon_check, LandUseIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use std::process::ExitCode;
+use std::time::Instant;
+
+const DEFAULT_POINTS: usize = 200_000;
+/// The number of random land use areas.
+const AREAS: usize = 2_000;
+/// The size of the square that the land use areas and points are in.
+const EXTENT: f32 = 5_000.0;
+const TYPES: [BuildingLandUseType; 4] = [
+    BuildingLandUseType::Commercial,
+    BuildingLandUseType::Education,
+    BuildingLandUseType::Industrial,
+    BuildingLandUseType::Residential,
+];
+
+fn main() -> ExitCode {
+    let points = std::env::args()
+        .nth(1)
+        .and_then(|points| points.parse().ok())
+        .unwrap_or(DEFAULT_POINTS);
+    let mut rng = StdRng::seed_from_u64(2015);
+
+    // Overlapping areas of very different sizes, some crossing the origin,
+    // where the grid cells change sign
+    let mut areas: Vec<(Vec<Vec2>, BuildingLandUseType)> = (0..AREAS)
+        .map(|_| {
+            let center = Vec2::new(rng.gen_range(-EXTENT..EXTENT), rng.gen_range(-EXTENT..EXTENT)) * 0.5;
+            let radius = rng.gen_range(5.0..400.0);
+            let count = rng.gen_range(3..100);
+            let polygon = random_ring(&mut rng, center, radius, count);
+            (polygon, TYPES[rng.gen_range(0..TYPES.len())])
+        })
+        .collect();
+    // Ordered like the land use of a chunk, from largest to smallest
+    areas.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+
+    let samples: Vec<Vec2> = (0..points)
+        .map(|i| {
+            let point = Vec2::new(rng.gen_range(-EXTENT..EXTENT), rng.gen_range(-EXTENT..EXTENT)) * 0.5;
+            // Points on cell borders and polygon vertices are looked up too
+            match i % 10 {
+                0 => point.round(),
+                1 => {
+                    let (polygon, _) = &areas[rng.gen_range(0..areas.len())];
+                    polygon[rng.gen_range(0..polygon.len())]
+                }
+                _ => point,
+            }
+        })
+        .collect();
+
+    let start = Instant::now();
+    let expected: Vec<Option<BuildingLandUseType>> = samples
+        .iter()
+        .map(|&point| brute_force_find(&areas, point))
+        .collect();
+    let brute_force = start.elapsed();
+
+    let start = Instant::now();
+    let index = LandUseIndex::new(areas, &WorldScale::default());
+    let build = start.elapsed();
+    let start = Instant::now();
+    let actual: Vec<Option<BuildingLandUseType>> = samples.iter().map(|&point| index.find(point)).collect();
+    let indexed = start.elapsed();
+
+    let found = expected.iter().filter(|landuse| landuse.is_some()).count();
+    println!(
+        "{} points in {} areas, {} inside: {:.1} ms testing every area, {:.1} ms with the index \
+         ({:.1} ms to build it, {:.0}x faster)",
+        points,
+        AREAS,
+        found,
+        brute_force.as_secs_f64() * 1000.0,
+        indexed.as_secs_f64() * 1000.0,
+        build.as_secs_f64() * 1000.0,
+        brute_force.as_secs_f64() / indexed.as_secs_f64(),
+    );
+
+    let mismatches: Vec<usize> = (0..points).filter(|&i| expected[i] != actual[i]).collect();
+    if let Some(&first) = mismatches.first() {
+        eprintln!(
+            "Results differ for {} points, e.g. {:?}: expected {:?}, found {:?}",
+            mismatches.len(),
+            samples[first],
+            expected[first],
+            actual[first]
+        );
+        return ExitCode::FAILURE;
+    }
+    println!("all points give the same land use");
+    ExitCode::SUCCESS
+}
+
+/// Returns the type of the first land use area that contains `point`, by
+/// testing every area.
+fn brute_force_find(areas: &[(Vec<Vec2>, BuildingLandUseType)], point: Vec2) -> Option<BuildingLandUseType> {
+    areas
+        .iter()
+        .find(|(polygon, _)| point_in_polygon_check(polygon, point))
+        .map(|(_, landuse_type)| *landuse_type)
+}
+
+/// Returns a noisy ring around `center`, like the boundary of a land use
+/// area.
+fn random_ring(rng: &mut StdRng, center: Vec2, radius: f32, points: usize) -> Vec<Vec2> {
+    (0..points)
+        .map(|i| {
+            let angle = i as f32 / points as f32 * std::f32::consts::TAU;
+            center + Vec2::from_angle(angle) * radius * rng.gen_range(0.5..1.0)
+        })
+        .collect()
+}
@@ -0,0 +1,132 @@
+//! Builds the same synthetic buildings and roads with a plain `MeshBuilder`
+//! and with one that reserves room up front and deduplicates vertices, and
+//! prints how long it took, how many allocations it made and how many
+//! vertices the meshes have.
+//!
+//! Run with `cargo run --release --example mesh_builder_bench [count]`.
+
+use bevy::prelude::*;
+use city_visualizer::earth::mesh_builder::{MeshBuilder, PrismStyle};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// Counts allocations and reallocations, to show how often buffers grow.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const DEFAULT_COUNT: usize = 10_000;
+/// Corners of every building and nodes of every road.
+const CORNERS: usize = 6;
+
+fn main() {
+    let count = std::env::args()
+        .nth(1)
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(DEFAULT_COUNT);
+
+    run("plain", count, MeshBuilder::new, MeshBuilder::new);
+    run(
+        "reserved and deduplicated",
+        count,
+        || {
+            MeshBuilder::with_capacity(count * CORNERS * 9, count * CORNERS * 15)
+                .with_deduplication()
+        },
+        || {
+            MeshBuilder::with_capacity(count * CORNERS * 2, count * CORNERS * 6)
+                .with_deduplication()
+        },
+    );
+}
+
+/// Builds `count` buildings and roads with the builders that the functions
+/// return, and prints the results.
+fn run(
+    name: &str,
+    count: usize,
+    buildings: impl Fn() -> MeshBuilder,
+    roads: impl Fn() -> MeshBuilder,
+) {
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    let mut building_builder = buildings();
+    let mut road_builder = roads();
+    for i in 0..count {
+        let origin = Vec2::new((i % 100) as f32 * 30.0, (i / 100) as f32 * 30.0);
+        building_builder
+            .add_prism_from_path(&building_base(origin), 12.0, style())
+            .expect("building bases are regular polygons");
+        add_road(&mut road_builder, origin + Vec2::new(0.0, 20.0));
+    }
+    let building_mesh = building_builder.into_mesh();
+    let road_mesh = road_builder.into_mesh();
+
+    println!(
+        "{}: {:.1} ms, {} allocations, {} building vertices, {} road vertices",
+        name,
+        start.elapsed().as_secs_f64() * 1000.0,
+        ALLOCATIONS.load(Ordering::Relaxed) - allocations,
+        building_mesh.count_vertices(),
+        road_mesh.count_vertices(),
+    );
+}
+
+/// Returns the counterclockwise base of a building with `CORNERS` corners.
+fn building_base(origin: Vec2) -> Vec<Vec2> {
+    (0..CORNERS)
+        .map(|i| {
+            origin + Vec2::from_angle(i as f32 / CORNERS as f32 * std::f32::consts::TAU) * 10.0
+        })
+        .collect()
+}
+
+fn style() -> PrismStyle {
+    PrismStyle {
+        wall_uv: (0.0..=0.25, 0.0..=0.25),
+        tile_size: Vec2::new(4.0, 3.0),
+        wall_color: Color::WHITE,
+        roof_uv: Vec2::ZERO,
+        roof_color: Color::WHITE,
+    }
+}
+
+/// Adds a straight road of `CORNERS` nodes as a strip of flat quads, like a
+/// road ribbon.
+fn add_road(mesh_builder: &mut MeshBuilder, start: Vec2) {
+    let uv = Vec2::new(0.5, 0.5);
+    for i in 0..CORNERS - 1 {
+        let (x1, x2) = (start.x + i as f32 * 5.0, start.x + (i + 1) as f32 * 5.0);
+        mesh_builder.add_quad(
+            [
+                Vec3::new(x1, 0.0, start.y + 2.0),
+                Vec3::new(x2, 0.0, start.y + 2.0),
+                Vec3::new(x2, 0.0, start.y - 2.0),
+                Vec3::new(x1, 0.0, start.y - 2.0),
+            ],
+            [uv; 4],
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! A test scene for overlay layers: five overlays are stacked on one road,
+//! each narrower than the one below it, while the camera sweeps around the
+//! road at grazing angles. Every layer should stay visible as a clean band on
+//! top of the layer below it, without flickering.
+//!
+//! Run with `cargo run --example overlay_layers`.
+
+use city_visualizer::earth::overlay::{OverlayLayer, OVERLAY_HEIGHT};
+
+use bevy::pbr::{NotShadowCaster, NotShadowReceiver};
+use bevy::prelude::*;
+
+const ROAD_LENGTH: f32 = 200.0;
+const ROAD_WIDTH: f32 = 10.0;
+/// The height of the road, in the range of roads in the world.
+const ROAD_HEIGHT: f32 = 0.015;
+/// How far the camera is from the middle of the road, and how high, so it
+/// looks at the road at a grazing angle.
+const CAMERA_DISTANCE: f32 = 60.0;
+const CAMERA_HEIGHT: f32 = 0.6;
+/// How long one sweep around the road takes, in seconds.
+const SWEEP_SECONDS: f32 = 20.0;
+
+fn main() {
+    App::new()
+        .add_plugins(DefaultPlugins)
+        .add_systems(Startup, setup_scene)
+        .add_systems(Update, sweep_camera)
+        .run();
+}
+
+fn setup_scene(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.spawn(PbrBundle {
+        mesh: meshes.add(Plane3d::default().mesh().size(ROAD_WIDTH, ROAD_LENGTH)),
+        material: materials.add(Color::DARK_GRAY),
+        transform: Transform::from_xyz(0.0, ROAD_HEIGHT, 0.0),
+        ..default()
+    });
+
+    // every layer is narrower, so the edge of the layer below stays visible
+    let colors = [
+        Color::WHITE,
+        Color::YELLOW,
+        Color::RED,
+        Color::BLACK,
+        Color::CYAN,
+    ];
+    for (i, (layer, color)) in OverlayLayer::ALL.into_iter().zip(colors).enumerate() {
+        let width = ROAD_WIDTH * (1.0 - (i + 1) as f32 / 6.0);
+        commands.spawn((
+            PbrBundle {
+                mesh: meshes.add(Plane3d::default().mesh().size(width, ROAD_LENGTH)),
+                material: materials.add(layer.material(StandardMaterial {
+                    base_color: color,
+                    unlit: true,
+                    ..default()
+                })),
+                transform: Transform::from_xyz(0.0, OVERLAY_HEIGHT, 0.0),
+                ..default()
+            },
+            NotShadowCaster,
+            NotShadowReceiver,
+        ));
+    }
+
+    commands.spawn(DirectionalLightBundle {
+        transform: Transform::from_xyz(1.0, 2.0, 1.0).looking_at(Vec3::ZERO, Vec3::Y),
+        ..default()
+    });
+    commands.spawn(Camera3dBundle::default());
+}
+
+fn sweep_camera(time: Res<Time>, mut cameras: Query<&mut Transform, With<Camera>>) {
+    let angle = time.elapsed_seconds() / SWEEP_SECONDS * std::f32::consts::TAU;
+    let position = Vec3::new(
+        angle.cos() * CAMERA_DISTANCE,
+        CAMERA_HEIGHT,
+        angle.sin() * CAMERA_DISTANCE,
+    );
+    for mut transform in &mut cameras {
+        *transform = Transform::from_translation(position).looking_at(Vec3::ZERO, Vec3::Y);
+    }
+}
@@ -0,0 +1,131 @@
+//! Checks that `simplify_polygon` gives exactly the same result as the
+//! original quadratic implementation of Visvalingam–Whyatt on random
+//! polygons, and compares how long both take on a large ring.
+//!
+//! Exits with a failure if the results differ.
+//!
+//! Run with `cargo run --release --example simplification_bench [points]`.
+
+use bevy::math::Vec2;
+use city_visualizer::earth::simplification::simplify_polygon;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use std::process::ExitCode;
+use std::time::Instant;
+
+const DEFAULT_POINTS: usize = 20_000;
+/// The number of random polygons that are compared.
+const RANDOM_POLYGONS: usize = 2_000;
+
+fn main() -> ExitCode {
+    let points = std::env::args()
+        .nth(1)
+        .and_then(|points| points.parse().ok())
+        .unwrap_or(DEFAULT_POINTS);
+    let mut rng = StdRng::seed_from_u64(2068);
+
+    for i in 0..RANDOM_POLYGONS {
+        // Points on a grid make many triangles with equal areas, which checks
+        // that ties are broken the same way
+        let snap = i % 2 == 0;
+        let count = rng.gen_range(3..200);
+        let polygon = random_ring(&mut rng, count, snap);
+        let threshold = rng.gen_range(0.0..50.0);
+        let expected = simplify_polygon_quadratic(polygon.clone(), threshold);
+        let actual = simplify_polygon(polygon.clone(), threshold);
+        if expected != actual {
+            eprintln!(
+                "Results differ for polygon {} with threshold {}: {:?}",
+                i, threshold, polygon
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+    println!("{} random polygons give the same result", RANDOM_POLYGONS);
+
+    let ring = random_ring(&mut rng, points, false);
+    let threshold = 5.0;
+    let start = Instant::now();
+    let expected = simplify_polygon_quadratic(ring.clone(), threshold);
+    let quadratic = start.elapsed();
+    let start = Instant::now();
+    let actual = simplify_polygon(ring, threshold);
+    let heap = start.elapsed();
+    println!(
+        "{} points to {}: {:.1} ms before, {:.1} ms with a heap ({:.0}x faster)",
+        points,
+        actual.len(),
+        quadratic.as_secs_f64() * 1000.0,
+        heap.as_secs_f64() * 1000.0,
+        quadratic.as_secs_f64() / heap.as_secs_f64(),
+    );
+    if expected != actual {
+        eprintln!("Results differ for the large ring");
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
+
+/// Returns a noisy ring around the origin, like the boundary of a forest.
+fn random_ring(rng: &mut StdRng, points: usize, snap: bool) -> Vec<Vec2> {
+    (0..points)
+        .map(|i| {
+            let angle = i as f32 / points as f32 * std::f32::consts::TAU;
+            let point = Vec2::from_angle(angle) * rng.gen_range(80.0..120.0);
+            if snap {
+                point.round()
+            } else {
+                point
+            }
+        })
+        .collect()
+}
+
+/// The original implementation, which looks for the smallest area among all
+/// points for every point it removes.
+fn simplify_polygon_quadratic(mut polygon: Vec<Vec2>, threshold: f32) -> Vec<Vec2> {
+    if polygon.len() < 4 {
+        return polygon;
+    }
+
+    let area_at = |polygon: &Vec<Vec2>, i: usize| {
+        let count = polygon.len();
+        triangle_area(
+            polygon[(i + count - 1) % count],
+            polygon[i],
+            polygon[(i + 1) % count],
+        )
+    };
+    let mut areas: Vec<f32> = (0..polygon.len()).map(|i| area_at(&polygon, i)).collect();
+
+    while polygon.len() > 4 {
+        let mut min_area = f32::MAX;
+        let mut min_index = 0;
+        for (i, &area) in areas.iter().enumerate() {
+            if area < min_area {
+                min_area = area;
+                min_index = i;
+            }
+        }
+        if min_area > threshold {
+            break;
+        }
+
+        polygon.remove(min_index);
+        areas.remove(min_index);
+
+        let previous_index = (min_index + polygon.len() - 1) % polygon.len();
+        let next_index = min_index % polygon.len();
+        areas[previous_index] = area_at(&polygon, previous_index);
+        areas[next_index] = area_at(&polygon, next_index);
+    }
+    polygon
+}
+
+fn triangle_area(previous_point: Vec2, current_point: Vec2, next_point: Vec2) -> f32 {
+    0.5 * (previous_point.x * (current_point.y - next_point.y)
+        + current_point.x * (next_point.y - previous_point.y)
+        + next_point.x * (previous_point.y - current_point.y))
+        .abs()
+}
@@ -0,0 +1,473 @@
+//! Bookmarks of places in the world, to return to interesting spots later.
+//! Every bookmark stores the view of the camera and the offset of the data it
+//! was made in, and bookmarks are saved so they survive restarts.
+
+use crate::commands::CommandRegistry;
+use crate::common::{AppError, StatusEvent};
+use crate::data::geography::{Offset, WorldScale};
+use crate::earth::floating_origin::OriginShiftEvent;
+use crate::player::{perspective_projection, CameraMode, Player};
+
+use bevy::prelude::*;
+use bevy_egui::egui;
+use bevy_egui::EguiContexts;
+use serde_json::{json, Value};
+
+use std::collections::BTreeMap;
+
+/// The name of the file that bookmarks are saved to, on native.
+#[cfg(not(target_arch = "wasm32"))]
+const BOOKMARKS_FILE: &str = ".bookmarks.json";
+
+/// The key in the local storage that bookmarks are saved under, on the web.
+#[cfg(target_arch = "wasm32")]
+const BOOKMARKS_KEY: &str = "city_visualizer_bookmarks";
+
+/// Offsets that differ less than this are considered the same, to allow for
+/// rounding when they are saved.
+const OFFSET_TOLERANCE: f64 = 1e-9;
+
+/// A saved view of the camera.
+#[derive(Clone, Copy, Debug)]
+pub struct Bookmark {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    /// The offset of the data that was loaded when the bookmark was made.
+    /// World coordinates only mean the same place with the same offset.
+    pub offset: Offset,
+}
+
+impl Bookmark {
+    /// Returns whether the bookmark was made in data with the given offset.
+    pub fn matches_offset(&self, offset: &Offset) -> bool {
+        (self.offset.x - offset.x).abs() < OFFSET_TOLERANCE
+            && (self.offset.y - offset.y).abs() < OFFSET_TOLERANCE
+            && (self.offset.latitude - offset.latitude).abs() < OFFSET_TOLERANCE
+            && self.offset.scale == offset.scale
+    }
+
+    fn to_json(self) -> Value {
+        let Vec3 { x, y, z } = self.translation;
+        let [rx, ry, rz, rw] = self.rotation.to_array();
+        json!({
+            "translation": [x, y, z],
+            "rotation": [rx, ry, rz, rw],
+            "offset": [
+                self.offset.x,
+                self.offset.y,
+                self.offset.latitude,
+                self.offset.scale.units_per_meter,
+            ],
+        })
+    }
+
+    fn from_json(value: &Value) -> Option<Self> {
+        let numbers = |key: &str| -> Option<Vec<f64>> {
+            value
+                .get(key)?
+                .as_array()?
+                .iter()
+                .map(Value::as_f64)
+                .collect()
+        };
+        let translation = numbers("translation")?;
+        let rotation = numbers("rotation")?;
+        let offset = numbers("offset")?;
+        if translation.len() != 3 || rotation.len() != 4 || !(3..=4).contains(&offset.len()) {
+            return None;
+        }
+        // bookmarks without a scale were made at the default scale
+        let units_per_meter = offset
+            .get(3)
+            .map_or(WorldScale::default().units_per_meter, |&units| units as f32);
+        Some(Bookmark {
+            translation: Vec3::new(
+                translation[0] as f32,
+                translation[1] as f32,
+                translation[2] as f32,
+            ),
+            rotation: Quat::from_xyzw(
+                rotation[0] as f32,
+                rotation[1] as f32,
+                rotation[2] as f32,
+                rotation[3] as f32,
+            )
+            .normalize(),
+            offset: Offset {
+                x: offset[0],
+                y: offset[1],
+                latitude: offset[2],
+                scale: WorldScale { units_per_meter },
+            },
+        })
+    }
+}
+
+/// Moves the camera to the view of the bookmark with the given name.
+#[derive(Clone, Debug, Event)]
+pub struct RecallBookmarkEvent {
+    pub name: String,
+}
+
+/// All bookmarks, by name.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use city_visualizer::bookmarks::{Bookmark, Bookmarks};
+/// use city_visualizer::data::geography::{Offset, WorldScale};
+///
+/// let offset = Offset { x: 0.5, y: 0.25, latitude: 52.0, scale: WorldScale::default() };
+/// let mut bookmarks = Bookmarks::default();
+/// bookmarks.insert(
+///     "Square".to_owned(),
+///     Bookmark { translation: Vec3::new(1.0, 2.0, 3.0), rotation: Quat::IDENTITY, offset },
+/// );
+///
+/// let loaded = Bookmarks::from_json(&bookmarks.to_json());
+/// let square = loaded.get("Square").unwrap();
+/// assert_eq!(square.translation, Vec3::new(1.0, 2.0, 3.0));
+/// assert!(square.matches_offset(&offset));
+/// assert!(!square.matches_offset(&Offset { y: 0.3, ..offset }));
+/// assert!(!square.matches_offset(&Offset { scale: WorldScale { units_per_meter: 1.0 }, ..offset }));
+/// ```
+#[derive(Debug, Default, Resource)]
+pub struct Bookmarks {
+    bookmarks: BTreeMap<String, Bookmark>,
+    /// Whether the bookmarks window is shown.
+    pub window_visible: bool,
+    /// The name of the bookmark that was recalled last, to jump to the next.
+    last_recalled: Option<String>,
+}
+
+impl Bookmarks {
+    /// Adds a bookmark, replacing the bookmark with the same name.
+    pub fn insert(&mut self, name: String, bookmark: Bookmark) {
+        self.bookmarks.insert(name, bookmark);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Bookmark> {
+        self.bookmarks.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Bookmark> {
+        self.bookmarks.get(name)
+    }
+
+    /// Moves the bookmarks made in data with the `previous` offset by
+    /// `-shift`, when the origin of the world is moved by `shift`. Returns
+    /// whether any bookmark was moved.
+    pub fn shift(&mut self, previous: &Offset, shift: Vec2) -> bool {
+        let mut shifted = false;
+        for bookmark in self.bookmarks.values_mut() {
+            if bookmark.matches_offset(previous) {
+                bookmark.translation -= Vec3::new(shift.x, 0.0, shift.y);
+                bookmark.offset = previous.shifted(shift);
+                shifted = true;
+            }
+        }
+        shifted
+    }
+
+    /// Returns the name of the first bookmark after `name`, in order of
+    /// names, that was made in data with the given offset. Starts over at the
+    /// first one after the last one, or if `name` is `None`.
+    ///
+    /// ```
+    /// use bevy::prelude::*;
+    /// use city_visualizer::bookmarks::{Bookmark, Bookmarks};
+    /// use city_visualizer::data::geography::{Offset, WorldScale};
+    ///
+    /// let offset = Offset { x: 0.5, y: 0.25, latitude: 52.0, scale: WorldScale::default() };
+    /// let other = Offset { x: 0.75, ..offset };
+    /// let mut bookmarks = Bookmarks::default();
+    /// for (name, offset) in [("Bridge", offset), ("Church", other), ("Square", offset)] {
+    ///     bookmarks.insert(
+    ///         name.to_owned(),
+    ///         Bookmark { translation: Vec3::ZERO, rotation: Quat::IDENTITY, offset },
+    ///     );
+    /// }
+    ///
+    /// let next = |name| bookmarks.next_after(name, &offset).map(String::as_str);
+    /// assert_eq!(next(None), Some("Bridge"));
+    /// // the church is in other data
+    /// assert_eq!(next(Some("Bridge")), Some("Square"));
+    /// assert_eq!(next(Some("Square")), Some("Bridge"));
+    /// assert_eq!(next(Some("Removed")), Some("Square"));
+    /// assert_eq!(Bookmarks::default().next_after(None, &offset), None);
+    /// ```
+    pub fn next_after(&self, name: Option<&str>, offset: &Offset) -> Option<&String> {
+        let matching = || {
+            self.bookmarks
+                .iter()
+                .filter(|(_, bookmark)| bookmark.matches_offset(offset))
+                .map(|(name, _)| name)
+        };
+        name.and_then(|name| matching().find(|next| next.as_str() > name))
+            .or_else(|| matching().next())
+    }
+
+    /// Returns the bookmarks ordered by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Bookmark)> {
+        self.bookmarks.iter()
+    }
+
+    pub fn to_json(&self) -> String {
+        let bookmarks: serde_json::Map<String, Value> = self
+            .bookmarks
+            .iter()
+            .map(|(name, bookmark)| (name.clone(), bookmark.to_json()))
+            .collect();
+        Value::Object(bookmarks).to_string()
+    }
+
+    /// Reads bookmarks saved by `to_json`. Bookmarks that cannot be read are
+    /// skipped.
+    pub fn from_json(string: &str) -> Self {
+        let mut bookmarks = Bookmarks::default();
+        if let Ok(Value::Object(map)) = serde_json::from_str::<Value>(string) {
+            for (name, value) in map {
+                if let Some(bookmark) = Bookmark::from_json(&value) {
+                    bookmarks.insert(name, bookmark);
+                }
+            }
+        }
+        bookmarks
+    }
+}
+
+/// A system that loads the bookmarks that were saved before.
+pub fn setup_bookmarks(mut bookmarks: ResMut<Bookmarks>) {
+    if let Some(saved) = load_bookmarks() {
+        bookmarks.bookmarks = Bookmarks::from_json(&saved).bookmarks;
+    }
+}
+
+/// A system that keeps bookmarks at their place when the origin of the world
+/// is moved.
+pub fn update_bookmark_offsets(
+    mut shift_events: EventReader<OriginShiftEvent>,
+    mut bookmarks: ResMut<Bookmarks>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let mut shifted = false;
+    for event in shift_events.read() {
+        shifted |= bookmarks.shift(&event.previous_offset, event.shift);
+    }
+    if shifted {
+        if let Err(error) = save_bookmarks(&bookmarks.to_json()) {
+            status_events.send(StatusEvent::Error(error));
+        }
+    }
+}
+
+/// A system that registers the commands for bookmarks.
+pub fn register_bookmark_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle bookmarks",
+        "Shows the saved places, to save the current view or go back to one",
+        Some(KeyCode::KeyB),
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut bookmarks = world.resource_mut::<Bookmarks>();
+                bookmarks.window_visible = !bookmarks.window_visible;
+            })
+        },
+    );
+    registry.register(
+        "Jump to next bookmark",
+        "Moves the camera to the next saved place in the loaded data, in order of name",
+        Some(KeyCode::KeyN),
+        |commands| {
+            commands.add(|world: &mut World| {
+                let offset = *world.resource::<Offset>();
+                let bookmarks = world.resource::<Bookmarks>();
+                match bookmarks.next_after(bookmarks.last_recalled.as_deref(), &offset) {
+                    Some(name) => {
+                        let name = name.clone();
+                        world.send_event(RecallBookmarkEvent { name });
+                    }
+                    None => {
+                        world.send_event(StatusEvent::Error(AppError::MissingData {
+                            message: "there are no bookmarks in the loaded data, save a view first".to_owned(),
+                        }));
+                    }
+                }
+            })
+        },
+    );
+}
+
+/// A system that shows the bookmarks window, where the current view can be
+/// saved and saved views can be recalled or deleted.
+pub fn update_bookmarks_window(
+    mut contexts: EguiContexts,
+    mut bookmarks: ResMut<Bookmarks>,
+    mut new_name: Local<String>,
+    offset: Res<Offset>,
+    players: Query<&Transform, With<Player>>,
+    mut recall_events: EventWriter<RecallBookmarkEvent>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    if !bookmarks.window_visible {
+        return;
+    }
+
+    let mut save = false;
+    let mut recall = None;
+    let mut delete = None;
+    let mut visible = true;
+    egui::Window::new("Bookmarks")
+        .open(&mut visible)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(&mut *new_name)
+                        .hint_text("Name")
+                        .desired_width(140.0),
+                );
+                save = ui.button("Save view").clicked();
+            });
+            ui.separator();
+
+            if bookmarks.iter().next().is_none() {
+                ui.label("No bookmarks yet");
+            }
+            egui::ScrollArea::vertical()
+                .max_height(200.0)
+                .show(ui, |ui| {
+                    for (name, bookmark) in bookmarks.iter() {
+                        ui.horizontal(|ui| {
+                            let button = ui.button(name);
+                            let button = if bookmark.matches_offset(&offset) {
+                                button
+                            } else {
+                                button.on_hover_text("Made in other data than the loaded data")
+                            };
+                            if button.clicked() {
+                                recall = Some(name.clone());
+                            }
+                            if ui.small_button("Delete").clicked() {
+                                delete = Some(name.clone());
+                            }
+                        });
+                    }
+                });
+        });
+    bookmarks.window_visible = visible;
+
+    if save {
+        let Ok(transform) = players.get_single() else {
+            return;
+        };
+        if offset.x == f64::NEG_INFINITY {
+            status_events.send(StatusEvent::Error(AppError::MissingData {
+                message: "load data before saving a bookmark".to_owned(),
+            }));
+            return;
+        }
+        let name = match new_name.trim() {
+            "" => format!("Bookmark {}", bookmarks.bookmarks.len() + 1),
+            name => name.to_owned(),
+        };
+        bookmarks.insert(
+            name.clone(),
+            Bookmark {
+                translation: transform.translation,
+                rotation: transform.rotation,
+                offset: *offset,
+            },
+        );
+        new_name.clear();
+        match save_bookmarks(&bookmarks.to_json()) {
+            Ok(()) => status_events.send(StatusEvent::Update(format!("Saved bookmark \"{}\"", name))),
+            Err(error) => status_events.send(StatusEvent::Error(error)),
+        };
+    }
+
+    if let Some(name) = delete {
+        bookmarks.remove(&name);
+        if let Err(error) = save_bookmarks(&bookmarks.to_json()) {
+            status_events.send(StatusEvent::Error(error));
+        }
+    }
+
+    if let Some(name) = recall {
+        recall_events.send(RecallBookmarkEvent { name });
+    }
+}
+
+/// A system that moves the camera to the bookmarks that are recalled, if they
+/// were made in the loaded data.
+pub fn update_bookmark_recalls(
+    mut recall_events: EventReader<RecallBookmarkEvent>,
+    mut bookmarks: ResMut<Bookmarks>,
+    offset: Res<Offset>,
+    mut players: Query<(&mut Player, &mut Transform, &mut Projection)>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    for RecallBookmarkEvent { name } in recall_events.read() {
+        let Some(bookmark) = bookmarks.get(name).copied() else {
+            continue;
+        };
+        // the same coordinates are somewhere else in other data
+        if !bookmark.matches_offset(&offset) {
+            status_events.send(StatusEvent::Error(AppError::MissingData {
+                message: format!(
+                    "bookmark \"{}\" was made in other data, load that data to go there",
+                    name
+                ),
+            }));
+            continue;
+        }
+        for (mut player, mut transform, mut projection) in &mut players {
+            // bookmarks are views of the free camera
+            if player.camera_mode != CameraMode::Perspective {
+                player.camera_mode = CameraMode::Perspective;
+                *projection = Projection::Perspective(perspective_projection(&offset.scale));
+            }
+            transform.translation = bookmark.translation;
+            transform.rotation = bookmark.rotation;
+        }
+        bookmarks.last_recalled = Some(name.clone());
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_bookmarks() -> Option<String> {
+    std::fs::read_to_string(BOOKMARKS_FILE).ok()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn save_bookmarks(json: &str) -> Result<(), AppError> {
+    std::fs::write(BOOKMARKS_FILE, json).map_err(|error| AppError::Io {
+        url: None,
+        status: None,
+        message: format!("could not save the bookmarks to {}: {}", BOOKMARKS_FILE, error),
+    })
+}
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok().flatten()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn load_bookmarks() -> Option<String> {
+    local_storage()?.get_item(BOOKMARKS_KEY).ok().flatten()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn save_bookmarks(json: &str) -> Result<(), AppError> {
+    let saved = local_storage().map_or(false, |storage| {
+        storage.set_item(BOOKMARKS_KEY, json).is_ok()
+    });
+    if saved {
+        Ok(())
+    } else {
+        Err(AppError::Io {
+            url: None,
+            status: None,
+            message: "could not save the bookmarks in the local storage of the browser".to_owned(),
+        })
+    }
+}
@@ -0,0 +1,242 @@
+//! A central registry of user-facing actions, which powers the command palette
+//! (opened with Ctrl+P) and the keyboard cheat sheet.
+
+use bevy::prelude::*;
+
+use bevy_egui::egui;
+use bevy_egui::EguiContexts;
+
+/// The function that is run when a command is executed.
+pub type CommandAction = fn(&mut Commands);
+
+/// A single user-facing action.
+pub struct AppCommand {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub keybinding: Option<KeyCode>,
+    pub action: CommandAction,
+}
+
+/// All actions that can be executed from the command palette or with their
+/// keybinding. Feature modules register their commands in a startup system.
+#[derive(Default, Resource)]
+pub struct CommandRegistry {
+    commands: Vec<AppCommand>,
+}
+
+impl CommandRegistry {
+    /// Adds a command to the registry.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        description: &'static str,
+        keybinding: Option<KeyCode>,
+        action: CommandAction,
+    ) {
+        self.commands.push(AppCommand {
+            name,
+            description,
+            keybinding,
+            action,
+        });
+    }
+
+    /// Returns all registered commands, in order of registration.
+    pub fn commands(&self) -> &[AppCommand] {
+        &self.commands
+    }
+
+    /// Returns the indices of the commands matching `query`, best match first.
+    pub fn search(&self, query: &str) -> Vec<usize> {
+        let mut matches: Vec<(usize, i32)> = self
+            .commands
+            .iter()
+            .enumerate()
+            .filter_map(|(i, command)| fuzzy_score(query, command.name).map(|score| (i, score)))
+            .collect();
+        // stable sort, so equally good matches keep their registration order
+        matches.sort_by(|a, b| b.1.cmp(&a.1));
+        matches.into_iter().map(|(i, _)| i).collect()
+    }
+}
+
+/// Returns a score for how well `query` matches `candidate`, or `None` if the
+/// characters of `query` do not all appear in order in `candidate`.
+///
+/// Consecutive characters and characters at the start of a word score higher.
+fn fuzzy_score(query: &str, candidate: &str) -> Option<i32> {
+    let candidate: Vec<char> = candidate.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut position = 0;
+    let mut previous_match: Option<usize> = None;
+
+    for query_char in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let offset = candidate[position..].iter().position(|&c| c == query_char)?;
+        let index = position + offset;
+
+        score += 1;
+        if previous_match.is_some_and(|previous| previous + 1 == index) {
+            score += 5;
+        }
+        if index == 0 || candidate[index - 1] == ' ' {
+            score += 3;
+        }
+
+        previous_match = Some(index);
+        position = index + 1;
+    }
+
+    // prefer shorter names when the rest is equal
+    Some(score * 100 - candidate.len() as i32)
+}
+
+/// The state of the command palette and the keyboard cheat sheet.
+#[derive(Debug, Default, Resource)]
+pub struct CommandPaletteState {
+    pub open: bool,
+    pub query: String,
+    pub selected: usize,
+    pub show_cheat_sheet: bool,
+}
+
+/// A system that registers the commands of the palette itself.
+pub fn register_palette_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Show keyboard shortcuts",
+        "Toggles the list of all commands with a keybinding",
+        Some(KeyCode::F1),
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut state = world.resource_mut::<CommandPaletteState>();
+                state.show_cheat_sheet = !state.show_cheat_sheet;
+            })
+        },
+    );
+}
+
+/// A system that draws the command palette and the cheat sheet, and executes
+/// the commands that are picked in it.
+pub fn update_command_palette(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut state: ResMut<CommandPaletteState>,
+    registry: Res<CommandRegistry>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+) {
+    let ctx = contexts.ctx_mut();
+
+    let control = keyboard_input.pressed(KeyCode::ControlLeft)
+        || keyboard_input.pressed(KeyCode::ControlRight);
+    if control && keyboard_input.just_pressed(KeyCode::KeyP) {
+        state.open = !state.open;
+        state.query.clear();
+        state.selected = 0;
+    }
+
+    if state.show_cheat_sheet {
+        let mut open = true;
+        egui::Window::new("Keyboard Shortcuts")
+            .id("keyboard_cheat_sheet".into())
+            .open(&mut open)
+            .show(ctx, |ui| {
+                egui::Grid::new("cheat_sheet_grid").striped(true).show(ui, |ui| {
+                    ui.label("Ctrl+P");
+                    ui.label("Open the command palette");
+                    ui.end_row();
+                    for command in registry.commands() {
+                        if let Some(key) = command.keybinding {
+                            ui.label(format!("{:?}", key));
+                            ui.label(command.description);
+                            ui.end_row();
+                        }
+                    }
+                });
+            });
+        state.show_cheat_sheet = open;
+    }
+
+    if !state.open {
+        return;
+    }
+
+    let mut run: Option<usize> = None;
+    let mut close = false;
+
+    egui::Window::new("Command Palette")
+        .id("command_palette".into())
+        .title_bar(false)
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_TOP, [0.0, 40.0])
+        .show(ctx, |ui| {
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut state.query)
+                    .hint_text("Type a command..."),
+            );
+            response.request_focus();
+            if response.changed() {
+                state.selected = 0;
+            }
+
+            let results = registry.search(&state.query);
+            if !results.is_empty() {
+                if ui.input(|i| i.key_pressed(egui::Key::ArrowDown)) {
+                    state.selected = (state.selected + 1) % results.len();
+                }
+                if ui.input(|i| i.key_pressed(egui::Key::ArrowUp)) {
+                    state.selected = (state.selected + results.len() - 1) % results.len();
+                }
+                state.selected = state.selected.min(results.len() - 1);
+            }
+
+            ui.separator();
+            for (position, &index) in results.iter().enumerate() {
+                let command = &registry.commands()[index];
+                let label = ui
+                    .selectable_label(position == state.selected, command.name)
+                    .on_hover_text(command.description);
+                if label.clicked() {
+                    run = Some(index);
+                }
+            }
+            if results.is_empty() {
+                ui.label("No matching commands");
+            }
+
+            if ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                run = results.get(state.selected).copied();
+            }
+            if ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                close = true;
+            }
+        });
+
+    if let Some(index) = run {
+        (registry.commands()[index].action)(&mut commands);
+        close = true;
+    }
+    if close {
+        state.open = false;
+        state.query.clear();
+        state.selected = 0;
+    }
+}
+
+/// A system that executes commands when their keybinding is pressed, unless
+/// the user is typing in the UI.
+pub fn update_command_keybindings(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    registry: Res<CommandRegistry>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+) {
+    if contexts.ctx_mut().wants_keyboard_input() {
+        return;
+    }
+
+    for command in registry.commands() {
+        if command.keybinding.is_some_and(|key| keyboard_input.just_pressed(key)) {
+            (command.action)(&mut commands);
+        }
+    }
+}
@@ -5,6 +5,8 @@ use bevy::tasks::AsyncComputeTaskPool;
 use bevy::tasks::{futures_lite::future, Task};
 
 use bevy_mod_reqwest::reqwest::StatusCode;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
 
 use std::fmt::{Display, Formatter};
 use std::future::Future;
@@ -19,6 +21,9 @@ use std::path::Path;
 pub enum DataFormat {
     OsmJson,
     GeoJson,
+    /// Converted data that was saved by this app, see
+    /// [`city_bin`](../data/city_bin/index.html).
+    CityBin,
 }
 
 impl Display for DataFormat {
@@ -26,6 +31,7 @@ impl Display for DataFormat {
         match self {
             DataFormat::OsmJson => write!(f, "osm json"),
             DataFormat::GeoJson => write!(f, "geojson"),
+            DataFormat::CityBin => write!(f, "citybin"),
         }
     }
 }
@@ -131,8 +137,34 @@ impl Display for AppError {
 pub enum StatusEvent {
     Error(AppError),
     Update(String),
+    /// The progress of loading data, shown as a progress bar until the
+    /// fraction reaches 1. An error also ends the progress.
+    Progress {
+        label: String,
+        /// How much of the current step is done, from 0 to 1, or `None` if
+        /// that is unknown.
+        fraction: Option<f32>,
+    },
+}
+
+impl StatusEvent {
+    /// Creates a progress event for a step of which the progress is unknown,
+    /// such as waiting for a server.
+    pub fn progress_step(label: impl Into<String>) -> Self {
+        StatusEvent::Progress { label: label.into(), fraction: None }
+    }
+
+    /// Creates a progress event that ends the progress.
+    pub fn progress_finished() -> Self {
+        StatusEvent::Progress { label: String::new(), fraction: Some(1.0) }
+    }
 }
 
+/// An event to stop loading data: queries that are waiting for an answer are
+/// dropped, and tasks that are parsing or generating data are cancelled.
+#[derive(Event)]
+pub struct CancelLoadingEvent;
+
 #[derive(Component)]
 pub struct AsyncComputation<T>
 where
@@ -144,6 +176,12 @@ where
     pub receiver: crossbeam_channel::Receiver<T>,
 }
 
+/// Marks an entity with an `AsyncComputation` of which the result has not
+/// been handled yet, whatever the type of the result. Used to find out whether
+/// all work has settled.
+#[derive(Component)]
+pub struct PendingComputation;
+
 /// Spawns an asynchronous task in a different thread, and adds it as an
 /// entity+component to the world.
 /// 
@@ -162,7 +200,7 @@ where
         let task = task_pool.spawn(computation);
         // by adding this as an entity, we can poll for this event in
         // a system, which is where the produced data is then used
-        commands.spawn(AsyncComputation { task });
+        commands.spawn((AsyncComputation { task }, PendingComputation));
     }
 
     #[cfg(target_arch = "wasm32")]
@@ -171,27 +209,47 @@ where
         task_pool.spawn(async move {
             let _ = sender.send(computation.await);
         }).detach();
-        commands.spawn(AsyncComputation { receiver });
+        commands.spawn((AsyncComputation { receiver }, PendingComputation));
     }
 }
 
 /// Polls all async compute tasks given in `query` and calls `callback` on them
 /// if they gave back a result.
 pub fn handle_compute_tasks<T>(
+    commands: &mut Commands,
+    query: Query<(Entity, &mut AsyncComputation<T>)>,
+    callback: impl FnMut(&mut Commands, T),
+)
+where
+    T: Send + Sync + 'static,
+{
+    handle_compute_tasks_limited(commands, query, usize::MAX, callback);
+}
+
+/// Like `handle_compute_tasks`, but handles at most `max_results` results.
+/// The remaining results are handled in later calls.
+pub fn handle_compute_tasks_limited<T>(
     commands: &mut Commands,
     mut query: Query<(Entity, &mut AsyncComputation<T>)>,
+    max_results: usize,
     mut callback: impl FnMut(&mut Commands, T),
 )
 where
     T: Send + Sync + 'static,
 {
+    let mut handled = 0;
+
     #[cfg(not(target_arch = "wasm32"))]
-    future::block_on(async move {
+    future::block_on(async {
         for (id, mut computation) in &mut query {
+            if handled >= max_results {
+                break;
+            }
             match future::poll_once(&mut computation.task).await {
                 Some(result) => {
                     callback(commands, result);
-                    commands.entity(id).remove::<AsyncComputation<T>>();
+                    commands.entity(id).remove::<(AsyncComputation<T>, PendingComputation)>();
+                    handled += 1;
                 },
                 None => {},
             }
@@ -200,12 +258,33 @@ where
 
     #[cfg(target_arch = "wasm32")]
     for (id, computation) in &mut query {
+        if handled >= max_results {
+            break;
+        }
         match computation.receiver.try_recv() {
             Ok(result) => {
                 callback(commands, result);
-                commands.entity(id).remove::<AsyncComputation<T>>();
+                commands.entity(id).remove::<(AsyncComputation<T>, PendingComputation)>();
+                handled += 1;
             },
             Err(_) => {}, // computation does not have a result yet
         }
     }
 }
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen(inline_js = "
+export function download_file(name, type, bytes) {
+    const url = URL.createObjectURL(new Blob([bytes], { type }));
+    const link = document.createElement('a');
+    link.href = url;
+    link.download = name;
+    link.click();
+    setTimeout(() => URL.revokeObjectURL(url), 0);
+}
+")]
+extern "C" {
+    /// Makes the browser download `bytes` as a file named `name`, since pages
+    /// cannot write files themselves.
+    pub fn download_file(name: &str, mime_type: &str, bytes: &[u8]);
+}
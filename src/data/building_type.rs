@@ -1,6 +1,7 @@
 use std::{convert::Infallible, str::FromStr};
 
 use bevy::math::Vec2;
+use bevy::render::color::Color;
 
 /// This module defines the `BuildingType` and `RoofShape` enums and the `PartialBuilding` and `Building` structs.
 ///
@@ -97,6 +98,75 @@ pub fn get_random_range_building(building_type: BuildingType) -> (i32, i32) {
     }
 }
 
+/// How the windows of a building are lit at night.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum LightingClass {
+    /// Homes and other buildings where people stay the night. Some windows
+    /// are lit, and which ones changes over time.
+    Residential,
+    /// Offices, shops and public buildings, which are lit in the evening only.
+    Office,
+    /// Warehouses, factories, barns and unknown buildings, which stay dark.
+    Dark,
+}
+
+/// Maps a `BuildingType` to how its windows are lit at night. Buildings of
+/// unknown type in residential land use are houses or apartments by then.
+///
+/// ```
+/// use city_visualizer::data::building_type::{building_type_to_lighting, BuildingType, LightingClass};
+///
+/// assert_eq!(building_type_to_lighting(BuildingType::Apartments), LightingClass::Residential);
+/// assert_eq!(building_type_to_lighting(BuildingType::House), LightingClass::Residential);
+/// assert_eq!(building_type_to_lighting(BuildingType::Residential), LightingClass::Residential);
+/// assert_eq!(building_type_to_lighting(BuildingType::Office), LightingClass::Office);
+/// assert_eq!(building_type_to_lighting(BuildingType::Retail), LightingClass::Office);
+/// assert_eq!(building_type_to_lighting(BuildingType::Industrial), LightingClass::Dark);
+/// assert_eq!(building_type_to_lighting(BuildingType::Warehouse), LightingClass::Dark);
+/// assert_eq!(building_type_to_lighting(BuildingType::Other), LightingClass::Dark);
+/// ```
+pub fn building_type_to_lighting(building_type: BuildingType) -> LightingClass {
+    match building_type {
+        BuildingType::Apartments
+        | BuildingType::Barracks
+        | BuildingType::Bungalow
+        | BuildingType::Cabin
+        | BuildingType::Detached
+        | BuildingType::Dormitory
+        | BuildingType::Farm
+        | BuildingType::Hotel
+        | BuildingType::House
+        | BuildingType::Houseboat
+        | BuildingType::Residential
+        | BuildingType::SemidetachedHouse
+        | BuildingType::StaticCaravan
+        | BuildingType::Terrace
+        | BuildingType::Hospital
+        | BuildingType::FireStation => LightingClass::Residential,
+        BuildingType::Commercial
+        | BuildingType::Kiosk
+        | BuildingType::Office
+        | BuildingType::Retail
+        | BuildingType::Supermarket
+        | BuildingType::Civic
+        | BuildingType::College
+        | BuildingType::Government
+        | BuildingType::Kindergarten
+        | BuildingType::Museum
+        | BuildingType::Public
+        | BuildingType::School
+        | BuildingType::TrainStation
+        | BuildingType::Transportation
+        | BuildingType::University => LightingClass::Office,
+        BuildingType::Industrial
+        | BuildingType::Warehouse
+        | BuildingType::Bakehouse
+        | BuildingType::Bridge
+        | BuildingType::Toilets
+        | BuildingType::Other => LightingClass::Dark,
+    }
+}
+
 /// Error type for parsing a `BuildingType`.
 /// Can realistically only happen when the building was marked as present but type was not specified https://wiki.openstreetmap.org/wiki/Tag:building%3Dyes
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -183,10 +253,14 @@ pub struct PartialBuilding {
     pub id: u64,
     pub building_type: Option<BuildingType>,
     pub levels: Option<i32>,
+    /// The height in meters, including the roof.
+    pub height: Option<f32>,
     pub base: Vec<Vec2>,
     pub roof_shape: Option<RoofShape>,
     pub roof_levels: Option<i32>,
     pub inside_area: BuildingLandUseType,
+    pub colour: Option<Color>,
+    pub roof_colour: Option<Color>,
 }
 
 /// What land use area a building is in, useful for determining building type if that is not known
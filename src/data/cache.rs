@@ -0,0 +1,199 @@
+//! A cache of Overpass responses on disk, so loading the same query again
+//! does not download the data again. Responses are stored under a hash of the
+//! query, and old responses are removed when they expire or the cache grows
+//! too large.
+//!
+//! The cache is only available on native. On the web, it is never hit.
+
+use bevy::prelude::*;
+
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// How long a cached response is used, before the data is downloaded again.
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How many bytes of responses are kept at most.
+const DEFAULT_MAX_SIZE: u64 = 500 * 1024 * 1024;
+
+/// The extension of cached responses, which are OSM JSON.
+const CACHE_EXTENSION: &str = "json";
+
+/// Returns the name under which the response to `query` is cached: the
+/// 64-bit FNV-1a hash of the query in hexadecimal. Unlike the hasher of the
+/// standard library, this is the same in every build.
+///
+/// ```
+/// use city_visualizer::data::cache::cache_key;
+///
+/// assert_eq!(cache_key(""), "cbf29ce484222325");
+/// assert_eq!(cache_key("a"), "af63dc4c8601ec8c");
+/// ```
+pub fn cache_key(query: &str) -> String {
+    let hash = query.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// A response that was found in the cache.
+#[derive(Clone, Debug)]
+pub struct CachedResponse {
+    pub path: PathBuf,
+    /// How long ago the response was stored.
+    pub age: Duration,
+}
+
+/// The cache of Overpass responses, and its settings.
+///
+/// ```
+/// use city_visualizer::data::cache::ResponseCache;
+///
+/// let dir = std::env::temp_dir().join(format!("city-loader-doc-{}", std::process::id()));
+/// let mut cache = ResponseCache::in_dir(dir.clone());
+/// let query = "[out:json];way[building];out;";
+/// assert!(cache.get(query).is_none());
+///
+/// cache.insert(query, r#"{"elements": []}"#).unwrap();
+/// let cached = cache.get(query).unwrap();
+/// assert_eq!(std::fs::read_to_string(&cached.path).unwrap(), r#"{"elements": []}"#);
+///
+/// // the oldest responses are removed when the cache is too large
+/// let an_hour_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(3600);
+/// std::fs::File::options().write(true).open(&cached.path).unwrap().set_modified(an_hour_ago).unwrap();
+/// cache.max_size = 20;
+/// cache.insert("other query", r#"{"elements": [1]}"#).unwrap();
+/// assert!(cache.get(query).is_none());
+/// assert!(cache.get("other query").is_some());
+///
+/// assert_eq!(cache.clear().unwrap(), 1);
+/// assert!(cache.get("other query").is_none());
+/// std::fs::remove_dir(&dir).unwrap();
+/// ```
+#[derive(Clone, Debug, Resource)]
+pub struct ResponseCache {
+    /// The directory responses are stored in, or `None` if there is no cache.
+    dir: Option<PathBuf>,
+    /// Whether queries skip the cache and always download the data. Their
+    /// responses are still stored.
+    pub bypass: bool,
+    /// How old responses may be before they are downloaded again.
+    pub max_age: Duration,
+    /// How many bytes of responses are kept at most.
+    pub max_size: u64,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache {
+            dir: default_cache_dir(),
+            bypass: false,
+            max_age: DEFAULT_MAX_AGE,
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+impl ResponseCache {
+    /// Creates a cache that stores responses in `dir`, which is created when
+    /// the first response is stored.
+    pub fn in_dir(dir: PathBuf) -> Self {
+        ResponseCache {
+            dir: Some(dir),
+            ..ResponseCache::default()
+        }
+    }
+
+    /// Returns whether responses can be cached on this platform.
+    pub fn is_available(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    /// Returns the cached response to `query`, if it is not expired. The
+    /// response itself is not read, since it can be large.
+    pub fn get(&self, query: &str) -> Option<CachedResponse> {
+        let path = self.path(query)?;
+        let modified = std::fs::metadata(&path).and_then(|metadata| metadata.modified()).ok()?;
+        let age = modified.elapsed().unwrap_or_default();
+        (age <= self.max_age).then_some(CachedResponse { path, age })
+    }
+
+    /// Stores the response to `query`, and removes expired responses and the
+    /// oldest responses while the cache is too large.
+    pub fn insert(&self, query: &str, response: &str) -> io::Result<()> {
+        let (Some(dir), Some(path)) = (&self.dir, self.path(query)) else {
+            return Ok(());
+        };
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(path, response)?;
+        self.evict()
+    }
+
+    /// Removes all cached responses, and returns how many there were.
+    pub fn clear(&self) -> io::Result<usize> {
+        let entries = self.entries()?;
+        for (path, _, _) in &entries {
+            std::fs::remove_file(path)?;
+        }
+        Ok(entries.len())
+    }
+
+    fn path(&self, query: &str) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        Some(dir.join(cache_key(query)).with_extension(CACHE_EXTENSION))
+    }
+
+    /// Removes expired responses, and then the oldest responses until the
+    /// cache fits in `max_size`.
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = self.entries()?;
+        // newest first, so the oldest are at the end
+        entries.sort_by(|(_, a, _), (_, b, _)| b.cmp(a));
+
+        let mut size = 0;
+        for (path, modified, length) in entries {
+            let expired = modified.elapsed().unwrap_or_default() > self.max_age;
+            if expired || size + length > self.max_size {
+                std::fs::remove_file(path)?;
+            } else {
+                size += length;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the path, modification time and size of all cached responses.
+    fn entries(&self) -> io::Result<Vec<(PathBuf, SystemTime, u64)>> {
+        let Some(dir) = &self.dir else {
+            return Ok(Vec::new());
+        };
+        let read_dir = match std::fs::read_dir(dir) {
+            Ok(read_dir) => read_dir,
+            // nothing was cached yet
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+
+        let mut entries = Vec::new();
+        for entry in read_dir {
+            let path = entry?.path();
+            if path.extension().map_or(true, |extension| extension != CACHE_EXTENSION) {
+                continue;
+            }
+            let metadata = std::fs::metadata(&path)?;
+            entries.push((path, metadata.modified()?, metadata.len()));
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn default_cache_dir() -> Option<PathBuf> {
+    Some(dirs::cache_dir()?.join("city-loader"))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn default_cache_dir() -> Option<PathBuf> {
+    None
+}
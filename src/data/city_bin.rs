@@ -0,0 +1,204 @@
+//! Saves converted data in a compact binary format, so that it can be loaded
+//! again without parsing and converting the original OSM JSON or GeoJSON,
+//! which takes far longer for large cities.
+//!
+//! A `.citybin` file starts with a magic header and a format version,
+//! followed by the `GeoData` encoded with [bincode]. Files of other versions
+//! are rejected, since bincode data cannot be read with a different layout.
+//!
+//! [bincode]: https://docs.rs/bincode
+
+use crate::common::{handle_compute_tasks, spawn_compute_task, AppError, AsyncComputation, DataFormat, StatusEvent};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::commands::CommandRegistry;
+use crate::data::geography::GeoData;
+use crate::earth::LoadedGeoData;
+
+use bevy::prelude::*;
+
+use std::path::{Path, PathBuf};
+
+/// The bytes that every saved data file starts with.
+const MAGIC: &[u8; 8] = b"CITYBIN\0";
+
+/// The version of the layout of saved data. Increase it whenever `GeoData`
+/// or any of the types in it changes.
+const VERSION: u32 = 1;
+
+/// The length of the magic bytes and the version.
+const HEADER_LENGTH: usize = MAGIC.len() + 4;
+
+/// Returns whether `bytes` start like a saved data file.
+pub fn is_city_bin(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Encodes data in the saved data format.
+///
+/// Decoding gives back the same data, with all node locations, tags and
+/// chunks:
+///
+/// ```
+/// use city_visualizer::data::city_bin::{decode_geo_data, encode_geo_data, is_city_bin};
+/// use city_visualizer::data::geography::convert_osm_json;
+///
+/// let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/fixtures/small_town.json");
+/// let json = serde_json::from_str(&std::fs::read_to_string(fixture).unwrap()).unwrap();
+/// let data = convert_osm_json(json).unwrap();
+///
+/// let bytes = encode_geo_data(&data).unwrap();
+/// assert!(is_city_bin(&bytes));
+/// let decoded = decode_geo_data(&bytes).unwrap();
+/// assert_eq!(decoded.node_locations, data.node_locations);
+/// assert_eq!(decoded.chunks, data.chunks);
+/// assert_eq!(decoded, data);
+///
+/// // data that was cut off
+/// assert!(decode_geo_data(&bytes[..bytes.len() / 2]).is_err());
+/// ```
+pub fn encode_geo_data(data: &GeoData) -> Result<Vec<u8>, AppError> {
+    let mut bytes = Vec::with_capacity(HEADER_LENGTH);
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&VERSION.to_le_bytes());
+    bincode::serialize_into(&mut bytes, data)
+        .map_err(|error| syntax_error(format!("could not encode the data: {}", error)))?;
+    Ok(bytes)
+}
+
+/// Decodes data that was encoded by `encode_geo_data`.
+pub fn decode_geo_data(bytes: &[u8]) -> Result<GeoData, AppError> {
+    if !is_city_bin(bytes) || bytes.len() < HEADER_LENGTH {
+        return Err(syntax_error("the data does not start with the saved data header".to_owned()));
+    }
+    let mut version = [0; 4];
+    version.copy_from_slice(&bytes[MAGIC.len()..HEADER_LENGTH]);
+    let version = u32::from_le_bytes(version);
+    if version != VERSION {
+        return Err(syntax_error(format!(
+            "the data was saved in version {} of the format, but only version {} can be loaded; save it again from the original file",
+            version, VERSION,
+        )));
+    }
+    bincode::deserialize(&bytes[HEADER_LENGTH..])
+        .map_err(|error| syntax_error(format!("could not decode the data: {}", error)))
+}
+
+fn syntax_error(message: String) -> AppError {
+    AppError::DataSyntax {
+        format: DataFormat::CityBin,
+        line: None,
+        character: None,
+        message,
+    }
+}
+
+/// An event that saves the data of the world to a `.citybin` file at the
+/// given path.
+#[derive(Clone, Debug, Event)]
+pub struct SaveGeoDataEvent {
+    pub path: PathBuf,
+}
+
+/// The result of a save task: the path and size of the written file.
+pub struct GeoDataSave(Result<(PathBuf, usize), AppError>);
+
+/// A system that starts a task for every `SaveGeoDataEvent`, which merges
+/// the data of all loads in the world and writes it to the file.
+pub fn start_geo_data_save(
+    mut commands: Commands,
+    mut save_events: EventReader<SaveGeoDataEvent>,
+    loaded_geo_data: Res<LoadedGeoData>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let Some(event) = save_events.read().last().cloned() else {
+        return;
+    };
+    if loaded_geo_data.chunk_count() == 0 {
+        status_events.send(StatusEvent::Error(AppError::MissingData {
+            message: "load data before saving it".to_owned(),
+        }));
+        return;
+    }
+
+    status_events.send(StatusEvent::progress_step("Saving the loaded data"));
+    // the data is shared with the world, so only the handles are copied here
+    let loaded_geo_data = loaded_geo_data.clone();
+    let path = event.path;
+    spawn_compute_task(&mut commands, async move {
+        GeoDataSave(save_geo_data(&loaded_geo_data.to_geo_data(), &path).map(|size| (path, size)))
+    });
+}
+
+/// Writes data to a `.citybin` file, and returns the size of the file.
+pub fn save_geo_data(data: &GeoData, path: &Path) -> Result<usize, AppError> {
+    let bytes = encode_geo_data(data)?;
+    std::fs::write(path, &bytes).map_err(|error| AppError::from_io_error(error, path))?;
+    Ok(bytes.len())
+}
+
+/// A system that reports the result of saving the data.
+pub fn update_geo_data_save(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<GeoDataSave>)>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    handle_compute_tasks(&mut commands, query, |_, GeoDataSave(result)| {
+        status_events.send(StatusEvent::progress_finished());
+        match result {
+            Ok((path, size)) => {
+                status_events.send(StatusEvent::Update(format!(
+                    "Saved the loaded data to {} ({:.1} MB)",
+                    path.display(),
+                    size as f64 / 1_000_000.0,
+                )));
+            }
+            Err(error) => {
+                status_events.send(StatusEvent::Error(error));
+            }
+        }
+    });
+}
+
+/// The file that was chosen in the save dialog, or `None` if the dialog was
+/// cancelled.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct GeoDataSaveTarget(Option<PathBuf>);
+
+/// Opens a dialog to choose where to save the data to, without blocking the
+/// frame. The chosen file is handled by `update_geo_data_save_dialog`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn_geo_data_save_dialog(commands: &mut Commands) {
+    let dialog = rfd::AsyncFileDialog::new()
+        .set_title("Save the loaded data")
+        .add_filter("Saved city data", &["citybin"])
+        .set_file_name("city.citybin");
+    spawn_compute_task(commands, async move {
+        GeoDataSaveTarget(dialog.save_file().await.map(|file| file.path().to_owned()))
+    });
+}
+
+/// A system that saves the data to the file that was chosen in the save
+/// dialog.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn update_geo_data_save_dialog(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<GeoDataSaveTarget>)>,
+    mut save_events: EventWriter<SaveGeoDataEvent>,
+) {
+    handle_compute_tasks(&mut commands, query, |_, GeoDataSaveTarget(path)| {
+        if let Some(path) = path {
+            save_events.send(SaveGeoDataEvent { path });
+        }
+    });
+}
+
+/// A system that registers the command that saves the data.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn register_city_bin_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Save loaded data",
+        "Saves the data of the world as a .citybin file, which loads much faster than the original file",
+        None,
+        |commands| spawn_geo_data_save_dialog(commands),
+    );
+}
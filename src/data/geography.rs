@@ -5,22 +5,138 @@
 //! external APIs or things like that.
 
 use crate::common::{DataFormat, AppError};
-use crate::earth::GLOBAL_SCALE_FACTOR;
+use crate::data::levels::validate_building_tags;
 use wasm_bindgen::prelude::*;
 
 use bevy::ecs::system::Resource;
 use bevy::math::Vec2;
 
+use serde::{Deserialize, Serialize};
 use serde_json::{Map, Number, Value as JsonValue};
 
 use std::collections::hash_map::HashMap;
 use std::f64::consts::PI;
+use std::time::Duration;
 
 /// A collection of geographic data.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct GeoData {
     pub node_locations: HashMap<u64, GeoLocation>,
     pub chunks: HashMap<ChunkIndex, Chunk>,
+    /// Administrative areas such as city districts, by relation id. They are
+    /// not split into chunks, since they usually span many of them.
+    pub districts: HashMap<u64, DistrictFeature>,
+    /// When the OSM database was last updated before this data was exported,
+    /// as given by Overpass (e.g. "2024-03-20T12:34:56Z").
+    pub snapshot_timestamp: Option<String>,
+    /// How old the data is, if it was loaded from a local cache instead of
+    /// being freshly downloaded.
+    pub cache_age: Option<Duration>,
+    /// What had to be skipped while converting the data.
+    pub report: ParseReport,
+}
+
+/// Problems in the input data that were worked around during conversion, and
+/// how much of the data was converted.
+///
+/// ```
+/// use city_visualizer::data::geography::{FeatureType, GeoDataBuilder};
+///
+/// let mut builder = GeoDataBuilder::new();
+/// builder
+///     .add_node(1, 51.4416, 5.4697)
+///     .add_node(2, 51.4416, 5.4699)
+///     .add_road(10, vec![1, 2], "residential", [("name", "Main Street")])
+///     .add_way(11, vec![1, 2], [("fence_type", "wood")])
+///     .skip_relation();
+/// let report = builder.build().report;
+///
+/// assert_eq!(report.nodes, 2);
+/// assert_eq!(report.ways, 2);
+/// assert_eq!(report.feature_ways[&FeatureType::Road], 1);
+/// assert_eq!(report.skipped_relations, 1);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ParseReport {
+    /// The number of references from features to nodes that are not in the
+    /// data. These nodes are skipped.
+    pub missing_nodes: usize,
+    /// The number of features that were dropped because fewer than two thirds
+    /// of their nodes are in the data, see `has_enough_nodes`.
+    pub dropped_features: usize,
+    /// Tag values that were out of range and clamped, such as a building with
+    /// 999 levels.
+    pub warnings: Vec<String>,
+    /// The ids of buildings, land uses and lakes that were skipped because
+    /// they exceed the `FeatureLimits`, such as the boundary of a national
+    /// forest.
+    pub oversized_features: Vec<u64>,
+    /// How long converting the data took.
+    pub parse_time: Duration,
+    /// The number of nodes with a location in the data.
+    pub nodes: usize,
+    /// The number of ways in the data, including those that are not a
+    /// feature.
+    pub ways: usize,
+    /// The number of ways that became a feature, per type of feature.
+    pub feature_ways: HashMap<FeatureType, usize>,
+    /// The number of relations that were skipped, which are all relations
+    /// except administrative boundaries.
+    pub skipped_relations: usize,
+}
+
+/// Sanity limits for single area features. Features that exceed them are
+/// skipped, since generating them takes seconds and covers the whole world.
+///
+/// ```
+/// use city_visualizer::data::geography::GeoDataBuilder;
+///
+/// let mut builder = GeoDataBuilder::new();
+/// builder
+///     // a forest of about 100 by 100 km
+///     .add_node(1, 51.0, 5.0)
+///     .add_node(2, 51.0, 6.4)
+///     .add_node(3, 51.9, 6.4)
+///     .add_node(4, 51.9, 5.0)
+///     .add_way(20, vec![1, 2, 3, 4, 1], [("landuse", "forest")])
+///     // a small building
+///     .add_node(5, 51.4416, 5.4697)
+///     .add_node(6, 51.4416, 5.4699)
+///     .add_node(7, 51.4418, 5.4699)
+///     .add_building(10, vec![5, 6, 7, 5], [("building", "yes")]);
+/// let data = builder.build();
+///
+/// assert_eq!(data.report.oversized_features, vec![20]);
+/// let chunk = data.chunks.values().find(|chunk| !chunk.building_features.is_empty()).unwrap();
+/// assert!(chunk.building_features.contains_key(&10));
+/// assert!(data.chunks.values().all(|chunk| chunk.land_use_features.is_empty()));
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct FeatureLimits {
+    /// The maximum width and height of the bounding box of a feature, as a
+    /// multiple of the chunk size.
+    pub max_extent_chunks: f32,
+    /// The maximum number of nodes of a feature. This is checked before the
+    /// outline is simplified, so it is generous.
+    pub max_nodes: usize,
+}
+
+impl Default for FeatureLimits {
+    fn default() -> Self {
+        FeatureLimits {
+            max_extent_chunks: 8.0,
+            max_nodes: 20_000,
+        }
+    }
+}
+
+impl FeatureLimits {
+    /// Returns whether a feature with the given bounding box, in chunks (see
+    /// `chunk_coordinates`), and number of nodes exceeds the limits.
+    pub fn is_exceeded(&self, min: Vec2, max: Vec2, nodes: usize) -> bool {
+        let size = max - min;
+        size.x > self.max_extent_chunks || size.y > self.max_extent_chunks || nodes > self.max_nodes
+    }
 }
 
 impl GeoData {
@@ -32,7 +148,7 @@ impl GeoData {
 }
 
 /// The nodes and features that lie within a chunk.
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Chunk {
     pub nodes: HashMap<u64, GeoNode>,
     pub building_features: HashMap<u64, BuildingFeature>,
@@ -43,28 +159,118 @@ pub struct Chunk {
 }
 
 /// An identifier/index for a chunk.
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub struct ChunkIndex {
     pub x: i64,
     pub z: i64,
 }
 
 impl ChunkIndex {
-    /// Returns the index of the chunk that the given 2D world coordinates lie
-    /// inside of.
-    pub fn from_vec2(coords: Vec2) -> Self {
+    /// Returns the index of the chunk that the given location lies inside
+    /// of. Chunks form a fixed grid over the whole earth, so data always ends
+    /// up in the same chunks, whatever the offset of the world is.
+    pub fn from_location(location: &GeoLocation) -> Self {
+        let (x, y) = location.project_no_scale();
+        ChunkIndex {
+            x: (x * CHUNKS_AROUND_EARTH).floor() as i64,
+            z: (y * CHUNKS_AROUND_EARTH).floor() as i64,
+        }
+    }
+
+    /// Returns the index of the chunk that the given position in the world
+    /// lies inside of, where the world is centered around `offset`.
+    pub fn from_world(position: Vec2, offset: &Offset) -> Self {
+        let scale = offset.units_per_projected();
         ChunkIndex {
-            x: (coords.x / CHUNK_SIZE).floor() as i64,
-            z: (coords.y / CHUNK_SIZE).floor() as i64,
+            x: ((position.x as f64 / scale + offset.x) * CHUNKS_AROUND_EARTH).floor() as i64,
+            z: ((position.y as f64 / scale + offset.y) * CHUNKS_AROUND_EARTH).floor() as i64,
+        }
+    }
+}
+
+/// The number of chunks around the earth, along both axes of the Web Mercator
+/// projection. Chunks are about 5 km wide at the equator, and narrower
+/// further away from it.
+const CHUNKS_AROUND_EARTH: f64 = 8000.0;
+
+/// Returns the position of a location in the grid of chunks, where every
+/// chunk is one by one.
+fn chunk_coordinates(location: &GeoLocation) -> Vec2 {
+    let (x, y) = location.project_no_scale();
+    Vec2::new((x * CHUNKS_AROUND_EARTH) as f32, (y * CHUNKS_AROUND_EARTH) as f32)
+}
+
+/// The scale of the world, in world units per meter.
+///
+/// The scale is set when the app starts and does not change afterwards. All
+/// lengths in the world scale along with it, from the projection of the data
+/// to the speed of the player and the distances at which meshes are hidden,
+/// so the world looks and handles the same at every scale. Lengths in the code
+/// are given in world units at the default scale and converted with `units`.
+/// Only the tiny heights that keep flat layers such as water, roads and
+/// overlays apart stay the same.
+#[derive(Clone, Copy, Debug, PartialEq, Resource)]
+pub struct WorldScale {
+    pub units_per_meter: f32,
+}
+
+/// The scale of the world when none is given.
+const DEFAULT_UNITS_PER_METER: f32 = 0.25;
+
+impl Default for WorldScale {
+    fn default() -> Self {
+        WorldScale {
+            units_per_meter: DEFAULT_UNITS_PER_METER,
         }
     }
 }
-const CHUNK_SIZE: f32 = 8.0 * GLOBAL_SCALE_FACTOR as f32;
 
+impl WorldScale {
+    /// Converts a length in world units at the default scale to world units
+    /// at this scale.
+    ///
+    /// ```
+    /// use city_visualizer::data::geography::WorldScale;
+    ///
+    /// let meters = WorldScale { units_per_meter: 1.0 };
+    /// assert_eq!(meters.units(2.0), 8.0);
+    /// assert_eq!(meters.area(2.0), 32.0);
+    /// assert_eq!(meters.meters(3.0), 3.0);
+    /// assert_eq!(WorldScale::default().units(2.0), 2.0);
+    /// ```
+    pub fn units(&self, length: f32) -> f32 {
+        length * self.factor()
+    }
+
+    /// Converts an area in square world units at the default scale to square
+    /// world units at this scale.
+    pub fn area(&self, area: f32) -> f32 {
+        area * self.factor() * self.factor()
+    }
+
+    /// Converts a length in meters to world units.
+    pub fn meters(&self, meters: f32) -> f32 {
+        meters * self.units_per_meter
+    }
+
+    /// How many times larger this scale is than the default.
+    fn factor(&self) -> f32 {
+        self.units_per_meter / DEFAULT_UNITS_PER_METER
+    }
+}
+
+/// The origin of the world, in the coordinates of `project_no_scale`, the
+/// latitude at which distances in the world are true, and the scale of the
+/// world.
 #[derive(Clone, Debug, Copy, Resource)]
 pub struct Offset {
     pub x: f64,
     pub y: f64,
+    /// In degrees. This is the latitude of the origin when the first data was
+    /// loaded, and it stays the same when the origin is shifted, so the scale
+    /// of the world does not change.
+    pub latitude: f64,
+    pub scale: WorldScale,
 }
 
 impl Default for Offset {
@@ -72,12 +278,119 @@ impl Default for Offset {
         Offset {
             x: f64::NEG_INFINITY,
             y: f64::NEG_INFINITY,
+            latitude: 0.0,
+            scale: WorldScale::default(),
+        }
+    }
+}
+
+impl Offset {
+    /// Returns the offset that puts `location` at the origin of the world,
+    /// with distances that are true at its latitude.
+    pub fn centered_on(location: &GeoLocation, scale: WorldScale) -> Offset {
+        let (x, y) = location.project_no_scale();
+        Offset {
+            x,
+            y,
+            latitude: location.latitude,
+            scale,
+        }
+    }
+
+    /// Returns how many world units one unit of `project_no_scale` is. Web
+    /// Mercator stretches distances by 1 / cos(latitude), which is undone at
+    /// the reference latitude.
+    fn units_per_projected(&self) -> f64 {
+        EARTH_CIRCUMFERENCE
+            * (self.latitude / 180.0 * PI).cos()
+            * self.scale.units_per_meter as f64
+    }
+
+    /// Returns the offset after moving the origin of the world by `shift`, in
+    /// world units. Positions in the world have to be moved by `-shift` to
+    /// stay at the same location.
+    ///
+    /// The offset is kept in `f64`, so shifting the origin many times does not
+    /// lose precision, unlike the `f32` positions in the world.
+    ///
+    /// ```
+    /// use bevy::math::Vec2;
+    /// use city_visualizer::data::geography::{GeoLocation, Offset, WorldScale};
+    ///
+    /// // two points about 10 km apart
+    /// let a = GeoLocation { longitude: 5.4697, latitude: 51.4416 };
+    /// let b = GeoLocation { longitude: 5.6134, latitude: 51.4416 };
+    /// let mut offset = Offset::centered_on(&a, WorldScale::default());
+    /// let (mut position_a, mut position_b) = (a.project(&offset), b.project(&offset));
+    ///
+    /// for shift in [Vec2::new(1000.0, 0.0), Vec2::new(1200.0, -700.0), Vec2::new(-300.0, 950.5)] {
+    ///     offset = offset.shifted(shift);
+    ///     position_a -= shift;
+    ///     position_b -= shift;
+    /// }
+    ///
+    /// // the points are still where they are projected after the shifts, and
+    /// // their distance is off by less than a centimeter
+    /// let meters_per_unit = a.meters_per_unit(&offset) as f32;
+    /// assert!((position_a - a.project(&offset)).length() * meters_per_unit < 0.01);
+    /// assert!((position_b - b.project(&offset)).length() * meters_per_unit < 0.01);
+    /// let error = (position_b - position_a).length() - (b.project(&offset) - a.project(&offset)).length();
+    /// assert!(error.abs() * meters_per_unit < 0.01);
+    /// ```
+    pub fn shifted(&self, shift: Vec2) -> Offset {
+        let scale = self.units_per_projected();
+        Offset {
+            x: self.x + shift.x as f64 / scale,
+            y: self.y + shift.y as f64 / scale,
+            latitude: self.latitude,
+            scale: self.scale,
+        }
+    }
+}
+
+/// The (projected) area covered by all geographic data currently loaded into
+/// the world.
+#[derive(Clone, Copy, Debug, Resource)]
+pub struct LoadedBounds {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl Default for LoadedBounds {
+    fn default() -> Self {
+        LoadedBounds {
+            min: Vec2::INFINITY,
+            max: Vec2::NEG_INFINITY,
         }
     }
 }
 
+impl LoadedBounds {
+    /// Grows the bounds so that they also cover the rectangle spanned by the
+    /// two given corners.
+    pub fn extend(&mut self, corner1: Vec2, corner2: Vec2) {
+        self.min = self.min.min(corner1.min(corner2));
+        self.max = self.max.max(corner1.max(corner2));
+    }
+
+    /// Moves the bounds by `-shift`, along with the world when its origin is
+    /// moved by `shift`.
+    pub fn shift(&mut self, shift: Vec2) {
+        self.min -= shift;
+        self.max -= shift;
+    }
+
+    /// Returns whether `point` lies within `margin` of the edge of the bounds.
+    pub fn is_near_boundary(&self, point: Vec2, margin: f32) -> bool {
+        point.x - self.min.x < margin
+            || self.max.x - point.x < margin
+            || point.y - self.min.y < margin
+            || self.max.y - point.y < margin
+    }
+}
+
 /// A single point on the surface of the earth.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct GeoLocation {
     /// West to east.
     pub longitude: f64,
@@ -85,75 +398,227 @@ pub struct GeoLocation {
     pub latitude: f64,
 }
 
-const LATITUDAL_SCALE_FACTOR: f64 = 64000.0 * (GLOBAL_SCALE_FACTOR as f64);
-const LONGITUDAL_SCALE_FACTOR: f64 = 64000.0 * (GLOBAL_SCALE_FACTOR as f64);
+/// The circumference of the earth at the equator, in meters.
+const EARTH_CIRCUMFERENCE: f64 = 40_075_016.686;
 
 impl GeoLocation {
-    /// Convert from geographic coordinates to XZ coordinates on a plane.
-    /// 
-    /// One longitudal degree is approximately 110.6 km at the Equator at sea
-    /// level, but this strongly varies depending on the location on earth
-    /// (hence a rather complicated calculation is required). One latitudal
-    /// degree is approximately 111.3 km at the Equator at sea level.
+    /// Converts from geographic coordinates to XZ coordinates on the plane of
+    /// the world, where the offset is at the origin.
+    ///
+    /// This is the Web Mercator projection, scaled so that distances are true
+    /// at the reference latitude of the offset. The projection does not
+    /// stretch shapes, and its scale changes slowly with the latitude: within
+    /// 10 km of the reference latitude, distances are off by less than 0.5%
+    /// up to 70 degrees north or south.
+    ///
+    /// ```
+    /// use city_visualizer::data::geography::{GeoLocation, Offset, WorldScale};
+    ///
+    /// // the great-circle distance in meters
+    /// fn haversine(a: &GeoLocation, b: &GeoLocation) -> f64 {
+    ///     let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    ///     let d_lat = lat_b - lat_a;
+    ///     let d_lon = (b.longitude - a.longitude).to_radians();
+    ///     let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    ///     2.0 * 6_371_008.8 * h.sqrt().asin()
+    /// }
+    ///
+    /// // Nairobi, Eindhoven, Helsinki and Tromsø, with points 5 km around them
+    /// for (latitude, longitude) in [(-1.29, 36.82), (51.44, 5.47), (60.17, 24.94), (69.65, 18.96)] {
+    ///     let center = GeoLocation { longitude, latitude };
+    ///     let offset = Offset::centered_on(&center, WorldScale { units_per_meter: 1.0 });
+    ///     let d_lat = 5000.0 / 111_195.0;
+    ///     let d_lon = d_lat / latitude.to_radians().cos();
+    ///     let points = [(-d_lat, -d_lon), (d_lat, d_lon), (-d_lat, d_lon), (d_lat, -d_lon), (0.0, d_lon), (d_lat, 0.0)]
+    ///         .map(|(d_lat, d_lon)| GeoLocation { longitude: longitude + d_lon, latitude: latitude + d_lat });
+    ///     for a in &points {
+    ///         for b in points.iter().chain([&center]) {
+    ///             let expected = haversine(a, b);
+    ///             if expected == 0.0 {
+    ///                 continue;
+    ///             }
+    ///             let projected = (a.project(&offset) - b.project(&offset)).length();
+    ///             let error = (projected as f64 - expected).abs() / expected;
+    ///             assert!(error < 0.01, "{:.2}% off at latitude {}", error * 100.0, latitude);
+    ///         }
+    ///     }
+    /// }
+    /// ```
     pub fn project(&self, offset: &Offset) -> Vec2 {
-        // not entirely sure if this is the best projection function
-        let x = ((self.longitude + 180.0) / 360.0 - offset.x)* LONGITUDAL_SCALE_FACTOR;
-        let lat_radians = (self.latitude) / 180.0 * PI;
-        let y = ((1.0 - lat_radians.tan().asinh() / PI) / 2.0 - offset.y)* LATITUDAL_SCALE_FACTOR;
-        Vec2::new(x as f32, y as f32)
+        let (x, y) = self.project_no_scale();
+        let scale = offset.units_per_projected();
+        Vec2::new(((x - offset.x) * scale) as f32, ((y - offset.y) * scale) as f32)
+    }
+
+    /// The inverse of `project`: converts XZ coordinates on the plane back to
+    /// geographic coordinates.
+    ///
+    /// ```
+    /// use bevy::math::Vec2;
+    /// use city_visualizer::data::geography::{GeoLocation, Offset, WorldScale};
+    ///
+    /// let eindhoven = GeoLocation { longitude: 5.4697, latitude: 51.4416 };
+    /// let offset = Offset::centered_on(&eindhoven, WorldScale::default());
+    ///
+    /// for location in [
+    ///     eindhoven.clone(),
+    ///     GeoLocation { longitude: 5.48, latitude: 51.43 },
+    ///     GeoLocation { longitude: 5.45, latitude: 51.46 },
+    /// ] {
+    ///     let result = GeoLocation::unproject(location.project(&offset), &offset);
+    ///     assert!((result.longitude - location.longitude).abs() < 1e-6);
+    ///     assert!((result.latitude - location.latitude).abs() < 1e-6);
+    /// }
+    ///
+    /// // the offset is the origin of the plane
+    /// let origin = GeoLocation::unproject(Vec2::ZERO, &offset);
+    /// assert!((origin.longitude - eindhoven.longitude).abs() < 1e-9);
+    /// assert!((origin.latitude - eindhoven.latitude).abs() < 1e-9);
+    /// ```
+    pub fn unproject(position: Vec2, offset: &Offset) -> GeoLocation {
+        let scale = offset.units_per_projected();
+        let x = position.x as f64 / scale + offset.x;
+        let y = position.y as f64 / scale + offset.y;
+        let lat_radians = (PI * (1.0 - 2.0 * y)).sinh().atan();
+        GeoLocation {
+            longitude: x * 360.0 - 180.0,
+            latitude: lat_radians / PI * 180.0,
+        }
+    }
+
+    /// Returns how many meters one world unit is at this location, in a world
+    /// with the given offset. This is one over the units per meter of the
+    /// scale at the reference latitude of the offset, and slightly differs
+    /// away from it.
+    pub fn meters_per_unit(&self, offset: &Offset) -> f64 {
+        (self.latitude / 180.0 * PI).cos()
+            / ((offset.latitude / 180.0 * PI).cos() * offset.scale.units_per_meter as f64)
     }
 
-    /// Perform the same projection as `project`, but without scaling the result.
-    /// This is to calculate the enables accurate relative positioning of points for recentering
+    /// Returns the coordinates of the location in the Web Mercator
+    /// projection, normalized to lie between 0 and 1 over the whole earth.
+    /// Unlike `project`, these do not depend on the offset, and are precise
+    /// enough to compare locations anywhere on earth.
     pub fn project_no_scale(&self) -> (f64, f64) {
-        // not entirely sure if this is the best projection function
         let x = (self.longitude + 180.0) / 360.0;
         let lat_radians = (self.latitude) / 180.0 * PI;
         let y = (1.0 - lat_radians.tan().asinh() / PI) / 2.0;
-        (x as f64, y as f64)
+        (x, y)
     }
 }
 
 /// A single point on earth that carries some associated information.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct GeoNode {
     pub tags: HashMap<String, String>,
 }
 
 /// A map feature that models a building.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct BuildingFeature {
     pub nodes: Vec<u64>,
     pub tags: HashMap<String, String>,
 }
 
 /// A map feature that models a road.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct RoadFeature {
     pub nodes: Vec<u64>,
     pub tags: HashMap<String, String>,
 }
 
 /// A map feature that models the land use of an area.
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct LandUseFeature {
     pub nodes: Vec<u64>,
     pub tags: HashMap<String, String>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct LakeFeature {
     pub nodes: Vec<u64>,
     pub tags: HashMap<String, String>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct RiverFeature {
     pub nodes: Vec<u64>,
     pub tags: HashMap<String, String>,
 }
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// An administrative area, such as a district of a city.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DistrictFeature {
+    /// The outer rings of the area, which are closed implicitly. Holes are
+    /// ignored.
+    pub rings: Vec<Vec<u64>>,
+    pub tags: HashMap<String, String>,
+}
+
+impl DistrictFeature {
+    /// Returns the name of the district, or its id if it has no name.
+    pub fn name(&self, id: u64) -> String {
+        match self.tags.get("name") {
+            Some(name) => name.clone(),
+            None => format!("District {}", id),
+        }
+    }
+}
+
+/// Normalizes the nodes of a closed way into a ring where every vertex
+/// appears exactly once.
+///
+/// OSM closed ways repeat the first node as the last node. Polygon-producing
+/// code closes its rings implicitly, so the duplicate is stripped here; ways
+/// that were not closed in the data are treated as if they were.
+///
+/// ```
+/// use city_visualizer::data::geography::close_ring;
+///
+/// for way in [vec![1, 2, 3, 4], vec![1, 2, 3, 4, 1], vec![1, 2, 3, 4, 1, 1, 1]] {
+///     assert_eq!(close_ring(&way), vec![1, 2, 3, 4]);
+/// }
+/// ```
+pub fn close_ring(nodes: &[u64]) -> Vec<u64> {
+    let mut ring = nodes.to_vec();
+    while ring.len() > 1 && ring.first() == ring.last() {
+        ring.pop();
+    }
+    ring
+}
+
+/// Returns whether enough of the nodes of a feature are in the data to keep
+/// the feature without the missing nodes: at least two thirds of them. A
+/// feature with fewer nodes would look too different from what it should be.
+///
+/// ```
+/// use city_visualizer::data::geography::has_enough_nodes;
+///
+/// assert!(has_enough_nodes(49, 50));
+/// assert!(has_enough_nodes(2, 3));
+/// assert!(!has_enough_nodes(1, 3));
+/// assert!(!has_enough_nodes(0, 0));
+/// ```
+pub fn has_enough_nodes(resolved: usize, total: usize) -> bool {
+    resolved > 0 && resolved * 3 >= total * 2
+}
+
+/// Projects the locations of the given nodes, skipping nodes that are not in
+/// the data, so that a single missing node does not drop a whole feature.
+/// Returns `None` if too many nodes are missing, see `has_enough_nodes`.
+pub fn project_nodes(
+    node_locations: &HashMap<u64, GeoLocation>,
+    nodes: &[u64],
+    offset: &Offset,
+) -> Option<Vec<Vec2>> {
+    let points: Vec<Vec2> = nodes
+        .iter()
+        .filter_map(|node_id| node_locations.get(node_id).map(|node| node.project(offset)))
+        .collect();
+    has_enough_nodes(points.len(), nodes.len()).then_some(points)
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
 pub enum FeatureType {
     Building,
     Road,
@@ -162,11 +627,34 @@ pub enum FeatureType {
     River
 }
 
+impl FeatureType {
+    pub const ALL: [FeatureType; 5] = [
+        FeatureType::Building,
+        FeatureType::Road,
+        FeatureType::LandUse,
+        FeatureType::Lake,
+        FeatureType::River,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FeatureType::Building => "Buildings",
+            FeatureType::Road => "Roads",
+            FeatureType::LandUse => "Land uses",
+            FeatureType::Lake => "Lakes",
+            FeatureType::River => "Rivers",
+        }
+    }
+}
+
 /// Converts a Serde JSON value to the internal `GeoData` data structure.
 /// 
 /// The locations of nodes that were in the JSON data but not in
 /// `node_locations` yet will be added to this map. Nodes will also be added
 /// to the `nodes` field on the result iff they have any tags.
+///
+/// All elements are converted at once, see `OsmJsonConverter` to convert
+/// them in batches.
 /// 
 /// # See also
 /// [OSM JSON format], [Overpass JSON format] (almost the same)
@@ -176,161 +664,504 @@ pub enum FeatureType {
 pub fn convert_osm_json(
     json: JsonValue,
 ) -> Result<GeoData, AppError> {
-    // good example: https://api.openstreetmap.org/api/0.6/relation/10000000/full.json
-    let root_object = match json {
-        JsonValue::Object(object) => object,
-        _ => return error("OSM JSON root must be an object"),
-    };
-
-    let elements = match root_object.get("elements") {
-        Some(JsonValue::Array(array)) => array,
-        _ => return error(
-            "OSM JSON root needs to have an `elements` key that is an array",
-        ),
-    };
+    let mut converter = OsmJsonConverter::new(json)?;
+    converter.convert(usize::MAX)?;
+    Ok(converter.finish())
+}
 
-    // we do two passes: one purely for node locations and one for other
-    // features, because we need to find the chunk that a feature lies in by
-    // the location of its nodes
-    let mut node_locations = HashMap::new();
+/// Converts OSM JSON to `GeoData` a batch of elements at a time, so that a
+/// large response can be converted over several frames instead of stalling
+/// one of them. The result is the same as that of `convert_osm_json`.
+///
+/// ```
+/// use city_visualizer::data::geography::{convert_osm_json, OsmJsonConverter};
+/// use serde_json::json;
+///
+/// // three buildings with 4 nodes each
+/// let mut elements = Vec::new();
+/// for building in 0..3u64 {
+///     for corner in 0..4u64 {
+///         elements.push(json!({
+///             "type": "node",
+///             "id": building * 4 + corner,
+///             "lat": 51.4 + (corner / 2) as f64 * 0.0001,
+///             "lon": 5.4 + building as f64 * 0.0002 + (corner % 2) as f64 * 0.0001,
+///         }));
+///     }
+///     let first = building * 4;
+///     elements.push(json!({
+///         "type": "way",
+///         "id": building,
+///         "nodes": [first, first + 1, first + 3, first + 2, first],
+///         "tags": { "building": "yes" },
+///     }));
+/// }
+/// let document = json!({
+///     "osm3s": { "timestamp_osm_base": "2024-03-20T12:34:56Z" },
+///     "elements": elements,
+/// });
+///
+/// let mut converter = OsmJsonConverter::new(document.clone()).unwrap();
+/// assert_eq!(converter.total(), 15);
+/// let mut batches = 0;
+/// while !converter.convert(4).unwrap() {
+///     batches += 1;
+///     assert_eq!(converter.converted(), batches * 4);
+/// }
+/// assert_eq!(batches, 3);
+/// assert_eq!(converter.finish(), convert_osm_json(document).unwrap());
+/// ```
+#[derive(Debug, Default)]
+pub struct OsmJsonConverter {
+    elements: std::vec::IntoIter<JsonValue>,
+    total: usize,
+    snapshot_timestamp: Option<String>,
+    // the builder assigns features to chunks once all nodes are known, since
+    // the chunk that a feature lies in depends on the locations of its nodes
+    builder: GeoDataBuilder,
+}
 
-    for element in elements {
-        let element_object = match element {
+impl OsmJsonConverter {
+    /// Prepares the conversion of `json`, which must be an object with an
+    /// array of elements. The elements themselves are only checked once they
+    /// are converted.
+    pub fn new(json: JsonValue) -> Result<Self, AppError> {
+        // good example: https://api.openstreetmap.org/api/0.6/relation/10000000/full.json
+        let mut root_object = match json {
             JsonValue::Object(object) => object,
+            _ => return error("OSM JSON root must be an object"),
+        };
+
+        // Overpass adds the time of its database snapshot to the output
+        let snapshot_timestamp = root_object
+            .get("osm3s")
+            .and_then(|osm3s| osm3s.get("timestamp_osm_base"))
+            .and_then(|timestamp| timestamp.as_str())
+            .map(|timestamp| timestamp.to_owned());
+
+        let elements = match root_object.remove("elements") {
+            Some(JsonValue::Array(array)) => array,
             _ => return error(
-                "an element in the `elements` array must be an object",
+                "OSM JSON root needs to have an `elements` key that is an array",
             ),
         };
 
-        if get_element_type(element_object)? == "node" {
-            let id = get_id(element_object)?;
+        Ok(OsmJsonConverter {
+            total: elements.len(),
+            elements: elements.into_iter(),
+            snapshot_timestamp,
+            builder: GeoDataBuilder::new(),
+        })
+    }
+
+    /// The number of elements in the data.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// The number of elements that have been converted so far.
+    pub fn converted(&self) -> usize {
+        self.total - self.elements.len()
+    }
+
+    /// Converts at most `max_elements` of the remaining elements, and returns
+    /// whether all elements have been converted.
+    pub fn convert(&mut self, max_elements: usize) -> Result<bool, AppError> {
+        for element in self.elements.by_ref().take(max_elements) {
+            convert_element(&mut self.builder, element)?;
+        }
+        Ok(self.elements.len() == 0)
+    }
+
+    /// Assigns the converted elements to chunks and returns the result.
+    /// Elements that were not converted yet are left out.
+    pub fn finish(self) -> GeoData {
+        GeoData {
+            snapshot_timestamp: self.snapshot_timestamp,
+            ..self.builder.build()
+        }
+    }
+}
+
+/// Adds a single element of OSM JSON to `builder`.
+fn convert_element(builder: &mut GeoDataBuilder, element: JsonValue) -> Result<(), AppError> {
+    let element_object = match element {
+        JsonValue::Object(object) => object,
+        _ => return error(
+            "an element in the `elements` array must be an object",
+        ),
+    };
+
+    let element_type = get_element_type(&element_object)?;
+    let id = get_id(&element_object)?;
+    let tags = get_tags(&element_object)?;
 
+    // the "type"s that exist and their formats:
+    // "type": "node", "id": num, [ "lon": num, "lat": num, "tags": <...> ]
+    // "type": "way", "id": num, [ "tags": obj, ] "nodes": array
+    // "type": "relation", "id": num, "members": array, [ "tags": obj ]
+    // tags that seem interesting: landuse, highway, religion, historic,
+    // addr:*, building:*, name
+    match element_type {
+        "node" => {
             // if a node doesn't have "lon" and "lat", we ignore
             let longitude = match element_object.get("lon") {
-                Some(JsonValue::Number(number)) => truncate_to_f64(number),
-                _ => continue,
+                Some(JsonValue::Number(number)) => Some(truncate_to_f64(number)),
+                _ => None,
             };
             let latitude = match element_object.get("lat") {
-                Some(JsonValue::Number(number)) => truncate_to_f64(number),
-                _ => continue,
+                Some(JsonValue::Number(number)) => Some(truncate_to_f64(number)),
+                _ => None,
             };
-            let location = GeoLocation { longitude, latitude };
-            node_locations.insert(id, location);
-        }
-    }
-
-    let mut chunks = HashMap::new();
-
-    for element in elements {
-        let element_object = element.as_object().unwrap_throw();
-
-        let element_type = get_element_type(element_object)?;
-        let id = get_id(element_object)?;
-        let tags = get_tags(element_object)?;
-
-        // the "type"s that exist and their formats:
-        // "type": "node", "id": num, [ "lon": num, "lat": num, "tags": <...> ]
-        // "type": "way", "id": num, [ "tags": obj, ] "nodes": array
-        // "type": "relation", "id": num, "members": array, [ "tags": obj ]
-        // tags that seem interesting: landuse, highway, religion, historic,
-        // addr:*, building:*, name
-        match element_type {
-            "node" => {
-                if !tags.is_empty() {
-                    let location = match node_locations.get(&id) {
-                        Some(location) => location,
-                        None => return error("node has tags but no location"),
-                    };
-                    let chunk = ChunkIndex::from_vec2(location.project( &Offset { x: 0.0, y: 0.0 }));  // TODO verify if this works once offset is changed
-                    chunks.entry(chunk)
-                        .or_insert(Chunk::default())
-                        .nodes.insert(id, GeoNode { tags });
-                }
-            },
-            "way" => {
-                // confusingly, things like buildings are also "way"s
-                let nodes_field = match element_object.get("nodes") {
+            match (latitude, longitude) {
+                (Some(latitude), Some(longitude)) => {
+                    builder.add_node_with_tags(id, latitude, longitude, tags);
+                },
+                _ if !tags.is_empty() => return error("node has tags but no location"),
+                _ => {},
+            }
+        },
+        "way" => {
+            // confusingly, things like buildings are also "way"s
+            let nodes_field = match element_object.get("nodes") {
+                Some(JsonValue::Array(array)) => array,
+                _ => return error(
+                    "a \"way\" element must have a `nodes` key",
+                ),
+            };
+            let nodes = match parse_u64_array(nodes_field) {
+                Some(nodes) => nodes,
+                None => return error(
+                    "`nodes` array must not contain non-integral values",
+                ),
+            };
+            builder.add_way(id, nodes, tags);
+        },
+        "relation" => {
+            // administrative boundaries are used for statistics per
+            // district, other relations are ignored for now
+            // TODO forest have rings and the rings have nodes this is a relation
+            if tags.get("boundary").map(String::as_str) == Some("administrative") {
+                let members = match element_object.get("members") {
                     Some(JsonValue::Array(array)) => array,
                     _ => return error(
-                        "a \"way\" element must have a `nodes` key",
-                    ),
-                };
-                let nodes = match parse_u64_array(nodes_field) {
-                    Some(nodes) => nodes,
-                    None => return error(
-                        "`nodes` array must not contain non-integral values",
+                        "a \"relation\" element must have a `members` key",
                     ),
                 };
+                builder.add_district(id, get_outer_ways(members), tags);
+            } else {
+                builder.skip_relation();
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+/// Assembles `GeoData` from nodes and ways, assigning every feature to the
+/// chunk that it lies in. The kind of feature (building, road, ...) is
+/// determined by its tags, like in OSM data.
+///
+/// # Example
+/// ```
+/// use city_visualizer::data::geography::GeoDataBuilder;
+///
+/// let mut builder = GeoDataBuilder::new();
+/// builder
+///     .add_node(1, 51.4416, 5.4697)
+///     .add_node(2, 51.4416, 5.4699)
+///     .add_node(3, 51.4418, 5.4699)
+///     .add_node(4, 51.4418, 5.4697)
+///     .add_building(10, vec![1, 2, 3, 4, 1], [("building:levels", "3")])
+///     .add_road(11, vec![1, 4], "residential", [("name", "Main Street")]);
+/// let data = builder.build();
+/// assert_eq!(data.node_locations.len(), 4);
+/// ```
+#[derive(Debug, Default)]
+pub struct GeoDataBuilder {
+    node_locations: HashMap<u64, GeoLocation>,
+    node_tags: Vec<(u64, HashMap<String, String>)>,
+    ways: Vec<(u64, Vec<u64>, HashMap<String, String>)>,
+    /// Districts, with the ids of the ways that form their outline.
+    districts: Vec<(u64, Vec<u64>, HashMap<String, String>)>,
+    skipped_relations: usize,
+    limits: FeatureLimits,
+}
+
+impl GeoDataBuilder {
+    pub fn new() -> Self {
+        GeoDataBuilder::default()
+    }
+
+    /// Sets the limits above which buildings, land uses and lakes are
+    /// skipped.
+    pub fn with_limits(&mut self, limits: FeatureLimits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Adds a node without tags, which can be used by ways.
+    pub fn add_node(&mut self, id: u64, latitude: f64, longitude: f64) -> &mut Self {
+        self.node_locations.insert(id, GeoLocation { longitude, latitude });
+        self
+    }
+
+    /// Adds a node with tags. Nodes are only stored as features if they have
+    /// any tags.
+    pub fn add_node_with_tags<K: Into<String>, V: Into<String>>(
+        &mut self,
+        id: u64,
+        latitude: f64,
+        longitude: f64,
+        tags: impl IntoIterator<Item = (K, V)>,
+    ) -> &mut Self {
+        self.add_node(id, latitude, longitude);
+        let tags = collect_tags(tags);
+        if !tags.is_empty() {
+            self.node_tags.push((id, tags));
+        }
+        self
+    }
+
+    /// Adds a way, which becomes a feature if its tags are recognized. Closed
+    /// ways (areas) repeat their first node at the end.
+    pub fn add_way<K: Into<String>, V: Into<String>>(
+        &mut self,
+        id: u64,
+        nodes: Vec<u64>,
+        tags: impl IntoIterator<Item = (K, V)>,
+    ) -> &mut Self {
+        self.ways.push((id, nodes, collect_tags(tags)));
+        self
+    }
+
+    /// Adds a building with the given outline, which should be closed.
+    pub fn add_building<K: Into<String>, V: Into<String>>(
+        &mut self,
+        id: u64,
+        nodes: Vec<u64>,
+        tags: impl IntoIterator<Item = (K, V)>,
+    ) -> &mut Self {
+        let mut tags = collect_tags(tags);
+        tags.entry("building".to_owned()).or_insert_with(|| "yes".to_owned());
+        self.ways.push((id, nodes, tags));
+        self
+    }
+
+    /// Adds a road of the given highway type, e.g. "residential".
+    pub fn add_road<K: Into<String>, V: Into<String>>(
+        &mut self,
+        id: u64,
+        nodes: Vec<u64>,
+        highway: &str,
+        tags: impl IntoIterator<Item = (K, V)>,
+    ) -> &mut Self {
+        let mut tags = collect_tags(tags);
+        tags.insert("highway".to_owned(), highway.to_owned());
+        self.ways.push((id, nodes, tags));
+        self
+    }
+
+    /// Adds an administrative area, whose outline is formed by the given
+    /// ways. The ways are joined into rings when the data is built.
+    pub fn add_district<K: Into<String>, V: Into<String>>(
+        &mut self,
+        id: u64,
+        outer_ways: Vec<u64>,
+        tags: impl IntoIterator<Item = (K, V)>,
+    ) -> &mut Self {
+        self.districts.push((id, outer_ways, collect_tags(tags)));
+        self
+    }
+
+    /// Records that a relation was not converted, for the `ParseReport`.
+    pub fn skip_relation(&mut self) -> &mut Self {
+        self.skipped_relations += 1;
+        self
+    }
+
+    /// Assigns all nodes and features to chunks and returns the result.
+    pub fn build(self) -> GeoData {
+        let mut chunks = HashMap::new();
+        let mut report = ParseReport {
+            nodes: self.node_locations.len(),
+            ways: self.ways.len(),
+            skipped_relations: self.skipped_relations,
+            ..ParseReport::default()
+        };
+
+        // the ways of districts are usually not features themselves
+        let way_nodes: HashMap<u64, &Vec<u64>> =
+            self.ways.iter().map(|(id, nodes, _)| (*id, nodes)).collect();
+        let mut districts = HashMap::new();
+        for (id, outer_ways, tags) in self.districts {
+            let ways = outer_ways
+                .iter()
+                .filter_map(|way| way_nodes.get(way).map(|nodes| (*nodes).clone()))
+                .collect();
+            let rings = join_rings(ways);
+            if rings.is_empty() {
+                report.dropped_features += 1;
+                continue;
+            }
+            districts.insert(id, DistrictFeature { rings, tags });
+        }
+
+        for (id, tags) in self.node_tags {
+            let location = &self.node_locations[&id];
+            let chunk = ChunkIndex::from_location(location);
+            chunks.entry(chunk)
+                .or_insert(Chunk::default())
+                .nodes.insert(id, GeoNode { tags });
+        }
 
-                if let Some(feature_type) = find_feature_type(&tags) {
-                    // to determine in what chunk a feature lies, we take the
-                    // average of the locations of its nodes
-                    let mut sum_lon = 0.0;
-                    let mut sum_lat = 0.0;
-                    let mut count = 0usize;
-                    for id in &nodes {
-                        if let Some(location) = node_locations.get(id) {
-                            sum_lon += location.longitude;
-                            sum_lat += location.latitude;
-                            count += 1;
-                        }
-                        // we ignore node IDs that are not in the data
-                    }
-
-                    if count == 0 {
-                        continue;
-                    }
-
-                    let avg = GeoLocation {
-                        longitude: sum_lon / count as f64,
-                        latitude: sum_lat / count as f64,
-                    };
-                    let index = ChunkIndex::from_vec2(avg.project(&Offset { x: 0.0, y: 0.0 }));  // TODO verify
-                    let chunk = chunks.entry(index)
-                        .or_insert(Chunk::default());
-                    match feature_type {
-                        FeatureType::Building => {
-                            chunk.building_features
-                                .insert(id, BuildingFeature { nodes, tags });
-                        },
-                        FeatureType::Road => {
-                            chunk.road_features
-                                .insert(id, RoadFeature { nodes, tags });
-                        },
-                        FeatureType::LandUse => {
-                            chunk.land_use_features
-                                .insert(id, LandUseFeature { nodes, tags });
-                        },
-                        FeatureType::Lake => {
-                            chunk.lake_features
-                                .insert(id, LakeFeature { nodes, tags });
-                        },
-                        FeatureType::River => {
-                            chunk.river_features
-                                .insert(id, RiverFeature { nodes, tags });
-                        },
-                    }
+        for (id, nodes, tags) in self.ways {
+            let Some(feature_type) = find_feature_type(&tags) else {
+                continue;
+            };
+
+            // to determine in what chunk a feature lies, we take the
+            // average of the locations of its nodes
+            let mut sum_lon = 0.0;
+            let mut sum_lat = 0.0;
+            let mut count = 0usize;
+            let mut min = Vec2::INFINITY;
+            let mut max = Vec2::NEG_INFINITY;
+            for id in &nodes {
+                if let Some(location) = self.node_locations.get(id) {
+                    sum_lon += location.longitude;
+                    sum_lat += location.latitude;
+                    count += 1;
+                    let point = chunk_coordinates(location);
+                    min = min.min(point);
+                    max = max.max(point);
+                } else {
+                    // node IDs that are not in the data are skipped
+                    // when the feature is created
+                    report.missing_nodes += 1;
                 }
+            }
+
+            if !has_enough_nodes(count, nodes.len()) {
+                report.dropped_features += 1;
+                continue;
+            }
 
-                // Adjusted conditions for lakes and rivers based on new definitions
-                // https://wiki.openstreetmap.org/wiki/Key:water
-                // Remove this if merge successful
-                // && ["pond", "harbour", "lake", "lagoon", "reflecting_pool", "oxbow", "stream"].iter().any(|&value| tags.get("water").map_or(false, |water| water == value))
-                // else if tags.get("natural") == Some(&String::from("water"))  {
-                //     lake_features.insert(id, WaterFeature { nodes, tags: tags.clone() });
-                // } else if ["river", "canal", "ditch", "stream"].iter().any(|&value| tags.get("waterway").map_or(false, |waterway| waterway == value)) {
-                //     river_features.insert(id, WaterFeature { nodes, tags: tags.clone() });
-                // }
-            },
-            "relation" => {
-                // ignore for now
-                // TODO forest have rings and the rings have nodes this is a relation
-            },
-            _ => {},
+            // Roads and rivers are generated per segment, so only areas can
+            // become too large to generate
+            let is_area = matches!(
+                feature_type,
+                FeatureType::Building | FeatureType::LandUse | FeatureType::Lake,
+            );
+            if is_area && self.limits.is_exceeded(min, max, nodes.len()) {
+                report.oversized_features.push(id);
+                continue;
+            }
+
+            let avg = GeoLocation {
+                longitude: sum_lon / count as f64,
+                latitude: sum_lat / count as f64,
+            };
+            let index = ChunkIndex::from_location(&avg);
+            let chunk = chunks.entry(index)
+                .or_insert(Chunk::default());
+            *report.feature_ways.entry(feature_type).or_default() += 1;
+            match feature_type {
+                FeatureType::Building => {
+                    report.warnings.extend(validate_building_tags(id, &tags));
+                    chunk.building_features
+                        .insert(id, BuildingFeature { nodes, tags });
+                },
+                FeatureType::Road => {
+                    chunk.road_features
+                        .insert(id, RoadFeature { nodes, tags });
+                },
+                FeatureType::LandUse => {
+                    chunk.land_use_features
+                        .insert(id, LandUseFeature { nodes, tags });
+                },
+                FeatureType::Lake => {
+                    chunk.lake_features
+                        .insert(id, LakeFeature { nodes, tags });
+                },
+                FeatureType::River => {
+                    chunk.river_features
+                        .insert(id, RiverFeature { nodes, tags });
+                },
+            }
+        }
+
+        GeoData {
+            node_locations: self.node_locations,
+            chunks,
+            districts,
+            snapshot_timestamp: None,
+            cache_age: None,
+            report,
         }
     }
+}
+
+fn collect_tags<K: Into<String>, V: Into<String>>(
+    tags: impl IntoIterator<Item = (K, V)>,
+) -> HashMap<String, String> {
+    tags.into_iter().map(|(key, value)| (key.into(), value.into())).collect()
+}
+
+/// Returns the ids of the ways that form the outline of a relation: the
+/// members with the role "outer", or without a role.
+fn get_outer_ways(members: &[JsonValue]) -> Vec<u64> {
+    members
+        .iter()
+        .filter_map(|member| {
+            let member = member.as_object()?;
+            let role = member.get("role").and_then(JsonValue::as_str).unwrap_or("");
+            if member.get("type")?.as_str()? != "way" || !(role == "outer" || role.is_empty()) {
+                return None;
+            }
+            member.get("ref")?.as_u64()
+        })
+        .collect()
+}
+
+/// Joins ways that share their first or last node into closed rings, without
+/// the duplicated closing node. Ways that do not form a closed ring are
+/// dropped.
+///
+/// ```
+/// use city_visualizer::data::geography::join_rings;
+///
+/// // a square made of two ways, of which one is reversed
+/// let rings = join_rings(vec![vec![1, 2, 3], vec![1, 4, 3]]);
+/// assert_eq!(rings, vec![vec![1, 4, 3, 2]]);
+///
+/// // a closed way is a ring by itself, an open way is dropped
+/// let rings = join_rings(vec![vec![5, 6, 7, 5], vec![8, 9]]);
+/// assert_eq!(rings, vec![vec![5, 6, 7]]);
+/// ```
+pub fn join_rings(mut ways: Vec<Vec<u64>>) -> Vec<Vec<u64>> {
+    let mut rings = Vec::new();
+    while let Some(mut ring) = ways.pop() {
+        while ring.len() > 1 && ring.first() != ring.last() {
+            let end = *ring.last().unwrap_throw();
+            let Some(index) = ways
+                .iter()
+                .position(|way| way.first() == Some(&end) || way.last() == Some(&end))
+            else {
+                break;
+            };
+            let mut way = ways.swap_remove(index);
+            if way.first() != Some(&end) {
+                way.reverse();
+            }
+            ring.extend(way.into_iter().skip(1));
+        }
 
-    Ok(GeoData { node_locations, chunks })
+        if ring.len() > 3 && ring.first() == ring.last() {
+            rings.push(close_ring(&ring));
+        }
+    }
+    rings
 }
 
 /// For an element in the JSON "elements" array, returns the "type" field if it
@@ -340,9 +1171,9 @@ fn get_element_type<'a>(
 ) -> Result<&'a str, AppError> {
     match element_object.get("type") {
         Some(JsonValue::String(string)) => Ok(string),
-        _ => Err(error(
+        _ => error(
             "an element must have a `type` tag that is a string",
-        ).unwrap_err()),
+        ),
     }
 }
 
@@ -354,9 +1185,9 @@ fn get_id(
             Ok(number.as_u64().unwrap_throw())
         },
         _ => {
-            Err(error(
+            error(
                 "an element must have an `id` tag that is a nonnegative integer",
-            ).unwrap_err())
+            )
         },
     }
 }
@@ -368,7 +1199,7 @@ fn get_tags(
 ) -> Result<HashMap<String, String>, AppError> {
     let tags_field = match element_object.get("tags") {
         Some(JsonValue::Object(object)) => object,
-        Some(_) => return Err(error("`tags` field must be an object").unwrap_err()),
+        Some(_) => return error("`tags` field must be an object"),
         None => return Ok(HashMap::new()),
     };
 
@@ -376,9 +1207,9 @@ fn get_tags(
     for (key, value) in tags_field {
         let string = match value {
             JsonValue::String(string) => string,
-            _ => return Err(error(
+            _ => return error(
                 "`tags` field must be a map from strings to strings",
-            ).unwrap_err()),
+            ),
         };
         result.insert(key.clone(), string.clone());
     }
@@ -431,7 +1262,7 @@ fn truncate_to_f64(number: &Number) -> f64 {
     }
 }
 
-fn error(message: &str) -> Result<GeoData, AppError> {
+fn error<T>(message: &str) -> Result<T, AppError> {
     Err(AppError::DataSyntax {
         format: DataFormat::OsmJson,
         line: None,
@@ -439,3 +1270,73 @@ fn error(message: &str) -> Result<GeoData, AppError> {
         message: message.to_owned(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MISSING_NODES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/fixtures/missing_nodes.json");
+
+    /// The road, river and land use of the fixture each miss 1 of their
+    /// nodes and are kept without it. The last road misses 4 of its 6 nodes
+    /// and is skipped.
+    #[test]
+    fn features_are_kept_without_a_few_missing_nodes() {
+        let json = serde_json::from_str(&std::fs::read_to_string(MISSING_NODES).unwrap()).unwrap();
+        let data = convert_osm_json(json).unwrap();
+        let offset = Offset::centered_on(&data.node_locations[&3001], WorldScale::default());
+
+        let chunks = || data.chunks.values();
+        let road = chunks().find_map(|chunk| chunk.road_features.get(&4001)).unwrap();
+        let river = chunks().find_map(|chunk| chunk.river_features.get(&4002)).unwrap();
+        let land_use = chunks().find_map(|chunk| chunk.land_use_features.get(&4003)).unwrap();
+        for nodes in [&road.nodes, &river.nodes, &close_ring(&land_use.nodes)] {
+            assert_eq!(nodes.len(), 50);
+            assert_eq!(project_nodes(&data.node_locations, nodes, &offset).map(|path| path.len()), Some(49));
+        }
+
+        assert!(chunks().all(|chunk| !chunk.road_features.contains_key(&4004)));
+        assert_eq!(project_nodes(&data.node_locations, &[3001, 3002, 9001, 9002, 9003, 9004], &offset), None);
+        assert_eq!(data.report.missing_nodes, 7);
+        assert_eq!(data.report.dropped_features, 1);
+    }
+
+    /// Converts a grid of 100,000 buildings in batches, which takes a while,
+    /// so it only runs with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn large_data_is_converted_in_batches() {
+        let mut elements = Vec::new();
+        for building in 0..100_000u64 {
+            let (row, column) = ((building / 400) as f64, (building % 400) as f64);
+            for corner in 0..4u64 {
+                elements.push(serde_json::json!({
+                    "type": "node",
+                    "id": building * 4 + corner,
+                    "lat": 51.4 + row * 0.0002 + (corner / 2) as f64 * 0.0001,
+                    "lon": 5.4 + column * 0.0002 + (corner % 2) as f64 * 0.0001,
+                }));
+            }
+            let first = building * 4;
+            elements.push(serde_json::json!({
+                "type": "way",
+                "id": building,
+                "nodes": [first, first + 1, first + 3, first + 2, first],
+                "tags": { "building": "yes" },
+            }));
+        }
+        let document = serde_json::json!({ "elements": elements });
+
+        let mut converter = OsmJsonConverter::new(document.clone()).unwrap();
+        assert_eq!(converter.total(), 500_000);
+        let mut batches = 0;
+        while !converter.convert(5000).unwrap() {
+            batches += 1;
+            assert_eq!(converter.converted(), batches * 5000);
+        }
+        assert_eq!(batches, 99);
+        let data = converter.finish();
+        assert_eq!(data.report.feature_ways[&FeatureType::Building], 100_000);
+        assert_eq!(data, convert_osm_json(document).unwrap());
+    }
+}
@@ -0,0 +1,210 @@
+//! Converts GeoJSON to the internal `GeoData` data structure.
+//!
+//! GeoJSON has no node ids or shared nodes, so every coordinate becomes a new
+//! node. Properties of features are used as OSM tags, so a polygon with the
+//! property `"building": "yes"` becomes a building.
+//!
+//! # See also
+//! [GeoJSON specification](https://datatracker.ietf.org/doc/html/rfc7946)
+
+use crate::common::{AppError, DataFormat};
+use crate::data::geography::{GeoData, GeoDataBuilder};
+
+use serde_json::{Map, Value as JsonValue};
+
+use std::collections::HashMap;
+
+/// Converts a GeoJSON `FeatureCollection`, `Feature` or geometry to `GeoData`.
+pub fn convert_geojson(json: JsonValue) -> Result<GeoData, AppError> {
+    let mut converter = GeoJsonConverter {
+        builder: GeoDataBuilder::new(),
+        next_node_id: 1,
+        next_way_id: 1,
+    };
+    converter.add_object(&json)?;
+    Ok(converter.builder.build())
+}
+
+struct GeoJsonConverter {
+    builder: GeoDataBuilder,
+    next_node_id: u64,
+    next_way_id: u64,
+}
+
+impl GeoJsonConverter {
+    fn add_object(&mut self, json: &JsonValue) -> Result<(), AppError> {
+        let object = match json {
+            JsonValue::Object(object) => object,
+            _ => return Err(error("GeoJSON object must be an object")),
+        };
+
+        match object.get("type").and_then(|value| value.as_str()) {
+            Some("FeatureCollection") => {
+                let features = match object.get("features") {
+                    Some(JsonValue::Array(features)) => features,
+                    _ => return Err(error("`features` of a collection must be an array")),
+                };
+                for feature in features {
+                    self.add_object(feature)?;
+                }
+                Ok(())
+            }
+            Some("Feature") => {
+                let tags = get_properties(object)?;
+                match object.get("geometry") {
+                    Some(JsonValue::Object(geometry)) => self.add_geometry(geometry, &tags),
+                    // features without a location are allowed, but useless
+                    Some(JsonValue::Null) | None => Ok(()),
+                    Some(_) => Err(error("`geometry` of a feature must be an object")),
+                }
+            }
+            Some(_) => self.add_geometry(object, &HashMap::new()),
+            None => Err(error("GeoJSON object must have a `type` that is a string")),
+        }
+    }
+
+    fn add_geometry(
+        &mut self,
+        geometry: &Map<String, JsonValue>,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), AppError> {
+        let coordinates = geometry.get("coordinates").unwrap_or(&JsonValue::Null);
+        match geometry.get("type").and_then(|value| value.as_str()) {
+            Some("Point") => {
+                let [longitude, latitude] = parse_position(coordinates)?;
+                let id = self.next_node_id;
+                self.next_node_id += 1;
+                self.builder.add_node_with_tags(id, latitude, longitude, tags.clone());
+            }
+            Some("MultiPoint") => {
+                for position in parse_array(coordinates)? {
+                    let [longitude, latitude] = parse_position(position)?;
+                    let id = self.next_node_id;
+                    self.next_node_id += 1;
+                    self.builder.add_node_with_tags(id, latitude, longitude, tags.clone());
+                }
+            }
+            Some("LineString") => self.add_line(coordinates, tags)?,
+            Some("MultiLineString") => {
+                for line in parse_array(coordinates)? {
+                    self.add_line(line, tags)?;
+                }
+            }
+            Some("Polygon") => self.add_polygon(coordinates, tags)?,
+            Some("MultiPolygon") => {
+                for polygon in parse_array(coordinates)? {
+                    self.add_polygon(polygon, tags)?;
+                }
+            }
+            Some("GeometryCollection") => {
+                let geometries = match geometry.get("geometries") {
+                    Some(JsonValue::Array(geometries)) => geometries,
+                    _ => return Err(error("`geometries` must be an array")),
+                };
+                for geometry in geometries {
+                    match geometry {
+                        JsonValue::Object(geometry) => self.add_geometry(geometry, tags)?,
+                        _ => return Err(error("a geometry must be an object")),
+                    }
+                }
+            }
+            Some(other) => return Err(error(&format!("unknown geometry type {:?}", other))),
+            None => return Err(error("a geometry must have a `type` that is a string")),
+        }
+        Ok(())
+    }
+
+    /// Adds a way through the given positions.
+    fn add_line(
+        &mut self,
+        coordinates: &JsonValue,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), AppError> {
+        let mut positions = parse_array(coordinates)?
+            .iter()
+            .map(parse_position)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // rings in GeoJSON repeat the first position, which becomes a closed
+        // way like in OSM data
+        let closed = positions.len() > 1 && positions.first() == positions.last();
+        if closed {
+            positions.pop();
+        }
+
+        let mut nodes = Vec::with_capacity(positions.len() + 1);
+        for [longitude, latitude] in positions {
+            let id = self.next_node_id;
+            self.next_node_id += 1;
+            self.builder.add_node(id, latitude, longitude);
+            nodes.push(id);
+        }
+        if closed {
+            nodes.push(nodes[0]);
+        }
+
+        let id = self.next_way_id;
+        self.next_way_id += 1;
+        self.builder.add_way(id, nodes, tags.clone());
+        Ok(())
+    }
+
+    /// Adds the outer ring of a polygon. Holes are not supported yet.
+    fn add_polygon(
+        &mut self,
+        coordinates: &JsonValue,
+        tags: &HashMap<String, String>,
+    ) -> Result<(), AppError> {
+        match parse_array(coordinates)?.first() {
+            Some(outer_ring) => self.add_line(outer_ring, tags),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns the properties of a feature as tags. Values that are not strings
+/// are converted to strings, and `null` values are skipped.
+fn get_properties(feature: &Map<String, JsonValue>) -> Result<HashMap<String, String>, AppError> {
+    let properties = match feature.get("properties") {
+        Some(JsonValue::Object(properties)) => properties,
+        Some(JsonValue::Null) | None => return Ok(HashMap::new()),
+        Some(_) => return Err(error("`properties` of a feature must be an object")),
+    };
+
+    let mut tags = HashMap::new();
+    for (key, value) in properties {
+        let value = match value {
+            JsonValue::String(string) => string.clone(),
+            JsonValue::Null => continue,
+            other => other.to_string(),
+        };
+        tags.insert(key.clone(), value);
+    }
+    Ok(tags)
+}
+
+fn parse_array(json: &JsonValue) -> Result<&Vec<JsonValue>, AppError> {
+    match json {
+        JsonValue::Array(array) => Ok(array),
+        _ => Err(error("`coordinates` must be (nested) arrays")),
+    }
+}
+
+/// Parses a GeoJSON position, which is [longitude, latitude] with an optional
+/// altitude that is ignored.
+fn parse_position(json: &JsonValue) -> Result<[f64; 2], AppError> {
+    let array = parse_array(json)?;
+    match (array.first().and_then(|x| x.as_f64()), array.get(1).and_then(|y| y.as_f64())) {
+        (Some(longitude), Some(latitude)) => Ok([longitude, latitude]),
+        _ => Err(error("a position must contain at least two numbers")),
+    }
+}
+
+fn error(message: &str) -> AppError {
+    AppError::DataSyntax {
+        format: DataFormat::GeoJson,
+        line: None,
+        character: None,
+        message: message.to_owned(),
+    }
+}
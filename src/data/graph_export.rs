@@ -0,0 +1,212 @@
+//! Exports the traffic graph as GraphML or DOT, to analyze the road network
+//! in tools like networkx, Gephi or Graphviz. On native the graph is written
+//! to a file, and in the web version the browser downloads it.
+
+use crate::commands::CommandRegistry;
+#[cfg(target_arch = "wasm32")]
+use crate::common::download_file;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::common::{handle_compute_tasks, spawn_compute_task, AsyncComputation};
+use crate::common::{AppError, StatusEvent};
+use crate::data::geography::Offset;
+use crate::data::traffic_graph::TrafficGraph;
+
+use bevy::prelude::*;
+
+use std::path::{Path, PathBuf};
+
+/// The format that the traffic graph is exported in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum GraphFormat {
+    GraphMl,
+    Dot,
+}
+
+impl GraphFormat {
+    /// Returns the format of the file at `path` by its extension: DOT for
+    /// `.dot` and `.gv` files, and GraphML for all others.
+    ///
+    /// ```
+    /// use city_visualizer::data::graph_export::GraphFormat;
+    /// use std::path::Path;
+    ///
+    /// assert_eq!(GraphFormat::from_path(Path::new("roads.dot")), GraphFormat::Dot);
+    /// assert_eq!(GraphFormat::from_path(Path::new("roads.gv")), GraphFormat::Dot);
+    /// assert_eq!(GraphFormat::from_path(Path::new("roads.graphml")), GraphFormat::GraphMl);
+    /// assert_eq!(GraphFormat::from_path(Path::new("roads")), GraphFormat::GraphMl);
+    /// ```
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext == "dot" || ext == "gv" => GraphFormat::Dot,
+            _ => GraphFormat::GraphMl,
+        }
+    }
+
+    /// Returns the graph in this format.
+    pub fn write(self, graph: &TrafficGraph, offset: &Offset) -> String {
+        match self {
+            GraphFormat::GraphMl => graph.to_graphml(offset),
+            GraphFormat::Dot => graph.to_dot(offset),
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn mime_type(self) -> &'static str {
+        match self {
+            GraphFormat::GraphMl => "application/graphml+xml",
+            GraphFormat::Dot => "text/vnd.graphviz",
+        }
+    }
+}
+
+/// An event that exports the traffic graph to the given path. The format is
+/// chosen by the extension, see `GraphFormat::from_path`. In the web version
+/// only the file name is used, for the download.
+#[derive(Clone, Debug, Event)]
+pub struct ExportGraphEvent {
+    pub path: PathBuf,
+}
+
+/// Keeps track of exports of the traffic graph.
+#[derive(Debug, Default, Resource)]
+pub struct GraphExportState {
+    /// The number of exports that finished, successfully or not.
+    pub finished: usize,
+}
+
+/// The result of an export task: the path of the written file, and the
+/// number of vertices and edges in it.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct GraphExport(Result<(PathBuf, usize, usize), AppError>);
+
+/// A system that exports the traffic graph for every `ExportGraphEvent`. On
+/// native the file is written by a task, with a copy of the graph.
+pub fn start_graph_export(
+    #[cfg(not(target_arch = "wasm32"))] mut commands: Commands,
+    mut export_events: EventReader<ExportGraphEvent>,
+    traffic_graph: Res<TrafficGraph>,
+    offset: Res<Offset>,
+    #[cfg(target_arch = "wasm32")] mut export_state: ResMut<GraphExportState>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let Some(event) = export_events.read().last().cloned() else {
+        return;
+    };
+    if traffic_graph.get_size() == 0 {
+        status_events.send(StatusEvent::Error(AppError::MissingData {
+            message: "load data with roads before exporting the traffic graph".to_owned(),
+        }));
+        return;
+    }
+    let format = GraphFormat::from_path(&event.path);
+    let (nodes, edges) = (traffic_graph.get_size(), traffic_graph.get_edge_count());
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let graph = traffic_graph.clone();
+        let offset = *offset;
+        let path = event.path;
+        spawn_compute_task(&mut commands, async move {
+            GraphExport(match std::fs::write(&path, format.write(&graph, &offset)) {
+                Ok(()) => Ok((path, nodes, edges)),
+                Err(error) => Err(AppError::from_io_error(error, &path)),
+            })
+        });
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        let name = event.path.file_name().map_or_else(
+            || "traffic_graph.graphml".to_owned(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        let contents = format.write(&traffic_graph, &offset);
+        download_file(&name, format.mime_type(), contents.as_bytes());
+        export_state.finished += 1;
+        status_events.send(StatusEvent::Update(format!(
+            "Exported {} vertices and {} edges to {}",
+            nodes, edges, name,
+        )));
+    }
+}
+
+/// A system that reports the result of exporting the traffic graph.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn update_graph_export(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<GraphExport>)>,
+    mut export_state: ResMut<GraphExportState>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    handle_compute_tasks(&mut commands, query, |_, GraphExport(result)| {
+        export_state.finished += 1;
+        match result {
+            Ok((path, nodes, edges)) => {
+                status_events.send(StatusEvent::Update(format!(
+                    "Exported {} vertices and {} edges to {}",
+                    nodes,
+                    edges,
+                    path.display(),
+                )));
+            }
+            Err(error) => {
+                status_events.send(StatusEvent::Error(error));
+            }
+        }
+    });
+}
+
+/// The file that was chosen in the export dialog, or `None` if the dialog
+/// was cancelled.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct GraphExportTarget(Option<PathBuf>);
+
+/// Starts an export of the traffic graph. On native a dialog asks where to
+/// write it to, without blocking the frame, and the chosen file is handled
+/// by `update_graph_export_dialog`. In the web version the graph is
+/// downloaded as GraphML right away.
+pub fn request_graph_export(commands: &mut Commands) {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let dialog = rfd::AsyncFileDialog::new()
+            .set_title("Export the traffic graph")
+            .add_filter("GraphML", &["graphml"])
+            .add_filter("Graphviz DOT", &["dot", "gv"])
+            .set_file_name("traffic_graph.graphml");
+        spawn_compute_task(commands, async move {
+            GraphExportTarget(dialog.save_file().await.map(|file| file.path().to_owned()))
+        });
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    commands.add(|world: &mut World| {
+        world.send_event(ExportGraphEvent {
+            path: PathBuf::from("traffic_graph.graphml"),
+        });
+    });
+}
+
+/// A system that exports the traffic graph to the file that was chosen in
+/// the export dialog.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn update_graph_export_dialog(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<GraphExportTarget>)>,
+    mut export_events: EventWriter<ExportGraphEvent>,
+) {
+    handle_compute_tasks(&mut commands, query, |_, GraphExportTarget(path)| {
+        if let Some(path) = path {
+            export_events.send(ExportGraphEvent { path });
+        }
+    });
+}
+
+/// A system that registers the command that exports the traffic graph.
+pub fn register_graph_export_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Export traffic graph",
+        "Saves the road network that agents travel on as GraphML or DOT, for example to analyze it in networkx or Gephi",
+        None,
+        |commands| request_graph_export(commands),
+    );
+}
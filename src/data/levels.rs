@@ -0,0 +1,139 @@
+//! Parsing and validation of OSM tags that determine the height of buildings,
+//! such as `building:levels`, `roof:levels` and `height`.
+//!
+//! Values are clamped to a plausible range, so vandalised or erroneous values
+//! like `building:levels=999` do not produce buildings that dwarf the city.
+//!
+//! # See also
+//! https://wiki.openstreetmap.org/wiki/Key:building:levels
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// The allowed range of the `building:levels` tag.
+pub const LEVELS_RANGE: RangeInclusive<i32> = 1..=120;
+/// The allowed range of the `roof:levels` tag.
+pub const ROOF_LEVELS_RANGE: RangeInclusive<i32> = 0..=5;
+/// The allowed range of the `height` tag, in meters.
+pub const HEIGHT_RANGE: RangeInclusive<f32> = 1.0..=1000.0;
+
+// What tags OSM uses for the height of buildings
+const TAG_BUILDING_LEVELS: &str = "building:levels";
+const TAG_BUILDING_ROOF_LEVELS: &str = "roof:levels";
+const TAG_BUILDING_HEIGHT: &str = "height";
+
+/// A tag value that was parsed and checked against an allowed range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Validated<T> {
+    /// The value is within the allowed range.
+    Valid(T),
+    /// The value was outside of the allowed range, and is clamped to it.
+    Clamped(T),
+}
+
+impl<T> Validated<T> {
+    /// Returns the value, clamped if it was out of range.
+    pub fn value(self) -> T {
+        match self {
+            Validated::Valid(value) | Validated::Clamped(value) => value,
+        }
+    }
+
+    pub fn is_clamped(&self) -> bool {
+        matches!(self, Validated::Clamped(_))
+    }
+
+    /// Converts the value, keeping whether it was clamped.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Validated<U> {
+        match self {
+            Validated::Valid(value) => Validated::Valid(f(value)),
+            Validated::Clamped(value) => Validated::Clamped(f(value)),
+        }
+    }
+}
+
+/// Parses a number of levels, such as " 2.5 ", which is rounded to 3.
+/// Returns `None` if the value is not a number.
+///
+/// ```
+/// use city_visualizer::data::levels::{parse_levels, Validated, LEVELS_RANGE};
+///
+/// assert_eq!(parse_levels(" 4 ", LEVELS_RANGE), Some(Validated::Valid(4)));
+/// assert_eq!(parse_levels("2.5", LEVELS_RANGE), Some(Validated::Valid(3)));
+/// assert_eq!(parse_levels("-2", LEVELS_RANGE), Some(Validated::Clamped(1)));
+/// assert_eq!(parse_levels("999", LEVELS_RANGE), Some(Validated::Clamped(120)));
+/// assert_eq!(parse_levels("1e30", LEVELS_RANGE), Some(Validated::Clamped(120)));
+/// assert_eq!(parse_levels("many", LEVELS_RANGE), None);
+/// ```
+pub fn parse_levels(value: &str, range: RangeInclusive<i32>) -> Option<Validated<i32>> {
+    let number = parse_number(value)?.round();
+    // clamp as a float first, so huge values do not overflow
+    if number < *range.start() as f32 {
+        Some(Validated::Clamped(*range.start()))
+    } else if number > *range.end() as f32 {
+        Some(Validated::Clamped(*range.end()))
+    } else {
+        Some(Validated::Valid(number as i32))
+    }
+}
+
+/// Parses a height in meters, such as "12.5" or "12.5 m". Returns `None` if
+/// the value is not a number.
+///
+/// ```
+/// use city_visualizer::data::levels::{parse_height, Validated, HEIGHT_RANGE};
+///
+/// assert_eq!(parse_height("12.5 m", HEIGHT_RANGE), Some(Validated::Valid(12.5)));
+/// assert_eq!(parse_height("-3", HEIGHT_RANGE), Some(Validated::Clamped(1.0)));
+/// assert_eq!(parse_height("50000", HEIGHT_RANGE), Some(Validated::Clamped(1000.0)));
+/// ```
+pub fn parse_height(value: &str, range: RangeInclusive<f32>) -> Option<Validated<f32>> {
+    let value = value.trim();
+    let value = value.strip_suffix('m').unwrap_or(value);
+    let number = parse_number(value)?;
+    if range.contains(&number) {
+        Some(Validated::Valid(number))
+    } else {
+        Some(Validated::Clamped(number.clamp(*range.start(), *range.end())))
+    }
+}
+
+fn parse_number(value: &str) -> Option<f32> {
+    value.trim().parse::<f32>().ok().filter(|number| number.is_finite())
+}
+
+/// Returns the validated `building:levels` of a building.
+pub fn get_building_levels(tags: &HashMap<String, String>) -> Option<Validated<i32>> {
+    parse_levels(tags.get(TAG_BUILDING_LEVELS)?, LEVELS_RANGE)
+}
+
+/// Returns the validated `roof:levels` of a building.
+pub fn get_roof_levels(tags: &HashMap<String, String>) -> Option<Validated<i32>> {
+    parse_levels(tags.get(TAG_BUILDING_ROOF_LEVELS)?, ROOF_LEVELS_RANGE)
+}
+
+/// Returns the validated `height` of a building in meters.
+pub fn get_building_height(tags: &HashMap<String, String>) -> Option<Validated<f32>> {
+    parse_height(tags.get(TAG_BUILDING_HEIGHT)?, HEIGHT_RANGE)
+}
+
+/// Returns a warning for every height tag of a building with a value that is
+/// out of range.
+pub fn validate_building_tags(id: u64, tags: &HashMap<String, String>) -> Vec<String> {
+    let clamped = [
+        (TAG_BUILDING_LEVELS, get_building_levels(tags).map(|levels| levels.map(|v| v as f32))),
+        (TAG_BUILDING_ROOF_LEVELS, get_roof_levels(tags).map(|levels| levels.map(|v| v as f32))),
+        (TAG_BUILDING_HEIGHT, get_building_height(tags)),
+    ];
+
+    clamped
+        .into_iter()
+        .filter_map(|(tag, value)| match value {
+            Some(Validated::Clamped(value)) => Some(format!(
+                "building {}: {}={:?} is out of range, clamped to {}",
+                id, tag, tags[tag], value,
+            )),
+            _ => None,
+        })
+        .collect()
+}
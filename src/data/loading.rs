@@ -1,19 +1,53 @@
 //! Defines systems and functions for loading external data using queries.
 use wasm_bindgen::prelude::*;
 
+use crate::commands::CommandRegistry;
 use crate::common::{
-    spawn_compute_task, AppError, AsyncComputation, DataFormat,
-    handle_compute_tasks, StatusEvent,
+    spawn_compute_task, AppError, AsyncComputation, CancelLoadingEvent, DataFormat,
+    handle_compute_tasks, PendingComputation, StatusEvent,
 };
-use crate::data::geography::{convert_osm_json, GeoData};
-use crate::data::query::DataQuery;
+use crate::data::cache::ResponseCache;
+use crate::data::city_bin::{decode_geo_data, is_city_bin};
+use crate::data::geography::{GeoData, OsmJsonConverter};
+use crate::data::geojson::convert_geojson;
+use crate::data::query::{city_name_query, format_from_extension, parse_places, DataQuery, Place};
 use crate::earth::GeoDataEvent;
 
 use bevy::prelude::*;
+use bevy::utils::Instant;
+use bevy::window::FileDragAndDrop;
 
+use bevy_mod_reqwest::reqwest::{StatusCode, Url};
 use bevy_mod_reqwest::{BevyReqwest, Listener, On, ReqResponse};
 
+use flate2::read::GzDecoder;
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+
+/// Information about the source of the data that is currently shown, which
+/// has to be displayed for attribution.
+#[derive(Debug, Default, Resource)]
+pub struct DataAttribution {
+    /// Whether any OpenStreetMap data is currently shown.
+    pub shown: bool,
+    /// The time of the OSM database snapshot of the latest loaded data.
+    pub snapshot_timestamp: Option<String>,
+    /// How old the latest loaded data was if it came from a local cache.
+    pub cache_age: Option<Duration>,
+}
+
+/// Places with the name of a city query, of which the user should choose
+/// one. Empty if there is nothing to choose.
+#[derive(Debug, Default, Resource)]
+pub struct PlaceMatches {
+    /// The name that was looked up.
+    pub name: String,
+    pub places: Vec<Place>,
+}
 
 /// An event for querying and loading external data.
 #[derive(Clone, Debug, Event)]
@@ -21,7 +55,513 @@ pub struct DataQueryEvent {
     pub query: DataQuery,
 }
 
+/// A small town that is built into the application, to try it out without
+/// downloading or picking data.
+const SAMPLE_NAME: &str = "small_town.json.gz";
+const SAMPLE_DATA: &[u8] = include_bytes!("../../examples/fixtures/small_town.json.gz");
+
 const OVERPASS_URL: &'static str = "https://overpass-api.de/api/interpreter";
+const KUMI_OVERPASS_URL: &'static str = "https://overpass.kumi.systems/api/interpreter";
+
+/// How long an Overpass endpoint may take to answer before the next one is
+/// tried. Large cities take a while, so this is generous.
+const DEFAULT_OVERPASS_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often a query is retried when all endpoints are rate limited, and how
+/// long it waits before the first retry. The wait doubles every retry.
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+const RATE_LIMIT_BASE_DELAY: Duration = Duration::from_secs(2);
+
+/// How many bytes at the end of an Overpass response are searched for a
+/// remark, which comes after all elements.
+const REMARK_SEARCH_LENGTH: usize = 4096;
+
+/// How many OSM JSON elements are converted per frame at most, and how long
+/// converting them may take, so that the frame rate holds up while a large
+/// response is converted. Elements are converted in batches, and the time is
+/// checked after every batch.
+const MAX_OSM_ELEMENTS_PER_FRAME: usize = 5000;
+const OSM_ELEMENTS_PER_BATCH: usize = 500;
+const OSM_CONVERSION_FRAME_BUDGET: Duration = Duration::from_millis(8);
+
+/// Data that was read by a query task.
+pub enum ParsedData {
+    Converted(GeoData),
+    /// OSM JSON, which is converted over several frames by
+    /// `update_osm_conversions`.
+    OsmJson(OsmJsonConversion),
+}
+
+/// OSM JSON that is being converted to `GeoData`.
+#[derive(Component)]
+pub struct OsmJsonConversion {
+    converter: OsmJsonConverter,
+    cache_age: Option<Duration>,
+    /// How long reading and converting the data took so far.
+    parse_time: Duration,
+}
+
+/// The Overpass instances that queries are sent to.
+#[derive(Clone, Debug, Resource)]
+pub struct OverpassSettings {
+    /// The endpoints in the order they are tried. The next one is tried when
+    /// an endpoint cannot be reached, is busy or times out.
+    pub endpoints: Vec<String>,
+    /// How long to wait for an answer of a single endpoint.
+    pub timeout: Duration,
+}
+
+impl Default for OverpassSettings {
+    fn default() -> Self {
+        OverpassSettings {
+            endpoints: vec![OVERPASS_URL.to_owned(), KUMI_OVERPASS_URL.to_owned()],
+            timeout: DEFAULT_OVERPASS_TIMEOUT,
+        }
+    }
+}
+
+impl OverpassSettings {
+    /// Returns the endpoints that are HTTP(S) URLs, in order. Others, such as
+    /// an endpoint that is still being typed, are skipped.
+    ///
+    /// ```
+    /// use city_visualizer::data::loading::OverpassSettings;
+    ///
+    /// let settings = OverpassSettings {
+    ///     endpoints: vec![
+    ///         "".to_owned(),
+    ///         " https://overpass.example/api/interpreter ".to_owned(),
+    ///         "overpass.example".to_owned(),
+    ///         "ftp://overpass.example".to_owned(),
+    ///     ],
+    ///     ..Default::default()
+    /// };
+    /// assert_eq!(settings.valid_endpoints(), ["https://overpass.example/api/interpreter"]);
+    /// ```
+    pub fn valid_endpoints(&self) -> Vec<String> {
+        self.endpoints
+            .iter()
+            .map(|endpoint| endpoint.trim())
+            .filter(|endpoint| match Url::parse(endpoint) {
+                Ok(url) => url.scheme() == "http" || url.scheme() == "https",
+                Err(_) => false,
+            })
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+/// Why an Overpass endpoint did not give data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EndpointFailure {
+    /// There was no answer in time, e.g. because the endpoint could not be
+    /// reached.
+    Timeout,
+    /// The endpoint answered with an error status.
+    Status(StatusCode),
+}
+
+impl EndpointFailure {
+    /// Returns whether the endpoint asks to slow down.
+    pub fn is_rate_limit(self) -> bool {
+        self == EndpointFailure::Status(StatusCode::TOO_MANY_REQUESTS)
+    }
+
+    /// Returns whether another endpoint could do better: when this one is
+    /// unreachable, rate limited (429) or timed out itself (504). Other errors,
+    /// like a syntax error in the query, would fail on every endpoint.
+    pub fn is_retryable(self) -> bool {
+        match self {
+            EndpointFailure::Timeout => true,
+            EndpointFailure::Status(status) => {
+                status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::GATEWAY_TIMEOUT
+            }
+        }
+    }
+}
+
+/// The endpoints that are tried for a single query, in order.
+///
+/// ```
+/// use bevy_mod_reqwest::reqwest::StatusCode;
+/// use city_visualizer::data::loading::{EndpointFailure, EndpointFallback};
+///
+/// // a responder that stands in for the network
+/// let respond = |endpoint: &str| match endpoint {
+///     "https://busy.example" => Err(EndpointFailure::Status(StatusCode::TOO_MANY_REQUESTS)),
+///     "https://down.example" => Err(EndpointFailure::Timeout),
+///     "https://broken.example" => Err(EndpointFailure::Status(StatusCode::BAD_REQUEST)),
+///     _ => Ok(()),
+/// };
+/// // returns the endpoint that served the data, and all endpoints that were tried
+/// let run = |endpoints: &[&str]| {
+///     let mut fallback = EndpointFallback::new(endpoints.iter().map(|e| e.to_string()).collect());
+///     let mut tried = Vec::new();
+///     let mut endpoint = fallback.current().map(str::to_owned);
+///     while let Some(current) = endpoint {
+///         tried.push(current.clone());
+///         match respond(&current) {
+///             Ok(()) => return (Some(current), tried),
+///             Err(failure) => endpoint = fallback.advance(failure).map(str::to_owned),
+///         }
+///     }
+///     (None, tried)
+/// };
+///
+/// let (served, tried) = run(&["https://busy.example", "https://down.example", "https://mirror.example"]);
+/// assert_eq!(served.as_deref(), Some("https://mirror.example"));
+/// assert_eq!(tried.len(), 3);
+///
+/// // errors that every endpoint would give are not retried
+/// let (served, tried) = run(&["https://broken.example", "https://mirror.example"]);
+/// assert_eq!(served, None);
+/// assert_eq!(tried, ["https://broken.example"]);
+///
+/// // it stops when all endpoints failed
+/// let (served, tried) = run(&["https://busy.example", "https://down.example"]);
+/// assert_eq!(served, None);
+/// assert_eq!(tried.len(), 2);
+///
+/// // after waiting, all endpoints can be tried again
+/// let mut fallback = EndpointFallback::new(vec!["https://busy.example".to_owned()]);
+/// assert_eq!(fallback.advance(EndpointFailure::Status(StatusCode::TOO_MANY_REQUESTS)), None);
+/// fallback.restart();
+/// assert_eq!(fallback.current(), Some("https://busy.example"));
+///
+/// assert_eq!(EndpointFallback::new(Vec::new()).current(), None);
+/// ```
+#[derive(Clone, Debug)]
+pub struct EndpointFallback {
+    endpoints: Vec<String>,
+    index: usize,
+}
+
+impl EndpointFallback {
+    pub fn new(endpoints: Vec<String>) -> Self {
+        EndpointFallback { endpoints, index: 0 }
+    }
+
+    /// Returns the endpoint that is tried now, or `None` if all endpoints
+    /// failed.
+    pub fn current(&self) -> Option<&str> {
+        self.endpoints.get(self.index).map(String::as_str)
+    }
+
+    /// Records that the current endpoint failed, and returns the next
+    /// endpoint to try, if the failure is worth retrying and one is left.
+    pub fn advance(&mut self, failure: EndpointFailure) -> Option<&str> {
+        self.index = if failure.is_retryable() {
+            self.index + 1
+        } else {
+            self.endpoints.len()
+        };
+        self.current()
+    }
+
+    /// Starts over at the first endpoint.
+    pub fn restart(&mut self) {
+        self.index = 0;
+    }
+}
+
+/// Returns how long to wait before retry `retry` (starting at 1) of a query
+/// that was rate limited.
+///
+/// ```
+/// use city_visualizer::data::loading::rate_limit_delay;
+/// use std::time::Duration;
+///
+/// assert_eq!(rate_limit_delay(1), Duration::from_secs(2));
+/// assert_eq!(rate_limit_delay(3), Duration::from_secs(8));
+/// ```
+pub fn rate_limit_delay(retry: u32) -> Duration {
+    RATE_LIMIT_BASE_DELAY * 2u32.pow(retry.saturating_sub(1))
+}
+
+/// An error that Overpass reports in the "remark" of a response, which is
+/// otherwise successful but has no or partial data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OverpassRemark {
+    /// Too many queries were sent from this address.
+    RateLimited,
+    /// The query needed more memory than allowed.
+    OutOfMemory,
+    /// The query took longer than allowed.
+    TimedOut,
+    /// Another runtime error.
+    Other(String),
+}
+
+impl OverpassRemark {
+    /// Finds the runtime error in the remark at the end of an Overpass
+    /// response, if there is one.
+    ///
+    /// ```
+    /// use city_visualizer::data::loading::OverpassRemark;
+    ///
+    /// let response = r#"{
+    ///   "version": 0.6,
+    ///   "elements": [],
+    ///   "remark": "runtime error: Query run out of memory using about 2048 MB of RAM."
+    /// }"#;
+    /// assert_eq!(OverpassRemark::from_response(response), Some(OverpassRemark::OutOfMemory));
+    ///
+    /// let response = r#"{ "elements": [], "remark": "runtime error: Query timed out in \"query\" at line 3 after 181 seconds." }"#;
+    /// assert_eq!(OverpassRemark::from_response(response), Some(OverpassRemark::TimedOut));
+    ///
+    /// let response = r#"{ "elements": [], "remark": "runtime error: open64: 0 Success /osm3s_osm_base Dispatcher_Client::request_read_and_idx::rate_limited. Please check /api/status for the quota of your IP address." }"#;
+    /// assert_eq!(OverpassRemark::from_response(response), Some(OverpassRemark::RateLimited));
+    ///
+    /// assert_eq!(OverpassRemark::from_response(r#"{ "elements": [] }"#), None);
+    /// ```
+    pub fn from_response(body: &str) -> Option<Self> {
+        let tail = &body[ceil_char_boundary(body, body.len().saturating_sub(REMARK_SEARCH_LENGTH))..];
+        let rest = &tail[tail.rfind(r#""remark""#)? + r#""remark""#.len()..];
+        let rest = rest.trim_start().strip_prefix(':')?;
+        // the remark is a JSON string, which may contain escapes
+        let remark = serde_json::Deserializer::from_str(rest)
+            .into_iter::<String>()
+            .next()?
+            .ok()?;
+
+        if !remark.contains("runtime error") {
+            return None;
+        }
+        Some(if remark.contains("rate_limited") {
+            OverpassRemark::RateLimited
+        } else if remark.contains("out of memory") {
+            OverpassRemark::OutOfMemory
+        } else if remark.contains("timed out") {
+            OverpassRemark::TimedOut
+        } else {
+            OverpassRemark::Other(remark)
+        })
+    }
+
+    /// Returns an explanation of the error for the user.
+    pub fn message(&self) -> String {
+        match self {
+            OverpassRemark::RateLimited => {
+                "Overpass received too many queries, wait a bit before loading more".to_owned()
+            },
+            OverpassRemark::OutOfMemory => {
+                "the query needed more memory than Overpass allows, try a smaller area".to_owned()
+            },
+            OverpassRemark::TimedOut => {
+                "the query took longer than Overpass allows, try a smaller area".to_owned()
+            },
+            OverpassRemark::Other(remark) => format!("Overpass could not run the query: {}", remark),
+        }
+    }
+}
+
+/// An Overpass query that is waiting for an answer.
+#[derive(Debug)]
+struct PendingOverpassQuery {
+    query: String,
+    fallback: EndpointFallback,
+    /// When the current endpoint was asked.
+    started: Instant,
+    /// How many requests were sent, so that answers to earlier requests can
+    /// be recognized.
+    attempt: usize,
+    /// How often the query was retried after all endpoints were rate
+    /// limited.
+    retries: u32,
+    /// The wait before the next retry, while rate limited.
+    retry_timer: Option<Timer>,
+}
+
+/// Files that were dropped onto the window, which are loaded one after
+/// another.
+#[derive(Debug, Default, Resource)]
+pub struct DroppedFiles {
+    queue: VecDeque<PathBuf>,
+    state: DroppedFileState,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+enum DroppedFileState {
+    /// The next file can be loaded.
+    #[default]
+    Idle,
+    /// A file was sent to be loaded, but loading has not started yet.
+    Sent,
+    /// A file is being read or converted.
+    Loading,
+}
+
+/// The Overpass queries that are waiting for an answer, by id.
+#[derive(Debug, Default, Resource)]
+pub struct OverpassRequests {
+    next_id: u64,
+    pending: HashMap<u64, PendingOverpassQuery>,
+}
+const NOMINATIM_URL: &'static str = "https://nominatim.openstreetmap.org/search";
+
+#[cfg(not(target_arch = "wasm32"))]
+const USER_AGENT: &'static str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// The most places that are looked up for the name of a city.
+const MAX_PLACE_MATCHES: usize = 10;
+
+/// How many bytes at the start of a file are used to detect its format.
+const SNIFF_LENGTH: usize = 4096;
+
+/// The bytes that every gzip file starts with.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The format of data as detected from its contents.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DetectedFormat {
+    /// A format that can be imported.
+    Supported(DataFormat),
+    /// [OSM XML](https://wiki.openstreetmap.org/wiki/OSM_XML), which cannot be
+    /// imported yet.
+    OsmXml,
+}
+
+/// Detects the format of data from its first few kilobytes, without relying
+/// on a file extension. Returns `None` if the format is not recognized.
+///
+/// ```
+/// use city_visualizer::common::DataFormat;
+/// use city_visualizer::data::loading::{sniff_data_format, DetectedFormat};
+///
+/// let geojson = r#"{ "type": "FeatureCollection", "features": [] }"#;
+/// assert_eq!(sniff_data_format(geojson), Some(DetectedFormat::Supported(DataFormat::GeoJson)));
+///
+/// let osm_json = r#"{
+///   "version": 0.6,
+///   "generator": "Overpass API",
+///   "elements": [
+///     { "type": "node", "id": 1, "lat": 52.0, "lon": 4.3 }
+///   ]
+/// }"#;
+/// assert_eq!(sniff_data_format(osm_json), Some(DetectedFormat::Supported(DataFormat::OsmJson)));
+///
+/// let osm_xml = r#"<?xml version="1.0" encoding="UTF-8"?>
+/// <osm version="0.6" generator="JOSM">"#;
+/// assert_eq!(sniff_data_format(osm_xml), Some(DetectedFormat::OsmXml));
+///
+/// assert_eq!(sniff_data_format("name,lat,lon"), None);
+/// ```
+pub fn sniff_data_format(contents: &str) -> Option<DetectedFormat> {
+    let header = &contents[..floor_char_boundary(contents, SNIFF_LENGTH)];
+
+    if header.trim_start().starts_with('<') {
+        return header.contains("<osm").then_some(DetectedFormat::OsmXml);
+    }
+
+    // whitespace between keys and values differs between tools
+    let compact: String = header.chars().filter(|c| !c.is_whitespace()).collect();
+    let has_elements = compact.contains(r#""elements":["#);
+    let has_osm_element = [r#""elements":[]"#, r#""type":"node""#, r#""type":"way""#, r#""type":"relation""#]
+        .iter()
+        .any(|element| compact.contains(element));
+    if has_elements && has_osm_element {
+        return Some(DetectedFormat::Supported(DataFormat::OsmJson));
+    }
+    if compact.contains(r#""type":"FeatureCollection""#) || compact.contains(r#""type":"Feature""#) {
+        return Some(DetectedFormat::Supported(DataFormat::GeoJson));
+    }
+    None
+}
+
+/// Returns the smallest index of at least `index` that is on a char boundary.
+fn ceil_char_boundary(string: &str, index: usize) -> usize {
+    let mut index = index.min(string.len());
+    while !string.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+/// Returns the largest index of at most `index` that is on a char boundary.
+fn floor_char_boundary(string: &str, index: usize) -> usize {
+    let mut index = index.min(string.len());
+    while !string.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// Determines the format of a file from its contents, falling back to the
+/// format suggested by its extension.
+fn detect_file_format(
+    contents: &str,
+    extension_format: Option<DataFormat>,
+    file_path: &Path,
+) -> Result<DataFormat, AppError> {
+    match (sniff_data_format(contents), extension_format) {
+        (Some(DetectedFormat::Supported(format)), _) => Ok(format),
+        (Some(DetectedFormat::OsmXml), _) => Err(AppError::InputSyntax {
+            message: format!(
+                "{} contains OSM XML, which is not supported; export it as OSM JSON or GeoJSON instead",
+                file_path.display(),
+            ),
+        }),
+        (None, Some(format)) => Ok(format),
+        (None, None) => Err(AppError::InputSyntax {
+            message: format!(
+                "could not detect the format of {}: found no GeoJSON \"type\", OSM JSON \"elements\" or OSM XML <osm in the first {} bytes, and the extension is not .json or .geojson (optionally followed by .gz)",
+                file_path.display(),
+                SNIFF_LENGTH,
+            ),
+        }),
+    }
+}
+
+/// Reads the contents of a data file, decompressing it first if it is
+/// gzipped. Whether a file is gzipped is detected from its first bytes, like
+/// its format.
+///
+/// ```
+/// use city_visualizer::common::AppError;
+/// use city_visualizer::data::loading::read_data_file;
+/// use std::path::Path;
+///
+/// let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/fixtures");
+/// let compressed = read_data_file(&fixtures.join("small_town.json.gz"), None).unwrap();
+/// let uncompressed = read_data_file(&fixtures.join("small_town.json"), None).unwrap();
+/// assert_eq!(compressed, uncompressed);
+///
+/// // a gzip file that was cut off
+/// let bytes = std::fs::read(fixtures.join("small_town.json.gz")).unwrap();
+/// let path = std::env::temp_dir().join(format!("city-loader-doc-{}.json.gz", std::process::id()));
+/// std::fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+/// let error = read_data_file(&path, None).unwrap_err();
+/// std::fs::remove_file(&path).unwrap();
+/// assert!(matches!(&error, AppError::DataSyntax { message, .. } if message.contains(&*path.to_string_lossy())));
+/// ```
+pub fn read_data_file(file_path: &Path, extension_format: Option<DataFormat>) -> Result<String, AppError> {
+    match std::fs::read(file_path) {
+        Ok(bytes) => decode_data_file(bytes, file_path, extension_format),
+        Err(error) => Err(AppError::from_io_error(error, file_path)),
+    }
+}
+
+/// Decodes the contents of the data file at `file_path` as text, like
+/// `read_data_file`.
+fn decode_data_file(bytes: Vec<u8>, file_path: &Path, extension_format: Option<DataFormat>) -> Result<String, AppError> {
+    if !bytes.starts_with(&GZIP_MAGIC) {
+        return String::from_utf8(bytes).map_err(|error| AppError::from_io_error(
+            std::io::Error::new(std::io::ErrorKind::InvalidData, error),
+            file_path,
+        ));
+    }
+    let mut contents = String::new();
+    match GzDecoder::new(bytes.as_slice()).read_to_string(&mut contents) {
+        Ok(_) => Ok(contents),
+        Err(error) => Err(AppError::DataSyntax {
+            // the format of the compressed data is not known yet
+            format: extension_format.unwrap_or(DataFormat::OsmJson),
+            line: None,
+            character: None,
+            message: format!("{} is not a valid gzip file: {}", file_path.display(), error),
+        }),
+    }
+}
 
 /// A system that reads geographic data load requests, which are normally
 /// generated by the UI when the user enters a query.
@@ -35,96 +575,541 @@ pub fn update_data_queries(
     mut commands: Commands,
     mut client: BevyReqwest,
     mut data_load_events: EventReader<DataQueryEvent>,
+    mut overpass_requests: ResMut<OverpassRequests>,
+    overpass_settings: Res<OverpassSettings>,
+    mut status_events: EventWriter<StatusEvent>,
+    response_cache: Res<ResponseCache>,
 ) {
     for event in data_load_events.read() {
         match &event.query {
             DataQuery::OverpassQL { value } => {
-                let request = client.get(OVERPASS_URL)
-                    .query(&[("data", value)])
-                    .build()
-                    .unwrap_throw();
-                client.send(request, On::run(overpass_listener));
+                // the same query was answered before, so it is not sent again
+                let cached = if response_cache.bypass { None } else { response_cache.get(value) };
+                if let Some(cached) = cached {
+                    status_events.send(StatusEvent::Update(
+                        "Loaded the response from the cache, now importing...".to_owned(),
+                    ));
+                    status_events.send(StatusEvent::progress_step("Parsing the cached response"));
+                    spawn_compute_task(&mut commands, async move {
+                        let body = std::fs::read_to_string(&cached.path)
+                            .map_err(|error| AppError::from_io_error(error, &cached.path))?;
+                        parse_osm_json(&body, Some(cached.age))
+                    });
+                    continue;
+                }
+
+                let fallback = EndpointFallback::new(overpass_settings.valid_endpoints());
+                let Some(endpoint) = fallback.current() else {
+                    status_events.send(StatusEvent::Error(AppError::MissingData {
+                        message: "there are no valid Overpass endpoints, add one in the Overpass settings".to_owned(),
+                    }));
+                    continue;
+                };
+                let id = overpass_requests.next_id;
+                overpass_requests.next_id += 1;
+                send_overpass_request(&mut client, id, 0, endpoint, value);
+                status_events.send(StatusEvent::progress_step(format!("Downloading from {}", endpoint)));
+                overpass_requests.pending.insert(id, PendingOverpassQuery {
+                    query: value.clone(),
+                    fallback,
+                    started: Instant::now(),
+                    attempt: 0,
+                    retries: 0,
+                    retry_timer: None,
+                });
+            },
+            DataQuery::City { name } => {
+                let limit = MAX_PLACE_MATCHES.to_string();
+                let builder = client.get(NOMINATIM_URL)
+                    .query(&[
+                        ("q", name.as_str()),
+                        ("format", "jsonv2"),
+                        ("addressdetails", "1"),
+                        ("limit", &limit),
+                    ]);
+                // Nominatim refuses requests without a user agent, which
+                // browsers always send themselves
+                #[cfg(not(target_arch = "wasm32"))]
+                let builder = builder.header("User-Agent", USER_AGENT);
+                let request = builder.build().unwrap_throw();
+                status_events.send(StatusEvent::progress_step(format!("Looking up {}", name)));
+                let name = name.clone();
+                client.send(request, On::run(
+                    move |req: Listener<ReqResponse>,
+                          data_query_events: EventWriter<DataQueryEvent>,
+                          place_matches: ResMut<PlaceMatches>,
+                          status_events: EventWriter<StatusEvent>| {
+                        nominatim_listener(&name, req, data_query_events, place_matches, status_events)
+                    },
+                ));
             },
             DataQuery::File { format, file_path } => {
+                status_events.send(StatusEvent::progress_step(format!(
+                    "Reading {}",
+                    file_path.display(),
+                )));
                 let file_path_clone = file_path.clone();
-                let format_clone = format.clone();
+                let extension_format = *format;
                 spawn_compute_task(&mut commands, async move {
-                    let file_contents = match std::fs::read_to_string(&file_path_clone) {
-                        Ok(value) => value,
-                        Err(error) => return Err(
-                            AppError::from_io_error(error, &file_path_clone),
+                    let bytes = std::fs::read(&file_path_clone)
+                        .map_err(|error| AppError::from_io_error(error, &file_path_clone))?;
+                    // the file may have been saved long before the data in it
+                    // was downloaded, so only cache hits have a known age
+                    parse_file_bytes(bytes, extension_format, &file_path_clone, None)
+                });
+            },
+            DataQuery::FileContents { format, name, bytes } => {
+                status_events.send(StatusEvent::progress_step(format!("Reading {}", name)));
+                let file_path = PathBuf::from(name);
+                let extension_format = *format;
+                let bytes = Arc::clone(bytes);
+                spawn_compute_task(&mut commands, async move {
+                    parse_file_bytes(bytes.to_vec(), extension_format, &file_path, None)
+                });
+            },
+            DataQuery::GeoJson { value } => {
+                status_events.send(StatusEvent::progress_step("Parsing GeoJSON"));
+                let value_clone = value.clone();
+                spawn_compute_task(&mut commands, async move {
+                    let start = Instant::now();
+                    let data = match serde_json::from_str(&value_clone) {
+                        Ok(json) => convert_geojson(json),
+                        Err(error) => Err(
+                            AppError::from_json_error(error, DataFormat::GeoJson),
                         ),
                     };
-                    match format_clone {
-                        DataFormat::GeoJson => {
-                            todo!();
-                        },
-                        DataFormat::OsmJson => match serde_json::from_str(&file_contents) {
-                            Ok(json) => convert_osm_json(json),
-                            Err(error) => Err(
-                                AppError::from_json_error(error, DataFormat::OsmJson),
-                            ),
-                        },
-                    }
+                    with_parse_time(data, start).map(ParsedData::Converted)
                 });
             },
         }
     }
 }
 
+/// Sends attempt `attempt` of the Overpass query with id `id` to `endpoint`.
+fn send_overpass_request(client: &mut BevyReqwest, id: u64, attempt: usize, endpoint: &str, query: &str) {
+    let request = client.get(endpoint)
+        .query(&[("data", query)])
+        .build()
+        .unwrap_throw();
+    client.send(request, On::run(
+        move |req: Listener<ReqResponse>,
+              commands: Commands,
+              client: BevyReqwest,
+              overpass_requests: ResMut<OverpassRequests>,
+              status_events: EventWriter<StatusEvent>,
+              response_cache: Res<ResponseCache>| {
+            overpass_listener(
+                id, attempt, req, commands, client, overpass_requests, status_events, response_cache,
+            )
+        },
+    ));
+}
+
+/// Tries the next endpoint of a pending Overpass query after the current one
+/// failed. If there is none left, a query that was rate limited is tried
+/// again after a wait, and other failures are reported.
+fn retry_overpass_query(
+    client: &mut BevyReqwest,
+    overpass_requests: &mut OverpassRequests,
+    status_events: &mut EventWriter<StatusEvent>,
+    id: u64,
+    failure: EndpointFailure,
+) {
+    let Some(pending) = overpass_requests.pending.get_mut(&id) else {
+        return;
+    };
+    let failed = pending.fallback.current().unwrap_or_default().to_owned();
+    let reason = match failure {
+        EndpointFailure::Timeout => "did not answer in time".to_owned(),
+        _ if failure.is_rate_limit() => "is rate limited".to_owned(),
+        EndpointFailure::Status(status) => format!("answered with status {}", status),
+    };
+    match pending.fallback.advance(failure).map(str::to_owned) {
+        Some(endpoint) => {
+            status_events.send(StatusEvent::Update(format!(
+                "{} {}, trying {}",
+                failed, reason, endpoint,
+            )));
+            pending.attempt += 1;
+            send_overpass_request(client, id, pending.attempt, &endpoint, &pending.query);
+            pending.started = Instant::now();
+            status_events.send(StatusEvent::progress_step(format!("Downloading from {}", endpoint)));
+        },
+        None if failure.is_rate_limit() && pending.retries < MAX_RATE_LIMIT_RETRIES => {
+            // waiting is handled by `update_overpass_requests`, so the frame
+            // is not blocked
+            pending.retries += 1;
+            let delay = rate_limit_delay(pending.retries);
+            status_events.send(StatusEvent::Update(format!(
+                "Rate limited, retrying in {}s…",
+                delay.as_secs(),
+            )));
+            status_events.send(StatusEvent::progress_step("Waiting to retry after being rate limited"));
+            pending.fallback.restart();
+            pending.retry_timer = Some(Timer::new(delay, TimerMode::Once));
+        },
+        None => {
+            let status = match failure {
+                EndpointFailure::Status(status) => Some(status),
+                EndpointFailure::Timeout => None,
+            };
+            let message = if failure.is_rate_limit() {
+                OverpassRemark::RateLimited.message()
+            } else {
+                format!("Overpass {}", reason)
+            };
+            status_events.send(StatusEvent::Error(AppError::Io {
+                message,
+                status,
+                url: Some(failed),
+            }));
+            overpass_requests.pending.remove(&id);
+        },
+    }
+}
+
+/// A system that sends Overpass queries again once their wait after being
+/// rate limited is over, and tries the next endpoint for queries whose
+/// current endpoint did not answer in time.
+pub fn update_overpass_requests(
+    mut client: BevyReqwest,
+    mut overpass_requests: ResMut<OverpassRequests>,
+    overpass_settings: Res<OverpassSettings>,
+    mut status_events: EventWriter<StatusEvent>,
+    time: Res<Time>,
+) {
+    for (&id, pending) in overpass_requests.pending.iter_mut() {
+        let Some(timer) = &mut pending.retry_timer else {
+            continue;
+        };
+        if !timer.tick(time.delta()).finished() {
+            continue;
+        }
+        pending.retry_timer = None;
+        if let Some(endpoint) = pending.fallback.current() {
+            pending.attempt += 1;
+            send_overpass_request(&mut client, id, pending.attempt, endpoint, &pending.query);
+            pending.started = Instant::now();
+            status_events.send(StatusEvent::progress_step(format!("Downloading from {}", endpoint)));
+        }
+    }
+
+    let timed_out: Vec<u64> = overpass_requests
+        .pending
+        .iter()
+        .filter(|(_, pending)| {
+            pending.retry_timer.is_none() && pending.started.elapsed() > overpass_settings.timeout
+        })
+        .map(|(id, _)| *id)
+        .collect();
+    for id in timed_out {
+        retry_overpass_query(
+            &mut client,
+            &mut overpass_requests,
+            &mut status_events,
+            id,
+            EndpointFailure::Timeout,
+        );
+    }
+}
+
 fn overpass_listener(
+    id: u64,
+    attempt: usize,
     req: Listener<ReqResponse>,
     mut commands: Commands,
+    mut client: BevyReqwest,
+    mut overpass_requests: ResMut<OverpassRequests>,
     mut status_events: EventWriter<StatusEvent>,
+    response_cache: Res<ResponseCache>,
 ) {
+    // answers of endpoints that were given up on are ignored, since the
+    // query was sent to the next endpoint already
+    let endpoint = match overpass_requests.pending.get(&id) {
+        Some(pending) if pending.attempt == attempt && pending.retry_timer.is_none() => {
+            pending.fallback.current().unwrap_or_default().to_owned()
+        },
+        _ => return,
+    };
+
+    if !req.status().is_success() {
+        retry_overpass_query(
+            &mut client,
+            &mut overpass_requests,
+            &mut status_events,
+            id,
+            EndpointFailure::Status(req.status()),
+        );
+        return;
+    }
+
     let body = match req.as_string() {
         Ok(body) => body,
         Err(error) => {
             status_events.send(StatusEvent::Error(AppError::Io {
                 message: error.to_string(),
                 status: Some(req.status()),
-                url: None, // can't get that from the request :/
+                url: Some(endpoint),
             }));
+            overpass_requests.pending.remove(&id);
             return;
         },
     };
 
-    status_events.send(StatusEvent::Update(
-        "Successfully received data from Overpass, now importing...".to_owned(),
-    ));
+    // errors while running the query are reported in a remark, with a
+    // successful status
+    match OverpassRemark::from_response(&body) {
+        Some(OverpassRemark::RateLimited) => {
+            retry_overpass_query(
+                &mut client,
+                &mut overpass_requests,
+                &mut status_events,
+                id,
+                EndpointFailure::Status(StatusCode::TOO_MANY_REQUESTS),
+            );
+            return;
+        },
+        Some(remark) => {
+            status_events.send(StatusEvent::Error(AppError::Io {
+                message: remark.message(),
+                status: Some(req.status()),
+                url: Some(endpoint),
+            }));
+            overpass_requests.pending.remove(&id);
+            return;
+        },
+        None => {},
+    }
+    let query = overpass_requests.pending.remove(&id)
+        .map(|pending| pending.query)
+        .unwrap_or_default();
 
-    // Server does not allow to save the data as folder doesn't exist and it's not allowed to create it
-    // std::fs::write("./geocache/last.json", &body).unwrap_throw();
+    status_events.send(StatusEvent::Update(format!(
+        "Successfully received data from {}, now importing...",
+        endpoint,
+    )));
+    // the download itself can only be followed as a whole, since responses
+    // are only passed on once they are complete
+    status_events.send(StatusEvent::progress_step(format!(
+        "Parsing {:.1} MB of JSON",
+        body.len() as f64 / 1_000_000.0,
+    )));
 
+    // the response is cached in its own task, so parsing does not wait for
+    // it, and failures can be reported by `update_response_cache_writes`
+    let response_cache = response_cache.clone();
+    let response = body.clone();
     spawn_compute_task(&mut commands, async move {
-        match serde_json::from_str(&body) {
-            Ok(json) => convert_osm_json(json),
-            Err(error) => Err(
-                AppError::from_json_error(error, DataFormat::OsmJson),
-            ),
+        ResponseCacheWrite(response_cache.insert(&query, &response))
+    });
+    spawn_compute_task(&mut commands, async move {
+        parse_osm_json(&body, None)
+    });
+}
+
+/// The result of a task that stores an Overpass response in the
+/// `ResponseCache`.
+pub struct ResponseCacheWrite(std::io::Result<()>);
+
+/// A system that reports Overpass responses that could not be cached. The
+/// data is still loaded, so this is reported as an update rather than an
+/// error, which would end the loading progress and fail a headless run.
+pub fn update_response_cache_writes(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<ResponseCacheWrite>)>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    handle_compute_tasks(&mut commands, query, |_, ResponseCacheWrite(result)| {
+        if let Err(error) = result {
+            status_events.send(StatusEvent::Update(format!(
+                "Could not cache the Overpass response: {}",
+                error,
+            )));
         }
     });
 }
 
+/// Parses the bytes of a data file. Saved data is decoded right away, and
+/// other files are decoded as text and parsed by `parse_file_contents`.
+fn parse_file_bytes(
+    bytes: Vec<u8>,
+    extension_format: Option<DataFormat>,
+    file_path: &Path,
+    cache_age: Option<Duration>,
+) -> Result<ParsedData, AppError> {
+    if is_city_bin(&bytes) {
+        let start = Instant::now();
+        let data = decode_geo_data(&bytes).map(|data| GeoData { cache_age, ..data });
+        return with_parse_time(data, start).map(ParsedData::Converted);
+    }
+    let file_contents = decode_data_file(bytes, file_path, extension_format)?;
+    parse_file_contents(&file_contents, extension_format, file_path, cache_age)
+}
+
+/// Parses the contents of a data file in the format that is detected from
+/// them.
+fn parse_file_contents(
+    file_contents: &str,
+    extension_format: Option<DataFormat>,
+    file_path: &Path,
+    cache_age: Option<Duration>,
+) -> Result<ParsedData, AppError> {
+    let format = detect_file_format(file_contents, extension_format, file_path)?;
+    let start = Instant::now();
+    match format {
+        DataFormat::GeoJson => {
+            let data = match serde_json::from_str(file_contents) {
+                Ok(json) => convert_geojson(json),
+                Err(error) => Err(
+                    AppError::from_json_error(error, DataFormat::GeoJson),
+                ),
+            };
+            with_parse_time(data, start)
+                .map(|data| ParsedData::Converted(GeoData { cache_age, ..data }))
+        },
+        DataFormat::OsmJson => parse_osm_json(file_contents, cache_age),
+        // saved data is recognized before it is decoded as text
+        DataFormat::CityBin => Err(AppError::DataSyntax {
+            format: DataFormat::CityBin,
+            line: None,
+            character: None,
+            message: format!("{} is not a saved data file", file_path.display()),
+        }),
+    }
+}
+
+/// Parses a response in OSM JSON, such as the response of Overpass. Its
+/// elements are converted later by `update_osm_conversions`, a batch per
+/// frame.
+fn parse_osm_json(body: &str, cache_age: Option<Duration>) -> Result<ParsedData, AppError> {
+    let start = Instant::now();
+    let json = match serde_json::from_str(body) {
+        Ok(json) => json,
+        Err(error) => return Err(
+            AppError::from_json_error(error, DataFormat::OsmJson),
+        ),
+    };
+    Ok(ParsedData::OsmJson(OsmJsonConversion {
+        converter: OsmJsonConverter::new(json)?,
+        cache_age,
+        parse_time: start.elapsed(),
+    }))
+}
+
+/// Handles the places Nominatim found for `name`: a single place is loaded
+/// right away, and the user chooses between several places. If the lookup
+/// failed or found nothing, Overpass is asked for an area with the name
+/// instead.
+fn nominatim_listener(
+    name: &str,
+    req: Listener<ReqResponse>,
+    mut data_query_events: EventWriter<DataQueryEvent>,
+    mut place_matches: ResMut<PlaceMatches>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let places = if req.status().is_success() {
+        match req.as_string() {
+            Ok(body) => parse_places(&body).map_err(|error| error.to_string()),
+            Err(error) => Err(error.to_string()),
+        }
+    } else {
+        Err(format!("status {}", req.status()))
+    };
+
+    let mut places = match places {
+        Ok(places) if !places.is_empty() => places,
+        Ok(_) => {
+            status_events.send(StatusEvent::Update(format!(
+                "Nominatim found no place named \"{}\", searching Overpass for it instead",
+                name,
+            )));
+            data_query_events.send(DataQueryEvent { query: city_name_query(name) });
+            return;
+        },
+        Err(message) => {
+            status_events.send(StatusEvent::Update(format!(
+                "Could not look up \"{}\" with Nominatim ({}), searching Overpass for it instead",
+                name, message,
+            )));
+            data_query_events.send(DataQueryEvent { query: city_name_query(name) });
+            return;
+        },
+    };
+
+    if places.len() == 1 {
+        let place = places.remove(0);
+        status_events.send(StatusEvent::Update(format!(
+            "Found {}, now loading it",
+            place.display_name,
+        )));
+        data_query_events.send(DataQueryEvent { query: place.query() });
+    } else {
+        status_events.send(StatusEvent::Update(format!(
+            "Found {} places named \"{}\", choose one to load",
+            places.len(), name,
+        )));
+        *place_matches = PlaceMatches { name: name.to_owned(), places };
+        status_events.send(StatusEvent::progress_finished());
+    }
+}
+
+/// Records in the report of converted data how long it took since `start`.
+fn with_parse_time(data: Result<GeoData, AppError>, start: Instant) -> Result<GeoData, AppError> {
+    data.map(|mut data| {
+        data.report.parse_time = start.elapsed();
+        data
+    })
+}
+
 /// A system that polls data query tasks that are not yet fulfilled.
 pub fn update_query_tasks(
     mut commands: Commands,
-    query: Query<(Entity, &mut AsyncComputation<Result<GeoData, AppError>>)>,
+    query: Query<(Entity, &mut AsyncComputation<Result<ParsedData, AppError>>)>,
     mut geo_data_events: EventWriter<GeoDataEvent>,
     mut status_events: EventWriter<StatusEvent>,
+    mut attribution: ResMut<DataAttribution>,
 ) {
-    handle_compute_tasks(&mut commands, query, move |_, data| {
+    handle_compute_tasks(&mut commands, query, move |commands, data| {
         match data {
-            Ok(value) => {
+            Ok(ParsedData::OsmJson(conversion)) => {
+                status_events.send(StatusEvent::Progress {
+                    label: format!("Converting {} elements", conversion.converter.total()),
+                    fraction: Some(0.0),
+                });
+                commands.spawn((conversion, PendingComputation));
+            },
+            Ok(ParsedData::Converted(value)) => {
                 if value.is_empty() {
                     status_events.send(StatusEvent::Error(AppError::MissingData {
                         message: "no geographic data was found".to_owned(),
                     }));
                 } else {
-                    status_events.send(StatusEvent::Update(
-                        "Successfully imported data, now adding to the world...".to_owned(),
-                    ));
+                    let report = &value.report;
+                    let mut message = if report.missing_nodes > 0 {
+                        format!(
+                            "Successfully imported data (skipped {} missing nodes and {} incomplete features), now adding to the world...",
+                            report.missing_nodes, report.dropped_features,
+                        )
+                    } else {
+                        "Successfully imported data, now adding to the world...".to_owned()
+                    };
+                    if !report.oversized_features.is_empty() {
+                        message += &format!(
+                            " Skipped {} oversized features: {:?}.",
+                            report.oversized_features.len(), report.oversized_features,
+                        );
+                    }
+                    if !report.warnings.is_empty() {
+                        message += &format!(" {} tag values were out of range and clamped.", report.warnings.len());
+                    }
+                    status_events.send(StatusEvent::Update(message));
+                    *attribution = DataAttribution {
+                        shown: true,
+                        snapshot_timestamp: value.snapshot_timestamp.clone(),
+                        cache_age: value.cache_age,
+                    };
                     geo_data_events.send(GeoDataEvent { data: Arc::new(value) });
+                    status_events.send(StatusEvent::progress_step("Adding to the world"));
                 }
             },
             Err(error) => {
@@ -133,3 +1118,174 @@ pub fn update_query_tasks(
         }
     });
 }
+
+/// A system that converts the elements of OSM JSON that was parsed by a query
+/// task, at most `MAX_OSM_ELEMENTS_PER_FRAME` per frame. Once all elements
+/// are converted, they are assigned to chunks by another query task.
+pub fn update_osm_conversions(
+    mut commands: Commands,
+    mut conversions: Query<(Entity, &mut OsmJsonConversion)>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    // conversions are done one at a time, so they all stay within the budget
+    let Some((entity, mut conversion)) = conversions.iter_mut().next() else {
+        return;
+    };
+
+    let start = Instant::now();
+    let mut converted = 0;
+    let finished = loop {
+        match conversion.converter.convert(OSM_ELEMENTS_PER_BATCH) {
+            Ok(true) => break true,
+            Ok(false) => {},
+            Err(error) => {
+                status_events.send(StatusEvent::Error(error));
+                commands.entity(entity).despawn();
+                return;
+            },
+        }
+        converted += OSM_ELEMENTS_PER_BATCH;
+        if converted >= MAX_OSM_ELEMENTS_PER_FRAME || start.elapsed() >= OSM_CONVERSION_FRAME_BUDGET {
+            break false;
+        }
+    };
+    conversion.parse_time += start.elapsed();
+
+    let (done, total) = (conversion.converter.converted(), conversion.converter.total());
+    if !finished {
+        status_events.send(StatusEvent::Progress {
+            label: format!("Converting {} elements", total),
+            // a full bar would end the progress
+            fraction: Some((done as f32 / total as f32).min(0.99)),
+        });
+        return;
+    }
+
+    commands.entity(entity).despawn();
+    let converter = std::mem::take(&mut conversion.converter);
+    let (cache_age, parse_time) = (conversion.cache_age, conversion.parse_time);
+    status_events.send(StatusEvent::progress_step(format!("Assigning {} elements to chunks", total)));
+    spawn_compute_task(&mut commands, async move {
+        let start = Instant::now();
+        let mut data = converter.finish();
+        data.cache_age = cache_age;
+        data.report.parse_time = parse_time + start.elapsed();
+        Ok::<_, AppError>(ParsedData::Converted(data))
+    });
+}
+
+/// A system that loads files that are dropped onto the window. Files with an
+/// unknown extension are rejected, and the others are loaded one at a time,
+/// in the order they were dropped.
+pub fn update_dropped_files(
+    mut drop_events: EventReader<FileDragAndDrop>,
+    mut cancel_events: EventReader<CancelLoadingEvent>,
+    mut dropped_files: ResMut<DroppedFiles>,
+    loading: Query<(), Or<(With<AsyncComputation<Result<ParsedData, AppError>>>, With<OsmJsonConversion>)>>,
+    mut data_query_events: EventWriter<DataQueryEvent>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    for event in drop_events.read() {
+        let FileDragAndDrop::DroppedFile { path_buf, .. } = event else {
+            continue;
+        };
+        if format_from_extension(path_buf).is_none() {
+            status_events.send(StatusEvent::Error(AppError::InputSyntax {
+                message: format!(
+                    "cannot load {}: only .json and .geojson files, optionally gzipped, and .citybin files can be loaded",
+                    file_name(path_buf),
+                ),
+            }));
+            continue;
+        }
+        dropped_files.queue.push_back(path_buf.clone());
+    }
+
+    if cancel_events.read().count() > 0 {
+        dropped_files.queue.clear();
+        dropped_files.state = DroppedFileState::Idle;
+        return;
+    }
+
+    // the next file is only loaded once the previous one has been read and
+    // converted, which takes at least a frame to start
+    let is_loading = !loading.is_empty();
+    dropped_files.state = match (dropped_files.state, is_loading) {
+        (DroppedFileState::Sent, true) => DroppedFileState::Loading,
+        (DroppedFileState::Loading, false) => DroppedFileState::Idle,
+        (state, _) => state,
+    };
+    if dropped_files.state != DroppedFileState::Idle || is_loading {
+        return;
+    }
+    let Some(file_path) = dropped_files.queue.pop_front() else {
+        return;
+    };
+
+    let mut message = format!("Loading dropped file {}", file_name(&file_path));
+    if !dropped_files.queue.is_empty() {
+        message += &format!(" ({} more queued)", dropped_files.queue.len());
+    }
+    status_events.send(StatusEvent::Update(message));
+    data_query_events.send(DataQueryEvent {
+        query: DataQuery::File {
+            format: format_from_extension(&file_path),
+            file_path,
+        },
+    });
+    dropped_files.state = DroppedFileState::Sent;
+}
+
+/// Returns the name of a file without its directory, for messages.
+fn file_name(file_path: &Path) -> String {
+    match file_path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => file_path.display().to_string(),
+    }
+}
+
+/// A system that registers the commands for loading data.
+pub fn register_loading_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Load sample",
+        "Loads a small town that is built into the application, without downloading anything",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                world.send_event(StatusEvent::Update(format!("Loading the sample {}", SAMPLE_NAME)));
+                world.send_event(DataQueryEvent {
+                    query: DataQuery::FileContents {
+                        format: format_from_extension(Path::new(SAMPLE_NAME)),
+                        name: SAMPLE_NAME.to_owned(),
+                        bytes: SAMPLE_DATA.into(),
+                    },
+                });
+            })
+        },
+    );
+}
+
+/// A system that drops the queries, parsing tasks and conversions of data
+/// that is being loaded, when loading is cancelled. Generation is cancelled by
+/// `cancel_generation`.
+pub fn cancel_data_queries(
+    mut commands: Commands,
+    mut cancel_events: EventReader<CancelLoadingEvent>,
+    tasks: Query<Entity, Or<(With<AsyncComputation<Result<ParsedData, AppError>>>, With<OsmJsonConversion>)>>,
+    mut overpass_requests: ResMut<OverpassRequests>,
+    mut place_matches: ResMut<PlaceMatches>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    if cancel_events.read().count() == 0 {
+        return;
+    }
+
+    // answers to dropped queries are ignored by `overpass_listener`, and
+    // dropping a task cancels it
+    overpass_requests.pending.clear();
+    place_matches.places.clear();
+    for entity in &tasks {
+        commands.entity(entity).despawn();
+    }
+    status_events.send(StatusEvent::Update("Cancelled loading".to_owned()));
+}
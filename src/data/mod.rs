@@ -1,8 +1,14 @@
 //! These modules load and update geographic data.
 
+pub mod cache;
+pub mod city_bin;
 pub mod geography;
+pub mod geojson;
+pub mod graph_export;
+pub mod levels;
 pub mod loading;
 pub mod query;
 pub mod road_type;
 pub mod building_type;
+pub mod colour;
 pub mod traffic_graph;
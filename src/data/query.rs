@@ -1,8 +1,12 @@
 //! Defines queries for loading external data.
 
 use crate::common::{DataFormat, AppError};
+use crate::data::geography::GeoLocation;
 
-use std::path::PathBuf;
+use serde_json::Value;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// A query in internal format that can be executed to load geographic data.
 /// 
@@ -17,11 +21,37 @@ pub enum DataQuery {
     OverpassQL {
         value: String,
     },
+    /// A city by name, which is first looked up with [Nominatim] so that the
+    /// user can choose between places with the same name. Falls back to
+    /// `city_name_query` if the lookup fails.
+    ///
+    /// [Nominatim]: https://nominatim.org/release-docs/latest/api/Search/
+    City {
+        name: String,
+    },
     /// A file on the local file system.
     File {
-        format: DataFormat,
+        /// The format suggested by the file extension, if it is a known one.
+        /// The format detected from the contents of the file takes
+        /// precedence.
+        format: Option<DataFormat>,
         file_path: PathBuf,
     },
+    /// The contents of a file that were read already. In browsers, picked
+    /// files can only be read like this, since there are no paths.
+    FileContents {
+        /// The format suggested by the file extension, like for `File`.
+        format: Option<DataFormat>,
+        /// The name of the file, for messages.
+        name: String,
+        bytes: Arc<[u8]>,
+    },
+    /// A snippet of [GeoJSON] that was entered directly.
+    /// 
+    /// [GeoJSON]: https://datatracker.ietf.org/doc/html/rfc7946
+    GeoJson {
+        value: String,
+    },
 }
 
 /// The type of a user's query in the UI.
@@ -32,8 +62,268 @@ pub enum DataQuery {
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum InputQueryType {
     City,
+    /// A bounding box as `south, west, north, east` in degrees, which is the
+    /// same as the south-west corner followed by the north-east corner.
+    BBox,
     File,
     Overpass,
+    GeoJson,
+}
+
+/// The Overpass QL selectors of the features that are loaded by city and
+/// bounding box queries, without the area they are searched in.
+const FEATURE_SELECTORS: [&str; 6] = [
+    r#"way["highway"]"#,
+    r#"way["building"]"#,
+    r#"way["landuse"]"#,
+    r#"way["natural"="water"]"#,
+    r#"way["waterway"~"river|stream|canal|ditch"]"#,
+    r#"relation["boundary"="administrative"]["admin_level"~"^(9|10)$"]"#,
+];
+
+/// The length of one degree of latitude, in meters.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Builds an Overpass QL query for all features in `FEATURE_SELECTORS`, where
+/// `filter` restricts the area they are searched in. `setup` is run first,
+/// e.g. to define the area.
+fn feature_query(setup: &str, filter: &str) -> String {
+    let selectors: String = FEATURE_SELECTORS
+        .iter()
+        .map(|selector| format!("\n    {}{};", selector, filter))
+        .collect();
+    // finds all features, and then appends their nodes (and the ways of
+    // relations). `out body` means outputting all tags
+    format!(
+        "[out:json];\n{}\n({}\n)->.result;\n(.result; .result >;);\nout body;",
+        setup, selectors,
+    )
+}
+
+/// Creates a query for the features within `radius` meters of `center`, in a
+/// square bounding box.
+///
+/// ```
+/// use city_visualizer::data::geography::GeoLocation;
+/// use city_visualizer::data::query::{bounding_box_query, DataQuery};
+///
+/// let center = GeoLocation { longitude: 0.0, latitude: 0.0 };
+/// let DataQuery::OverpassQL { value } = bounding_box_query(&center, 1113.2) else {
+///     panic!("expected an Overpass query");
+/// };
+/// assert!(value.contains(r#"way["building"](-0.010000,-0.010000,0.010000,0.010000);"#));
+/// ```
+pub fn bounding_box_query(center: &GeoLocation, radius: f64) -> DataQuery {
+    // degrees of longitude get shorter towards the poles
+    let latitude_delta = radius / METERS_PER_DEGREE;
+    let longitude_delta = radius / (METERS_PER_DEGREE * center.latitude.to_radians().cos());
+    let filter = bounding_box_filter(
+        (center.latitude - latitude_delta).max(-90.0),
+        (center.longitude - longitude_delta).max(-180.0),
+        (center.latitude + latitude_delta).min(90.0),
+        (center.longitude + longitude_delta).min(180.0),
+    );
+    DataQuery::OverpassQL {
+        value: feature_query("", &filter),
+    }
+}
+
+/// Creates a query for the features in the area with the given name, which is
+/// whichever area Overpass finds first if several areas have that name.
+pub fn city_name_query(name: &str) -> DataQuery {
+    // ->. stores the result of the area[name=...] query in searchArea
+    let setup = format!(r#"area[name="{}"]->.searchArea;"#, name);
+    DataQuery::OverpassQL {
+        value: feature_query(&setup, "(area.searchArea)"),
+    }
+}
+
+/// Overpass derives the ids of areas from the ids of the relations and ways
+/// they are made of, by adding these.
+const RELATION_AREA_ID_OFFSET: u64 = 3_600_000_000;
+const WAY_AREA_ID_OFFSET: u64 = 2_400_000_000;
+
+/// A place that was found by looking up a name with Nominatim.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Place {
+    pub name: String,
+    pub country: Option<String>,
+    /// The full name of the place, including the regions it is in.
+    pub display_name: String,
+    /// The bounding box as south, west, north, east in degrees.
+    pub bounding_box: [f64; 4],
+    /// The id of the Overpass area of the place, if it is a relation or way.
+    /// Places that are nodes have no area.
+    pub area_id: Option<u64>,
+}
+
+impl Place {
+    /// Creates a query for the features in the area of the place, or in its
+    /// bounding box if it has no area.
+    ///
+    /// ```
+    /// use city_visualizer::data::query::{DataQuery, Place};
+    ///
+    /// let mut place = Place {
+    ///     name: "Springfield".to_owned(),
+    ///     country: Some("United States".to_owned()),
+    ///     display_name: "Springfield, Sangamon County, Illinois, United States".to_owned(),
+    ///     bounding_box: [39.70, -89.77, 39.87, -89.55],
+    ///     area_id: Some(3_600_123_456),
+    /// };
+    /// let DataQuery::OverpassQL { value } = place.query() else {
+    ///     panic!("expected an Overpass query");
+    /// };
+    /// assert!(value.contains("area(id:3600123456)->.searchArea;"));
+    ///
+    /// place.area_id = None;
+    /// let DataQuery::OverpassQL { value } = place.query() else {
+    ///     panic!("expected an Overpass query");
+    /// };
+    /// assert!(value.contains(r#"way["building"](39.700000,-89.770000,39.870000,-89.550000);"#));
+    /// ```
+    pub fn query(&self) -> DataQuery {
+        let value = match self.area_id {
+            Some(id) => feature_query(&format!("area(id:{})->.searchArea;", id), "(area.searchArea)"),
+            None => {
+                let [south, west, north, east] = self.bounding_box;
+                feature_query("", &bounding_box_filter(south, west, north, east))
+            }
+        };
+        DataQuery::OverpassQL { value }
+    }
+}
+
+/// Reads the places in a response of the Nominatim search API in the `jsonv2`
+/// format, with `addressdetails=1`. Results that cannot be read are skipped.
+///
+/// ```
+/// use city_visualizer::data::query::parse_places;
+///
+/// let response = r#"[
+///   {
+///     "osm_type": "relation", "osm_id": 123456, "name": "Springfield",
+///     "display_name": "Springfield, Sangamon County, Illinois, United States",
+///     "boundingbox": ["39.70", "39.87", "-89.77", "-89.55"],
+///     "address": { "city": "Springfield", "country": "United States" }
+///   },
+///   {
+///     "osm_type": "node", "osm_id": 42, "name": "Springfield",
+///     "display_name": "Springfield, Tasmania, Australia",
+///     "boundingbox": ["-41.2", "-41.1", "147.4", "147.5"]
+///   },
+///   { "osm_type": "node", "osm_id": 43 }
+/// ]"#;
+/// let places = parse_places(response).unwrap();
+/// assert_eq!(places.len(), 2);
+/// assert_eq!(places[0].area_id, Some(3_600_123_456));
+/// assert_eq!(places[0].country.as_deref(), Some("United States"));
+/// assert_eq!(places[0].bounding_box, [39.70, -89.77, 39.87, -89.55]);
+/// assert_eq!(places[1].area_id, None);
+///
+/// assert!(parse_places("{}").is_err());
+/// ```
+pub fn parse_places(response: &str) -> Result<Vec<Place>, AppError> {
+    let json: Value = serde_json::from_str(response).map_err(|error| AppError::InputSyntax {
+        message: format!("could not read the response of Nominatim: {}", error),
+    })?;
+    let Some(results) = json.as_array() else {
+        return Err(AppError::InputSyntax {
+            message: "expected a list of places from Nominatim".to_owned(),
+        });
+    };
+    Ok(results.iter().filter_map(parse_place).collect())
+}
+
+fn parse_place(value: &Value) -> Option<Place> {
+    let display_name = value.get("display_name")?.as_str()?.to_owned();
+    // the bounding box is south, north, west, east, as strings
+    let bounds: Vec<f64> = value
+        .get("boundingbox")?
+        .as_array()?
+        .iter()
+        .map(|bound| bound.as_str()?.parse().ok())
+        .collect::<Option<_>>()?;
+    let [south, north, west, east] = bounds[..] else {
+        return None;
+    };
+    let osm_id = value.get("osm_id")?.as_u64()?;
+    let area_id = match value.get("osm_type")?.as_str()? {
+        "relation" => Some(RELATION_AREA_ID_OFFSET + osm_id),
+        "way" => Some(WAY_AREA_ID_OFFSET + osm_id),
+        _ => None,
+    };
+    let name = match value.get("name").and_then(Value::as_str) {
+        Some(name) if !name.is_empty() => name.to_owned(),
+        _ => display_name.split(',').next().unwrap_or_default().to_owned(),
+    };
+    let country = value
+        .get("address")
+        .and_then(|address| address.get("country"))
+        .and_then(Value::as_str)
+        .map(str::to_owned);
+    Some(Place {
+        name,
+        country,
+        display_name,
+        bounding_box: [south, west, north, east],
+        area_id,
+    })
+}
+
+/// Returns the Overpass QL filter for features within a bounding box.
+fn bounding_box_filter(south: f64, west: f64, north: f64, east: f64) -> String {
+    format!("({:.6},{:.6},{:.6},{:.6})", south, west, north, east)
+}
+
+/// Parses a bounding box entered by the user as `south, west, north, east`.
+///
+/// ```
+/// use city_visualizer::common::AppError;
+/// use city_visualizer::data::query::{parse_data_query, DataQuery, InputQueryType};
+///
+/// let query = parse_data_query(InputQueryType::BBox, "51.44, 5.47, 51.45, 5.48").unwrap();
+/// let DataQuery::OverpassQL { value } = query else {
+///     panic!("expected an Overpass query");
+/// };
+/// assert!(value.contains(r#"way["highway"](51.440000,5.470000,51.450000,5.480000);"#));
+///
+/// // two corners separated by whitespace work as well
+/// assert!(parse_data_query(InputQueryType::BBox, "51.44,5.47 51.45,5.48").is_ok());
+///
+/// let message = |string| match parse_data_query(InputQueryType::BBox, string) {
+///     Err(AppError::InputSyntax { message }) => message,
+///     _ => panic!("expected a syntax error for {:?}", string),
+/// };
+/// assert!(message("51.44, 5.47, 51.45").contains("east"));
+/// assert!(message("51.44, x, 51.45, 5.48").contains("west"));
+/// assert!(message("51.44, 5.47, 95.0, 5.48").contains("north"));
+/// assert!(message("51.45, 5.47, 51.44, 5.48").contains("south"));
+/// assert!(message("51.44, 5.47, 51.45, 5.48, 1.0").contains("too many"));
+/// ```
+fn parse_bounding_box(string: &str) -> Result<DataQuery, AppError> {
+    let expected = "\"south, west, north, east\"";
+    let mut values = string
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty());
+    let south = parse_coordinate(values.next(), "south", 90.0, expected)?;
+    let west = parse_coordinate(values.next(), "west", 180.0, expected)?;
+    let north = parse_coordinate(values.next(), "north", 90.0, expected)?;
+    let east = parse_coordinate(values.next(), "east", 180.0, expected)?;
+    if values.next().is_some() {
+        return Err(AppError::InputSyntax {
+            message: format!("too many values, expected a bounding box as {}", expected),
+        });
+    }
+    if south >= north {
+        return Err(AppError::InputSyntax {
+            message: format!("south {} should be less than north {}", south, north),
+        });
+    }
+
+    Ok(DataQuery::OverpassQL {
+        value: feature_query("", &bounding_box_filter(south, west, north, east)),
+    })
 }
 
 /// Converts a query string given by the user to a query in internal format.
@@ -49,44 +339,120 @@ pub fn parse_data_query(
                 });
             }
 
-            // city queries are mapped to overpass QL queries
-            // ->. stores the result of the area[name=...] query in searchArea
-            // it then finds all "way", and then appends the nodes inside
-            // `out body` means outputting all tags
-            Ok(DataQuery::OverpassQL {
-                value: format!(r#"[out:json];
-                    area[name="{}"]->.searchArea;
-                    (
-                        way["highway"](area.searchArea);
-                        way["building"](area.searchArea);
-                        way["landuse"](area.searchArea);
-                        way["natural"="water"](area.searchArea);
-                        way["waterway"~"river|stream|canal|ditch"](area.searchArea);
-                    )->.result;
-                    (.result; .result >;);
-                    out body;"#,
-                    string,
-                ),
-            })
+            // city queries are looked up first, and then mapped to overpass
+            // QL queries
+            Ok(DataQuery::City { name: string.to_owned() })
         },
+        InputQueryType::BBox => parse_bounding_box(string),
         InputQueryType::Overpass => {
             Ok(DataQuery::OverpassQL { value: string.to_owned() })
         },
+        InputQueryType::GeoJson => {
+            Ok(DataQuery::GeoJson { value: string.to_owned() })
+        },
         InputQueryType::File => {
             let file_path = PathBuf::from(string);
-            let extension = file_path.extension();
-            let format = match extension {
-                Some(ext) if ext == "json" => DataFormat::OsmJson,
-                Some(ext) if ext == "geojson" => DataFormat::GeoJson,
-                Some(ext) => return Err(AppError::InputSyntax {
-                    message: format!("unsupported file extension {:?}", ext),
-                }),
-                None => return Err(AppError::InputSyntax {
-                    message: format!("file without file extension"),
-                }),
-            };
+            // extensions are often wrong, so the format is detected from the
+            // contents when the file is loaded, and this is only a fallback
+            let format = format_from_extension(&file_path);
 
             Ok(DataQuery::File { format, file_path })
         },
     }
 }
+
+/// Returns the format suggested by the extension of a data file, or `None`
+/// if it is not a known extension. Gzipped files are decompressed when they
+/// are loaded, so their format is that of the inner extension. Saved data is
+/// not gzipped, since it is compact already.
+///
+/// ```
+/// use city_visualizer::common::DataFormat;
+/// use city_visualizer::data::query::format_from_extension;
+/// use std::path::Path;
+///
+/// assert_eq!(format_from_extension(Path::new("eindhoven.json")), Some(DataFormat::OsmJson));
+/// assert_eq!(format_from_extension(Path::new("extract.osm.json.gz")), Some(DataFormat::OsmJson));
+/// assert_eq!(format_from_extension(Path::new("parks.geojson.gz")), Some(DataFormat::GeoJson));
+/// assert_eq!(format_from_extension(Path::new("eindhoven.citybin")), Some(DataFormat::CityBin));
+/// assert_eq!(format_from_extension(Path::new("eindhoven.citybin.gz")), None);
+/// assert_eq!(format_from_extension(Path::new("extract.osm.pbf")), None);
+/// assert_eq!(format_from_extension(Path::new("archive.gz")), None);
+/// ```
+pub fn format_from_extension(file_path: &Path) -> Option<DataFormat> {
+    let format_path = match file_path.extension() {
+        Some(ext) if ext == "gz" => file_path.with_extension(""),
+        Some(ext) if ext == "citybin" => return Some(DataFormat::CityBin),
+        _ => file_path.to_owned(),
+    };
+    match format_path.extension() {
+        Some(ext) if ext == "json" => Some(DataFormat::OsmJson),
+        Some(ext) if ext == "geojson" => Some(DataFormat::GeoJson),
+        _ => None,
+    }
+}
+
+/// Parses coordinates entered by the user as `latitude, longitude`, in
+/// degrees. Links to Google Maps and similar sites are also accepted, which
+/// have the coordinates after an `@` or `q=`.
+///
+/// ```
+/// use city_visualizer::data::query::parse_coordinates;
+///
+/// let location = parse_coordinates("52.3676, 4.9041").unwrap();
+/// assert_eq!((location.latitude, location.longitude), (52.3676, 4.9041));
+///
+/// let location = parse_coordinates(" -33.8688  151.2093 ").unwrap();
+/// assert_eq!((location.latitude, location.longitude), (-33.8688, 151.2093));
+///
+/// let location = parse_coordinates("https://www.google.com/maps/@51.4416,5.4697,15z").unwrap();
+/// assert_eq!((location.latitude, location.longitude), (51.4416, 5.4697));
+///
+/// let location = parse_coordinates("https://maps.google.com/?q=40.7128,-74.0060&z=12").unwrap();
+/// assert_eq!((location.latitude, location.longitude), (40.7128, -74.006));
+///
+/// assert!(parse_coordinates("").is_err());
+/// assert!(parse_coordinates("52.3676").is_err());
+/// assert!(parse_coordinates("Amsterdam").is_err());
+/// assert!(parse_coordinates("91.0, 4.9").is_err());
+/// assert!(parse_coordinates("52.3, 181.0").is_err());
+/// ```
+pub fn parse_coordinates(string: &str) -> Result<GeoLocation, AppError> {
+    let string = string.trim();
+    let coordinates = if let Some(index) = string.rfind('@') {
+        &string[index + 1..]
+    } else if let Some(index) = string.find("q=") {
+        &string[index + 2..]
+    } else {
+        string
+    };
+    // other parameters of a link follow after the coordinates
+    let coordinates = coordinates.split(|c| c == '&' || c == '/').next().unwrap_or("");
+
+    let mut values = coordinates
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|value| !value.is_empty());
+    let expected = "\"latitude, longitude\"";
+    let latitude = parse_coordinate(values.next(), "latitude", 90.0, expected)?;
+    let longitude = parse_coordinate(values.next(), "longitude", 180.0, expected)?;
+    Ok(GeoLocation { longitude, latitude })
+}
+
+/// Parses a single latitude or longitude, which should be at most `max`
+/// degrees from 0. `expected` describes all values, for when it is missing.
+fn parse_coordinate(value: Option<&str>, name: &str, max: f64, expected: &str) -> Result<f64, AppError> {
+    let Some(value) = value else {
+        return Err(AppError::InputSyntax {
+            message: format!("expected {}, {} is missing", expected, name),
+        });
+    };
+    match value.parse::<f64>() {
+        Ok(degrees) if degrees.abs() <= max => Ok(degrees),
+        Ok(degrees) => Err(AppError::InputSyntax {
+            message: format!("{} {} is out of range, it should be between -{} and {}", name, degrees, max, max),
+        }),
+        Err(_) => Err(AppError::InputSyntax {
+            message: format!("{} \"{}\" is not a number", name, value),
+        }),
+    }
+}
@@ -1,7 +1,7 @@
 /// This module defines the `RoadType` enum and the `Road` struct.
 /// 
 /// `RoadType` is an enumeration of the different types of roads that can be encountered in the dataset.
-/// It includes types such as Primary, Secondary, Residential, Footway, Path, and Unclassified.
+/// It includes types such as Primary, Secondary, Residential, Service, Footway, Path, and Unclassified.
 ///
 /// `Road` is a struct that represents a road. It includes a `RoadType`, a `width`, and a `color`.
 /// The `width` and `color` attributes are used for rendering the road.
@@ -51,8 +51,18 @@ pub enum RoadType {
     TertiaryLink, // https://wiki.openstreetmap.org/wiki/Tag:highway%3Dtertiary_link
 
     /// **SPECIAL ROAD TYPES**
-    /// Ignored for now
-    /// Living_street, Pedestrian are the most important ones
+
+    // For living streets, which are residential streets where pedestrians have legal priority over cars, which have a very low speed limit
+    LivingStreet, // https://wiki.openstreetmap.org/wiki/Tag:highway%3Dliving_street
+
+    // For access roads to, or within an industrial estate, camp site, business park, car park, alleys, etc.
+    Service, // https://wiki.openstreetmap.org/wiki/Tag:highway%3Dservice
+
+    // For roads used mainly or exclusively for pedestrians in shopping and some residential areas
+    Pedestrian, // https://wiki.openstreetmap.org/wiki/Tag:highway%3Dpedestrian
+
+    // Roads for mostly agricultural or forestry uses
+    Track, // https://wiki.openstreetmap.org/wiki/Tag:highway%3Dtrack
 
     /// **PATHS**
     /// A path mainly or exclusively for pedestrians.
@@ -61,6 +71,9 @@ pub enum RoadType {
     // For designated footpaths; mainly/exclusively for pedestrian (and for bikes ofc ;) )
     Footway, // https://wiki.openstreetmap.org/wiki/Tag:highway%3Dfootway
 
+    // For designated cycleways, which pedestrians may usually use as well
+    Cycleway, // https://wiki.openstreetmap.org/wiki/Tag:highway%3Dcycleway
+
     // For flights of steps (stairs) on footways. Use with step_count=* to indicate the number of steps
     Steps, // https://wiki.openstreetmap.org/wiki/Tag:highway%3Dsteps
 
@@ -93,7 +106,12 @@ impl FromStr for RoadType {
             "primary_link" => Ok(RoadType::PrimaryLink),
             "secondary_link" => Ok(RoadType::SecondaryLink),
             "tertiary_link" => Ok(RoadType::TertiaryLink),
+            "living_street" => Ok(RoadType::LivingStreet),
+            "service" => Ok(RoadType::Service),
+            "pedestrian" => Ok(RoadType::Pedestrian),
+            "track" => Ok(RoadType::Track),
             "footway" => Ok(RoadType::Footway),
+            "cycleway" => Ok(RoadType::Cycleway),
             "steps" => Ok(RoadType::Steps),
             "path" => Ok(RoadType::Path),
             _ =>  Ok(RoadType::NotCovered),
@@ -101,7 +119,45 @@ impl FromStr for RoadType {
     }
 }
 
-/// Represents the width of 1 lane of the road.
+/// Returns the value of the highway tag of a road type, the inverse of
+/// parsing it. Roads of types that are not covered are called "other".
+///
+/// ```
+/// use city_visualizer::data::road_type::{road_type_to_tag, RoadType};
+/// use strum::IntoEnumIterator;
+///
+/// for road_type in RoadType::iter().filter(|&road_type| road_type != RoadType::NotCovered) {
+///     assert_eq!(road_type_to_tag(&road_type).parse::<RoadType>(), Ok(road_type));
+/// }
+/// assert_eq!(road_type_to_tag(&RoadType::NotCovered), "other");
+/// ```
+pub fn road_type_to_tag(road_type: &RoadType) -> &'static str {
+    match road_type {
+        RoadType::Motorway => "motorway",
+        RoadType::Trunk => "trunk",
+        RoadType::Primary => "primary",
+        RoadType::Secondary => "secondary",
+        RoadType::Tertiary => "tertiary",
+        RoadType::Residential => "residential",
+        RoadType::Unclassified => "unclassified",
+        RoadType::MotorwayLink => "motorway_link",
+        RoadType::TrunkLink => "trunk_link",
+        RoadType::PrimaryLink => "primary_link",
+        RoadType::SecondaryLink => "secondary_link",
+        RoadType::TertiaryLink => "tertiary_link",
+        RoadType::LivingStreet => "living_street",
+        RoadType::Service => "service",
+        RoadType::Pedestrian => "pedestrian",
+        RoadType::Track => "track",
+        RoadType::Footway => "footway",
+        RoadType::Cycleway => "cycleway",
+        RoadType::Steps => "steps",
+        RoadType::Path => "path",
+        RoadType::NotCovered => "other",
+    }
+}
+
+/// Represents the width of 1 lane of the road, in meters.
 /// TODO bigger should be better
 pub fn road_type_to_width(road_type: &RoadType) -> f32 {
     match road_type {
@@ -116,7 +172,12 @@ pub fn road_type_to_width(road_type: &RoadType) -> f32 {
         RoadType::PrimaryLink => 3.75,  // In between primary and secondary
         RoadType::SecondaryLink => 3.75, // In between secondary and tertiary
         RoadType::TertiaryLink => 3.75, // Same as tertiary
+        RoadType::LivingStreet => 3.0,
+        RoadType::Service => 2.5, // Narrow access roads
+        RoadType::Pedestrian => 3.0, // Pedestrian, but as wide as a street
+        RoadType::Track => 2.5,
         RoadType::Footway => 0.5, // Pedestrian
+        RoadType::Cycleway => 1.0,
         RoadType::Steps => 0.5, // Pedestrian
         RoadType::Path => 0.5,  // Pedestrian
         RoadType::Unclassified => 3.0,
@@ -126,6 +187,35 @@ pub fn road_type_to_width(road_type: &RoadType) -> f32 {
     }
 }
 
+/// How far the drawn road may deviate from its nodes when the road is
+/// simplified, in meters. Fast roads have long, smooth curves with many
+/// nodes, while paths are narrow and wind more.
+pub fn road_type_to_simplification_tolerance(road_type: &RoadType) -> f32 {
+    match road_type {
+        RoadType::Motorway => 0.5,
+        RoadType::Trunk => 0.5,
+        RoadType::Primary => 0.3,
+        RoadType::Secondary => 0.3,
+        RoadType::Tertiary => 0.3,
+        RoadType::Residential => 0.25,
+        RoadType::MotorwayLink => 0.5,
+        RoadType::TrunkLink => 0.5,
+        RoadType::PrimaryLink => 0.3,
+        RoadType::SecondaryLink => 0.3,
+        RoadType::TertiaryLink => 0.3,
+        RoadType::LivingStreet => 0.25,
+        RoadType::Service => 0.2,
+        RoadType::Pedestrian => 0.25,
+        RoadType::Track => 0.25,
+        RoadType::Footway => 0.1,
+        RoadType::Cycleway => 0.1,
+        RoadType::Steps => 0.1,
+        RoadType::Path => 0.1,
+        RoadType::Unclassified => 0.25,
+        RoadType::NotCovered => 0.1,
+    }
+}
+
 /// Map a road type to a height range.
 /// This is used to randomly pick height in between to prevent z-fighting (actually y-fighting)
 /// Roads are always between 0.01 and 0.02
@@ -143,7 +233,12 @@ fn road_type_to_height_range(road_type: &RoadType) -> (f32, f32) {
         RoadType::PrimaryLink => (0.010, 0.013),
         RoadType::SecondaryLink => (0.010, 0.013),
         RoadType::TertiaryLink => (0.010, 0.013),
+        RoadType::LivingStreet => (0.010, 0.013),
+        RoadType::Service => (0.010, 0.013),
+        RoadType::Pedestrian => (0.017, 0.02),
+        RoadType::Track => (0.010, 0.013),
         RoadType::Footway => (0.017, 0.02),
+        RoadType::Cycleway => (0.017, 0.02),
         RoadType::Steps => (0.017, 0.02),
         RoadType::Path => (0.017, 0.02),
         RoadType::Unclassified => (0.010, 0.013),
@@ -172,7 +267,12 @@ pub fn road_type_to_default_lanes(road_type: &RoadType) -> u32 {
         RoadType::PrimaryLink => 1, // Links to primary roads, typically one lane
         RoadType::SecondaryLink => 1, // Links to secondary roads, typically one lane
         RoadType::TertiaryLink => 1, // Links to tertiary roads, typically one lane
+        RoadType::LivingStreet => 1, // Living streets are shared by all traffic
+        RoadType::Service => 1, // Service roads are narrow access roads
+        RoadType::Pedestrian => 1, // Pedestrian streets have no lanes
+        RoadType::Track => 1, // Tracks are unpaved and narrow
         RoadType::Footway => 1, // Pedestrian paths have no lanes
+        RoadType::Cycleway => 1, // Cycleways usually have a single lane
         RoadType::Steps => 1, // Steps are for pedestrians and have no lanes
         RoadType::Path => 1, // Paths are for pedestrians and have no lanes
         RoadType::Unclassified => 1, // Unclassified roads can vary but default to one lane
@@ -195,7 +295,12 @@ pub fn road_type_to_color(road_type: &RoadType) -> Color {
         RoadType::PrimaryLink => Color::rgba(0.8, 0.8, 0.0, 1.0), // Dark yellow
         RoadType::SecondaryLink => Color::rgba(0.0, 0.0, 0.8, 1.0), // Dark blue
         RoadType::TertiaryLink => Color::rgba(0.0, 0.8, 0.0, 1.0), // Dark green
+        RoadType::LivingStreet => Color::rgba(0.9, 0.9, 0.9, 1.0), // Off-white, like residential
+        RoadType::Service => Color::rgba(0.7, 0.7, 0.7, 1.0), // Light grey
+        RoadType::Pedestrian => Color::rgba(0.8, 0.75, 0.7, 1.0), // Paving stones
+        RoadType::Track => Color::rgba(0.6, 0.45, 0.3, 1.0), // Brown, like dirt
         RoadType::Footway => Color::rgba(0.6, 0.6, 0.6, 1.0), // Light grey
+        RoadType::Cycleway => Color::rgba(0.7, 0.3, 0.3, 1.0), // Red asphalt
         RoadType::Steps => Color::rgba(0.55, 0.55, 0.55, 1.0), // Grey
         RoadType::Path => Color::rgba(0.75, 0.75, 0.75, 1.0), // Silver
         RoadType::Unclassified => Color::rgba(1.0, 1.0, 0.4, 1.0), // Yellow to indicate unclassified
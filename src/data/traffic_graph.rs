@@ -1,39 +1,255 @@
-use std::{collections::HashMap, convert::Infallible};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    fmt::Write,
+    path::Path,
+};
 use wasm_bindgen::prelude::*;
 
 use bevy::{
-    ecs::system::{ResMut, Resource},
+    ecs::system::Resource,
     math::Vec2,
 };
 
 use petgraph::{
-    graph::{Graph, NodeIndex},
-    Directed,
+    graph::{EdgeIndex, NodeIndex},
+    stable_graph::StableGraph,
+    visit::{EdgeFiltered, EdgeRef},
+    Directed, Direction,
 };
+use rand::Rng;
 
 use crate::earth::agent::{agent_speed_on_road_type, AgentType, REFERENCE_SPEED};
+use crate::earth::roads::BOUNDARY_MARGIN;
+use crate::earth::SIZE_EXAGGERATION;
 
 use super::{
-    geography::{GeoLocation, Offset, RoadFeature},
-    road_type::RoadType,
-}; // maybe use StableGraph in the future if we want to delete singular edges/nodes
+    geography::{ChunkIndex, GeoLocation, LoadedBounds, Offset, RoadFeature, WorldScale},
+    road_type::{road_type_to_tag, road_type_to_width, RoadType},
+};
 
 /// The cost multiplier for disallowed edges for their agent type.
 /// This is a very high number to discourage agents from using these edges.
-const COST_MULTIPLIER_DISALLOWED: f32 = 100.0;
+pub const COST_MULTIPLIER_DISALLOWED: f32 = 100.0;
+
+/// How often a random destination is drawn before settling for a non-destination node.
+const DESTINATION_ATTEMPTS: usize = 10;
+
+/// The size of the cells of the grid in which vertices are stored by location,
+/// in world units at every scale. Only affects how fast lookups are.
+const NODE_CELL_SIZE: f32 = 100.0;
+
+/// The data of an edge in the traffic graph: a piece of road between two
+/// vertices.
+///
+/// ```
+/// use city_visualizer::data::geography::WorldScale;
+/// use city_visualizer::data::road_type::RoadType;
+/// use city_visualizer::data::traffic_graph::EdgeData;
+/// use city_visualizer::earth::agent::AgentType;
+///
+/// let footway = EdgeData::new(10.0, RoadType::Footway, true);
+/// let residential = EdgeData::new(10.0, RoadType::Residential, true);
+///
+/// // cars may not use footways, and are faster than pedestrians
+/// assert!(footway.cost_for(AgentType::Car) > residential.cost_for(AgentType::Car));
+/// assert!(residential.cost_for(AgentType::Car) < residential.cost_for(AgentType::Pedestrian));
+///
+/// // on two-way roads agents keep to their side of the road
+/// let scale = WorldScale::default();
+/// assert!(residential.lane_offset(false, &scale) > 0.0);
+/// assert_eq!(residential.lane_offset(true, &scale), -residential.lane_offset(false, &scale));
+/// assert_eq!(EdgeData::new(10.0, RoadType::Residential, false).lane_offset(false, &scale), 0.0);
+///
+/// // lanes and speeds grow with the scale of the world
+/// let meters = WorldScale { units_per_meter: 1.0 };
+/// assert_eq!(residential.lane_offset(false, &meters), 4.0 * residential.lane_offset(false, &scale));
+/// assert_eq!(residential.speed_for(AgentType::Car, &meters), 4.0 * residential.speed_for(AgentType::Car, &scale));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EdgeData {
+    /// The length of the edge in the plane
+    length: f32,
+    road_type: RoadType,
+    /// Whether the road can be traveled in both directions
+    two_way: bool,
+    /// Which agent types may use the road at all
+    access: Access,
+}
+
+impl EdgeData {
+    pub fn new(length: f32, road_type: RoadType, two_way: bool) -> Self {
+        EdgeData {
+            length,
+            road_type,
+            two_way,
+            access: Access::ALL,
+        }
+    }
+
+    /// Restricts which agent types may use the edge.
+    pub fn with_access(mut self, access: Access) -> Self {
+        self.access = access;
+        self
+    }
+
+    pub fn access(&self) -> Access {
+        self.access
+    }
+
+    pub fn length(&self) -> f32 {
+        self.length
+    }
+
+    pub fn road_type(&self) -> RoadType {
+        self.road_type
+    }
+
+    pub fn is_two_way(&self) -> bool {
+        self.two_way
+    }
+
+    /// Returns how far agents travel to the right of the middle of the road,
+    /// looking in the direction of travel. On two-way roads this is half a
+    /// lane, so agents going in opposite directions do not overlap. With
+    /// left-hand traffic the offset is negative, to the left of the middle.
+    /// Agents on one-way roads travel in the middle.
+    pub fn lane_offset(&self, left_hand_traffic: bool, scale: &WorldScale) -> f32 {
+        if !self.two_way {
+            return 0.0;
+        }
+        let offset = scale.meters(road_type_to_width(&self.road_type)) * SIZE_EXAGGERATION / 2.0;
+        if left_hand_traffic {
+            -offset
+        } else {
+            offset
+        }
+    }
+
+    /// Returns the speed of an agent of the given type on this edge, in world
+    /// units per second at the given scale.
+    pub fn speed_for(&self, agent_type: AgentType, scale: &WorldScale) -> f32 {
+        agent_speed_on_road_type(scale.units(REFERENCE_SPEED), agent_type, self.road_type)
+    }
+
+    /// Returns the cost of traveling over this edge for path finding, which
+    /// is proportional to the travel time, much higher if the agent type is
+    /// not allowed on the road.
+    pub fn cost_for(&self, agent_type: AgentType) -> f32 {
+        let mut cost = self.length;
+        if !road_type_allowed_for_agent_type(self.road_type, agent_type) {
+            cost *= COST_MULTIPLIER_DISALLOWED;
+        }
+        cost / self.speed_for(agent_type, &WorldScale::default())
+    }
+}
+
+/// Which agent types may use a road according to its access tags, such as
+/// `access=private`. Unlike road types that an agent type should avoid, roads
+/// that an agent type may not use are never part of its paths.
+///
+/// ```
+/// use city_visualizer::data::traffic_graph::Access;
+/// use city_visualizer::earth::agent::AgentType;
+/// use std::collections::HashMap;
+///
+/// let tags = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+///     pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+/// };
+///
+/// let driveway = Access::from_tags(&tags(&[("highway", "service"), ("access", "private")]));
+/// assert!(!driveway.allows(AgentType::Car));
+/// assert!(!driveway.allows(AgentType::Pedestrian));
+///
+/// // more specific tags override `access`
+/// let gated = Access::from_tags(&tags(&[("access", "no"), ("foot", "yes")]));
+/// assert!(gated.allows(AgentType::Pedestrian));
+/// assert!(!gated.allows(AgentType::Bicycle));
+///
+/// let bus_lane = Access::from_tags(&tags(&[("motor_vehicle", "no")]));
+/// assert!(!bus_lane.allows(AgentType::Car));
+/// assert!(bus_lane.allows(AgentType::Bicycle));
+///
+/// assert_eq!(Access::from_tags(&tags(&[("highway", "residential")])), Access::ALL);
+/// ```
+///
+/// # See also
+/// https://wiki.openstreetmap.org/wiki/Key:access
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Access {
+    pub cars: bool,
+    pub bicycles: bool,
+    pub pedestrians: bool,
+}
+
+impl Access {
+    /// Access for all agent types, for roads without access tags.
+    pub const ALL: Access = Access {
+        cars: true,
+        bicycles: true,
+        pedestrians: true,
+    };
+
+    /// Reads the access tags of a road, where more specific tags (e.g.
+    /// `motor_vehicle`) override more general ones (e.g. `access`).
+    pub fn from_tags(tags: &HashMap<String, String>) -> Self {
+        let tag = |key: &str| tags.get(key).and_then(|value| is_access_allowed(value));
+        let general = tag("access").unwrap_or(true);
+        let vehicle = tag("vehicle").unwrap_or(general);
+        Access {
+            cars: tag("motor_vehicle").unwrap_or(vehicle),
+            bicycles: tag("bicycle").unwrap_or(vehicle),
+            pedestrians: tag("foot").unwrap_or(general),
+        }
+    }
+
+    pub fn allows(&self, agent_type: AgentType) -> bool {
+        match agent_type {
+            AgentType::Car => self.cars,
+            AgentType::Bicycle => self.bicycles,
+            AgentType::Pedestrian => self.pedestrians,
+        }
+    }
+}
+
+/// Returns whether the value of an access tag allows the public to use the
+/// road, or None if the value is unknown.
+pub fn is_access_allowed(value: &str) -> Option<bool> {
+    match value {
+        "private" | "no" => Some(false),
+        "yes" | "permissive" | "designated" | "destination" | "customers" | "delivery" => Some(true),
+        _ => None,
+    }
+}
 
 /// Directed graph structure for agents to travel in the world.
+///
+/// A `StableGraph` is used, so that removing the roads of a chunk, or
+/// replacing the edges of a road that is loaded again, does not invalidate the
+/// node indices held by agents.
 #[derive(Debug, Resource, Clone)]
 pub struct TrafficGraph {
-    graph: Graph<Vec2, (f32, RoadType), Directed, u32>, // Vertices hold their location in the plane, edges weighted by distance (and holds road type)
-    hashmap: HashMap<u64, NodeIndex<u32>>,              // Maps OSM vertex IDs to graph indices
+    graph: StableGraph<Vec2, EdgeData, Directed, u32>,       // Vertices hold their location in the plane, edges hold their length and road type
+    hashmap: HashMap<u64, NodeIndex<u32>>,                    // Maps OSM vertex IDs to graph indices
+    osm_ids: HashMap<NodeIndex<u32>, u64>,                    // Maps graph indices back to OSM vertex IDs
+    generation: u32,                                          // Bumped on every reset, so stale node indices can be detected
+    non_destinations: HashSet<NodeIndex<u32>>,                // Vertices agents should not travel towards, e.g. roads cut off at the data boundary
+    way_edges: HashMap<u64, Vec<EdgeIndex<u32>>>,             // Maps OSM way IDs to the edges they contributed
+    chunk_ways: HashMap<ChunkIndex, HashSet<u64>>,            // Maps chunks to the OSM way IDs of the roads in them
+    node_cells: HashMap<(i32, i32), Vec<NodeIndex<u32>>>,     // Maps grid cells to the vertices in them, to find vertices near a location
 }
 
 impl Default for TrafficGraph {
     fn default() -> Self {
         TrafficGraph {
-            graph: Graph::new(),
+            graph: StableGraph::new(),
             hashmap: HashMap::new(),
+            osm_ids: HashMap::new(),
+            generation: 0,
+            non_destinations: HashSet::new(),
+            way_edges: HashMap::new(),
+            chunk_ways: HashMap::new(),
+            node_cells: HashMap::new(),
         }
     }
 }
@@ -46,11 +262,14 @@ impl TrafficGraph {
         } else {
             let index = self.graph.add_node(location);
             self.hashmap.insert(osm_id, index);
+            self.osm_ids.insert(index, osm_id);
+            self.node_cells.entry(node_cell(location)).or_default().push(index);
             index
         }
     }
 
     /// Add an edge to the graph. Represents a way to travel between two vertices.
+    /// Returns the indices of the edges that were added.
     pub fn add_connection(
         &mut self,
         from_index: u64,
@@ -59,64 +278,182 @@ impl TrafficGraph {
         to_location: Vec2,
         oneway: OneWay,
         road_type: RoadType,
-    ) {
+        access: Access,
+    ) -> Vec<EdgeIndex<u32>> {
         // Calculate the Euclidean distance between the two vertices
         let distance = (from_location - to_location).length();
+        let edge_data = EdgeData::new(distance, road_type, oneway == OneWay::No).with_access(access);
 
-        // We use update instead of add to not allow parallel edges
+        // Edges are always added, the caller tracks them per way with
+        // `track_way_edges`, so a road that is loaded again replaces its edges
         let from_index = self.add_node(from_index, from_location);
         let to_index = self.add_node(to_index, to_location);
         match oneway {
             OneWay::Yes => {
-                self.graph
-                    .add_edge(from_index, to_index, (distance, road_type));
+                vec![self.graph.add_edge(from_index, to_index, edge_data)]
             }
             OneWay::No => {
-                self.graph
-                    .add_edge(from_index, to_index, (distance, road_type));
-                self.graph
-                    .add_edge(to_index, from_index, (distance, road_type));
+                vec![
+                    self.graph.add_edge(from_index, to_index, edge_data),
+                    self.graph.add_edge(to_index, from_index, edge_data),
+                ]
             }
             OneWay::Reversed => {
-                self.graph
-                    .add_edge(to_index, from_index, (distance, road_type));
+                vec![self.graph.add_edge(to_index, from_index, edge_data)]
             }
         }
     }
 
+    /// Records that the given edges were contributed by the road with OSM way
+    /// ID `way_id` in chunk `chunk`, so they can be removed again when the road
+    /// is loaded again or the chunk is removed.
+    pub fn track_way_edges(&mut self, chunk: &ChunkIndex, way_id: u64, edges: Vec<EdgeIndex<u32>>) {
+        self.way_edges.entry(way_id).or_default().extend(edges);
+        self.chunk_ways.entry(chunk.clone()).or_default().insert(way_id);
+    }
+
+    /// Removes all edges contributed by the road with the given OSM way ID.
+    /// Vertices are kept, so node indices stay valid.
+    ///
+    /// ```
+    /// use bevy::math::Vec2;
+    /// use city_visualizer::data::geography::ChunkIndex;
+    /// use city_visualizer::data::road_type::RoadType;
+    /// use city_visualizer::data::traffic_graph::{Access, OneWay, TrafficGraph};
+    /// use city_visualizer::earth::agent::AgentType;
+    ///
+    /// let mut graph = TrafficGraph::default();
+    /// let add_way = |graph: &mut TrafficGraph| {
+    ///     let edges = graph.add_connection(1, Vec2::ZERO, 2, Vec2::X, OneWay::No, RoadType::Residential, Access::ALL);
+    ///     graph.track_way_edges(&ChunkIndex { x: 0, z: 0 }, 10, edges);
+    /// };
+    ///
+    /// add_way(&mut graph);
+    /// let (from, to) = (graph.get_index(1).unwrap(), graph.get_index(2).unwrap());
+    /// assert_eq!(graph.get_edge_count(), 2);
+    ///
+    /// graph.remove_way_edges(10);
+    /// assert_eq!(graph.get_edge_count(), 0);
+    /// assert!(graph.get_shortest_path(from, to, AgentType::Car).is_none());
+    ///
+    /// // the vertices are reused and the edges are not duplicated
+    /// add_way(&mut graph);
+    /// assert_eq!((graph.get_index(1), graph.get_index(2)), (Some(from), Some(to)));
+    /// assert_eq!((graph.get_size(), graph.get_edge_count()), (2, 2));
+    /// assert_eq!(graph.get_shortest_path(from, to, AgentType::Car), Some(vec![from, to]));
+    ///
+    /// // only the edges of the last time the way was added are tracked
+    /// graph.remove_way_edges(10);
+    /// assert_eq!(graph.get_edge_count(), 0);
+    /// ```
+    pub fn remove_way_edges(&mut self, way_id: u64) {
+        for edge in self.way_edges.remove(&way_id).unwrap_or_default() {
+            self.graph.remove_edge(edge);
+        }
+    }
+
+    /// Removes all edges contributed by the roads in the given chunk.
+    /// Vertices are kept, so node indices held by agents stay valid.
+    pub fn remove_chunk_edges(&mut self, chunk: &ChunkIndex) {
+        for way_id in self.chunk_ways.remove(chunk).unwrap_or_default() {
+            self.remove_way_edges(way_id);
+        }
+    }
+
+    /// Removes all vertices that are not connected to any edge anymore, e.g.
+    /// after removing the edges of chunks.
+    ///
+    /// The indices of removed vertices are reused, so the generation is bumped
+    /// if any vertex is removed, like on a reset.
+    pub fn compact(&mut self) {
+        let isolated: Vec<NodeIndex<u32>> = self.graph
+            .node_indices()
+            .filter(|&node| self.graph.neighbors_undirected(node).next().is_none())
+            .collect();
+        if isolated.is_empty() {
+            return;
+        }
+        for node in &isolated {
+            self.graph.remove_node(*node);
+            self.non_destinations.remove(node);
+            self.osm_ids.remove(node);
+        }
+        let graph = &self.graph;
+        self.hashmap.retain(|_, index| graph.contains_node(*index));
+        for nodes in self.node_cells.values_mut() {
+            nodes.retain(|index| graph.contains_node(*index));
+        }
+        self.node_cells.retain(|_, nodes| !nodes.is_empty());
+        self.generation = self.generation.wrapping_add(1);
+    }
+
     /// Get the index of a vertex in the graph for a given OSM node.
     pub fn get_index(&self, osm_id: u64) -> Option<NodeIndex<u32>> {
         self.hashmap.get(&osm_id).copied()
     }
 
-    // Get the shortest path between two vertices in the graph, based on their node IDs
+    /// Get the OSM node of a vertex in the graph.
+    pub fn get_osm_id(&self, index: NodeIndex<u32>) -> Option<u64> {
+        self.osm_ids.get(&index).copied()
+    }
+
+    /// Get the shortest path between two vertices in the graph, over the
+    /// edges that the agent type may use.
+    ///
+    /// ```
+    /// use bevy::math::Vec2;
+    /// use city_visualizer::data::road_type::RoadType;
+    /// use city_visualizer::data::traffic_graph::{Access, OneWay, TrafficGraph};
+    /// use city_visualizer::earth::agent::AgentType;
+    ///
+    /// // a private driveway is the short way from 0 to 2, a public street
+    /// // around it the long way
+    /// let private = Access { cars: false, bicycles: false, pedestrians: false };
+    /// let mut graph = TrafficGraph::default();
+    /// let (a, b, c, d) = (Vec2::new(0.0, 0.0), Vec2::new(10.0, 0.0), Vec2::new(20.0, 0.0), Vec2::new(10.0, 50.0));
+    /// graph.add_connection(0, a, 1, b, OneWay::No, RoadType::Service, private);
+    /// graph.add_connection(1, b, 2, c, OneWay::No, RoadType::Service, private);
+    /// graph.add_connection(0, a, 3, d, OneWay::No, RoadType::Residential, Access::ALL);
+    /// graph.add_connection(3, d, 2, c, OneWay::No, RoadType::Residential, Access::ALL);
+    ///
+    /// let (from, to) = (graph.get_index(0).unwrap(), graph.get_index(2).unwrap());
+    /// let path = graph.get_shortest_path(from, to, AgentType::Car).unwrap();
+    /// assert_eq!(path, vec![from, graph.get_index(3).unwrap(), to]);
+    ///
+    /// // the driveway itself cannot be reached by car
+    /// assert!(graph.get_shortest_path(from, graph.get_index(1).unwrap(), AgentType::Car).is_none());
+    /// ```
     pub fn get_shortest_path(
         &self,
         from_index: NodeIndex,
         to_index: NodeIndex,
         agent_type: AgentType,
+    ) -> Option<Vec<NodeIndex>> {
+        self.get_shortest_path_with_cost(from_index, to_index, agent_type, |edge| {
+            edge.cost_for(agent_type)
+        })
+    }
+
+    /// Returns the shortest path like `get_shortest_path`, over the edges
+    /// that the agent type may use, but with the cost of every edge given by
+    /// `cost` instead of `EdgeData::cost_for`.
+    pub fn get_shortest_path_with_cost(
+        &self,
+        from_index: NodeIndex,
+        to_index: NodeIndex,
+        agent_type: AgentType,
+        cost: impl Fn(&EdgeData) -> f32,
     ) -> Option<Vec<NodeIndex>> {
         let goal_location = self.graph[to_index];
+        let allowed_edges = EdgeFiltered::from_fn(&self.graph, |edge| {
+            edge.weight().access.allows(agent_type)
+        });
 
         let path = petgraph::algo::astar(
-            &self.graph,
+            &allowed_edges,
             from_index,
             |node| node == to_index,
-            |edge| {
-                let road_type = edge.weight().1;
-                let mut weight = edge.weight().0; // Starting weight is the distance
-
-                // See if road type is allowed for agent type
-                if !road_type_allowed_for_agent_type(road_type, agent_type) {
-                    weight = weight * COST_MULTIPLIER_DISALLOWED;
-                }
-
-                // Account for speed multiplier
-                weight = weight / agent_speed_on_road_type(REFERENCE_SPEED, agent_type, road_type);
-
-                // return
-                weight
-            },
+            |edge| cost(edge.weight()),
             |node| {
                 let location = self.graph[node];
                 (goal_location - location).length()
@@ -129,30 +466,436 @@ impl TrafficGraph {
     pub fn reset(&mut self) {
         self.graph.clear();
         self.hashmap.clear();
+        self.osm_ids.clear();
+        self.non_destinations.clear();
+        self.way_edges.clear();
+        self.chunk_ways.clear();
+        self.node_cells.clear();
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    /// Returns the generation of the graph, which changes every time the graph
+    /// is reset. Node indices are only valid within the generation they were
+    /// obtained in.
+    pub fn get_generation(&self) -> u32 {
+        self.generation
     }
 
     pub fn get_size(&self) -> usize {
         self.graph.node_count()
     }
 
+    pub fn get_edge_count(&self) -> usize {
+        self.graph.edge_count()
+    }
+
     pub fn get_node_location(&self, index: NodeIndex) -> Vec2 {
         self.graph[index]
     }
 
-    pub fn get_random_node_index(&self) -> NodeIndex {
-        let index = rand::random::<usize>() % self.graph.node_count();
+    /// Moves all vertices by `-shift`, along with the world when its origin is
+    /// moved by `shift`.
+    pub fn shift(&mut self, shift: Vec2) {
+        for location in self.graph.node_weights_mut() {
+            *location -= shift;
+        }
+        self.node_cells.clear();
+        for index in self.graph.node_indices() {
+            self.node_cells.entry(node_cell(self.graph[index])).or_default().push(index);
+        }
+    }
+
+    pub fn get_random_node_index(&self, rng: &mut impl Rng) -> NodeIndex {
+        let index = rng.gen_range(0..self.graph.node_count());
         let node = self.graph.node_indices().nth(index).unwrap_throw();
         node
     }
 
-    pub fn get_road_type(&self, from_index: NodeIndex, to_index: NodeIndex) -> RoadType {
-        let edge = self.graph.find_edge(from_index, to_index);
-        match edge {
-            Some(edge) => {
-                let road_type = self.graph[edge].1;
-                road_type
+    /// Returns a random vertex that agents may travel towards. Vertices marked
+    /// as non-destinations are avoided, unless no other vertex is found after
+    /// a few attempts.
+    pub fn get_random_destination_node_index(&self, rng: &mut impl Rng) -> NodeIndex {
+        for _ in 0..DESTINATION_ATTEMPTS {
+            let node = self.get_random_node_index(rng);
+            if !self.non_destinations.contains(&node) {
+                return node;
+            }
+        }
+        self.get_random_node_index(rng)
+    }
+
+    /// Returns all vertices at most `radius` away from `center`.
+    pub fn nodes_within_radius(&self, center: Vec2, radius: f32) -> Vec<NodeIndex> {
+        let (min_x, min_z) = node_cell(center - Vec2::splat(radius));
+        let (max_x, max_z) = node_cell(center + Vec2::splat(radius));
+        let mut nodes = Vec::new();
+        for x in min_x..=max_x {
+            for z in min_z..=max_z {
+                let Some(cell) = self.node_cells.get(&(x, z)) else {
+                    continue;
+                };
+                nodes.extend(
+                    cell.iter()
+                        .filter(|&&index| (self.graph[index] - center).length() <= radius),
+                );
+            }
+        }
+        nodes
+    }
+
+    /// Returns the vertex with an edge closest to `location`, if there is one
+    /// at most `max_distance` away. The grid of vertices is searched in rings
+    /// of cells around the location, so far away vertices are not looked at.
+    ///
+    /// ```
+    /// use bevy::math::Vec2;
+    /// use city_visualizer::data::road_type::RoadType;
+    /// use city_visualizer::data::traffic_graph::{Access, OneWay, TrafficGraph};
+    ///
+    /// // a road with a vertex every 150 units, across several cells
+    /// let mut graph = TrafficGraph::default();
+    /// for id in 0..10u64 {
+    ///     let from = Vec2::new(id as f32 * 150.0, 0.0);
+    ///     let to = Vec2::new((id + 1) as f32 * 150.0, 0.0);
+    ///     graph.add_connection(id, from, id + 1, to, OneWay::No, RoadType::Residential, Access::ALL);
+    /// }
+    ///
+    /// let nearest = graph.nearest_node(Vec2::new(460.0, 30.0), 500.0).unwrap();
+    /// assert_eq!(graph.get_osm_id(nearest), Some(3));
+    /// let nearest = graph.nearest_node(Vec2::new(-120.0, -250.0), 500.0).unwrap();
+    /// assert_eq!(graph.get_osm_id(nearest), Some(0));
+    /// assert!(graph.nearest_node(Vec2::new(0.0, 1000.0), 500.0).is_none());
+    /// ```
+    pub fn nearest_node(&self, location: Vec2, max_distance: f32) -> Option<NodeIndex> {
+        let (center_x, center_z) = node_cell(location);
+        let max_ring = (max_distance / NODE_CELL_SIZE).ceil() as i32 + 1;
+        let mut nearest: Option<(NodeIndex, f32)> = None;
+        for ring in 0..=max_ring {
+            for x in center_x - ring..=center_x + ring {
+                for z in center_z - ring..=center_z + ring {
+                    // only the cells on the border of the ring are new
+                    if (x - center_x).abs() != ring && (z - center_z).abs() != ring {
+                        continue;
+                    }
+                    let Some(cell) = self.node_cells.get(&(x, z)) else {
+                        continue;
+                    };
+                    for &index in cell {
+                        // vertices of removed chunks stay until they are compacted
+                        if self.graph.neighbors_undirected(index).next().is_none() {
+                            continue;
+                        }
+                        let distance = (self.graph[index] - location).length();
+                        if distance <= max_distance && nearest.map_or(true, |(_, nearest)| distance < nearest) {
+                            nearest = Some((index, distance));
+                        }
+                    }
+                }
+            }
+            // vertices in the next ring are at least this far away
+            if nearest.is_some_and(|(_, distance)| distance <= ring as f32 * NODE_CELL_SIZE) {
+                break;
+            }
+        }
+        nearest.map(|(index, _)| index)
+    }
+
+    /// Returns a random vertex that agents may travel towards, at most
+    /// `radius` away from the vertex `origin` as the crow flies. Like
+    /// `get_random_destination_node_index`, non-destinations are avoided if
+    /// possible. Returns `origin` if there is no other vertex nearby.
+    ///
+    /// ```
+    /// use bevy::math::Vec2;
+    /// use city_visualizer::data::road_type::RoadType;
+    /// use city_visualizer::data::traffic_graph::{Access, OneWay, TrafficGraph};
+    /// use rand::{rngs::StdRng, SeedableRng};
+    ///
+    /// // a long straight road, with a vertex every 10 units
+    /// let mut graph = TrafficGraph::default();
+    /// for id in 0..100u64 {
+    ///     let from = Vec2::new(id as f32 * 10.0, 0.0);
+    ///     let to = Vec2::new((id + 1) as f32 * 10.0, 0.0);
+    ///     graph.add_connection(id, from, id + 1, to, OneWay::No, RoadType::Residential, Access::ALL);
+    /// }
+    ///
+    /// let origin = graph.get_index(50).unwrap();
+    /// let mut rng = StdRng::seed_from_u64(0);
+    /// for _ in 0..100 {
+    ///     let destination = graph.get_random_destination_near(origin, 45.0, &mut rng);
+    ///     let distance = graph.get_node_location(origin).distance(graph.get_node_location(destination));
+    ///     assert!(distance <= 45.0);
+    /// }
+    /// ```
+    pub fn get_random_destination_near(
+        &self,
+        origin: NodeIndex,
+        radius: f32,
+        rng: &mut impl Rng,
+    ) -> NodeIndex {
+        let candidates: Vec<NodeIndex> = self
+            .nodes_within_radius(self.graph[origin], radius)
+            .into_iter()
+            .filter(|&node| node != origin)
+            .collect();
+        if candidates.is_empty() {
+            return origin;
+        }
+        for _ in 0..DESTINATION_ATTEMPTS {
+            let node = candidates[rng.gen_range(0..candidates.len())];
+            if !self.non_destinations.contains(&node) {
+                return node;
             }
-            None => return RoadType::NotCovered,
+        }
+        candidates[rng.gen_range(0..candidates.len())]
+    }
+
+    /// Returns the end vertex and data of a random edge leaving the given
+    /// vertex that the agent type may use, or None if there is no such edge.
+    pub fn get_random_outgoing_edge(
+        &self,
+        from_index: NodeIndex,
+        agent_type: AgentType,
+        rng: &mut impl Rng,
+    ) -> Option<(NodeIndex, EdgeData)> {
+        let edges: Vec<_> = self
+            .graph
+            .edges_directed(from_index, Direction::Outgoing)
+            .filter(|edge| edge.weight().access.allows(agent_type))
+            .collect();
+        if edges.is_empty() {
+            return None;
+        }
+        let edge = edges[rng.gen_range(0..edges.len())];
+        Some((edge.target(), *edge.weight()))
+    }
+
+    /// Marks a vertex as one that agents should not pick as their destination.
+    pub fn mark_non_destination(&mut self, index: NodeIndex) {
+        self.non_destinations.insert(index);
+    }
+
+    /// Returns whether agents should not pick the vertex as their destination.
+    pub fn is_non_destination(&self, index: NodeIndex) -> bool {
+        self.non_destinations.contains(&index)
+    }
+
+    /// Makes the ends of roads that were cut off at the edge of the loaded
+    /// data destinations again, once they are no longer within `margin` of
+    /// the edge since data around them was loaded. The bounds only grow
+    /// while the graph is in use, so vertices are never marked here.
+    ///
+    /// ```
+    /// use bevy::math::Vec2;
+    /// use city_visualizer::data::geography::LoadedBounds;
+    /// use city_visualizer::data::road_type::RoadType;
+    /// use city_visualizer::data::traffic_graph::{Access, OneWay, TrafficGraph};
+    ///
+    /// let mut graph = TrafficGraph::default();
+    /// graph.add_connection(0, Vec2::ZERO, 1, Vec2::new(100.0, 0.0), OneWay::No, RoadType::Residential, Access::ALL);
+    /// let end = graph.get_index(1).unwrap();
+    /// graph.mark_non_destination(end);
+    ///
+    /// let mut bounds = LoadedBounds::default();
+    /// bounds.extend(Vec2::new(0.0, -50.0), Vec2::new(100.0, 50.0));
+    /// graph.update_non_destinations(&bounds, 10.0);
+    /// assert!(graph.is_non_destination(end));
+    ///
+    /// // data east of the end of the road was loaded
+    /// bounds.extend(Vec2::new(100.0, -50.0), Vec2::new(200.0, 50.0));
+    /// graph.update_non_destinations(&bounds, 10.0);
+    /// assert!(!graph.is_non_destination(end));
+    /// ```
+    pub fn update_non_destinations(&mut self, bounds: &LoadedBounds, margin: f32) {
+        let graph = &self.graph;
+        self.non_destinations
+            .retain(|&index| bounds.is_near_boundary(graph[index], margin));
+    }
+
+    /// Returns the data of the edge between two vertices, or an empty edge
+    /// of an unknown road type if they are not connected.
+    pub fn get_edge_data(&self, from_index: NodeIndex, to_index: NodeIndex) -> EdgeData {
+        match self.graph.find_edge(from_index, to_index) {
+            Some(edge) => self.graph[edge],
+            None => EdgeData::new(0.0, RoadType::NotCovered, false),
+        }
+    }
+
+    pub fn get_road_type(&self, from_index: NodeIndex, to_index: NodeIndex) -> RoadType {
+        self.get_edge_data(from_index, to_index).road_type()
+    }
+
+    /// Returns the edge from one vertex to another, if they are connected.
+    pub fn find_edge(&self, from_index: NodeIndex, to_index: NodeIndex) -> Option<EdgeIndex> {
+        self.graph.find_edge(from_index, to_index)
+    }
+
+    /// Returns the start and end vertex of an edge, or `None` if the edge was
+    /// removed.
+    pub fn get_edge_endpoints(&self, edge: EdgeIndex) -> Option<(NodeIndex, NodeIndex)> {
+        self.graph.edge_endpoints(edge)
+    }
+
+    /// Returns the graph in [GraphML], for analysis in tools like networkx
+    /// and Gephi. Vertices are named after their OSM node and have their
+    /// position in the world and their latitude and longitude. Edges are
+    /// directed, so two-way roads have an edge in both directions, and have
+    /// their length in meters, road type and whether the road is one-way.
+    ///
+    /// ```
+    /// use bevy::math::Vec2;
+    /// use city_visualizer::data::geography::{GeoLocation, Offset, WorldScale};
+    /// use city_visualizer::data::road_type::RoadType;
+    /// use city_visualizer::data::traffic_graph::{Access, OneWay, TrafficGraph};
+    ///
+    /// let offset = Offset::centered_on(&GeoLocation { longitude: 5.4697, latitude: 51.4416 }, WorldScale::default());
+    /// let mut graph = TrafficGraph::default();
+    /// let (a, b, c) = (Vec2::new(0.0, 0.0), Vec2::new(10.0, 0.0), Vec2::new(10.0, 20.0));
+    /// graph.add_connection(1, a, 2, b, OneWay::No, RoadType::Residential, Access::ALL);
+    /// graph.add_connection(2, b, 3, c, OneWay::Yes, RoadType::Primary, Access::ALL);
+    ///
+    /// let graphml = graph.to_graphml(&offset);
+    /// let elements = |name: &str| graphml.split(&format!("<{} ", name)).skip(1).collect::<Vec<_>>();
+    /// let data = |element: &str, key: &str| {
+    ///     let start = element.find(&format!("<data key=\"{}\">", key)).unwrap() + key.len() + 13;
+    ///     element[start..start + element[start..].find('<').unwrap()].to_owned()
+    /// };
+    ///
+    /// let nodes = elements("node");
+    /// assert_eq!(nodes.len(), 3);
+    /// let first = nodes.iter().find(|node| node.starts_with("id=\"n1\"")).unwrap();
+    /// assert!((data(first, "lat").parse::<f64>().unwrap() - 51.4416).abs() < 1e-6);
+    /// assert!((data(first, "lon").parse::<f64>().unwrap() - 5.4697).abs() < 1e-6);
+    ///
+    /// let edges = elements("edge");
+    /// assert_eq!(edges.len(), 3);
+    /// let primary = edges.iter().find(|edge| edge.starts_with("source=\"n2\" target=\"n3\"")).unwrap();
+    /// assert_eq!(data(primary, "road_type"), "primary");
+    /// assert_eq!(data(primary, "oneway"), "true");
+    /// // 20 units at 0.25 units per meter
+    /// assert_eq!(data(primary, "length").parse::<f32>().unwrap(), 80.0);
+    /// assert_eq!(edges.iter().filter(|edge| data(edge, "oneway") == "false").count(), 2);
+    /// ```
+    ///
+    /// [GraphML]: http://graphml.graphdrawing.org/
+    pub fn to_graphml(&self, offset: &Offset) -> String {
+        let mut graphml = String::from(concat!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+            "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n",
+            "  <key id=\"osm_id\" for=\"node\" attr.name=\"osm_id\" attr.type=\"long\"/>\n",
+            "  <key id=\"x\" for=\"node\" attr.name=\"x\" attr.type=\"double\"/>\n",
+            "  <key id=\"z\" for=\"node\" attr.name=\"z\" attr.type=\"double\"/>\n",
+            "  <key id=\"lat\" for=\"node\" attr.name=\"lat\" attr.type=\"double\"/>\n",
+            "  <key id=\"lon\" for=\"node\" attr.name=\"lon\" attr.type=\"double\"/>\n",
+            "  <key id=\"length\" for=\"edge\" attr.name=\"length\" attr.type=\"double\"/>\n",
+            "  <key id=\"road_type\" for=\"edge\" attr.name=\"road_type\" attr.type=\"string\"/>\n",
+            "  <key id=\"oneway\" for=\"edge\" attr.name=\"oneway\" attr.type=\"boolean\"/>\n",
+            "  <graph id=\"traffic\" edgedefault=\"directed\">\n",
+        ));
+        // writing to a string cannot fail
+        for index in self.graph.node_indices() {
+            let location = self.graph[index];
+            let geo_location = GeoLocation::unproject(location, offset);
+            let _ = write!(
+                graphml,
+                "    <node id=\"{}\"><data key=\"osm_id\">{}</data><data key=\"x\">{}</data><data key=\"z\">{}</data><data key=\"lat\">{}</data><data key=\"lon\">{}</data></node>\n",
+                self.export_node_id(index),
+                self.get_osm_id(index).unwrap_or_default(),
+                location.x,
+                location.y,
+                geo_location.latitude,
+                geo_location.longitude,
+            );
+        }
+        for edge in self.graph.edge_indices() {
+            let Some((source, target)) = self.graph.edge_endpoints(edge) else {
+                continue;
+            };
+            let data = &self.graph[edge];
+            let _ = write!(
+                graphml,
+                "    <edge source=\"{}\" target=\"{}\"><data key=\"length\">{}</data><data key=\"road_type\">{}</data><data key=\"oneway\">{}</data></edge>\n",
+                self.export_node_id(source),
+                self.export_node_id(target),
+                data.length / offset.scale.units_per_meter,
+                road_type_to_tag(&data.road_type),
+                !data.two_way,
+            );
+        }
+        graphml.push_str("  </graph>\n</graphml>\n");
+        graphml
+    }
+
+    /// Returns the graph in the [DOT] language of Graphviz, with the same
+    /// attributes as `to_graphml`.
+    ///
+    /// ```
+    /// use bevy::math::Vec2;
+    /// use city_visualizer::data::geography::{GeoLocation, Offset, WorldScale};
+    /// use city_visualizer::data::road_type::RoadType;
+    /// use city_visualizer::data::traffic_graph::{Access, OneWay, TrafficGraph};
+    ///
+    /// let offset = Offset::centered_on(&GeoLocation { longitude: 5.4697, latitude: 51.4416 }, WorldScale::default());
+    /// let mut graph = TrafficGraph::default();
+    /// graph.add_connection(1, Vec2::ZERO, 2, Vec2::X, OneWay::Reversed, RoadType::Service, Access::ALL);
+    ///
+    /// let dot = graph.to_dot(&offset);
+    /// assert!(dot.starts_with("digraph traffic {"));
+    /// assert!(dot.contains("n2 -> n1 [length=4, road_type=\"service\", oneway=true];"));
+    /// ```
+    ///
+    /// [DOT]: https://graphviz.org/doc/info/lang.html
+    pub fn to_dot(&self, offset: &Offset) -> String {
+        let mut dot = String::from("digraph traffic {\n");
+        for index in self.graph.node_indices() {
+            let location = self.graph[index];
+            let geo_location = GeoLocation::unproject(location, offset);
+            let _ = writeln!(
+                dot,
+                "  {} [osm_id={}, x={}, z={}, lat={}, lon={}];",
+                self.export_node_id(index),
+                self.get_osm_id(index).unwrap_or_default(),
+                location.x,
+                location.y,
+                geo_location.latitude,
+                geo_location.longitude,
+            );
+        }
+        for edge in self.graph.edge_indices() {
+            let Some((source, target)) = self.graph.edge_endpoints(edge) else {
+                continue;
+            };
+            let data = &self.graph[edge];
+            let _ = writeln!(
+                dot,
+                "  {} -> {} [length={}, road_type=\"{}\", oneway={}];",
+                self.export_node_id(source),
+                self.export_node_id(target),
+                data.length / offset.scale.units_per_meter,
+                road_type_to_tag(&data.road_type),
+                !data.two_way,
+            );
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Writes the graph to a GraphML file, see `to_graphml`.
+    pub fn export_graphml(&self, path: &Path, offset: &Offset) -> std::io::Result<()> {
+        std::fs::write(path, self.to_graphml(offset))
+    }
+
+    /// Writes the graph to a DOT file, see `to_dot`.
+    pub fn export_dot(&self, path: &Path, offset: &Offset) -> std::io::Result<()> {
+        std::fs::write(path, self.to_dot(offset))
+    }
+
+    /// Returns the name of a vertex in exported graphs: its OSM node, or its
+    /// index if it has none.
+    fn export_node_id(&self, index: NodeIndex) -> String {
+        match self.get_osm_id(index) {
+            Some(osm_id) => format!("n{}", osm_id),
+            None => format!("i{}", index.index()),
         }
     }
 }
@@ -161,13 +904,23 @@ impl TrafficGraph {
 pub fn update_traffic_graph(
     node_locations: &HashMap<u64, GeoLocation>,
     road_features: &HashMap<u64, RoadFeature>,
-    graph: &mut ResMut<TrafficGraph>,
+    chunk: &ChunkIndex,
+    graph: &mut TrafficGraph,
     offset: &Offset,
+    bounds: &LoadedBounds,
 ) {
-    // Loop over roads and add the connections to the graph
-    for (_, road) in road_features.iter() {
+    // Loop over roads (in a fixed order, so node indices are reproducible) and
+    // add the connections to the graph
+    let mut road_ids: Vec<&u64> = road_features.keys().collect();
+    road_ids.sort();
+    for (&way_id, road) in road_ids.into_iter().map(|id| (id, &road_features[id])) {
+        // Replace the edges of the road if it was already added before
+        graph.remove_way_edges(way_id);
+
         let mut last_vertex_osm_id: Option<u64> = None;
         let mut last_vertex_location: Option<Vec2> = None;
+        let mut first_vertex: Option<(NodeIndex, Vec2)> = None;
+        let mut last_vertex: Option<(NodeIndex, Vec2)> = None;
 
         let oneway = match road.tags.get("oneway") {
             Some(value) => value.parse().unwrap_throw(),
@@ -179,6 +932,8 @@ pub fn update_traffic_graph(
             None => RoadType::NotCovered,
         };
 
+        let access = Access::from_tags(&road.tags);
+
         for osm_vertex_id in road.nodes.iter() {
             let geolocation = match node_locations.get(osm_vertex_id) {
                 Some(location) => location,
@@ -187,27 +942,49 @@ pub fn update_traffic_graph(
             let location = geolocation.project(&offset);
 
             // Add node
-            graph.add_node(*osm_vertex_id, location);
+            let index = graph.add_node(*osm_vertex_id, location);
+            if first_vertex.is_none() {
+                first_vertex = Some((index, location));
+            }
+            last_vertex = Some((index, location));
 
             // Add edge
             if let Some(last_node) = last_vertex_osm_id {
                 let last_location = last_vertex_location.unwrap_throw();
-                graph.add_connection(
+                let edges = graph.add_connection(
                     last_node,
                     last_location,
                     *osm_vertex_id,
                     location,
                     oneway,
                     road_type,
+                    access,
                 );
+                graph.track_way_edges(chunk, way_id, edges);
             }
 
             last_vertex_osm_id = Some(*osm_vertex_id);
             last_vertex_location = Some(location);
         }
+
+        // Roads that are cut off at the edge of the data lead nowhere, so
+        // agents should not drive towards their ends
+        for (index, location) in [first_vertex, last_vertex].into_iter().flatten() {
+            if bounds.is_near_boundary(location, offset.scale.units(BOUNDARY_MARGIN)) {
+                graph.mark_non_destination(index);
+            }
+        }
     }
 }
 
+/// Returns the cell of the vertex grid that contains `location`.
+fn node_cell(location: Vec2) -> (i32, i32) {
+    (
+        (location.x / NODE_CELL_SIZE).floor() as i32,
+        (location.y / NODE_CELL_SIZE).floor() as i32,
+    )
+}
+
 /// Represents if a road is one-way, two-way, or one-way with a reversed direction.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum OneWay {
@@ -235,7 +1012,9 @@ impl std::str::FromStr for OneWay {
     }
 }
 
-fn road_type_allowed_for_agent_type(road_type: RoadType, agent_type: AgentType) -> bool {
+/// Returns whether the agent type is meant to use roads of the given type.
+/// Agents can still travel over other roads, at a much higher cost.
+pub fn road_type_allowed_for_agent_type(road_type: RoadType, agent_type: AgentType) -> bool {
     match agent_type {
         AgentType::Car => match road_type {
             RoadType::Motorway => true,
@@ -249,18 +1028,103 @@ fn road_type_allowed_for_agent_type(road_type: RoadType, agent_type: AgentType)
             RoadType::SecondaryLink => true,
             RoadType::TertiaryLink => true,
             RoadType::MotorwayLink => true,
+            RoadType::LivingStreet => true,
+            RoadType::Service => true,
+            RoadType::Track => true,
             RoadType::NotCovered => true,
             _ => false,
         },
         AgentType::Pedestrian => match road_type {
             RoadType::Tertiary => true,
             RoadType::Residential => true,
+            RoadType::LivingStreet => true,
+            RoadType::Service => true,
+            RoadType::Pedestrian => true,
+            RoadType::Track => true,
             RoadType::Footway => true,
+            RoadType::Cycleway => true,
             RoadType::Steps => true,
             RoadType::Path => true,
             RoadType::Unclassified => true,
             RoadType::NotCovered => true,
             _ => false,
         },
+        // Bicycles may use almost any road, they prefer cycleways by being
+        // faster there
+        AgentType::Bicycle => match road_type {
+            RoadType::Motorway => false,
+            RoadType::Trunk => false,
+            RoadType::MotorwayLink => false,
+            RoadType::TrunkLink => false,
+            RoadType::Steps => false,
+            _ => true,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The nodes and roads of a chunk: a two-way road over three vertices and
+    /// a one-way road that continues it.
+    fn chunk_data() -> (HashMap<u64, GeoLocation>, HashMap<u64, RoadFeature>) {
+        let node_locations = (1..=4)
+            .map(|id| {
+                let location = GeoLocation {
+                    longitude: 5.47 + id as f64 * 0.001,
+                    latitude: 51.44,
+                };
+                (id, location)
+            })
+            .collect();
+        let road = |nodes: Vec<u64>, tags: &[(&str, &str)]| RoadFeature {
+            nodes,
+            tags: tags.iter().map(|&(key, value)| (key.to_owned(), value.to_owned())).collect(),
+        };
+        let road_features = HashMap::from([
+            (10, road(vec![1, 2, 3], &[("highway", "residential")])),
+            (11, road(vec![3, 4], &[("highway", "residential"), ("oneway", "yes")])),
+        ]);
+        (node_locations, road_features)
+    }
+
+    #[test]
+    fn chunk_is_removed_and_added_again() {
+        let (node_locations, road_features) = chunk_data();
+        let chunk = ChunkIndex { x: 0, z: 0 };
+        let offset = Offset::centered_on(&node_locations[&1], WorldScale::default());
+        let bounds = LoadedBounds::default();
+        let mut graph = TrafficGraph::default();
+        let add_chunk = |graph: &mut TrafficGraph| {
+            update_traffic_graph(&node_locations, &road_features, &chunk, graph, &offset, &bounds);
+        };
+
+        add_chunk(&mut graph);
+        let (size, edge_count) = (graph.get_size(), graph.get_edge_count());
+        let (hashmap, generation) = (graph.hashmap.clone(), graph.get_generation());
+        assert_eq!((size, edge_count), (4, 5));
+
+        // the vertices are kept, so the indices held by agents stay valid
+        graph.remove_chunk_edges(&chunk);
+        assert_eq!((graph.get_size(), graph.get_edge_count()), (size, 0));
+        assert_eq!(graph.get_generation(), generation);
+        add_chunk(&mut graph);
+        assert_eq!((graph.get_size(), graph.get_edge_count()), (size, edge_count));
+        assert_eq!(graph.hashmap, hashmap);
+        assert_eq!(graph.get_generation(), generation);
+
+        // compacting removes the vertices, and their indices may be reused
+        graph.remove_chunk_edges(&chunk);
+        graph.compact();
+        assert_eq!((graph.get_size(), graph.get_edge_count()), (0, 0));
+        assert!(graph.hashmap.is_empty() && graph.osm_ids.is_empty() && graph.node_cells.is_empty());
+        assert!(graph.chunk_ways.is_empty() && graph.way_edges.is_empty());
+        assert_ne!(graph.get_generation(), generation);
+        add_chunk(&mut graph);
+        assert_eq!((graph.get_size(), graph.get_edge_count()), (size, edge_count));
+        let mut osm_ids: Vec<u64> = graph.hashmap.keys().copied().collect();
+        osm_ids.sort();
+        assert_eq!(osm_ids, vec![1, 2, 3, 4]);
     }
 }
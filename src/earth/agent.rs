@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use wasm_bindgen::prelude::*;
 
@@ -5,26 +6,230 @@ use bevy::{
     ecs::{
         component::Component,
         entity::Entity,
-        system::{Query, Res},
+        event::Event,
+        query::{Has, With},
+        system::{Commands, Local, Query, Res, ResMut, Resource},
+        world::World,
     },
-    math::{vec2, Quat, Vec3},
+    hierarchy::DespawnRecursiveExt,
+    math::{vec2, Quat, Vec2, Vec3},
     time::Time,
     transform::components::Transform,
 };
 use petgraph::graph::NodeIndex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
+use crate::commands::CommandRegistry;
+use crate::common::{handle_compute_tasks, spawn_compute_task, AsyncComputation};
 use crate::data::{
-    road_type::{road_type_to_width, RoadType},
-    traffic_graph::TrafficGraph,
+    geography::WorldScale,
+    road_type::RoadType,
+    traffic_graph::{EdgeData, TrafficGraph},
 };
+use crate::ui::InputMode;
 
-use super::GLOBAL_SCALE_FACTOR;
+use super::buildings::BuildingFootprints;
+use super::edge_usage::EdgeUsage;
+use super::{is_generation_throttled, SimulationSettings};
+use super::traffic_signals::{
+    Approach, TrafficSignals, SIGNAL_BRAKING_DISTANCE, SIGNAL_STOP_DISTANCE,
+};
+/// Reference speed for agents. This is the speed of a pedestrian, in world
+/// units per second at the default scale.
+pub const REFERENCE_SPEED: f32 = 1.0;
+
+/// The minimum distance a car keeps to the car ahead of it, on the same edge
+/// or on the next edge of its path, in world units at the default scale.
+pub const CAR_HEADWAY: f32 = 6.0;
+
+/// How far the corner of a lane is from the middle of the road at most, in
+/// lane offsets. Limits how far agents swing out at sharp turns.
+const MAX_MITER_SCALE: f32 = 2.0;
+
+/// The maximum number of path finding tasks that run at the same time.
+const MAX_PATH_TASKS: usize = 4;
+
+/// The maximum number of paths that are found by a single path finding task.
+const PATH_BATCH_SIZE: usize = 64;
+
+/// How many random destinations are tried for an agent that cannot travel back to where it
+/// started, before it is removed.
+const REROUTE_ATTEMPTS: usize = 5;
+
+/// The seed from which the random number generators of agent creation are
+/// derived. Loading the same data with the same seed spawns the same agents.
+/// Random unless it is inserted before the plugin, e.g. with `--seed`.
+#[derive(Clone, Copy, Debug, Resource)]
+pub struct AgentSeed(pub u64);
+
+impl Default for AgentSeed {
+    fn default() -> Self {
+        AgentSeed(rand::random())
+    }
+}
 
-/// Number between 0 and 1 that determines the split between pedestrian and car agents. Higher means more cars.
-const PEDESTRIAN_CAR_SPLIT: f32 = 0.5;
+impl AgentSeed {
+    /// Returns the seed for the agent batch with the given index.
+    pub fn for_batch(&self, batch_index: u64) -> u64 {
+        // mix in the batch index, so consecutive batches are not correlated
+        self.0 ^ batch_index.wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+    }
+}
 
-/// Reference speed for agents. This is the speed of a pedestrian.
-pub const REFERENCE_SPEED: f32 = 0.01 * GLOBAL_SCALE_FACTOR;
+/// The number of agents that are kept in the world by default.
+pub const DEFAULT_TARGET_AGENTS: usize = 1000;
+
+/// The default maximum distance between where an agent starts and its
+/// destination, as the crow flies, in world units at the default scale. About
+/// 2 km.
+pub const DEFAULT_MAX_ROUTE_DISTANCE: f32 = 500.0;
+
+/// Keeps track of the agent creation tasks that were started.
+#[derive(Debug, Default, Resource)]
+pub struct AgentSpawner {
+    /// The index of the next batch, from which its seed is derived
+    batch_index: u64,
+    /// The number of agents requested from tasks that did not finish yet
+    pending: usize,
+}
+
+impl AgentSpawner {
+    /// Returns the seed for the next batch of agents.
+    pub fn next_batch_seed(&mut self, agent_seed: &AgentSeed) -> u64 {
+        let seed = agent_seed.for_batch(self.batch_index);
+        self.batch_index += 1;
+        seed
+    }
+
+    /// Returns the number of agents that are still being created.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    pub fn add_pending(&mut self, count: usize) {
+        self.pending += count;
+    }
+
+    pub fn finish_pending(&mut self, count: usize) {
+        self.pending = self.pending.saturating_sub(count);
+    }
+}
+
+/// An event that changes the agents in the world, normally sent by the UI.
+#[derive(Clone, Copy, Debug, Event)]
+pub enum AgentCommandEvent {
+    /// Sets the number of agents to keep in the world.
+    SetTarget(usize),
+    /// Adds a number of agents, by raising the target.
+    Spawn(usize),
+    /// Removes all agents, and sets the target to 0.
+    DespawnAll,
+    /// Sets how new agents are split between the agent types.
+    SetMix(AgentMix),
+    /// Sets the maximum distance between the start and destination of new
+    /// agents.
+    SetMaxRouteDistance(f32),
+}
+
+/// Settings for the agents that move through the world.
+#[derive(Clone, Copy, Debug, Resource)]
+pub struct AgentSettings {
+    /// Whether agents are spawned when new data is loaded.
+    pub enabled: bool,
+    /// Whether pedestrians are pushed out of buildings they walk into, e.g.
+    /// when cutting corners. Costs a lookup per pedestrian per frame.
+    pub building_collision: bool,
+    /// How spawned agents are split between the agent types.
+    pub mix: AgentMix,
+    /// The number of agents that is kept in the world. Loading data spawns
+    /// agents up to this number.
+    pub target_agents: usize,
+    /// Whether agents keep to the left side of two-way roads, as in e.g. the
+    /// United Kingdom and Japan, instead of the right side.
+    pub left_hand_traffic: bool,
+    /// The maximum distance between where a spawned agent starts and its
+    /// destination, as the crow flies. Keeps paths short, and agents near
+    /// where they are spawned. In world units at the default scale, so the
+    /// distance is the same at every scale of the world.
+    pub max_route_distance: f32,
+}
+
+impl Default for AgentSettings {
+    fn default() -> Self {
+        AgentSettings {
+            enabled: true,
+            building_collision: false,
+            mix: AgentMix::default(),
+            target_agents: DEFAULT_TARGET_AGENTS,
+            left_hand_traffic: false,
+            max_route_distance: DEFAULT_MAX_ROUTE_DISTANCE,
+        }
+    }
+}
+
+/// A system that registers the commands for agent settings.
+pub fn register_agent_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle pedestrian building collision",
+        "Pushes pedestrians out of buildings when they cut corners",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut settings = world.resource_mut::<AgentSettings>();
+                settings.building_collision = !settings.building_collision;
+            })
+        },
+    );
+    registry.register(
+        "Toggle left-hand traffic",
+        "Makes agents keep to the left side of two-way roads instead of the right",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut settings = world.resource_mut::<AgentSettings>();
+                settings.left_hand_traffic = !settings.left_hand_traffic;
+            })
+        },
+    );
+}
+
+/// The relative number of agents of every type that are spawned. The ratios
+/// do not have to add up to 1.
+#[derive(Clone, Copy, Debug)]
+pub struct AgentMix {
+    pub cars: f32,
+    pub bicycles: f32,
+    pub pedestrians: f32,
+}
+
+impl Default for AgentMix {
+    fn default() -> Self {
+        AgentMix {
+            cars: 0.4,
+            bicycles: 0.25,
+            pedestrians: 0.35,
+        }
+    }
+}
+
+impl AgentMix {
+    /// Picks a random agent type according to the ratios. Pedestrians are
+    /// picked if all ratios are 0.
+    pub fn choose(&self, rng: &mut impl Rng) -> AgentType {
+        let total = self.cars.max(0.0) + self.bicycles.max(0.0) + self.pedestrians.max(0.0);
+        if total <= 0.0 {
+            return AgentType::Pedestrian;
+        }
+        let pick = rng.gen::<f32>() * total;
+        if pick < self.cars.max(0.0) {
+            AgentType::Car
+        } else if pick < self.cars.max(0.0) + self.bicycles.max(0.0) {
+            AgentType::Bicycle
+        } else {
+            AgentType::Pedestrian
+        }
+    }
+}
 
 /// Agents move through the world. They can be cars or pedestrians.
 /// They have a position (implicit), a destination node id, and a path to follow.
@@ -37,151 +242,584 @@ pub struct Agent {
     pub destination: NodeIndex,
 
     /// The path the agent is following
-    pub path: Vec<NodeIndex>,
+    pub path: Box<[NodeIndex]>,
 
     /// What the next node in the path is
     pub path_index: usize,
 
-    /// Next location and the road type cached
-    pub next_path_location_road: Option<(Vec3, RoadType)>,
+    /// Next location and the edge towards it cached
+    pub next_path_location_edge: Option<(Vec3, EdgeData)>,
+
+    /// The generation of the traffic graph the path was computed in
+    pub graph_generation: u32,
+}
+
+/// Marks an agent that waits at its node for a new path, which is found by
+/// an async task.
+#[derive(Component, Debug)]
+pub struct PendingPath {
+    /// The node the agent is waiting at
+    pub from: NodeIndex,
+    /// The node the agent wants to travel to
+    pub to: NodeIndex,
+    /// Whether a task is already finding the path
+    pub requested: bool,
+}
+
+/// The cars on every edge of the traffic graph, ordered by their distance to
+/// the end of the edge. Rebuilt every frame, so cars can find the car ahead of
+/// them without comparing against all other cars.
+///
+/// ```
+/// use bevy::ecs::entity::Entity;
+/// use city_visualizer::earth::agent::EdgeOccupancy;
+/// use petgraph::graph::NodeIndex;
+///
+/// let edge = (NodeIndex::new(0), NodeIndex::new(1));
+/// let (first, second) = (Entity::from_raw(1), Entity::from_raw(2));
+///
+/// let mut occupancy = EdgeOccupancy::default();
+/// occupancy.insert(edge, 10.0, second);
+/// occupancy.insert(edge, 4.0, first);
+/// occupancy.sort();
+///
+/// assert_eq!(occupancy.gap_ahead(edge, 10.0, second), Some(6.0));
+/// assert_eq!(occupancy.gap_ahead(edge, 4.0, first), None);
+/// assert_eq!(occupancy.rearmost(edge), Some(10.0));
+/// assert_eq!(occupancy.rearmost((NodeIndex::new(1), NodeIndex::new(2))), None);
+/// ```
+#[derive(Debug, Default)]
+pub struct EdgeOccupancy {
+    edges: HashMap<(NodeIndex, NodeIndex), Vec<(f32, Entity)>>,
+}
+
+impl EdgeOccupancy {
+    /// Removes all cars, but keeps the allocated buckets.
+    pub fn clear(&mut self) {
+        for cars in self.edges.values_mut() {
+            cars.clear();
+        }
+    }
+
+    /// Adds a car that is `distance` away from the end of `edge`.
+    pub fn insert(&mut self, edge: (NodeIndex, NodeIndex), distance: f32, entity: Entity) {
+        self.edges.entry(edge).or_default().push((distance, entity));
+    }
+
+    /// Orders the cars on every edge, which has to be done after inserting
+    /// them and before looking up gaps.
+    pub fn sort(&mut self) {
+        for cars in self.edges.values_mut() {
+            cars.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        }
+    }
+
+    /// Returns the distance to the car ahead of the given car on the same
+    /// edge, or `None` if it is the first car or is not on the edge.
+    pub fn gap_ahead(&self, edge: (NodeIndex, NodeIndex), distance: f32, entity: Entity) -> Option<f32> {
+        let cars = self.edges.get(&edge)?;
+        let index = cars
+            .binary_search_by(|car| car.0.total_cmp(&distance).then(car.1.cmp(&entity)))
+            .ok()?;
+        let ahead = cars.get(index.checked_sub(1)?)?;
+        Some(distance - ahead.0)
+    }
+
+    /// Returns the distance of the last car on `edge` to the end of it, or
+    /// `None` if there are no cars on the edge.
+    pub fn rearmost(&self, edge: (NodeIndex, NodeIndex)) -> Option<f32> {
+        self.edges.get(&edge)?.last().map(|car| car.0)
+    }
+}
+
+/// Returns the edge a car is driving on and its distance to the end of it,
+/// if it is driving towards a node. Cars that have not cached where they
+/// travel towards yet, such as ones that just passed a node, are measured to
+/// the node itself.
+fn get_car_edge(
+    agent: &Agent,
+    transform: &Transform,
+    traffic_graph: &TrafficGraph,
+) -> Option<((NodeIndex, NodeIndex), f32)> {
+    if !matches!(agent.agent_type, AgentType::Car) {
+        return None;
+    }
+    let from = *agent.path.get(agent.path_index)?;
+    let to = *agent.path.get(agent.path_index + 1)?;
+    let next_location = match agent.next_path_location_edge {
+        Some((next_location, _)) => next_location,
+        None => {
+            let location = traffic_graph.get_node_location(to);
+            Vec3::new(location.x, 0.0, location.y)
+        }
+    };
+    Some(((from, to), (next_location - transform.translation).length()))
 }
 
 /// Note could be made more efficient by caching destination locations and only updating when needed.
 pub fn update_agents(
+    mut commands: Commands,
     time: Res<Time>,
-    mut agents: Query<(Entity, &mut Agent, &mut Transform)>,
+    mut agents: Query<(Entity, &mut Agent, &mut Transform, Has<PendingPath>)>,
     traffic_graph: Res<TrafficGraph>,
+    mut edge_usage: ResMut<EdgeUsage>,
+    agent_settings: Res<AgentSettings>,
+    footprints: Res<BuildingFootprints>,
+    traffic_signals: Res<TrafficSignals>,
+    mut occupancy: Local<EdgeOccupancy>,
+    simulation: Res<SimulationSettings>,
+    scale: Res<WorldScale>,
 ) {
-    for (_, mut agent, mut transform) in agents.iter_mut() {
-        // If the agent has reached the destination, get a new path and reset
-        if agent.path_index >= agent.path.len() - 1 {
-            // Reverse the path to get the path from end to start
-            agent.destination = agent.path[0];
-            agent.path.reverse();
+    if simulation.paused {
+        return;
+    }
+    let delta_seconds = simulation.delta_seconds(&time);
+    let braking_distance = scale.units(SIGNAL_BRAKING_DISTANCE);
+    let stop_distance = scale.units(SIGNAL_STOP_DISTANCE);
+
+    // Find where all cars are, before any of them moves. Cars ahead only
+    // move forward, so the gaps can only grow during this frame.
+    occupancy.clear();
+    for (entity, agent, transform, _) in agents.iter() {
+        if let Some((edge, distance)) = get_car_edge(agent, transform, &traffic_graph) {
+            occupancy.insert(edge, distance, entity);
+        }
+    }
+    occupancy.sort();
 
-            // Reset index
-            agent.path_index = 0;
+    for (entity, mut agent, mut transform, has_pending_path) in agents.iter_mut() {
+        // Agents from before a graph reset refer to nodes that no longer exist
+        if agent.graph_generation != traffic_graph.get_generation() {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
+
+        // Agents without at least one edge to travel over have nowhere to go
+        if agent.path.len() < 2 {
+            commands.entity(entity).despawn_recursive();
+            continue;
+        }
 
+        // If the agent has reached the destination, wait for a new path back
+        // to where it started. The path is not simply reversed, since that
+        // would go against one-way streets.
+        if agent.path_index >= agent.path.len() - 1 {
+            if !has_pending_path {
+                commands.entity(entity).insert(PendingPath {
+                    from: agent.path[agent.path.len() - 1],
+                    to: agent.path[0],
+                    requested: false,
+                });
+            }
             continue;
         }
 
         let current_agent_location = transform.translation;
+        // Found before the next location is cached, in the same way as when
+        // the cars were added to the occupancy
+        let car_edge = get_car_edge(&agent, &transform, &traffic_graph);
 
-        if agent.next_path_location_road.is_none() {
+        if agent.next_path_location_edge.is_none() {
             // Get the next node in the path
             let current_node = agent.path[agent.path_index];
             let next_node = agent.path[agent.path_index + 1];
 
-            // Get the road type of the road between the current node and the next node
-            let road_type = traffic_graph.get_road_type(current_node, next_node);
+            // Get the edge between the current node and the next node
+            let edge_data = traffic_graph.get_edge_data(current_node, next_node);
 
             // Get the location of where to travel towards, next node location
-            // with an offset to stay on the right side of the road
-
+            // with an offset to stay on the correct side of the road
             let current_node_location = traffic_graph.get_node_location(current_node);
             let next_node_location = traffic_graph.get_node_location(next_node);
-            // Do offset based on angle between current node, next node and the one after that
-            // We want the cars to drive on the right side of the road, so we calculate the angle
-            // and then offset the car to the right side of the road.
-            let offset = match agent.path.get(agent.path_index + 2) {
-                Some(next_next_node) => {
-                    let next_next_location = traffic_graph.get_node_location(*next_next_node);
-
-                    // We want the angle between the vector of current->next to next->next-next. Angle is [0, 2*PI]
-                    let vector_this_next = next_node_location - current_node_location;
-                    let angle = (vector_this_next)
-                        .angle_between(next_next_location - next_node_location)
-                        + std::f32::consts::PI / 2.0;
-
-                    let road_width =
-                        road_type_to_width(&road_type) * 0.01 as f32 * GLOBAL_SCALE_FACTOR; // TODO: account for lanes
-
-                    // We now rotate a vector perpendicular to the current->next vector by the angle
-                    let perpendicular = vec2(-vector_this_next.y, vector_this_next.x)
-                        .normalize_or_zero()
-                        * road_width;
-
-                    Vec3::new(
-                        perpendicular.x * angle.cos(),
-                        0.0,
-                        perpendicular.y * angle.sin(),
-                    )
-                }
-                None => Vec3::ZERO,
-            };
-
-            // Cache location and road type so we do not have to query graph again next time
-            agent.next_path_location_road = Some((
-                Vec3::new(next_node_location.x, 0.0, next_node_location.y) + offset,
-                road_type,
+            let after_next_location = agent
+                .path
+                .get(agent.path_index + 2)
+                .map(|node| traffic_graph.get_node_location(*node));
+            let location = lane_location(
+                current_node_location,
+                next_node_location,
+                after_next_location,
+                edge_data.lane_offset(agent_settings.left_hand_traffic, &scale),
+            );
+
+            // Cache location and edge so we do not have to query graph again next time
+            agent.next_path_location_edge = Some((
+                Vec3::new(location.x, 0.0, location.y),
+                edge_data,
             ));
         }
 
         // Get cached location
-        let cached = agent.next_path_location_road.unwrap_throw();
+        let cached = agent.next_path_location_edge.unwrap_throw();
         let next_location = cached.0;
-        let road_type = cached.1;
+        let edge_data = cached.1;
 
         // Calculate the direction the agent should move in
         let direction = (next_location - current_agent_location).normalize();
 
         // Get appropriate speed for the agent based on road type
-        let speed = agent_speed_on_road_type(REFERENCE_SPEED, agent.agent_type, road_type);
+        let mut speed = edge_data.speed_for(agent.agent_type, &scale);
+
+        // Slow down for a red light at the next node, and wait before it
+        let current_node = agent.path[agent.path_index];
+        let next_node = agent.path[agent.path_index + 1];
+        let signal = traffic_graph
+            .get_osm_id(next_node)
+            .and_then(|osm_id| traffic_signals.get(osm_id));
+        if let Some(signal) = signal {
+            let approach = Approach::from_direction(
+                traffic_graph.get_node_location(next_node)
+                    - traffic_graph.get_node_location(current_node),
+            );
+            let distance = (next_location - current_agent_location).length();
+            if !signal.is_green(approach) && distance < braking_distance {
+                speed *= ((distance - stop_distance) / (braking_distance - stop_distance))
+                    .clamp(0.0, 1.0);
+            }
+        }
+
+        // Cars keep their distance to the car ahead of them, which is the
+        // last car on the next edge of the path if none is ahead on this one
+        if let Some((edge, distance)) = car_edge {
+            let gap = occupancy.gap_ahead(edge, distance, entity).or_else(|| {
+                let after_next_node = *agent.path.get(agent.path_index + 2)?;
+                let next_edge = (next_node, after_next_node);
+                let rearmost = occupancy.rearmost(next_edge)?;
+                let next_edge_length = traffic_graph.get_edge_data(next_node, after_next_node).length();
+                Some(distance + next_edge_length - rearmost)
+            });
+            if let Some(gap) = gap {
+                let max_step = (gap - scale.units(CAR_HEADWAY)).max(0.0);
+                speed = speed.min(max_step / delta_seconds.max(f32::EPSILON));
+            }
+        }
 
         // Move the agent towards the next node
-        transform.translation += direction * speed * time.delta_seconds();
+        transform.translation += direction * speed * delta_seconds;
+
+        // Keep pedestrians from walking through buildings
+        if agent_settings.building_collision && matches!(agent.agent_type, AgentType::Pedestrian) {
+            let position = vec2(transform.translation.x, transform.translation.z);
+            if let Some(pushed) = footprints.push_out(position, &scale) {
+                transform.translation.x = pushed.x;
+                transform.translation.z = pushed.y;
+            }
+        }
 
         // Update rotation towards direction (linear interpolation)
         let rotation = transform.rotation;
         let target_rotation = Quat::from_rotation_y(direction.x.atan2(direction.z));
-        transform.rotation = rotation.slerp(target_rotation, (time.delta_seconds() * 3.0).min(1.0));
+        transform.rotation = rotation.slerp(target_rotation, (delta_seconds * 3.0).min(1.0));
 
         // If the agent has reached the next node, move to the next node in the path
-        if (transform.translation - next_location).length() < speed * time.delta_seconds() {
+        if (transform.translation - next_location).length() < speed * delta_seconds {
+            // Count the edge for the heatmap of edge usage
+            edge_usage.record(&traffic_graph, current_node, next_node);
             // Update index
             agent.path_index += 1;
             // Reset cached location
-            agent.next_path_location_road = None;
+            agent.next_path_location_edge = None;
         }
     }
 }
 
-/// Adds a number of agents to the world, starting at a random node going towards a random node.
+/// A system that starts async tasks that find paths for agents with a
+/// `PendingPath`, in batches. At most `MAX_PATH_TASKS` tasks run at the same
+/// time, so agents that arrive at the same time are spread over frames. No
+/// tasks are started while generation is throttled.
+pub fn request_agent_paths(
+    mut commands: Commands,
+    mut pending: Query<(Entity, &Agent, &mut PendingPath)>,
+    tasks: Query<(), With<AsyncComputation<AgentRoutes>>>,
+    traffic_graph: Res<TrafficGraph>,
+    mut graph_snapshot: Local<Option<Arc<TrafficGraph>>>,
+    input_mode: Res<InputMode>,
+) {
+    if is_generation_throttled(&input_mode) {
+        return;
+    }
+
+    let mut running_tasks = tasks.iter().count();
+    let mut requests = pending
+        .iter_mut()
+        .filter(|(_, _, pending_path)| !pending_path.requested)
+        .peekable();
+
+    while running_tasks < MAX_PATH_TASKS && requests.peek().is_some() {
+        let route_requests: Vec<RouteRequest> = requests
+            .by_ref()
+            .take(PATH_BATCH_SIZE)
+            .map(|(entity, agent, mut pending_path)| {
+                pending_path.requested = true;
+                RouteRequest {
+                    entity,
+                    from: pending_path.from,
+                    to: pending_path.to,
+                    agent_type: agent.agent_type,
+                }
+            })
+            .collect();
+        spawn_route_task(&mut commands, route_requests, &traffic_graph, &mut graph_snapshot);
+        running_tasks += 1;
+    }
+}
+
+fn spawn_route_task(
+    commands: &mut Commands,
+    route_requests: Vec<RouteRequest>,
+    traffic_graph: &TrafficGraph,
+    graph_snapshot: &mut Option<Arc<TrafficGraph>>,
+) {
+    // Pathfinding happens on a copy of the graph, which is only made again
+    // when the graph changes
+    let outdated = graph_snapshot.as_ref().map_or(true, |graph| {
+        graph.get_generation() != traffic_graph.get_generation()
+            || graph.get_size() != traffic_graph.get_size()
+            || graph.get_edge_count() != traffic_graph.get_edge_count()
+    });
+    if outdated {
+        *graph_snapshot = Some(Arc::new(traffic_graph.clone()));
+    }
+    let graph = Arc::clone(graph_snapshot.as_ref().unwrap_throw());
+    let seed = rand::random();
+    spawn_compute_task(commands, async move {
+        let generation = graph.get_generation();
+        AgentRoutes(find_routes(&graph, route_requests, seed), generation)
+    });
+}
+
+/// A request for a new path for the agent `entity`.
+struct RouteRequest {
+    entity: Entity,
+    from: NodeIndex,
+    to: NodeIndex,
+    agent_type: AgentType,
+}
+
+/// A type for storing the paths found by path finding tasks, and the generation
+/// of the traffic graph they were found in. A path is None if the agent is
+/// stuck.
+pub struct AgentRoutes(Vec<(Entity, Option<Vec<NodeIndex>>)>, u32);
+
+/// Finds a path for every request. Agents that cannot reach the requested
+/// node get a path to a random node they can reach instead.
+fn find_routes(
+    graph: &TrafficGraph,
+    requests: Vec<RouteRequest>,
+    seed: u64,
+) -> Vec<(Entity, Option<Vec<NodeIndex>>)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let is_valid = |path: &Option<Vec<NodeIndex>>| path.as_ref().map_or(false, |path| path.len() >= 2);
+
+    requests
+        .into_iter()
+        .map(|request| {
+            let mut path = graph.get_shortest_path(request.from, request.to, request.agent_type);
+            for _ in 0..REROUTE_ATTEMPTS {
+                if is_valid(&path) {
+                    break;
+                }
+                let destination = graph.get_random_destination_node_index(&mut rng);
+                path = graph.get_shortest_path(request.from, destination, request.agent_type);
+            }
+            (request.entity, path.filter(|path| path.len() >= 2))
+        })
+        .collect()
+}
+
+/// A system that polls path finding tasks, and gives agents their new path.
+/// Agents for which no path was found are removed.
+pub fn update_agent_route_tasks(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<AgentRoutes>)>,
+    mut agents: Query<&mut Agent>,
+    traffic_graph: Res<TrafficGraph>,
+) {
+    handle_compute_tasks(&mut commands, query, move |commands, data| {
+        let AgentRoutes(routes, generation) = data;
+        // The graph was reset while the paths were being found
+        if generation != traffic_graph.get_generation() {
+            return;
+        }
+
+        for (entity, path) in routes {
+            let Ok(mut agent) = agents.get_mut(entity) else {
+                continue;
+            };
+            match path {
+                Some(path) => {
+                    agent.destination = path[path.len() - 1];
+                    agent.path = path.into_boxed_slice();
+                    agent.path_index = 0;
+                    agent.next_path_location_edge = None;
+                    commands.entity(entity).remove::<PendingPath>();
+                }
+                None => commands.entity(entity).despawn_recursive(),
+            }
+        }
+    });
+}
+
+/// Returns the unit vector to the right of `direction` in the plane, looking
+/// in that direction.
+fn right_of(direction: Vec2) -> Vec2 {
+    vec2(-direction.y, direction.x).normalize_or_zero()
+}
+
+/// Returns where an agent traveling from `from` to `to` aims for at `to`, in
+/// its lane `lane_offset` to the right of the middle of the road. If the path
+/// continues to `after`, the location is where the lanes of both edges meet,
+/// so the agent does not cross the road at corners.
+///
+/// Two agents traveling in opposite directions over a straight two-way road
+/// are a lane apart:
+///
+/// ```
+/// use bevy::math::vec2;
+/// use city_visualizer::data::geography::WorldScale;
+/// use city_visualizer::data::road_type::RoadType;
+/// use city_visualizer::data::traffic_graph::EdgeData;
+/// use city_visualizer::earth::agent::lane_location;
+///
+/// let (west, middle, east) = (vec2(0.0, 0.0), vec2(50.0, 0.0), vec2(100.0, 0.0));
+/// let road = EdgeData::new(50.0, RoadType::Residential, true);
+/// let offset = road.lane_offset(false, &WorldScale::default());
+///
+/// for (eastbound, westbound) in [
+///     (lane_location(west, middle, Some(east), offset), lane_location(east, middle, Some(west), offset)),
+///     (lane_location(middle, east, None, offset), lane_location(middle, west, None, offset)),
+/// ] {
+///     assert!((eastbound.y - westbound.y).abs() >= 2.0 * offset - 1e-4);
+/// }
+/// ```
+pub fn lane_location(from: Vec2, to: Vec2, after: Option<Vec2>, lane_offset: f32) -> Vec2 {
+    let right = right_of(to - from);
+    let corner = match after {
+        Some(after) => match (right + right_of(after - to)).try_normalize() {
+            // sharp turns would put the corner far away, so limit how far
+            // the agent leaves the road
+            Some(miter) => miter * (1.0 / miter.dot(right).max(f32::EPSILON)).min(MAX_MITER_SCALE),
+            // the path turns back over the same road
+            None => right,
+        },
+        None => right,
+    };
+    to + corner * lane_offset
+}
+
+/// Adds a number of agents to the world, starting at a random point on a random edge going
+/// towards a random node. The random choices are made by a generator created from `seed`, and
+/// the types of the agents are split according to the mix of `settings`. Destinations are at
+/// most the maximum route distance of `settings` away from the start, at the given scale of
+/// the world.
+///
+/// Agents start in their lane of an edge leaving their start node, so agents that start at
+/// the same busy node do not all appear on the same spot.
+///
+/// Agents that start at the middle of a star are spread over all of its edges:
+///
+/// ```
+/// use bevy::math::{Vec2, Vec3Swizzles};
+/// use city_visualizer::data::geography::WorldScale;
+/// use city_visualizer::data::road_type::RoadType;
+/// use city_visualizer::data::traffic_graph::{Access, OneWay, TrafficGraph};
+/// use city_visualizer::earth::agent::{create_agents, AgentSettings};
+/// use std::sync::Arc;
+///
+/// let spokes = 8;
+/// let mut graph = TrafficGraph::default();
+/// for id in 1..=spokes {
+///     let end = Vec2::from_angle(id as f32 / spokes as f32 * std::f32::consts::TAU) * 100.0;
+///     graph.add_connection(0, Vec2::ZERO, id, end, OneWay::No, RoadType::Residential, Access::ALL);
+/// }
+/// let middle = graph.get_index(0).unwrap();
+/// let graph = Arc::new(graph);
+///
+/// let agents = create_agents(2000, Arc::clone(&graph), 0, AgentSettings::default(), WorldScale::default());
+/// let from_middle: Vec<_> = agents.iter().filter(|(_, agent)| agent.path[0] == middle).collect();
+///
+/// let mut per_edge = vec![0; spokes as usize + 1];
+/// for (location, agent) in &from_middle {
+///     per_edge[agent.path[1].index()] += 1;
+///     // somewhere along the edge towards the next vertex of the path
+///     let direction = graph.get_node_location(agent.path[1]).normalize();
+///     let along = location.xz().dot(direction);
+///     assert!((0.0..=100.0).contains(&along), "{} is not along its edge", location);
+/// }
+/// let most = per_edge.iter().max().unwrap();
+/// assert!(per_edge[1..].iter().all(|&count| count > 0), "not every edge is used: {:?}", per_edge);
+/// assert!(*most < from_middle.len() / 2, "most agents start on one edge: {:?}", per_edge);
+///
+/// // and not on the same spot
+/// let mut locations: Vec<[u32; 3]> = from_middle.iter().map(|(location, _)| location.to_array().map(f32::to_bits)).collect();
+/// locations.sort();
+/// locations.dedup();
+/// assert_eq!(locations.len(), from_middle.len());
+/// ```
 pub fn create_agents(
     number_of_agents: i32,
     traffic_graph: Arc<TrafficGraph>,
+    seed: u64,
+    settings: AgentSettings,
+    scale: WorldScale,
 ) -> Vec<(Vec3, Agent)> {
     let mut agents = Vec::new();
+    let max_route_distance = scale.units(settings.max_route_distance);
+    let mut rng = StdRng::seed_from_u64(seed);
 
     for _ in 0..number_of_agents {
-        let start_node = traffic_graph.get_random_node_index();
-        let end_node = traffic_graph.get_random_node_index();
+        let start_node = traffic_graph.get_random_node_index(&mut rng);
+        let mut end_node = traffic_graph.get_random_destination_node_index(&mut rng);
+        let distance = traffic_graph
+            .get_node_location(start_node)
+            .distance(traffic_graph.get_node_location(end_node));
+        if distance > max_route_distance {
+            end_node =
+                traffic_graph.get_random_destination_near(start_node, max_route_distance, &mut rng);
+        }
 
-        // 50% chance of being a pedestrian or car
-        let agent_type = if rand::random::<f32>() < PEDESTRIAN_CAR_SPLIT {
-            AgentType::Car
-        } else {
-            AgentType::Pedestrian
-        };
+        let agent_type = settings.mix.choose(&mut rng);
+
+        // Start somewhere on an edge leaving the start node, and travel to
+        // the end of that edge first, so the agent does not drive back
+        let start_edge = traffic_graph.get_random_outgoing_edge(start_node, agent_type, &mut rng);
+        let path_start = start_edge.map_or(start_node, |(next_node, _)| next_node);
 
         let maybe_path: Option<Vec<NodeIndex>> =
-            traffic_graph.get_shortest_path(start_node, end_node, agent_type);
+            traffic_graph.get_shortest_path(path_start, end_node, agent_type);
 
         if maybe_path.is_none() {
             // Should only very rarely happen, start and end are in different connected components
             continue;
         }
-        let path = maybe_path.unwrap_throw();
-
-        let location_2d = traffic_graph.get_node_location(start_node);
+        let mut path = maybe_path.unwrap_throw();
+
+        let start_location = traffic_graph.get_node_location(start_node);
+        let location_2d = match start_edge {
+            Some((next_node, edge_data)) => {
+                path.insert(0, start_node);
+
+                let next_location = traffic_graph.get_node_location(next_node);
+                let direction = next_location - start_location;
+                let lane_offset = edge_data.lane_offset(settings.left_hand_traffic, &scale);
+                start_location + direction * rng.gen::<f32>() + right_of(direction) * lane_offset
+            }
+            None => start_location,
+        };
         let location = Vec3::new(location_2d.x, 0.0, location_2d.y);
 
+        // Skip trivial paths, e.g. when the start and end node are the same
+        if path.len() < 2 {
+            continue;
+        }
+
         let agent = Agent {
             agent_type: agent_type,
             destination: end_node,
-            path: path,
+            path: path.into_boxed_slice(),
             path_index: 0,
-            next_path_location_road: None,
+            next_path_location_edge: None,
+            graph_generation: traffic_graph.get_generation(),
         };
 
         agents.push((location, agent));
@@ -190,10 +828,23 @@ pub fn create_agents(
     agents
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum AgentType {
     Car,
     Pedestrian,
+    Bicycle,
+}
+
+impl AgentType {
+    pub const ALL: [AgentType; 3] = [AgentType::Car, AgentType::Pedestrian, AgentType::Bicycle];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AgentType::Car => "car",
+            AgentType::Pedestrian => "pedestrian",
+            AgentType::Bicycle => "bicycle",
+        }
+    }
 }
 
 /// Reference speed is the average speed of a pedestrian; about 5 km/h in real life.
@@ -214,10 +865,24 @@ pub fn agent_speed_on_road_type(
                 RoadType::Tertiary => 12.0,
                 RoadType::Residential => 6.0, // about 30 km/h
                 RoadType::Unclassified => 6.0,
+                RoadType::Service => 4.0, // about 20 km/h
+                RoadType::Track => 4.0,
+                RoadType::LivingStreet => 3.0, // about 15 km/h
                 _ => 6.0,
             };
             multiplier * reference_speed
         }
         AgentType::Pedestrian => reference_speed, // Pedestrians always move at speed 1
+        AgentType::Bicycle => {
+            let multiplier = match road_type {
+                RoadType::Cycleway => 4.0, // about 20 km/h
+                RoadType::Path => 3.5,
+                RoadType::Residential => 3.5,
+                RoadType::Footway => 2.0, // shared with pedestrians
+                RoadType::Pedestrian => 2.0,
+                _ => 3.0, // about 15 km/h in traffic
+            };
+            multiplier * reference_speed
+        }
     }
 }
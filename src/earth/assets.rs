@@ -1,15 +1,79 @@
+use crate::data::building_type::LightingClass;
 use crate::data::road_type::{road_type_to_color, RoadType};
 
 use bevy::prelude::*;
 use bevy::render::render_asset::RenderAssetUsages;
 use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
-use bevy::render::texture::ImageSampler;
+use bevy::render::texture::{ImageAddressMode, ImageSampler, ImageSamplerDescriptor};
 
 use std::ops::RangeInclusive;
 
 use strum::IntoEnumIterator;
 
 use super::agent::AgentType;
+use super::overlay::OverlayLayer;
+
+/// Replaces the building facades by checker patterns, to check how wall
+/// textures are mapped onto buildings.
+const DEBUG_CHECKER_BUILDING_TEXTURE: bool = false;
+
+/// The number of different building styles in the building texture atlas.
+const BUILDING_STYLE_COUNT: u32 = 10;
+
+/// The number of cells per row in the building texture atlas.
+const BUILDING_ATLAS_COLUMNS: u32 = 5;
+
+/// The width and height in pixels of the facade tile of one building style.
+const FACADE_TILE_SIZE: u32 = 64;
+
+/// The width and height in pixels of the normal map of asphalt, which
+/// repeats.
+const ASPHALT_NORMAL_MAP_SIZE: u32 = 64;
+
+/// How bumpy the normal map of asphalt is: the slope of the steepest bumps.
+const ASPHALT_BUMP_STRENGTH: f32 = 2.0;
+
+/// The opacity of roads in tunnels, when they are shown.
+const TUNNEL_ALPHA: f32 = 0.4;
+
+/// How much of the saturation and lightness of the color of a road type is
+/// kept for private roads.
+const PRIVATE_ROAD_SATURATION: f32 = 0.4;
+const PRIVATE_ROAD_LIGHTNESS: f32 = 0.8;
+
+/// The width and height in pixels of the blob shadow texture under agents.
+const BLOB_SHADOW_TEXTURE_SIZE: u32 = 32;
+
+/// The opacity of the middle of the blob shadows under agents.
+const BLOB_SHADOW_OPACITY: f32 = 0.5;
+
+/// The color of the light from lit windows at night, which is faint.
+const WINDOW_LIGHT_COLOR: Color = Color::rgb(0.6, 0.45, 0.25);
+
+/// The color of the light from the windows of offices, which is colder.
+const OFFICE_WINDOW_LIGHT_COLOR: Color = Color::rgb(0.45, 0.5, 0.55);
+
+/// The number of different patterns of lit windows in homes, which take turns
+/// during the night.
+const WINDOW_PATTERN_COUNT: u32 = 4;
+
+/// The percentage of windows in homes that are lit at night.
+const LIT_WINDOW_PERCENTAGE: u32 = 45;
+
+/// The color of the light that lit roads reflect at night, multiplied by the
+/// color of the road.
+const LIT_ROAD_GLOW_COLOR: Color = Color::rgb(0.35, 0.28, 0.18);
+
+/// The height of traffic lights. Like all meshes of props such as agents and
+/// street lamps, their mesh is sized for the default scale, and the entities
+/// that use it are scaled along with the world.
+const TRAFFIC_LIGHT_HEIGHT: f32 = 4.0;
+
+/// The height of street lamps, where their head is.
+pub const STREET_LAMP_HEIGHT: f32 = 6.0;
+
+/// The color of the light of street lamps.
+pub const STREET_LAMP_LIGHT_COLOR: Color = Color::rgb(1.0, 0.8, 0.5);
 
 /// A global cache for assets that are reused between geographic features.
 #[derive(Resource)]
@@ -17,10 +81,19 @@ pub struct AssetCache {
     /// The number of different colors in the building color textures.
     building_texture_count: u32,
     building_material: Handle<StandardMaterial>,
+    building_residential_night_material: Handle<StandardMaterial>,
+    building_office_night_material: Handle<StandardMaterial>,
+    /// Emissive textures of the building texture atlas, with different
+    /// windows lit in each.
+    building_window_atlases: Vec<Handle<Image>>,
 
     /// The number of different colors in the building color textures.
     road_texture_count: u32,
     road_material: Handle<StandardMaterial>,
+    road_lit_night_material: Handle<StandardMaterial>,
+    road_stub_material: Handle<StandardMaterial>,
+    road_tunnel_material: Handle<StandardMaterial>,
+    road_normal_mapped_material: Handle<StandardMaterial>,
     river_material: Handle<StandardMaterial>,
 
     triangle_tree: Handle<Mesh>,
@@ -38,9 +111,70 @@ pub struct AssetCache {
     agent_pedestrian_mesh: Handle<Mesh>,
     agent_pedestrian_mesh_simple: Handle<Mesh>,
     agent_pedestrian_material: Handle<StandardMaterial>,
+    agent_bicycle_mesh: Handle<Mesh>,
+    agent_bicycle_material: Handle<StandardMaterial>,
+    agent_shadow_mesh: Handle<Mesh>,
+    agent_shadow_material: Handle<StandardMaterial>,
+
+    traffic_light_mesh: Handle<Mesh>,
+    traffic_light_red_material: Handle<StandardMaterial>,
+    traffic_light_green_material: Handle<StandardMaterial>,
+
+    street_lamp_mesh: Handle<Mesh>,
+    street_lamp_material: Handle<StandardMaterial>,
+    street_lamp_head_mesh: Handle<Mesh>,
+    street_lamp_head_material: Handle<StandardMaterial>,
+    street_lamp_head_lit_material: Handle<StandardMaterial>,
 }
 
 impl AssetCache {
+    /// Returns a cache with the texture layout of the real cache, but without
+    /// any assets: all handles are default handles.
+    ///
+    /// Used for generating meshes outside of a Bevy app, since mesh generation
+    /// only needs to know where features are in the texture atlases.
+    pub fn without_assets() -> Self {
+        AssetCache {
+            building_texture_count: BUILDING_STYLE_COUNT,
+            building_material: Handle::default(),
+            building_residential_night_material: Handle::default(),
+            building_office_night_material: Handle::default(),
+            building_window_atlases: Vec::new(),
+            road_texture_count: RoadType::iter().count() as u32,
+            road_material: Handle::default(),
+            road_lit_night_material: Handle::default(),
+            road_stub_material: Handle::default(),
+            road_tunnel_material: Handle::default(),
+            road_normal_mapped_material: Handle::default(),
+            river_material: Handle::default(),
+            triangle_tree: Handle::default(),
+            complex_tree: Handle::default(),
+            complex_tree_simple: Handle::default(),
+            tree_material: Handle::default(),
+            grass_material: Handle::default(),
+            white_material: Handle::default(),
+            agent_car_mesh: Handle::default(),
+            agent_car_mesh_simple: Handle::default(),
+            agent_car_material: Handle::default(),
+            agent_car_material_simple: Handle::default(),
+            agent_pedestrian_mesh: Handle::default(),
+            agent_pedestrian_mesh_simple: Handle::default(),
+            agent_pedestrian_material: Handle::default(),
+            agent_bicycle_mesh: Handle::default(),
+            agent_bicycle_material: Handle::default(),
+            agent_shadow_mesh: Handle::default(),
+            agent_shadow_material: Handle::default(),
+            traffic_light_mesh: Handle::default(),
+            traffic_light_red_material: Handle::default(),
+            traffic_light_green_material: Handle::default(),
+            street_lamp_mesh: Handle::default(),
+            street_lamp_material: Handle::default(),
+            street_lamp_head_mesh: Handle::default(),
+            street_lamp_head_material: Handle::default(),
+            street_lamp_head_lit_material: Handle::default(),
+        }
+    }
+
     /// Returns a clone of this object where all the handles to assets are
     /// weak.
     ///
@@ -50,8 +184,21 @@ impl AssetCache {
         AssetCache {
             building_texture_count: self.building_texture_count,
             building_material: self.building_material.clone_weak(),
+            building_residential_night_material: self
+                .building_residential_night_material
+                .clone_weak(),
+            building_office_night_material: self.building_office_night_material.clone_weak(),
+            building_window_atlases: self
+                .building_window_atlases
+                .iter()
+                .map(Handle::clone_weak)
+                .collect(),
             road_texture_count: self.road_texture_count,
             road_material: self.road_material.clone_weak(),
+            road_lit_night_material: self.road_lit_night_material.clone_weak(),
+            road_stub_material: self.road_stub_material.clone_weak(),
+            road_tunnel_material: self.road_tunnel_material.clone_weak(),
+            road_normal_mapped_material: self.road_normal_mapped_material.clone_weak(),
             river_material: self.river_material.clone_weak(),
             triangle_tree: self.triangle_tree.clone_weak(),
             complex_tree: self.complex_tree.clone_weak(),
@@ -66,6 +213,18 @@ impl AssetCache {
             agent_pedestrian_mesh: self.agent_pedestrian_mesh.clone_weak(),
             agent_pedestrian_mesh_simple: self.agent_pedestrian_mesh_simple.clone_weak(),
             agent_pedestrian_material: self.agent_pedestrian_material.clone_weak(),
+            agent_bicycle_mesh: self.agent_bicycle_mesh.clone_weak(),
+            agent_bicycle_material: self.agent_bicycle_material.clone_weak(),
+            agent_shadow_mesh: self.agent_shadow_mesh.clone_weak(),
+            agent_shadow_material: self.agent_shadow_material.clone_weak(),
+            traffic_light_mesh: self.traffic_light_mesh.clone_weak(),
+            traffic_light_red_material: self.traffic_light_red_material.clone_weak(),
+            traffic_light_green_material: self.traffic_light_green_material.clone_weak(),
+            street_lamp_mesh: self.street_lamp_mesh.clone_weak(),
+            street_lamp_material: self.street_lamp_material.clone_weak(),
+            street_lamp_head_mesh: self.street_lamp_head_mesh.clone_weak(),
+            street_lamp_head_material: self.street_lamp_head_material.clone_weak(),
+            street_lamp_head_lit_material: self.street_lamp_head_lit_material.clone_weak(),
         }
     }
 
@@ -74,19 +233,61 @@ impl AssetCache {
         Handle::clone(&self.building_material)
     }
 
-    /// Returns the number of different building styles (currently just plain
-    /// colors) that are stored in the building texture atlas.
+    /// Returns a handle to the material used for buildings of the given
+    /// lighting class when their windows are lit. Dark buildings always use
+    /// the normal building material.
+    pub fn get_building_night_material(&self, lighting: LightingClass) -> Handle<StandardMaterial> {
+        match lighting {
+            LightingClass::Residential => Handle::clone(&self.building_residential_night_material),
+            LightingClass::Office => Handle::clone(&self.building_office_night_material),
+            LightingClass::Dark => Handle::clone(&self.building_material),
+        }
+    }
+
+    /// Returns the emissive texture of the building texture atlas with the
+    /// given pattern of lit windows, which wraps around.
+    pub fn get_building_window_atlas(&self, pattern: u32) -> Option<Handle<Image>> {
+        let count = self.building_window_atlases.len();
+        if count == 0 {
+            return None;
+        }
+        let atlas = &self.building_window_atlases[pattern as usize % count];
+        Some(Handle::clone(atlas))
+    }
+
+    /// Returns the number of different building styles (facade tiles) that are
+    /// stored in the building texture atlas, excluding the neutral style.
     pub fn get_building_texture_count(&self) -> u32 {
         self.building_texture_count
     }
 
-    /// Returns for a building style index the (u, v) coordinate range in the
-    /// building texture atlas.
+    /// Returns for a building style index the (u, v) coordinate range of its
+    /// facade tile in the building texture atlas.
+    ///
+    /// The ranges are shrunk by half a pixel, so that neighbouring tiles do
+    /// not bleed in when the texture is sampled.
     pub fn get_wall_uv(&self, index: u32) -> (RangeInclusive<f32>, RangeInclusive<f32>) {
-        assert!(index < self.building_texture_count);
-        let interval_size = 1.0 / self.building_texture_count as f32;
-        let x_range = index as f32 * interval_size..=(index + 1) as f32 * interval_size;
-        (x_range, 0.0..=1.0)
+        assert!(index <= self.building_texture_count);
+        let tiles = self.building_texture_count + 1;
+        let rows = (tiles + BUILDING_ATLAS_COLUMNS - 1) / BUILDING_ATLAS_COLUMNS;
+        let width = (BUILDING_ATLAS_COLUMNS * FACADE_TILE_SIZE) as f32;
+        let height = (rows * FACADE_TILE_SIZE) as f32;
+
+        let column = index % BUILDING_ATLAS_COLUMNS;
+        let row = index / BUILDING_ATLAS_COLUMNS;
+        let x = (column * FACADE_TILE_SIZE) as f32;
+        let y = (row * FACADE_TILE_SIZE) as f32;
+        let tile = FACADE_TILE_SIZE as f32;
+        (
+            (x + 0.5) / width..=(x + tile - 0.5) / width,
+            (y + 0.5) / height..=(y + tile - 0.5) / height,
+        )
+    }
+
+    /// Returns the index of the white facade tile in the building texture
+    /// atlas, which is used for buildings that have a known colour.
+    pub fn get_neutral_wall_index(&self) -> u32 {
+        self.building_texture_count
     }
 
     /// Returns a handle to the material used for roads, which uses
@@ -96,6 +297,33 @@ impl AssetCache {
         Handle::clone(&self.road_material)
     }
 
+    /// Returns a handle to the material used for roads with street lighting at
+    /// night, which glow in the light of their lamps.
+    pub fn get_road_lit_night_material(&self) -> Handle<StandardMaterial> {
+        Handle::clone(&self.road_lit_night_material)
+    }
+
+    /// Returns a handle to the transparent material used for the stubs of
+    /// roads that are cut off, which uses a texture "atlas" that contains all
+    /// possible road colors fading out from top to bottom.
+    pub fn get_road_stub_material(&self) -> Handle<StandardMaterial> {
+        Handle::clone(&self.road_stub_material)
+    }
+
+    /// Returns a handle to the translucent material used for roads in
+    /// tunnels, which uses the same texture "atlas" as the road material.
+    pub fn get_road_tunnel_material(&self) -> Handle<StandardMaterial> {
+        Handle::clone(&self.road_tunnel_material)
+    }
+
+    /// Returns a handle to a variant of the road material with the bumps of
+    /// asphalt in a repeating normal map. Meshes that use it need tangents,
+    /// see `MeshBuilder::with_tangents`, and uv coordinates that vary over
+    /// the road for the bumps to show.
+    pub fn get_road_normal_mapped_material(&self) -> Handle<StandardMaterial> {
+        Handle::clone(&self.road_normal_mapped_material)
+    }
+
     /// Returns a handle to the material used for roads, which uses
     /// a texture "atlas" that contains all possible colors for the road. This
     /// is necessary to combine river meshes within a chunk.
@@ -106,11 +334,45 @@ impl AssetCache {
     pub fn get_road_uv(&self, road_type: RoadType) -> (RangeInclusive<f32>, RangeInclusive<f32>) {
         let index = road_type as u32;
         assert!(index < self.road_texture_count);
-        let interval_size = 1.0 / self.road_texture_count as f32;
-        let x_range = index as f32 * interval_size..=(index + 1) as f32 * interval_size;
+        self.get_road_texel_uv(index)
+    }
+
+    /// Returns the UV coordinates of the muted color of a road type, for
+    /// private roads. These are stored after the colors of public roads.
+    ///
+    /// ```
+    /// use city_visualizer::data::road_type::RoadType;
+    /// use city_visualizer::earth::assets::AssetCache;
+    ///
+    /// let asset_cache = AssetCache::without_assets();
+    /// let (public, _) = asset_cache.get_road_uv(RoadType::Residential);
+    /// let (private, _) = asset_cache.get_private_road_uv(RoadType::Residential);
+    /// assert!(private.start() >= public.end());
+    /// assert!(*private.end() <= 1.0);
+    /// ```
+    pub fn get_private_road_uv(&self, road_type: RoadType) -> (RangeInclusive<f32>, RangeInclusive<f32>) {
+        let index = road_type as u32;
+        assert!(index < self.road_texture_count);
+        self.get_road_texel_uv(self.road_texture_count + index)
+    }
+
+    /// Returns the UV coordinates of a texel of the road texture atlas, which
+    /// has a public and a private color for every road type.
+    fn get_road_texel_uv(&self, texel: u32) -> (RangeInclusive<f32>, RangeInclusive<f32>) {
+        let interval_size = 1.0 / (2 * self.road_texture_count) as f32;
+        let x_range = texel as f32 * interval_size..=(texel + 1) as f32 * interval_size;
         (x_range, 0.0..=1.0)
     }
 
+    /// Returns for a road type the u coordinate and the v coordinate range
+    /// (from opaque to transparent) in the road stub texture atlas.
+    pub fn get_road_stub_uv(&self, road_type: RoadType) -> (f32, RangeInclusive<f32>) {
+        let (x_range, _) = self.get_road_uv(road_type);
+        // the middle of the texels, so the colors of neighbouring road types
+        // and the edges of the fade are not sampled
+        ((x_range.start() + x_range.end()) / 2.0, 0.25..=0.75)
+    }
+
     pub fn get_river_uv(&self) -> (RangeInclusive<f32>, RangeInclusive<f32>) {
         (0.0..=1.0, 0.0..=1.0)
     }
@@ -145,11 +407,13 @@ impl AssetCache {
             match agent_type {
                 AgentType::Car => Handle::clone(&self.agent_car_mesh_simple),
                 AgentType::Pedestrian => Handle::clone(&self.agent_pedestrian_mesh_simple),
+                AgentType::Bicycle => Handle::clone(&self.agent_bicycle_mesh),
             }
         } else {
             match agent_type {
                 AgentType::Car => Handle::clone(&self.agent_car_mesh),
                 AgentType::Pedestrian => Handle::clone(&self.agent_pedestrian_mesh),
+                AgentType::Bicycle => Handle::clone(&self.agent_bicycle_mesh),
             }
         }
     }
@@ -168,10 +432,86 @@ impl AssetCache {
                 }
             }
             AgentType::Pedestrian => Handle::clone(&self.agent_pedestrian_material),
+            AgentType::Bicycle => Handle::clone(&self.agent_bicycle_material),
+        }
+    }
+
+    /// Returns a handle to the flat 1 by 1 quad used for the blob shadows
+    /// under agents.
+    pub fn get_agent_shadow_mesh(&self) -> Handle<Mesh> {
+        Handle::clone(&self.agent_shadow_mesh)
+    }
+
+    /// Returns a handle to the transparent material of the blob shadows under
+    /// agents, which is darkest in the middle.
+    pub fn get_agent_shadow_material(&self) -> Handle<StandardMaterial> {
+        Handle::clone(&self.agent_shadow_material)
+    }
+
+    /// Returns a handle to the mesh of traffic lights, a pole standing on
+    /// the origin.
+    pub fn get_traffic_light_mesh(&self) -> Handle<Mesh> {
+        Handle::clone(&self.traffic_light_mesh)
+    }
+
+    /// Returns a handle to the glowing material of a traffic light that is
+    /// green or red.
+    pub fn get_traffic_light_material(&self, green: bool) -> Handle<StandardMaterial> {
+        if green {
+            Handle::clone(&self.traffic_light_green_material)
+        } else {
+            Handle::clone(&self.traffic_light_red_material)
+        }
+    }
+
+    /// Returns a handle to the mesh of the pole of street lamps, standing on
+    /// the origin.
+    pub fn get_street_lamp_mesh(&self) -> Handle<Mesh> {
+        Handle::clone(&self.street_lamp_mesh)
+    }
+
+    /// Returns a handle to the material of the pole of street lamps.
+    pub fn get_street_lamp_material(&self) -> Handle<StandardMaterial> {
+        Handle::clone(&self.street_lamp_material)
+    }
+
+    /// Returns a handle to the mesh of the head of street lamps, around the
+    /// origin.
+    pub fn get_street_lamp_head_mesh(&self) -> Handle<Mesh> {
+        Handle::clone(&self.street_lamp_head_mesh)
+    }
+
+    /// Returns a handle to the material of the head of a street lamp, which
+    /// glows if the lamp is lit.
+    pub fn get_street_lamp_head_material(&self, lit: bool) -> Handle<StandardMaterial> {
+        if lit {
+            Handle::clone(&self.street_lamp_head_lit_material)
+        } else {
+            Handle::clone(&self.street_lamp_head_material)
         }
     }
 }
 
+/// Loads a model or texture from the assets directory, or returns a default
+/// handle if the app has no loader for the file, such as headless apps that
+/// only have the `AssetPlugin`. Those never show the model, so they do not
+/// need it.
+fn load_file<A: Asset>(asset_server: &AssetServer, path: &'static str) -> Handle<A> {
+    let extension = path
+        .split('#')
+        .next()
+        .and_then(|file| file.rsplit_once('.'))
+        .map_or("", |(_, extension)| extension);
+    // loaders are registered when the plugins are built, so this does not wait
+    let has_loader =
+        bevy::tasks::block_on(asset_server.get_asset_loader_with_extension(extension)).is_ok();
+    if has_loader {
+        asset_server.load(path)
+    } else {
+        Handle::default()
+    }
+}
+
 /// A system that initializes the global asset cache for geographic features.
 pub fn setup_asset_cache(
     mut commands: Commands,
@@ -181,25 +521,69 @@ pub fn setup_asset_cache(
     asset_server: Res<AssetServer>,
 ) {
     // buildings
-    let mut building_texture_data = Vec::new();
-    for i in 0..10 {
-        let color = Color::hsl(i as f32 / 10.0 * 360.0, 1.0, 0.75);
-        building_texture_data.extend(color.as_rgba_u8());
-    }
-
-    let building_texture_count = (building_texture_data.len() / 4) as u32;
-    let building_texture_atlas = images.add(create_color_map(building_texture_data));
-    let building_material = materials.add(create_texture_material(building_texture_atlas));
+    let mut building_colors = (0..BUILDING_STYLE_COUNT)
+        .map(|i| Color::hsl(i as f32 / BUILDING_STYLE_COUNT as f32 * 360.0, 0.35, 0.7))
+        .collect::<Vec<_>>();
+
+    let building_texture_count = building_colors.len() as u32;
+    // the last tile is neutral, so vertex colors can give it any color
+    building_colors.push(Color::WHITE);
+    let building_texture_atlas = images.add(create_building_atlas(&building_colors));
+    let building_material = materials.add(create_texture_material(building_texture_atlas.clone()));
+    let building_window_atlases = (0..WINDOW_PATTERN_COUNT)
+        .map(|pattern| {
+            images.add(create_building_window_atlas(
+                building_colors.len() as u32,
+                pattern,
+                LIT_WINDOW_PERCENTAGE,
+            ))
+        })
+        .collect::<Vec<_>>();
+    let building_residential_night_material = materials.add(StandardMaterial {
+        emissive: WINDOW_LIGHT_COLOR,
+        emissive_texture: Some(building_window_atlases[0].clone()),
+        ..create_texture_material(building_texture_atlas.clone())
+    });
+    // offices have all their lights on, or none at all
+    let office_window_atlas =
+        images.add(create_building_window_atlas(building_colors.len() as u32, 0, 100));
+    let building_office_night_material = materials.add(StandardMaterial {
+        emissive: OFFICE_WINDOW_LIGHT_COLOR,
+        emissive_texture: Some(office_window_atlas),
+        ..create_texture_material(building_texture_atlas)
+    });
 
-    // roads
+    // roads, with the muted colors of private roads after all public colors
     let mut road_texture_data = Vec::new();
     for road_type in RoadType::iter() {
         road_texture_data.extend(road_type_to_color(&road_type).as_rgba_u8());
     }
-
     let road_texture_count = (road_texture_data.len() / 4) as u32;
+    for road_type in RoadType::iter() {
+        road_texture_data.extend(private_road_color(road_type_to_color(&road_type)).as_rgba_u8());
+    }
+
+    let road_stub_texture_atlas = images.add(create_fading_color_map(road_texture_data.clone()));
     let road_texture_atlas = images.add(create_color_map(road_texture_data));
+    let road_tunnel_material = materials.add(StandardMaterial {
+        base_color: Color::rgba(1.0, 1.0, 1.0, TUNNEL_ALPHA),
+        alpha_mode: AlphaMode::Blend,
+        ..create_texture_material(road_texture_atlas.clone())
+    });
+    let road_lit_night_material = materials.add(StandardMaterial {
+        emissive: LIT_ROAD_GLOW_COLOR,
+        emissive_texture: Some(road_texture_atlas.clone()),
+        ..create_texture_material(road_texture_atlas.clone())
+    });
+    let road_normal_mapped_material = materials.add(StandardMaterial {
+        normal_map_texture: Some(images.add(create_asphalt_normal_map())),
+        ..create_texture_material(road_texture_atlas.clone())
+    });
     let road_material = materials.add(create_texture_material(road_texture_atlas));
+    let road_stub_material = materials.add(StandardMaterial {
+        alpha_mode: AlphaMode::Blend,
+        ..create_texture_material(road_stub_texture_atlas)
+    });
 
     let river_material = materials.add(StandardMaterial {
         base_color: Color::BLUE,
@@ -219,9 +603,9 @@ pub fn setup_asset_cache(
     tree_image.sampler = ImageSampler::nearest();
     let tree_atlas = images.add(tree_image);
     let tree_material = materials.add(create_texture_material(tree_atlas));
-    let triangle_tree = asset_server.load("triangle-tree.glb#Mesh0/Primitive0");
-    let complex_tree = asset_server.load("complex-tree.glb#Mesh0/Primitive0");
-    let complex_tree_simple = asset_server.load("complex-tree-simple.glb#Mesh0/Primitive0");
+    let triangle_tree = load_file(&asset_server, "triangle-tree.glb#Mesh0/Primitive0");
+    let complex_tree = load_file(&asset_server, "complex-tree.glb#Mesh0/Primitive0");
+    let complex_tree_simple = load_file(&asset_server, "complex-tree-simple.glb#Mesh0/Primitive0");
 
     // Grass
     let grass_material = materials.add(StandardMaterial {
@@ -230,12 +614,12 @@ pub fn setup_asset_cache(
         ..default()
     });
 
-    let agent_car_mesh = asset_server.load("Car.glb#Mesh0/Primitive0");
+    let agent_car_mesh = load_file(&asset_server, "Car.glb#Mesh0/Primitive0");
     let agent_car_material = materials.add(create_texture_material(
-        asset_server.load("Car_texture.png").into(),
+        load_file(&asset_server, "Car_texture.png"),
     ));
 
-    let agent_car_mesh_simple = asset_server.load("Car_low.glb#Mesh0/Primitive0");
+    let agent_car_mesh_simple = load_file(&asset_server, "Car_low.glb#Mesh0/Primitive0");
     let agent_car_material_simple = materials.add(StandardMaterial {
         base_color: Color::rgba_u8(227, 0, 6, 255), // Red from car :)
         ..default()
@@ -249,11 +633,64 @@ pub fn setup_asset_cache(
         ..Default::default()
     });
 
+    // A bicycle with its rider, as a thin block facing the z-axis
+    let agent_bicycle_mesh =
+        meshes.add(Mesh::from(Cuboid::new(0.4, 1.8, 1.8)).translated_by(Vec3::new(0.0, 0.9, 0.0)));
+    let agent_bicycle_material = materials.add(StandardMaterial {
+        base_color: Color::rgb(0.9, 0.6, 0.1),
+        ..Default::default()
+    });
+
+    let agent_shadow_mesh = meshes.add(Plane3d::default().mesh().size(1.0, 1.0));
+    let agent_shadow_material = materials.add(OverlayLayer::AgentShadow.material(StandardMaterial {
+        base_color_texture: Some(images.add(create_blob_shadow_image())),
+        alpha_mode: AlphaMode::Blend,
+        unlit: true,
+        ..default()
+    }));
+
+    // Traffic lights
+    let traffic_light_mesh = meshes.add(
+        Mesh::from(Cuboid::new(0.4, TRAFFIC_LIGHT_HEIGHT, 0.4))
+            .translated_by(Vec3::new(0.0, TRAFFIC_LIGHT_HEIGHT / 2.0, 0.0)),
+    );
+    let traffic_light_red_material = materials.add(StandardMaterial {
+        base_color: Color::RED,
+        emissive: Color::RED,
+        ..default()
+    });
+    let traffic_light_green_material = materials.add(StandardMaterial {
+        base_color: Color::GREEN,
+        emissive: Color::GREEN,
+        ..default()
+    });
+
+    // Street lamps
+    let street_lamp_mesh = meshes.add(
+        Mesh::from(Cuboid::new(0.2, STREET_LAMP_HEIGHT, 0.2))
+            .translated_by(Vec3::new(0.0, STREET_LAMP_HEIGHT / 2.0, 0.0)),
+    );
+    let street_lamp_material = materials.add(Color::rgb(0.2, 0.2, 0.2));
+    let street_lamp_head_mesh = meshes.add(Mesh::from(Sphere::new(0.5)));
+    let street_lamp_head_material = materials.add(Color::rgb(0.8, 0.8, 0.75));
+    let street_lamp_head_lit_material = materials.add(StandardMaterial {
+        base_color: STREET_LAMP_LIGHT_COLOR,
+        emissive: STREET_LAMP_LIGHT_COLOR,
+        ..default()
+    });
+
     commands.insert_resource(AssetCache {
         building_texture_count,
         building_material,
+        building_residential_night_material,
+        building_office_night_material,
+        building_window_atlases,
         road_texture_count,
         road_material,
+        road_lit_night_material,
+        road_stub_material,
+        road_tunnel_material,
+        road_normal_mapped_material,
         river_material,
         triangle_tree,
         complex_tree_simple,
@@ -268,10 +705,29 @@ pub fn setup_asset_cache(
         agent_pedestrian_mesh_simple,
         agent_car_material_simple,
         agent_pedestrian_material,
+        agent_bicycle_mesh,
+        agent_bicycle_material,
+        agent_shadow_mesh,
+        agent_shadow_material,
+        traffic_light_mesh,
+        traffic_light_red_material,
+        traffic_light_green_material,
+        street_lamp_mesh,
+        street_lamp_material,
+        street_lamp_head_mesh,
+        street_lamp_head_material,
+        street_lamp_head_lit_material,
     });
 }
 
 /// Creates an image (texture) with thee given data, assumed to be RGBA.
+/// Mutes the color of a road for private roads: desaturated and a bit darker,
+/// so that white roads are distinguishable as well.
+fn private_road_color(color: Color) -> Color {
+    let [hue, saturation, lightness, alpha] = color.as_hsla_f32();
+    Color::hsla(hue, saturation * PRIVATE_ROAD_SATURATION, lightness * PRIVATE_ROAD_LIGHTNESS, alpha)
+}
+
 fn create_color_map(texture_data: Vec<u8>) -> Image {
     let count = texture_data.len() as u32 / 4;
     Image::new(
@@ -287,6 +743,220 @@ fn create_color_map(texture_data: Vec<u8>) -> Image {
     )
 }
 
+/// Creates the building texture atlas, which contains a procedural facade tile
+/// for every given wall color: the wall, a darker grid of windows and a darker
+/// band at the floor. Every tile covers one level of a building.
+///
+/// If `DEBUG_CHECKER_BUILDING_TEXTURE` is set, the tiles are checker patterns
+/// instead.
+fn create_building_atlas(colors: &[Color]) -> Image {
+    let count = colors.len() as u32;
+    let rows = (count + BUILDING_ATLAS_COLUMNS - 1) / BUILDING_ATLAS_COLUMNS;
+    let width = BUILDING_ATLAS_COLUMNS * FACADE_TILE_SIZE;
+    let height = rows * FACADE_TILE_SIZE;
+
+    let mut data = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let style = (y / FACADE_TILE_SIZE) * BUILDING_ATLAS_COLUMNS + x / FACADE_TILE_SIZE;
+            let Some(&wall) = colors.get(style as usize) else {
+                data.extend([0, 0, 0, 255]);
+                continue;
+            };
+            let color = get_facade_color(wall, x % FACADE_TILE_SIZE, y % FACADE_TILE_SIZE);
+            data.extend(color.as_rgba_u8());
+        }
+    }
+
+    Image::new(
+        Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8Unorm,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+}
+
+/// Creates the emissive texture of the building texture atlas at night, with
+/// the same layout: some windows are lit, everything else is black.
+///
+/// About `lit_percentage` percent of the windows are lit; which ones depends
+/// on `pattern`, so that different patterns can take turns.
+fn create_building_window_atlas(count: u32, pattern: u32, lit_percentage: u32) -> Image {
+    let rows = (count + BUILDING_ATLAS_COLUMNS - 1) / BUILDING_ATLAS_COLUMNS;
+    let width = BUILDING_ATLAS_COLUMNS * FACADE_TILE_SIZE;
+    let height = rows * FACADE_TILE_SIZE;
+
+    let mut data = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let style = (y / FACADE_TILE_SIZE) * BUILDING_ATLAS_COLUMNS + x / FACADE_TILE_SIZE;
+            let (tile_x, tile_y) = (x % FACADE_TILE_SIZE, y % FACADE_TILE_SIZE);
+            let in_window_column = (8..24).contains(&tile_x) || (40..56).contains(&tile_x);
+            let in_window_row = (12..44).contains(&tile_y);
+            // the neutral style is used for other things than facades
+            let is_facade = style < count - 1;
+            // light some of the windows, depending on the style, so
+            // buildings do not look the same
+            let window = u32::from(tile_x >= FACADE_TILE_SIZE / 2);
+            let lit = window_hash(style, window, pattern) % 100 < lit_percentage;
+            if is_facade && in_window_column && in_window_row && lit {
+                data.extend([255, 255, 255, 255]);
+            } else {
+                data.extend([0, 0, 0, 255]);
+            }
+        }
+    }
+
+    Image::new(
+        Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8Unorm,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+}
+
+/// Returns a number for a window of a facade tile in a pattern of lit windows
+/// that looks random, but is the same every time.
+fn window_hash(style: u32, window: u32, pattern: u32) -> u32 {
+    let mut hash = style
+        .wrapping_mul(73_856_093)
+        ^ window.wrapping_mul(19_349_663)
+        ^ pattern.wrapping_mul(83_492_791);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0x5bd1_e995);
+    hash ^ (hash >> 15)
+}
+
+/// Returns the color at pixel (`x`, `y`) of a facade tile with the given wall
+/// color. The top left pixel is always the wall color, since it is used for
+/// the roofs.
+fn get_facade_color(wall: Color, x: u32, y: u32) -> Color {
+    if DEBUG_CHECKER_BUILDING_TEXTURE {
+        return if (x / 8 + y / 8) % 2 == 0 { wall } else { Color::BLACK };
+    }
+
+    // two windows per tile, side by side
+    let in_window_column = (8..24).contains(&x) || (40..56).contains(&x);
+    let in_window_row = (12..44).contains(&y);
+    if in_window_column && in_window_row {
+        return Color::rgb(0.15, 0.18, 0.22);
+    }
+
+    // band that separates the floors
+    if y >= 56 {
+        return Color::rgb(wall.r() * 0.7, wall.g() * 0.7, wall.b() * 0.7);
+    }
+
+    wall
+}
+
+/// Creates an image (texture) with two rows: the first row contains the given
+/// RGBA data, and the second row contains the same colors but fully
+/// transparent. Sampling between the two rows fades the colors out.
+fn create_fading_color_map(mut texture_data: Vec<u8>) -> Image {
+    let count = texture_data.len() as u32 / 4;
+    let transparent = texture_data
+        .chunks(4)
+        .flat_map(|color| [color[0], color[1], color[2], 0])
+        .collect::<Vec<_>>();
+    texture_data.extend(transparent);
+    Image::new(
+        Extent3d {
+            width: count,
+            height: 2,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        texture_data,
+        TextureFormat::Rgba8Unorm,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+}
+
+/// Creates the repeating normal map of asphalt, from a height map of random
+/// bumps that is smoothed so neighbouring pixels form small grains.
+fn create_asphalt_normal_map() -> Image {
+    let size = ASPHALT_NORMAL_MAP_SIZE;
+    // the same hash as the lit windows, so the bumps are the same every time
+    let noise = |x: u32, y: u32| {
+        (window_hash(x % size, y % size, 0) % 1000) as f32 / 1000.0
+    };
+    let height = |x: u32, y: u32| {
+        let (left, right) = ((x + size - 1) % size, (x + 1) % size);
+        let (top, bottom) = ((y + size - 1) % size, (y + 1) % size);
+        (noise(x, y) * 4.0 + noise(left, y) + noise(right, y) + noise(x, top) + noise(x, bottom))
+            / 8.0
+    };
+
+    let mut data = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            let dx = height((x + 1) % size, y) - height((x + size - 1) % size, y);
+            let dy = height(x, (y + 1) % size) - height(x, (y + size - 1) % size);
+            let normal = Vec3::new(-dx * ASPHALT_BUMP_STRENGTH, -dy * ASPHALT_BUMP_STRENGTH, 1.0).normalize();
+            let [r, g, b] = (normal * 0.5 + 0.5).to_array().map(|value| (value * 255.0).round() as u8);
+            data.extend([r, g, b, 255]);
+        }
+    }
+
+    let mut image = Image::new(
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        // normals are not colors, so they are stored linearly
+        TextureFormat::Rgba8Unorm,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        address_mode_u: ImageAddressMode::Repeat,
+        address_mode_v: ImageAddressMode::Repeat,
+        ..ImageSamplerDescriptor::linear()
+    });
+    image
+}
+
+/// Creates a black image that is transparent at the edges and most opaque
+/// in the middle, for blob shadows.
+fn create_blob_shadow_image() -> Image {
+    let size = BLOB_SHADOW_TEXTURE_SIZE;
+    let mut data = Vec::with_capacity((size * size * 4) as usize);
+    for y in 0..size {
+        for x in 0..size {
+            // distance to the center, 1 at the middle of the edges
+            let dx = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+            let dy = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+            let distance = (dx * dx + dy * dy).sqrt().min(1.0);
+            let alpha = BLOB_SHADOW_OPACITY * (1.0 - distance * distance);
+            data.extend([0, 0, 0, (alpha * 255.0) as u8]);
+        }
+    }
+    Image::new(
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::RENDER_WORLD,
+    )
+}
+
 fn create_texture_material(texture: Handle<Image>) -> StandardMaterial {
     StandardMaterial {
         base_color: Color::WHITE,
@@ -1,11 +1,18 @@
 use super::assets::AssetCache;
-use super::GLOBAL_SCALE_FACTOR;
+use super::SIZE_EXAGGERATION;
+use crate::data::colour::parse_colour;
+use crate::data::levels::{get_building_height, get_building_levels, get_roof_levels};
 use crate::data::building_type::{
-    get_random_range_building, BuildingLandUseType, BuildingType, PartialBuilding, RoofShape,
+    building_type_to_lighting, get_random_range_building, BuildingLandUseType, BuildingType,
+    LightingClass, PartialBuilding, RoofShape,
 };
-use crate::data::geography::{BuildingFeature, GeoLocation, LandUseFeature, Offset};
-use crate::earth::mesh_builder::MeshBuilder;
+use crate::data::geography::{
+    close_ring, project_nodes, BuildingFeature, GeoLocation, LandUseFeature, Offset, WorldScale,
+};
+use crate::earth::generation_settings::GenerationSettings;
+use crate::earth::mesh_builder::{clean_ring, MeshBuilder, PrismStyle};
 use crate::earth::simplification::simplify_polygon;
+use crate::lod::LodMesh;
 use wasm_bindgen::prelude::*;
 
 use bevy::prelude::*;
@@ -13,43 +20,73 @@ use rand::Rng;
 use std::collections::hash_map::HashMap;
 use std::str::FromStr;
 
-const DIST_UNIT_PER_LEVEL: f32 = 0.04 * GLOBAL_SCALE_FACTOR;
-const THRESHOLD_SIMPLIFICATION: f32 = 0.00001 * GLOBAL_SCALE_FACTOR * GLOBAL_SCALE_FACTOR;
-const THRESHOLD_APARTMENT_BASE_SIZE: f32 = 0.75 * GLOBAL_SCALE_FACTOR; // Residential buildings with a base smaller than this are considered houses, else apartments
-const THRESHOLD_SMALL_BUILDING: f32 = 0.05 * GLOBAL_SCALE_FACTOR; // Buildings with a base smaller than this are considered small and thus can only have 1 level
-const THRESHOLD_NON_RESIDENTIAL_BUILDING: f32 = 0.25 * GLOBAL_SCALE_FACTOR; // Non-residential buildings are capped for their height depending on this, so that small based buildings aren't enormous
+// Lengths and areas are in world units at the default scale, see `WorldScale`
+const METERS_PER_LEVEL: f32 = 3.0;
+const THRESHOLD_FAR_SIMPLIFICATION: f32 = 4.0; // Coarser simplification of the footprints of buildings in the distance
+
+// Rough number of vertices and indices of a prism per corner of its base: a
+// roof vertex and a couple of wall pieces of four vertices and two triangles
+const ESTIMATED_VERTICES_PER_CORNER: usize = 9;
+const ESTIMATED_INDICES_PER_CORNER: usize = 15;
+const THRESHOLD_APARTMENT_BASE_SIZE: f32 = 75.0; // Residential buildings with a base smaller than this are considered houses, else apartments
+const THRESHOLD_SMALL_BUILDING: f32 = 5.0; // Buildings with a base smaller than this are considered small and thus can only have 1 level
+const THRESHOLD_NON_RESIDENTIAL_BUILDING: f32 = 25.0; // Non-residential buildings are capped for their height depending on this, so that small based buildings aren't enormous
+const WALL_TILE_WIDTH: f32 = 8.0; // Width of a wall after which the wall texture repeats
+const LANDUSE_GRID_CELL_SIZE: f32 = 100.0; // Size of the cells of the grid used to look up land use areas
+const FOOTPRINT_GRID_CELL_SIZE: f32 = 25.0; // Size of the cells of the grid used to look up building footprints, at every scale, which only affects how fast lookups are
+const FOOTPRINT_PUSH_DISTANCE: f32 = 0.2; // How far outside of a footprint points are pushed, so they end up clearly outside
 
 // What tags OSM uses for buildings
 const TAG_BUILDING_TYPE: &str = "building";
-const TAG_BUILDING_LEVELS: &str = "building:levels";
 const TAG_BUILDING_ROOF_SHAPE: &str = "roof:shape";
-const TAG_BUILDING_ROOF_LEVELS: &str = "roof:levels";
-
+const TAG_BUILDING_COLOUR: &str = "building:colour";
+const TAG_BUILDING_ROOF_COLOUR: &str = "roof:colour";
+
+/// Converts the building features in a chunk to meshes, one for every way
+/// their windows are lit at night, each with a far version with coarser
+/// footprints. Also returns the footprint (base polygon) of every building,
+/// by OSM id, and the number of buildings that were skipped because their
+/// footprint crosses itself or has no area. Footprints and land use areas
+/// are simplified as much as `settings` says.
 pub fn create_building_data(
     node_locations: &HashMap<u64, GeoLocation>,
     building_features: &HashMap<u64, BuildingFeature>,
     landuse_features: &HashMap<u64, LandUseFeature>,
     asset_cache: &AssetCache,
     offset: &Offset,
-) -> Mesh {
-    let mut rng = rand::thread_rng();
+    settings: &GenerationSettings,
+) -> (Vec<(LightingClass, LodMesh)>, Vec<(u64, Vec<Vec2>)>, usize) {
     let mut partial_buildings = Vec::new();
 
     // Go over all land use areas related to buildings
-    let building_related_landuse = get_building_land_use(landuse_features, node_locations, offset);
+    let scale = offset.scale;
+    let building_related_landuse = get_building_land_use(landuse_features, node_locations, offset, settings);
+    let level_height = scale.meters(METERS_PER_LEVEL) * SIZE_EXAGGERATION;
 
     // loop over building data and create partial buildings
     let mut _total_vertices = 0;
     let mut _total_vertices_simplified = 0;
+    let mut skipped = 0;
     for (&id, building) in building_features {
         // Create base from nodes and fix ordering of base vertices
         let base_locations = match create_building_base(node_locations, &building, offset) {
             Some(base) => base,
             None => continue,
         };
-        let base = polygon_counterclockwise_ordering(base_locations);
+        // Drop repeated and collinear nodes, and skip footprints that can
+        // not be triangulated
+        let base = match clean_ring(&base_locations) {
+            Ok(base) => polygon_counterclockwise_ordering(base),
+            Err(_) => {
+                skipped += 1;
+                continue;
+            }
+        };
         _total_vertices += base.len();
-        let base = simplify_polygon(base, THRESHOLD_SIMPLIFICATION);
+        // Simplifying can make a footprint cross itself, in which case the
+        // footprint is kept as it is
+        let threshold = scale.area(settings.building_simplification);
+        let base = clean_ring(&simplify_polygon(base.clone(), threshold)).unwrap_or(base);
         _total_vertices_simplified += base.len();
 
         // Get all the data
@@ -69,11 +106,8 @@ pub fn create_building_data(
 
         // Check if we are inside the polygon for any of the land use areas
         let building_point = partial_building.base[0];
-        for (landuse_polygon, landuse_type) in &building_related_landuse {
-            if point_in_polygon_check(landuse_polygon, building_point) {
-                partial_building.inside_area = *landuse_type;
-                break;
-            }
+        if let Some(landuse_type) = building_related_landuse.find(building_point) {
+            partial_building.inside_area = landuse_type;
         }
     }
 
@@ -83,9 +117,12 @@ pub fn create_building_data(
     //     total_vertices, total_vertices_simplified
     // );
 
-    // loop over partial buildings, fill in gaps in data and create the entities
-    let mut builder = MeshBuilder::new();
+    // loop over partial buildings and fill in gaps in data
+    let mut prisms = Vec::with_capacity(partial_buildings.len());
+    let mut footprints = Vec::with_capacity(partial_buildings.len());
     for partial_building in partial_buildings {
+        footprints.push((partial_building.id, partial_building.base.clone()));
+
         // Fill in gaps in data
         // let mut interpolated = false;
 
@@ -95,14 +132,7 @@ pub fn create_building_data(
             // interpolated = true;
             match partial_building.inside_area {
                 BuildingLandUseType::Residential => {
-                    // If the base is small we assume it is a house, otherwise an apartment building
-                    if calculate_polygon_area(&partial_building.base)
-                        < THRESHOLD_APARTMENT_BASE_SIZE
-                    {
-                        BuildingType::House
-                    } else {
-                        BuildingType::Apartments
-                    }
+                    residential_building_type(&partial_building.base, &scale)
                 }
                 BuildingLandUseType::Commercial => BuildingType::Commercial,
                 BuildingLandUseType::Industrial => BuildingType::Industrial,
@@ -117,7 +147,7 @@ pub fn create_building_data(
         let number_of_levels = if partial_building.levels.is_none() {
             // interpolated = true;
             let area = calculate_polygon_area(&partial_building.base);
-            if area < THRESHOLD_SMALL_BUILDING {
+            if area < scale.area(THRESHOLD_SMALL_BUILDING) {
                 1
             } else {
                 let mut rng = rand::thread_rng();
@@ -135,7 +165,7 @@ pub fn create_building_data(
                     || building_type == BuildingType::Transportation
                     || building_type == BuildingType::Civic
                 {
-                    let cap = (area / THRESHOLD_NON_RESIDENTIAL_BUILDING).floor() as i32 + 1;
+                    let cap = (area / scale.area(THRESHOLD_NON_RESIDENTIAL_BUILDING)).floor() as i32 + 1;
                     levels = levels.min(cap);
                 }
 
@@ -145,22 +175,90 @@ pub fn create_building_data(
             partial_building.levels.unwrap_throw()
         };
 
-        let height = DIST_UNIT_PER_LEVEL
-            * (number_of_levels + partial_building.roof_levels.clone().unwrap_or(0)) as f32;
+        let height = match partial_building.height {
+            Some(meters) => scale.meters(meters) * SIZE_EXAGGERATION,
+            None => level_height
+                * (number_of_levels + partial_building.roof_levels.clone().unwrap_or(0)) as f32,
+        };
+
+        // Pick the style based on the id, so a building always looks the same,
+        // unless the colour of the building is known
+        let index = match partial_building.colour {
+            Some(_) => asset_cache.get_neutral_wall_index(),
+            None => (partial_building.id % asset_cache.get_building_texture_count() as u64) as u32,
+        };
+        let wall_uv = asset_cache.get_wall_uv(index);
+
+        // Roofs use the top left pixel of a facade tile, which is the wall
+        // color; a known roof colour is applied on top of a neutral tile
+        let roof_uv = match partial_building.roof_colour {
+            Some(_) => asset_cache.get_wall_uv(asset_cache.get_neutral_wall_index()),
+            None => wall_uv.clone(),
+        };
 
-        let index = rng.gen_range(0..asset_cache.get_building_texture_count());
-        let uv_range = asset_cache.get_wall_uv(index);
-        let uv = Vec2::new(*uv_range.0.start(), *uv_range.1.start());
+        let style = PrismStyle {
+            wall_uv,
+            tile_size: Vec2::new(scale.units(WALL_TILE_WIDTH), level_height),
+            wall_color: partial_building.colour.unwrap_or(Color::WHITE),
+            roof_uv: Vec2::new(*roof_uv.0.start(), *roof_uv.1.start()),
+            roof_color: partial_building.roof_colour.unwrap_or(Color::WHITE),
+        };
+        let far_base = simplify_polygon(
+            partial_building.base.clone(),
+            scale.area(THRESHOLD_FAR_SIMPLIFICATION),
+        );
+        let lighting = building_type_to_lighting(building_type);
+        prisms.push((lighting, partial_building.base, far_base, height, style));
+    }
 
-        // Generate mesh from base
-        builder.add_prism_from_path(&partial_building.base, height, uv);
+    // Make room for the prisms of every lighting class up front
+    let mut corners: HashMap<LightingClass, (usize, usize)> = HashMap::new();
+    for (lighting, base, far_base, _, _) in &prisms {
+        let count = corners.entry(*lighting).or_default();
+        count.0 += base.len();
+        count.1 += far_base.len();
+    }
+    let mut builders: HashMap<LightingClass, (MeshBuilder, MeshBuilder)> = corners
+        .into_iter()
+        .map(|(lighting, (corners, far_corners))| {
+            let builder = |corners: usize| {
+                MeshBuilder::with_capacity(
+                    corners * ESTIMATED_VERTICES_PER_CORNER,
+                    corners * ESTIMATED_INDICES_PER_CORNER,
+                )
+            };
+            (lighting, (builder(corners), builder(far_corners)))
+        })
+        .collect();
+
+    // Generate meshes from the bases
+    for (lighting, base, far_base, height, style) in prisms {
+        let (builder, far_builder) = builders.get_mut(&lighting).unwrap_throw();
+        if builder.add_prism_from_path(&base, height, style.clone()).is_err() {
+            skipped += 1;
+            continue;
+        }
+        // A far footprint that is too coarse falls back to the detailed one
+        if far_builder.add_prism_from_path(&far_base, height, style.clone()).is_err() {
+            far_builder.add_prism_from_path(&base, height, style).unwrap_throw();
+        }
     }
 
-    builder.into_mesh()
+    let meshes = builders
+        .into_iter()
+        .map(|(lighting, (builder, far_builder))| {
+            let mesh = LodMesh {
+                detailed: builder.into_mesh(),
+                far: far_builder.into_mesh(),
+            };
+            (lighting, mesh)
+        })
+        .collect();
+    (meshes, footprints, skipped)
 }
 
-// Note: this is kinda of an awful way to do this, better would be some precomputed spatial data structure with fast queries
-fn point_in_polygon_check(polygon: &Vec<Vec2>, point: Vec2) -> bool {
+/// Returns whether `point` lies inside of `polygon`, using ray casting.
+pub fn point_in_polygon_check(polygon: &Vec<Vec2>, point: Vec2) -> bool {
     let mut inside = false;
     
     for i in 0..polygon.len() {
@@ -177,12 +275,171 @@ fn point_in_polygon_check(polygon: &Vec<Vec2>, point: Vec2) -> bool {
     inside
 }
 
+/// A uniform grid over land use polygons, used to quickly find the polygons
+/// that may contain a point.
+pub struct LandUseIndex {
+    /// Land use polygons with their bounding box (min, max), from largest to smallest.
+    areas: Vec<(Vec<Vec2>, BuildingLandUseType, Vec2, Vec2)>,
+    /// The size of the grid cells, in world units.
+    cell_size: f32,
+    /// For every grid cell, the indices into `areas` of the polygons whose
+    /// bounding box overlaps the cell, in ascending order.
+    cells: HashMap<(i64, i64), Vec<usize>>,
+}
+
+impl LandUseIndex {
+    /// Builds the index over land use polygons, which are expected to be
+    /// ordered from largest to smallest.
+    pub fn new(areas: Vec<(Vec<Vec2>, BuildingLandUseType)>, scale: &WorldScale) -> Self {
+        let mut index = LandUseIndex {
+            areas: Vec::with_capacity(areas.len()),
+            cell_size: scale.units(LANDUSE_GRID_CELL_SIZE),
+            cells: HashMap::new(),
+        };
+
+        for (i, (polygon, landuse_type)) in areas.into_iter().enumerate() {
+            let min = polygon.iter().fold(Vec2::INFINITY, |acc, point| acc.min(*point));
+            let max = polygon.iter().fold(Vec2::NEG_INFINITY, |acc, point| acc.max(*point));
+
+            // Empty polygons have no (finite) bounding box and cannot contain points
+            if !polygon.is_empty() {
+                let (min_x, min_z) = grid_cell(min, index.cell_size);
+                let (max_x, max_z) = grid_cell(max, index.cell_size);
+                for x in min_x..=max_x {
+                    for z in min_z..=max_z {
+                        index.cells.entry((x, z)).or_default().push(i);
+                    }
+                }
+            }
+
+            index.areas.push((polygon, landuse_type, min, max));
+        }
+
+        index
+    }
+
+    /// Returns the type of the first (i.e. largest) land use area that
+    /// contains `point`, if any.
+    pub fn find(&self, point: Vec2) -> Option<BuildingLandUseType> {
+        let candidates = self.cells.get(&grid_cell(point, self.cell_size))?;
+        candidates
+            .iter()
+            .map(|&i| &self.areas[i])
+            .find(|(polygon, _, min, max)| {
+                point.cmpge(*min).all()
+                    && point.cmple(*max).all()
+                    && point_in_polygon_check(polygon, point)
+            })
+            .map(|(_, landuse_type, _, _)| *landuse_type)
+    }
+}
+
+/// Returns the cell of a grid with cells of the given size that contains
+/// `point`.
+fn grid_cell(point: Vec2, cell_size: f32) -> (i64, i64) {
+    (
+        (point.x / cell_size).floor() as i64,
+        (point.y / cell_size).floor() as i64,
+    )
+}
+
+/// The footprints of all buildings in the world, in a uniform grid so that
+/// the buildings near a point can be found quickly.
+#[derive(Default, Resource)]
+pub struct BuildingFootprints {
+    /// Footprint polygons with their bounding box (min, max), by OSM id.
+    footprints: HashMap<u64, (Vec<Vec2>, Vec2, Vec2)>,
+    /// For every grid cell, the ids of the buildings whose bounding box
+    /// overlaps the cell.
+    cells: HashMap<(i64, i64), Vec<u64>>,
+}
+
+impl BuildingFootprints {
+    /// Adds the footprint of a building. Buildings that were added before
+    /// (e.g. because their chunk was loaded again) are ignored.
+    pub fn insert(&mut self, id: u64, polygon: Vec<Vec2>) {
+        if polygon.len() < 3 || self.footprints.contains_key(&id) {
+            return;
+        }
+
+        let min = polygon.iter().fold(Vec2::INFINITY, |acc, point| acc.min(*point));
+        let max = polygon.iter().fold(Vec2::NEG_INFINITY, |acc, point| acc.max(*point));
+        let (min_x, min_z) = grid_cell(min, FOOTPRINT_GRID_CELL_SIZE);
+        let (max_x, max_z) = grid_cell(max, FOOTPRINT_GRID_CELL_SIZE);
+        for x in min_x..=max_x {
+            for z in min_z..=max_z {
+                self.cells.entry((x, z)).or_default().push(id);
+            }
+        }
+        self.footprints.insert(id, (polygon, min, max));
+    }
+
+    /// Returns the footprint of a building, if it was added.
+    pub fn get(&self, id: u64) -> Option<&[Vec2]> {
+        self.footprints.get(&id).map(|(polygon, _, _)| polygon.as_slice())
+    }
+
+    /// Removes all footprints.
+    pub fn clear(&mut self) {
+        self.footprints.clear();
+        self.cells.clear();
+    }
+
+    /// Moves all footprints by `-shift`, along with the world when its origin
+    /// is moved by `shift`.
+    pub fn shift(&mut self, shift: Vec2) {
+        let footprints = std::mem::take(&mut self.footprints);
+        self.cells.clear();
+        for (id, (mut polygon, _, _)) in footprints {
+            for point in &mut polygon {
+                *point -= shift;
+            }
+            self.insert(id, polygon);
+        }
+    }
+
+    /// If `point` lies inside of a building, returns the closest point just
+    /// outside of that building. Only buildings whose bounding box contains
+    /// the point are tested.
+    pub fn push_out(&self, point: Vec2, scale: &WorldScale) -> Option<Vec2> {
+        let candidates = self.cells.get(&grid_cell(point, FOOTPRINT_GRID_CELL_SIZE))?;
+        let (polygon, _, _) = candidates
+            .iter()
+            .map(|id| &self.footprints[id])
+            .find(|(polygon, min, max)| {
+                point.cmpge(*min).all()
+                    && point.cmple(*max).all()
+                    && point_in_polygon_check(polygon, point)
+            })?;
+
+        // Find the closest point on the edges of the footprint
+        let closest = (0..polygon.len())
+            .map(|i| closest_point_on_segment(polygon[i], polygon[(i + 1) % polygon.len()], point))
+            .min_by(|a, b| a.distance_squared(point).total_cmp(&b.distance_squared(point)))?;
+
+        Some(closest + (closest - point).normalize_or_zero() * scale.units(FOOTPRINT_PUSH_DISTANCE))
+    }
+}
+
+/// Returns the point on the line segment from `a` to `b` that is closest to
+/// `point`.
+fn closest_point_on_segment(a: Vec2, b: Vec2, point: Vec2) -> Vec2 {
+    let ab = b - a;
+    let length_squared = ab.length_squared();
+    if length_squared == 0.0 {
+        return a;
+    }
+    let t = ((point - a).dot(ab) / length_squared).clamp(0.0, 1.0);
+    a + ab * t
+}
+
 /// Filters all landuse areas to ones useful for identifying buildings, sorts them by size and simplifies them.
 fn get_building_land_use(
     landuse_features: &HashMap<u64, LandUseFeature>,
     node_locations: &HashMap<u64, GeoLocation>,
     offset: &Offset,
-) -> Vec<(Vec<Vec2>, BuildingLandUseType)> {
+    settings: &GenerationSettings,
+) -> LandUseIndex {
     let mut building_related_landuse = Vec::new();
 
     // Go over all land use areas
@@ -195,17 +452,13 @@ fn get_building_land_use(
         // Check if the land use area is related to a building
         if landuse_type != BuildingLandUseType::Unknown {
             // Turn the land use area into a polygon
-            let polygon: Vec<Vec2> = landuse_feature
-                .nodes
-                .iter()
-                .filter_map(|node_id| {
-                    node_locations
-                        .get(node_id)
-                        .map(|node| node.project(&offset))
-                })
-                .collect();
+            let Some(polygon) = project_nodes(node_locations, &close_ring(&landuse_feature.nodes), offset)
+                .filter(|polygon| polygon.len() >= 3)
+            else {
+                continue;
+            };
             // Simplify the polygon
-            let polygon = simplify_polygon(polygon, THRESHOLD_SIMPLIFICATION);
+            let polygon = simplify_polygon(polygon, offset.scale.area(settings.building_simplification));
 
             building_related_landuse.push((polygon, landuse_type));
         }
@@ -214,7 +467,7 @@ fn get_building_land_use(
     // Order land use by number of vertices in polygon as a proxy for size, from largest to smallest
     building_related_landuse.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
 
-    building_related_landuse
+    LandUseIndex::new(building_related_landuse, &offset.scale)
 }
 
 /// Makes sure the vertices of a polygon are in counter-clockwise order.
@@ -253,14 +506,9 @@ fn get_partial_building_from_tags(
         id,
         // Get building type
         building_type: building_type,
-        // Get building height
-        levels: match building.tags.get(TAG_BUILDING_LEVELS) {
-            Some(s) => match s.parse() {
-                Ok(i) => Some(i),
-                Err(_) => None,
-            },
-            None => None,
-        },
+        // Get building height, values out of range are clamped
+        levels: get_building_levels(&building.tags).map(|levels| levels.value()),
+        height: get_building_height(&building.tags).map(|height| height.value()),
         // Fill in base from before
         base,
         // Get roof type
@@ -269,32 +517,30 @@ fn get_partial_building_from_tags(
             None => None,
         },
         // Get roof levels
-        roof_levels: match building.tags.get(TAG_BUILDING_ROOF_LEVELS) {
-            Some(s) => match s.parse() {
-                Ok(i) => Some(i),
-                Err(_) => None,
-            },
-            None => None,
-        },
+        roof_levels: get_roof_levels(&building.tags).map(|levels| levels.value()),
         inside_area: if building_type.is_some() {
             BuildingLandUseType::NOTNECESSARY
         } else {
             BuildingLandUseType::Unknown
         },
+        // Get colours, unknown values are ignored
+        colour: building.tags.get(TAG_BUILDING_COLOUR).and_then(|s| parse_colour(s)),
+        roof_colour: building.tags.get(TAG_BUILDING_ROOF_COLOUR).and_then(|s| parse_colour(s)),
     }
 }
 
-/// Creates a building base from a list of nodes. Returns None if any of the nodes are not found or the number of nodes is less than 3
+/// Creates a building base from a list of nodes, without the duplicated closing node.
+/// Returns None if any of the nodes are not found or the number of distinct nodes is less than 3
 fn create_building_base(
     node_locations: &HashMap<u64, GeoLocation>,
     building: &BuildingFeature,
     offset: &Offset,
 ) -> Option<Vec<Vec2>> {
-    if building.nodes.len() < 3 {
+    let ring = close_ring(&building.nodes);
+    if ring.len() < 3 {
         return None;
     }
-    building
-        .nodes
+    ring
         .iter()
         .map(|node_id| Some(node_locations.get(node_id)?.project(&offset)))
         .collect()
@@ -306,12 +552,62 @@ pub struct Building {
     pub interpolated: bool, // Is true when the building contains any interpolated data
 }
 
-fn calculate_polygon_area(polygon: &Vec<Vec2>) -> f32 {
+/// Returns the type of a residential building without a type of its own: a
+/// house if its base is small, otherwise an apartment building.
+fn residential_building_type(base: &[Vec2], scale: &WorldScale) -> BuildingType {
+    if calculate_polygon_area(base) < scale.area(THRESHOLD_APARTMENT_BASE_SIZE) {
+        BuildingType::House
+    } else {
+        BuildingType::Apartments
+    }
+}
+
+/// Returns the (unsigned) area of a polygon, regardless of the winding order
+/// of its vertices.
+fn calculate_polygon_area(polygon: &[Vec2]) -> f32 {
     let mut area = 0.0;
     for i in 0..polygon.len() {
         let j = (i + 1) % polygon.len();
         area += polygon[i].x * polygon[j].y;
         area -= polygon[j].x * polygon[i].y;
     }
-    area / 2.0
+    (area / 2.0).abs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the corners of a square with sides of `size`, counterclockwise.
+    fn square(size: f32) -> Vec<Vec2> {
+        vec![Vec2::ZERO, Vec2::new(size, 0.0), Vec2::new(size, size), Vec2::new(0.0, size)]
+    }
+
+    fn reversed(polygon: &[Vec2]) -> Vec<Vec2> {
+        polygon.iter().rev().copied().collect()
+    }
+
+    #[test]
+    fn polygon_area_does_not_depend_on_winding() {
+        assert_eq!(calculate_polygon_area(&square(2.0)), 4.0);
+        assert_eq!(calculate_polygon_area(&reversed(&square(2.0))), 4.0);
+    }
+
+    #[test]
+    fn closed_and_open_ways_have_the_same_area() {
+        let corners = |id: &u64| square(1.0)[*id as usize - 1];
+        for way in [vec![1, 2, 3, 4], vec![1, 2, 3, 4, 1], vec![1, 2, 3, 4, 1, 1, 1]] {
+            let ring: Vec<Vec2> = close_ring(&way).iter().map(corners).collect();
+            assert_eq!(calculate_polygon_area(&ring), 1.0);
+        }
+    }
+
+    #[test]
+    fn residential_building_type_does_not_depend_on_winding() {
+        let scale = WorldScale::default();
+        for (size, building_type) in [(5.0, BuildingType::House), (20.0, BuildingType::Apartments)] {
+            assert_eq!(residential_building_type(&square(size), &scale), building_type);
+            assert_eq!(residential_building_type(&reversed(&square(size)), &scale), building_type);
+        }
+    }
 }
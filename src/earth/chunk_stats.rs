@@ -0,0 +1,136 @@
+//! Statistics about the generation of every chunk, and an overlay that shows
+//! them for the chunk the player is in.
+//!
+//! Useful for finding out why a single chunk is slow to generate or huge.
+
+use crate::commands::CommandRegistry;
+use crate::data::geography::{Chunk, ChunkIndex, Offset};
+use crate::earth::agent::Agent;
+use crate::player::Player;
+
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use bevy_egui::egui;
+use bevy_egui::EguiContexts;
+
+use std::collections::HashMap;
+
+/// Statistics of a single chunk, filled in as its generation tasks finish.
+#[derive(Clone, Debug, Default)]
+pub struct ChunkStatistics {
+    pub buildings: usize,
+    pub roads: usize,
+    pub land_uses: usize,
+    pub lakes: usize,
+    pub rivers: usize,
+    /// The number of vertices in all building meshes of the chunk.
+    pub building_vertices: usize,
+    /// The number of vertices in all road meshes of the chunk.
+    pub road_vertices: usize,
+    /// How long generating the building meshes took, once it is done.
+    pub building_time: Option<Duration>,
+    /// How long generating the road meshes took, once it is done.
+    pub road_time: Option<Duration>,
+}
+
+impl ChunkStatistics {
+    /// Returns the statistics of a chunk of which no task finished yet.
+    pub fn new(chunk: &Chunk) -> Self {
+        ChunkStatistics {
+            buildings: chunk.building_features.len(),
+            roads: chunk.road_features.len(),
+            land_uses: chunk.land_use_features.len(),
+            lakes: chunk.lake_features.len(),
+            rivers: chunk.river_features.len(),
+            ..default()
+        }
+    }
+}
+
+/// The statistics of all chunks in the world, and whether they are shown.
+#[derive(Debug, Default, Resource)]
+pub struct ChunkStats {
+    pub chunks: HashMap<ChunkIndex, ChunkStatistics>,
+    pub overlay_visible: bool,
+}
+
+impl ChunkStats {
+    /// Removes the statistics of all chunks.
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+    }
+}
+
+/// A system that registers the commands for chunk statistics.
+pub fn register_chunk_stats_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle chunk statistics",
+        "Shows statistics of the chunk the camera is above",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut chunk_stats = world.resource_mut::<ChunkStats>();
+                chunk_stats.overlay_visible = !chunk_stats.overlay_visible;
+            })
+        },
+    );
+}
+
+/// A system that shows the statistics of the chunk the player is above, and
+/// of the agents in the world.
+pub fn update_chunk_stats_overlay(
+    mut contexts: EguiContexts,
+    chunk_stats: Res<ChunkStats>,
+    players: Query<&Transform, With<Player>>,
+    agents: Query<&Agent>,
+    offset: Res<Offset>,
+) {
+    if !chunk_stats.overlay_visible {
+        return;
+    }
+    let Ok(transform) = players.get_single() else {
+        return;
+    };
+    let position = Vec2::new(transform.translation.x, transform.translation.z);
+
+    egui::Window::new("Chunk statistics").show(contexts.ctx_mut(), |ui| {
+        // no data was loaded yet
+        if !offset.x.is_finite() || !offset.y.is_finite() {
+            ui.label("No data loaded");
+            return;
+        }
+
+        let agent_count = agents.iter().count();
+        let path_nodes: usize = agents.iter().map(|agent| agent.path.len()).sum();
+        ui.label(format!("Agents: {}", agent_count));
+        if agent_count > 0 {
+            let average = path_nodes as f32 / agent_count as f32;
+            ui.label(format!("Average path length: {:.1} nodes", average));
+        }
+        ui.separator();
+        let index = ChunkIndex::from_world(position, &offset);
+        ui.label(format!("Chunk ({}, {})", index.x, index.z));
+
+        let Some(stats) = chunk_stats.chunks.get(&index) else {
+            ui.label("No data in this chunk");
+            return;
+        };
+        ui.label(format!("Buildings: {}", stats.buildings));
+        ui.label(format!("Roads: {}", stats.roads));
+        ui.label(format!("Land uses: {}", stats.land_uses));
+        ui.label(format!("Lakes: {}", stats.lakes));
+        ui.label(format!("Rivers: {}", stats.rivers));
+        ui.separator();
+        ui.label(format!("Building vertices: {}", stats.building_vertices));
+        ui.label(format!("Road vertices: {}", stats.road_vertices));
+        ui.label(format!("Building generation: {}", format_time(stats.building_time)));
+        ui.label(format!("Road generation: {}", format_time(stats.road_time)));
+    });
+}
+
+fn format_time(time: Option<Duration>) -> String {
+    match time {
+        Some(time) => format!("{:.1} ms", time.as_secs_f64() * 1000.0),
+        None => "in progress".to_owned(),
+    }
+}
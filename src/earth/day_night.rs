@@ -0,0 +1,235 @@
+//! A day/night cycle: the sun moves over the sky, the light and sky change
+//! color, and windows of buildings and lit roads light up at night.
+
+use crate::data::building_type::LightingClass;
+use crate::earth::assets::AssetCache;
+use crate::earth::SimulationSettings;
+
+use bevy::prelude::*;
+
+use std::f32::consts::PI;
+
+/// The highest elevation of the sun, at noon, in radians.
+const MAX_SUN_ELEVATION: f32 = PI / 3.0;
+
+/// The lowest elevation of the light, in radians. At night the light acts as
+/// moonlight, which still casts shadows.
+const MIN_LIGHT_ELEVATION: f32 = 0.15;
+
+const DAY_ILLUMINANCE: f32 = 10_000.0;
+const NIGHT_ILLUMINANCE: f32 = 300.0;
+
+/// The brightness of the ambient light at noon, the default of Bevy.
+const DAY_AMBIENT_BRIGHTNESS: f32 = 80.0;
+const NIGHT_AMBIENT_BRIGHTNESS: f32 = 15.0;
+
+const DUSK_LIGHT_COLOR: Color = Color::rgb(1.0, 0.55, 0.3);
+const NIGHT_LIGHT_COLOR: Color = Color::rgb(0.55, 0.65, 1.0);
+
+const DAY_SKY_COLOR: Color = Color::rgb(0.55, 0.7, 0.9);
+const DUSK_SKY_COLOR: Color = Color::rgb(0.8, 0.5, 0.35);
+const NIGHT_SKY_COLOR: Color = Color::rgb(0.02, 0.03, 0.08);
+
+/// Below this amount of daylight it is night, and lights are turned on.
+const NIGHT_THRESHOLD: f32 = 0.3;
+
+/// The hour at which offices turn their lights off.
+const OFFICE_CLOSING_HOUR: f32 = 22.0;
+
+/// How often per hour other windows of homes light up.
+const WINDOW_PATTERN_CHANGES_PER_HOUR: f32 = 2.0;
+
+/// The time of day in the world, which determines where the sun is.
+#[derive(Clone, Copy, Debug, Resource)]
+pub struct TimeOfDay {
+    /// The hour of the day, from 0 to 24.
+    pub hours: f32,
+    /// How many hours pass per second of simulated time. 0 stops the clock.
+    pub speed: f32,
+}
+
+impl Default for TimeOfDay {
+    fn default() -> Self {
+        TimeOfDay {
+            hours: 12.0,
+            speed: 0.0,
+        }
+    }
+}
+
+impl TimeOfDay {
+    /// Returns the sine of the elevation of the sun: 1 at noon, 0 at 6:00
+    /// and 18:00, and -1 at midnight.
+    pub fn sun_height(&self) -> f32 {
+        ((self.hours - 6.0) / 12.0 * PI).sin()
+    }
+
+    /// Returns how much daylight there is, from 0 at night to 1 during the
+    /// day. The transition is around sunrise and sunset.
+    pub fn daylight(&self) -> f32 {
+        ((self.sun_height() + 0.1) / 0.4).clamp(0.0, 1.0)
+    }
+
+    /// Returns whether it is dark enough for lights to be on, such as the
+    /// windows of buildings and street lamps.
+    pub fn is_night(&self) -> bool {
+        self.daylight() < NIGHT_THRESHOLD
+    }
+
+    /// Returns whether it is dark, but before offices close. In the morning
+    /// before sunrise offices are still dark.
+    pub fn is_evening(&self) -> bool {
+        self.is_night() && (12.0..OFFICE_CLOSING_HOUR).contains(&self.hours)
+    }
+}
+
+/// How a mesh lights up at night, decided when it is generated.
+#[derive(Clone, Copy, Component, Debug, Eq, PartialEq)]
+pub enum NightLighting {
+    /// Buildings, whose windows are lit depending on what they are used for.
+    Building(LightingClass),
+    /// Roads with street lighting.
+    LitRoad,
+}
+
+impl NightLighting {
+    /// Returns the material of a mesh with this lighting at the given time of
+    /// day.
+    fn material(
+        self,
+        time_of_day: &TimeOfDay,
+        asset_cache: &AssetCache,
+    ) -> Handle<StandardMaterial> {
+        match self {
+            NightLighting::Building(lighting) => {
+                let lit = match lighting {
+                    LightingClass::Residential => time_of_day.is_night(),
+                    LightingClass::Office => time_of_day.is_evening(),
+                    LightingClass::Dark => false,
+                };
+                if lit {
+                    asset_cache.get_building_night_material(lighting)
+                } else {
+                    asset_cache.get_building_material()
+                }
+            }
+            NightLighting::LitRoad if time_of_day.is_night() => {
+                asset_cache.get_road_lit_night_material()
+            }
+            NightLighting::LitRoad => asset_cache.get_road_material(),
+        }
+    }
+}
+
+/// Marks the directional light that acts as the sun.
+#[derive(Component)]
+pub struct Sun;
+
+/// A system that advances the time of day, and moves and colors the sun, the
+/// sky and the ambient light accordingly.
+pub fn update_time_of_day(
+    time: Res<Time>,
+    simulation: Res<SimulationSettings>,
+    mut time_of_day: ResMut<TimeOfDay>,
+    mut suns: Query<(&mut DirectionalLight, &mut Transform), With<Sun>>,
+    mut clear_color: ResMut<ClearColor>,
+    mut ambient_light: ResMut<AmbientLight>,
+) {
+    if time_of_day.speed > 0.0 {
+        let hours = time_of_day.hours + time_of_day.speed * simulation.delta_seconds(&time);
+        time_of_day.hours = hours.rem_euclid(24.0);
+    }
+    if !time_of_day.is_changed() {
+        return;
+    }
+
+    let sun_height = time_of_day.sun_height();
+    let daylight = time_of_day.daylight();
+    // the sun is warmer the lower it is, and white from halfway up
+    let warmth = 1.0 - (sun_height / 0.5).clamp(0.0, 1.0);
+
+    for (mut light, mut transform) in suns.iter_mut() {
+        // the sun goes from east to west, and is highest at noon
+        let azimuth = (time_of_day.hours - 12.0) / 12.0 * PI;
+        let elevation = (sun_height * MAX_SUN_ELEVATION).max(MIN_LIGHT_ELEVATION);
+        transform.rotation = Quat::from_euler(EulerRot::YXZ, azimuth, -elevation, 0.0);
+
+        let day_color = lerp_color(Color::WHITE, DUSK_LIGHT_COLOR, warmth);
+        light.color = lerp_color(NIGHT_LIGHT_COLOR, day_color, daylight);
+        light.illuminance = NIGHT_ILLUMINANCE + (DAY_ILLUMINANCE - NIGHT_ILLUMINANCE) * daylight;
+    }
+
+    let day_sky = lerp_color(DAY_SKY_COLOR, DUSK_SKY_COLOR, warmth);
+    clear_color.0 = lerp_color(NIGHT_SKY_COLOR, day_sky, daylight);
+    ambient_light.brightness =
+        NIGHT_AMBIENT_BRIGHTNESS + (DAY_AMBIENT_BRIGHTNESS - NIGHT_AMBIENT_BRIGHTNESS) * daylight;
+}
+
+/// A system that gives buildings and lit roads the material with lights at
+/// night, and the normal material during the day.
+pub fn update_night_materials(
+    time_of_day: Res<TimeOfDay>,
+    asset_cache: Res<AssetCache>,
+    mut lights_on: Local<(bool, bool)>,
+    mut meshes: ParamSet<(
+        Query<(&NightLighting, &mut Handle<StandardMaterial>)>,
+        Query<(&NightLighting, &mut Handle<StandardMaterial>), Added<NightLighting>>,
+    )>,
+) {
+    // all meshes change when night falls, offices close or night ends, after
+    // that only the meshes that are added
+    let new_lights_on = (time_of_day.is_night(), time_of_day.is_evening());
+    if new_lights_on != *lights_on {
+        *lights_on = new_lights_on;
+        for (lighting, mut material) in meshes.p0().iter_mut() {
+            set_night_material(*lighting, &mut material, &time_of_day, &asset_cache);
+        }
+    } else {
+        for (lighting, mut material) in meshes.p1().iter_mut() {
+            set_night_material(*lighting, &mut material, &time_of_day, &asset_cache);
+        }
+    }
+}
+
+fn set_night_material(
+    lighting: NightLighting,
+    material: &mut Handle<StandardMaterial>,
+    time_of_day: &TimeOfDay,
+    asset_cache: &AssetCache,
+) {
+    let new_material = lighting.material(time_of_day, asset_cache);
+    if *material != new_material {
+        *material = new_material;
+    }
+}
+
+/// A system that lights other windows of homes every now and then at night,
+/// by changing the emissive texture of their material.
+pub fn update_window_patterns(
+    time_of_day: Res<TimeOfDay>,
+    asset_cache: Res<AssetCache>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut current_pattern: Local<u32>,
+) {
+    if !time_of_day.is_night() {
+        return;
+    }
+    let pattern = (time_of_day.hours * WINDOW_PATTERN_CHANGES_PER_HOUR) as u32;
+    if pattern == *current_pattern {
+        return;
+    }
+    *current_pattern = pattern;
+
+    let handle = asset_cache.get_building_night_material(LightingClass::Residential);
+    if let Some(material) = materials.get_mut(&handle) {
+        material.emissive_texture = asset_cache.get_building_window_atlas(pattern);
+    }
+}
+
+fn lerp_color(from: Color, to: Color, t: f32) -> Color {
+    Color::rgb(
+        from.r() + (to.r() - from.r()) * t,
+        from.g() + (to.g() - from.g()) * t,
+        from.b() + (to.b() - from.b()) * t,
+    )
+}
@@ -0,0 +1,414 @@
+//! Statistics per district of a city: how much of it is green, water or
+//! covered by buildings, and how dense its road network is. Feature polygons
+//! are clipped against the outline of every district, so features on a
+//! border count for both sides.
+//!
+//! Only features that are generated are counted: green areas are land uses,
+//! water areas are lakes (rivers are lines), and roads include footways.
+
+use crate::commands::CommandRegistry;
+use crate::data::geography::{project_nodes, DistrictFeature, GeoData, Offset};
+use crate::player::PlayerTeleportEvent;
+
+use bevy::prelude::*;
+use bevy_egui::egui;
+use bevy_egui::EguiContexts;
+use geo::{Area, BooleanOps, BoundingRect, EuclideanLength, Intersects};
+
+use std::cmp::Ordering;
+
+/// The land uses that count as green area.
+///
+/// # See also
+/// https://wiki.openstreetmap.org/wiki/Key:landuse
+const GREEN_LAND_USES: [&str; 11] = [
+    "allotments",
+    "cemetery",
+    "flowerbed",
+    "forest",
+    "grass",
+    "meadow",
+    "orchard",
+    "park",
+    "recreation_ground",
+    "village_green",
+    "vineyard",
+];
+
+/// The height at which the outline of the selected district is drawn, above
+/// roads and below most buildings.
+const DISTRICT_OUTLINE_HEIGHT: f32 = 0.5;
+
+const DISTRICT_OUTLINE_COLOR: Color = Color::rgb(1.0, 0.85, 0.1);
+
+/// The statistics of a single district.
+#[derive(Clone, Debug)]
+pub struct DistrictStatistics {
+    pub id: u64,
+    pub name: String,
+    /// The outer rings of the district, in world coordinates.
+    pub outline: Vec<Vec<Vec2>>,
+    /// The middle of the bounding box of the district.
+    pub center: Vec2,
+    /// In square kilometers.
+    pub area: f64,
+    pub green_percentage: f64,
+    pub water_percentage: f64,
+    /// The percentage of the district covered by building footprints.
+    pub building_percentage: f64,
+    /// The length of roads per area, in kilometers per square kilometer.
+    pub road_density: f64,
+}
+
+/// A column of the district statistics table, to sort by.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DistrictColumn {
+    #[default]
+    Name,
+    Area,
+    Green,
+    Water,
+    Buildings,
+    Roads,
+}
+
+impl DistrictColumn {
+    const ALL: [DistrictColumn; 6] = [
+        DistrictColumn::Name,
+        DistrictColumn::Area,
+        DistrictColumn::Green,
+        DistrictColumn::Water,
+        DistrictColumn::Buildings,
+        DistrictColumn::Roads,
+    ];
+
+    fn title(self) -> &'static str {
+        match self {
+            DistrictColumn::Name => "District",
+            DistrictColumn::Area => "Area (km²)",
+            DistrictColumn::Green => "Green",
+            DistrictColumn::Water => "Water",
+            DistrictColumn::Buildings => "Buildings",
+            DistrictColumn::Roads => "Roads (km/km²)",
+        }
+    }
+
+    fn compare(self, a: &DistrictStatistics, b: &DistrictStatistics) -> Ordering {
+        match self {
+            DistrictColumn::Name => a.name.cmp(&b.name),
+            DistrictColumn::Area => a.area.total_cmp(&b.area),
+            DistrictColumn::Green => a.green_percentage.total_cmp(&b.green_percentage),
+            DistrictColumn::Water => a.water_percentage.total_cmp(&b.water_percentage),
+            DistrictColumn::Buildings => a.building_percentage.total_cmp(&b.building_percentage),
+            DistrictColumn::Roads => a.road_density.total_cmp(&b.road_density),
+        }
+    }
+}
+
+/// The statistics of the districts in the loaded data.
+#[derive(Debug, Default, Resource)]
+pub struct DistrictStats {
+    districts: Vec<DistrictStatistics>,
+    /// The offset the world coordinates of the districts are relative to.
+    offset: Option<Offset>,
+    /// The district that is outlined in the world.
+    selected: Option<u64>,
+    sort_column: DistrictColumn,
+    sort_descending: bool,
+    /// Whether the statistics window is shown.
+    pub window_visible: bool,
+}
+
+impl DistrictStats {
+    /// Adds the statistics of newly loaded districts. Districts that were
+    /// computed for another offset are replaced, since they are somewhere
+    /// else in the world.
+    pub fn add(&mut self, districts: Vec<DistrictStatistics>, offset: Offset) {
+        let same_offset = self
+            .offset
+            .map_or(false, |old| old.x == offset.x && old.y == offset.y);
+        if !same_offset {
+            self.districts.clear();
+            self.selected = None;
+            self.offset = Some(offset);
+        }
+        for district in districts {
+            self.districts.retain(|old| old.id != district.id);
+            self.districts.push(district);
+        }
+        self.sort();
+    }
+
+    fn sort(&mut self) {
+        let column = self.sort_column;
+        self.districts.sort_by(|a, b| column.compare(a, b));
+        if self.sort_descending {
+            self.districts.reverse();
+        }
+    }
+
+    /// Moves the districts by `-shift` when the origin of the world is moved
+    /// by `shift`, if they belong to the data with the `previous` offset.
+    pub fn shift(&mut self, previous: &Offset, shift: Vec2) {
+        if !self.matches_offset(previous) {
+            return;
+        }
+        for district in &mut self.districts {
+            for ring in &mut district.outline {
+                for point in ring {
+                    *point -= shift;
+                }
+            }
+            district.center -= shift;
+        }
+        self.offset = Some(previous.shifted(shift));
+    }
+
+    /// Returns whether the districts belong to the data with the given
+    /// offset.
+    fn matches_offset(&self, offset: &Offset) -> bool {
+        self.offset
+            .map_or(false, |old| old.x == offset.x && old.y == offset.y)
+    }
+}
+
+/// A type for storing the statistics computed by district statistics tasks,
+/// and the offset they were computed with.
+pub struct DistrictStatsCreation(pub Vec<DistrictStatistics>, pub Offset);
+
+/// Computes the statistics of all districts in the data, by clipping the
+/// features of every chunk against the outlines of the districts.
+pub fn compute_district_statistics(data: &GeoData, offset: &Offset) -> Vec<DistrictStatistics> {
+    let mut statistics = Vec::with_capacity(data.districts.len());
+    for (&id, district) in &data.districts {
+        if let Some(district_statistics) = compute_statistics(id, district, data, offset) {
+            statistics.push(district_statistics);
+        }
+    }
+    statistics
+}
+
+fn compute_statistics(
+    id: u64,
+    district: &DistrictFeature,
+    data: &GeoData,
+    offset: &Offset,
+) -> Option<DistrictStatistics> {
+    let outline: Vec<Vec<Vec2>> = district
+        .rings
+        .iter()
+        .filter_map(|ring| project_nodes(&data.node_locations, ring, offset))
+        .filter(|ring| ring.len() >= 3)
+        .collect();
+    let shape = geo::MultiPolygon::new(outline.iter().map(|ring| to_polygon(ring)).collect());
+    let bounds = shape.bounding_rect()?;
+    let area = shape.unsigned_area();
+    if area <= 0.0 {
+        return None;
+    }
+
+    // distances are converted to meters at the district
+    let location = district
+        .rings
+        .iter()
+        .flatten()
+        .find_map(|node| data.node_locations.get(node))?;
+    let meters_per_unit = location.meters_per_unit(offset);
+
+    // the area of the polygons of a kind of feature inside of the district,
+    // overlapping features are counted twice
+    let clipped_area = |polygons: &mut dyn Iterator<Item = Vec<Vec2>>| -> f64 {
+        polygons
+            .filter(|polygon| polygon.len() >= 3)
+            .map(|polygon| geo::MultiPolygon::new(vec![to_polygon(&polygon)]))
+            .filter(|polygon| {
+                polygon
+                    .bounding_rect()
+                    .map_or(false, |rect| rect.intersects(&bounds))
+            })
+            .map(|polygon| shape.intersection(&polygon).unsigned_area())
+            .sum()
+    };
+
+    let mut green = 0.0;
+    let mut water = 0.0;
+    let mut buildings = 0.0;
+    let mut road_length = 0.0;
+    for chunk in data.chunks.values() {
+        green += clipped_area(&mut chunk.land_use_features.values().filter_map(|feature| {
+            let landuse = feature.tags.get("landuse")?;
+            GREEN_LAND_USES
+                .contains(&landuse.as_str())
+                .then(|| project_nodes(&data.node_locations, &feature.nodes, offset))
+                .flatten()
+        }));
+        water += clipped_area(
+            &mut chunk
+                .lake_features
+                .values()
+                .filter_map(|feature| project_nodes(&data.node_locations, &feature.nodes, offset)),
+        );
+        buildings += clipped_area(
+            &mut chunk
+                .building_features
+                .values()
+                .filter_map(|feature| project_nodes(&data.node_locations, &feature.nodes, offset)),
+        );
+
+        let roads = geo::MultiLineString::new(
+            chunk
+                .road_features
+                .values()
+                .filter_map(|feature| project_nodes(&data.node_locations, &feature.nodes, offset))
+                .filter(|road| road.len() >= 2)
+                .map(|road| to_line_string(&road))
+                .filter(|road| {
+                    road.bounding_rect()
+                        .map_or(false, |rect| rect.intersects(&bounds))
+                })
+                .collect(),
+        );
+        road_length += shape.clip(&roads, false).euclidean_length();
+    }
+
+    let square_kilometers = area * meters_per_unit * meters_per_unit / 1_000_000.0;
+    let percentage = |part: f64| (part / area * 100.0).min(100.0);
+    Some(DistrictStatistics {
+        id,
+        name: district.name(id),
+        center: Vec2::new(
+            ((bounds.min().x + bounds.max().x) / 2.0) as f32,
+            ((bounds.min().y + bounds.max().y) / 2.0) as f32,
+        ),
+        outline,
+        area: square_kilometers,
+        green_percentage: percentage(green),
+        water_percentage: percentage(water),
+        building_percentage: percentage(buildings),
+        road_density: road_length * meters_per_unit / 1000.0 / square_kilometers,
+    })
+}
+
+fn to_polygon(ring: &[Vec2]) -> geo::Polygon {
+    let points: Vec<_> = ring
+        .iter()
+        .map(|point| geo::Point::new(point.x as f64, point.y as f64))
+        .collect();
+    geo::Polygon::new(points.into(), vec![])
+}
+
+fn to_line_string(line: &[Vec2]) -> geo::LineString {
+    let points: Vec<_> = line
+        .iter()
+        .map(|point| geo::Point::new(point.x as f64, point.y as f64))
+        .collect();
+    points.into()
+}
+
+/// A system that registers the commands for district statistics.
+pub fn register_district_stats_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle district statistics",
+        "Shows how green, wet, built-up and dense in roads every loaded district is",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut stats = world.resource_mut::<DistrictStats>();
+                stats.window_visible = !stats.window_visible;
+            })
+        },
+    );
+}
+
+/// A system that shows the statistics of the districts in a table, which can
+/// be sorted by clicking a column. Clicking a district outlines it and moves
+/// the player to it.
+pub fn update_district_stats_window(
+    mut contexts: EguiContexts,
+    mut stats: ResMut<DistrictStats>,
+    offset: Res<Offset>,
+    mut teleport_events: EventWriter<PlayerTeleportEvent>,
+) {
+    if !stats.window_visible {
+        return;
+    }
+
+    let mut visible = true;
+    let mut sort_by = None;
+    let mut clicked = None;
+    egui::Window::new("District statistics")
+        .open(&mut visible)
+        .show(contexts.ctx_mut(), |ui| {
+            if !stats.matches_offset(&offset) || stats.districts.is_empty() {
+                ui.label("No districts in the loaded data");
+                return;
+            }
+
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .show(ui, |ui| {
+                    egui::Grid::new("district_stats_grid")
+                        .striped(true)
+                        .show(ui, |ui| {
+                            for column in DistrictColumn::ALL {
+                                let title = if column != stats.sort_column {
+                                    column.title().to_owned()
+                                } else if stats.sort_descending {
+                                    format!("{} ⏷", column.title())
+                                } else {
+                                    format!("{} ⏶", column.title())
+                                };
+                                if ui.button(title).clicked() {
+                                    sort_by = Some(column);
+                                }
+                            }
+                            ui.end_row();
+
+                            for district in &stats.districts {
+                                let selected = stats.selected == Some(district.id);
+                                if ui.selectable_label(selected, &district.name).clicked() {
+                                    clicked = Some((district.id, district.center));
+                                }
+                                ui.label(format!("{:.2}", district.area));
+                                ui.label(format!("{:.1}%", district.green_percentage));
+                                ui.label(format!("{:.1}%", district.water_percentage));
+                                ui.label(format!("{:.1}%", district.building_percentage));
+                                ui.label(format!("{:.1}", district.road_density));
+                                ui.end_row();
+                            }
+                        });
+                });
+        });
+    stats.window_visible = visible;
+
+    if let Some(column) = sort_by {
+        // clicking the sorted column again reverses the order
+        stats.sort_descending = column == stats.sort_column && !stats.sort_descending;
+        stats.sort_column = column;
+        stats.sort();
+    }
+    if let Some((id, center)) = clicked {
+        stats.selected = Some(id);
+        teleport_events.send(PlayerTeleportEvent { position: center });
+    }
+}
+
+/// A system that draws the outline of the selected district.
+pub fn draw_selected_district(mut gizmos: Gizmos, stats: Res<DistrictStats>, offset: Res<Offset>) {
+    if !stats.window_visible || !stats.matches_offset(&offset) {
+        return;
+    }
+    let Some(district) = stats
+        .selected
+        .and_then(|id| stats.districts.iter().find(|district| district.id == id))
+    else {
+        return;
+    };
+    for ring in &district.outline {
+        let points = ring
+            .iter()
+            .chain(ring.first())
+            .map(|point| Vec3::new(point.x, DISTRICT_OUTLINE_HEIGHT, point.y));
+        gizmos.linestrip(points, DISTRICT_OUTLINE_COLOR);
+    }
+}
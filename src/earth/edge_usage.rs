@@ -0,0 +1,404 @@
+//! A heatmap over the roads of how often agents travel over them. Every time
+//! an agent reaches the end of an edge of the traffic graph, the usage of
+//! that edge is counted in `EdgeUsage`, see `EdgeUsage::record`.
+//!
+//! Usages halve every half-life of simulated time, so the heatmap shows
+//! recent traffic, or they are kept until they are reset. The heatmap covers
+//! every used road in one mesh, so it is rebuilt at most every
+//! `REBUILD_INTERVAL`, rather than every time an agent reaches a vertex.
+
+use crate::commands::CommandRegistry;
+use crate::data::geography::WorldScale;
+use crate::data::road_type::road_type_to_width;
+use crate::data::traffic_graph::TrafficGraph;
+use crate::earth::assets::AssetCache;
+use crate::earth::mesh_builder::MeshBuilder;
+use crate::earth::overlay::{OverlayLayer, OVERLAY_HEIGHT};
+use crate::earth::trajectory::generate_trajectory;
+use crate::earth::{SimulationSettings, SIZE_EXAGGERATION};
+
+use bevy::pbr::{NotShadowCaster, NotShadowReceiver};
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use petgraph::graph::{EdgeIndex, NodeIndex};
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// The default time after which usages are halved, in simulated seconds.
+pub const DEFAULT_HALF_LIFE: f32 = 300.0;
+
+/// The range of the half-life that can be chosen in the UI, in simulated
+/// seconds.
+pub const HALF_LIFE_RANGE: RangeInclusive<f32> = 10.0..=3600.0;
+
+/// The least time between rebuilds of the mesh of the heatmap, in real time.
+const REBUILD_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The simulated time over which decay is collected before usages are
+/// decayed, so the usage of every edge is not touched every frame.
+const DECAY_STEP: f32 = 1.0;
+
+/// The usage below which an edge is forgotten when usages decay.
+const MIN_EDGE_USAGE: f32 = 0.01;
+
+/// How often agents traversed every edge of the traffic graph, decayed over
+/// time. Edges are removed and their indices reused when roads are loaded
+/// again, so the endpoints of every edge are kept to tell whether its index
+/// still belongs to the same edge.
+///
+/// ```
+/// use bevy::math::Vec2;
+/// use city_visualizer::data::road_type::RoadType;
+/// use city_visualizer::data::traffic_graph::{Access, OneWay, TrafficGraph};
+/// use city_visualizer::earth::edge_usage::EdgeUsage;
+///
+/// let mut graph = TrafficGraph::default();
+/// graph.add_connection(0, Vec2::ZERO, 1, Vec2::new(10.0, 0.0), OneWay::No, RoadType::Residential, Access::ALL);
+/// let (a, b) = (graph.get_index(0).unwrap(), graph.get_index(1).unwrap());
+///
+/// let mut usage = EdgeUsage::default();
+/// usage.record(&graph, a, b);
+/// usage.record(&graph, a, b);
+/// usage.record(&graph, b, a);
+/// assert_eq!(usage.get(&graph, a, b), 2.0);
+/// assert_eq!(usage.get(&graph, b, a), 1.0);
+/// assert_eq!(usage.max(), 2.0);
+///
+/// // counts fade away, and are forgotten once they are negligible
+/// usage.decay(0.5);
+/// assert_eq!(usage.get(&graph, a, b), 1.0);
+/// usage.decay(0.0);
+/// assert_eq!(usage.used_edges(&graph).count(), 0);
+///
+/// // a graph that was reset starts without usages
+/// usage.record(&graph, a, b);
+/// graph.reset();
+/// graph.add_connection(0, Vec2::ZERO, 1, Vec2::new(10.0, 0.0), OneWay::No, RoadType::Residential, Access::ALL);
+/// assert_eq!(usage.used_edges(&graph).count(), 0);
+/// ```
+#[derive(Resource, Default)]
+pub struct EdgeUsage {
+    usages: HashMap<EdgeIndex, (NodeIndex, NodeIndex, f32)>,
+    /// The generation of the traffic graph the usages were counted in.
+    generation: u32,
+}
+
+impl EdgeUsage {
+    /// Counts that an agent traversed the edge from one vertex to another.
+    /// Does nothing if the vertices are not connected.
+    pub fn record(&mut self, traffic_graph: &TrafficGraph, from_index: NodeIndex, to_index: NodeIndex) {
+        if self.generation != traffic_graph.get_generation() {
+            self.usages.clear();
+            self.generation = traffic_graph.get_generation();
+        }
+        let Some(edge) = traffic_graph.find_edge(from_index, to_index) else {
+            return;
+        };
+        let entry = self.usages.entry(edge).or_insert((from_index, to_index, 0.0));
+        if (entry.0, entry.1) != (from_index, to_index) {
+            // the index was reused by another edge
+            *entry = (from_index, to_index, 0.0);
+        }
+        entry.2 += 1.0;
+    }
+
+    /// Returns how often agents traversed the edge from one vertex to
+    /// another, decayed over time, or 0 if the vertices are not connected.
+    pub fn get(&self, traffic_graph: &TrafficGraph, from_index: NodeIndex, to_index: NodeIndex) -> f32 {
+        let Some(edge) = traffic_graph.find_edge(from_index, to_index) else {
+            return 0.0;
+        };
+        match self.usages.get(&edge) {
+            Some(&(from, to, usage))
+                if self.generation == traffic_graph.get_generation() && (from, to) == (from_index, to_index) =>
+            {
+                usage
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Returns the highest usage of any edge, or 0 if no edge was used.
+    pub fn max(&self) -> f32 {
+        self.usages.values().map(|&(_, _, usage)| usage).fold(0.0, f32::max)
+    }
+
+    /// Returns the start and end vertex and the usage of all edges of the
+    /// traffic graph that were traversed by agents.
+    pub fn used_edges<'a>(
+        &'a self,
+        traffic_graph: &'a TrafficGraph,
+    ) -> impl Iterator<Item = (NodeIndex, NodeIndex, f32)> + 'a {
+        let current = self.generation == traffic_graph.get_generation();
+        self.usages
+            .iter()
+            .filter(move |_| current)
+            .filter(move |&(&edge, &(from, to, _))| traffic_graph.get_edge_endpoints(edge) == Some((from, to)))
+            .map(|(_, &usage)| usage)
+    }
+
+    /// Multiplies the usage of all edges by `factor`, so that old traffic
+    /// fades away. Usages that become negligible are forgotten.
+    pub fn decay(&mut self, factor: f32) {
+        self.usages.retain(|_, (_, _, usage)| {
+            *usage *= factor;
+            *usage >= MIN_EDGE_USAGE
+        });
+    }
+
+    /// Forgets the usage of all edges.
+    pub fn reset(&mut self) {
+        self.usages.clear();
+    }
+}
+
+/// The colors that usages are shown in, from unused to the most used road.
+///
+/// ```
+/// use bevy::prelude::Color;
+/// use city_visualizer::earth::edge_usage::ColorRamp;
+///
+/// for ramp in ColorRamp::ALL {
+///     // values outside of the ramp get the color of its ends
+///     assert_eq!(ramp.color_at(-1.0), ramp.color_at(0.0));
+///     assert_eq!(ramp.color_at(2.0), ramp.color_at(1.0));
+///     assert_ne!(ramp.color_at(0.0), ramp.color_at(1.0));
+/// }
+///
+/// // colors between stops are blended
+/// let middle = ColorRamp::Grayscale.color_at(0.5);
+/// assert!(middle.r() > ColorRamp::Grayscale.color_at(0.0).r());
+/// assert!(middle.r() < ColorRamp::Grayscale.color_at(1.0).r());
+/// ```
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ColorRamp {
+    /// From blue through green and yellow to red.
+    #[default]
+    Heat,
+    /// The viridis ramp of matplotlib, which is readable with color blindness.
+    Viridis,
+    /// From dark gray to white.
+    Grayscale,
+}
+
+impl ColorRamp {
+    pub const ALL: [ColorRamp; 3] = [ColorRamp::Heat, ColorRamp::Viridis, ColorRamp::Grayscale];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorRamp::Heat => "Heat",
+            ColorRamp::Viridis => "Viridis",
+            ColorRamp::Grayscale => "Grayscale",
+        }
+    }
+
+    /// Returns the colors of the ramp in sRGB, evenly spread from 0 to 1.
+    fn stops(self) -> &'static [[f32; 3]] {
+        match self {
+            ColorRamp::Heat => &[
+                [0.15, 0.3, 0.95],
+                [0.1, 0.8, 0.35],
+                [1.0, 0.85, 0.1],
+                [0.9, 0.1, 0.05],
+            ],
+            ColorRamp::Viridis => &[
+                [0.267, 0.005, 0.329],
+                [0.229, 0.322, 0.546],
+                [0.128, 0.567, 0.551],
+                [0.369, 0.789, 0.383],
+                [0.993, 0.906, 0.144],
+            ],
+            ColorRamp::Grayscale => &[[0.2, 0.2, 0.2], [1.0, 1.0, 1.0]],
+        }
+    }
+
+    /// Returns the color at `t` on the ramp, where 0 is the color of unused
+    /// roads and 1 the color of the most used road.
+    pub fn color_at(self, t: f32) -> Color {
+        let stops = self.stops();
+        let position = t.clamp(0.0, 1.0) * (stops.len() - 1) as f32;
+        let index = (position as usize).min(stops.len() - 2);
+        let (from, to) = (stops[index], stops[index + 1]);
+        let t = position - index as f32;
+        Color::rgb(
+            from[0] + (to[0] - from[0]) * t,
+            from[1] + (to[1] - from[1]) * t,
+            from[2] + (to[2] - from[2]) * t,
+        )
+    }
+}
+
+/// The settings of the heatmap of edge usage, and its overlay.
+#[derive(Resource)]
+pub struct EdgeUsageHeatmap {
+    /// Whether the heatmap is shown. Usages are counted either way.
+    pub enabled: bool,
+    pub ramp: ColorRamp,
+    /// Whether usages fade away over time, or are kept until they are reset.
+    pub decay: bool,
+    /// The time after which usages are halved, in simulated seconds.
+    pub half_life: f32,
+    /// The simulated time since usages were last decayed.
+    undecayed: f32,
+    rebuild_timer: Timer,
+    /// The overlay and the ramp it was built with.
+    overlay: Option<(Entity, ColorRamp)>,
+    material: Option<Handle<StandardMaterial>>,
+}
+
+impl Default for EdgeUsageHeatmap {
+    fn default() -> Self {
+        EdgeUsageHeatmap {
+            enabled: false,
+            ramp: ColorRamp::default(),
+            decay: true,
+            half_life: DEFAULT_HALF_LIFE,
+            undecayed: 0.0,
+            rebuild_timer: Timer::new(REBUILD_INTERVAL, TimerMode::Repeating),
+            overlay: None,
+            material: None,
+        }
+    }
+}
+
+/// A system that lets the usage of edges fade away, halving it every
+/// half-life of simulated time.
+pub fn decay_edge_usage(
+    time: Res<Time>,
+    simulation: Res<SimulationSettings>,
+    mut heatmap: ResMut<EdgeUsageHeatmap>,
+    mut edge_usage: ResMut<EdgeUsage>,
+) {
+    if !heatmap.decay {
+        heatmap.undecayed = 0.0;
+        return;
+    }
+    heatmap.undecayed += simulation.delta_seconds(&time);
+    if heatmap.undecayed < DECAY_STEP {
+        return;
+    }
+    let factor = 0.5f32.powf(heatmap.undecayed / heatmap.half_life.max(f32::EPSILON));
+    heatmap.undecayed = 0.0;
+    edge_usage.decay(factor);
+}
+
+/// A system that shows the heatmap while it is enabled. Its mesh is rebuilt
+/// every `REBUILD_INTERVAL`, or right away when it is enabled or its ramp is
+/// changed.
+pub fn update_edge_usage_heatmap(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut heatmap: ResMut<EdgeUsageHeatmap>,
+    traffic_graph: Res<TrafficGraph>,
+    edge_usage: Res<EdgeUsage>,
+    asset_cache: Res<AssetCache>,
+    scale: Res<WorldScale>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let heatmap = &mut *heatmap;
+    if !heatmap.enabled {
+        if let Some((entity, _)) = heatmap.overlay.take() {
+            commands.entity(entity).despawn_recursive();
+        }
+        return;
+    }
+
+    heatmap.rebuild_timer.tick(time.delta());
+    let outdated = heatmap.overlay.map_or(true, |(_, ramp)| ramp != heatmap.ramp);
+    if !outdated && !heatmap.rebuild_timer.just_finished() {
+        return;
+    }
+
+    // both directions of a two-way road share one ribbon
+    let mut usages: HashMap<_, f32> = HashMap::new();
+    for (from, to, usage) in edge_usage.used_edges(&traffic_graph) {
+        *usages.entry((from.min(to), from.max(to))).or_default() += usage;
+    }
+    let max_usage = usages.values().copied().fold(0.0, f32::max);
+    if max_usage <= 0.0 {
+        if let Some((entity, _)) = heatmap.overlay.take() {
+            commands.entity(entity).despawn_recursive();
+        }
+        return;
+    }
+
+    let mut mesh_builder = MeshBuilder::new();
+    for ((from, to), usage) in usages {
+        let road_type = traffic_graph.get_road_type(from, to);
+        let width = scale.meters(road_type_to_width(&road_type)) * SIZE_EXAGGERATION;
+        let first = mesh_builder.vertex_count();
+        generate_trajectory(
+            vec![traffic_graph.get_node_location(from), traffic_graph.get_node_location(to)],
+            width,
+            0.0,
+            (0.0..=0.0, 0.0..=0.0),
+            &mut mesh_builder,
+            &asset_cache,
+        );
+        mesh_builder.set_vertex_colors_since(first, heatmap.ramp.color_at(usage / max_usage));
+    }
+    let mesh = meshes.add(mesh_builder.into_mesh());
+
+    let material = heatmap
+        .material
+        .get_or_insert_with(|| {
+            materials.add(OverlayLayer::Heatmap.material(StandardMaterial {
+                base_color: Color::WHITE,
+                unlit: true,
+                ..default()
+            }))
+        })
+        .clone();
+    // the overlay may have been moved with the origin, and its mesh is in
+    // the current world coordinates
+    let transform = Transform::from_xyz(0.0, OVERLAY_HEIGHT, 0.0);
+    let entity = match heatmap.overlay {
+        Some((entity, _)) => {
+            commands.entity(entity).insert((mesh, transform));
+            entity
+        }
+        None => commands
+            .spawn((
+                PbrBundle {
+                    mesh,
+                    material,
+                    transform,
+                    ..default()
+                },
+                NotShadowCaster,
+                NotShadowReceiver,
+            ))
+            .id(),
+    };
+    heatmap.overlay = Some((entity, heatmap.ramp));
+}
+
+/// Forgets how often agents used every edge, which clears the heatmap.
+pub fn reset_edge_usage(commands: &mut Commands) {
+    commands.add(|world: &mut World| {
+        world.resource_mut::<EdgeUsage>().reset();
+    });
+}
+
+/// A system that registers the commands of the heatmap of edge usage.
+pub fn register_edge_usage_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle edge usage heatmap",
+        "Colors the roads by how often agents travel over them",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut heatmap = world.resource_mut::<EdgeUsageHeatmap>();
+                heatmap.enabled = !heatmap.enabled;
+            })
+        },
+    );
+    registry.register(
+        "Reset edge usage heatmap",
+        "Forgets how often agents traveled over every road",
+        None,
+        |commands| reset_edge_usage(commands),
+    );
+}
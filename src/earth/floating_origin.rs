@@ -0,0 +1,294 @@
+//! A floating origin, so that the world stays precise wherever the player
+//! goes.
+//!
+//! Positions in the world are `f32`, which are only precise to about a
+//! millimeter a few kilometers from the origin, and to centimeters a few
+//! hundred kilometers away, where buildings and agents visibly jitter. When
+//! the player moves too far from the origin, everything in the world is moved
+//! back by the position of the player, and the shift is added to the
+//! `Offset`, which is kept in `f64`. Newly loaded data is projected with the
+//! shifted offset, so it lines up with the world.
+
+use crate::common::AsyncComputation;
+use crate::data::geography::{LoadedBounds, Offset};
+use crate::data::traffic_graph::TrafficGraph;
+use crate::earth::agent::Agent;
+use crate::earth::buildings::BuildingFootprints;
+use crate::earth::district_stats::{DistrictStats, DistrictStatsCreation};
+use crate::earth::{
+    AgentCreation, BuildingCreation, GenerationQueue, MeshPartQueue, RiverCreation, RoadCreation,
+    TerrainCreation,
+};
+use crate::player::{CameraMode, Player, PlayerTeleportEvent};
+
+use bevy::prelude::*;
+
+/// How far the player may move from the origin of the world before the
+/// origin is moved to the player, in world units at the default scale.
+pub const ORIGIN_SHIFT_DISTANCE: f32 = 2000.0;
+
+/// Sent when the origin of the world was moved by `shift`, in world units,
+/// so that positions outside of the world (such as bookmarks) can be moved by
+/// `-shift` as well.
+#[derive(Clone, Copy, Debug, Event)]
+pub struct OriginShiftEvent {
+    pub shift: Vec2,
+    /// The offset before the shift.
+    pub previous_offset: Offset,
+}
+
+/// A system that moves the origin of the world to the player when the player
+/// is further than `ORIGIN_SHIFT_DISTANCE` from it. It runs after `Update`,
+/// so entities that were spawned in this frame are moved as well, and before
+/// transforms are propagated.
+///
+/// Generation tasks hold positions relative to the origin they were started
+/// with, so the origin is only moved when none are running or queued. Agent route tasks
+/// only hold vertices of the traffic graph, so they do not matter.
+pub fn update_floating_origin(
+    mut offset: ResMut<Offset>,
+    mut bounds: ResMut<LoadedBounds>,
+    mut traffic_graph: ResMut<TrafficGraph>,
+    mut footprints: ResMut<BuildingFootprints>,
+    mut district_stats: ResMut<DistrictStats>,
+    (mesh_parts, generation_queue): (Res<MeshPartQueue>, Res<GenerationQueue>),
+    teleport_events: Res<Events<PlayerTeleportEvent>>,
+    tasks: Query<
+        (),
+        Or<(
+            With<AsyncComputation<BuildingCreation>>,
+            With<AsyncComputation<RoadCreation>>,
+            With<AsyncComputation<RiverCreation>>,
+            With<AsyncComputation<TerrainCreation>>,
+            With<AsyncComputation<DistrictStatsCreation>>,
+            With<AsyncComputation<AgentCreation>>,
+        )>,
+    >,
+    mut roots: Query<(&mut Transform, Option<&mut Player>), Without<Parent>>,
+    mut agents: Query<&mut Agent>,
+    mut shift_events: EventWriter<OriginShiftEvent>,
+) {
+    // without data, there is nothing to keep precise
+    if offset.x == f64::NEG_INFINITY {
+        return;
+    }
+    let Some(player_position) = roots
+        .iter()
+        .find_map(|(transform, player)| player.map(|_| transform.translation))
+    else {
+        return;
+    };
+    let shift = Vec2::new(player_position.x, player_position.z).round();
+    if shift.length() < offset.scale.units(ORIGIN_SHIFT_DISTANCE) {
+        return;
+    }
+    // teleports that were not handled yet are relative to the current origin
+    if !tasks.is_empty()
+        || !generation_queue.is_empty()
+        || !mesh_parts.is_empty()
+        || !teleport_events.is_empty()
+    {
+        return;
+    }
+
+    let shift_3d = Vec3::new(shift.x, 0.0, shift.y);
+    for (mut transform, mut player) in &mut roots {
+        shift_root(&mut transform, player.as_deref_mut(), shift_3d);
+    }
+    for mut agent in &mut agents {
+        shift_agent(&mut agent, shift_3d);
+    }
+    let previous_offset = shift_resources(
+        shift,
+        &mut offset,
+        &mut bounds,
+        &mut traffic_graph,
+        &mut footprints,
+        &mut district_stats,
+    );
+    shift_events.send(OriginShiftEvent {
+        shift,
+        previous_offset,
+    });
+}
+
+/// Moves an entity without a parent by `-shift`, and the positions its player
+/// holds if it is the player.
+fn shift_root(transform: &mut Transform, player: Option<&mut Player>, shift: Vec3) {
+    transform.translation -= shift;
+    let Some(player) = player else {
+        return;
+    };
+    if let Some(target) = &mut player.teleport_target {
+        *target -= shift;
+    }
+    match &mut player.camera_mode {
+        CameraMode::Perspective => {}
+        CameraMode::TopDown { previous } => previous.translation -= shift,
+        CameraMode::Orbit { focus } => *focus -= shift,
+        CameraMode::Follow(follow) => {
+            if let Some(position) = &mut follow.last_position {
+                *position -= shift;
+            }
+        }
+    }
+}
+
+/// Moves the location an agent drives to by `-shift`.
+fn shift_agent(agent: &mut Agent, shift: Vec3) {
+    if let Some((location, _)) = &mut agent.next_path_location_edge {
+        *location -= shift;
+    }
+}
+
+/// Moves the positions in the resources of the world by `-shift`, and adds
+/// the shift to the offset. Returns the offset before the shift.
+fn shift_resources(
+    shift: Vec2,
+    offset: &mut Offset,
+    bounds: &mut LoadedBounds,
+    traffic_graph: &mut TrafficGraph,
+    footprints: &mut BuildingFootprints,
+    district_stats: &mut DistrictStats,
+) -> Offset {
+    traffic_graph.shift(shift);
+    footprints.shift(shift);
+    bounds.shift(shift);
+    district_stats.shift(offset, shift);
+
+    let previous_offset = *offset;
+    *offset = offset.shifted(shift);
+    previous_offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::geography::{GeoLocation, WorldScale};
+    use crate::data::road_type::RoadType;
+    use crate::data::traffic_graph::{Access, OneWay};
+    use crate::earth::agent::AgentType;
+
+    use bevy::math::{DVec2, Vec3Swizzles};
+
+    /// Two points about 10 km apart.
+    const WEST: GeoLocation = GeoLocation {
+        longitude: 5.4697,
+        latitude: 51.4416,
+    };
+    const EAST: GeoLocation = GeoLocation {
+        longitude: 5.6134,
+        latitude: 51.4416,
+    };
+    /// The shifts of the origin, as if the player was this far away from it.
+    const SHIFTS: [Vec2; 6] = [
+        Vec2::new(2500.0, 0.0),
+        Vec2::new(0.0, 2100.0),
+        Vec2::new(-2400.0, -300.0),
+        Vec2::new(2600.0, 2600.0),
+        Vec2::new(1234.0, -2223.0),
+        Vec2::new(-3000.0, 100.0),
+    ];
+    /// The largest error that is allowed, in meters.
+    const MAX_ERROR: f64 = 0.01;
+    /// Half of the width of the buildings at both points, in world units.
+    const BUILDING_SIZE: f32 = 5.0;
+
+    /// Moves the origin several times, and checks that the traffic graph,
+    /// building footprints, loaded bounds and an agent near two points 10 km
+    /// apart are still where they should be, to within a centimeter, and that
+    /// their distance is still right to within a centimeter.
+    #[test]
+    fn shifted_world_stays_precise() {
+        let scale = WorldScale::default();
+        let mut offset = Offset::centered_on(&WEST, scale);
+        let (west, east) = (WEST.project(&offset), EAST.project(&offset));
+
+        let mut traffic_graph = TrafficGraph::default();
+        traffic_graph.add_connection(1, west, 2, east, OneWay::No, RoadType::Residential, Access::ALL);
+        let path = vec![traffic_graph.get_index(1).unwrap(), traffic_graph.get_index(2).unwrap()];
+        let edge_data = traffic_graph.get_edge_data(path[0], path[1]);
+        let mut agent = Agent {
+            agent_type: AgentType::Car,
+            destination: path[1],
+            path: path.clone().into_boxed_slice(),
+            path_index: 0,
+            next_path_location_edge: Some((Vec3::new(east.x, 0.0, east.y), edge_data)),
+            graph_generation: traffic_graph.get_generation(),
+        };
+        let mut agent_transform = Transform::from_xyz(west.x, 0.0, west.y);
+        let mut footprints = BuildingFootprints::default();
+        footprints.insert(1, square(west));
+        footprints.insert(2, square(east));
+        let mut bounds = LoadedBounds::default();
+        bounds.extend(west, east);
+        let mut district_stats = DistrictStats::default();
+
+        // Everything should have moved by the total shift, which is added up
+        // in f64, and since shifts are whole units, exactly
+        let mut total_shift = DVec2::ZERO;
+        for (shifts, shift) in SHIFTS.into_iter().enumerate() {
+            let shift_3d = Vec3::new(shift.x, 0.0, shift.y);
+            shift_root(&mut agent_transform, None, shift_3d);
+            shift_agent(&mut agent, shift_3d);
+            let previous_offset = shift_resources(
+                shift,
+                &mut offset,
+                &mut bounds,
+                &mut traffic_graph,
+                &mut footprints,
+                &mut district_stats,
+            );
+            assert_ne!((offset.x, offset.y), (previous_offset.x, previous_offset.y));
+            total_shift += shift.as_dvec2();
+
+            let meters_per_unit = WEST.meters_per_unit(&offset);
+            let expected = |original: Vec2| original.as_dvec2() - total_shift;
+            let check = |name: &str, actual: (Vec2, Vec2), original: (Vec2, Vec2)| {
+                let error_west = (actual.0.as_dvec2() - expected(original.0)).length() * meters_per_unit;
+                let error_east = (actual.1.as_dvec2() - expected(original.1)).length() * meters_per_unit;
+                let distance = (actual.1 - actual.0).as_dvec2();
+                let error_distance = (distance - (original.1 - original.0).as_dvec2()).length() * meters_per_unit;
+                assert!(
+                    error_west.max(error_east).max(error_distance) < MAX_ERROR,
+                    "after {} shifts, the {} are off by {:.4} m and {:.4} m, and their distance by {:.4} m",
+                    shifts + 1,
+                    name,
+                    error_west,
+                    error_east,
+                    error_distance
+                );
+            };
+
+            let nodes = (traffic_graph.get_node_location(path[0]), traffic_graph.get_node_location(path[1]));
+            check("traffic graph vertices", nodes, (west, east));
+            // newly loaded data is projected with the shifted offset, and
+            // should line up with what was loaded before
+            check("projected points", (WEST.project(&offset), EAST.project(&offset)), (west, east));
+
+            let (west_building, east_building) = (footprints.get(1).unwrap(), footprints.get(2).unwrap());
+            for (corner, (&corner_west, &corner_east)) in west_building.iter().zip(east_building).enumerate() {
+                check("building footprints", (corner_west, corner_east), (square(west)[corner], square(east)[corner]));
+            }
+            assert!(
+                footprints.push_out(west_building[0] + Vec2::splat(BUILDING_SIZE), &scale).is_some()
+                    && footprints.push_out(east_building[0] + Vec2::splat(BUILDING_SIZE), &scale).is_some(),
+                "after {} shifts, the buildings are not found at their footprints",
+                shifts + 1
+            );
+
+            check("loaded bounds", (bounds.min, bounds.max), (west, east));
+
+            let (next_location, _) = agent.next_path_location_edge.unwrap();
+            check("agent and its next location", (agent_transform.translation.xz(), next_location.xz()), (west, east));
+        }
+    }
+
+    /// Returns the footprint of a square building around `center`.
+    fn square(center: Vec2) -> Vec<Vec2> {
+        [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)]
+            .into_iter()
+            .map(|(x, y)| center + Vec2::new(x, y) * BUILDING_SIZE)
+            .collect()
+    }
+}
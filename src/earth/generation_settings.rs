@@ -0,0 +1,234 @@
+//! Parameters of the generation of the world that can be tuned while the app
+//! runs, in a settings window. Changed values are used for data that is
+//! loaded afterwards, and for loaded data once it is regenerated. The
+//! settings are saved, so tweaks survive restarts.
+
+use crate::commands::CommandRegistry;
+use crate::common::{AppError, StatusEvent};
+use crate::earth::layers::LayerVisibility;
+use crate::earth::RegenerateEvent;
+
+use bevy::prelude::*;
+use bevy_egui::egui;
+use bevy_egui::EguiContexts;
+use serde_json::{json, Value};
+
+/// The name of the file that settings are saved to, on native.
+#[cfg(not(target_arch = "wasm32"))]
+const SETTINGS_FILE: &str = ".settings.json";
+
+/// The key in the local storage that settings are saved under, on the web.
+#[cfg(target_arch = "wasm32")]
+const SETTINGS_KEY: &str = "city_visualizer_settings";
+
+/// Parameters of the generation of buildings and trees. Every generation
+/// task gets a copy of the settings when it is started.
+///
+/// ```
+/// use city_visualizer::earth::generation_settings::GenerationSettings;
+///
+/// let settings = GenerationSettings {
+///     tree_density: 0.1,
+///     ..Default::default()
+/// };
+/// let saved = GenerationSettings::from_json(&settings.to_json());
+/// assert_eq!(saved, settings);
+///
+/// // values that are missing or cannot be read keep their defaults
+/// let saved = GenerationSettings::from_json(r#"{"tree_density": "many"}"#);
+/// assert_eq!(saved, GenerationSettings::default());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Resource)]
+pub struct GenerationSettings {
+    /// Points of building footprints and landuse areas that make a triangle
+    /// with their neighbours smaller than this are removed, in square world
+    /// units at the default scale.
+    pub building_simplification: f32,
+    /// The number of trees per area, in square world units at the default
+    /// scale.
+    pub tree_density: f32,
+    /// Trees where a noise pattern is below this value use the complex tree
+    /// mesh. The noise is between -1 and 1, so -1 gives no complex trees and
+    /// 1 only complex trees.
+    pub complex_tree_threshold: f64,
+    /// Whether the settings window is shown.
+    pub window_visible: bool,
+}
+
+impl Default for GenerationSettings {
+    fn default() -> Self {
+        GenerationSettings {
+            building_simplification: 0.1,
+            tree_density: 0.05,
+            complex_tree_threshold: 0.0,
+            window_visible: false,
+        }
+    }
+}
+
+impl GenerationSettings {
+    pub fn to_json(&self) -> String {
+        json!({
+            "building_simplification": self.building_simplification,
+            "tree_density": self.tree_density,
+            "complex_tree_threshold": self.complex_tree_threshold,
+        })
+        .to_string()
+    }
+
+    /// Reads settings saved by `to_json`. Values that cannot be read keep
+    /// their defaults.
+    pub fn from_json(string: &str) -> Self {
+        let mut settings = GenerationSettings::default();
+        let Ok(Value::Object(map)) = serde_json::from_str::<Value>(string) else {
+            return settings;
+        };
+        let number = |key: &str| map.get(key).and_then(Value::as_f64);
+        if let Some(value) = number("building_simplification") {
+            settings.building_simplification = value as f32;
+        }
+        if let Some(value) = number("tree_density") {
+            settings.tree_density = value as f32;
+        }
+        if let Some(value) = number("complex_tree_threshold") {
+            settings.complex_tree_threshold = value;
+        }
+        settings
+    }
+}
+
+/// A system that loads the settings that were saved before.
+pub fn setup_generation_settings(mut settings: ResMut<GenerationSettings>) {
+    if let Some(saved) = load_settings() {
+        let window_visible = settings.window_visible;
+        *settings = GenerationSettings {
+            window_visible,
+            ..GenerationSettings::from_json(&saved)
+        };
+    }
+}
+
+/// A system that registers the command that shows the settings window.
+pub fn register_generation_settings_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle generation settings",
+        "Shows the parameters of generation, to tune them and generate the world again",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut settings = world.resource_mut::<GenerationSettings>();
+                settings.window_visible = !settings.window_visible;
+            })
+        },
+    );
+}
+
+/// A system that shows the settings window. Changes are saved once the
+/// mouse is released, so dragging a slider does not save every frame.
+pub fn update_generation_settings_window(
+    mut contexts: EguiContexts,
+    mut settings: ResMut<GenerationSettings>,
+    mut unsaved: Local<bool>,
+    layers: Res<LayerVisibility>,
+    mut regenerate_events: EventWriter<RegenerateEvent>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    if !settings.window_visible {
+        return;
+    }
+
+    let mut edited = *settings;
+    let mut regenerate = false;
+    let mut visible = true;
+    let ctx = contexts.ctx_mut();
+    egui::Window::new("Settings")
+        .open(&mut visible)
+        .show(ctx, |ui| {
+            ui.add(
+                egui::Slider::new(&mut edited.building_simplification, 0.0..=10.0)
+                    .logarithmic(true)
+                    .text("Building simplification"),
+            )
+            .on_hover_text("Removes corners of buildings that add less area than this");
+            ui.add(
+                egui::Slider::new(&mut edited.tree_density, 0.0..=0.5)
+                    .logarithmic(true)
+                    .text("Tree density"),
+            );
+            ui.add(
+                egui::Slider::new(&mut edited.complex_tree_threshold, -1.0..=1.0)
+                    .text("Complex trees"),
+            )
+            .on_hover_text("Higher values use the detailed tree mesh for more trees");
+            ui.separator();
+            ui.label("Changes apply to newly loaded data");
+            ui.horizontal(|ui| {
+                regenerate = ui
+                    .button("Regenerate")
+                    .on_hover_text("Generates the shown layers again with these settings")
+                    .clicked();
+                if ui.button("Reset to defaults").clicked() {
+                    edited = GenerationSettings {
+                        window_visible: true,
+                        ..Default::default()
+                    };
+                }
+            });
+        });
+    edited.window_visible = visible;
+
+    if edited != *settings {
+        // showing or hiding the window is not saved
+        *unsaved |= edited.to_json() != settings.to_json();
+        *settings = edited;
+    }
+    if *unsaved && !ctx.input(|input| input.pointer.any_down()) {
+        if let Err(error) = save_settings(&settings.to_json()) {
+            status_events.send(StatusEvent::Error(error));
+        }
+        *unsaved = false;
+    }
+    if regenerate {
+        regenerate_events.send(RegenerateEvent::visible(&layers));
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn load_settings() -> Option<String> {
+    std::fs::read_to_string(SETTINGS_FILE).ok()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn save_settings(json: &str) -> Result<(), AppError> {
+    std::fs::write(SETTINGS_FILE, json).map_err(|error| AppError::Io {
+        url: None,
+        status: None,
+        message: format!("could not save the settings to {}: {}", SETTINGS_FILE, error),
+    })
+}
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok().flatten()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn load_settings() -> Option<String> {
+    local_storage()?.get_item(SETTINGS_KEY).ok().flatten()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn save_settings(json: &str) -> Result<(), AppError> {
+    let saved = local_storage().map_or(false, |storage| {
+        storage.set_item(SETTINGS_KEY, json).is_ok()
+    });
+    if saved {
+        Ok(())
+    } else {
+        Err(AppError::Io {
+            url: None,
+            status: None,
+            message: "could not save the settings in the local storage of the browser".to_owned(),
+        })
+    }
+}
@@ -0,0 +1,785 @@
+//! Export of the generated world to a binary glTF (GLB) file, to use the city
+//! in other programs such as Blender.
+//!
+//! The meshes of every layer are merged per chunk, so a large city becomes a
+//! node for every layer in every chunk, named after both. Textures are not
+//! exported: the colors that the texture atlases give every vertex are baked
+//! into vertex colors instead, and materials only keep their plain factors.
+//! Agents and the user interface are not part of the export.
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::commands::CommandRegistry;
+use crate::common::{
+    handle_compute_tasks, spawn_compute_task, AppError, AsyncComputation, StatusEvent,
+};
+use crate::data::geography::{ChunkIndex, Offset};
+use crate::earth::assets::AssetCache;
+use crate::earth::layers::{FeatureLayer, LayerVisibility};
+use crate::earth::trees::TreeChunk;
+use crate::earth::{GeoFeature, Tunnel, TunnelSettings};
+use crate::lod::LOD;
+
+use bevy::asset::AssetId;
+use bevy::math::Affine3A;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology, VertexAttributeValues};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::TextureFormat;
+use serde_json::{json, Value as JsonValue};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// glTF constants, see https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html
+const GLTF_FLOAT: u32 = 5126;
+const GLTF_UNSIGNED_INT: u32 = 5125;
+const GLTF_ARRAY_BUFFER: u32 = 34962;
+const GLTF_ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const GLB_MAGIC: u32 = 0x4654_6C67;
+const GLB_VERSION: u32 = 2;
+const GLB_JSON_CHUNK: u32 = 0x4E4F_534A;
+const GLB_BIN_CHUNK: u32 = 0x004E_4942;
+
+/// The label of the progress bar while exporting.
+const EXPORT_LABEL: &str = "Exporting glTF";
+
+/// A minimal glTF writer, which stores every node as a mesh with positions,
+/// normals, texture coordinates, vertex colors and indices, and optionally a
+/// material.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use city_visualizer::earth::gltf_export::GltfWriter;
+///
+/// let material = StandardMaterial::from(Color::BLUE);
+/// let mut writer = GltfWriter::default();
+/// let water = writer.add_material("water", &material);
+/// let mesh = Mesh::from(Plane3d::default().mesh());
+/// writer.add_node("Lakes", &[(&mesh, Some(water))]);
+///
+/// let glb = writer.to_glb();
+/// assert_eq!(&glb[0..4], b"glTF");
+/// assert_eq!(glb.len() % 4, 0);
+/// assert_eq!(u32::from_le_bytes(glb[8..12].try_into().unwrap()) as usize, glb.len());
+/// ```
+#[derive(Default)]
+pub struct GltfWriter {
+    buffer: Vec<u8>,
+    buffer_views: Vec<JsonValue>,
+    accessors: Vec<JsonValue>,
+    materials: Vec<JsonValue>,
+    meshes: Vec<JsonValue>,
+    nodes: Vec<JsonValue>,
+}
+
+impl GltfWriter {
+    /// Adds a node with a single mesh without a material.
+    pub fn add_mesh(&mut self, name: &str, mesh: &Mesh) {
+        self.add_node(name, &[(mesh, None)]);
+    }
+
+    /// Adds a node with a mesh that has a primitive for every given mesh,
+    /// with the material of the given index. Meshes without positions, and
+    /// meshes that are not lists of triangles, are skipped.
+    pub fn add_node(&mut self, name: &str, primitives: &[(&Mesh, Option<usize>)]) {
+        let primitives: Vec<JsonValue> = primitives
+            .iter()
+            .filter_map(|&(mesh, material)| self.add_primitive(mesh, material))
+            .collect();
+        if primitives.is_empty() {
+            return;
+        }
+        self.nodes
+            .push(json!({ "name": name, "mesh": self.meshes.len() }));
+        self.meshes
+            .push(json!({ "name": name, "primitives": primitives }));
+    }
+
+    /// Adds a material with the plain factors of a bevy material, and returns
+    /// its index. Textures are left out.
+    pub fn add_material(&mut self, name: &str, material: &StandardMaterial) -> usize {
+        let [red, green, blue, _] = material.emissive.as_linear_rgba_f32();
+        let mut gltf_material = json!({
+            "name": name,
+            "pbrMetallicRoughness": {
+                "baseColorFactor": material.base_color.as_linear_rgba_f32(),
+                "metallicFactor": material.metallic,
+                "roughnessFactor": material.perceptual_roughness,
+            },
+            // glTF only allows emission up to 1
+            "emissiveFactor": [red.min(1.0), green.min(1.0), blue.min(1.0)],
+            "doubleSided": material.double_sided || material.cull_mode.is_none(),
+        });
+        match material.alpha_mode {
+            AlphaMode::Opaque => {}
+            AlphaMode::Mask(cutoff) => {
+                gltf_material["alphaMode"] = json!("MASK");
+                gltf_material["alphaCutoff"] = json!(cutoff);
+            }
+            _ => gltf_material["alphaMode"] = json!("BLEND"),
+        }
+        self.materials.push(gltf_material);
+        self.materials.len() - 1
+    }
+
+    /// Returns the number of nodes that were added.
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn add_primitive(&mut self, mesh: &Mesh, material: Option<usize>) -> Option<JsonValue> {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return None;
+        }
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            return None;
+        };
+        if positions.is_empty() {
+            return None;
+        }
+        let indices: Vec<u32> = match mesh.indices() {
+            Some(indices) => indices.iter().map(|i| i as u32).collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+
+        // glTF requires the bounds of the positions
+        let mut min = [f32::INFINITY; 3];
+        let mut max = [f32::NEG_INFINITY; 3];
+        for position in positions {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(position[axis]);
+                max[axis] = max[axis].max(position[axis]);
+            }
+        }
+
+        let mut attributes = serde_json::Map::new();
+        let position_accessor = self.add_accessor(
+            positions
+                .iter()
+                .flatten()
+                .flat_map(|v| v.to_le_bytes())
+                .collect(),
+            GLTF_ARRAY_BUFFER,
+            json!({ "componentType": GLTF_FLOAT, "count": positions.len(), "type": "VEC3",
+                    "min": min, "max": max }),
+        );
+        attributes.insert("POSITION".to_owned(), json!(position_accessor));
+        if let Some(VertexAttributeValues::Float32x3(normals)) =
+            mesh.attribute(Mesh::ATTRIBUTE_NORMAL)
+        {
+            let normal_accessor = self.add_accessor(
+                normals
+                    .iter()
+                    .flatten()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect(),
+                GLTF_ARRAY_BUFFER,
+                json!({ "componentType": GLTF_FLOAT, "count": normals.len(), "type": "VEC3" }),
+            );
+            attributes.insert("NORMAL".to_owned(), json!(normal_accessor));
+        }
+        if let Some(VertexAttributeValues::Float32x2(uvs)) = mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            let uv_accessor = self.add_accessor(
+                uvs.iter().flatten().flat_map(|v| v.to_le_bytes()).collect(),
+                GLTF_ARRAY_BUFFER,
+                json!({ "componentType": GLTF_FLOAT, "count": uvs.len(), "type": "VEC2" }),
+            );
+            attributes.insert("TEXCOORD_0".to_owned(), json!(uv_accessor));
+        }
+        if let Some(VertexAttributeValues::Float32x4(colors)) =
+            mesh.attribute(Mesh::ATTRIBUTE_COLOR)
+        {
+            let color_accessor = self.add_accessor(
+                colors
+                    .iter()
+                    .flatten()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect(),
+                GLTF_ARRAY_BUFFER,
+                json!({ "componentType": GLTF_FLOAT, "count": colors.len(), "type": "VEC4" }),
+            );
+            attributes.insert("COLOR_0".to_owned(), json!(color_accessor));
+        }
+        let index_accessor = self.add_accessor(
+            indices.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            GLTF_ELEMENT_ARRAY_BUFFER,
+            json!({ "componentType": GLTF_UNSIGNED_INT, "count": indices.len(), "type": "SCALAR" }),
+        );
+
+        let mut primitive = json!({ "attributes": attributes, "indices": index_accessor });
+        if let Some(material) = material {
+            primitive["material"] = json!(material);
+        }
+        Some(primitive)
+    }
+
+    /// Appends the bytes to the buffer and adds a buffer view and accessor
+    /// for them. Returns the index of the accessor.
+    fn add_accessor(&mut self, bytes: Vec<u8>, target: u32, mut accessor: JsonValue) -> usize {
+        let view = self.buffer_views.len();
+        self.buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": self.buffer.len(),
+            "byteLength": bytes.len(),
+            "target": target,
+        }));
+        self.buffer.extend(bytes);
+
+        accessor["bufferView"] = json!(view);
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+
+    /// Returns the glTF JSON, with the buffer at `buffer_uri`, or in the
+    /// binary chunk of a GLB file if it is `None`.
+    fn to_json(&self, buffer_uri: Option<&str>) -> JsonValue {
+        let mut buffer = json!({ "byteLength": self.buffer.len() });
+        if let Some(uri) = buffer_uri {
+            buffer["uri"] = json!(uri);
+        }
+        let mut gltf = json!({
+            "asset": { "version": "2.0", "generator": "city_visualizer" },
+            "scene": 0,
+            "scenes": [{ "nodes": (0..self.nodes.len()).collect::<Vec<_>>() }],
+            "nodes": self.nodes,
+            "meshes": self.meshes,
+            "accessors": self.accessors,
+            "bufferViews": self.buffer_views,
+            "buffers": [buffer],
+        });
+        // glTF does not allow empty arrays
+        if !self.materials.is_empty() {
+            gltf["materials"] = json!(self.materials);
+        }
+        gltf
+    }
+
+    /// Returns the contents of a GLB file: a header, the glTF JSON and the
+    /// buffer, with both chunks padded to 4 bytes.
+    pub fn to_glb(&self) -> Vec<u8> {
+        let mut json = self.to_json(None).to_string().into_bytes();
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let mut bin = self.buffer.clone();
+        bin.resize(bin.len().next_multiple_of(4), 0);
+
+        let length = 12 + 8 + json.len() + 8 + bin.len();
+        let mut glb = Vec::with_capacity(length);
+        for word in [GLB_MAGIC, GLB_VERSION, length as u32] {
+            glb.extend(word.to_le_bytes());
+        }
+        for (chunk_type, chunk) in [(GLB_JSON_CHUNK, json), (GLB_BIN_CHUNK, bin)] {
+            glb.extend((chunk.len() as u32).to_le_bytes());
+            glb.extend(chunk_type.to_le_bytes());
+            glb.extend(chunk);
+        }
+        glb
+    }
+
+    /// Writes the glTF file to the given path, and the buffer next to it.
+    pub fn write_gltf(&self, path: &Path) -> std::io::Result<()> {
+        let buffer_path = path.with_extension("bin");
+        let buffer_name = buffer_path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let gltf = self.to_json(Some(&buffer_name));
+
+        std::fs::write(&buffer_path, &self.buffer)?;
+        std::fs::write(path, serde_json::to_string_pretty(&gltf)?)
+    }
+}
+
+/// An event that exports the world to a GLB file at the given path.
+#[derive(Clone, Debug, Event)]
+pub struct ExportGltfEvent {
+    pub path: PathBuf,
+}
+
+/// The progress of the running export, if any.
+#[derive(Debug, Default, Resource)]
+pub struct GltfExportState {
+    /// The number of nodes that are written, and the number of nodes of the
+    /// whole export.
+    progress: Option<(Arc<AtomicUsize>, usize)>,
+    /// The number of written nodes that was last reported.
+    reported: usize,
+    /// The number of exports that finished, successfully or not.
+    pub finished: usize,
+}
+
+impl GltfExportState {
+    /// Returns whether an export is running.
+    pub fn is_running(&self) -> bool {
+        self.progress.is_some()
+    }
+}
+
+/// A material of the export, with the texture that is baked into the vertex
+/// colors of its meshes.
+struct ExportMaterial {
+    name: String,
+    material: StandardMaterial,
+    texture: Option<Image>,
+}
+
+/// A mesh in the world, to be merged with the other meshes of its layer and
+/// chunk.
+struct ExportPart {
+    layer: FeatureLayer,
+    chunk: ChunkIndex,
+    /// The index of the mesh in `ExportScene::meshes`, since many trees and
+    /// lamps share a mesh.
+    mesh: usize,
+    material: usize,
+    transform: Affine3A,
+}
+
+/// Everything an export task needs, copied from the world so the task can
+/// run on another thread.
+#[derive(Default)]
+struct ExportScene {
+    meshes: Vec<Mesh>,
+    mesh_indices: HashMap<AssetId<Mesh>, usize>,
+    materials: Vec<ExportMaterial>,
+    material_indices: HashMap<AssetId<StandardMaterial>, usize>,
+    parts: Vec<ExportPart>,
+}
+
+impl ExportScene {
+    /// Adds a mesh with a material at the given transform, if both are
+    /// loaded.
+    fn add(
+        &mut self,
+        layer: FeatureLayer,
+        mesh: &Handle<Mesh>,
+        material: &Handle<StandardMaterial>,
+        transform: Affine3A,
+        assets: (&Assets<Mesh>, &Assets<StandardMaterial>, &Assets<Image>),
+        offset: &Offset,
+    ) {
+        let (meshes, materials, images) = assets;
+        let mesh = match self.mesh_indices.get(&mesh.id()) {
+            Some(&index) => index,
+            None => {
+                let Some(loaded) = meshes.get(mesh) else {
+                    return;
+                };
+                self.meshes.push(loaded.clone());
+                self.mesh_indices.insert(mesh.id(), self.meshes.len() - 1);
+                self.meshes.len() - 1
+            }
+        };
+        let material = match self.material_indices.get(&material.id()) {
+            Some(&index) => index,
+            None => {
+                let Some(loaded) = materials.get(material) else {
+                    return;
+                };
+                self.materials.push(ExportMaterial {
+                    name: format!("{} {}", layer.name(), self.materials.len()),
+                    material: loaded.clone(),
+                    texture: loaded
+                        .base_color_texture
+                        .as_ref()
+                        .and_then(|texture| images.get(texture))
+                        .cloned(),
+                });
+                self.material_indices
+                    .insert(material.id(), self.materials.len() - 1);
+                self.materials.len() - 1
+            }
+        };
+        let position = transform.translation;
+        self.parts.push(ExportPart {
+            layer,
+            chunk: ChunkIndex::from_world(Vec2::new(position.x, position.z), offset),
+            mesh,
+            material,
+            transform,
+        });
+    }
+
+    /// Returns the parts grouped by layer and chunk, in the order of the
+    /// layers.
+    fn groups(&self) -> BTreeMap<(usize, ChunkIndex), Vec<&ExportPart>> {
+        let mut groups: BTreeMap<(usize, ChunkIndex), Vec<&ExportPart>> = BTreeMap::new();
+        for part in &self.parts {
+            let layer = FeatureLayer::ALL
+                .iter()
+                .position(|&layer| layer == part.layer)
+                .unwrap_or_default();
+            groups
+                .entry((layer, part.chunk.clone()))
+                .or_default()
+                .push(part);
+        }
+        groups
+    }
+
+    /// Merges the meshes of every layer in every chunk into a node, and
+    /// returns the GLB file. Counts the written nodes in `progress`.
+    fn to_glb(&self, progress: &AtomicUsize) -> (Vec<u8>, usize) {
+        let mut writer = GltfWriter::default();
+        for export_material in &self.materials {
+            writer.add_material(&export_material.name, &export_material.material);
+        }
+        for ((_, chunk), parts) in self.groups() {
+            let mut merged: BTreeMap<usize, MergedMesh> = BTreeMap::new();
+            for part in &parts {
+                merged.entry(part.material).or_default().add(
+                    &self.meshes[part.mesh],
+                    part.transform,
+                    self.materials[part.material].texture.as_ref(),
+                );
+            }
+            let meshes: Vec<(Mesh, usize)> = merged
+                .into_iter()
+                .map(|(material, mesh)| (mesh.into_mesh(), material))
+                .collect();
+            let primitives: Vec<(&Mesh, Option<usize>)> = meshes
+                .iter()
+                .map(|(mesh, material)| (mesh, Some(*material)))
+                .collect();
+            let name = format!("{} {}_{}", parts[0].layer.name(), chunk.x, chunk.z);
+            writer.add_node(&name, &primitives);
+            progress.fetch_add(1, Ordering::Relaxed);
+        }
+        (writer.to_glb(), writer.node_count())
+    }
+}
+
+/// Meshes with the same material merged into one, in world coordinates.
+#[derive(Default)]
+struct MergedMesh {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    colors: Vec<[f32; 4]>,
+    indices: Vec<u32>,
+}
+
+impl MergedMesh {
+    /// Adds a mesh at the given transform. The vertex colors of the mesh are
+    /// multiplied with the color of the texture at its texture coordinates.
+    fn add(&mut self, mesh: &Mesh, transform: Affine3A, texture: Option<&Image>) {
+        if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+            return;
+        }
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            return;
+        };
+        let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+            Some(VertexAttributeValues::Float32x3(normals)) => Some(normals),
+            _ => None,
+        };
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float32x2(uvs)) => Some(uvs),
+            _ => None,
+        };
+        let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(colors)) => Some(colors),
+            _ => None,
+        };
+
+        let index_offset = self.positions.len() as u32;
+        for (i, &position) in positions.iter().enumerate() {
+            let position = transform.transform_point3(Vec3::from_array(position));
+            self.positions.push(position.to_array());
+            // meshes are scaled the same along all axes, so normals only
+            // have to be turned
+            let normal = normals.map_or(Vec3::Y, |normals| Vec3::from_array(normals[i]));
+            let normal = transform.transform_vector3(normal).normalize_or_zero();
+            self.normals.push(normal.to_array());
+
+            let mut color = colors.map_or([1.0; 4], |colors| colors[i]);
+            let texture_color = texture
+                .zip(uvs)
+                .and_then(|(texture, uvs)| sample_texture(texture, uvs[i]));
+            if let Some(texture_color) = texture_color {
+                for (channel, texture_channel) in color.iter_mut().zip(texture_color) {
+                    *channel *= texture_channel;
+                }
+            }
+            self.colors.push(color);
+        }
+        match mesh.indices() {
+            Some(indices) => self
+                .indices
+                .extend(indices.iter().map(|i| index_offset + i as u32)),
+            None => self
+                .indices
+                .extend(index_offset..index_offset + positions.len() as u32),
+        }
+    }
+
+    fn into_mesh(self) -> Mesh {
+        let mut mesh = Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::MAIN_WORLD,
+        );
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, self.positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, self.colors);
+        mesh.insert_indices(Indices::U32(self.indices));
+        mesh
+    }
+}
+
+/// Returns the linear color of the pixel of a texture at the given texture
+/// coordinates, which repeat outside of 0 to 1. Returns `None` for textures
+/// that are not 8-bit RGBA.
+fn sample_texture(image: &Image, uv: [f32; 2]) -> Option<[f32; 4]> {
+    let srgb = match image.texture_descriptor.format {
+        TextureFormat::Rgba8UnormSrgb => true,
+        TextureFormat::Rgba8Unorm => false,
+        _ => return None,
+    };
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    if width == 0 || height == 0 {
+        return None;
+    }
+    let x = ((uv[0].rem_euclid(1.0) * width as f32) as usize).min(width - 1);
+    let y = ((uv[1].rem_euclid(1.0) * height as f32) as usize).min(height - 1);
+    let start = (y * width + x) * 4;
+    let &[red, green, blue, alpha] = image.data.get(start..start + 4)? else {
+        return None;
+    };
+    let color = if srgb {
+        Color::rgba_u8(red, green, blue, alpha)
+    } else {
+        let [red, green, blue, alpha] = [red, green, blue, alpha].map(|c| c as f32 / 255.0);
+        Color::rgba_linear(red, green, blue, alpha)
+    };
+    Some(color.as_linear_rgba_f32())
+}
+
+/// The result of an export task: the path and size of the written file, and
+/// the number of nodes in it.
+pub struct GltfExport(Result<(PathBuf, usize, usize), AppError>);
+
+/// A system that starts an export task for every `ExportGltfEvent`. The
+/// meshes of all shown features are copied from the world, except for
+/// agents and tunnels that are hidden. Trees are exported as the simple
+/// meshes that are shown in the distance, so forests stay small.
+pub fn start_gltf_export(
+    mut commands: Commands,
+    mut export_events: EventReader<ExportGltfEvent>,
+    mut export_state: ResMut<GltfExportState>,
+    features: Query<
+        (Entity, &FeatureLayer, &GlobalTransform, Option<&TreeChunk>),
+        With<GeoFeature>,
+    >,
+    children: Query<&Children>,
+    mesh_entities: Query<(
+        &Handle<Mesh>,
+        &Handle<StandardMaterial>,
+        &GlobalTransform,
+        Option<&LOD>,
+        Option<&Tunnel>,
+    )>,
+    (meshes, materials, images): (
+        Res<Assets<Mesh>>,
+        Res<Assets<StandardMaterial>>,
+        Res<Assets<Image>>,
+    ),
+    (asset_cache, layers, tunnel_settings, offset): (
+        Res<AssetCache>,
+        Res<LayerVisibility>,
+        Res<TunnelSettings>,
+        Res<Offset>,
+    ),
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let Some(event) = export_events.read().last().cloned() else {
+        return;
+    };
+    if export_state.is_running() {
+        status_events.send(StatusEvent::Update(
+            "Wait for the running export to finish before exporting again".to_owned(),
+        ));
+        return;
+    }
+
+    let assets = (&*meshes, &*materials, &*images);
+    let mut scene = ExportScene::default();
+    for (entity, &layer, chunk_transform, tree_chunk) in &features {
+        if layer == FeatureLayer::Agents || !layers.is_visible(layer) {
+            continue;
+        }
+        // the children of a tree chunk show its trees both near and far
+        if let Some(tree_chunk) = tree_chunk {
+            let chunk_transform = chunk_transform.affine();
+            add_trees(
+                &mut scene,
+                tree_chunk,
+                chunk_transform,
+                &asset_cache,
+                assets,
+                &offset,
+            );
+            continue;
+        }
+        for entity in std::iter::once(entity).chain(children.iter_descendants(entity)) {
+            let Ok((mesh, material, transform, lod, tunnel)) = mesh_entities.get(entity) else {
+                continue;
+            };
+            if tunnel.is_some() && !tunnel_settings.visible {
+                continue;
+            }
+            // far meshes replace the mesh of the entity in the distance
+            let mesh = lod.map_or(mesh, |lod| &lod.high_quality_mesh);
+            scene.add(layer, mesh, material, transform.affine(), assets, &offset);
+        }
+    }
+
+    if scene.parts.is_empty() {
+        status_events.send(StatusEvent::Error(AppError::MissingData {
+            message: "load data before exporting it".to_owned(),
+        }));
+        return;
+    }
+    let total = scene.groups().len();
+    let progress = Arc::new(AtomicUsize::new(0));
+    export_state.progress = Some((Arc::clone(&progress), total));
+    export_state.reported = 0;
+    status_events.send(StatusEvent::Progress {
+        label: EXPORT_LABEL.to_owned(),
+        fraction: Some(0.0),
+    });
+
+    let path = event.path;
+    spawn_compute_task(&mut commands, async move {
+        let (glb, nodes) = scene.to_glb(&progress);
+        GltfExport(match std::fs::write(&path, &glb) {
+            Ok(()) => Ok((path, glb.len(), nodes)),
+            Err(error) => Err(AppError::Io {
+                url: None,
+                status: None,
+                message: format!("could not write {}: {}", path.display(), error),
+            }),
+        })
+    });
+}
+
+/// Adds the trees of a chunk with the simple tree meshes.
+fn add_trees(
+    scene: &mut ExportScene,
+    tree_chunk: &TreeChunk,
+    chunk_transform: Affine3A,
+    asset_cache: &AssetCache,
+    assets: (&Assets<Mesh>, &Assets<StandardMaterial>, &Assets<Image>),
+    offset: &Offset,
+) {
+    let material = asset_cache.get_tree_material();
+    let triangle_tree = asset_cache.get_triangle_tree_mesh();
+    let complex_tree = asset_cache.get_simplified_complex_tree_mesh();
+    for tree in tree_chunk.trees() {
+        let mesh = if tree.complex {
+            &complex_tree
+        } else {
+            &triangle_tree
+        };
+        let transform = chunk_transform * tree.transform.compute_affine();
+        scene.add(
+            FeatureLayer::Trees,
+            mesh,
+            &material,
+            transform,
+            assets,
+            offset,
+        );
+    }
+}
+
+/// A system that reports the progress of the running export, and the result
+/// once it is done.
+pub fn update_gltf_export(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<GltfExport>)>,
+    mut export_state: ResMut<GltfExportState>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let progress = export_state
+        .progress
+        .as_ref()
+        .map(|(written, total)| (written.load(Ordering::Relaxed), *total));
+    if let Some((written, total)) = progress {
+        if written != export_state.reported {
+            // writing the file is the last step
+            let fraction = written as f32 / (total + 1) as f32;
+            status_events.send(StatusEvent::Progress {
+                label: EXPORT_LABEL.to_owned(),
+                fraction: Some(fraction),
+            });
+            export_state.reported = written;
+        }
+    }
+
+    handle_compute_tasks(&mut commands, query, |_, GltfExport(result)| {
+        export_state.progress = None;
+        export_state.finished += 1;
+        status_events.send(StatusEvent::progress_finished());
+        match result {
+            Ok((path, size, nodes)) => {
+                status_events.send(StatusEvent::Update(format!(
+                    "Exported {} nodes to {} ({:.1} MB)",
+                    nodes,
+                    path.display(),
+                    size as f64 / 1_000_000.0
+                )));
+            }
+            Err(error) => {
+                status_events.send(StatusEvent::Error(error));
+            }
+        }
+    });
+}
+
+/// The file that was chosen in the export dialog, or `None` if the dialog
+/// was cancelled.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct GltfExportTarget(Option<PathBuf>);
+
+/// Opens a dialog to choose where to export the world to, without blocking
+/// the frame. The chosen file is handled by `update_gltf_export_dialog`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn_gltf_export_dialog(commands: &mut Commands) {
+    let dialog = rfd::AsyncFileDialog::new()
+        .set_title("Export the city")
+        .add_filter("Binary glTF", &["glb"])
+        .set_file_name("city.glb");
+    spawn_compute_task(commands, async move {
+        GltfExportTarget(dialog.save_file().await.map(|file| file.path().to_owned()))
+    });
+}
+
+/// A system that exports the world to the file that was chosen in the
+/// export dialog.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn update_gltf_export_dialog(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<GltfExportTarget>)>,
+    mut export_events: EventWriter<ExportGltfEvent>,
+) {
+    handle_compute_tasks(&mut commands, query, |_, GltfExportTarget(path)| {
+        if let Some(path) = path {
+            export_events.send(ExportGltfEvent { path });
+        }
+    });
+}
+
+/// A system that registers the command that exports the world.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn register_gltf_export_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Export to glTF",
+        "Saves the shown layers of the world as a GLB file, for example to open it in Blender",
+        None,
+        |commands| spawn_gltf_export_dialog(commands),
+    );
+}
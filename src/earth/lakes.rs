@@ -4,12 +4,25 @@ use bevy::prelude::*;
 // Import randon
 
 
-use crate::data::geography::{GeoLocation, LakeFeature, Offset};
-use crate::earth::{GeoFeature, GLOBAL_SCALE_FACTOR};
+use crate::data::geography::{close_ring, project_nodes, GeoLocation, LakeFeature, Offset};
+use crate::earth::layers::FeatureLayer;
+use crate::earth::scene_stats::SceneStats;
+use crate::earth::GeoFeature;
+use crate::earth::buildings::point_in_polygon_check;
 use crate::earth::mesh_builder::MeshBuilder;
 use crate::earth::simplification::simplify_polygon;
 
-const LAKE_SIMPLIFICATION_THRESHOLD: f32 = 0.00001 * GLOBAL_SCALE_FACTOR * GLOBAL_SCALE_FACTOR;
+// Lengths and areas are in world units at the default scale, see `WorldScale`
+const LAKE_SIMPLIFICATION_THRESHOLD: f32 = 0.1;
+
+// Interior points are placed on a grid with at least this spacing, and at most
+// this many steps along each axis of the lake, to keep triangulation cheap
+const LAKE_GRID_SPACING: f32 = 20.0;
+const LAKE_MAX_GRID_STEPS: f32 = 32.0;
+
+// At this distance from the shore (and further) the water is darkest
+const LAKE_DEPTH_DISTANCE: f32 = 50.0;
+const LAKE_DEEP_BRIGHTNESS: f32 = 0.4;
 
 fn generate_lake(
     commands: &mut Commands,
@@ -17,25 +30,40 @@ fn generate_lake(
     materials: &mut ResMut<Assets<StandardMaterial>>,
     node_locations: &HashMap<u64, GeoLocation>,
     lake: &LakeFeature,
-    offset: &Offset
+    offset: &Offset,
+    scene_stats: &mut SceneStats,
 ) {
 
-    let area: Vec<Vec2> = lake
-        .nodes
-        .iter()
-        .filter_map(|node_id| node_locations.get(node_id).map(|node| node.project(offset)))
-        .collect();
+    let Some(area) = project_nodes(node_locations, &close_ring(&lake.nodes), offset)
+        .filter(|area| area.len() >= 3)
+    else {
+        return;
+    };
 
-    let area_simplified = simplify_polygon(area, LAKE_SIMPLIFICATION_THRESHOLD);
+    let area_simplified = simplify_polygon(area, offset.scale.area(LAKE_SIMPLIFICATION_THRESHOLD));
     let points: Vec<_> = area_simplified.iter()
         .map(|vec2| geo::Point::new(vec2.x as f64, vec2.y as f64))
         .collect();
     let polygon = geo::Polygon::new(points.into(), vec![]);
 
+    // Add points in the middle of the lake, so there are vertices to darken
+    let interior_points =
+        get_interior_grid_points(&area_simplified, offset.scale.units(LAKE_GRID_SPACING));
+
+    let depth_distance = offset.scale.units(LAKE_DEPTH_DISTANCE);
     let mut mesh_builder = MeshBuilder::new();
     let uv = Vec2::new(0.0, 0.0);  // TODO tweak?
-    mesh_builder.add_polygon_xz(&polygon, 0.009, uv);  // Up normal
+    let added = mesh_builder.add_polygon_xz_with_points(&polygon, &interior_points, 0.009, uv, |point| {
+        // Darken the water further away from the shore, as a cheap depth cue
+        let depth = (distance_to_boundary(&area_simplified, point) / depth_distance).min(1.0);
+        let brightness = 1.0 - depth * (1.0 - LAKE_DEEP_BRIGHTNESS);
+        Color::rgb(brightness, brightness, brightness)
+    });  // Up normal
+    if added.is_err() {
+        return;
+    }
     let mesh = mesh_builder.into_mesh();
+    scene_stats.add_mesh(FeatureLayer::Lakes, &mesh);
 
     let lake_material: Handle<StandardMaterial> = materials.add(StandardMaterial {
         base_color: Color::BLUE,
@@ -47,7 +75,73 @@ fn generate_lake(
         mesh: meshes.add(mesh),
         material: lake_material,
         ..Default::default()
-    }).insert(GeoFeature { id: 0 });
+    }).insert((GeoFeature { id: 0 }, FeatureLayer::Lakes));
+}
+
+/// Returns the points of a coarse grid, with at least `min_spacing` between
+/// them, that lie inside of the polygon, not too close to its boundary.
+fn get_interior_grid_points(polygon: &Vec<Vec2>, min_spacing: f32) -> Vec<Vec2> {
+    if polygon.len() < 3 {
+        return Vec::new();
+    }
+
+    let min = polygon.iter().fold(Vec2::INFINITY, |acc, point| acc.min(*point));
+    let max = polygon.iter().fold(Vec2::NEG_INFINITY, |acc, point| acc.max(*point));
+    let size = max - min;
+    let spacing = min_spacing.max(size.max_element() / LAKE_MAX_GRID_STEPS);
+
+    let mut points = Vec::new();
+    let mut x = min.x + spacing / 2.0;
+    while x < max.x {
+        let mut z = min.y + spacing / 2.0;
+        while z < max.y {
+            let point = Vec2::new(x, z);
+            // Points close to the shore would only create slivers
+            if point_in_polygon_check(polygon, point)
+                && distance_to_boundary(polygon, point) > spacing / 2.0
+            {
+                points.push(point);
+            }
+            z += spacing;
+        }
+        x += spacing;
+    }
+    points
+}
+
+/// Returns the shortest distance from `point` to any edge of the polygon,
+/// whether the point is inside or outside of it.
+///
+/// ```
+/// use bevy::math::Vec2;
+/// use city_visualizer::earth::lakes::distance_to_boundary;
+///
+/// let square = vec![Vec2::new(0.0, 0.0), Vec2::new(10.0, 0.0), Vec2::new(10.0, 10.0), Vec2::new(0.0, 10.0)];
+/// // the middle, nearer to one edge, on an edge and on a corner
+/// assert_eq!(distance_to_boundary(&square, Vec2::new(5.0, 5.0)), 5.0);
+/// assert_eq!(distance_to_boundary(&square, Vec2::new(2.0, 4.0)), 2.0);
+/// assert_eq!(distance_to_boundary(&square, Vec2::new(10.0, 3.0)), 0.0);
+/// assert_eq!(distance_to_boundary(&square, Vec2::new(10.0, 10.0)), 0.0);
+/// // outside, closest to a corner
+/// assert_eq!(distance_to_boundary(&square, Vec2::new(13.0, 14.0)), 5.0);
+/// // a repeated corner makes an edge of length 0, which does not get in the way
+/// let repeated = vec![square[0], square[1], square[1], square[2], square[3]];
+/// assert_eq!(distance_to_boundary(&repeated, Vec2::new(5.0, 1.0)), 1.0);
+/// ```
+pub fn distance_to_boundary(polygon: &Vec<Vec2>, point: Vec2) -> f32 {
+    let mut min_distance = f32::MAX;
+    for i in 0..polygon.len() {
+        let a = polygon[i];
+        let b = polygon[(i + 1) % polygon.len()];
+        let edge = b - a;
+        let t = if edge.length_squared() > 0.0 {
+            ((point - a).dot(edge) / edge.length_squared()).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        min_distance = min_distance.min(point.distance(a + edge * t));
+    }
+    min_distance
 }
 
 // Define or import the generate_terrain function here
@@ -57,10 +151,11 @@ pub fn update_lake(
     materials: &mut ResMut<Assets<StandardMaterial>>,
     node_locations: &HashMap<u64, GeoLocation>,
     lake_features: &HashMap<u64, LakeFeature>,
-    offset: &Offset
+    offset: &Offset,
+    scene_stats: &mut SceneStats,
 ) {
     for (_id, lake) in lake_features.iter() {
 
-        generate_lake(commands, meshes, materials, node_locations, lake, &offset);
+        generate_lake(commands, meshes, materials, node_locations, lake, &offset, scene_stats);
     }
 }
\ No newline at end of file
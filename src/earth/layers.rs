@@ -0,0 +1,205 @@
+//! Layers of features that can be shown or hidden, for example to only look
+//! at the road network of a city.
+
+use crate::commands::CommandRegistry;
+use crate::earth::{Tunnel, TunnelSettings};
+
+use bevy::prelude::*;
+
+/// Marks an entity as part of a layer of features.
+#[derive(Clone, Copy, Component, Debug, PartialEq, Eq, Hash)]
+pub enum FeatureLayer {
+    Buildings,
+    /// Roads, and the traffic lights and street lamps along them.
+    Roads,
+    Rivers,
+    Lakes,
+    /// Grass and other land use on the ground.
+    Terrain,
+    Trees,
+    Agents,
+}
+
+impl FeatureLayer {
+    pub const ALL: [FeatureLayer; 7] = [
+        FeatureLayer::Buildings,
+        FeatureLayer::Roads,
+        FeatureLayer::Rivers,
+        FeatureLayer::Lakes,
+        FeatureLayer::Terrain,
+        FeatureLayer::Trees,
+        FeatureLayer::Agents,
+    ];
+
+    /// Returns the name of the layer, as shown in the user interface.
+    pub fn name(self) -> &'static str {
+        match self {
+            FeatureLayer::Buildings => "Buildings",
+            FeatureLayer::Roads => "Roads",
+            FeatureLayer::Rivers => "Rivers",
+            FeatureLayer::Lakes => "Lakes",
+            FeatureLayer::Terrain => "Terrain",
+            FeatureLayer::Trees => "Trees",
+            FeatureLayer::Agents => "Agents",
+        }
+    }
+}
+
+/// Which layers of features are shown. Features of hidden layers are not
+/// spawned either, so data that is loaded while a layer is hidden does not
+/// show up in it until it is loaded again.
+///
+/// The layers are kept when the world is cleared, so they apply to the next
+/// load as well.
+///
+/// ```
+/// use city_visualizer::earth::layers::{FeatureLayer, LayerVisibility};
+///
+/// let mut layers = LayerVisibility::default();
+/// assert!(layers.is_visible(FeatureLayer::Buildings));
+/// *layers.get_mut(FeatureLayer::Buildings) = false;
+/// assert!(!layers.buildings);
+/// assert!(layers.is_visible(FeatureLayer::Roads));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Resource)]
+pub struct LayerVisibility {
+    pub buildings: bool,
+    pub roads: bool,
+    pub rivers: bool,
+    pub lakes: bool,
+    pub terrain: bool,
+    pub trees: bool,
+    pub agents: bool,
+}
+
+impl Default for LayerVisibility {
+    fn default() -> Self {
+        LayerVisibility {
+            buildings: true,
+            roads: true,
+            rivers: true,
+            lakes: true,
+            terrain: true,
+            trees: true,
+            agents: true,
+        }
+    }
+}
+
+impl LayerVisibility {
+    /// Returns whether the layer is shown.
+    pub fn is_visible(&self, layer: FeatureLayer) -> bool {
+        match layer {
+            FeatureLayer::Buildings => self.buildings,
+            FeatureLayer::Roads => self.roads,
+            FeatureLayer::Rivers => self.rivers,
+            FeatureLayer::Lakes => self.lakes,
+            FeatureLayer::Terrain => self.terrain,
+            FeatureLayer::Trees => self.trees,
+            FeatureLayer::Agents => self.agents,
+        }
+    }
+
+    /// Returns whether the layer is shown, to change it.
+    pub fn get_mut(&mut self, layer: FeatureLayer) -> &mut bool {
+        match layer {
+            FeatureLayer::Buildings => &mut self.buildings,
+            FeatureLayer::Roads => &mut self.roads,
+            FeatureLayer::Rivers => &mut self.rivers,
+            FeatureLayer::Lakes => &mut self.lakes,
+            FeatureLayer::Terrain => &mut self.terrain,
+            FeatureLayer::Trees => &mut self.trees,
+            FeatureLayer::Agents => &mut self.agents,
+        }
+    }
+}
+
+/// A system that registers the commands that show or hide layers.
+pub fn register_layer_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle buildings layer",
+        "Shows or hides the buildings",
+        None,
+        |commands| toggle_layer(commands, FeatureLayer::Buildings),
+    );
+    registry.register(
+        "Toggle roads layer",
+        "Shows or hides the roads, with their traffic lights and street lamps",
+        None,
+        |commands| toggle_layer(commands, FeatureLayer::Roads),
+    );
+    registry.register(
+        "Toggle rivers layer",
+        "Shows or hides the rivers",
+        None,
+        |commands| toggle_layer(commands, FeatureLayer::Rivers),
+    );
+    registry.register(
+        "Toggle lakes layer",
+        "Shows or hides the lakes",
+        None,
+        |commands| toggle_layer(commands, FeatureLayer::Lakes),
+    );
+    registry.register(
+        "Toggle terrain layer",
+        "Shows or hides the grass and other land use on the ground",
+        None,
+        |commands| toggle_layer(commands, FeatureLayer::Terrain),
+    );
+    registry.register(
+        "Toggle trees layer",
+        "Shows or hides the trees",
+        None,
+        |commands| toggle_layer(commands, FeatureLayer::Trees),
+    );
+    registry.register(
+        "Toggle agents layer",
+        "Shows or hides the agents",
+        None,
+        |commands| toggle_layer(commands, FeatureLayer::Agents),
+    );
+    registry.register(
+        "Toggle tunnels",
+        "Shows roads in tunnels below the ground",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut settings = world.resource_mut::<TunnelSettings>();
+                settings.visible = !settings.visible;
+            })
+        },
+    );
+}
+
+/// Shows the layer if it is hidden, and hides it otherwise.
+fn toggle_layer(commands: &mut Commands, layer: FeatureLayer) {
+    commands.add(move |world: &mut World| {
+        let mut layers = world.resource_mut::<LayerVisibility>();
+        let visible = layers.get_mut(layer);
+        *visible = !*visible;
+    });
+}
+
+/// A system that shows or hides the features of every layer, and tunnels,
+/// when `LayerVisibility` or `TunnelSettings` changes. Features that are
+/// spawned in a hidden layer anyway, such as traffic lights, are hidden as
+/// soon as they are added.
+pub fn update_layer_visibility(
+    layers: Res<LayerVisibility>,
+    tunnel_settings: Res<TunnelSettings>,
+    mut features: Query<(Ref<FeatureLayer>, &mut Visibility, Has<Tunnel>)>,
+) {
+    let changed = layers.is_changed() || tunnel_settings.is_changed();
+    for (layer, mut visibility, tunnel) in features.iter_mut() {
+        if !changed && !layer.is_added() {
+            continue;
+        }
+        *visibility = if !layers.is_visible(*layer) {
+            Visibility::Hidden
+        } else if tunnel {
+            tunnel_settings.visibility()
+        } else {
+            Visibility::Inherited
+        };
+    }
+}
@@ -1,5 +1,6 @@
 
 use bevy::math::{Mat3, Vec2, Vec3, Vec4Swizzles};
+use bevy::render::color::Color;
 use bevy::render::mesh::{Indices, Mesh, PrimitiveTopology, VertexAttributeValues};
 use bevy::render::render_asset::RenderAssetUsages;
 use bevy::transform::components::Transform;
@@ -9,36 +10,330 @@ use earcutr::earcut;
 
 use geo::{coord, CoordsIter, LineString, Polygon};
 
-use std::iter::repeat;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
 
 pub struct MeshBuilder {
     positions: Vec<Vec3>,
     normals: Vec<Vec3>,
     uvs: Vec<Vec2>,
+    /// Linear RGBA vertex colors, white unless set otherwise.
+    colors: Vec<[f32; 4]>,
+    /// Whether any vertex has a color other than white.
+    uses_colors: bool,
+    /// Tangents of the vertices, only known for vertices of meshes that were
+    /// added with tangents.
+    tangents: Vec<[f32; 4]>,
+    /// Whether any added mesh had tangents.
+    uses_tangents: bool,
+    /// Whether tangents are generated when the mesh is finished.
+    generate_tangents: bool,
     indices: Vec<u32>,
+    /// When vertices are deduplicated, the index of every added vertex by
+    /// its quantized position, normal and uv coordinates.
+    shared_vertices: Option<HashMap<[i32; 8], u32>>,
+}
+
+const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
+/// The tangent of vertices of which the tangent is not known.
+const NO_TANGENT: [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+
+/// Points of a polygon closer together than this are merged, and points
+/// that are less than this away from the line through their neighbours are
+/// dropped, in world units.
+const POLYGON_EPSILON: f32 = 1e-4;
+
+/// Why a polygon could not be added to a mesh.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidPolygon {
+    /// Fewer than three distinct, non-collinear points are left.
+    TooFewPoints,
+    /// The outline crosses or touches itself, like a bow-tie.
+    SelfIntersecting,
+    /// The polygon could not be triangulated.
+    Triangulation,
+}
+
+/// Returns the outline of a polygon without repeated points, such as a last
+/// point that closes the ring, and without points on a straight line between
+/// their neighbours. Fails if the outline crosses or touches itself, or if
+/// nothing of it is left.
+///
+/// Crossings are found by sweeping over the segments sorted by their lowest
+/// x coordinate, only comparing segments of which the x ranges overlap.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use city_visualizer::earth::mesh_builder::{clean_ring, InvalidPolygon};
+///
+/// // a closed ring with a repeated and a collinear point
+/// let ring = [
+///     Vec2::new(0.0, 0.0),
+///     Vec2::new(5.0, 0.0),
+///     Vec2::new(5.0, 0.0),
+///     Vec2::new(10.0, 0.0),
+///     Vec2::new(10.0, 10.0),
+///     Vec2::new(0.0, 10.0),
+///     Vec2::new(0.0, 0.0),
+/// ];
+/// assert_eq!(clean_ring(&ring).unwrap().len(), 4);
+///
+/// // a bow-tie, of which the sides cross in the middle
+/// let bow_tie = [
+///     Vec2::new(0.0, 0.0),
+///     Vec2::new(10.0, 10.0),
+///     Vec2::new(10.0, 0.0),
+///     Vec2::new(0.0, 10.0),
+/// ];
+/// assert_eq!(clean_ring(&bow_tie), Err(InvalidPolygon::SelfIntersecting));
+///
+/// let line = [Vec2::new(0.0, 0.0), Vec2::new(5.0, 0.0), Vec2::new(10.0, 0.0)];
+/// assert_eq!(clean_ring(&line), Err(InvalidPolygon::TooFewPoints));
+/// ```
+pub fn clean_ring(points: &[Vec2]) -> Result<Vec<Vec2>, InvalidPolygon> {
+    if points.iter().any(|point| !point.is_finite()) {
+        return Err(InvalidPolygon::TooFewPoints);
+    }
+
+    let mut ring: Vec<Vec2> = Vec::with_capacity(points.len());
+    for &point in points {
+        if ring.last().map_or(true, |last| last.distance(point) > POLYGON_EPSILON) {
+            ring.push(point);
+        }
+    }
+    while ring.len() > 1 && ring[0].distance(ring[ring.len() - 1]) <= POLYGON_EPSILON {
+        ring.pop();
+    }
+
+    // dropping a point can make its neighbour collinear, so repeat until
+    // nothing changes
+    loop {
+        let count = ring.len();
+        if count < 3 {
+            return Err(InvalidPolygon::TooFewPoints);
+        }
+        let kept: Vec<Vec2> = (0..count)
+            .filter(|&i| {
+                let previous = ring[(i + count - 1) % count];
+                let next = ring[(i + 1) % count];
+                let base = next - previous;
+                let length = base.length();
+                // also drops spikes, where the outline goes back the same way
+                length > POLYGON_EPSILON
+                    && base.perp_dot(ring[i] - previous).abs() / length > POLYGON_EPSILON
+            })
+            .map(|i| ring[i])
+            .collect();
+        if kept.len() == count {
+            break;
+        }
+        ring = kept;
+    }
+
+    if is_self_intersecting(&ring) {
+        return Err(InvalidPolygon::SelfIntersecting);
+    }
+    Ok(ring)
+}
+
+/// Returns whether any two segments of a closed ring that are not next to
+/// each other cross or touch.
+fn is_self_intersecting(ring: &[Vec2]) -> bool {
+    let count = ring.len();
+    let segment = |i: usize| (ring[i], ring[(i + 1) % count]);
+    let min_x = |i: usize| ring[i].x.min(ring[(i + 1) % count].x);
+    let max_x = |i: usize| ring[i].x.max(ring[(i + 1) % count].x);
+
+    let mut order: Vec<usize> = (0..count).collect();
+    order.sort_by(|&a, &b| min_x(a).total_cmp(&min_x(b)));
+
+    let mut active: Vec<usize> = Vec::new();
+    for i in order {
+        active.retain(|&j| max_x(j) >= min_x(i));
+        for &j in &active {
+            let adjacent = (i + 1) % count == j || (j + 1) % count == i;
+            if !adjacent && segments_intersect(segment(i), segment(j)) {
+                return true;
+            }
+        }
+        active.push(i);
+    }
+    false
+}
+
+/// Returns whether two segments cross or touch.
+fn segments_intersect((a, b): (Vec2, Vec2), (c, d): (Vec2, Vec2)) -> bool {
+    let side = |from: Vec2, to: Vec2, point: Vec2| (to - from).perp_dot(point - from);
+    let on_segment = |from: Vec2, to: Vec2, point: Vec2| {
+        point.x >= from.x.min(to.x) - POLYGON_EPSILON
+            && point.x <= from.x.max(to.x) + POLYGON_EPSILON
+            && point.y >= from.y.min(to.y) - POLYGON_EPSILON
+            && point.y <= from.y.max(to.y) + POLYGON_EPSILON
+    };
+
+    let (d1, d2) = (side(c, d, a), side(c, d, b));
+    let (d3, d4) = (side(a, b, c), side(a, b, d));
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0))
+        && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0))
+    {
+        return true;
+    }
+    // touching, or overlapping along the same line
+    (d1 == 0.0 && on_segment(c, d, a))
+        || (d2 == 0.0 && on_segment(c, d, b))
+        || (d3 == 0.0 && on_segment(a, b, c))
+        || (d4 == 0.0 && on_segment(a, b, d))
+}
+
+/// Vertices whose position, normal and uv coordinates are the same after
+/// rounding to a multiple of this are merged when deduplicating.
+const VERTEX_QUANTUM: f32 = 1e-4;
+
+/// Returns the key of a vertex for deduplication.
+fn vertex_key(position: Vec3, normal: Vec3, uv: Vec2) -> [i32; 8] {
+    [
+        position.x, position.y, position.z, normal.x, normal.y, normal.z, uv.x, uv.y,
+    ]
+    .map(|value| (value / VERTEX_QUANTUM).round() as i32)
+}
+
+/// How the faces of a prism are textured and colored.
+#[derive(Clone)]
+pub struct PrismStyle {
+    /// The cell of the texture atlas used for the walls.
+    pub wall_uv: (RangeInclusive<f32>, RangeInclusive<f32>),
+    /// The size of the wall in world units after which the texture repeats.
+    pub tile_size: Vec2,
+    /// The color the wall texture is multiplied with.
+    pub wall_color: Color,
+    /// The texture coordinate used for the roof.
+    pub roof_uv: Vec2,
+    /// The color the roof texture is multiplied with.
+    pub roof_color: Color,
 }
 
 impl MeshBuilder {
     /// Creates a new mesh builder with no vertices
     pub fn new() -> Self {
+        Self::with_capacity(0, 0)
+    }
+
+    /// Creates a new mesh builder with room for the given number of vertices
+    /// and indices, so building a mesh of about that size does not have to
+    /// grow its buffers over and over.
+    pub fn with_capacity(vertices: usize, indices: usize) -> Self {
         MeshBuilder {
-            positions: Vec::new(),
-            normals: Vec::new(),
-            uvs: Vec::new(),
-            indices: Vec::new(),
+            positions: Vec::with_capacity(vertices),
+            normals: Vec::with_capacity(vertices),
+            uvs: Vec::with_capacity(vertices),
+            colors: Vec::with_capacity(vertices),
+            uses_colors: false,
+            tangents: Vec::with_capacity(vertices),
+            uses_tangents: false,
+            generate_tangents: false,
+            indices: Vec::with_capacity(indices),
+            shared_vertices: None,
         }
     }
 
-    /// Adds a new vertex to the mesh and returns its index.
+    /// Makes the builder reuse an added vertex when a vertex with the same
+    /// position, normal and uv coordinates is added again, such as where the
+    /// quads of a road meet. Vertices of `add_mesh` are not deduplicated.
+    ///
+    /// Colors apply to every use of a vertex, so vertices are only shared
+    /// within one prism of `add_prism_from_path`, and callers that color
+    /// vertices should call `forget_shared_vertices` between differently
+    /// colored parts.
+    ///
+    /// ```
+    /// use bevy::prelude::*;
+    /// use city_visualizer::earth::mesh_builder::{MeshBuilder, PrismStyle};
+    ///
+    /// let style = PrismStyle {
+    ///     wall_uv: (0.0..=1.0, 0.0..=1.0),
+    ///     tile_size: Vec2::new(10.0, 10.0),
+    ///     wall_color: Color::WHITE,
+    ///     roof_uv: Vec2::ZERO,
+    ///     roof_color: Color::WHITE,
+    /// };
+    /// let square = vec![
+    ///     Vec2::new(0.0, 0.0),
+    ///     Vec2::new(5.0, 0.0),
+    ///     Vec2::new(5.0, 5.0),
+    ///     Vec2::new(0.0, 5.0),
+    /// ];
+    /// // two quads of a road, which meet at x = 5
+    /// let add_road = |builder: &mut MeshBuilder| {
+    ///     for x in [0.0, 5.0] {
+    ///         builder.add_quad(
+    ///             [
+    ///                 Vec3::new(x, 0.0, 1.0),
+    ///                 Vec3::new(x + 5.0, 0.0, 1.0),
+    ///                 Vec3::new(x + 5.0, 0.0, -1.0),
+    ///                 Vec3::new(x, 0.0, -1.0),
+    ///             ],
+    ///             [Vec2::ZERO; 4],
+    ///         );
+    ///     }
+    /// };
+    ///
+    /// let mut builder = MeshBuilder::new();
+    /// builder.add_prism_from_path(&square, 5.0, style.clone()).unwrap();
+    /// add_road(&mut builder);
+    /// let mut deduplicated = MeshBuilder::new().with_deduplication();
+    /// deduplicated.add_prism_from_path(&square, 5.0, style).unwrap();
+    /// add_road(&mut deduplicated);
+    ///
+    /// // walls keep their own corners, since their normals differ
+    /// assert_eq!(builder.into_mesh().count_vertices(), 4 + 4 * 4 + 2 * 4);
+    /// assert_eq!(deduplicated.into_mesh().count_vertices(), 4 + 4 * 4 + 6);
+    /// ```
+    pub fn with_deduplication(mut self) -> Self {
+        self.shared_vertices = Some(HashMap::new());
+        self
+    }
+
+    /// Makes the builder generate tangents for the finished meshes, which
+    /// materials with a normal map need to be lit correctly. Tangents of
+    /// added meshes are replaced by the generated ones.
+    pub fn with_tangents(mut self) -> Self {
+        self.generate_tangents = true;
+        self
+    }
+
+    /// Stops sharing the vertices that were added so far with vertices that
+    /// are added later, when deduplicating vertices.
+    pub fn forget_shared_vertices(&mut self) {
+        if let Some(shared_vertices) = &mut self.shared_vertices {
+            shared_vertices.clear();
+        }
+    }
+
+    /// Adds a new vertex to the mesh and returns its index. When
+    /// deduplicating, returns the index of the same vertex if it was already
+    /// added instead.
     pub fn add_vertex(
         &mut self,
         position: Vec3,
         normal: Vec3,
         uv: Vec2,
     ) -> u32 {
+        let next_index = self.positions.len() as u32;
+        if let Some(shared_vertices) = &mut self.shared_vertices {
+            let index = *shared_vertices
+                .entry(vertex_key(position, normal, uv))
+                .or_insert(next_index);
+            if index != next_index {
+                return index;
+            }
+        }
+
         self.positions.push(position);
         self.normals.push(normal);
         self.uvs.push(uv);
+        self.colors.push(WHITE);
+        self.tangents.push(NO_TANGENT);
 
         assert_eq!(self.normals.len(), self.positions.len());
         assert_eq!(self.uvs.len(), self.positions.len());
@@ -46,8 +341,34 @@ impl MeshBuilder {
         self.positions.len() as u32 - 1
     }
 
+    /// Sets the color of an already added vertex. The color is multiplied with
+    /// the color of the material.
+    pub fn set_vertex_color(&mut self, index: u32, color: Color) {
+        self.colors[index as usize] = color.as_linear_rgba_f32();
+        self.uses_colors = true;
+    }
+
+    /// Returns the number of vertices that were added so far, which is the
+    /// index of the next vertex that is added.
+    pub fn vertex_count(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// Sets the color of all vertices that were added since the vertex with
+    /// index `first`. White is the default, so it is not set explicitly.
+    pub fn set_vertex_colors_since(&mut self, first: usize, color: Color) {
+        if color == Color::WHITE {
+            return;
+        }
+        for index in first..self.positions.len() {
+            self.set_vertex_color(index as u32, color);
+        }
+    }
+
     /// Adds a quad to the mesh. `coords` should be in counterclockwise order
     /// of the quad, assuming a right handed system.
+    /// Both triangles of the quad face the same way as its normal. A quad
+    /// without area gets a zero normal rather than one that is not a number.
     pub fn add_quad(
         &mut self,
         positions: [Vec3; 4],
@@ -55,56 +376,147 @@ impl MeshBuilder {
     ) {
         let bottom_line = positions[1] - positions[0];
         let up_line = positions[2] - positions[1];
-        let normal = up_line.cross(bottom_line).normalize();
+        let normal = up_line.cross(bottom_line).normalize_or_zero();
         let a = self.add_vertex(positions[0], normal, uvs[0]);
         let b = self.add_vertex(positions[1], normal, uvs[1]);
         let c = self.add_vertex(positions[2], normal, uvs[2]);
         let d = self.add_vertex(positions[3], normal, uvs[3]);
 
         self.indices.extend([ c, b, a ]);
-        self.indices.extend([ a, c, d ]);
+        self.indices.extend([ d, c, a ]);
+    }
+
+    /// Adds a triangle to the mesh. `positions` should be in the same order as
+    /// the first three positions of a quad in `add_quad`.
+    pub fn add_triangle(
+        &mut self,
+        positions: [Vec3; 3],
+        uvs: [Vec2; 3],
+    ) {
+        let bottom_line = positions[1] - positions[0];
+        let up_line = positions[2] - positions[1];
+        let normal = up_line.cross(bottom_line).normalize_or_zero();
+        let a = self.add_vertex(positions[0], normal, uvs[0]);
+        let b = self.add_vertex(positions[1], normal, uvs[1]);
+        let c = self.add_vertex(positions[2], normal, uvs[2]);
+
+        self.indices.extend([ c, b, a ]);
     }
 
-    /// Adds the faces of a vertically-oriented prism to the mesh with the
-    /// given `polygon` as the base, and with `y1` as the bottom and `y2` as
-    /// the top.
+    /// Adds a flat, horizontal polygon at height `y` with an upward normal.
+    /// The outline is cleaned with `clean_ring` first, and nothing is added
+    /// if it is invalid.
     pub fn add_polygon_xz(
         &mut self,
         polygon: &Polygon,
         y: f32,
         uv: Vec2,
-    ) {
-        let coords_flat = polygon.exterior_coords_iter()
-            .flat_map(|coord| [coord.x, coord.y])
+    ) -> Result<(), InvalidPolygon> {
+        let outline = polygon.exterior_coords_iter()
+            .map(|coord| Vec2::new(coord.x as f32, coord.y as f32))
             .collect::<Vec<_>>();
-        let triangulation = earcut(&coords_flat, &[], 2).unwrap_throw();
-
-        let index_offset = self.positions.len();
-        let vertices = polygon.exterior().coords_count();
+        let outline = clean_ring(&outline)?;
+        let coords_flat = outline.iter()
+            .flat_map(|point| [point.x as f64, point.y as f64])
+            .collect::<Vec<_>>();
+        let triangulation = earcut(&coords_flat, &[], 2)
+            .map_err(|_| InvalidPolygon::Triangulation)?;
 
         // add vertices
-        self.positions.extend(
-            polygon.exterior_coords_iter()
-                .map(|coord| Vec3::new(coord.x as f32, y, coord.y as f32)),
+        let vertex_indices = outline.iter()
+            .map(|point| self.add_vertex(Vec3::new(point.x, y, point.y), Vec3::Y, uv))
+            .collect::<Vec<_>>();
+
+        // add bottom face indices
+        self.indices.extend(
+            triangulation.iter()
+                .map(|&index| vertex_indices[index]),
         );
-        self.normals.extend(repeat(Vec3::Y).take(vertices));
-        self.uvs.extend(repeat(uv).take(vertices));
+        Ok(())
+    }
 
-        assert_eq!(self.normals.len(), self.positions.len());
-        assert_eq!(self.uvs.len(), self.positions.len());
+    /// Same as `add_polygon_xz`, but also adds `points` inside the polygon as
+    /// extra (Steiner) vertices of the triangulation, and colors every vertex
+    /// with the result of `color`.
+    ///
+    /// The outline is not cleaned, since the points have to stay inside of
+    /// it, but nothing is added if it can not be triangulated.
+    ///
+    /// When deduplicating, the colored vertices are not shared with vertices
+    /// that were added before or are added after, which would otherwise take
+    /// on each other's colors.
+    ///
+    /// ```
+    /// use bevy::prelude::*;
+    /// use bevy::render::mesh::VertexAttributeValues;
+    /// use city_visualizer::earth::mesh_builder::MeshBuilder;
+    /// use geo::{LineString, Polygon};
+    ///
+    /// let square = Polygon::new(LineString::from(vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)]), vec![]);
+    /// let mut builder = MeshBuilder::new().with_deduplication();
+    /// builder.add_polygon_xz_with_points(&square, &[], 0.0, Vec2::ZERO, |_| Color::RED).unwrap();
+    /// builder.add_polygon_xz_with_points(&square, &[], 0.0, Vec2::ZERO, |_| Color::BLUE).unwrap();
+    /// let mesh = builder.into_mesh();
+    ///
+    /// // the closing corner of each square is shared, the squares are not
+    /// assert_eq!(mesh.count_vertices(), 2 * 4);
+    /// let Some(VertexAttributeValues::Float32x4(colors)) = mesh.attribute(Mesh::ATTRIBUTE_COLOR) else {
+    ///     panic!("the vertices are colored");
+    /// };
+    /// assert!(colors[..4].iter().all(|&color| color == Color::RED.as_linear_rgba_f32()));
+    /// assert!(colors[4..].iter().all(|&color| color == Color::BLUE.as_linear_rgba_f32()));
+    /// ```
+    ///
+    /// # Preconditions
+    /// All `points` should lie strictly inside of the polygon.
+    pub fn add_polygon_xz_with_points(
+        &mut self,
+        polygon: &Polygon,
+        points: &[Vec2],
+        y: f32,
+        uv: Vec2,
+        color: impl Fn(Vec2) -> Color,
+    ) -> Result<(), InvalidPolygon> {
+        let exterior_vertices = polygon.exterior().coords_count();
+
+        // earcut treats holes that consist of a single point as Steiner points
+        let coords_flat = polygon.exterior_coords_iter()
+            .flat_map(|coord| [coord.x, coord.y])
+            .chain(points.iter().flat_map(|point| [point.x as f64, point.y as f64]))
+            .collect::<Vec<_>>();
+        let hole_indices = (0..points.len())
+            .map(|i| exterior_vertices + i)
+            .collect::<Vec<_>>();
+        let triangulation = earcut(&coords_flat, &hole_indices, 2)
+            .map_err(|_| InvalidPolygon::Triangulation)?;
+
+        // vertices at the same place get the same color, so they may only be
+        // shared within this polygon
+        self.forget_shared_vertices();
+        let vertex_indices = polygon.exterior_coords_iter()
+            .map(|coord| Vec2::new(coord.x as f32, coord.y as f32))
+            .chain(points.iter().copied())
+            .map(|vertex| {
+                let index = self.add_vertex(Vec3::new(vertex.x, y, vertex.y), Vec3::Y, uv);
+                self.set_vertex_color(index, color(vertex));
+                index
+            })
+            .collect::<Vec<_>>();
+        self.forget_shared_vertices();
 
-        // add bottom face indices
         self.indices.extend(
             triangulation.iter()
-                .map(|&index| (index_offset + index) as u32),
+                .map(|&index| vertex_indices[index]),
         );
+        Ok(())
     }
 
     pub fn get_triangle_from_earcuttr(&self, polygon: &Polygon) -> Vec<[Vec3; 3]> {
         let coords_flat = polygon.exterior_coords_iter()
             .flat_map(|coord| [coord.x, coord.y])
             .collect::<Vec<_>>();
-        let triangulation = earcut(&coords_flat, &[], 2).unwrap_throw();
+        // polygons that can not be triangulated have no triangles
+        let triangulation = earcut(&coords_flat, &[], 2).unwrap_or_default();
 
         triangulation.chunks(3)
             .map(|chunk| [
@@ -140,16 +552,31 @@ impl MeshBuilder {
     
     /// Generates a Bevy mesh given the 2D path (of points) and extrude amount.
     /// `path_2d` is assumed to be in counter-clockwise order.
+    ///
+    /// The walls are textured with the cell `style.wall_uv` of a texture
+    /// atlas, which repeats every `tile_size.x` world units horizontally and
+    /// every `tile_size.y` world units vertically (starting at the floor).
+    /// Because a texture can not wrap within an atlas cell, walls are split
+    /// into pieces of at most one tile.
+    ///
+    /// The path is cleaned with `clean_ring` first, and nothing is added if
+    /// it is invalid.
     pub fn add_prism_from_path(
         &mut self,
         path_2d: &Vec<Vec2>,
         extrude_amount: f32,
-        uv: Vec2,
-    ) {
+        style: PrismStyle,
+    ) -> Result<(), InvalidPolygon> {
+        let path_2d = clean_ring(path_2d)?;
+
         // Floor and ceiling heights
         let y1 = 0.;
         let y2 = extrude_amount;
 
+        let tile_size = style.tile_size;
+        let (u_start, u_end) = (*style.wall_uv.0.start(), *style.wall_uv.0.end());
+        let (v_top, v_bottom) = (*style.wall_uv.1.start(), *style.wall_uv.1.end());
+
         let polygon = Polygon::new(
             LineString::new(
                 path_2d
@@ -160,18 +587,62 @@ impl MeshBuilder {
             Vec::new(),
         );
 
+        // Vertices are colored per prism, so they are not shared with other
+        // prisms
+        self.forget_shared_vertices();
+
         // Ceiling
-        self.add_polygon_xz(&polygon, y2, uv);
+        let roof_start = self.positions.len();
+        self.add_polygon_xz(&polygon, y2, style.roof_uv)?;
+        self.set_vertex_colors_since(roof_start, style.roof_color);
 
-        // For every line along the polygon base, add a face
+        // For every line along the polygon base, add the faces of the wall
+        let walls_start = self.positions.len();
         for line in polygon.exterior().lines() {
-            let corner1 = Vec3::new(line.end.x as f32, y1, line.end.y as f32);
-            let corner2 = Vec3::new(line.start.x as f32, y1, line.start.y as f32);
-            let corner3 = Vec3::new(line.start.x as f32, y2, line.start.y as f32);
-            let corner4 = Vec3::new(line.end.x as f32, y2, line.end.y as f32);
+            let start = Vec2::new(line.start.x as f32, line.start.y as f32);
+            let end = Vec2::new(line.end.x as f32, line.end.y as f32);
+            let length = start.distance(end);
+            if length <= 0.0 {
+                continue;
+            }
+            let direction = (end - start) / length;
+
+            let mut distance = 0.0;
+            while distance < length {
+                let piece_length = tile_size.x.min(length - distance);
+                let piece_start = start + direction * distance;
+                let piece_end = start + direction * (distance + piece_length);
+                let piece_u_end = u_start + (u_end - u_start) * piece_length / tile_size.x;
+
+                let mut bottom = y1;
+                while bottom < y2 {
+                    let piece_height = tile_size.y.min(y2 - bottom);
+                    let top = bottom + piece_height;
+                    let piece_v_top = v_bottom - (v_bottom - v_top) * piece_height / tile_size.y;
+
+                    let corner1 = Vec3::new(piece_end.x, bottom, piece_end.y);
+                    let corner2 = Vec3::new(piece_start.x, bottom, piece_start.y);
+                    let corner3 = Vec3::new(piece_start.x, top, piece_start.y);
+                    let corner4 = Vec3::new(piece_end.x, top, piece_end.y);
 
-            self.add_quad([corner1, corner4, corner3, corner2], [uv, uv, uv, uv]);
+                    self.add_quad(
+                        [corner1, corner4, corner3, corner2],
+                        [
+                            Vec2::new(piece_u_end, v_bottom),
+                            Vec2::new(piece_u_end, piece_v_top),
+                            Vec2::new(u_start, piece_v_top),
+                            Vec2::new(u_start, v_bottom),
+                        ],
+                    );
+
+                    bottom = top;
+                }
+
+                distance += piece_length;
+            }
         }
+        self.set_vertex_colors_since(walls_start, style.wall_color);
+        Ok(())
     }
 
     /// Adds an entire already-built mesh to the final mesh.
@@ -221,6 +692,29 @@ impl MeshBuilder {
             panic!("Expected (f32, f32) uv coordinates in the mesh");
         }
 
+        // colors are optional
+        let attribute = mesh.attribute(Mesh::ATTRIBUTE_COLOR);
+        if let Some(VertexAttributeValues::Float32x4(colors)) = attribute {
+            self.colors.extend(colors);
+            self.uses_colors = true;
+        } else {
+            self.colors.resize(self.positions.len(), WHITE);
+        }
+
+        // tangents are optional, they lie in the surface like positions do,
+        // and keep their handedness
+        let attribute = mesh.attribute(Mesh::ATTRIBUTE_TANGENT);
+        if let Some(VertexAttributeValues::Float32x4(tangents)) = attribute {
+            for &[x, y, z, w] in tangents {
+                let tangent = transform.rotation * (transform.scale * Vec3::new(x, y, z));
+                let [x, y, z] = tangent.normalize_or_zero().to_array();
+                self.tangents.push([x, y, z, w]);
+            }
+            self.uses_tangents = true;
+        } else {
+            self.tangents.resize(self.positions.len(), NO_TANGENT);
+        }
+
         assert_eq!(self.normals.len(), self.positions.len());
         assert_eq!(self.uvs.len(), self.positions.len());
 
@@ -239,8 +733,143 @@ impl MeshBuilder {
         mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, self.positions);
         mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals);
         mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, self.uvs);
+        if self.uses_colors {
+            mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, self.colors);
+        }
         mesh.insert_indices(Indices::U32(self.indices));
+        if self.generate_tangents {
+            generate_tangents(&mut mesh);
+        } else if self.uses_tangents {
+            mesh.insert_attribute(Mesh::ATTRIBUTE_TANGENT, self.tangents);
+        }
 
         mesh
     }
+
+    /// Turns the data into one or more bevy `Mesh`es with at most
+    /// `max_vertices` vertices each. Triangles are never split over meshes,
+    /// so vertices that are shared by triangles in different meshes are
+    /// duplicated.
+    pub fn into_meshes(self, max_vertices: usize) -> Vec<Mesh> {
+        assert!(max_vertices >= 3, "a mesh needs room for at least one triangle");
+        if self.positions.len() <= max_vertices {
+            return vec![self.into_mesh()];
+        }
+
+        let new_part = || MeshBuilder {
+            uses_colors: self.uses_colors,
+            uses_tangents: self.uses_tangents,
+            generate_tangents: self.generate_tangents,
+            ..MeshBuilder::new()
+        };
+        let mut meshes = Vec::new();
+        let mut part = new_part();
+        // the index of every vertex in the current part, if it is in there
+        let mut part_index: Vec<Option<u32>> = vec![None; self.positions.len()];
+        let mut part_vertices: Vec<usize> = Vec::new();
+
+        for triangle in self.indices.chunks_exact(3) {
+            let new_vertices = triangle
+                .iter()
+                .filter(|&&index| part_index[index as usize].is_none())
+                .count();
+            if part.positions.len() + new_vertices > max_vertices {
+                meshes.push(part.into_mesh());
+                part = new_part();
+                for vertex in part_vertices.drain(..) {
+                    part_index[vertex] = None;
+                }
+            }
+
+            for &index in triangle {
+                let vertex = index as usize;
+                let new_index = match part_index[vertex] {
+                    Some(new_index) => new_index,
+                    None => {
+                        let new_index = part.add_vertex(
+                            self.positions[vertex],
+                            self.normals[vertex],
+                            self.uvs[vertex],
+                        );
+                        part.colors[new_index as usize] = self.colors[vertex];
+                        part.tangents[new_index as usize] = self.tangents[vertex];
+                        part_index[vertex] = Some(new_index);
+                        part_vertices.push(vertex);
+                        new_index
+                    }
+                };
+                part.indices.push(new_index);
+            }
+        }
+
+        if !part.indices.is_empty() {
+            meshes.push(part.into_mesh());
+        }
+        meshes
+    }
+}
+
+/// Generates the tangents of a mesh with positions, normals, uv coordinates
+/// and indices. Where the uv coordinates do not tell the direction of the
+/// tangent, such as on a face with the same uv coordinates at every corner,
+/// any tangent in the surface is used, rather than a NaN or zero tangent.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use city_visualizer::earth::mesh_builder::MeshBuilder;
+///
+/// // a road ribbon, which has the same uv coordinates everywhere
+/// let mut builder = MeshBuilder::new().with_tangents();
+/// let uv = Vec2::new(0.5, 0.5);
+/// builder.add_quad(
+///     [
+///         Vec3::new(0.0, 0.0, 1.0),
+///         Vec3::new(10.0, 0.0, 1.0),
+///         Vec3::new(10.0, 0.0, -1.0),
+///         Vec3::new(0.0, 0.0, -1.0),
+///     ],
+///     [uv; 4],
+/// );
+/// let mesh = builder.into_mesh();
+///
+/// let Some(bevy::render::mesh::VertexAttributeValues::Float32x4(tangents)) =
+///     mesh.attribute(Mesh::ATTRIBUTE_TANGENT)
+/// else {
+///     panic!("expected tangents");
+/// };
+/// assert_eq!(tangents.len(), 4);
+/// for &[x, y, z, w] in tangents {
+///     let tangent = Vec3::new(x, y, z);
+///     assert!(tangent.is_finite() && w.is_finite());
+///     assert!((tangent.length() - 1.0).abs() < 1e-4);
+///     assert!(tangent.dot(Vec3::Y).abs() < 1e-4);
+/// }
+/// ```
+pub fn generate_tangents(mesh: &mut Mesh) {
+    let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+        Some(VertexAttributeValues::Float32x3(normals)) => normals.clone(),
+        _ => return,
+    };
+    // without a usable mapping, every tangent is replaced below
+    if mesh.generate_tangents().is_err() {
+        mesh.insert_attribute(Mesh::ATTRIBUTE_TANGENT, vec![[0.0f32; 4]; normals.len()]);
+    }
+
+    if let Some(VertexAttributeValues::Float32x4(tangents)) =
+        mesh.attribute_mut(Mesh::ATTRIBUTE_TANGENT)
+    {
+        for (tangent, normal) in tangents.iter_mut().zip(normals) {
+            let direction = Vec3::new(tangent[0], tangent[1], tangent[2]);
+            if direction.is_finite() && direction.length_squared() > 1e-12 && tangent[3].is_finite() {
+                continue;
+            }
+            let normal = Vec3::from_array(normal).normalize_or_zero();
+            let fallback = if normal == Vec3::ZERO {
+                Vec3::X
+            } else {
+                normal.any_orthonormal_vector()
+            };
+            *tangent = [fallback.x, fallback.y, fallback.z, 1.0];
+        }
+    }
 }
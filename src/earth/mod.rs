@@ -1,60 +1,147 @@
-use crate::common::{handle_compute_tasks, spawn_compute_task, AsyncComputation, StatusEvent};
+use crate::commands::CommandRegistry;
+use crate::common::{
+    handle_compute_tasks_limited, spawn_compute_task, AsyncComputation, CancelLoadingEvent,
+    PendingComputation, StatusEvent,
+};
 
-use crate::data::geography::{GeoData, GeoLocation, Offset};
+use crate::data::building_type::LightingClass;
+use crate::data::geography::{
+    Chunk, ChunkIndex, GeoData, GeoLocation, LoadedBounds, Offset, WorldScale,
+};
+use crate::data::loading::DataAttribution;
 use crate::data::traffic_graph::{update_traffic_graph, TrafficGraph};
-use crate::earth::agent::create_agents;
+use crate::earth::agent::{create_agents, AgentCommandEvent, AgentSeed, AgentSettings, AgentSpawner};
 use crate::earth::assets::AssetCache;
-use crate::earth::buildings::create_building_data;
+use crate::earth::buildings::{create_building_data, BuildingFootprints};
+use crate::earth::chunk_stats::{ChunkStatistics, ChunkStats};
+use crate::earth::day_night::{NightLighting, Sun};
+use crate::earth::district_stats::{compute_district_statistics, DistrictStats, DistrictStatsCreation};
+use crate::earth::generation_settings::GenerationSettings;
 use crate::earth::lakes::update_lake;
+use crate::earth::layers::{FeatureLayer, LayerVisibility};
+use crate::earth::mesh_builder::MeshBuilder;
+use crate::earth::overlay::OVERLAY_HEIGHT;
+use crate::earth::pipeline_timings::{time_stage, PipelineStage, PipelineTimings};
 use crate::earth::rivers::create_river_data;
-use crate::earth::roads::create_road_data;
+use crate::earth::roads::{create_road_data, create_stub_mesh, RoadStub, RoadStubs, BOUNDARY_MARGIN};
+use crate::earth::scene_stats::SceneStats;
 use crate::earth::terrain::create_terrain_data;
-use crate::lod::{DEFAULT_LOD_DISTANCE_SQUARED, DEFAULT_REMOVE_DISTANCE_SQUARED, LOD};
-use crate::player::Player;
+use crate::earth::street_lamps::add_street_lamps;
+use crate::earth::traffic_signals::{add_traffic_signals, TrafficSignals};
+use crate::earth::trees::{spawn_tree_chunk, TreeInstance};
+use crate::lod::{
+    LodMesh, CHUNK_LOD_DISTANCE_SQUARED, DEFAULT_LOD_DISTANCE_SQUARED, DEFAULT_REMOVE_DISTANCE_SQUARED, LOD,
+    SHADOW_REMOVE_DISTANCE_SQUARED,
+};
+use crate::player::PlayerTeleportEvent;
+use crate::ui::InputMode;
 use wasm_bindgen::prelude::*;
 
+use bevy::pbr::{NotShadowCaster, NotShadowReceiver};
 use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
 use noise::{NoiseFn, Perlin};
 
 use std::cmp::min;
+use std::collections::{HashSet, VecDeque};
 use std::f32::consts::PI;
 use std::sync::Arc;
 
-use self::agent::Agent;
+use self::agent::{Agent, AgentType};
 
 use std::cmp::Ordering;
 pub mod agent;
 pub mod assets;
 pub mod buildings;
+pub mod chunk_stats;
+pub mod day_night;
+pub mod district_stats;
+pub mod edge_usage;
+pub mod floating_origin;
+pub mod generation_settings;
+pub mod gltf_export;
 pub mod lakes;
+pub mod layers;
 pub mod mesh_builder;
+pub mod overlay;
+pub mod pipeline_timings;
 pub mod rivers;
 pub mod roads;
+pub mod route;
+pub mod scene_stats;
 pub mod simplification;
+pub mod street_lamps;
+pub mod telemetry;
 pub mod terrain;
+pub mod traffic_signals;
 pub mod trajectory;
+pub mod trees;
 
-pub const GLOBAL_SCALE_FACTOR: f32 = 100.0;
+/// How many times larger than they are roads and buildings are drawn, so
+/// that they can be seen from the height the city is usually viewed from.
+pub const SIZE_EXAGGERATION: f32 = 4.0;
 
-pub const CHANCE_COMPLEX_TREE: f64 = 0.0;
+/// The maximum number of vertices in a single building or road mesh. Larger
+/// meshes are split, so uploading them to the GPU is spread over frames.
+pub const MAX_MESH_PART_VERTICES: usize = 16_384;
+
+/// The maximum number of mesh parts that are spawned per frame.
+pub const MESH_PARTS_PER_FRAME: usize = 4;
+
+/// Whether generation is throttled while the user types a query. Only the
+/// single-threaded wasm build needs this, since generation there runs between
+/// frames and makes typing lag.
+const THROTTLE_WHILE_TYPING: bool = cfg!(target_arch = "wasm32");
+
+/// The maximum number of finished tasks of each kind that are handled per
+/// frame. Handling a result adds meshes and spawns entities, which is what
+/// makes frames slow while loading.
+const TASK_RESULTS_PER_FRAME: usize = 2;
+
+/// The maximum number of finished tasks of each kind that are handled per
+/// frame while generation is throttled.
+const THROTTLED_TASK_RESULTS_PER_FRAME: usize = 1;
+
+/// The maximum number of mesh parts that are spawned per frame while
+/// generation is throttled.
+const THROTTLED_MESH_PARTS_PER_FRAME: usize = 1;
+
+/// Returns whether generation should give priority to user input, in which
+/// case no new tasks are launched and fewer results are handled per frame.
+pub fn is_generation_throttled(input_mode: &InputMode) -> bool {
+    THROTTLE_WHILE_TYPING && *input_mode == InputMode::TextEntry
+}
+
+/// Returns how many finished tasks of each kind may be handled this frame.
+fn task_result_budget(input_mode: &InputMode) -> usize {
+    if is_generation_throttled(input_mode) {
+        THROTTLED_TASK_RESULTS_PER_FRAME
+    } else {
+        TASK_RESULTS_PER_FRAME
+    }
+}
 
 /// Sets up an empty earth.
 pub fn setup_earth(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
+    scale: Res<WorldScale>,
 ) {
     // light
     let rotation = Quat::from_rotation_x(-PI / 3.0);
-    commands.spawn(DirectionalLightBundle {
-        directional_light: DirectionalLight {
-            shadows_enabled: true,
-            illuminance: 10_000.0,
+    commands.spawn((
+        DirectionalLightBundle {
+            directional_light: DirectionalLight {
+                shadows_enabled: true,
+                illuminance: 10_000.0,
+                ..default()
+            },
+            transform: Transform::from_xyz(0.0, 10.0, 0.0).with_rotation(rotation),
             ..default()
         },
-        transform: Transform::from_xyz(0.0, 10.0, 0.0).with_rotation(rotation),
-        ..default()
-    });
+        Sun,
+    ));
 
     // add a tiny plane, just to have some sort of reference frame
     commands
@@ -62,7 +149,7 @@ pub fn setup_earth(
             mesh: meshes.add(
                 Plane3d::default()
                     .mesh()
-                    .size(BASE_PLANE_SIZE, BASE_PLANE_SIZE),
+                    .size(scale.units(BASE_PLANE_SIZE), scale.units(BASE_PLANE_SIZE)),
             ),
             material: materials.add(Color::WHITE),
             transform: Transform::from_translation(Vec3::new(0.0, 0.0, 0.0)),
@@ -71,121 +158,233 @@ pub fn setup_earth(
         .insert(GeoFeature { id: 0 });
 }
 
-const BASE_PLANE_SIZE: f32 = 5.0 * GLOBAL_SCALE_FACTOR;
+/// In world units at the default scale.
+const BASE_PLANE_SIZE: f32 = 500.0;
 
 /// An event that adds new geographic data to the world.
+/// 
+/// Other applications can send this event to add data that they created,
+/// e.g. with a `GeoDataBuilder`. Events that arrive in the same frame are
+/// added together, with one ground plane and one teleport of the player.
 #[derive(Debug, Event)]
 pub struct GeoDataEvent {
     pub data: Arc<GeoData>,
 }
 
-// Max distance is set to euclidian distance from Eindhoven to Izmir. Might be adjusted down if too many artifacts persist
-pub const MAX_DISTANCE: f64 = 0.083291353581523;
+impl GeoDataEvent {
+    pub fn new(data: GeoData) -> Self {
+        GeoDataEvent { data: Arc::new(data) }
+    }
+}
+
+/// Marks the plane underneath the data that was added in a frame.
+#[derive(Component)]
+pub struct GroundPlane;
+
+/// How far new data may be from the origin of the world, in meters, to be
+/// added to the world. Data further away replaces the world instead, since
+/// the projection of the world is only true near its reference latitude: at
+/// this distance, distances are off by up to 2% at 50 degrees north or south.
+pub const MAX_DISTANCE: f64 = 100_000.0;
 
 /// A system that updates the world when new data should be added. // TODO: how does this work with removals?
 pub fn update_earth(
     mut commands: Commands,
-    mut players: Query<(&Player, &mut Transform)>,
+    mut teleport_events: EventWriter<PlayerTeleportEvent>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut geo_data_events: EventReader<GeoDataEvent>,
     mut status_events: EventWriter<StatusEvent>,
     asset_cache: Res<AssetCache>,
     mut offset_resource: ResMut<Offset>,
+    mut loaded_bounds: ResMut<LoadedBounds>,
     geo_query: Query<(Entity, &GeoFeature)>,
     agent_query: Query<(Entity, &Agent)>,
     mut traffic_graph: ResMut<TrafficGraph>,
+    (agent_seed, agent_settings, mut agent_spawner, scale): (
+        Res<AgentSeed>,
+        Res<AgentSettings>,
+        ResMut<AgentSpawner>,
+        Res<WorldScale>,
+    ),
+    mut deferred_data: Local<Vec<Arc<GeoData>>>,
+    (mut footprints, mut mesh_parts, mut chunk_stats, mut traffic_signals, mut timings, mut loaded_features, mut generation_progress): (
+        ResMut<BuildingFootprints>,
+        ResMut<MeshPartQueue>,
+        ResMut<ChunkStats>,
+        ResMut<TrafficSignals>,
+        ResMut<PipelineTimings>,
+        ResMut<LoadedFeatures>,
+        ResMut<GenerationProgress>,
+    ),
+    (input_mode, layers, mut scene_stats, mut generation_queue, mut loaded_geo_data): (
+        Res<InputMode>,
+        Res<LayerVisibility>,
+        ResMut<SceneStats>,
+        ResMut<GenerationQueue>,
+        ResMut<LoadedGeoData>,
+    ),
 ) {
-    for event in geo_data_events.read() {
-        let old_traffic_graph_size = traffic_graph.get_size();
+    // While generation is throttled, new data is kept until the user is done
+    // typing, instead of launching its tasks right away
+    deferred_data.extend(geo_data_events.read().map(|event| Arc::clone(&event.data)));
+    if is_generation_throttled(&input_mode) {
+        return;
+    }
 
-        // Get the current offset
-        let mut offset = offset_resource.clone();
+    // All data that arrived this frame is added as a single load, so the
+    // offset is decided once and there is one ground plane and one teleport
+    let frame_data: Vec<Arc<GeoData>> = deferred_data.drain(..).collect();
+    if frame_data.is_empty() {
+        return;
+    }
 
-        // First compute center and bounds
-        let (_, avg, _) = find_bounds(&event.data);
-        let offset_candidate: Offset = Offset {
-            x: avg.project_no_scale().0,
-            y: avg.project_no_scale().1,
-        };
+    let mut old_traffic_graph_size = traffic_graph.get_size();
+    let mut live_agents = agent_query.iter().count();
 
-        // Calculate the Euclidean distance between the current offset and the candidate offset
-        let distance = ((offset_candidate.x - offset.x).powi(2)
-            + (offset_candidate.y - offset.y).powi(2))
-        .sqrt();
+    // Get the current offset
+    let mut offset = offset_resource.clone();
 
-        if distance > MAX_DISTANCE {
-            delete_all(&mut commands, &geo_query, &agent_query, &mut traffic_graph);
-            println!("Too far away, deleting old data"); // TODO possibly notify the user
+    // First compute center and bounds of all data together
+    let (bounds_min, avg, bounds_max) = find_bounds(&frame_data);
+    let offset_candidate = Offset::centered_on(&avg, *scale);
 
-            // Update offset
-            *offset_resource = offset_candidate;
-            offset = offset_candidate;
-        }
+    // The distance between the origin and the new data, which is infinite
+    // while no data is loaded
+    let distance = avg.project(&offset).length() as f64 * avg.meters_per_unit(&offset);
 
-        for (index, _) in &event.data.chunks {
-            // Update buildings, handle result in `update_building_generation_tasks`
-            let data = Arc::clone(&event.data);
-            let index_clone = index.clone(); // for borrow checking purposes
-            let asset_cache_ref = asset_cache.clone_weak();
-            spawn_compute_task(&mut commands, async move {
-                let chunk = data.chunks.get(&index_clone).unwrap_throw();
-                let mesh = create_building_data(
-                    &data.node_locations,
-                    &chunk.building_features,
-                    &chunk.land_use_features,
-                    &asset_cache_ref,
-                    &offset,
-                );
-                BuildingCreation(mesh)
-            });
+    if distance > MAX_DISTANCE {
+        delete_all(
+            &mut commands,
+            &geo_query,
+            &agent_query,
+            &mut traffic_graph,
+            &mut footprints,
+            &mut mesh_parts,
+            &mut chunk_stats,
+            &mut traffic_signals,
+            &mut loaded_features,
+            &mut scene_stats,
+            &mut generation_queue,
+            &mut loaded_geo_data,
+        );
+        println!("Too far away, deleting old data"); // TODO possibly notify the user
+        old_traffic_graph_size = 0;
+        live_agents = 0;
 
-            // Update roads, handle result in `update_road_generation_tasks`
-            let data = Arc::clone(&event.data);
-            let index_clone = index.clone();
-            let asset_cache_ref = asset_cache.clone_weak();
-            spawn_compute_task(&mut commands, async move {
-                let chunk = data.chunks.get(&index_clone).unwrap_throw();
-                let mesh = create_road_data(
-                    &data.node_locations,
-                    &chunk.road_features,
-                    &asset_cache_ref,
-                    &offset,
-                );
-                RoadCreation(mesh)
-            });
+        // Update offset
+        *offset_resource = offset_candidate;
+        offset = offset_candidate;
+        *loaded_bounds = LoadedBounds::default();
+    }
 
-            // Update traffic network graph
-            let data = Arc::clone(&event.data);
-            let index_clone = index.clone();
-            // spawn_compute_task(&mut commands, async move {
-            {
-                let chunk = data.chunks.get(&index_clone).unwrap_throw();
-                update_traffic_graph(
-                    &data.node_locations,
-                    &chunk.road_features,
-                    &mut traffic_graph,
-                    &offset,
-                );
+    // Keep track of the area covered by the loaded data
+    loaded_bounds.extend(bounds_min.project(&offset), bounds_max.project(&offset));
+    let bounds = *loaded_bounds;
+    // Roads that were cut off at the old edge may continue in the new data
+    traffic_graph.update_non_destinations(&bounds, scale.units(BOUNDARY_MARGIN));
+
+    // Chunks of which all features are in the world already are skipped, so
+    // loading the same data again does not duplicate it. Chunks are handled in
+    // a fixed order, so the traffic graph is built the same way every time the
+    // same data is loaded
+    let mut new_chunks: Vec<(&Arc<GeoData>, &ChunkIndex)> = Vec::new();
+    for geo_data in &frame_data {
+        let mut chunk_indices: Vec<&ChunkIndex> = geo_data.chunks.keys().collect();
+        chunk_indices.sort();
+        for index in chunk_indices {
+            let chunk = &geo_data.chunks[index];
+            if !loaded_features.contains_chunk(chunk) {
+                loaded_features.insert_chunk(chunk);
+                new_chunks.push((geo_data, index));
             }
-            // });
+        }
+    }
+    if new_chunks.is_empty() {
+        status_events.send(StatusEvent::Update("This data is loaded already".to_owned()));
+        status_events.send(StatusEvent::progress_finished());
+        return;
+    }
 
-            // Update rivers
-            let data = Arc::clone(&event.data);
-            let index_clone = index.clone();
-            let asset_cache_ref = asset_cache.clone_weak();
+    if !timings.is_loading() {
+        scene_stats.start_load();
+    }
+    timings.start_load();
+    generation_progress.chunks.extend(new_chunks.iter().map(|(_, index)| (*index).clone()));
+    status_events.send(StatusEvent::Progress {
+        label: format!("Generating chunks (0/{})", generation_progress.chunks.len()),
+        fraction: Some(0.0),
+    });
+    for geo_data in &frame_data {
+        timings.record(PipelineStage::Parse, geo_data.report.parse_time);
+        scene_stats.add_report(&geo_data.report);
+    }
+
+    // Compute statistics per district, handle result in
+    // `update_district_stats_tasks`
+    for geo_data in &frame_data {
+        if !geo_data.districts.is_empty() {
+            let data = Arc::clone(geo_data);
             spawn_compute_task(&mut commands, async move {
-                let chunk = data.chunks.get(&index_clone).unwrap_throw();
-                let mesh = create_river_data(
-                    &data.node_locations,
-                    &chunk.river_features,
-                    &asset_cache_ref,
-                    &offset,
-                );
-                RiverCreation(mesh)
+                DistrictStatsCreation(compute_district_statistics(&data, &offset), offset)
             });
+        }
+    }
+
+    for &(geo_data, index) in &new_chunks {
+        chunk_stats.chunks.insert(index.clone(), ChunkStatistics::new(&geo_data.chunks[index]));
+
+        // Buildings, roads, rivers and terrain are generated in tasks, of
+        // which only a few chunks are started at a time
+        generation_queue.push(QueuedChunk {
+            data: Arc::clone(geo_data),
+            index: index.clone(),
+            offset,
+            bounds,
+            tasks: ChunkTasks::ALL,
+        });
+        loaded_geo_data.chunks.push((Arc::clone(geo_data), index.clone()));
+
+        // Update traffic network graph
+        let data = Arc::clone(geo_data);
+        let index_clone = index.clone();
+        // spawn_compute_task(&mut commands, async move {
+        time_stage(&mut timings, PipelineStage::Graph, || {
+            let chunk = data.chunks.get(&index_clone).unwrap_throw();
+            update_traffic_graph(
+                &data.node_locations,
+                &chunk.road_features,
+                &index_clone,
+                &mut traffic_graph,
+                &offset,
+                &bounds,
+            );
+        });
+        // });
+
+        // Add traffic lights at signalled junctions
+        add_traffic_signals(
+            &mut commands,
+            &geo_data.chunks[index],
+            &geo_data.node_locations,
+            &offset,
+            &asset_cache,
+            &mut traffic_signals,
+        );
 
-            let data = Arc::clone(&event.data);
+        // Add street lamps
+        if layers.roads {
+            add_street_lamps(
+                &mut commands,
+                &geo_data.chunks[index],
+                &geo_data.node_locations,
+                &offset,
+                &asset_cache,
+            );
+        }
+
+        if layers.lakes {
+            let data = Arc::clone(geo_data);
             let index_clone = index.clone();
             let chunk = data.chunks.get(&index_clone).unwrap_throw();
             update_lake(
@@ -195,76 +394,433 @@ pub fn update_earth(
                 &data.node_locations,
                 &chunk.lake_features,
                 &offset,
+                &mut scene_stats,
             );
+        }
+    }
 
-            // Update terrain, handle result in `update_terrain_generation_tasks`
-            let data = Arc::clone(&event.data);
-            let index_clone = index.clone();
-            spawn_compute_task(&mut commands, async move {
-                let chunk = data.chunks.get(&index_clone).unwrap_throw();
-                let (tree_transforms, grass_areas) =
-                    create_terrain_data(&data.node_locations, &chunk.land_use_features, &offset);
-                TerrainCreation(tree_transforms, grass_areas)
-            });
+    // Add a plane underneath, covering all data of this frame
+    let min = bounds_min.project(&offset);
+    let max = bounds_max.project(&offset);
+    let x_size = (max.x - min.x).abs();
+    let z_size = (max.y - min.y).abs();
+    let mid_x = (min.x + max.x) / 2.0;
+    let mid_z = (min.y + max.y) / 2.0;
+    commands
+        .spawn(PbrBundle {
+            mesh: meshes.add(Plane3d::default().mesh().size(x_size, z_size)),
+            material: materials.add(Color::WHITE),
+            transform: Transform::from_translation(Vec3::new(mid_x, -scale.units(0.1), mid_z)), // A bit below the ground, since we have rounding errors
+            ..default()
+        })
+        .insert((GeoFeature { id: 0 }, GroundPlane));
+
+    status_events.send(StatusEvent::Update(format!(
+        "Successfully added data, teleporting player to {:.5}, {:.5}",
+        avg.latitude, avg.longitude
+    )));
+
+    // Teleport player to average of nodes
+    let new_position = avg.project(&offset);
+    teleport_events.send(PlayerTeleportEvent { position: new_position });
+
+    if !agent_settings.enabled || !layers.agents {
+        return;
+    }
+
+    // Spawn agents tasks async in batches, only once the graph is complete
+    // for this frame, so that batches are spawned in a fixed order. One agent
+    // is added per 100 new nodes, up to the target number of agents.
+    let new_nodes = traffic_graph.get_size().saturating_sub(old_traffic_graph_size);
+    let expected_agents = live_agents + agent_spawner.pending();
+    let count = (new_nodes / 100).min(agent_settings.target_agents.saturating_sub(expected_agents));
+    if count > 0 {
+        // This is not a great way to do it, but the graph needs to stay
+        // mutable for next iteration while also having the data available
+        // for the agents
+        let graph_arc = Arc::new((*traffic_graph).clone());
+        spawn_agent_batches(
+            &mut commands,
+            graph_arc,
+            count,
+            &agent_seed,
+            &agent_settings,
+            &mut agent_spawner,
+            &scale,
+        );
+    }
+}
+
+/// The number of agents created by a single agent creation task.
+const AGENT_BATCH_SIZE: usize = 100;
+
+/// The maximum number of agents that `reconcile_agent_count` starts creating
+/// per frame.
+const MAX_RECONCILE_SPAWNS_PER_FRAME: usize = 500;
+
+/// Starts tasks that create `count` agents on the given graph, in batches of
+/// `AGENT_BATCH_SIZE`. Every batch gets its own random number generator,
+/// derived from the seed and the index of the batch.
+fn spawn_agent_batches(
+    commands: &mut Commands,
+    graph: Arc<TrafficGraph>,
+    count: usize,
+    agent_seed: &AgentSeed,
+    agent_settings: &AgentSettings,
+    agent_spawner: &mut AgentSpawner,
+    scale: &WorldScale,
+) {
+    let settings = *agent_settings;
+    let scale = *scale;
+    let mut spawns_left = count;
+    while spawns_left > 0 {
+        let graph = Arc::clone(&graph);
+        let spawns = min(AGENT_BATCH_SIZE, spawns_left);
+        let seed = agent_spawner.next_batch_seed(agent_seed);
+        agent_spawner.add_pending(spawns);
+        spawn_compute_task(commands, async move {
+            let generation = graph.get_generation();
+            let agents = create_agents(spawns as i32, graph, seed, settings, scale);
+
+            AgentCreation(agents, generation, spawns)
+        });
+        spawns_left -= spawns;
+    }
+}
+
+/// A system that handles `AgentCommandEvent`s, and moves the number of
+/// agents towards the target by creating or removing agents.
+pub fn reconcile_agent_count(
+    mut commands: Commands,
+    mut agent_command_events: EventReader<AgentCommandEvent>,
+    mut agent_settings: ResMut<AgentSettings>,
+    agent_seed: Res<AgentSeed>,
+    mut agent_spawner: ResMut<AgentSpawner>,
+    agent_query: Query<(Entity, &Agent)>,
+    traffic_graph: Res<TrafficGraph>,
+    input_mode: Res<InputMode>,
+    scale: Res<WorldScale>,
+    layers: Res<LayerVisibility>,
+) {
+    let mut despawned_all = false;
+    for event in agent_command_events.read() {
+        match *event {
+            AgentCommandEvent::SetTarget(target) => agent_settings.target_agents = target,
+            AgentCommandEvent::Spawn(count) => agent_settings.target_agents += count,
+            AgentCommandEvent::DespawnAll => {
+                agent_settings.target_agents = 0;
+                delete_agents(&mut commands, &agent_query);
+                despawned_all = true;
+            }
+            AgentCommandEvent::SetMix(mix) => agent_settings.mix = mix,
+            AgentCommandEvent::SetMaxRouteDistance(distance) => {
+                agent_settings.max_route_distance = distance
+            }
+        }
+    }
+    if despawned_all {
+        return;
+    }
+
+    // Remove the agents that are too many
+    let live_agents = agent_query.iter().count();
+    let target = agent_settings.target_agents;
+    if live_agents > target {
+        for (entity, _) in agent_query.iter().take(live_agents - target) {
+            commands.entity(entity).despawn_recursive();
         }
+        return;
+    }
+
+    if !agent_settings.enabled
+        || !layers.agents
+        || traffic_graph.get_size() == 0
+        || is_generation_throttled(&input_mode)
+    {
+        return;
+    }
+    // Batches that create fewer agents than requested are only made up for
+    // once all batches are done, so the size of every batch, and with that
+    // the agents of a seed, does not depend on when batches finish
+    if agent_spawner.pending() > 0 {
+        return;
+    }
+    let missing = target.saturating_sub(live_agents);
+    if missing > 0 {
+        spawn_agent_batches(
+            &mut commands,
+            Arc::new((*traffic_graph).clone()),
+            missing.min(MAX_RECONCILE_SPAWNS_PER_FRAME),
+            &agent_seed,
+            &agent_settings,
+            &mut agent_spawner,
+            &scale,
+        );
+    }
+}
 
-        // Print size of traffic graph
-        println!("Updated traffic graph size: {}", traffic_graph.get_size());
+/// An event that removes all geographic data from the world.
+#[derive(Debug, Event)]
+pub struct ClearWorldEvent;
 
-        // Spawn agents tasks async in batches of 100
-        let graph_arc = Arc::new((*traffic_graph).clone()); // This is not a great way to do it, but the graph
-                                                            // needs to stay mutable for next iteration while also having the data available for the agents
-        let diff = if old_traffic_graph_size > traffic_graph.get_size() {
-            0 // or some other default value
-        } else {
-            (traffic_graph.get_size() - old_traffic_graph_size) / 100
+/// The data of every chunk in the world, kept so that its meshes can be
+/// generated again without downloading and parsing the data again.
+#[derive(Clone, Default, Resource)]
+pub struct LoadedGeoData {
+    chunks: Vec<(Arc<GeoData>, ChunkIndex)>,
+}
+
+impl LoadedGeoData {
+    /// Returns the number of chunks of which the data is kept.
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Forgets the data, when the world is cleared.
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+    }
+
+    /// Merges the kept chunks into a single `GeoData`, with the node
+    /// locations and districts of all loads. Features of chunks that were
+    /// loaded more than once are merged too. The parse report is empty, since
+    /// it described the original loads.
+    pub fn to_geo_data(&self) -> GeoData {
+        let mut merged = GeoData {
+            node_locations: Default::default(),
+            chunks: Default::default(),
+            districts: Default::default(),
+            snapshot_timestamp: None,
+            cache_age: None,
+            report: Default::default(),
         };
-        // Only add diff if positive, else add 0
-        let mut agent_spawns_left = diff.max(0) as i32;
+        let mut merged_loads: Vec<&Arc<GeoData>> = Vec::new();
+        for (data, index) in &self.chunks {
+            let chunk = merged.chunks.entry(index.clone()).or_default();
+            let source = &data.chunks[index];
+            chunk.nodes.extend(source.nodes.iter().map(|(&id, node)| (id, node.clone())));
+            chunk.building_features.extend(source.building_features.iter().map(|(&id, feature)| (id, feature.clone())));
+            chunk.road_features.extend(source.road_features.iter().map(|(&id, feature)| (id, feature.clone())));
+            chunk.land_use_features.extend(source.land_use_features.iter().map(|(&id, feature)| (id, feature.clone())));
+            chunk.lake_features.extend(source.lake_features.iter().map(|(&id, feature)| (id, feature.clone())));
+            chunk.river_features.extend(source.river_features.iter().map(|(&id, feature)| (id, feature.clone())));
 
-        while agent_spawns_left > 0 {
-            let graph = graph_arc.clone();
-            let spawns = min(100, agent_spawns_left);
-            spawn_compute_task(&mut commands, async move {
-                let agents = create_agents(spawns, graph);
+            // the locations of a load are shared by all of its chunks
+            if merged_loads.iter().any(|load| Arc::ptr_eq(load, data)) {
+                continue;
+            }
+            merged_loads.push(data);
+            merged.node_locations.extend(data.node_locations.iter().map(|(&id, location)| (id, location.clone())));
+            merged.districts.extend(data.districts.iter().map(|(&id, district)| (id, district.clone())));
+            // the latest load is the newest data, usually
+            if data.snapshot_timestamp.is_some() {
+                merged.snapshot_timestamp = data.snapshot_timestamp.clone();
+            }
+        }
+        merged
+    }
+}
 
-                AgentCreation(agents)
-            });
-            agent_spawns_left -= 100;
-        }
-
-        // Add a plane underneath
-        let (min, avg, max) = find_bounds(&event.data);
-        let min = min.project(&offset);
-        let max = max.project(&offset);
-        let x_size = (max.x - min.x).abs();
-        let z_size = (max.y - min.y).abs();
-        let mid_x = (min.x + max.x) / 2.0;
-        let mid_z = (min.y + max.y) / 2.0;
-        commands
-            .spawn(PbrBundle {
-                mesh: meshes.add(Plane3d::default().mesh().size(x_size, z_size)),
-                material: materials.add(Color::WHITE),
-                transform: Transform::from_translation(Vec3::new(mid_x, -0.1, mid_z)), // A bit below the ground, since we have rounding errors
-                ..default()
-            })
-            .insert(GeoFeature { id: 0 });
+/// An event that generates the meshes of the given layers of every chunk in
+/// the world again, from the data in `LoadedGeoData`. Useful after changing
+/// a parameter of generation. Agents are not generated again, and since
+/// grass and trees are generated together, asking for one of them generates
+/// both.
+#[derive(Debug, Event)]
+pub struct RegenerateEvent {
+    pub layers: Vec<FeatureLayer>,
+}
+
+impl RegenerateEvent {
+    /// Returns an event that generates all shown layers again.
+    pub fn visible(layers: &LayerVisibility) -> Self {
+        RegenerateEvent {
+            layers: FeatureLayer::ALL
+                .into_iter()
+                .filter(|&layer| layers.is_visible(layer))
+                .collect(),
+        }
+    }
+}
 
+/// A system that removes the generated meshes of the layers in
+/// `RegenerateEvent`s and queues their generation again. Street lamps and
+/// traffic lights are kept, since they do not depend on generation.
+///
+/// Results of tasks that are still running would be added twice, so nothing
+/// is generated again until the current load is done.
+pub fn regenerate_world(
+    mut commands: Commands,
+    mut regenerate_events: EventReader<RegenerateEvent>,
+    mut status_events: EventWriter<StatusEvent>,
+    loaded_geo_data: Res<LoadedGeoData>,
+    features: Query<(Entity, &GeoFeature, &FeatureLayer)>,
+    tasks: Query<
+        (),
+        Or<(
+            With<AsyncComputation<BuildingCreation>>,
+            With<AsyncComputation<RoadCreation>>,
+            With<AsyncComputation<RiverCreation>>,
+            With<AsyncComputation<TerrainCreation>>,
+        )>,
+    >,
+    (mut generation_queue, mesh_parts): (ResMut<GenerationQueue>, Res<MeshPartQueue>),
+    (offset, bounds, layers): (Res<Offset>, Res<LoadedBounds>, Res<LayerVisibility>),
+    (mut meshes, mut materials): (ResMut<Assets<Mesh>>, ResMut<Assets<StandardMaterial>>),
+    mut scene_stats: ResMut<SceneStats>,
+    mut timings: ResMut<PipelineTimings>,
+) {
+    let mut requested: HashSet<FeatureLayer> = regenerate_events
+        .read()
+        .flat_map(|event| event.layers.iter().copied())
+        .filter(|&layer| layer != FeatureLayer::Agents)
+        .collect();
+    if requested.is_empty() {
+        return;
+    }
+    if loaded_geo_data.chunks.is_empty() {
+        status_events.send(StatusEvent::Update("There is no data to regenerate".to_owned()));
+        return;
+    }
+    if !tasks.is_empty() || !generation_queue.is_empty() || !mesh_parts.is_empty() {
         status_events.send(StatusEvent::Update(
-            "Successfully added data, teleporting player".to_owned(),
+            "Wait until the world is generated before regenerating it".to_owned(),
         ));
+        return;
+    }
 
-        // Teleport player to average of nodes
-        let new_position = avg.project(&offset);
-        eprintln!("in the world that's {:?}", new_position);
-        for (_, mut transform) in &mut players {
-            transform.translation.x = new_position.x;
-            transform.translation.z = new_position.y;
-            if transform.translation.y <= 0.0 {
-                transform.translation.y = 5.0;
-            }
+    // grass and trees come from the same task
+    if requested.contains(&FeatureLayer::Terrain) || requested.contains(&FeatureLayer::Trees) {
+        requested.extend([FeatureLayer::Terrain, FeatureLayer::Trees]);
+    }
+    for (entity, feature, layer) in &features {
+        if feature.is_chunk_mesh() && requested.contains(layer) {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+    for &layer in &requested {
+        scene_stats.clear_layer(layer);
+    }
+    if requested.contains(&FeatureLayer::Buildings) {
+        scene_stats.invalid_footprints = 0;
+    }
+
+    timings.start_load();
+    let chunk_tasks = ChunkTasks::for_layers(&requested);
+    for (data, index) in &loaded_geo_data.chunks {
+        if chunk_tasks.count() > 0 {
+            generation_queue.push(QueuedChunk {
+                data: Arc::clone(data),
+                index: index.clone(),
+                offset: *offset,
+                bounds: *bounds,
+                tasks: chunk_tasks,
+            });
+        }
+        if requested.contains(&FeatureLayer::Lakes) && layers.lakes {
+            update_lake(
+                &mut commands,
+                &mut meshes,
+                &mut materials,
+                &data.node_locations,
+                &data.chunks[index].lake_features,
+                &offset,
+                &mut scene_stats,
+            );
         }
     }
+
+    let names: Vec<&str> = FeatureLayer::ALL
+        .into_iter()
+        .filter(|layer| requested.contains(layer))
+        .map(FeatureLayer::name)
+        .collect();
+    status_events.send(StatusEvent::Update(format!(
+        "Regenerating {} of {} chunks",
+        names.join(", ").to_lowercase(),
+        loaded_geo_data.chunks.len(),
+    )));
+}
+
+/// A system that registers the commands related to the world.
+pub fn register_earth_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Clear world",
+        "Removes all loaded geographic data and agents",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                world.send_event(ClearWorldEvent);
+            })
+        },
+    );
+    registry.register(
+        "Regenerate world",
+        "Generates the meshes of the shown layers again from the loaded data",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                let layers = *world.resource::<LayerVisibility>();
+                world.send_event(RegenerateEvent::visible(&layers));
+            })
+        },
+    );
+    registry.register(
+        "Toggle simulation pause",
+        "Pauses or resumes the agents and traffic lights",
+        Some(KeyCode::F2),
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut simulation = world.resource_mut::<SimulationSettings>();
+                simulation.paused = !simulation.paused;
+            })
+        },
+    );
+}
+
+/// A system that removes everything from the world when requested.
+pub fn clear_world(
+    mut commands: Commands,
+    mut clear_events: EventReader<ClearWorldEvent>,
+    mut status_events: EventWriter<StatusEvent>,
+    mut offset_resource: ResMut<Offset>,
+    mut loaded_bounds: ResMut<LoadedBounds>,
+    mut attribution: ResMut<DataAttribution>,
+    geo_query: Query<(Entity, &GeoFeature)>,
+    agent_query: Query<(Entity, &Agent)>,
+    mut traffic_graph: ResMut<TrafficGraph>,
+    mut footprints: ResMut<BuildingFootprints>,
+    mut mesh_parts: ResMut<MeshPartQueue>,
+    mut chunk_stats: ResMut<ChunkStats>,
+    mut traffic_signals: ResMut<TrafficSignals>,
+    mut loaded_features: ResMut<LoadedFeatures>,
+    mut scene_stats: ResMut<SceneStats>,
+    (mut generation_queue, mut loaded_geo_data): (ResMut<GenerationQueue>, ResMut<LoadedGeoData>),
+) {
+    if clear_events.read().count() == 0 {
+        return;
+    }
+    *attribution = DataAttribution::default();
+
+    delete_all(
+        &mut commands,
+        &geo_query,
+        &agent_query,
+        &mut traffic_graph,
+        &mut footprints,
+        &mut mesh_parts,
+        &mut chunk_stats,
+        &mut traffic_signals,
+        &mut loaded_features,
+        &mut scene_stats,
+        &mut generation_queue,
+        &mut loaded_geo_data,
+    );
+    // the next data that is loaded determines the new offset
+    *offset_resource = Offset::default();
+    *loaded_bounds = LoadedBounds::default();
+
+    status_events.send(StatusEvent::Update("Cleared the world".to_owned()));
 }
 
 fn delete_all(
@@ -272,10 +828,26 @@ fn delete_all(
     geo_query: &Query<(Entity, &GeoFeature)>,
     agent_query: &Query<(Entity, &Agent)>,
     traffic_graph: &mut ResMut<TrafficGraph>,
+    footprints: &mut ResMut<BuildingFootprints>,
+    mesh_parts: &mut ResMut<MeshPartQueue>,
+    chunk_stats: &mut ResMut<ChunkStats>,
+    traffic_signals: &mut ResMut<TrafficSignals>,
+    loaded_features: &mut ResMut<LoadedFeatures>,
+    scene_stats: &mut ResMut<SceneStats>,
+    generation_queue: &mut ResMut<GenerationQueue>,
+    loaded_geo_data: &mut ResMut<LoadedGeoData>,
 ) {
     delete_geo_features(commands, geo_query);
     delete_agents(commands, agent_query);
     traffic_graph.reset();
+    footprints.clear();
+    mesh_parts.clear();
+    chunk_stats.clear();
+    traffic_signals.clear();
+    loaded_features.clear();
+    scene_stats.clear();
+    generation_queue.clear();
+    loaded_geo_data.clear();
 }
 
 fn delete_geo_features(commands: &mut Commands, query: &Query<(Entity, &GeoFeature)>) {
@@ -290,8 +862,26 @@ fn delete_agents(commands: &mut Commands, query: &Query<(Entity, &Agent)>) {
     }
 }
 
-/// Returns (-lat-lon corner, the median location, +lat+lon corner).
-fn find_bounds(data: &GeoData) -> (GeoLocation, GeoLocation, GeoLocation) {
+/// Returns (-lat-lon corner, the median location, +lat+lon corner) of the
+/// nodes of all given data together.
+///
+/// ```
+/// use std::sync::Arc;
+/// use city_visualizer::data::geography::GeoDataBuilder;
+/// use city_visualizer::earth::find_bounds;
+///
+/// // two loads that arrive in the same frame are framed as one area
+/// let mut west = GeoDataBuilder::new();
+/// west.add_node(1, 51.0, 5.0);
+/// let mut east = GeoDataBuilder::new();
+/// east.add_node(2, 52.0, 6.0).add_node(3, 53.0, 7.0);
+///
+/// let (min, median, max) = find_bounds(&[Arc::new(west.build()), Arc::new(east.build())]);
+/// assert_eq!((min.longitude, min.latitude), (5.0, 51.0));
+/// assert_eq!((median.longitude, median.latitude), (6.0, 52.0));
+/// assert_eq!((max.longitude, max.latitude), (7.0, 53.0));
+/// ```
+pub fn find_bounds(data: &[Arc<GeoData>]) -> (GeoLocation, GeoLocation, GeoLocation) {
     // Initialize min and max values
     let mut min_lat = f64::MAX;
     let mut max_lat = f64::MIN;
@@ -302,7 +892,7 @@ fn find_bounds(data: &GeoData) -> (GeoLocation, GeoLocation, GeoLocation) {
     let mut loc_sums: Vec<(&GeoLocation, f64)> = Vec::new();
 
     // Populate the min, max values and the vector with locations and their sums
-    for (_, location) in &data.node_locations {
+    for (_, location) in data.iter().flat_map(|data| &data.node_locations) {
         min_lat = min_lat.min(location.latitude);
         max_lat = max_lat.max(location.latitude);
         min_lon = min_lon.min(location.longitude);
@@ -314,7 +904,7 @@ fn find_bounds(data: &GeoData) -> (GeoLocation, GeoLocation, GeoLocation) {
     loc_sums.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal));
 
     // Calculate the median location based on sorted sums
-    let median_location = if !data.node_locations.is_empty() {
+    let median_location = if !loc_sums.is_empty() {
         let mid = loc_sums.len() / 2;
         if loc_sums.len() % 2 == 0 {
             // Even number of elements, choose the lower middle element for simplicity
@@ -351,39 +941,820 @@ fn find_bounds(data: &GeoData) -> (GeoLocation, GeoLocation, GeoLocation) {
 pub fn update_building_generation_tasks(
     mut commands: Commands,
     query: Query<(Entity, &mut AsyncComputation<BuildingCreation>)>,
+    asset_cache: Res<AssetCache>,
+    mut footprints: ResMut<BuildingFootprints>,
+    mut mesh_parts: ResMut<MeshPartQueue>,
+    mut chunk_stats: ResMut<ChunkStats>,
+    mut timings: ResMut<PipelineTimings>,
+    mut scene_stats: ResMut<SceneStats>,
+    input_mode: Res<InputMode>,
+) {
+    handle_compute_tasks_limited(&mut commands, query, task_result_budget(&input_mode), move |_, data| {
+        let BuildingCreation(parts, building_footprints, skipped, index, time) = data;
+        timings.record(PipelineStage::Buildings, time);
+        scene_stats.invalid_footprints += skipped;
+        for (id, footprint) in building_footprints {
+            footprints.insert(id, footprint);
+        }
+        if let Some(stats) = chunk_stats.chunks.get_mut(&index) {
+            stats.building_vertices = parts
+                .iter()
+                .flat_map(|(_, parts)| &parts.parts)
+                .map(Mesh::count_vertices)
+                .sum();
+            stats.building_time = Some(time);
+        }
+        for (lighting, parts) in parts {
+            mesh_parts.push_lod(
+                FeatureLayer::Buildings,
+                parts,
+                asset_cache.get_building_material(),
+                Some(NightLighting::Building(lighting)),
+            );
+        }
+    })
+}
+
+/// A type for storing data generated by building generation tasks: the parts
+/// of the meshes for every lighting class, the footprints of the buildings,
+/// the number of skipped invalid footprints, the chunk and how long the
+/// generation took.
+pub struct BuildingCreation(
+    Vec<(LightingClass, LodParts)>,
+    Vec<(u64, Vec<Vec2>)>,
+    usize,
+    ChunkIndex,
+    Duration,
+);
+
+/// Splits a generated mesh into parts of at most `MAX_MESH_PART_VERTICES`
+/// vertices.
+fn split_mesh(mesh: &Mesh) -> Vec<Mesh> {
+    let mut mesh_builder = MeshBuilder::new();
+    mesh_builder.add_mesh(mesh, Transform::IDENTITY);
+    mesh_builder.into_meshes(MAX_MESH_PART_VERTICES)
+}
+
+/// The parts of a generated mesh with a far version, moved so that the
+/// center of the mesh is at the origin. The parts are spawned at `center`,
+/// so their distance to the player is measured from there.
+pub struct LodParts {
+    center: Vec3,
+    parts: Vec<Mesh>,
+    far: Mesh,
+}
+
+/// Splits the detailed version of a generated mesh into parts like
+/// `split_mesh`, and moves both versions to the center of the mesh.
+fn split_lod_mesh(mesh: &LodMesh) -> LodParts {
+    let center = mesh
+        .detailed
+        .compute_aabb()
+        .map_or(Vec3::ZERO, |aabb| Vec3::new(aabb.center.x, 0.0, aabb.center.z));
+    let to_center = Transform::from_translation(-center);
+
+    let mut mesh_builder = MeshBuilder::new();
+    mesh_builder.add_mesh(&mesh.detailed, to_center);
+    let parts = mesh_builder.into_meshes(MAX_MESH_PART_VERTICES);
+    let mut far_builder = MeshBuilder::new();
+    far_builder.add_mesh(&mesh.far, to_center);
+    LodParts {
+        center,
+        parts,
+        far: far_builder.into_mesh(),
+    }
+}
+
+/// Meshes that are generated, but not yet spawned in the world. Spawning
+/// them is spread over frames, so a large city does not upload all of its
+/// geometry to the GPU in a single frame.
+#[derive(Resource, Default)]
+pub struct MeshPartQueue {
+    parts: VecDeque<MeshPart>,
+}
+
+struct MeshPart {
+    layer: FeatureLayer,
+    mesh: Mesh,
+    material: Handle<StandardMaterial>,
+    tunnel: bool,
+    lighting: Option<NightLighting>,
+    /// Where the part is spawned, its vertices are relative to this.
+    center: Vec3,
+    lod: Option<PartLod>,
+}
+
+/// What a part of a mesh with a far version shows in the distance.
+enum PartLod {
+    /// The far version of the whole mesh.
+    Far(Mesh),
+    /// Nothing, since the first part shows the far version.
+    Hidden,
+}
+
+impl MeshPartQueue {
+    /// Adds mesh parts that should be spawned with the given material.
+    pub fn push(
+        &mut self,
+        layer: FeatureLayer,
+        parts: Vec<Mesh>,
+        material: Handle<StandardMaterial>,
+    ) {
+        self.push_parts(layer, parts, material, false, None);
+    }
+
+    /// Adds mesh parts of tunnels, which are only visible when
+    /// `TunnelSettings::visible` is set.
+    pub fn push_tunnels(
+        &mut self,
+        layer: FeatureLayer,
+        parts: Vec<Mesh>,
+        material: Handle<StandardMaterial>,
+    ) {
+        self.push_parts(layer, parts, material, true, None);
+    }
+
+
+    /// Adds the parts of a mesh with a far version, which is shown instead of
+    /// the parts beyond `CHUNK_LOD_DISTANCE_SQUARED`. Parts with `lighting`
+    /// light up at night, and use the given material during the day.
+    pub fn push_lod(
+        &mut self,
+        layer: FeatureLayer,
+        parts: LodParts,
+        material: Handle<StandardMaterial>,
+        lighting: Option<NightLighting>,
+    ) {
+        let mut far = Some(parts.far);
+        for mesh in parts.parts {
+            self.parts.push_back(MeshPart {
+                layer,
+                mesh,
+                material: material.clone(),
+                tunnel: false,
+                lighting,
+                center: parts.center,
+                lod: Some(far.take().map_or(PartLod::Hidden, PartLod::Far)),
+            });
+        }
+    }
+
+    fn push_parts(
+        &mut self,
+        layer: FeatureLayer,
+        parts: Vec<Mesh>,
+        material: Handle<StandardMaterial>,
+        tunnel: bool,
+        lighting: Option<NightLighting>,
+    ) {
+        for mesh in parts {
+            self.parts.push_back(MeshPart {
+                layer,
+                mesh,
+                material: material.clone(),
+                tunnel,
+                lighting,
+                center: Vec3::ZERO,
+                lod: None,
+            });
+        }
+    }
+
+    /// Removes all parts that were not spawned yet.
+    pub fn clear(&mut self) {
+        self.parts.clear();
+    }
+
+    /// Returns whether all parts were spawned.
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+}
+
+/// A system that spawns at most `MESH_PARTS_PER_FRAME` queued mesh parts, or
+/// fewer while generation is throttled. Parts of hidden layers are dropped.
+pub fn spawn_mesh_parts(
+    mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
+    mut mesh_parts: ResMut<MeshPartQueue>,
+    tunnel_settings: Res<TunnelSettings>,
+    layers: Res<LayerVisibility>,
+    mut scene_stats: ResMut<SceneStats>,
+    mut timings: ResMut<PipelineTimings>,
+    input_mode: Res<InputMode>,
+) {
+    if mesh_parts.parts.is_empty() {
+        return;
+    }
+    let start = Instant::now();
+    let budget = if is_generation_throttled(&input_mode) {
+        THROTTLED_MESH_PARTS_PER_FRAME
+    } else {
+        MESH_PARTS_PER_FRAME
+    };
+    for _ in 0..budget {
+        let Some(part) = mesh_parts.parts.pop_front() else {
+            break;
+        };
+        if !layers.is_visible(part.layer) {
+            continue;
+        }
+        scene_stats.add_mesh(part.layer, &part.mesh);
+        let mesh = meshes.add(part.mesh);
+        let mut entity = commands.spawn(PbrBundle {
+            mesh: mesh.clone(),
+            material: part.material.clone(),
+            transform: Transform::from_translation(part.center),
+            ..default()
+        });
+        entity.insert((GeoFeature { id: 0 }, part.layer));
+        match part.lod {
+            Some(PartLod::Far(far)) => {
+                entity.insert(LOD {
+                    remove_distance_squared: f32::MAX,
+                    lod_distance_distance_squared: CHUNK_LOD_DISTANCE_SQUARED,
+                    high_quality_mesh: mesh,
+                    high_quality_material: part.material.clone(),
+                    low_quality_mesh: meshes.add(far),
+                    low_quality_material: part.material,
+                });
+            }
+            Some(PartLod::Hidden) => {
+                entity.insert(LOD {
+                    remove_distance_squared: CHUNK_LOD_DISTANCE_SQUARED,
+                    lod_distance_distance_squared: CHUNK_LOD_DISTANCE_SQUARED,
+                    high_quality_mesh: mesh.clone(),
+                    high_quality_material: part.material.clone(),
+                    low_quality_mesh: mesh,
+                    low_quality_material: part.material,
+                });
+            }
+            None => {}
+        }
+        if part.tunnel {
+            entity.insert((Tunnel, tunnel_settings.visibility()));
+        }
+        if let Some(lighting) = part.lighting {
+            entity.insert(lighting);
+        }
+    }
+    timings.record(PipelineStage::Spawn, start.elapsed());
+}
+
+/// A system that ends the timing of a load once all generation tasks are done
+/// and all meshes are spawned, and reports the timings.
+///
+/// Runs before `update_earth`, so the tasks it starts exist by the time this
+/// system looks for them.
+pub fn finish_pipeline_timings(
+    mut timings: ResMut<PipelineTimings>,
+    mesh_parts: Res<MeshPartQueue>,
+    tasks: Query<
+        (),
+        Or<(
+            With<AsyncComputation<BuildingCreation>>,
+            With<AsyncComputation<RoadCreation>>,
+            With<AsyncComputation<RiverCreation>>,
+            With<AsyncComputation<TerrainCreation>>,
+        )>,
+    >,
+    scene_stats: Res<SceneStats>,
+    generation_queue: Res<GenerationQueue>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    if !timings.is_loading()
+        || !mesh_parts.parts.is_empty()
+        || !tasks.is_empty()
+        || !generation_queue.is_empty()
+    {
+        return;
+    }
+    let Some(total) = timings.finish_load() else {
+        return;
+    };
+    let mut message = format!("Generated the world in {:.1} s", total.as_secs_f32());
+    if scene_stats.invalid_footprints > 0 {
+        message += &format!(
+            ", {} invalid footprints skipped",
+            scene_stats.invalid_footprints
+        );
+    }
+    status_events.send(StatusEvent::Update(message));
+}
+
+/// The OSM elements of which the features are in the world, so that loading
+/// the same data again does not add them twice.
+///
+/// ```
+/// use city_visualizer::data::geography::GeoDataBuilder;
+/// use city_visualizer::earth::LoadedFeatures;
+///
+/// let mut builder = GeoDataBuilder::new();
+/// builder
+///     .add_node(1, 51.4416, 5.4697)
+///     .add_node(2, 51.4416, 5.4699)
+///     .add_node(3, 51.4418, 5.4699)
+///     .add_building(10, vec![1, 2, 3, 1], [("building", "yes")]);
+/// let data = builder.build();
+/// let chunk = data.chunks.values().next().unwrap();
+///
+/// let mut loaded = LoadedFeatures::default();
+/// assert!(!loaded.contains_chunk(chunk));
+/// loaded.insert_chunk(chunk);
+/// assert!(loaded.contains_chunk(chunk));
+/// ```
+#[derive(Debug, Default, Resource)]
+pub struct LoadedFeatures {
+    nodes: HashSet<u64>,
+    ways: HashSet<u64>,
+}
+
+impl LoadedFeatures {
+    /// Returns whether all features of the chunk are in the world already.
+    pub fn contains_chunk(&self, chunk: &Chunk) -> bool {
+        chunk.nodes.keys().all(|id| self.nodes.contains(id))
+            && chunk_way_ids(chunk).all(|id| self.ways.contains(id))
+    }
+
+    /// Records that the features of the chunk are in the world.
+    pub fn insert_chunk(&mut self, chunk: &Chunk) {
+        self.nodes.extend(chunk.nodes.keys());
+        self.ways.extend(chunk_way_ids(chunk));
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.ways.clear();
+    }
+}
+
+/// Returns the ids of the ways of all features in a chunk.
+fn chunk_way_ids(chunk: &Chunk) -> impl Iterator<Item = &u64> {
+    chunk
+        .building_features
+        .keys()
+        .chain(chunk.road_features.keys())
+        .chain(chunk.land_use_features.keys())
+        .chain(chunk.lake_features.keys())
+        .chain(chunk.river_features.keys())
+}
+
+/// Returns whether the world is done with all data that was sent to it: no
+/// `GeoDataEvent` is waiting, no chunk is queued for generation, no compute
+/// task is running and all generated meshes are spawned. Useful to step an app without a window until the data
+/// is in the world, see the `smoke_test` test.
+pub fn is_world_settled(world: &mut World) -> bool {
+    let pending = world
+        .query_filtered::<(), With<PendingComputation>>()
+        .iter(world)
+        .next()
+        .is_some();
+    !pending
+        && world.resource::<GenerationQueue>().is_empty()
+        && world.resource::<MeshPartQueue>().parts.is_empty()
+        && world.resource::<Events<GeoDataEvent>>().is_empty()
+}
+
+/// Marks an entity as (part of) the mesh of tunnels.
+#[derive(Component)]
+pub struct Tunnel;
+
+/// Settings for drawing tunnels, which are hidden by default.
+#[derive(Resource, Default)]
+pub struct TunnelSettings {
+    /// Whether tunnels are drawn below the ground.
+    pub visible: bool,
+}
+
+impl TunnelSettings {
+    fn visibility(&self) -> Visibility {
+        if self.visible {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        }
+    }
+}
+
+/// The range of time scales of the simulation that can be chosen.
+pub const TIME_SCALE_RANGE: std::ops::RangeInclusive<f32> = 0.1..=10.0;
+
+/// Settings for the simulation of agents and traffic lights. The camera is not
+/// affected by them.
+#[derive(Clone, Copy, Debug, Resource)]
+pub struct SimulationSettings {
+    pub paused: bool,
+    /// How much faster than real time the simulation runs.
+    pub time_scale: f32,
+}
+
+impl Default for SimulationSettings {
+    fn default() -> Self {
+        SimulationSettings {
+            paused: false,
+            time_scale: 1.0,
+        }
+    }
+}
+
+impl SimulationSettings {
+    /// Returns the simulated time since the last frame, which is 0 while
+    /// paused.
+    pub fn delta_seconds(&self, time: &Time) -> f32 {
+        if self.paused {
+            0.0
+        } else {
+            time.delta_seconds() * self.time_scale
+        }
+    }
+}
+
+/// Which of the generation tasks of a chunk are started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ChunkTasks {
+    buildings: bool,
+    roads: bool,
+    rivers: bool,
+    /// Grass and trees, which are generated together.
+    terrain: bool,
+}
+
+impl ChunkTasks {
+    const ALL: ChunkTasks = ChunkTasks {
+        buildings: true,
+        roads: true,
+        rivers: true,
+        terrain: true,
+    };
+
+    /// Returns the tasks that generate the given layers.
+    fn for_layers(layers: &HashSet<FeatureLayer>) -> Self {
+        ChunkTasks {
+            buildings: layers.contains(&FeatureLayer::Buildings),
+            roads: layers.contains(&FeatureLayer::Roads),
+            rivers: layers.contains(&FeatureLayer::Rivers),
+            terrain: layers.contains(&FeatureLayer::Terrain)
+                || layers.contains(&FeatureLayer::Trees),
+        }
+    }
+
+    /// Returns the number of tasks that are started.
+    fn count(self) -> usize {
+        [self.buildings, self.roads, self.rivers, self.terrain]
+            .into_iter()
+            .filter(|&started| started)
+            .count()
+    }
+}
+
+/// A chunk of which the generation tasks have not been started yet, with the
+/// offset and bounds of the load it is part of.
+struct QueuedChunk {
+    data: Arc<GeoData>,
+    index: ChunkIndex,
+    offset: Offset,
+    bounds: LoadedBounds,
+    tasks: ChunkTasks,
+}
+
+/// The chunks that wait for their generation tasks to be started. Only a
+/// limited number of tasks runs at a time, so a large city does not flood
+/// the task pool and all of its results do not land in the same frame.
+///
+/// Insert this resource before adding the plugin to use another limit.
+#[derive(Resource)]
+pub struct GenerationQueue {
+    chunks: VecDeque<QueuedChunk>,
+    /// The number of generation tasks that may run at the same time. The
+    /// tasks of a chunk are started together, so a chunk is started when
+    /// all of its tasks fit, or when nothing is running.
+    pub max_in_flight: usize,
+    /// The number of generation tasks that were running in the last frame.
+    in_flight: usize,
+}
+
+impl Default for GenerationQueue {
+    /// Allows twice as many tasks as there are cores, so cores do not wait
+    /// while the results of finished tasks are handled.
+    fn default() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+        GenerationQueue::new(2 * cores)
+    }
+}
+
+impl GenerationQueue {
+    /// Returns an empty queue that runs at most `max_in_flight` tasks at a
+    /// time.
+    pub fn new(max_in_flight: usize) -> Self {
+        GenerationQueue {
+            chunks: VecDeque::new(),
+            max_in_flight,
+            in_flight: 0,
+        }
+    }
+
+    fn push(&mut self, chunk: QueuedChunk) {
+        self.chunks.push_back(chunk);
+    }
+
+    /// Returns the number of chunks of which the tasks were not started yet.
+    pub fn queued(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Returns the number of generation tasks that are running.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Returns whether no chunks are waiting.
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Forgets the chunks that are waiting, when the world is cleared or
+    /// loading is cancelled.
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+    }
+}
+
+/// A system that starts the generation tasks of queued chunks, as long as
+/// fewer than `GenerationQueue::max_in_flight` tasks are running. Runs after
+/// `update_earth`, so the chunks it queues start in the same frame.
+pub fn start_queued_generation(
+    mut commands: Commands,
+    mut generation_queue: ResMut<GenerationQueue>,
+    tasks: Query<
+        (),
+        Or<(
+            With<AsyncComputation<BuildingCreation>>,
+            With<AsyncComputation<RoadCreation>>,
+            With<AsyncComputation<RiverCreation>>,
+            With<AsyncComputation<TerrainCreation>>,
+        )>,
+    >,
     asset_cache: Res<AssetCache>,
+    input_mode: Res<InputMode>,
+    settings: Res<GenerationSettings>,
 ) {
-    handle_compute_tasks(&mut commands, query, move |commands, data| {
-        let BuildingCreation(mesh) = data;
-        commands
-            .spawn(PbrBundle {
-                mesh: meshes.add(mesh),
-                material: asset_cache.get_building_material(),
-                ..default()
+    let mut in_flight = tasks.iter().count();
+    if !is_generation_throttled(&input_mode) {
+        while let Some(chunk) = generation_queue.chunks.front() {
+            let count = chunk.tasks.count();
+            if in_flight > 0 && in_flight + count > generation_queue.max_in_flight {
+                break;
+            }
+            let chunk = generation_queue.chunks.pop_front().unwrap_throw();
+            spawn_chunk_tasks(&mut commands, chunk, &asset_cache, &settings);
+            in_flight += count;
+        }
+    }
+    if generation_queue.in_flight != in_flight {
+        generation_queue.in_flight = in_flight;
+    }
+}
+
+/// Starts the tasks that generate the buildings, roads, rivers and terrain of
+/// a chunk, or the ones of them that were asked for, with a copy of the
+/// current generation settings.
+fn spawn_chunk_tasks(
+    commands: &mut Commands,
+    queued: QueuedChunk,
+    asset_cache: &AssetCache,
+    settings: &GenerationSettings,
+) {
+    let QueuedChunk { data, index, offset, bounds, tasks } = queued;
+
+    // Update buildings, handle result in `update_building_generation_tasks`
+    if tasks.buildings {
+        let data = Arc::clone(&data);
+        let settings = *settings;
+        let index_clone = index.clone(); // for borrow checking purposes
+        let asset_cache_ref = asset_cache.clone_weak();
+        spawn_compute_task(commands, async move {
+            let start = Instant::now();
+            let chunk = data.chunks.get(&index_clone).unwrap_throw();
+            let (meshes, footprints, skipped) = create_building_data(
+                &data.node_locations,
+                &chunk.building_features,
+                &chunk.land_use_features,
+                &asset_cache_ref,
+                &offset,
+                &settings,
+            );
+            let parts = meshes
+                .iter()
+                .map(|(lighting, mesh)| (*lighting, split_lod_mesh(mesh)))
+                .collect();
+            BuildingCreation(parts, footprints, skipped, index_clone, start.elapsed())
+        });
+    }
+
+    // Update roads, handle result in `update_road_generation_tasks`
+    if tasks.roads {
+        let data = Arc::clone(&data);
+        let index_clone = index.clone();
+        let asset_cache_ref = asset_cache.clone_weak();
+        spawn_compute_task(commands, async move {
+            let start = Instant::now();
+            let chunk = data.chunks.get(&index_clone).unwrap_throw();
+            let (mesh, lit_mesh, stubs, tunnel_mesh) = create_road_data(
+                &data.node_locations,
+                &chunk.road_features,
+                &asset_cache_ref,
+                &offset,
+                &bounds,
+            );
+            let lod_parts = [split_lod_mesh(&mesh), split_lod_mesh(&lit_mesh)];
+            RoadCreation(lod_parts, stubs, split_mesh(&tunnel_mesh), index_clone, start.elapsed())
+        });
+    }
+
+    // Update rivers, handle result in `update_river_generation_tasks`
+    if tasks.rivers {
+        let data = Arc::clone(&data);
+        let index_clone = index.clone();
+        let asset_cache_ref = asset_cache.clone_weak();
+        spawn_compute_task(commands, async move {
+            let start = Instant::now();
+            let chunk = data.chunks.get(&index_clone).unwrap_throw();
+            let mesh = create_river_data(
+                &data.node_locations,
+                &chunk.river_features,
+                &asset_cache_ref,
+                &offset,
+            );
+            RiverCreation(mesh, start.elapsed())
+        });
+    }
+
+    // Update terrain, handle result in `update_terrain_generation_tasks`
+    if tasks.terrain {
+        let settings = *settings;
+        spawn_compute_task(commands, async move {
+            let start = Instant::now();
+            let chunk = data.chunks.get(&index).unwrap_throw();
+            let (tree_transforms, grass_areas) = create_terrain_data(
+                &data.node_locations,
+                &chunk.land_use_features,
+                &offset,
+                &settings,
+            );
+            TerrainCreation(tree_transforms, grass_areas, start.elapsed())
+        });
+    }
+}
+
+/// The chunks of which the buildings and roads are being generated, to report
+/// the progress of generation.
+#[derive(Debug, Default, Resource)]
+pub struct GenerationProgress {
+    chunks: Vec<ChunkIndex>,
+}
+
+impl GenerationProgress {
+    /// Returns how many of the chunks are done.
+    fn finished(&self, chunk_stats: &ChunkStats) -> usize {
+        // chunks without statistics were removed from the world
+        self.chunks
+            .iter()
+            .filter(|index| match chunk_stats.chunks.get(index) {
+                Some(stats) => stats.building_time.is_some() && stats.road_time.is_some(),
+                None => true,
             })
-            .insert(GeoFeature { id: 0 });
-    })
+            .count()
+    }
+}
+
+/// A system that reports how many of the chunks that are being generated are
+/// done, whenever a generation task finishes.
+pub fn update_generation_progress(
+    mut generation_progress: ResMut<GenerationProgress>,
+    chunk_stats: Res<ChunkStats>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    if generation_progress.chunks.is_empty() || !chunk_stats.is_changed() {
+        return;
+    }
+
+    let finished = generation_progress.finished(&chunk_stats);
+    let total = generation_progress.chunks.len();
+    status_events.send(StatusEvent::Progress {
+        label: format!("Generating chunks ({}/{})", finished, total),
+        fraction: Some(finished as f32 / total as f32),
+    });
+    if finished == total {
+        generation_progress.chunks.clear();
+    }
 }
 
-/// A type for storing data generated by building generation tasks.
-pub struct BuildingCreation(Mesh);
+/// A system that cancels the generation tasks of chunks when loading is
+/// cancelled. Meshes that were generated already are still added, and the
+/// chunks stay incomplete until the world is cleared.
+pub fn cancel_generation(
+    mut commands: Commands,
+    mut cancel_events: EventReader<CancelLoadingEvent>,
+    tasks: Query<
+        Entity,
+        Or<(
+            With<AsyncComputation<BuildingCreation>>,
+            With<AsyncComputation<RoadCreation>>,
+            With<AsyncComputation<RiverCreation>>,
+            With<AsyncComputation<TerrainCreation>>,
+            With<AsyncComputation<DistrictStatsCreation>>,
+        )>,
+    >,
+    mut generation_progress: ResMut<GenerationProgress>,
+    mut generation_queue: ResMut<GenerationQueue>,
+    chunk_stats: Res<ChunkStats>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    if cancel_events.read().count() == 0 {
+        return;
+    }
+
+    // dropping a task cancels it, and queued chunks are never started
+    for entity in &tasks {
+        commands.entity(entity).despawn();
+    }
+    generation_queue.clear();
+    let unfinished = generation_progress.chunks.len() - generation_progress.finished(&chunk_stats);
+    if unfinished > 0 {
+        status_events.send(StatusEvent::Update(format!(
+            "Cancelled generating {} chunks, clear the world to load them again",
+            unfinished,
+        )));
+    }
+    generation_progress.chunks.clear();
+}
+
+/// A system that polls district statistics tasks that are not yet fulfilled.
+pub fn update_district_stats_tasks(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<DistrictStatsCreation>)>,
+    mut stats: ResMut<DistrictStats>,
+    input_mode: Res<InputMode>,
+) {
+    handle_compute_tasks_limited(&mut commands, query, task_result_budget(&input_mode), move |_, data| {
+        let DistrictStatsCreation(districts, offset) = data;
+        stats.add(districts, offset);
+    });
+}
 
 /// A system that polls road generation tasks that are not yet fulfilled.
+/// Stubs are spawned right away, since they are small, and only those that
+/// are still at the edge of the loaded data: more data may have been loaded
+/// around the chunk since it was generated.
 pub fn update_road_generation_tasks(
     mut commands: Commands,
     query: Query<(Entity, &mut AsyncComputation<RoadCreation>)>,
-    mut meshes: ResMut<Assets<Mesh>>,
     asset_cache: Res<AssetCache>,
+    mut mesh_parts: ResMut<MeshPartQueue>,
+    mut chunk_stats: ResMut<ChunkStats>,
+    mut timings: ResMut<PipelineTimings>,
+    input_mode: Res<InputMode>,
+    (mut meshes, bounds, scale, layers, mut scene_stats): (
+        ResMut<Assets<Mesh>>,
+        Res<LoadedBounds>,
+        Res<WorldScale>,
+        Res<LayerVisibility>,
+        ResMut<SceneStats>,
+    ),
 ) {
-    handle_compute_tasks(&mut commands, query, move |commands, data| {
-        let RoadCreation(mesh) = data;
-        let entity_bundle = PbrBundle {
-            mesh: meshes.add(mesh),
-            material: asset_cache.get_road_material(), // TODO use this or generalize to trajectory
-            ..default()
-        };
-        commands.spawn(entity_bundle).insert(GeoFeature { id: 0 });
+    let margin = scale.units(BOUNDARY_MARGIN);
+    handle_compute_tasks_limited(&mut commands, query, task_result_budget(&input_mode), move |commands, data| {
+        let RoadCreation([parts, lit_parts], mut stubs, tunnel_parts, index, time) = data;
+        timings.record(PipelineStage::Roads, time);
+        stubs.retain(|stub| bounds.is_near_boundary(stub.end, margin));
+        let stub_mesh = (!stubs.is_empty()).then(|| create_stub_mesh(&stubs, &scale));
+        if let Some(stats) = chunk_stats.chunks.get_mut(&index) {
+            stats.road_vertices = [&parts.parts, &lit_parts.parts, &tunnel_parts]
+                .into_iter()
+                .flatten()
+                .chain(&stub_mesh)
+                .map(Mesh::count_vertices)
+                .sum();
+            stats.road_time = Some(time);
+        }
+        // TODO use this or generalize to trajectory
+        let layer = FeatureLayer::Roads;
+        mesh_parts.push_lod(layer, parts, asset_cache.get_road_material(), None);
+        mesh_parts.push_lod(layer, lit_parts, asset_cache.get_road_material(), Some(NightLighting::LitRoad));
+        mesh_parts.push_tunnels(layer, tunnel_parts, asset_cache.get_road_tunnel_material());
+        if let Some(stub_mesh) = stub_mesh.filter(|_| layers.is_visible(layer)) {
+            scene_stats.add_mesh(layer, &stub_mesh);
+            commands.spawn((
+                PbrBundle {
+                    mesh: meshes.add(stub_mesh),
+                    material: asset_cache.get_road_stub_material(),
+                    ..default()
+                },
+                RoadStubs(stubs),
+                GeoFeature { id: 0 },
+                layer,
+            ));
+        }
     });
 }
 
@@ -392,61 +1763,58 @@ pub fn update_terrain_generation_tasks(
     query: Query<(Entity, &mut AsyncComputation<TerrainCreation>)>,
     mut meshes: ResMut<Assets<Mesh>>,
     asset_cache: Res<AssetCache>,
+    mut timings: ResMut<PipelineTimings>,
+    input_mode: Res<InputMode>,
+    scale: Res<WorldScale>,
+    layers: Res<LayerVisibility>,
+    mut scene_stats: ResMut<SceneStats>,
+    settings: Res<GenerationSettings>,
 ) {
-    handle_compute_tasks(&mut commands, query, move |commands, data| {
-        let TerrainCreation(tree_transforms, grass_areas) = data;
-        let perlin = Perlin::new(rand::random::<u32>());
-        for transform in tree_transforms {
-            // Get meshes, randomly pick between simple and complex trees
-            let hq_mesh;
-            let simple_mesh;
-            if perlin.get(
-                transform
-                    .translation
-                    .to_array()
-                    .map(|val| val / GLOBAL_SCALE_FACTOR)
-                    .map(f64::from),
-            ) < CHANCE_COMPLEX_TREE
-            {
-                hq_mesh = asset_cache.get_complex_tree_mesh();
-                simple_mesh = asset_cache.get_simplified_complex_tree_mesh();
-            } else {
-                hq_mesh = asset_cache.get_triangle_tree_mesh();
-                simple_mesh = hq_mesh.clone();
-            }
-
-            // Spawn tree
-            commands
-                .spawn(PbrBundle {
-                    mesh: hq_mesh.clone(),
-                    material: asset_cache.get_tree_material(),
-                    transform,
-                    ..default()
-                })
-                .insert(GeoFeature { id: 0 })
-                .insert(LOD {
-                    remove_distance_squared: 2.0 * DEFAULT_REMOVE_DISTANCE_SQUARED,
-                    lod_distance_distance_squared: DEFAULT_LOD_DISTANCE_SQUARED,
-                    high_quality_mesh: hq_mesh,
-                    high_quality_material: asset_cache.get_tree_material(),
-                    low_quality_mesh: simple_mesh,
-                    low_quality_material: asset_cache.get_tree_material(),
-                });
+    let noise_scale = scale.units(100.0);
+    let complex_tree_threshold = settings.complex_tree_threshold;
+    handle_compute_tasks_limited(&mut commands, query, task_result_budget(&input_mode), move |commands, data| {
+        let TerrainCreation(mut tree_transforms, mut grass_areas, time) = data;
+        timings.record(PipelineStage::Terrain, time);
+        if !layers.trees {
+            tree_transforms.clear();
+        }
+        if !layers.terrain {
+            grass_areas.clear();
         }
+        let perlin = Perlin::new(rand::random::<u32>());
+        let trees = tree_transforms
+            .into_iter()
+            .map(|transform| TreeInstance {
+                transform,
+                // Randomly pick between simple and complex trees
+                complex: perlin.get(
+                    transform
+                        .translation
+                        .to_array()
+                        .map(|val| val / noise_scale)
+                        .map(f64::from),
+                ) < complex_tree_threshold,
+            })
+            .collect();
+        // Trees are shown by `update_tree_chunks`
+        spawn_tree_chunk(commands, trees, &scale);
         for grass_area in grass_areas {
+            scene_stats.add_mesh(FeatureLayer::Terrain, &grass_area);
             commands
                 .spawn(PbrBundle {
                     mesh: meshes.add(grass_area),
                     material: asset_cache.get_grass_material(),
                     ..Default::default()
                 })
-                .insert(GeoFeature { id: 0 });
+                .insert((GeoFeature { id: 0 }, FeatureLayer::Terrain));
         }
     });
 }
 
-/// A type for storing data generated by terrain generation tasks.
-pub struct TerrainCreation(Vec<Transform>, Vec<Mesh>);
+/// A type for storing data generated by terrain generation tasks: the
+/// transforms of the trees, the meshes of grass and how long the generation
+/// took.
+pub struct TerrainCreation(Vec<Transform>, Vec<Mesh>, Duration);
 
 /// A system that polls road generation tasks that are not yet fulfilled.
 pub fn update_river_generation_tasks(
@@ -454,33 +1822,65 @@ pub fn update_river_generation_tasks(
     query: Query<(Entity, &mut AsyncComputation<RiverCreation>)>,
     mut meshes: ResMut<Assets<Mesh>>,
     asset_cache: Res<AssetCache>,
+    mut timings: ResMut<PipelineTimings>,
+    input_mode: Res<InputMode>,
+    layers: Res<LayerVisibility>,
+    mut scene_stats: ResMut<SceneStats>,
 ) {
-    handle_compute_tasks(&mut commands, query, move |commands, data| {
-        let RiverCreation(mesh) = data;
+    handle_compute_tasks_limited(&mut commands, query, task_result_budget(&input_mode), move |commands, data| {
+        let RiverCreation(mesh, time) = data;
+        timings.record(PipelineStage::Rivers, time);
+        if !layers.rivers {
+            return;
+        }
+        scene_stats.add_mesh(FeatureLayer::Rivers, &mesh);
         let entity_bundle = PbrBundle {
             mesh: meshes.add(mesh),
             material: asset_cache.get_river_material(), // TODO use this or generalize to trajectory
             ..default()
         };
-        commands.spawn(entity_bundle).insert(GeoFeature { id: 0 });
+        commands.spawn(entity_bundle).insert((GeoFeature { id: 0 }, FeatureLayer::Rivers));
     });
 }
 
-/// A type for storing data generated by async generation tasks.
-pub struct RoadCreation(Mesh);
+/// A type for storing data generated by async generation tasks: the parts of
+/// the road mesh and of the lit road mesh with their far versions, the stubs
+/// at roads that are cut off, the parts of the mesh of roads in tunnels, the
+/// chunk and how long the generation took.
+pub struct RoadCreation([LodParts; 2], Vec<RoadStub>, Vec<Mesh>, ChunkIndex, Duration);
 
-pub struct RiverCreation(Mesh);
+/// A type for storing data generated by river generation tasks: the mesh and
+/// how long the generation took.
+pub struct RiverCreation(Mesh, Duration);
 
-/// Result of agent creation, is start location + agent component
-pub struct AgentCreation(Vec<(Vec3, Agent)>);
+/// Result of agent creation, is start location + agent component, the
+/// generation of the traffic graph the agents were created for, and the
+/// number of agents that was requested
+pub struct AgentCreation(Vec<(Vec3, Agent)>, u32, usize);
 
 /// A system that polls agent generation tasks that are not yet fulfilled.
 pub fn update_agent_generation_tasks(
     mut commands: Commands,
     query: Query<(Entity, &mut AsyncComputation<AgentCreation>)>,
     asset_cache: Res<AssetCache>,
+    traffic_graph: Res<TrafficGraph>,
+    input_mode: Res<InputMode>,
+    mut agent_spawner: ResMut<AgentSpawner>,
+    scale: Res<WorldScale>,
+    layers: Res<LayerVisibility>,
 ) {
-    handle_compute_tasks(&mut commands, query, move |commands, data| {
+    // the meshes of agents are sized for the default scale, and their shadows
+    // are scaled along as children
+    let agent_scale = Vec3::splat(scale.units(1.0));
+    handle_compute_tasks_limited(&mut commands, query, task_result_budget(&input_mode), move |commands, data| {
+        agent_spawner.finish_pending(data.2);
+
+        // The graph was reset while these agents were being created, or
+        // agents were hidden
+        if data.1 != traffic_graph.get_generation() || !layers.agents {
+            return;
+        }
+
         for agent_tuple in data.0 {
             let (start_location, agent) = agent_tuple;
             let agent_type = agent.agent_type;
@@ -488,10 +1888,10 @@ pub fn update_agent_generation_tasks(
                 .spawn(PbrBundle {
                     mesh: asset_cache.get_agent_mesh(agent.agent_type, true),
                     material: asset_cache.get_agent_material(agent.agent_type, true),
-                    transform: Transform::from_translation(start_location),
+                    transform: Transform::from_translation(start_location).with_scale(agent_scale),
                     ..default()
                 })
-                .insert(agent)
+                .insert((agent, FeatureLayer::Agents))
                 .insert(LOD {
                     remove_distance_squared: DEFAULT_REMOVE_DISTANCE_SQUARED,
                     lod_distance_distance_squared: DEFAULT_LOD_DISTANCE_SQUARED,
@@ -499,11 +1899,45 @@ pub fn update_agent_generation_tasks(
                     high_quality_material: asset_cache.get_agent_material(agent_type, false),
                     low_quality_mesh: asset_cache.get_agent_mesh(agent_type, true),
                     low_quality_material: asset_cache.get_agent_material(agent_type, true),
+                })
+                .with_children(|parent| {
+                    spawn_agent_shadow(parent, agent_type, &asset_cache);
                 });
         }
     });
 }
 
+/// Spawns a blob shadow under an agent, as a child so it moves along and is
+/// removed together with the agent.
+fn spawn_agent_shadow(parent: &mut ChildBuilder, agent_type: AgentType, asset_cache: &AssetCache) {
+    // agents face the z-axis
+    let size = match agent_type {
+        AgentType::Car => Vec3::new(2.2, 1.0, 4.4),
+        AgentType::Pedestrian => Vec3::new(1.4, 1.0, 1.4),
+        AgentType::Bicycle => Vec3::new(0.9, 1.0, 2.2),
+    };
+    let mesh = asset_cache.get_agent_shadow_mesh();
+    let material = asset_cache.get_agent_shadow_material();
+    parent.spawn((
+        PbrBundle {
+            mesh: mesh.clone(),
+            material: material.clone(),
+            transform: Transform::from_xyz(0.0, OVERLAY_HEIGHT, 0.0).with_scale(size),
+            ..default()
+        },
+        NotShadowCaster,
+        NotShadowReceiver,
+        LOD {
+            remove_distance_squared: SHADOW_REMOVE_DISTANCE_SQUARED,
+            lod_distance_distance_squared: SHADOW_REMOVE_DISTANCE_SQUARED,
+            high_quality_mesh: mesh.clone(),
+            high_quality_material: material.clone(),
+            low_quality_mesh: mesh,
+            low_quality_material: material,
+        },
+    ));
+}
+
 /// Marks an entity as a geographic feature, saving its unique identifier.
 #[derive(Component)]
 #[allow(dead_code)]
@@ -511,3 +1945,11 @@ pub struct GeoFeature {
     #[allow(dead_code)]
     id: u64,
 }
+
+impl GeoFeature {
+    /// Returns whether the entity is (part of) a mesh that was generated for
+    /// a whole chunk, rather than a single OSM element such as a street lamp.
+    fn is_chunk_mesh(&self) -> bool {
+        self.id == 0
+    }
+}
@@ -0,0 +1,73 @@
+//! Layers for flat features that are drawn on top of roads, such as blob
+//! shadows and highlights. Instead of every feature picking a slightly higher
+//! y value than the roads, which leads to z-fighting as features pile up, all
+//! overlays lie at the same height on top of the roads, and their order is
+//! decided by the depth bias of their materials.
+//!
+//! Structural heights, like bridge decks, are real y values and are not
+//! affected by this.
+
+use bevy::prelude::*;
+
+/// The height of all overlays: the top of the roads, which are between 0.01
+/// and 0.02, and below buildings.
+pub const OVERLAY_HEIGHT: f32 = 0.02;
+
+/// The difference in depth bias between consecutive layers. Roads have no
+/// depth bias, so even the lowest layer is drawn over them.
+const DEPTH_BIAS_STEP: f32 = 256.0;
+
+/// A layer of flat features on top of roads. Layers are listed from bottom to
+/// top: features in a later layer are drawn over features in earlier layers
+/// where they overlap, regardless of the viewing angle.
+///
+/// ```
+/// use city_visualizer::earth::overlay::OverlayLayer;
+///
+/// for pair in OverlayLayer::ALL.windows(2) {
+///     assert!(pair[0] < pair[1]);
+///     assert!(pair[0].depth_bias() < pair[1].depth_bias());
+/// }
+///
+/// let material = OverlayLayer::Highlight.material(Default::default());
+/// assert_eq!(material.depth_bias, OverlayLayer::Highlight.depth_bias());
+/// ```
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum OverlayLayer {
+    /// Paint on the road surface, like lane markings.
+    Marking,
+    /// Pedestrian crossings, painted over the markings of the road.
+    Crossing,
+    /// Colors showing a quantity over the roads, such as traffic density.
+    Heatmap,
+    /// Blob shadows under agents.
+    AgentShadow,
+    /// Outlines and fills of selected features, which should never be hidden.
+    Highlight,
+}
+
+impl OverlayLayer {
+    pub const ALL: [OverlayLayer; 5] = [
+        OverlayLayer::Marking,
+        OverlayLayer::Crossing,
+        OverlayLayer::Heatmap,
+        OverlayLayer::AgentShadow,
+        OverlayLayer::Highlight,
+    ];
+
+    /// Returns the depth bias of the materials in this layer. A higher bias
+    /// moves fragments closer to the camera.
+    pub fn depth_bias(self) -> f32 {
+        (self as u32 + 1) as f32 * DEPTH_BIAS_STEP
+    }
+
+    /// Returns the material with the depth bias of this layer. Entities of
+    /// overlays should also have `NotShadowCaster` and `NotShadowReceiver`,
+    /// since shadow maps do not know about the depth bias.
+    pub fn material(self, material: StandardMaterial) -> StandardMaterial {
+        StandardMaterial {
+            depth_bias: self.depth_bias(),
+            ..material
+        }
+    }
+}
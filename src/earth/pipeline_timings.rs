@@ -0,0 +1,201 @@
+//! Timings of the stages of the generation pipeline, from parsing the data to
+//! spawning the meshes, to find out what makes loading slow.
+//!
+//! Times are measured with `bevy::utils::Instant`, which is
+//! `std::time::Instant` on native and uses `performance.now()` on the web.
+
+use crate::commands::CommandRegistry;
+
+use bevy::prelude::*;
+use bevy::utils::{Duration, Instant};
+use bevy_egui::egui;
+use bevy_egui::EguiContexts;
+
+/// The width of the bars in the timings panel, in points.
+const BAR_WIDTH: f32 = 200.0;
+
+/// A stage of the generation pipeline.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PipelineStage {
+    /// Converting the downloaded or read data to `GeoData`.
+    Parse,
+    Buildings,
+    Roads,
+    Rivers,
+    Terrain,
+    /// Adding the roads to the traffic graph.
+    Graph,
+    /// Spawning the generated meshes.
+    Spawn,
+}
+
+impl PipelineStage {
+    pub const ALL: [PipelineStage; 7] = [
+        PipelineStage::Parse,
+        PipelineStage::Buildings,
+        PipelineStage::Roads,
+        PipelineStage::Rivers,
+        PipelineStage::Terrain,
+        PipelineStage::Graph,
+        PipelineStage::Spawn,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PipelineStage::Parse => "Parse",
+            PipelineStage::Buildings => "Buildings",
+            PipelineStage::Roads => "Roads",
+            PipelineStage::Rivers => "Rivers",
+            PipelineStage::Terrain => "Terrain",
+            PipelineStage::Graph => "Graph",
+            PipelineStage::Spawn => "Spawn",
+        }
+    }
+}
+
+/// The time spent in every stage of the pipeline during the last load.
+///
+/// Chunks are generated in parallel, so the stages can add up to more than
+/// the time the load took.
+///
+/// ```
+/// use bevy::utils::Duration;
+/// use city_visualizer::earth::pipeline_timings::{PipelineStage, PipelineTimings};
+///
+/// let mut timings = PipelineTimings::default();
+/// timings.start_load();
+/// timings.record(PipelineStage::Roads, Duration::from_millis(30));
+/// timings.record(PipelineStage::Roads, Duration::from_millis(20));
+///
+/// assert_eq!(timings.get(PipelineStage::Roads), Duration::from_millis(50));
+/// assert!(timings.is_loading());
+/// timings.finish_load();
+/// assert!(!timings.is_loading());
+/// ```
+#[derive(Debug, Default, Resource)]
+pub struct PipelineTimings {
+    stages: [Duration; PipelineStage::ALL.len()],
+    /// When the current load started, if data is being generated.
+    started: Option<Instant>,
+    /// How long the last finished load took from start to end.
+    total: Option<Duration>,
+    /// Whether the timings panel is shown.
+    pub panel_visible: bool,
+}
+
+impl PipelineTimings {
+    /// Starts timing a new load, unless a load is already in progress, in
+    /// which case both loads are timed together.
+    pub fn start_load(&mut self) {
+        if self.started.is_none() {
+            self.stages = Default::default();
+            self.started = Some(Instant::now());
+        }
+    }
+
+    /// Adds time spent in a stage to the current load.
+    pub fn record(&mut self, stage: PipelineStage, time: Duration) {
+        self.stages[stage as usize] += time;
+    }
+
+    pub fn get(&self, stage: PipelineStage) -> Duration {
+        self.stages[stage as usize]
+    }
+
+    /// Returns how long the last finished load took.
+    pub fn total(&self) -> Option<Duration> {
+        self.total
+    }
+
+    pub fn is_loading(&self) -> bool {
+        self.started.is_some()
+    }
+
+    /// Ends the current load, and returns how long it took.
+    pub fn finish_load(&mut self) -> Option<Duration> {
+        let total = self.started.take()?.elapsed();
+        self.total = Some(total);
+        Some(total)
+    }
+
+    /// Returns a summary of the timings of the last load, for the log.
+    pub fn report(&self) -> String {
+        let stages: Vec<String> = PipelineStage::ALL
+            .iter()
+            .map(|&stage| format!("{} {}", stage.name(), format_time(self.get(stage))))
+            .collect();
+        match self.total {
+            Some(total) => format!("Generated in {}: {}", format_time(total), stages.join(", ")),
+            None => stages.join(", "),
+        }
+    }
+}
+
+/// Runs `f` and adds the time it took to `stage`.
+pub fn time_stage<T>(timings: &mut PipelineTimings, stage: PipelineStage, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    timings.record(stage, start.elapsed());
+    result
+}
+
+/// A system that registers the commands for pipeline timings.
+pub fn register_pipeline_timings_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle pipeline timings",
+        "Shows how long every stage of generating the last loaded data took",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut timings = world.resource_mut::<PipelineTimings>();
+                timings.panel_visible = !timings.panel_visible;
+            })
+        },
+    );
+}
+
+/// A system that shows the timings of the last load as bars.
+pub fn update_pipeline_timings_panel(mut contexts: EguiContexts, timings: Res<PipelineTimings>) {
+    if !timings.panel_visible {
+        return;
+    }
+
+    egui::Window::new("Pipeline timings").show(contexts.ctx_mut(), |ui| {
+        if timings.is_loading() {
+            ui.label("Generating...");
+        } else {
+            match timings.total {
+                Some(total) => ui.label(format!("Generated in {}", format_time(total))),
+                None => ui.label("Nothing was loaded yet"),
+            };
+        }
+        ui.separator();
+
+        let longest = PipelineStage::ALL
+            .iter()
+            .map(|&stage| timings.get(stage))
+            .max()
+            .unwrap_or_default()
+            .max(Duration::from_micros(1));
+        egui::Grid::new("pipeline_timings_grid").show(ui, |ui| {
+            for stage in PipelineStage::ALL {
+                let time = timings.get(stage);
+                ui.label(stage.name());
+                ui.add(
+                    egui::ProgressBar::new(time.as_secs_f32() / longest.as_secs_f32())
+                        .desired_width(BAR_WIDTH)
+                        .text(format_time(time)),
+                );
+                ui.end_row();
+            }
+        });
+    });
+}
+
+pub fn format_time(time: Duration) -> String {
+    if time.as_secs_f64() >= 1.0 {
+        format!("{:.2} s", time.as_secs_f64())
+    } else {
+        format!("{:.1} ms", time.as_secs_f64() * 1000.0)
+    }
+}
@@ -1,24 +1,24 @@
 use std::collections::HashMap;
 use bevy::prelude::*;
-use crate::data::geography::{GeoLocation, Offset, RiverFeature};
-use super::{assets::AssetCache, mesh_builder::MeshBuilder, trajectory::generate_trajectory};
+use crate::data::geography::{project_nodes, GeoLocation, Offset, RiverFeature};
+use super::assets::AssetCache;
+use super::mesh_builder::MeshBuilder;
+use super::simplification::simplify_polyline;
+use super::trajectory::{generate_bridge, generate_trajectory, get_bridge_height};
 use wasm_bindgen::prelude::*;
 
+/// Height of rivers, under roads and lakes to avoid z-fighting.
+const RIVER_HEIGHT: f32 = 0.005;
+/// How far the drawn river may deviate from its nodes, relative to its
+/// width, so wide rivers are simplified more than streams.
+const RIVER_SIMPLIFICATION_FACTOR: f32 = 0.1;
+
 fn get_river_trajectory(
     node_locations: &HashMap<u64, GeoLocation>,
     river: &RiverFeature,
     offset: &Offset
 ) -> Option<Vec<Vec2>> {
-    if river.nodes.len() < 2 {
-        return None;
-    }
-    river
-        .nodes
-        .iter()
-        .map(|node_id| {
-            Some(node_locations.get(node_id)?.project(&offset))
-        })
-        .collect()
+    project_nodes(node_locations, &river.nodes, offset).filter(|path| path.len() >= 2)
 }
 
 pub fn create_river_data(
@@ -36,22 +36,36 @@ pub fn create_river_data(
         }
         let river: Vec<Vec2> = river.unwrap_throw();
 
-        let width = determine_width(&river_feature);
+        let width = offset.scale.units(determine_width(&river_feature));
+        let river = simplify_polyline(river, width * RIVER_SIMPLIFICATION_FACTOR);
         let uv_range = asset_cache.get_river_uv();
 
-        generate_trajectory(
-            river, 
-            width, 
-            0.005,  // Make river appear under roads and lakes to avoid z-fighting
-            uv_range,
-            &mut mesh_builder, 
-            asset_cache,
-        );
+        // Aqueducts are waterways on a bridge
+        match get_bridge_height(&river_feature.tags, &offset.scale) {
+            Some(deck_height) => generate_bridge(
+                river,
+                width,
+                RIVER_HEIGHT,
+                deck_height,
+                uv_range,
+                &mut mesh_builder,
+                &offset.scale,
+            ),
+            None => generate_trajectory(
+                river, 
+                width, 
+                RIVER_HEIGHT,
+                uv_range,
+                &mut mesh_builder, 
+                asset_cache,
+            ),
+        }
     }
     mesh_builder.into_mesh()
 }
 
-/// Determine the width of the river based on the tags, return in meters
+/// Determine the width of the river based on the tags, return in world units
+/// at the default scale
 fn determine_width(river: &RiverFeature) -> f32 {
     // Check if CEMT tag is present and use that
     if river.tags.contains_key("CEMT") {
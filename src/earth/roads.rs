@@ -1,75 +1,403 @@
 use bevy::prelude::*;
 use std::collections::hash_map::HashMap;
+use std::ops::RangeInclusive;
 use std::str::FromStr;
 use wasm_bindgen::prelude::*;
 
-use crate::data::geography::{GeoLocation, Offset, RoadFeature};
+use crate::data::geography::{
+    close_ring, project_nodes, GeoLocation, LoadedBounds, Offset, RoadFeature, WorldScale,
+};
 use crate::data::road_type::{
-    road_type_to_default_lanes, road_type_to_width, RoadType, road_type_to_random_height
+    road_type_to_default_lanes, road_type_to_width, RoadType, road_type_to_random_height,
+    road_type_to_simplification_tolerance,
 };
+use crate::data::traffic_graph::is_access_allowed;
 use crate::earth::assets::AssetCache;
 use crate::earth::mesh_builder::MeshBuilder;
-use super::trajectory::generate_trajectory;
-use super::GLOBAL_SCALE_FACTOR;
+use crate::earth::simplification::simplify_polyline;
+use crate::lod::LodMesh;
+use super::trajectory::{
+    generate_bridge, generate_stub, generate_trajectory, get_bridge_height, get_tunnel_depth,
+};
+use super::SIZE_EXAGGERATION;
+
+/// Roads ending within this distance of the edge of the loaded data are
+/// assumed to be cut off by the query. In world units at the default scale.
+pub const BOUNDARY_MARGIN: f32 = 10.0;
+
+/// Length of the fading continuation drawn at roads that are cut off, in world
+/// units at the default scale.
+const STUB_LENGTH: f32 = 8.0;
+
+// Tag that marks a closed highway way as an area, e.g. a square
+const TAG_AREA: &str = "area";
+
+// Tag that tells whether a road has street lighting
+const TAG_LIT: &str = "lit";
+
+// Tag that tells who may use a road
+const TAG_ACCESS: &str = "access";
+
+/// Height of the patches that fill junctions, above all roads for cars but
+/// below footways.
+const JUNCTION_HEIGHT: f32 = 0.0175;
+
+/// The number of corners of the polygon that fills a junction.
+const JUNCTION_SEGMENTS: u32 = 12;
+
+/// Rough number of vertices and indices of a road per node, to make room for
+/// them up front: the corners of a quad, of which half are shared with the
+/// next quad, and its two triangles.
+const ESTIMATED_VERTICES_PER_NODE: usize = 2;
+const ESTIMATED_INDICES_PER_NODE: usize = 6;
+
+/// A fading continuation of a road that ends at the edge of the loaded data,
+/// see `generate_stub`.
+#[derive(Clone, Debug)]
+pub struct RoadStub {
+    /// Where the road ends, and the direction in which it continues.
+    pub end: Vec2,
+    pub direction: Vec2,
+    pub width: f32,
+    pub y: f32,
+    pub uv: (f32, RangeInclusive<f32>),
+}
+
+/// The stubs that an entity shows, relative to its translation. Stubs are
+/// removed once more data is loaded around them, see `update_road_stubs`.
+#[derive(Component, Debug)]
+pub struct RoadStubs(pub Vec<RoadStub>);
+
+/// Returns the mesh of the given stubs.
+pub fn create_stub_mesh(stubs: &[RoadStub], scale: &WorldScale) -> Mesh {
+    let mut stubs = Vec::new();
+    for stub in stubs {
+        generate_stub(
+            stub.end,
+            stub.direction,
+            stub.width,
+            scale.units(STUB_LENGTH),
+            stub.y,
+            stub.uv.clone(),
+            &mut stub_builder,
+        );
+    }
+    stub_builder.into_mesh()
+}
+
+/// A system that removes the stubs of roads that are no longer at the edge
+/// of the loaded data, after data around them was loaded. Entities without
+/// stubs left are removed.
+pub fn update_road_stubs(
+    mut commands: Commands,
+    bounds: Res<LoadedBounds>,
+    scale: Res<WorldScale>,
+    mut stubs: Query<(Entity, &mut RoadStubs, &Transform, &Handle<Mesh>)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    if !bounds.is_changed() {
+        return;
+    }
+    let margin = scale.units(BOUNDARY_MARGIN);
+    for (entity, mut road_stubs, transform, mesh) in &mut stubs {
+        let count = road_stubs.0.len();
+        let origin = transform.translation.xz();
+        road_stubs.0.retain(|stub| bounds.is_near_boundary(origin + stub.end, margin));
+        if road_stubs.0.is_empty() {
+            commands.entity(entity).despawn_recursive();
+        } else if road_stubs.0.len() != count {
+            meshes.insert(mesh, create_stub_mesh(&road_stubs.0, &scale));
+        }
+    }
+}
 
-/// Creates a building base from a list of nodes. 
-/// Returns None if any of the nodes are not found or the number of nodes is less than 3
+/// Creates the path of a road from its list of nodes, skipping nodes that are
+/// not found. Returns None if too many nodes are missing or fewer than 2
+/// nodes are left.
 fn create_road_base(
     node_locations: &HashMap<u64, GeoLocation>,
     road: &RoadFeature,
     offset: &Offset
 ) -> Option<Vec<Vec2>> {
-    if road.nodes.len() < 2 {
-        return None;
-    }
-    road
-        .nodes
-        .iter()
-        .map(|node_id| {
-            Some(node_locations.get(node_id)?.project(&offset))
-        })
-        .collect()
+    project_nodes(node_locations, &road.nodes, offset).filter(|path| path.len() >= 2)
 }
 
 /// Converts the road features in the given chunks to data that can be drawn in
 /// the world (meshes and materials).
+/// 
+/// Returns the mesh of the roads, the mesh of the roads with street lighting,
+/// the fading stubs at roads that are cut off at the edge of the loaded data,
+/// and the mesh of the roads in tunnels, which are below the
+/// ground. The roads and lit roads also come with a far version, with a
+/// single quad per segment of a road and without junctions.
 pub fn create_road_data(
     node_locations: &HashMap<u64, GeoLocation>,
     road_features: &HashMap<u64, RoadFeature>,
     asset_cache: &AssetCache,
-    offset: &Offset
-) -> Mesh {
-    let mut mesh_builder = MeshBuilder::new();
+    offset: &Offset,
+    bounds: &LoadedBounds,
+) -> (LodMesh, LodMesh, Vec<RoadStub>, Mesh) {
+    // The quads of a road share the corners where they meet
+    let (mut nodes, mut lit_nodes) = (0, 0);
+    for road_feature in road_features.values() {
+        if is_road_lit(road_feature) {
+            lit_nodes += road_feature.nodes.len();
+        } else {
+            nodes += road_feature.nodes.len();
+        }
+    }
+    let builder = |nodes: usize| {
+        MeshBuilder::with_capacity(nodes * ESTIMATED_VERTICES_PER_NODE, nodes * ESTIMATED_INDICES_PER_NODE)
+            .with_deduplication()
+    };
+    let mut mesh_builder = builder(nodes);
+    let mut lit_builder = builder(lit_nodes);
+    let mut far_builder = builder(nodes);
+    let mut far_lit_builder = builder(lit_nodes);
+    let mut stub_builder = MeshBuilder::new();
+    let mut tunnel_builder = MeshBuilder::new();
+    let scale = &offset.scale;
+
+    // For every node, the number of roads that use it and the widest of them
+    let mut junctions: HashMap<u64, (usize, RoadType, f32)> = HashMap::new();
+
     for (_, road_feature) in road_features {
+        // Pedestrian areas like squares are closed ways that are drawn as
+        // flat polygons instead of as a road
+        if road_feature.tags.get(TAG_AREA).map_or(false, |value| value == "yes") {
+            add_road_area(node_locations, road_feature, asset_cache, offset, &mut mesh_builder);
+            continue;
+        }
+
+        let (road_type, width) = get_road_type_and_width(road_feature, scale);
+        // Curves of fast roads can have a node every few meters, which are
+        // not needed to draw them
+        let tolerance = scale.meters(road_type_to_simplification_tolerance(&road_type));
+
+        // Private roads are drawn in a muted color
+        let uv_range = if is_road_private(road_feature) {
+            asset_cache.get_private_road_uv(road_type)
+        } else {
+            asset_cache.get_road_uv(road_type)
+        };
+
+        // Tunnels are not drawn on the surface, and do not meet the roads
+        // there
+        if let Some(depth) = get_tunnel_depth(&road_feature.tags, scale) {
+            if let Some(road) = create_road_base(node_locations, road_feature, offset) {
+                generate_trajectory(
+                    simplify_polyline(road, tolerance),
+                    width,
+                    road_type_to_random_height(&road_type) - depth,
+                    uv_range,
+                    &mut tunnel_builder,
+                    asset_cache,
+                );
+            }
+            continue;
+        }
+
+        let bridge_height = get_bridge_height(&road_feature.tags, scale);
+
+        // Only the ends of a bridge are on the ground, where it can meet
+        // other roads
+        let junction_nodes = match (bridge_height, road_feature.nodes.first(), road_feature.nodes.last()) {
+            (Some(_), Some(&first), Some(&last)) => vec![first, last],
+            _ => close_ring(&road_feature.nodes),
+        };
+        for &node_id in &junction_nodes {
+            let junction = junctions.entry(node_id).or_insert((0, road_type, width));
+            junction.0 += 1;
+            if width > junction.2 {
+                junction.1 = road_type;
+                junction.2 = width;
+            }
+        }
+
         let road: Option<Vec<Vec2>> = create_road_base(node_locations, road_feature, offset);
 
         if road.is_none() {
             continue;
         }
-        let road: Vec<Vec2> = road.unwrap_throw();
+        let road: Vec<Vec2> = simplify_polyline(road.unwrap_throw(), tolerance);
         // println!("Road: {:?}", road);
 
-        // Convert to road type
-        let road_type = RoadType::from_str(&road_feature.tags["highway"])
-            .unwrap_or(RoadType::NotCovered);
-        
-        // Ridiculous high value will be fixed
-        let lanes = road_feature.tags.get("lanes")
-            .and_then(|s| s.parse::<u32>().ok())
-            .unwrap_or(road_type_to_default_lanes(&road_type));  // Ridiculous high value will be fixed
-        
-        let width = road_type_to_width(&road_type) * 0.01 * lanes as f32 * GLOBAL_SCALE_FACTOR;
-        let uv_range = asset_cache.get_road_uv(road_type);
         let y = road_type_to_random_height(&road_type); 
 
-        generate_trajectory(
-            road, 
-            width,             
-            y,  // Make road appear under buildings to avoid z-fighting
-            uv_range,
-            &mut mesh_builder, 
-            asset_cache,
+        // Continue roads that end at the data boundary with a fading stub
+        let last = road.len() - 1;
+        let ends = [(road[0], road[0] - road[1]), (road[last], road[last] - road[last - 1])];
+        for (end, direction) in ends {
+            if bounds.is_near_boundary(end, scale.units(BOUNDARY_MARGIN)) {
+                stubs.push(RoadStub {
+                    end,
+                    direction,
+                    width,
+                    y,
+                    uv: asset_cache.get_road_stub_uv(road_type),
+                });
+            }
+        }
+
+        // Lit roads glow at night, so they are drawn with another material
+        let (builder, far_builder) = if is_road_lit(road_feature) {
+            (&mut lit_builder, &mut far_lit_builder)
+        } else {
+            (&mut mesh_builder, &mut far_builder)
+        };
+        let far_uv = Vec2::new(*uv_range.0.start(), *uv_range.1.start());
+        add_far_road(&road, width, y + bridge_height.unwrap_or(0.0), far_uv, far_builder);
+        match bridge_height {
+            Some(deck_height) => generate_bridge(
+                road,
+                width,
+                y,
+                deck_height,
+                uv_range,
+                builder,
+                scale,
+            ),
+            None => generate_trajectory(
+                road, 
+                width,             
+                y,  // Make road appear under buildings to avoid z-fighting
+                uv_range,
+                builder, 
+                asset_cache,
+            ),
+        }
+    }
+
+    // Fill the junctions, where road ribbons meet, with a patch as wide as
+    // the widest road
+    for (node_id, (count, road_type, width)) in junctions {
+        if count < 2 {
+            continue;
+        }
+        let Some(location) = node_locations.get(&node_id) else {
+            continue;
+        };
+        let (u_range, v_range) = asset_cache.get_road_uv(road_type);
+        let uv = Vec2::new(*u_range.start(), *v_range.start());
+        add_junction_patch(location.project(offset), width / 2.0, uv, &mut mesh_builder);
+    }
+
+    (
+        LodMesh {
+            detailed: mesh_builder.into_mesh(),
+            far: far_builder.into_mesh(),
+        },
+        LodMesh {
+            detailed: lit_builder.into_mesh(),
+            far: far_lit_builder.into_mesh(),
+        },
+        stubs,
+        tunnel_builder.into_mesh(),
+    )
+}
+
+/// Returns whether a road has street lighting. Roads without the tag are
+/// assumed to be dark, like roads tagged `lit=no`.
+///
+/// # See also
+/// https://wiki.openstreetmap.org/wiki/Key:lit
+fn is_road_lit(road_feature: &RoadFeature) -> bool {
+    matches!(
+        road_feature.tags.get(TAG_LIT).map(String::as_str),
+        Some("yes" | "24/7" | "automatic" | "sunset-sunrise")
+    )
+}
+
+/// Returns whether the public may not use a road, e.g. a private driveway.
+///
+/// # See also
+/// https://wiki.openstreetmap.org/wiki/Key:access
+fn is_road_private(road_feature: &RoadFeature) -> bool {
+    road_feature.tags.get(TAG_ACCESS).and_then(|value| is_access_allowed(value)) == Some(false)
+}
+
+/// Returns the type of a road and its total width over all lanes, at the given
+/// scale of the world.
+fn get_road_type_and_width(road_feature: &RoadFeature, scale: &WorldScale) -> (RoadType, f32) {
+    // Convert to road type
+    let road_type = RoadType::from_str(&road_feature.tags["highway"])
+        .unwrap_or(RoadType::NotCovered);
+
+    // Ridiculous high value will be fixed
+    let lanes = road_feature.tags.get("lanes")
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or(road_type_to_default_lanes(&road_type));  // Ridiculous high value will be fixed
+
+    let width = scale.meters(road_type_to_width(&road_type) * lanes as f32) * SIZE_EXAGGERATION;
+    (road_type, width)
+}
+
+/// Adds a road as seen from far away: a flat quad for every segment, without
+/// smoothed corners or railings. Bridges are drawn at the height of their
+/// deck over their whole length.
+fn add_far_road(road: &[Vec2], width: f32, y: f32, uv: Vec2, mesh_builder: &mut MeshBuilder) {
+    for segment in road.windows(2) {
+        let direction = (segment[1] - segment[0]).normalize_or_zero();
+        if direction == Vec2::ZERO {
+            continue;
+        }
+        let side = direction.perp() * width / 2.0;
+        let to_3d = |point: Vec2| Vec3::new(point.x, y, point.y);
+        mesh_builder.add_quad(
+            [
+                to_3d(segment[0] + side),
+                to_3d(segment[1] + side),
+                to_3d(segment[1] - side),
+                to_3d(segment[0] - side),
+            ],
+            [uv; 4],
         );
     }
-    mesh_builder.into_mesh()
+}
+
+/// Adds a flat, round polygon with the given radius around a junction.
+fn add_junction_patch(center: Vec2, radius: f32, uv: Vec2, mesh_builder: &mut MeshBuilder) {
+    let points: Vec<_> = (0..JUNCTION_SEGMENTS)
+        .map(|i| {
+            let angle = i as f32 / JUNCTION_SEGMENTS as f32 * std::f32::consts::TAU;
+            let point = center + Vec2::from_angle(angle) * radius;
+            geo::Point::new(point.x as f64, point.y as f64)
+        })
+        .collect();
+    let polygon = geo::Polygon::new(points.into(), vec![]);
+    // only a zero radius makes the circle invalid, and then there is nothing
+    // to cover anyway
+    let _ = mesh_builder.add_polygon_xz(&polygon, JUNCTION_HEIGHT, uv);
+}
+
+/// Adds a road area (e.g. a square or plaza) as a flat polygon. Ways that are
+/// not closed or that cross themselves are ignored.
+fn add_road_area(
+    node_locations: &HashMap<u64, GeoLocation>,
+    road_feature: &RoadFeature,
+    asset_cache: &AssetCache,
+    offset: &Offset,
+    mesh_builder: &mut MeshBuilder,
+) {
+    let nodes = &road_feature.nodes;
+    if nodes.len() < 4 || nodes.first() != nodes.last() {
+        return;
+    }
+    let Some(area) = project_nodes(node_locations, &close_ring(nodes), offset)
+        .filter(|area| area.len() >= 3)
+    else {
+        return;
+    };
+
+    let road_type = RoadType::from_str(&road_feature.tags["highway"])
+        .unwrap_or(RoadType::NotCovered);
+    let (u_range, v_range) = asset_cache.get_road_uv(road_type);
+    let uv = Vec2::new(
+        (u_range.start() + u_range.end()) / 2.0,
+        (v_range.start() + v_range.end()) / 2.0,
+    );
+
+    let points: Vec<_> = area.iter().map(|point| geo::Point::new(point.x as f64, point.y as f64)).collect();
+    let polygon = geo::Polygon::new(points.into(), vec![]);
+    let _ = mesh_builder.add_polygon_xz(&polygon, road_type_to_random_height(&road_type), uv);
 }
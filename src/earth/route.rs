@@ -0,0 +1,296 @@
+//! Routes between two points, to check the paths that the router finds for
+//! agents. While "Pick route" is on, the first click on the ground is the
+//! start of a route and the second its end, or both are entered as latitude
+//! and longitude. Both ends snap to the nearest vertex of the traffic graph,
+//! and the shortest path for the chosen agent type is drawn as a bright line
+//! above the roads, with its length and travel time shown in the panel.
+
+use crate::common::{AppError, StatusEvent};
+use crate::data::geography::{GeoLocation, Offset, WorldScale};
+use crate::data::traffic_graph::TrafficGraph;
+use crate::earth::agent::AgentType;
+use crate::earth::assets::AssetCache;
+use crate::earth::mesh_builder::MeshBuilder;
+use crate::earth::trajectory::generate_trajectory;
+use crate::player::Player;
+
+use bevy::pbr::{NotShadowCaster, NotShadowReceiver};
+use bevy::prelude::*;
+use petgraph::graph::NodeIndex;
+
+// Lengths are in world units at the default scale, see `WorldScale::units`.
+
+/// How high the line of a route is drawn above the ground, so it is not
+/// hidden by roads, bridges or agents.
+const ROUTE_HEIGHT: f32 = 3.0;
+
+/// The width of the line of a route.
+const ROUTE_WIDTH: f32 = 1.5;
+
+/// How far a picked point may be from the nearest vertex of the traffic graph.
+const MAX_SNAP_DISTANCE: f32 = 200.0;
+
+/// The color of the line of a route, which stands out against the map.
+const ROUTE_COLOR: Color = Color::rgb(1.0, 0.1, 0.85);
+
+/// Picks or clears the ends of a route.
+#[derive(Clone, Debug, Event)]
+pub enum RouteEvent {
+    /// Picks an end of the route where the given position of the cursor in
+    /// the window hits the ground.
+    Pick(Vec2),
+    /// Finds the route from the first location to the second.
+    Between(GeoLocation, GeoLocation),
+    /// Removes the route.
+    Clear,
+}
+
+/// A route found by the router.
+#[derive(Clone, Debug)]
+pub struct Route {
+    /// The vertices of the traffic graph that the route goes through.
+    pub nodes: Vec<NodeIndex>,
+    pub agent_type: AgentType,
+    /// The length of the route, in meters.
+    pub length: f32,
+    /// How long agents of the type take to travel the route, in seconds,
+    /// without waiting at traffic signals or for other cars.
+    pub travel_time: f32,
+}
+
+impl Route {
+    /// Returns the length and travel time of the route, for the panel.
+    ///
+    /// ```
+    /// use city_visualizer::earth::agent::AgentType;
+    /// use city_visualizer::earth::route::Route;
+    ///
+    /// let route = Route { nodes: Vec::new(), agent_type: AgentType::Car, length: 2450.0, travel_time: 201.0 };
+    /// assert_eq!(route.summary(), "2.45 km, about 3 min 21 s by car");
+    ///
+    /// let route = Route { nodes: Vec::new(), agent_type: AgentType::Pedestrian, length: 320.0, travel_time: 42.4 };
+    /// assert_eq!(route.summary(), "320 m, about 42 s by pedestrian");
+    /// ```
+    pub fn summary(&self) -> String {
+        let length = if self.length >= 1000.0 {
+            format!("{:.2} km", self.length / 1000.0)
+        } else {
+            format!("{:.0} m", self.length)
+        };
+        let seconds = self.travel_time.round() as u64;
+        let time = match (seconds / 3600, seconds % 3600 / 60, seconds % 60) {
+            (0, 0, seconds) => format!("{} s", seconds),
+            (0, minutes, seconds) => format!("{} min {} s", minutes, seconds),
+            (hours, minutes, _) => format!("{} h {} min", hours, minutes),
+        };
+        format!("{}, about {} by {}", length, time, self.agent_type.name())
+    }
+}
+
+/// The route that is shown, and the settings to find it with.
+#[derive(Resource)]
+pub struct RoutePlanner {
+    /// Whether clicks in the view pick the ends of a route, rather than
+    /// moving the camera.
+    pub picking: bool,
+    /// The agent type to find the route for. Changing it finds the route
+    /// between the same ends again.
+    pub agent_type: AgentType,
+    /// The text of the start and end fields in the panel, as latitude and
+    /// longitude.
+    pub start_text: String,
+    pub end_text: String,
+    /// The picked start of the next route.
+    start: Option<NodeIndex>,
+    route: Option<Route>,
+    /// The generation of the traffic graph that the vertices are in.
+    graph_generation: u32,
+    overlay: Option<Entity>,
+    material: Option<Handle<StandardMaterial>>,
+}
+
+impl Default for RoutePlanner {
+    fn default() -> Self {
+        RoutePlanner {
+            picking: false,
+            agent_type: AgentType::Car,
+            start_text: String::new(),
+            end_text: String::new(),
+            start: None,
+            route: None,
+            graph_generation: 0,
+            overlay: None,
+            material: None,
+        }
+    }
+}
+
+impl RoutePlanner {
+    /// Returns the route that is shown, if any.
+    pub fn route(&self) -> Option<&Route> {
+        self.route.as_ref()
+    }
+
+    /// Returns whether the start of a route was picked, and its end is next.
+    pub fn has_start(&self) -> bool {
+        self.start.is_some()
+    }
+
+    /// Removes the route and the picked start.
+    fn clear(&mut self, commands: &mut Commands) {
+        self.start = None;
+        self.route = None;
+        if let Some(entity) = self.overlay.take() {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// A system that picks the ends of routes, finds the route between them and
+/// draws it. Picking a start after a route was found clears that route.
+/// Paths are found right away rather than in a task, since only one is
+/// found at a time.
+pub fn update_routes(
+    mut commands: Commands,
+    mut route_events: EventReader<RouteEvent>,
+    mut planner: ResMut<RoutePlanner>,
+    traffic_graph: Res<TrafficGraph>,
+    cameras: Query<(&Camera, &GlobalTransform), With<Player>>,
+    (offset, scale, asset_cache): (Res<Offset>, Res<WorldScale>, Res<AssetCache>),
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let planner = &mut *planner;
+    // vertices from before a reset of the graph no longer exist
+    if planner.graph_generation != traffic_graph.get_generation() {
+        planner.graph_generation = traffic_graph.get_generation();
+        planner.clear(&mut commands);
+    }
+
+    let snap = |point: Vec2, status_events: &mut EventWriter<StatusEvent>| {
+        let node = traffic_graph.nearest_node(point, scale.units(MAX_SNAP_DISTANCE));
+        if node.is_none() {
+            status_events.send(StatusEvent::Error(AppError::MissingData {
+                message: "there is no road near the end of the route".to_owned(),
+            }));
+        }
+        node
+    };
+
+    let mut ends = None;
+    for event in route_events.read() {
+        match event {
+            RouteEvent::Pick(cursor) => {
+                let Some(point) = ground_point(&cameras, *cursor) else {
+                    status_events.send(StatusEvent::Error(AppError::MissingData {
+                        message: "pick the ends of the route on the ground".to_owned(),
+                    }));
+                    continue;
+                };
+                let Some(node) = snap(point, &mut status_events) else {
+                    continue;
+                };
+                match planner.start {
+                    Some(start) if planner.route.is_none() => ends = Some((start, node)),
+                    _ => {
+                        planner.clear(&mut commands);
+                        planner.start = Some(node);
+                        status_events.send(StatusEvent::Update("Pick the end of the route".to_owned()));
+                    }
+                }
+            }
+            RouteEvent::Between(from, to) => {
+                planner.clear(&mut commands);
+                let from = snap(from.project(&offset), &mut status_events);
+                let to = snap(to.project(&offset), &mut status_events);
+                if let (Some(from), Some(to)) = (from, to) {
+                    ends = Some((from, to));
+                }
+            }
+            RouteEvent::Clear => planner.clear(&mut commands),
+        }
+    }
+
+    // a route for another agent type is found again between the same ends
+    if let Some(route) = planner.route.as_ref().filter(|route| route.agent_type != planner.agent_type) {
+        ends = route.nodes.first().copied().zip(route.nodes.last().copied());
+    }
+    let Some((from, to)) = ends else {
+        return;
+    };
+    planner.clear(&mut commands);
+
+    if from == to {
+        status_events.send(StatusEvent::Error(AppError::MissingData {
+            message: "the start and end of the route snap to the same crossing".to_owned(),
+        }));
+        return;
+    }
+    let agent_type = planner.agent_type;
+    let Some(nodes) = traffic_graph.get_shortest_path(from, to, agent_type) else {
+        status_events.send(StatusEvent::Error(AppError::MissingData {
+            message: format!("there is no route by {} between these points", agent_type.name()),
+        }));
+        return;
+    };
+
+    let (mut length, mut travel_time) = (0.0, 0.0);
+    for pair in nodes.windows(2) {
+        let edge = traffic_graph.get_edge_data(pair[0], pair[1]);
+        length += edge.length();
+        travel_time += edge.length() / edge.speed_for(agent_type, &scale);
+    }
+    let route = Route {
+        nodes,
+        agent_type,
+        length: length / scale.units_per_meter,
+        travel_time,
+    };
+    status_events.send(StatusEvent::Update(format!("Found a route of {}", route.summary())));
+
+    let mut mesh_builder = MeshBuilder::new();
+    generate_trajectory(
+        route.nodes.iter().map(|&node| traffic_graph.get_node_location(node)).collect(),
+        scale.units(ROUTE_WIDTH),
+        scale.units(ROUTE_HEIGHT),
+        (0.0..=0.0, 0.0..=0.0),
+        &mut mesh_builder,
+        &asset_cache,
+    );
+    let material = planner
+        .material
+        .get_or_insert_with(|| {
+            materials.add(StandardMaterial {
+                base_color: ROUTE_COLOR,
+                unlit: true,
+                ..default()
+            })
+        })
+        .clone();
+    let entity = commands
+        .spawn((
+            PbrBundle {
+                mesh: meshes.add(mesh_builder.into_mesh()),
+                material,
+                ..default()
+            },
+            NotShadowCaster,
+            NotShadowReceiver,
+        ))
+        .id();
+    planner.overlay = Some(entity);
+    planner.route = Some(route);
+}
+
+/// Returns where the ray through the given position of the cursor hits the
+/// ground, or None if it does not.
+fn ground_point(cameras: &Query<(&Camera, &GlobalTransform), With<Player>>, cursor: Vec2) -> Option<Vec2> {
+    let (camera, camera_transform) = cameras.get_single().ok()?;
+    let ray = camera.viewport_to_world(camera_transform, cursor)?;
+    if ray.direction.y >= -f32::EPSILON {
+        return None;
+    }
+    let point = ray.get_point(-ray.origin.y / ray.direction.y);
+    Some(Vec2::new(point.x, point.z))
+}
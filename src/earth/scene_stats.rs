@@ -0,0 +1,250 @@
+//! Statistics of what is in the world: how much of the data was converted,
+//! and how many vertices, triangles and entities were generated from it.
+//!
+//! Useful to see what loading a city actually produced, and to notice when
+//! generation suddenly produces a lot more geometry than before.
+
+use crate::commands::CommandRegistry;
+use crate::data::geography::{FeatureType, ParseReport};
+use crate::data::traffic_graph::TrafficGraph;
+use crate::earth::layers::FeatureLayer;
+use crate::earth::pipeline_timings::{format_time, PipelineStage, PipelineTimings};
+use crate::earth::trees::TreeChunk;
+use crate::earth::GenerationQueue;
+
+use bevy::prelude::*;
+use bevy::render::mesh::Indices;
+use bevy_egui::egui;
+use bevy_egui::EguiContexts;
+
+use std::collections::HashMap;
+
+/// The number of vertices and triangles of generated meshes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MeshCounts {
+    pub vertices: usize,
+    pub triangles: usize,
+}
+
+impl MeshCounts {
+    /// Returns the counts of a triangle list mesh.
+    ///
+    /// ```
+    /// use bevy::prelude::*;
+    /// use city_visualizer::earth::scene_stats::MeshCounts;
+    ///
+    /// let counts = MeshCounts::of(&Mesh::from(Plane3d::default()));
+    /// assert_eq!(counts, MeshCounts { vertices: 4, triangles: 2 });
+    /// ```
+    pub fn of(mesh: &Mesh) -> Self {
+        let vertices = mesh.count_vertices();
+        MeshCounts {
+            vertices,
+            triangles: mesh.indices().map_or(vertices, Indices::len) / 3,
+        }
+    }
+}
+
+/// Statistics of the data of the last load, and of the meshes in the world.
+/// Updated by the systems that handle the results of generation tasks.
+///
+/// Agents share their meshes, so they are only counted as entities. Trees
+/// are counted by the merged meshes they are shown with in the distance.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use city_visualizer::earth::layers::FeatureLayer;
+/// use city_visualizer::earth::scene_stats::SceneStats;
+///
+/// let mut stats = SceneStats::default();
+/// let mesh = Mesh::from(Plane3d::default());
+/// stats.add_mesh(FeatureLayer::Roads, &mesh);
+/// stats.add_mesh(FeatureLayer::Roads, &mesh);
+///
+/// assert_eq!(stats.meshes(FeatureLayer::Roads).triangles, 4);
+/// assert_eq!(stats.meshes(FeatureLayer::Buildings).triangles, 0);
+/// stats.clear();
+/// assert_eq!(stats.meshes(FeatureLayer::Roads).vertices, 0);
+/// ```
+#[derive(Debug, Default, Resource)]
+pub struct SceneStats {
+    /// What was converted of the data of the last load, summed over all data
+    /// of that load.
+    pub loaded: ParseReport,
+    /// The number of buildings of the last load that were skipped because
+    /// their footprint crosses itself or has no area.
+    pub invalid_footprints: usize,
+    meshes: HashMap<FeatureLayer, MeshCounts>,
+    /// Whether the statistics window is shown.
+    pub panel_visible: bool,
+}
+
+impl SceneStats {
+    /// Forgets the data of the previous load.
+    pub fn start_load(&mut self) {
+        self.loaded = ParseReport::default();
+        self.invalid_footprints = 0;
+    }
+
+    /// Adds the report of converting data to the statistics of the current
+    /// load.
+    pub fn add_report(&mut self, report: &ParseReport) {
+        let loaded = &mut self.loaded;
+        loaded.nodes += report.nodes;
+        loaded.ways += report.ways;
+        loaded.skipped_relations += report.skipped_relations;
+        loaded.missing_nodes += report.missing_nodes;
+        loaded.dropped_features += report.dropped_features;
+        loaded.parse_time += report.parse_time;
+        loaded.warnings.extend_from_slice(&report.warnings);
+        loaded
+            .oversized_features
+            .extend_from_slice(&report.oversized_features);
+        for (&feature_type, &count) in &report.feature_ways {
+            *loaded.feature_ways.entry(feature_type).or_default() += count;
+        }
+    }
+
+    /// Adds a mesh that was spawned in the world.
+    pub fn add_mesh(&mut self, layer: FeatureLayer, mesh: &Mesh) {
+        let counts = MeshCounts::of(mesh);
+        let total = self.meshes.entry(layer).or_default();
+        total.vertices += counts.vertices;
+        total.triangles += counts.triangles;
+    }
+
+    /// Returns the counts of all meshes of a layer in the world.
+    pub fn meshes(&self, layer: FeatureLayer) -> MeshCounts {
+        self.meshes.get(&layer).copied().unwrap_or_default()
+    }
+
+    /// Removes the counts of the meshes, when the world is cleared.
+    pub fn clear(&mut self) {
+        self.meshes.clear();
+    }
+
+    /// Removes the counts of the meshes of a layer, when they are generated
+    /// again.
+    pub fn clear_layer(&mut self, layer: FeatureLayer) {
+        self.meshes.remove(&layer);
+    }
+}
+
+/// A system that registers the commands for scene statistics.
+pub fn register_scene_stats_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle scene statistics",
+        "Shows how much data was loaded, and how many entities, vertices and triangles were generated from it",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut scene_stats = world.resource_mut::<SceneStats>();
+                scene_stats.panel_visible = !scene_stats.panel_visible;
+            })
+        },
+    );
+}
+
+/// A system that shows the statistics of the world and the timings of the
+/// last load.
+pub fn update_scene_stats_window(
+    mut contexts: EguiContexts,
+    scene_stats: Res<SceneStats>,
+    timings: Res<PipelineTimings>,
+    generation_queue: Res<GenerationQueue>,
+    traffic_graph: Res<TrafficGraph>,
+    features: Query<&FeatureLayer>,
+    tree_chunks: Query<&TreeChunk>,
+) {
+    if !scene_stats.panel_visible {
+        return;
+    }
+
+    let mut entities: HashMap<FeatureLayer, usize> = HashMap::new();
+    for &layer in &features {
+        *entities.entry(layer).or_default() += 1;
+    }
+
+    egui::Window::new("Scene statistics").show(contexts.ctx_mut(), |ui| {
+        let loaded = &scene_stats.loaded;
+        ui.collapsing("Last load", |ui| {
+            egui::Grid::new("scene_stats_data_grid").show(ui, |ui| {
+                ui.label("Nodes");
+                ui.label(loaded.nodes.to_string());
+                ui.end_row();
+                ui.label("Ways");
+                ui.label(loaded.ways.to_string());
+                ui.end_row();
+                for feature_type in FeatureType::ALL {
+                    let count = loaded.feature_ways.get(&feature_type).copied().unwrap_or(0);
+                    ui.label(format!("  {}", feature_type.name()));
+                    ui.label(count.to_string());
+                    ui.end_row();
+                }
+                ui.label("Skipped relations");
+                ui.label(loaded.skipped_relations.to_string());
+                ui.end_row();
+                ui.label("Dropped features");
+                ui.label(loaded.dropped_features.to_string());
+                ui.end_row();
+                ui.label("Oversized features");
+                ui.label(loaded.oversized_features.len().to_string());
+                ui.end_row();
+                ui.label("Invalid footprints");
+                ui.label(scene_stats.invalid_footprints.to_string());
+                ui.end_row();
+            });
+            ui.separator();
+            if timings.is_loading() {
+                ui.label(format!(
+                    "Generating: {} chunks queued, {} tasks running",
+                    generation_queue.queued(),
+                    generation_queue.in_flight(),
+                ));
+            } else {
+                match timings.total() {
+                    Some(total) => ui.label(format!("Generated in {}", format_time(total))),
+                    None => ui.label("Nothing was loaded yet"),
+                };
+            }
+            egui::Grid::new("scene_stats_timings_grid").show(ui, |ui| {
+                for stage in PipelineStage::ALL {
+                    ui.label(stage.name());
+                    ui.label(format_time(timings.get(stage)));
+                    ui.end_row();
+                }
+            });
+        });
+
+        ui.collapsing("World", |ui| {
+            egui::Grid::new("scene_stats_world_grid").show(ui, |ui| {
+                ui.strong("Layer");
+                ui.strong("Entities");
+                ui.strong("Vertices");
+                ui.strong("Triangles");
+                ui.end_row();
+                for layer in FeatureLayer::ALL {
+                    let meshes = scene_stats.meshes(layer);
+                    ui.label(layer.name());
+                    ui.label(entities.get(&layer).copied().unwrap_or(0).to_string());
+                    ui.label(meshes.vertices.to_string());
+                    ui.label(meshes.triangles.to_string());
+                    ui.end_row();
+                }
+            });
+            ui.separator();
+            let trees: usize = tree_chunks.iter().map(TreeChunk::tree_count).sum();
+            let cells: usize = tree_chunks.iter().map(TreeChunk::cell_count).sum();
+            let detailed: usize = tree_chunks.iter().map(TreeChunk::detailed_count).sum();
+            ui.label(format!(
+                "Trees: {} in {} cells, {} spawned up close",
+                trees, cells, detailed,
+            ));
+            ui.label(format!(
+                "Traffic graph: {} nodes, {} edges",
+                traffic_graph.get_size(),
+                traffic_graph.get_edge_count(),
+            ));
+        });
+    });
+}
@@ -1,56 +1,110 @@
 use bevy::math::Vec2;
 
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
 /// Simplifies a polygon by removing points that are not significant.
-/// Uses Visvalingam–Whyatt to maintain the shape of the polygon.
+/// Uses Visvalingam–Whyatt to maintain the shape of the polygon: the point
+/// that spans the smallest triangle with its neighbours is removed, as long
+/// as that area is at most `threshold` and more than 4 points are left. Of
+/// points with equal areas, the first one is removed.
+///
+/// The areas are kept in a heap, and the remaining points in a linked list,
+/// so that simplifying takes O(n log n) time. Entries of the heap are not
+/// removed when the area of their point changes, but skipped when they come
+/// up.
 pub fn simplify_polygon(polygon: Vec<Vec2>, threshold: f32) -> Vec<Vec2> {
-    let mut polygon = polygon;
-
     // If the polygon is too small, return it as is
     if polygon.len() < 4 {
         return polygon;
     }
 
+    let count = polygon.len();
+    let mut previous: Vec<usize> = (0..count).map(|i| (i + count - 1) % count).collect();
+    let mut next: Vec<usize> = (0..count).map(|i| (i + 1) % count).collect();
+    let mut removed = vec![false; count];
+
     // precompute areas spanned by all triangles
-    let mut areas = Vec::with_capacity(polygon.len());
-    let mut previous_point = polygon[polygon.len() - 1];
-    let mut current_point = polygon[0];
-    let mut next_point = polygon[1];
-    for i in 0..polygon.len() {
-        areas.push(triangle_area(previous_point, current_point, next_point));
-
-        previous_point = current_point;
-        current_point = next_point;
-        next_point = polygon[(i + 2) % polygon.len()];
-    }
+    let mut areas: Vec<f32> = (0..count)
+        .map(|i| triangle_area(polygon[previous[i]], polygon[i], polygon[next[i]]))
+        .collect();
+    let mut heap: BinaryHeap<Reverse<AreaEntry>> = areas
+        .iter()
+        .enumerate()
+        .map(|(index, &area)| Reverse(AreaEntry { area, index }))
+        .collect();
 
     // Go over all triangles, remove the one with the smallest area
-    while polygon.len() > 4 {
-        let mut min_area = f32::MAX;
-        let mut min_index = 0;
-        for i in 0..areas.len() {
-            if areas[i] < min_area {
-                min_area = areas[i];
-                min_index = i;
-            }
+    let mut remaining = count;
+    while remaining > 4 {
+        let Some(Reverse(entry)) = heap.pop() else {
+            break;
+        };
+        // Skip entries of removed points and of areas that changed since
+        if removed[entry.index] || entry.area.to_bits() != areas[entry.index].to_bits() {
+            continue;
         }
 
-        // Remove the point with the smallest area if its under threshold
-        if min_area > threshold {
+        // Remove the point with the smallest area if its under threshold,
+        // where areas that are not a number are never removed
+        if !(entry.area < f32::MAX) || entry.area > threshold {
             break;
         }
 
         // Remove the point
-        polygon.remove(min_index);
-        areas.remove(min_index);
+        let (before, after) = (previous[entry.index], next[entry.index]);
+        removed[entry.index] = true;
+        next[before] = after;
+        previous[after] = before;
+        remaining -= 1;
 
         // Update the areas
-        let previous_index = (min_index + polygon.len() - 1) % polygon.len();
-        let next_index = min_index % polygon.len();
-        areas[previous_index] = calculate_triangle_area_at_pos(&polygon, previous_index);
-        areas[next_index] = calculate_triangle_area_at_pos(&polygon, next_index);
+        for index in [before, after] {
+            let area = triangle_area(
+                polygon[previous[index]],
+                polygon[index],
+                polygon[next[index]],
+            );
+            areas[index] = area;
+            heap.push(Reverse(AreaEntry { area, index }));
+        }
     }
 
     polygon
+        .into_iter()
+        .zip(removed)
+        .filter_map(|(point, removed)| (!removed).then_some(point))
+        .collect()
+}
+
+/// The area of the triangle at a point of a polygon, ordered by the area and
+/// then by the index of the point.
+#[derive(Clone, Copy, Debug)]
+struct AreaEntry {
+    area: f32,
+    index: usize,
+}
+
+impl PartialEq for AreaEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for AreaEntry {}
+
+impl PartialOrd for AreaEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for AreaEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.area
+            .total_cmp(&other.area)
+            .then(self.index.cmp(&other.index))
+    }
 }
 
 fn triangle_area(previous_point: Vec2, current_point: Vec2, next_point: Vec2) -> f32 {
@@ -60,13 +114,85 @@ fn triangle_area(previous_point: Vec2, current_point: Vec2, next_point: Vec2) ->
         .abs()
 }
 
-fn calculate_triangle_area_at_pos(polygon: &Vec<Vec2>, i: usize) -> f32 {
-    let previous_point = polygon[(i + polygon.len() - 1) % polygon.len()];
-    let current_point = polygon[i];
-    let next_point = polygon[(i + 1) % polygon.len()];
-    let area = triangle_area(previous_point, current_point, next_point);
-    area
+/// Simplifies an open line, such as a road or a river, by removing points
+/// that are not significant. Uses Douglas–Peucker, so no point of the
+/// original line is further than `tolerance` from the simplified line, and
+/// the first and last points are always kept.
+///
+/// ```
+/// use bevy::math::Vec2;
+/// use city_visualizer::earth::simplification::simplify_polyline;
+///
+/// // a quarter circle with a point every half a unit
+/// let radius = 100.0;
+/// let arc: Vec<Vec2> = (0..=314)
+///     .map(|i| Vec2::from_angle(i as f32 / 314.0 * std::f32::consts::FRAC_PI_2) * radius)
+///     .collect();
+/// let tolerance = 0.5;
+/// let simplified = simplify_polyline(arc.clone(), tolerance);
+///
+/// assert!(simplified.len() < arc.len() / 10);
+/// assert_eq!(simplified.first(), arc.first());
+/// assert_eq!(simplified.last(), arc.last());
+/// let deviation = arc
+///     .iter()
+///     .map(|&point| {
+///         simplified
+///             .windows(2)
+///             .map(|segment| {
+///                 let along = segment[1] - segment[0];
+///                 let t = (point - segment[0]).dot(along) / along.length_squared();
+///                 point.distance(segment[0] + along * t.clamp(0.0, 1.0))
+///             })
+///             .fold(f32::MAX, f32::min)
+///     })
+///     .fold(0.0, f32::max);
+/// assert!(deviation <= tolerance);
+/// ```
+pub fn simplify_polyline(polyline: Vec<Vec2>, tolerance: f32) -> Vec<Vec2> {
+    if polyline.len() < 3 {
+        return polyline;
+    }
+
+    // Split ranges at their furthest point until every range is close enough
+    // to the line between its ends
+    let mut keep = vec![false; polyline.len()];
+    keep[0] = true;
+    keep[polyline.len() - 1] = true;
+    let mut ranges = vec![(0, polyline.len() - 1)];
+    while let Some((first, last)) = ranges.pop() {
+        let mut max_distance = 0.0;
+        let mut max_index = first;
+        for i in first + 1..last {
+            let distance = distance_to_segment(polyline[i], polyline[first], polyline[last]);
+            if distance > max_distance {
+                max_distance = distance;
+                max_index = i;
+            }
+        }
+
+        if max_distance > tolerance {
+            keep[max_index] = true;
+            ranges.push((first, max_index));
+            ranges.push((max_index, last));
+        }
+    }
+
+    polyline
+        .into_iter()
+        .zip(keep)
+        .filter_map(|(point, keep)| keep.then_some(point))
+        .collect()
 }
 
-// pub fn simplify_polyline(polyline: Vec<Vec2>) -> Vec<Vec2> {
-// }
+/// Returns the distance from `point` to the closest point of the segment
+/// from `start` to `end`.
+fn distance_to_segment(point: Vec2, start: Vec2, end: Vec2) -> f32 {
+    let along = end - start;
+    let length_squared = along.length_squared();
+    if length_squared == 0.0 {
+        return point.distance(start);
+    }
+    let t = ((point - start).dot(along) / length_squared).clamp(0.0, 1.0);
+    point.distance(start + along * t)
+}
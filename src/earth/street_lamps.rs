@@ -0,0 +1,163 @@
+//! Street lamps at nodes tagged `highway=street_lamp`, which light up at
+//! night.
+//!
+//! Real lights are expensive, so only the lamps closest to the player get
+//! one; the heads of all other lamps only glow.
+//!
+//! # See also
+//! https://wiki.openstreetmap.org/wiki/Tag:highway%3Dstreet_lamp
+
+use crate::data::geography::{Chunk, GeoLocation, Offset, WorldScale};
+use crate::earth::assets::{AssetCache, STREET_LAMP_HEIGHT, STREET_LAMP_LIGHT_COLOR};
+use crate::earth::day_night::TimeOfDay;
+use crate::earth::layers::FeatureLayer;
+use crate::earth::GeoFeature;
+use crate::player::Player;
+
+use bevy::prelude::*;
+
+use std::collections::HashMap;
+
+/// The maximum number of street lamps that cast light at the same time.
+const MAX_LAMP_LIGHTS: usize = 64;
+
+/// Street lamps further away from the player than this do not cast light, in
+/// world units at the default scale.
+const MAX_LAMP_LIGHT_DISTANCE: f32 = 300.0;
+
+/// How far the light of a street lamp reaches, in world units at the default
+/// scale.
+const LAMP_LIGHT_RANGE: f32 = 25.0;
+
+/// The intensity of the light of a street lamp, in lumens at the default
+/// scale. Light falls off with the square of the distance, so it grows with
+/// the square of the scale to light the same part of the street.
+const LAMP_LIGHT_INTENSITY: f32 = 200_000.0;
+
+/// Marks the head of a street lamp, which glows at night.
+#[derive(Component, Debug)]
+pub struct StreetLampHead;
+
+/// Marks one of the point lights that are moved to the street lamps closest
+/// to the player at night.
+#[derive(Component, Debug)]
+pub struct StreetLampLight;
+
+/// Adds the street lamps at the nodes of a chunk that are tagged as such.
+pub fn add_street_lamps(
+    commands: &mut Commands,
+    chunk: &Chunk,
+    node_locations: &HashMap<u64, GeoLocation>,
+    offset: &Offset,
+    asset_cache: &AssetCache,
+) {
+    for (&id, node) in &chunk.nodes {
+        if node.tags.get("highway").map(String::as_str) != Some("street_lamp") {
+            continue;
+        }
+        let Some(location) = node_locations.get(&id) else {
+            continue;
+        };
+
+        let position = location.project(offset);
+        commands
+            .spawn((
+                PbrBundle {
+                    mesh: asset_cache.get_street_lamp_mesh(),
+                    material: asset_cache.get_street_lamp_material(),
+                    // the head is a child, so it is scaled along
+                    transform: Transform::from_xyz(position.x, 0.0, position.y)
+                        .with_scale(Vec3::splat(offset.scale.units(1.0))),
+                    ..default()
+                },
+                GeoFeature { id },
+                FeatureLayer::Roads,
+            ))
+            .with_children(|parent| {
+                parent.spawn((
+                    PbrBundle {
+                        mesh: asset_cache.get_street_lamp_head_mesh(),
+                        material: asset_cache.get_street_lamp_head_material(false),
+                        transform: Transform::from_xyz(0.0, STREET_LAMP_HEIGHT, 0.0),
+                        ..default()
+                    },
+                    StreetLampHead,
+                ));
+            });
+    }
+}
+
+/// A system that adds the point lights that are used by street lamps. They
+/// are hidden until it is night.
+pub fn setup_street_lamp_lights(mut commands: Commands, scale: Res<WorldScale>) {
+    for _ in 0..MAX_LAMP_LIGHTS {
+        commands.spawn((
+            PointLightBundle {
+                point_light: PointLight {
+                    color: STREET_LAMP_LIGHT_COLOR,
+                    intensity: scale.area(LAMP_LIGHT_INTENSITY),
+                    range: scale.units(LAMP_LIGHT_RANGE),
+                    shadows_enabled: false,
+                    ..default()
+                },
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+            StreetLampLight,
+        ));
+    }
+}
+
+/// A system that turns street lamps on at night and off during the day. At
+/// night, the point lights are moved to the lit lamps closest to the player.
+pub fn update_street_lamps(
+    time_of_day: Res<TimeOfDay>,
+    asset_cache: Res<AssetCache>,
+    players: Query<&Transform, With<Player>>,
+    mut heads: Query<(&GlobalTransform, &mut Handle<StandardMaterial>), With<StreetLampHead>>,
+    mut lights: Query<(&mut Transform, &mut Visibility), (With<StreetLampLight>, Without<Player>)>,
+    scale: Res<WorldScale>,
+) {
+    let night = time_of_day.is_night();
+    let head_material = asset_cache.get_street_lamp_head_material(night);
+    for (_, mut material) in heads.iter_mut() {
+        if *material != head_material {
+            *material = head_material.clone();
+        }
+    }
+
+    // find the lamps closest to the player, during the day there are none
+    let mut nearest: Vec<(f32, Vec3)> = Vec::new();
+    let player = if night { players.get_single().ok() } else { None };
+    if let Some(player) = player {
+        let max_distance = scale.units(MAX_LAMP_LIGHT_DISTANCE);
+        let player_position = Vec2::new(player.translation.x, player.translation.z);
+        nearest = heads
+            .iter()
+            .map(|(transform, _)| {
+                let position = transform.translation();
+                (Vec2::new(position.x, position.z).distance_squared(player_position), position)
+            })
+            .filter(|(distance, _)| *distance < max_distance * max_distance)
+            .collect();
+        if nearest.len() > MAX_LAMP_LIGHTS {
+            nearest.select_nth_unstable_by(MAX_LAMP_LIGHTS, |a, b| a.0.total_cmp(&b.0));
+            nearest.truncate(MAX_LAMP_LIGHTS);
+        }
+    }
+
+    for (index, (mut transform, mut visibility)) in lights.iter_mut().enumerate() {
+        let new_visibility = match nearest.get(index) {
+            Some((_, position)) => {
+                if transform.translation != *position {
+                    transform.translation = *position;
+                }
+                Visibility::Visible
+            }
+            None => Visibility::Hidden,
+        };
+        if *visibility != new_visibility {
+            *visibility = new_visibility;
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! Telemetry of agents, to analyze traffic flows outside of the app. While
+//! recording, the location, road type and speed of every agent is sampled at
+//! an interval of simulated time. When recording stops, the samples are
+//! saved as CSV: to a file on native, and as a download in the web version.
+//!
+//! Samples are kept in memory until they are saved, at most
+//! `MAX_TELEMETRY_ROWS` of them. Once that many are kept, the oldest are
+//! dropped, and a warning is shown once.
+
+use crate::commands::CommandRegistry;
+#[cfg(target_arch = "wasm32")]
+use crate::common::download_file;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::common::{handle_compute_tasks, spawn_compute_task, AsyncComputation};
+use crate::common::{AppError, StatusEvent};
+use crate::data::geography::{GeoLocation, Offset};
+use crate::data::road_type::{road_type_to_tag, RoadType};
+use crate::earth::agent::{Agent, AgentType};
+use crate::earth::SimulationSettings;
+
+use bevy::ecs::system::CommandQueue;
+use bevy::prelude::*;
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// The number of samples that are kept, older samples are dropped. About 64
+/// MB of memory, or a quarter of an hour of 1000 agents sampled every second.
+pub const MAX_TELEMETRY_ROWS: usize = 1_000_000;
+
+/// The default time between samples, in simulated seconds.
+pub const DEFAULT_SAMPLE_INTERVAL: f32 = 1.0;
+
+/// The range of the time between samples that can be chosen in the UI, in
+/// simulated seconds.
+pub const SAMPLE_INTERVAL_RANGE: std::ops::RangeInclusive<f32> = 0.1..=60.0;
+
+/// The first line of the CSV, with the names of the columns.
+const CSV_HEADER: &str = "time_s,agent_id,agent_type,latitude,longitude,road_type,speed_m_s";
+
+/// A sample of an agent.
+#[derive(Clone, Debug)]
+pub struct TelemetryRow {
+    /// The simulated time since recording started, in seconds.
+    pub time: f32,
+    /// The id of the agent, which is unique within a run of the app.
+    pub agent_id: u64,
+    pub agent_type: AgentType,
+    pub location: GeoLocation,
+    /// The type of the road the agent travels on, or `None` if it is waiting
+    /// for a path.
+    pub road_type: Option<RoadType>,
+    /// The average speed since the previous sample of the agent, in meters
+    /// per simulated second, or `None` for the first sample of an agent.
+    pub speed: Option<f32>,
+}
+
+impl TelemetryRow {
+    /// Appends the row as a line of CSV.
+    fn write_csv(&self, csv: &mut String) {
+        let _ = write!(
+            csv,
+            "{:.2},{},{},{:.7},{:.7},{},",
+            self.time,
+            self.agent_id,
+            self.agent_type.name(),
+            self.location.latitude,
+            self.location.longitude,
+            self.road_type.as_ref().map_or("", road_type_to_tag),
+        );
+        if let Some(speed) = self.speed {
+            let _ = write!(csv, "{:.2}", speed);
+        }
+        csv.push('\n');
+    }
+}
+
+/// The recorded samples of agents, and whether they are being recorded.
+///
+/// ```
+/// use city_visualizer::data::geography::GeoLocation;
+/// use city_visualizer::data::road_type::RoadType;
+/// use city_visualizer::earth::agent::AgentType;
+/// use city_visualizer::earth::telemetry::{AgentTelemetry, TelemetryRow};
+///
+/// let mut telemetry = AgentTelemetry::with_capacity(3);
+/// telemetry.start();
+/// for time in 0..2 {
+///     for agent_id in [1, 2] {
+///         telemetry.push(TelemetryRow {
+///             time: time as f32,
+///             agent_id,
+///             agent_type: AgentType::Car,
+///             location: GeoLocation { latitude: 51.44, longitude: 5.47 },
+///             road_type: Some(RoadType::Residential),
+///             speed: (time > 0).then_some(8.5),
+///         });
+///     }
+/// }
+///
+/// // the oldest sample was dropped to make room
+/// assert_eq!(telemetry.dropped(), 1);
+/// let csv = telemetry.to_csv();
+/// let lines: Vec<_> = csv.lines().collect();
+/// assert_eq!(lines[0], "time_s,agent_id,agent_type,latitude,longitude,road_type,speed_m_s");
+/// assert_eq!(lines[1], "0.00,2,car,51.4400000,5.4700000,residential,");
+/// assert_eq!(lines[3], "1.00,2,car,51.4400000,5.4700000,residential,8.50");
+/// assert_eq!(lines.len(), 4);
+/// ```
+#[derive(Debug, Resource)]
+pub struct AgentTelemetry {
+    /// The time between samples, in simulated seconds.
+    pub interval: f32,
+    recording: bool,
+    rows: VecDeque<TelemetryRow>,
+    capacity: usize,
+    /// The number of samples that were dropped since recording started.
+    dropped: usize,
+    /// The simulated time since recording started.
+    elapsed: f32,
+    /// The simulated time at which the next sample is taken.
+    next_sample: f32,
+    /// The time and location of the last sample of every agent, to find
+    /// their speed.
+    last_samples: HashMap<Entity, (f32, GeoLocation)>,
+}
+
+impl Default for AgentTelemetry {
+    fn default() -> Self {
+        AgentTelemetry::with_capacity(MAX_TELEMETRY_ROWS)
+    }
+}
+
+impl AgentTelemetry {
+    /// Returns telemetry that keeps at most `capacity` samples.
+    pub fn with_capacity(capacity: usize) -> Self {
+        AgentTelemetry {
+            interval: DEFAULT_SAMPLE_INTERVAL,
+            recording: false,
+            rows: VecDeque::new(),
+            capacity,
+            dropped: 0,
+            elapsed: 0.0,
+            next_sample: 0.0,
+            last_samples: HashMap::new(),
+        }
+    }
+
+    /// Starts recording, dropping the samples of the previous recording.
+    pub fn start(&mut self) {
+        self.recording = true;
+        self.rows.clear();
+        self.dropped = 0;
+        self.elapsed = 0.0;
+        self.next_sample = 0.0;
+        self.last_samples.clear();
+    }
+
+    /// Stops recording. The samples are kept until recording starts again.
+    pub fn stop(&mut self) {
+        self.recording = false;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Adds a sample, dropping the oldest one if the buffer is full. Returns
+    /// whether a sample was dropped.
+    pub fn push(&mut self, row: TelemetryRow) -> bool {
+        let full = self.rows.len() >= self.capacity;
+        if full {
+            self.rows.pop_front();
+            self.dropped += 1;
+        }
+        if self.capacity > 0 {
+            self.rows.push_back(row);
+        }
+        full
+    }
+
+    /// Returns the number of samples that are kept.
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// Returns the number of samples that were dropped because the buffer was
+    /// full.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Returns all samples, from old to new.
+    pub fn rows(&self) -> impl Iterator<Item = &TelemetryRow> {
+        self.rows.iter()
+    }
+
+    /// Returns the samples as CSV, with a header line.
+    pub fn to_csv(&self) -> String {
+        // about 70 bytes per line
+        let mut csv = String::with_capacity((self.rows.len() + 1) * 70);
+        csv.push_str(CSV_HEADER);
+        csv.push('\n');
+        for row in &self.rows {
+            row.write_csv(&mut csv);
+        }
+        csv
+    }
+}
+
+/// A system that samples all agents while recording, every interval of
+/// simulated time. A warning is shown when the buffer is full for the first
+/// time.
+pub fn update_agent_telemetry(
+    time: Res<Time>,
+    simulation: Res<SimulationSettings>,
+    mut telemetry: ResMut<AgentTelemetry>,
+    agents: Query<(Entity, &Agent, &Transform)>,
+    offset: Res<Offset>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    if !telemetry.recording {
+        return;
+    }
+    telemetry.elapsed += simulation.delta_seconds(&time);
+    if telemetry.elapsed < telemetry.next_sample {
+        return;
+    }
+    let now = telemetry.elapsed;
+    // samples are taken an interval apart, unless frames take longer
+    let interval = telemetry.interval.max(f32::EPSILON);
+    telemetry.next_sample += interval;
+    if telemetry.next_sample <= now {
+        telemetry.next_sample = now + interval;
+    }
+
+    let mut last_samples = HashMap::with_capacity(telemetry.last_samples.len());
+    let mut warn = false;
+    for (entity, agent, transform) in agents.iter() {
+        let position = Vec2::new(transform.translation.x, transform.translation.z);
+        let location = GeoLocation::unproject(position, &offset);
+        // both locations are projected with the current offset, since the
+        // origin may have moved since the last sample
+        let speed = telemetry
+            .last_samples
+            .get(&entity)
+            .filter(|(last_time, _)| now > *last_time)
+            .map(|(last_time, last_location)| {
+                let units = position.distance(last_location.project(&offset)) as f64;
+                (units * location.meters_per_unit(&offset)) as f32 / (now - last_time)
+            });
+        last_samples.insert(entity, (now, location.clone()));
+
+        let row = TelemetryRow {
+            time: now,
+            agent_id: entity.to_bits(),
+            agent_type: agent.agent_type,
+            location,
+            road_type: agent.next_path_location_edge.map(|(_, edge)| edge.road_type()),
+            speed,
+        };
+        warn |= telemetry.push(row) && telemetry.dropped() == 1;
+    }
+    // agents that were removed are forgotten
+    telemetry.last_samples = last_samples;
+
+    if warn {
+        status_events.send(StatusEvent::Update(format!(
+            "Warning: the agent telemetry keeps at most {} samples, the oldest samples are dropped",
+            telemetry.capacity,
+        )));
+    }
+}
+
+/// An event that saves the recorded telemetry as CSV to the given path. In
+/// the web version only the file name is used, for the download.
+#[derive(Clone, Debug, Event)]
+pub struct ExportTelemetryEvent {
+    pub path: PathBuf,
+}
+
+/// The result of a telemetry export task: the path of the written file, and
+/// the number of samples in it.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct TelemetryExport(Result<(PathBuf, usize), AppError>);
+
+/// A system that saves the recorded telemetry for every
+/// `ExportTelemetryEvent`. On native the file is written by a task.
+pub fn start_telemetry_export(
+    #[cfg(not(target_arch = "wasm32"))] mut commands: Commands,
+    mut export_events: EventReader<ExportTelemetryEvent>,
+    telemetry: Res<AgentTelemetry>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let Some(event) = export_events.read().last().cloned() else {
+        return;
+    };
+    if telemetry.is_empty() {
+        status_events.send(StatusEvent::Error(AppError::MissingData {
+            message: "record agent telemetry before saving it".to_owned(),
+        }));
+        return;
+    }
+    let rows = telemetry.len();
+    let csv = telemetry.to_csv();
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let path = event.path;
+        spawn_compute_task(&mut commands, async move {
+            TelemetryExport(match std::fs::write(&path, csv) {
+                Ok(()) => Ok((path, rows)),
+                Err(error) => Err(AppError::from_io_error(error, &path)),
+            })
+        });
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        let name = event.path.file_name().map_or_else(
+            || "agent_telemetry.csv".to_owned(),
+            |name| name.to_string_lossy().into_owned(),
+        );
+        download_file(&name, "text/csv", csv.as_bytes());
+        status_events.send(StatusEvent::Update(format!(
+            "Saved {} agent samples to {}",
+            rows, name,
+        )));
+    }
+}
+
+/// A system that reports the result of saving the telemetry.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn update_telemetry_export(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<TelemetryExport>)>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    handle_compute_tasks(&mut commands, query, |_, TelemetryExport(result)| match result {
+        Ok((path, rows)) => {
+            status_events.send(StatusEvent::Update(format!(
+                "Saved {} agent samples to {}",
+                rows,
+                path.display(),
+            )));
+        }
+        Err(error) => {
+            status_events.send(StatusEvent::Error(error));
+        }
+    });
+}
+
+/// The file that was chosen in the save dialog, or `None` if the dialog was
+/// cancelled.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct TelemetryExportTarget(Option<PathBuf>);
+
+/// Saves the recorded telemetry. On native a dialog asks where to write it
+/// to, without blocking the frame, and the chosen file is handled by
+/// `update_telemetry_export_dialog`. In the web version the CSV is
+/// downloaded right away.
+pub fn request_telemetry_export(commands: &mut Commands) {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let dialog = rfd::AsyncFileDialog::new()
+            .set_title("Save the agent telemetry")
+            .add_filter("CSV", &["csv"])
+            .set_file_name("agent_telemetry.csv");
+        spawn_compute_task(commands, async move {
+            TelemetryExportTarget(dialog.save_file().await.map(|file| file.path().to_owned()))
+        });
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    commands.add(|world: &mut World| {
+        world.send_event(ExportTelemetryEvent {
+            path: PathBuf::from("agent_telemetry.csv"),
+        });
+    });
+}
+
+/// A system that saves the telemetry to the file that was chosen in the save
+/// dialog.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn update_telemetry_export_dialog(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<TelemetryExportTarget>)>,
+    mut export_events: EventWriter<ExportTelemetryEvent>,
+) {
+    handle_compute_tasks(&mut commands, query, |_, TelemetryExportTarget(path)| {
+        if let Some(path) = path {
+            export_events.send(ExportTelemetryEvent { path });
+        }
+    });
+}
+
+/// Starts recording telemetry, or stops it and saves the samples.
+pub fn toggle_telemetry_recording(commands: &mut Commands) {
+    commands.add(|world: &mut World| {
+        let mut telemetry = world.resource_mut::<AgentTelemetry>();
+        if telemetry.is_recording() {
+            telemetry.stop();
+            let rows = telemetry.len();
+            world.send_event(StatusEvent::Update(format!(
+                "Stopped recording agent telemetry with {} samples",
+                rows,
+            )));
+            if rows > 0 {
+                let mut queue = CommandQueue::default();
+                request_telemetry_export(&mut Commands::new(&mut queue, world));
+                queue.apply(world);
+            }
+        } else {
+            telemetry.start();
+            world.send_event(StatusEvent::Update(
+                "Started recording agent telemetry".to_owned(),
+            ));
+        }
+    });
+}
+
+/// A system that registers the commands of agent telemetry.
+pub fn register_telemetry_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle agent telemetry recording",
+        "Starts sampling the location, road type and speed of all agents, or stops and saves the samples as CSV",
+        None,
+        |commands| toggle_telemetry_recording(commands),
+    );
+    registry.register(
+        "Save agent telemetry",
+        "Saves the samples of the last telemetry recording as CSV",
+        None,
+        |commands| request_telemetry_export(commands),
+    );
+}
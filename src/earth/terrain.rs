@@ -1,6 +1,6 @@
 
-use crate::data::geography::{GeoLocation, LandUseFeature, Offset};
-use crate::earth::GLOBAL_SCALE_FACTOR;
+use crate::data::geography::{close_ring, project_nodes, GeoLocation, LandUseFeature, Offset};
+use crate::earth::generation_settings::GenerationSettings;
 use crate::earth::mesh_builder::MeshBuilder;
 use crate::earth::simplification::simplify_polygon;
 use wasm_bindgen::prelude::*;
@@ -12,11 +12,8 @@ use bevy::prelude::*;
 // Import randon
 use rand::Rng;
 
-// Amount of trees per area
-const DENSITY: f32 = 0.05;
-
 // NOTE: higher than for e.g. buildings
-const TERRAIN_SIMPLIFICATION_THRESHOLD: f32 = 0.0001 * GLOBAL_SCALE_FACTOR * GLOBAL_SCALE_FACTOR;
+const TERRAIN_SIMPLIFICATION_THRESHOLD: f32 = 1.0;
 
 // Used following color scheme:
 // https://www.schemecolor.com/tree-green-brown.php
@@ -26,22 +23,23 @@ fn generate_trees(
     node_locations: &HashMap<u64, GeoLocation>,
     feature: &LandUseFeature,
     offset: &Offset,
+    tree_density: f32,
     tree_transforms: &mut Vec<Transform>,
 ) {
-    let area: Vec<Vec2> = feature
-        .nodes
-        .iter()
-        .filter_map(|node_id| node_locations.get(node_id).map(|node| node.project(&offset)))
-        .collect();
-    let area_simplified = simplify_polygon(area, TERRAIN_SIMPLIFICATION_THRESHOLD);
+    let Some(area) = project_nodes(node_locations, &close_ring(&feature.nodes), offset)
+        .filter(|area| area.len() >= 3)
+    else {
+        return;
+    };
+    let area_simplified = simplify_polygon(area, offset.scale.area(TERRAIN_SIMPLIFICATION_THRESHOLD));
 
-    let points = get_random_points_in_polygon(&area_simplified, DENSITY);  
+    let points = get_random_points_in_polygon(&area_simplified, tree_density / offset.scale.area(1.0));
 
     for point in points.iter() {
         let rotation =
             Quat::from_rotation_y(rand::thread_rng().gen_range(0.0..std::f32::consts::PI));
 
-        let scale: f32 = rand::thread_rng().gen_range(0.015..0.025) * GLOBAL_SCALE_FACTOR; 
+        let scale = offset.scale.units(rand::thread_rng().gen_range(1.5..2.5));
         let scale: Vec3 = Vec3::new(scale, scale, scale);
 
         // Random position
@@ -144,7 +142,8 @@ fn get_random_point_in_triangle(
     Vec2::new(x, z)
 }
 
-/// Get the triangulation of the area
+/// Get the triangulation of the area, which is empty if the area crosses
+/// itself
 fn get_triangles(
     area: &Vec<Vec2>,
 ) -> Vec<[Vec3; 3]> {
@@ -154,16 +153,20 @@ fn get_triangles(
     // Triangulate
     let mut mesh_builder = MeshBuilder::new();
     let uv = Vec2::new(0.0, 0.0);  // TODO tweak?
-    mesh_builder.add_polygon_xz(&polygon, 0.0, uv);  // Up normal
+    if mesh_builder.add_polygon_xz(&polygon, 0.0, uv).is_err() {  // Up normal
+        return Vec::new();
+    }
     mesh_builder.get_triangles()
 }
 
 /// Creates the terrain data within one chunk. Returns a list of transforms for
-/// trees that have to be placed, and a list of meshes for grass areas.
+/// trees that have to be placed, as many as the tree density of `settings`
+/// says, and a list of meshes for grass areas.
 pub fn create_terrain_data(
     node_locations: &HashMap<u64, GeoLocation>,
     land_use_features: &HashMap<u64, LandUseFeature>,
-    offset: &Offset
+    offset: &Offset,
+    settings: &GenerationSettings,
 ) -> (Vec<Transform>, Vec<Mesh>) {
     let mut tree_transforms = Vec::new();
     let mut grass_areas = Vec::new();
@@ -179,13 +182,14 @@ pub fn create_terrain_data(
                 node_locations,
                 feature,
                 offset,
+                settings.tree_density,
                 &mut tree_transforms,
             );
         }
 
         // Generate grass area
         if landuse == "forest" || landuse == "wood" || landuse == "grass" {
-            grass_areas.push(generate_area(node_locations, feature, offset));
+            grass_areas.extend(generate_area(node_locations, feature, offset));
         }
     }
     (tree_transforms, grass_areas)
@@ -195,12 +199,9 @@ fn generate_area(
     node_locations: &HashMap<u64, GeoLocation>,
     feature: &LandUseFeature,
     offset: &Offset,
-) -> Mesh {
-    let area: Vec<Vec2> = feature
-        .nodes
-        .iter()
-        .filter_map(|node_id| node_locations.get(node_id).map(|node| node.project(&offset)))
-        .collect();
+) -> Option<Mesh> {
+    let area = project_nodes(node_locations, &close_ring(&feature.nodes), offset)
+        .filter(|area| area.len() >= 3)?;
 
     // Render green area plane
     let temp: Vec<_> = area.iter().map(|vec2| geo::Point::new(vec2.x as f64, vec2.y as f64)).collect();
@@ -208,6 +209,6 @@ fn generate_area(
 
     let mut mesh_builder = MeshBuilder::new();
     let uv = Vec2::new(0.0, 0.0);  // TODO tweak?
-    mesh_builder.add_polygon_xz(&polygon, 0.002, uv);  // Render under lakes
-    mesh_builder.into_mesh()
+    mesh_builder.add_polygon_xz(&polygon, 0.002, uv).ok()?;  // Render under lakes
+    Some(mesh_builder.into_mesh())
 }
@@ -0,0 +1,165 @@
+//! Traffic lights at junctions tagged `highway=traffic_signals`, which let
+//! cars from two directions take turns.
+//!
+//! # See also
+//! https://wiki.openstreetmap.org/wiki/Tag:highway%3Dtraffic_signals
+
+use crate::data::geography::{Chunk, GeoLocation, Offset};
+use crate::earth::assets::AssetCache;
+use crate::earth::layers::FeatureLayer;
+use crate::earth::{GeoFeature, SimulationSettings};
+
+use bevy::prelude::*;
+
+use std::collections::HashMap;
+
+/// How long each direction has a green light, in seconds.
+const SIGNAL_PHASE_DURATION: f32 = 8.0;
+
+/// Cars start braking for a red light this far before the junction, in world
+/// units at the default scale.
+pub const SIGNAL_BRAKING_DISTANCE: f32 = 8.0;
+
+/// Cars wait for a red light this far before the junction, in world units at
+/// the default scale.
+pub const SIGNAL_STOP_DISTANCE: f32 = 3.0;
+
+/// The direction from which a junction is approached. Traffic lights give
+/// the two axes green in turns.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Approach {
+    NorthSouth,
+    EastWest,
+}
+
+impl Approach {
+    /// Returns the approach of something moving in the given direction.
+    pub fn from_direction(direction: Vec2) -> Self {
+        if direction.x.abs() > direction.y.abs() {
+            Approach::EastWest
+        } else {
+            Approach::NorthSouth
+        }
+    }
+}
+
+/// The state of the traffic lights at a single junction.
+#[derive(Clone, Debug)]
+pub struct TrafficSignal {
+    /// The time since the last phase change, in seconds.
+    timer: f32,
+    /// The approach that currently has a green light.
+    green: Approach,
+}
+
+impl TrafficSignal {
+    pub fn is_green(&self, approach: Approach) -> bool {
+        self.green == approach
+    }
+}
+
+/// All junctions with traffic lights, by OSM node id.
+#[derive(Debug, Default, Resource)]
+pub struct TrafficSignals {
+    signals: HashMap<u64, TrafficSignal>,
+}
+
+impl TrafficSignals {
+    /// Adds a junction with traffic lights. The phase is derived from the
+    /// node id, so not all junctions change at the same time.
+    pub fn insert(&mut self, osm_id: u64) {
+        let start = (osm_id % 1000) as f32 / 1000.0 * 2.0 * SIGNAL_PHASE_DURATION;
+        let (timer, green) = if start < SIGNAL_PHASE_DURATION {
+            (start, Approach::NorthSouth)
+        } else {
+            (start - SIGNAL_PHASE_DURATION, Approach::EastWest)
+        };
+        self.signals.insert(osm_id, TrafficSignal { timer, green });
+    }
+
+    pub fn get(&self, osm_id: u64) -> Option<&TrafficSignal> {
+        self.signals.get(&osm_id)
+    }
+
+    /// Removes all junctions.
+    pub fn clear(&mut self) {
+        self.signals.clear();
+    }
+}
+
+/// Marks the entity of a traffic light, which shows the light for traffic
+/// approaching from the north or south.
+#[derive(Component, Debug)]
+pub struct TrafficLight {
+    osm_id: u64,
+}
+
+/// Adds the traffic lights at the nodes of a chunk that are tagged as such.
+pub fn add_traffic_signals(
+    commands: &mut Commands,
+    chunk: &Chunk,
+    node_locations: &HashMap<u64, GeoLocation>,
+    offset: &Offset,
+    asset_cache: &AssetCache,
+    traffic_signals: &mut TrafficSignals,
+) {
+    for (&id, node) in &chunk.nodes {
+        if node.tags.get("highway").map(String::as_str) != Some("traffic_signals") {
+            continue;
+        }
+        let Some(location) = node_locations.get(&id) else {
+            continue;
+        };
+        traffic_signals.insert(id);
+
+        let position = location.project(offset);
+        commands.spawn((
+            PbrBundle {
+                mesh: asset_cache.get_traffic_light_mesh(),
+                material: asset_cache.get_traffic_light_material(true),
+                transform: Transform::from_xyz(position.x, 0.0, position.y)
+                    .with_scale(Vec3::splat(offset.scale.units(1.0))),
+                ..default()
+            },
+            TrafficLight { osm_id: id },
+            GeoFeature { id },
+            FeatureLayer::Roads,
+        ));
+    }
+}
+
+/// A system that advances the phases of all traffic lights, and updates the
+/// color of the traffic light entities.
+pub fn update_traffic_signals(
+    time: Res<Time>,
+    mut traffic_signals: ResMut<TrafficSignals>,
+    mut lights: Query<(&TrafficLight, &mut Handle<StandardMaterial>)>,
+    asset_cache: Res<AssetCache>,
+    simulation: Res<SimulationSettings>,
+) {
+    if traffic_signals.signals.is_empty() || simulation.paused {
+        return;
+    }
+
+    let delta_seconds = simulation.delta_seconds(&time);
+    for signal in traffic_signals.signals.values_mut() {
+        signal.timer += delta_seconds;
+        if signal.timer >= SIGNAL_PHASE_DURATION {
+            signal.timer -= SIGNAL_PHASE_DURATION;
+            signal.green = match signal.green {
+                Approach::NorthSouth => Approach::EastWest,
+                Approach::EastWest => Approach::NorthSouth,
+            };
+        }
+    }
+
+    for (light, mut material) in lights.iter_mut() {
+        let Some(signal) = traffic_signals.get(light.osm_id) else {
+            continue;
+        };
+        let new_material = asset_cache.get_traffic_light_material(signal.is_green(Approach::NorthSouth));
+        if *material != new_material {
+            *material = new_material;
+        }
+    }
+}
@@ -1,13 +1,18 @@
 //! Rendering logic of trajectories such as rivers and roads
+use std::collections::HashMap;
+use std::f32::consts::PI;
 use std::ops::RangeInclusive;
 use bevy::math::{Vec2, Vec3};
 
 use super::{assets::AssetCache, mesh_builder::MeshBuilder};
+use crate::data::geography::WorldScale;
 
 
-/// Returns the 4 corner points of the rectangle of the provided trajectory segment
+/// Returns the 4 corner points of the rectangle of the provided trajectory
+/// segment. A segment without length has a rectangle without width, rather
+/// than corners that are not a number.
 fn get_rectangle_points(begin: Vec3, end: Vec3, width: f32) -> (Vec3, Vec3, Vec3, Vec3) {
-    let direction = (end - begin).normalize();
+    let direction = (end - begin).normalize_or_zero();
     let perpendicular = Vec3::new(-direction.z, 0., direction.x);
     let half_width = width / 2.;
 
@@ -19,6 +24,42 @@ fn get_rectangle_points(begin: Vec3, end: Vec3, width: f32) -> (Vec3, Vec3, Vec3
     (start_right, start_left, end_left, end_right)
 }
 
+/// The width of the tip of a stub, relative to the width of the trajectory.
+const STUB_TIP_WIDTH_FACTOR: f32 = 0.25;
+
+/// Generates a short, tapering continuation of a trajectory that starts at
+/// `end` and points in `direction`.
+///
+/// The v coordinate goes from the start of `uv.1` at the trajectory to the end
+/// of `uv.1` at the tip, so that a fading texture makes the stub fade out.
+pub fn generate_stub(
+    end: Vec2,
+    direction: Vec2,
+    width: f32,
+    length: f32,
+    y: f32,
+    uv: (f32, RangeInclusive<f32>),
+    mesh_builder: &mut MeshBuilder,
+) {
+    let direction = direction.normalize_or_zero();
+    if direction == Vec2::ZERO {
+        return;
+    }
+
+    let begin = Vec3::new(end.x, y, end.y);
+    let tip = begin + Vec3::new(direction.x, 0.0, direction.y) * length;
+    let (start_right, start_left, _, _) = get_rectangle_points(begin, tip, width);
+    let (_, _, end_left, end_right) =
+        get_rectangle_points(begin, tip, width * STUB_TIP_WIDTH_FACTOR);
+
+    let start_uv = Vec2::new(uv.0, *uv.1.start());
+    let end_uv = Vec2::new(uv.0, *uv.1.end());
+    mesh_builder.add_quad(
+        [start_right, end_right, end_left, start_left],
+        [start_uv, end_uv, end_uv, start_uv],
+    );
+}
+
 /// Generates a smoother trajectory by connecting the start point of the new segment
 /// with the end point of the previous segment.
 pub fn generate_trajectory_old(
@@ -29,7 +70,12 @@ pub fn generate_trajectory_old(
     _asset_cache: &AssetCache,
 ) {
     let uv = Vec2::new(*uv_range.0.start(), *uv_range.1.start());
-    
+    let heights = vec![0.0; trajectory.len()];
+    let (trajectory, _) = remove_short_segments(trajectory, heights);
+    if trajectory.len() < 2 {
+        return;
+    }
+
     for i in 0..trajectory.len() - 1 {
         let (x1, y1) = (trajectory[i].x as f32, trajectory[i].y as f32);
         let (x2, y2) = (trajectory[i+1].x as f32, trajectory[i+1].y as f32);
@@ -61,6 +107,108 @@ pub fn generate_trajectory_old(
 }
 
 
+/// Joints where the miter would stick out more than this many times half the
+/// width of the trajectory are beveled instead.
+const MITER_LIMIT: f32 = 2.0;
+
+/// The number of triangles used for the rounded cap at each end of a trajectory.
+const CAP_SEGMENTS: u32 = 4;
+
+/// Points of a trajectory closer together than this are merged.
+const MIN_SEGMENT_LENGTH: f32 = 1e-4;
+
+/// The height of every layer of bridges above the ground, in world units at
+/// the default scale.
+pub const BRIDGE_LAYER_HEIGHT: f32 = 8.0;
+
+/// The length of the ramps that connect a bridge deck to the ground, in world
+/// units at the default scale.
+const BRIDGE_RAMP_LENGTH: f32 = 40.0;
+
+/// The height of the railings on both sides of a bridge, in world units at the
+/// default scale.
+const RAILING_HEIGHT: f32 = 1.0;
+
+/// The depth of every layer of tunnels below the ground, in world units at the
+/// default scale.
+pub const TUNNEL_LAYER_DEPTH: f32 = 5.0;
+
+// Tags that describe bridges and tunnels, see
+// https://wiki.openstreetmap.org/wiki/Key:bridge and
+// https://wiki.openstreetmap.org/wiki/Key:tunnel
+const TAG_BRIDGE: &str = "bridge";
+const TAG_TUNNEL: &str = "tunnel";
+const TAG_LAYER: &str = "layer";
+
+/// Generates the mesh of a trajectory with the given width, with mitered
+/// joints (beveled when very sharp) and rounded caps at both ends.
+///
+/// Repeated points, which are common in OSM ways, and points that are not a
+/// number are skipped, so they do not end up in the mesh.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use bevy::render::mesh::VertexAttributeValues;
+/// use city_visualizer::earth::assets::AssetCache;
+/// use city_visualizer::earth::mesh_builder::MeshBuilder;
+/// use city_visualizer::earth::trajectory::generate_trajectory;
+///
+/// let trajectory = vec![
+///     Vec2::new(0.0, 0.0),
+///     Vec2::new(10.0, 0.0),
+///     Vec2::new(10.0, 0.0),
+///     Vec2::new(10.0, 10.0),
+/// ];
+/// let mut mesh_builder = MeshBuilder::new();
+/// let uv_range = (0.0..=1.0, 0.0..=1.0);
+/// let asset_cache = AssetCache::without_assets();
+/// generate_trajectory(trajectory, 2.0, 0.0, uv_range, &mut mesh_builder, &asset_cache);
+///
+/// let mesh = mesh_builder.into_mesh();
+/// let Some(VertexAttributeValues::Float32x3(positions)) =
+///     mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+/// else {
+///     panic!("the mesh has no positions");
+/// };
+/// assert!(!positions.is_empty());
+/// assert!(positions.iter().flatten().all(|coordinate| coordinate.is_finite()));
+/// ```
+///
+/// Every triangle of the joints and caps faces the same way as the segments,
+/// and none of them collapses, for a right angle (mitered) and a hairpin
+/// (beveled) turn, in both directions:
+///
+/// ```
+/// use bevy::prelude::*;
+/// use city_visualizer::earth::assets::AssetCache;
+/// use city_visualizer::earth::mesh_builder::MeshBuilder;
+/// use city_visualizer::earth::trajectory::generate_trajectory;
+///
+/// let width = 2.0;
+/// let turns = [
+///     vec![Vec2::new(0.0, 0.0), Vec2::new(10.0, 0.0), Vec2::new(10.0, 10.0)],
+///     vec![Vec2::new(0.0, 0.0), Vec2::new(10.0, 0.0), Vec2::new(10.0, -10.0)],
+///     vec![Vec2::new(0.0, 0.0), Vec2::new(10.0, 0.0), Vec2::new(0.0, 1.0)],
+///     vec![Vec2::new(0.0, 0.0), Vec2::new(10.0, 0.0), Vec2::new(0.0, -1.0)],
+/// ];
+/// for trajectory in turns {
+///     let mut mesh_builder = MeshBuilder::new();
+///     let uv_range = (0.0..=1.0, 0.0..=1.0);
+///     generate_trajectory(trajectory.clone(), width, 0.0, uv_range, &mut mesh_builder, &AssetCache::without_assets());
+///
+///     // twice the area in the xz-plane, negative for triangles facing down
+///     let areas: Vec<f32> = mesh_builder
+///         .get_triangles()
+///         .iter()
+///         .map(|[a, b, c]| (*b - *a).cross(*c - *a).y)
+///         .collect();
+///     let first = areas[0];
+///     for (i, area) in areas.iter().enumerate() {
+///         assert_eq!(area.signum(), first.signum(), "triangle {} of {:?} is flipped", i, trajectory);
+///         assert!(area.abs() > 0.01 * width * width, "triangle {} of {:?} has no area", i, trajectory);
+///     }
+/// }
+/// ```
 pub fn generate_trajectory(
     trajectory: Vec<Vec2>,
     width: f32,
@@ -70,47 +218,260 @@ pub fn generate_trajectory(
     _asset_cache: &AssetCache,
 ) {
     let uv = Vec2::new(*uv_range.0.start(), *uv_range.1.start());
-    
-    let width = width;
-    let mut last_end_left: Vec3 = Vec3::NAN;
-    let mut last_end_right: Vec3 = Vec3::NAN;
+    let heights = vec![y; trajectory.len()];
+    add_trajectory(trajectory, heights, width, uv, None, mesh_builder);
+}
 
-    for i in 0..trajectory.len() - 1 {
-        let (x1, y1) = (trajectory[i].x as f32, trajectory[i].y as f32);
-        let (x2, y2) = (trajectory[i+1].x as f32, trajectory[i+1].y as f32);
+/// Generates the mesh of a bridge: a trajectory that is lifted `deck_height`
+/// above `y`, with ramps down to `y` at both ends and railings on both sides.
+/// The ramps and railings are sized for the given scale of the world.
+pub fn generate_bridge(
+    trajectory: Vec<Vec2>,
+    width: f32,
+    y: f32,
+    deck_height: f32,
+    uv_range: (RangeInclusive<f32>, RangeInclusive<f32>),
+    mesh_builder: &mut MeshBuilder,
+    scale: &WorldScale,
+) {
+    let uv = Vec2::new(*uv_range.0.start(), *uv_range.1.start());
+    let ramp_length = scale.units(BRIDGE_RAMP_LENGTH);
+    let (points, heights) = get_bridge_profile(&trajectory, deck_height, ramp_length);
+    let heights = heights.into_iter().map(|height| y + height).collect();
+    let railing_height = scale.units(RAILING_HEIGHT);
+    add_trajectory(points, heights, width, uv, Some(railing_height), mesh_builder);
+}
 
-        let (mut start_right, mut start_left, mut end_left, mut end_right) = get_rectangle_points(
-            Vec3::new(x1 as f32, y, y1 as f32),
-            Vec3::new(x2 as f32, y, y2 as f32),
-            width,
-        );
+/// Returns how high above the ground a feature with the given tags is drawn,
+/// or None if it is not a bridge. Every `layer` above the ground adds
+/// `BRIDGE_LAYER_HEIGHT` at the given scale, and bridges without a layer are
+/// on layer 1.
+pub fn get_bridge_height(tags: &HashMap<String, String>, scale: &WorldScale) -> Option<f32> {
+    match tags.get(TAG_BRIDGE).map(String::as_str) {
+        None | Some("no") => return None,
+        Some(_) => {}
+    }
+    let layer = tags
+        .get(TAG_LAYER)
+        .and_then(|layer| layer.trim().parse::<i32>().ok())
+        .unwrap_or(1)
+        .max(1);
+    Some(layer as f32 * scale.units(BRIDGE_LAYER_HEIGHT))
+}
+
+/// Returns how deep below the ground a feature with the given tags is, or
+/// None if it is not a tunnel. Features with `tunnel` set or a negative
+/// `layer` are tunnels, and every layer below the ground adds
+/// `TUNNEL_LAYER_DEPTH` at the given scale.
+pub fn get_tunnel_depth(tags: &HashMap<String, String>, scale: &WorldScale) -> Option<f32> {
+    let layer = tags.get(TAG_LAYER).and_then(|layer| layer.trim().parse::<i32>().ok());
+    let tunnel = match tags.get(TAG_TUNNEL).map(String::as_str) {
+        None | Some("no") => layer.map_or(false, |layer| layer < 0),
+        Some(_) => true,
+    };
+    if !tunnel || get_bridge_height(tags, scale).is_some() {
+        return None;
+    }
+    let layer = layer.unwrap_or(-1).min(-1);
+    Some(-layer as f32 * scale.units(TUNNEL_LAYER_DEPTH))
+}
 
+/// Inserts points where the ramps at both ends of a bridge meet its deck, and
+/// returns the height of every point above the ground.
+fn get_bridge_profile(
+    trajectory: &[Vec2],
+    deck_height: f32,
+    ramp_length: f32,
+) -> (Vec<Vec2>, Vec<f32>) {
+    let distances: Vec<f32> = trajectory
+        .iter()
+        .scan((0.0, trajectory.first().copied()), |(distance, previous), &point| {
+            *distance += previous.map_or(0.0, |previous| previous.distance(point));
+            *previous = Some(point);
+            Some(*distance)
+        })
+        .collect();
+    let total = distances.last().copied().unwrap_or(0.0);
+    if total <= MIN_SEGMENT_LENGTH {
+        return (trajectory.to_vec(), vec![0.0; trajectory.len()]);
+    }
+
+    // Short bridges are a hump without a flat deck
+    let ramp_length = ramp_length.min(total / 3.0);
+    let height_at = |distance: f32| {
+        deck_height * (distance / ramp_length).min((total - distance) / ramp_length).min(1.0)
+    };
+
+    let mut points = Vec::with_capacity(trajectory.len() + 2);
+    let mut heights = Vec::with_capacity(trajectory.len() + 2);
+    for i in 0..trajectory.len() {
         if i > 0 {
-            // Replace start points with the last end points
-            start_left = last_end_left;
-            start_right = last_end_right;
+            let (from, to) = (distances[i - 1], distances[i]);
+            for split in [ramp_length, total - ramp_length] {
+                if from < split && split < to {
+                    let t = (split - from) / (to - from);
+                    points.push(trajectory[i - 1].lerp(trajectory[i], t));
+                    heights.push(height_at(split));
+                }
+            }
         }
+        points.push(trajectory[i]);
+        heights.push(height_at(distances[i]));
+    }
+    (points, heights)
+}
 
-        if i < trajectory.len() - 2 {
-            // Replace end points with more smoother variant
-            let (x3, y3) = (trajectory[i+2].x as f32, trajectory[i+2].y as f32);
+/// Adds a trajectory through the given points, where every point has its own
+/// height in `trajectory_heights`.
+/// If `railing_height` is given, railings of that height are added along both
+/// sides.
+fn add_trajectory(
+    trajectory: Vec<Vec2>,
+    trajectory_heights: Vec<f32>,
+    width: f32,
+    uv: Vec2,
+    railing_height: Option<f32>,
+    mesh_builder: &mut MeshBuilder,
+) {
+    let half_width = width / 2.0;
+    let to_3d = |point: Vec2, y: f32| Vec3::new(point.x, y, point.y);
 
-            let (next_start_right, next_start_left, _discard, _discard_2) = get_rectangle_points(
-                Vec3::new(x2 as f32, y, y2 as f32),
-                Vec3::new(x3 as f32, y, y3 as f32),
-                width,
-            );
+    let (points, heights) = remove_short_segments(trajectory, trajectory_heights);
+    if points.len() < 2 {
+        return;
+    }
 
-            // To make the trajectory smooth, we need to adjust the end points
-            // Can possibly directly use the next start points
-            end_left = (next_start_left + end_left) / 2.0;  // Take average of the two to make it more smooth
-            end_right = (next_start_right + end_right) / 2.0;  
+    let directions: Vec<Vec2> = points.windows(2).map(|w| (w[1] - w[0]).normalize()).collect();
+
+    // The (right, left) corners at the start of the current segment
+    let first_normal = perpendicular(directions[0]) * half_width;
+    let mut start = (points[0] + first_normal, points[0] - first_normal);
+
+    for i in 0..directions.len() {
+        let point = points[i + 1];
+        let y = heights[i + 1];
+        let normal = perpendicular(directions[i]);
+
+        // The (right, left) corners at the end of this segment, and at the
+        // start of the next segment
+        let (end, next_start) = match directions.get(i + 1) {
+            None => {
+                let offset = normal * half_width;
+                ((point + offset, point - offset), None)
+            }
+            Some(&next_direction) => {
+                let next_normal = perpendicular(next_direction);
+                let miter = (normal + next_normal).normalize_or_zero();
+                let cos = miter.dot(normal);
+                if miter != Vec2::ZERO && cos * MITER_LIMIT >= 1.0 {
+                    let offset = miter * half_width / cos;
+                    ((point + offset, point - offset), None)
+                } else {
+                    // Bevel: end this segment square, start the next one
+                    // square and fill the gap on the outside of the turn
+                    let offset = normal * half_width;
+                    let next_offset = next_normal * half_width;
+                    let outside = if directions[i].perp_dot(next_direction) > 0.0 { -1.0 } else { 1.0 };
+                    add_triangle_xz(
+                        mesh_builder,
+                        [
+                            to_3d(point, y),
+                            to_3d(point + offset * outside, y),
+                            to_3d(point + next_offset * outside, y),
+                        ],
+                        uv,
+                    );
+                    (
+                        (point + offset, point - offset),
+                        Some((point + next_offset, point - next_offset)),
+                    )
+                }
+            }
+        };
+
+        let start_y = heights[i];
+        let (start_right, start_left) = start;
+        let (end_right, end_left) = end;
+        mesh_builder.add_quad(
+            [
+                to_3d(start_right, start_y),
+                to_3d(end_right, y),
+                to_3d(end_left, y),
+                to_3d(start_left, start_y),
+            ],
+            [uv, uv, uv, uv],
+        );
+
+        if let Some(railing_height) = railing_height {
+            let up = Vec3::Y * railing_height;
+            for (from, to) in [(start_right, end_right), (start_left, end_left)] {
+                let (from, to) = (to_3d(from, start_y), to_3d(to, y));
+                mesh_builder.add_quad([from, to, to + up, from + up], [uv, uv, uv, uv]);
+            }
         }
-        last_end_left = end_left;
-        last_end_right = end_right;
+        start = next_start.unwrap_or(end);
+    }
 
-        mesh_builder.add_quad([start_right, end_right, end_left, start_left], [uv, uv, uv, uv]);
+    // Rounded caps, pointing away from the trajectory
+    let last = points.len() - 1;
+    add_round_cap(mesh_builder, to_3d(points[0], heights[0]), -directions[0], half_width, uv);
+    add_round_cap(mesh_builder, to_3d(points[last], heights[last]), directions[last - 1], half_width, uv);
+}
+
+/// Returns the points of a trajectory and their heights without the points
+/// that are closer than `MIN_SEGMENT_LENGTH` to the previous point, since
+/// zero length segments have no direction, and without points or heights
+/// that are not finite.
+fn remove_short_segments(trajectory: Vec<Vec2>, heights: Vec<f32>) -> (Vec<Vec2>, Vec<f32>) {
+    let mut points: Vec<Vec2> = Vec::with_capacity(trajectory.len());
+    let mut kept_heights: Vec<f32> = Vec::with_capacity(trajectory.len());
+    for (point, height) in trajectory.into_iter().zip(heights) {
+        if !point.is_finite() || !height.is_finite() {
+            continue;
+        }
+        if points.last().map_or(true, |last| last.distance(point) > MIN_SEGMENT_LENGTH) {
+            points.push(point);
+            kept_heights.push(height);
+        }
+    }
+    (points, kept_heights)
+}
+
+/// Returns the direction perpendicular to `direction`, pointing to the same
+/// side as the "right" points of `get_rectangle_points`.
+fn perpendicular(direction: Vec2) -> Vec2 {
+    Vec2::new(-direction.y, direction.x)
+}
+
+/// Adds a half disk around `center` that bulges out in `direction`.
+fn add_round_cap(
+    mesh_builder: &mut MeshBuilder,
+    center: Vec3,
+    direction: Vec2,
+    radius: f32,
+    uv: Vec2,
+) {
+    let normal = perpendicular(direction);
+    let rim = |step: u32| {
+        let angle = step as f32 / CAP_SEGMENTS as f32 * PI;
+        let offset = (normal * angle.cos() + direction * angle.sin()) * radius;
+        Vec3::new(center.x + offset.x, center.y, center.z + offset.y)
+    };
+    for step in 0..CAP_SEGMENTS {
+        add_triangle_xz(mesh_builder, [center, rim(step), rim(step + 1)], uv);
+    }
+}
+
+/// Adds a horizontal triangle, ordering its corners so that it faces the same
+/// way as the quads of trajectories.
+fn add_triangle_xz(mesh_builder: &mut MeshBuilder, mut positions: [Vec3; 3], uv: Vec2) {
+    let first = positions[1] - positions[0];
+    let second = positions[2] - positions[1];
+    // quads go from right to left at their end, which is clockwise in the xz-plane
+    if first.x * second.z - first.z * second.x > 0.0 {
+        positions.swap(1, 2);
     }
+    mesh_builder.add_triangle(positions, [uv, uv, uv]);
 }
 
 // pub fn convert_trajectory_to_bundle(
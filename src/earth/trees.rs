@@ -0,0 +1,295 @@
+//! Trees, which are spawned per chunk rather than per tree, since a forest
+//! can hold tens of thousands of them.
+//!
+//! The trees of a chunk are grouped in square cells. In the distance, a cell
+//! is a single entity with the simple meshes of all of its trees merged into
+//! one. Close to the viewer, its trees are spawned as entities of their own
+//! with the detailed meshes, and beyond `TREE_REMOVE_DISTANCE_SQUARED` the
+//! cell is hidden.
+
+use crate::data::geography::WorldScale;
+use crate::earth::assets::AssetCache;
+use crate::earth::layers::{FeatureLayer, LayerVisibility};
+use crate::earth::mesh_builder::MeshBuilder;
+use crate::earth::scene_stats::SceneStats;
+use crate::earth::GeoFeature;
+use crate::lod::{
+    lod_viewer_position, next_lod_level, LodLevel, LodUpdate, DEFAULT_LOD_DISTANCE_SQUARED,
+    DEFAULT_REMOVE_DISTANCE_SQUARED,
+};
+use crate::player::Player;
+
+use bevy::prelude::*;
+
+use std::collections::HashMap;
+
+/// The width of the cells that trees are grouped in, in world units at the
+/// default scale.
+pub const TREE_CELL_SIZE: f32 = 100.0;
+
+/// Squared distance from a cell beyond which its trees are hidden.
+const TREE_REMOVE_DISTANCE_SQUARED: f32 = 2.0 * DEFAULT_REMOVE_DISTANCE_SQUARED;
+
+/// A tree in a chunk.
+#[derive(Clone, Copy, Debug)]
+pub struct TreeInstance {
+    /// The transform of the tree, relative to the chunk entity.
+    pub transform: Transform,
+    /// Whether the tree uses the complex tree mesh instead of the triangle
+    /// tree.
+    pub complex: bool,
+}
+
+/// A square cell of trees in a chunk.
+struct TreeCell {
+    /// The center of the cell, relative to the chunk entity.
+    center: Vec3,
+    trees: Vec<TreeInstance>,
+    /// The level the cell is shown at, or None if it was not shown yet.
+    level: Option<LodLevel>,
+    /// The entity with the merged simple meshes of the trees, once the tree
+    /// meshes are loaded.
+    far: Option<Entity>,
+    /// The entities of the trees, while the cell is close to the viewer.
+    detailed: Vec<Entity>,
+}
+
+/// All trees of a chunk. The entity with this component is the parent of the
+/// entities that show the trees, so hiding it hides all of them.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use city_visualizer::data::geography::WorldScale;
+/// use city_visualizer::earth::trees::{TreeChunk, TreeInstance, TREE_CELL_SIZE};
+///
+/// let scale = WorldScale::default();
+/// let cell_size = scale.units(TREE_CELL_SIZE);
+/// let trees = [0.1, 0.2, 1.5, 3.5].map(|x| TreeInstance {
+///     transform: Transform::from_xyz(x * cell_size, 0.0, 0.5 * cell_size),
+///     complex: false,
+/// });
+///
+/// let chunk = TreeChunk::new(trees.to_vec(), &scale);
+/// assert_eq!(chunk.tree_count(), 4);
+/// assert_eq!(chunk.cell_count(), 3);
+/// assert_eq!(chunk.detailed_count(), 0);
+/// ```
+#[derive(Component)]
+pub struct TreeChunk {
+    cells: Vec<TreeCell>,
+    /// Whether some cells have no far entity yet.
+    pending: bool,
+}
+
+impl TreeChunk {
+    /// Groups the given trees in cells of `TREE_CELL_SIZE`.
+    pub fn new(trees: Vec<TreeInstance>, scale: &WorldScale) -> Self {
+        let cell_size = scale.units(TREE_CELL_SIZE);
+        let mut cells: HashMap<IVec2, Vec<TreeInstance>> = HashMap::new();
+        for tree in trees {
+            let position = tree.transform.translation;
+            let cell = Vec2::new(position.x, position.z) / cell_size;
+            cells.entry(cell.floor().as_ivec2()).or_default().push(tree);
+        }
+
+        let cells = cells
+            .into_iter()
+            .map(|(cell, trees)| {
+                let center = (cell.as_vec2() + 0.5) * cell_size;
+                TreeCell {
+                    center: Vec3::new(center.x, 0.0, center.y),
+                    trees,
+                    level: None,
+                    far: None,
+                    detailed: Vec::new(),
+                }
+            })
+            .collect();
+        TreeChunk {
+            cells,
+            pending: true,
+        }
+    }
+
+    /// Returns the number of trees in the chunk.
+    pub fn tree_count(&self) -> usize {
+        self.cells.iter().map(|cell| cell.trees.len()).sum()
+    }
+
+    /// Returns all trees of the chunk.
+    pub fn trees(&self) -> impl Iterator<Item = &TreeInstance> {
+        self.cells.iter().flat_map(|cell| &cell.trees)
+    }
+
+    /// Returns the number of cells the trees are grouped in.
+    pub fn cell_count(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Returns the number of trees that are spawned as entities of their own.
+    pub fn detailed_count(&self) -> usize {
+        self.cells.iter().map(|cell| cell.detailed.len()).sum()
+    }
+}
+
+/// Spawns the trees of a chunk. The entity is hidden along with the trees
+/// layer, like other features.
+pub fn spawn_tree_chunk(commands: &mut Commands, trees: Vec<TreeInstance>, scale: &WorldScale) {
+    if trees.is_empty() {
+        return;
+    }
+    commands.spawn((
+        SpatialBundle::default(),
+        TreeChunk::new(trees, scale),
+        GeoFeature { id: 0 },
+        FeatureLayer::Trees,
+    ));
+}
+
+/// Returns the mesh with the simple meshes of all trees of a cell, relative
+/// to the center of the cell, or None if the tree meshes are not loaded yet.
+fn merge_far_trees(
+    cell: &TreeCell,
+    meshes: &Assets<Mesh>,
+    asset_cache: &AssetCache,
+) -> Option<Mesh> {
+    let triangle_tree = meshes.get(asset_cache.get_triangle_tree_mesh())?;
+    let complex_tree = meshes.get(asset_cache.get_simplified_complex_tree_mesh());
+
+    let to_center = Transform::from_translation(-cell.center);
+    let mut mesh_builder = MeshBuilder::new();
+    for tree in &cell.trees {
+        let mesh = if tree.complex {
+            complex_tree?
+        } else {
+            triangle_tree
+        };
+        mesh_builder.add_mesh(mesh, to_center * tree.transform);
+    }
+    Some(mesh_builder.into_mesh())
+}
+
+/// Returns the squared distance from a point to the nearest point of the
+/// ground of a cell with the given center and size.
+fn distance_squared_to_cell(point: Vec3, center: Vec3, cell_size: f32) -> f32 {
+    let half_size = cell_size / 2.0;
+    let nearest = Vec3::new(
+        point.x.clamp(center.x - half_size, center.x + half_size),
+        center.y,
+        point.z.clamp(center.z - half_size, center.z + half_size),
+    );
+    point.distance_squared(nearest)
+}
+
+/// A system that shows the cells of trees at the level of detail for their
+/// distance to the viewer: the trees as entities of their own up close, the
+/// merged far mesh further away, or nothing. Like `lod_system`, it only runs
+/// when the viewer moved far enough or some time passed, and when the far
+/// meshes of new chunks still have to be built.
+pub fn update_tree_chunks(
+    mut commands: Commands,
+    mut chunks: Query<(Entity, &mut TreeChunk, &GlobalTransform)>,
+    mut visibilities: Query<&mut Visibility, Without<TreeChunk>>,
+    player_query: Query<(&Player, &Transform, &Projection)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    asset_cache: Res<AssetCache>,
+    (scale, layers): (Res<WorldScale>, Res<LayerVisibility>),
+    mut scene_stats: ResMut<SceneStats>,
+    time: Res<Time>,
+    mut last_update: Local<LodUpdate>,
+) {
+    // hidden trees keep their level until they are shown again
+    if !layers.trees {
+        return;
+    }
+    let Some((player, player_transform, projection)) = player_query.iter().next() else {
+        return;
+    };
+    let viewer_position = lod_viewer_position(player, player_transform, projection);
+    let pending = chunks.iter().any(|(_, chunk, _)| chunk.pending);
+    if !last_update.should_update(viewer_position, time.delta_seconds(), &scale, pending) {
+        return;
+    }
+
+    // squared distances scale like areas
+    let area_factor = scale.area(1.0);
+    let cell_size = scale.units(TREE_CELL_SIZE);
+
+    for (chunk_entity, mut chunk, transform) in chunks.iter_mut() {
+        let chunk = &mut *chunk;
+        let mut pending = false;
+        for cell in &mut chunk.cells {
+            let center = transform.transform_point(cell.center);
+            let distance_sq =
+                distance_squared_to_cell(viewer_position, center, cell_size) / area_factor;
+            let level = next_lod_level(
+                cell.level.unwrap_or(LodLevel::High),
+                distance_sq,
+                DEFAULT_LOD_DISTANCE_SQUARED,
+                TREE_REMOVE_DISTANCE_SQUARED,
+            );
+
+            let far_visibility = if level == LodLevel::Low {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            };
+            match cell.far {
+                Some(far) => {
+                    if let Ok(mut visibility) = visibilities.get_mut(far) {
+                        if *visibility != far_visibility {
+                            *visibility = far_visibility;
+                        }
+                    }
+                }
+                None => match merge_far_trees(cell, &meshes, &asset_cache) {
+                    Some(far_mesh) => {
+                        scene_stats.add_mesh(FeatureLayer::Trees, &far_mesh);
+                        let far = commands
+                            .spawn(PbrBundle {
+                                mesh: meshes.add(far_mesh),
+                                material: asset_cache.get_tree_material(),
+                                transform: Transform::from_translation(cell.center),
+                                visibility: far_visibility,
+                                ..default()
+                            })
+                            .set_parent(chunk_entity)
+                            .id();
+                        cell.far = Some(far);
+                    }
+                    None => pending = true,
+                },
+            }
+
+            if cell.level == Some(level) {
+                continue;
+            }
+            cell.level = Some(level);
+
+            if level == LodLevel::High {
+                for tree in &cell.trees {
+                    let mesh = if tree.complex {
+                        asset_cache.get_complex_tree_mesh()
+                    } else {
+                        asset_cache.get_triangle_tree_mesh()
+                    };
+                    let entity = commands
+                        .spawn(PbrBundle {
+                            mesh,
+                            material: asset_cache.get_tree_material(),
+                            transform: tree.transform,
+                            ..default()
+                        })
+                        .set_parent(chunk_entity)
+                        .id();
+                    cell.detailed.push(entity);
+                }
+            } else {
+                for entity in cell.detailed.drain(..) {
+                    commands.entity(entity).despawn_recursive();
+                }
+            }
+        }
+        chunk.pending = pending;
+    }
+}
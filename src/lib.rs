@@ -1,3 +1,4 @@
+pub mod commands;
 pub mod common;
 pub mod data;
 pub mod earth;
@@ -5,4 +6,10 @@ pub mod plugin;
 pub mod player;
 pub mod ui;
 pub mod fps;
-pub mod lod;
\ No newline at end of file
+pub mod lod;
+pub mod tutorial;
+pub mod bookmarks;
+pub mod screenshot;
+pub mod startup;
+pub mod status_log;
+pub mod web_api;
@@ -2,25 +2,69 @@ use bevy::ecs::component::Component;
 use bevy::prelude::*;
 use wasm_bindgen::prelude::*;
 
-use crate::earth::GLOBAL_SCALE_FACTOR;
+use crate::data::geography::WorldScale;
+use crate::earth::layers::{FeatureLayer, LayerVisibility};
 use crate::player;
 
 /// A level of detail system.
-/// Entities with the LOD component will have their high quality mesh replaced with lower quality ones or entirely removed if they are too far away.
+/// Entities with the LOD component will have their high quality mesh replaced with lower quality ones or entirely hidden if they are too far away.
 
 /// Squared distance at which agents do not render
-const DEFAULT_REMOVE_DIST: f32 = 10.0;
-pub const DEFAULT_REMOVE_DISTANCE_SQUARED: f32 =
-    (DEFAULT_REMOVE_DIST * GLOBAL_SCALE_FACTOR) * (DEFAULT_REMOVE_DIST * GLOBAL_SCALE_FACTOR);
+const DEFAULT_REMOVE_DIST: f32 = 1000.0;
+pub const DEFAULT_REMOVE_DISTANCE_SQUARED: f32 = DEFAULT_REMOVE_DIST * DEFAULT_REMOVE_DIST;
+
+/// Squared distance at which the blob shadows of agents do not render
+const SHADOW_REMOVE_DIST: f32 = 300.0;
+pub const SHADOW_REMOVE_DISTANCE_SQUARED: f32 = SHADOW_REMOVE_DIST * SHADOW_REMOVE_DIST;
+
+/// In the top-down map mode, the height from which distances are measured per
+/// unit of orthographic scale. The camera itself is far above the ground, so
+/// its real height would hide everything. The orthographic scale grows with
+/// the scale of the world, so this does not.
+const MAP_LOD_HEIGHT_PER_SCALE: f32 = 200.0;
 
 /// Squared distance low quality agents are rendered
-const DEFAULT_LOD_DIST: f32 = 5.0;
-pub const DEFAULT_LOD_DISTANCE_SQUARED: f32 =
-    (DEFAULT_LOD_DIST * GLOBAL_SCALE_FACTOR) * (DEFAULT_LOD_DIST * GLOBAL_SCALE_FACTOR);
+const DEFAULT_LOD_DIST: f32 = 500.0;
+pub const DEFAULT_LOD_DISTANCE_SQUARED: f32 = DEFAULT_LOD_DIST * DEFAULT_LOD_DIST;
+
+/// Squared distance from the center of the buildings or roads of a chunk at
+/// which their cheap far mesh is rendered. Chunks are a few kilometers wide,
+/// so this is well beyond the chunk the player is in.
+const CHUNK_LOD_DIST: f32 = 1500.0;
+pub const CHUNK_LOD_DISTANCE_SQUARED: f32 = CHUNK_LOD_DIST * CHUNK_LOD_DIST;
+
+/// An entity switches to a lower level of detail beyond a threshold distance,
+/// but only switches back within this fraction of it, so an entity right at
+/// the threshold does not flip between meshes while the player moves.
+const LOD_HYSTERESIS: f32 = 0.9;
+
+/// How far the viewer has to move before the levels of detail are evaluated
+/// again, in world units at the default scale.
+const LOD_UPDATE_DISTANCE: f32 = 5.0;
+
+/// How often the levels of detail are evaluated when the viewer stands still,
+/// for entities that were spawned or moved by themselves, in seconds.
+const LOD_UPDATE_INTERVAL: f32 = 0.5;
 
+/// A generated mesh, with a cheap version of it to render in the distance.
+pub struct LodMesh {
+    pub detailed: Mesh,
+    pub far: Mesh,
+}
+
+/// The level of detail that an entity is shown at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LodLevel {
+    High,
+    Low,
+    Removed,
+}
+
+/// The distances are in world units at the default scale, and are scaled along
+/// with the world when they are compared.
 #[derive(Component, Debug)]
 pub struct LOD {
-    /// The squared distance at which the mesh will be removed, squared distance used for performance
+    /// The squared distance at which the entity (and its children) will be hidden, squared distance used for performance
     pub remove_distance_squared: f32,
     /// The squared distance at which the entity will be replaced with a lower quality mesh
     pub lod_distance_distance_squared: f32,
@@ -38,39 +82,217 @@ pub struct LOD {
     pub low_quality_material: Handle<StandardMaterial>,
 }
 
-/// Updates LOD of entities.
+impl LOD {
+    /// Returns the level of detail that an entity at the given squared
+    /// distance (at the default scale) should have, given the level it has
+    /// now. Moving away switches to a lower level at the thresholds, moving
+    /// closer only switches back within `LOD_HYSTERESIS` of them.
+    ///
+    /// ```
+    /// use bevy::prelude::*;
+    /// use city_visualizer::lod::{LodLevel, LOD};
+    ///
+    /// let lod = LOD {
+    ///     remove_distance_squared: 1000.0 * 1000.0,
+    ///     lod_distance_distance_squared: 500.0 * 500.0,
+    ///     high_quality_mesh: Handle::default(),
+    ///     high_quality_material: Handle::default(),
+    ///     low_quality_mesh: Handle::default(),
+    ///     low_quality_material: Handle::default(),
+    /// };
+    ///
+    /// // a player walking back and forth across the threshold
+    /// let mut level = LodLevel::High;
+    /// let mut swaps = 0;
+    /// for step in 0..100 {
+    ///     let distance = if step % 2 == 0 { 499.0 } else { 501.0 };
+    ///     let next = lod.next_level(level, distance * distance);
+    ///     if next != level {
+    ///         swaps += 1;
+    ///     }
+    ///     level = next;
+    /// }
+    /// assert_eq!(swaps, 1);
+    /// assert_eq!(level, LodLevel::Low);
+    ///
+    /// // coming well within the threshold switches back
+    /// assert_eq!(lod.next_level(level, 440.0 * 440.0), LodLevel::High);
+    /// assert_eq!(lod.next_level(LodLevel::High, 1001.0 * 1001.0), LodLevel::Removed);
+    /// ```
+    pub fn next_level(&self, current: LodLevel, distance_squared: f32) -> LodLevel {
+        next_lod_level(
+            current,
+            distance_squared,
+            self.lod_distance_distance_squared,
+            self.remove_distance_squared,
+        )
+    }
+
+    /// Returns the level of detail that an entity with the given mesh and
+    /// visibility is shown at.
+    fn current_level(&self, mesh: &Handle<Mesh>, visibility: Visibility) -> LodLevel {
+        if visibility == Visibility::Hidden {
+            LodLevel::Removed
+        } else if *mesh == self.low_quality_mesh && *mesh != self.high_quality_mesh {
+            LodLevel::Low
+        } else {
+            LodLevel::High
+        }
+    }
+}
+
+/// Returns the level of detail for the given squared distance and squared
+/// thresholds, like `LOD::next_level`.
+pub(crate) fn next_lod_level(
+    current: LodLevel,
+    distance_squared: f32,
+    lod_distance_squared: f32,
+    remove_distance_squared: f32,
+) -> LodLevel {
+    let hysteresis_squared = LOD_HYSTERESIS * LOD_HYSTERESIS;
+    // the thresholds to move closer than, to return to a level
+    let low_threshold = match current {
+        LodLevel::Removed => remove_distance_squared * hysteresis_squared,
+        _ => remove_distance_squared,
+    };
+    let high_threshold = match current {
+        LodLevel::Removed | LodLevel::Low => lod_distance_squared * hysteresis_squared,
+        LodLevel::High => lod_distance_squared,
+    };
+
+    if distance_squared > low_threshold {
+        LodLevel::Removed
+    } else if distance_squared > high_threshold {
+        LodLevel::Low
+    } else {
+        LodLevel::High
+    }
+}
+
+/// When the levels of detail were last evaluated.
+#[derive(Default)]
+pub struct LodUpdate {
+    viewer_position: Option<Vec3>,
+    since: f32,
+}
+
+impl LodUpdate {
+    /// Returns whether levels of detail should be evaluated again, because
+    /// the viewer moved far enough, some time passed or `force` is set. If
+    /// so, the update is remembered.
+    pub(crate) fn should_update(
+        &mut self,
+        viewer_position: Vec3,
+        delta_seconds: f32,
+        scale: &WorldScale,
+        force: bool,
+    ) -> bool {
+        self.since += delta_seconds;
+        let moved = self.viewer_position.map_or(true, |position| {
+            position.distance(viewer_position) > scale.units(LOD_UPDATE_DISTANCE)
+        });
+        if !moved && self.since < LOD_UPDATE_INTERVAL && !force {
+            return false;
+        }
+        self.viewer_position = Some(viewer_position);
+        self.since = 0.0;
+        true
+    }
+}
+
+/// Updates LOD of entities, when the viewer moved far enough or some time
+/// passed since the last update. Materials are only swapped if the levels use
+/// different materials, so other systems can change the material of entities
+/// that use one, such as lit buildings at night.
+///
+/// Entities that are too far away are hidden rather than given another mesh,
+/// so their children are hidden along with them and they show their mesh
+/// right away when they come back in range. Entities of hidden layers are
+/// left alone, so they stay hidden, and the agent followed by the camera is
+/// never removed, so it does not vanish under the camera.
 pub fn lod_system(
-    mut lod_query: Query<(&LOD, &mut Handle<Mesh>, &mut Handle<StandardMaterial>, &Transform)>,
-    player_query: Query<(&player::Player, &Transform)>,
+    mut lod_query: Query<(
+        Entity,
+        &LOD,
+        &mut Handle<Mesh>,
+        &mut Handle<StandardMaterial>,
+        &mut Visibility,
+        &GlobalTransform,
+        Option<&FeatureLayer>,
+    )>,
+    player_query: Query<(&player::Player, &Transform, &Projection)>,
+    (scale, layers): (Res<WorldScale>, Res<LayerVisibility>),
+    time: Res<Time>,
+    mut last_update: Local<LodUpdate>,
 ) {
     // Get player position
     if player_query.iter().next().is_none() {
         return;
     }
-    let player_transform = player_query.iter().next().unwrap_throw().1;
+    let (player, player_transform, projection) = player_query.iter().next().unwrap_throw();
+    let viewer_position = lod_viewer_position(player, player_transform, projection);
+    let followed = match player.camera_mode {
+        player::CameraMode::Follow(follow) => Some(follow.agent),
+        _ => None,
+    };
+
+    // shown layers have to be hidden in the distance again
+    if !last_update.should_update(viewer_position, time.delta_seconds(), &scale, layers.is_changed()) {
+        return;
+    }
+
+    // squared distances scale like areas
+    let area_factor = scale.area(1.0);
 
     // Update LOD
-    let empty_mesh: Handle<Mesh> = Handle::default();
-    for (lod, mut mesh, mut material, transform) in lod_query.iter_mut() {
-        let distance_sq = Vec3::distance_squared(
-            transform.translation,
-            player_transform.translation,
-        );
-
-        if distance_sq > lod.remove_distance_squared {
-            if *mesh != empty_mesh {
-                *mesh = empty_mesh.clone();
-            }
-        } else if distance_sq > lod.lod_distance_distance_squared {
-            if *mesh != lod.low_quality_mesh {
-                *mesh = lod.low_quality_mesh.clone();
-                *material = lod.low_quality_material.clone();
-            }
+    for (entity, lod, mut mesh, mut material, mut visibility, transform, layer) in lod_query.iter_mut() {
+        if layer.is_some_and(|&layer| !layers.is_visible(layer)) {
+            continue;
+        }
+        // global, since children such as the shadows of agents have a
+        // transform relative to their parent. The followed agent is always
+        // shown in detail, however far the camera is behind it.
+        let distance_sq = if followed == Some(entity) {
+            0.0
         } else {
-            if *mesh != lod.high_quality_mesh {
-                *mesh = lod.high_quality_mesh.clone();
-                *material = lod.high_quality_material.clone();
+            Vec3::distance_squared(transform.translation(), viewer_position) / area_factor
+        };
+
+        let current = lod.current_level(&mesh, *visibility);
+        let (new_mesh, new_material) = match lod.next_level(current, distance_sq) {
+            LodLevel::High => (&lod.high_quality_mesh, &lod.high_quality_material),
+            LodLevel::Low => (&lod.low_quality_mesh, &lod.low_quality_material),
+            LodLevel::Removed => {
+                if *visibility != Visibility::Hidden {
+                    *visibility = Visibility::Hidden;
+                }
+                continue;
             }
+        };
+        // only assigned when different, so unchanged entities are not marked
+        // as changed
+        if *visibility == Visibility::Hidden {
+            *visibility = Visibility::Inherited;
+        }
+        if *mesh != *new_mesh {
+            *mesh = new_mesh.clone();
+        }
+        if lod.high_quality_material != lod.low_quality_material && *material != *new_material {
+            *material = new_material.clone();
         }
     }
-}
\ No newline at end of file
+}
+
+/// Returns the position from which the LOD distances are measured. This is the
+/// camera, except in the map mode, where it is above the center of the view,
+/// higher when zoomed out further.
+pub(crate) fn lod_viewer_position(player: &player::Player, transform: &Transform, projection: &Projection) -> Vec3 {
+    match (player.camera_mode, projection) {
+        (player::CameraMode::TopDown { .. }, Projection::Orthographic(ortho)) => Vec3::new(
+            transform.translation.x,
+            ortho.scale * MAP_LOD_HEIGHT_PER_SCALE,
+            transform.translation.z,
+        ),
+        _ => transform.translation,
+    }
+}
@@ -1,6 +1,7 @@
-
 use bevy::asset::AssetMetaCheck;
+use city_visualizer::earth::agent::AgentSeed;
 use city_visualizer::plugin::CityVisualizerPlugin;
+use city_visualizer::startup::{headless_app, parse_args, ArgsError, USAGE};
 
 use bevy::DefaultPlugins;
 use bevy::app::App;
@@ -11,12 +12,40 @@ use bevy::diagnostic::FrameTimeDiagnosticsPlugin;
 // for gui
 use bevy_egui::EguiPlugin;
 
-fn main() {
-    App::new()
-        .insert_resource(AssetMetaCheck::Never) // For web https://github.com/bevyengine/bevy/issues/10157
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    // invalid arguments are reported before the window is opened
+    let startup_args = match parse_args(std::env::args().skip(1)) {
+        Ok(startup_args) => startup_args,
+        Err(ArgsError::Help) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        },
+        Err(ArgsError::Invalid(message)) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            return ExitCode::FAILURE;
+        },
+    };
+
+    // failures exit the process early, see `update_exit_after_load`
+    if startup_args.headless {
+        headless_app(startup_args).run();
+        return ExitCode::SUCCESS;
+    }
+
+    let mut app = App::new();
+    // a random seed is used unless one was given
+    if let Some(seed) = startup_args.seed {
+        app.insert_resource(AgentSeed(seed));
+    }
+    app.insert_resource(AssetMetaCheck::Never) // For web https://github.com/bevyengine/bevy/issues/10157
+        .insert_resource(startup_args.scale)
+        .insert_resource(startup_args)
         .add_plugins(DefaultPlugins)
         .add_plugins(CityVisualizerPlugin)
         .add_plugins(EguiPlugin)
         .add_plugins(FrameTimeDiagnosticsPlugin::default())
         .run();
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,263 @@
+//! Riding along with an agent. Clicking an agent, or "Follow random car",
+//! puts the camera in a chase position behind the agent, which is smoothed
+//! and looks ahead by the velocity of the agent. Moving the mouse turns the
+//! camera around the agent and scrolling moves it closer or further away.
+//! Escape returns to free movement where the camera is.
+
+use crate::commands::CommandRegistry;
+use crate::common::{AppError, StatusEvent};
+use crate::data::geography::WorldScale;
+use crate::earth::agent::{Agent, AgentType};
+use crate::ui::InputMode;
+
+use super::{CameraMode, Player, PlayerMoveEvent, ORBIT_PITCH_RANGE, ORBIT_ZOOM_FACTOR};
+
+use bevy::prelude::*;
+use rand::seq::IteratorRandom;
+
+use std::f32::consts::PI;
+
+// Lengths are in world units at the default scale, see `WorldScale::units`.
+
+/// The distance between the camera and the agent it starts following.
+const DEFAULT_FOLLOW_DISTANCE: f32 = 20.0;
+
+/// The range of distances between the camera and the followed agent.
+const FOLLOW_DISTANCE_RANGE: std::ops::RangeInclusive<f32> = 3.0..=500.0;
+
+/// The pitch of the camera when it starts following an agent, looking down
+/// a bit.
+const DEFAULT_FOLLOW_PITCH: f32 = -0.1 * PI;
+
+/// The height above the agent that the camera looks at.
+const FOCUS_HEIGHT: f32 = 2.0;
+
+/// How far ahead of the agent the camera looks, in seconds of its velocity.
+const LOOK_AHEAD: f32 = 0.5;
+
+/// How fast the camera catches up with its chase position, per second. The
+/// higher, the more rigidly the camera is attached to the agent.
+const FOLLOW_SMOOTHING: f32 = 5.0;
+
+/// How fast the estimated velocity of the agent adapts, per second.
+const VELOCITY_SMOOTHING: f32 = 4.0;
+
+/// Agents that move further than this in one frame were moved, for example
+/// along with the origin of the world, rather than driving there.
+const MAX_FRAME_DISTANCE: f32 = 50.0;
+
+/// How close a click has to be to the center of an agent to pick it.
+const PICK_RADIUS: f32 = 3.0;
+
+/// The camera following an agent, see `CameraMode::Follow`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FollowCamera {
+    pub agent: Entity,
+    /// The angle of the camera around the agent, relative to right behind it.
+    pub yaw: f32,
+    pub pitch: f32,
+    /// The distance between the camera and the agent.
+    pub distance: f32,
+    /// The smoothed velocity of the agent, in world units per second.
+    pub velocity: Vec3,
+    /// Where the agent was last frame.
+    pub last_position: Option<Vec3>,
+}
+
+impl FollowCamera {
+    /// Returns a camera right behind the given agent.
+    pub fn new(agent: Entity, scale: &WorldScale) -> Self {
+        FollowCamera {
+            agent,
+            yaw: 0.0,
+            pitch: DEFAULT_FOLLOW_PITCH,
+            distance: scale.units(DEFAULT_FOLLOW_DISTANCE),
+            velocity: Vec3::ZERO,
+            last_position: None,
+        }
+    }
+
+    /// Turns the camera around the agent with the mouse, and moves it
+    /// closer or further away with the scroll wheel.
+    pub(crate) fn orbit(&mut self, player: &Player, event: &PlayerMoveEvent, scale: &WorldScale) {
+        self.yaw -= event.rotation.x * player.rotation_speed;
+        self.pitch = (self.pitch - event.rotation.y * player.rotation_speed)
+            .clamp(*ORBIT_PITCH_RANGE.start(), *ORBIT_PITCH_RANGE.end());
+        self.distance = (self.distance * ORBIT_ZOOM_FACTOR.powf(-event.scroll))
+            .clamp(scale.units(*FOLLOW_DISTANCE_RANGE.start()), scale.units(*FOLLOW_DISTANCE_RANGE.end()));
+    }
+}
+
+/// Starts or stops following an agent with the camera.
+#[derive(Clone, Copy, Debug, Event)]
+pub enum FollowAgentEvent {
+    /// Follows the agent under the given position of the cursor in the
+    /// window, if there is one.
+    Pick(Vec2),
+    /// Follows a random car.
+    RandomCar,
+    /// Stops following, and returns to free movement.
+    Stop,
+}
+
+/// Returns the candidate closest to the start of the ray that the ray passes
+/// within `radius` of, or None if it misses all of them.
+///
+/// ```
+/// use bevy::prelude::*;
+/// use city_visualizer::player::follow::pick_along_ray;
+///
+/// let candidates = [
+///     (1, Vec3::new(0.0, 0.0, -10.0)),
+///     (2, Vec3::new(0.0, 0.0, -5.0)),
+///     (3, Vec3::new(0.0, 0.0, 5.0)),
+///     (4, Vec3::new(4.0, 0.0, -3.0)),
+/// ];
+/// // both 1 and 2 are hit, 2 is in front; 3 is behind the ray
+/// assert_eq!(pick_along_ray(Vec3::ZERO, Vec3::NEG_Z, candidates, 1.0), Some(2));
+/// assert_eq!(pick_along_ray(Vec3::ZERO, Vec3::Z, candidates, 1.0), Some(3));
+/// assert_eq!(pick_along_ray(Vec3::ZERO, Vec3::X, candidates, 1.0), None);
+/// assert_eq!(pick_along_ray(Vec3::ZERO, Vec3::new(4.0, 0.0, -3.0).normalize(), candidates, 1.0), Some(4));
+/// ```
+pub fn pick_along_ray<T>(
+    origin: Vec3,
+    direction: Vec3,
+    candidates: impl IntoIterator<Item = (T, Vec3)>,
+    radius: f32,
+) -> Option<T> {
+    candidates
+        .into_iter()
+        .filter_map(|(candidate, center)| {
+            let along = (center - origin).dot(direction);
+            let off_ray = (center - origin).length_squared() - along * along;
+            (along >= 0.0 && off_ray <= radius * radius).then_some((candidate, along))
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(candidate, _)| candidate)
+}
+
+/// A system that starts following the agent that was clicked, or a random
+/// car, and stops following on Escape.
+pub fn update_follow_events(
+    mut follow_events: EventReader<FollowAgentEvent>,
+    mut players: Query<(&mut Player, &Camera, &GlobalTransform)>,
+    agents: Query<(Entity, &Agent, &GlobalTransform, &ViewVisibility)>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    input_mode: Res<InputMode>,
+    scale: Res<WorldScale>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let escape = keyboard_input.just_pressed(KeyCode::Escape) && *input_mode != InputMode::TextEntry;
+    let events = follow_events.read().copied().chain(escape.then_some(FollowAgentEvent::Stop));
+    for event in events {
+        for (mut player, camera, camera_transform) in &mut players {
+            let agent = match event {
+                FollowAgentEvent::Pick(cursor) => {
+                    let Some(ray) = camera.viewport_to_world(camera_transform, cursor) else {
+                        continue;
+                    };
+                    // only agents that are drawn can be clicked
+                    let candidates = agents
+                        .iter()
+                        .filter(|(_, _, _, visibility)| visibility.get())
+                        .map(|(entity, _, transform, _)| (entity, transform.translation()));
+                    pick_along_ray(ray.origin, *ray.direction, candidates, scale.units(PICK_RADIUS))
+                }
+                FollowAgentEvent::RandomCar => {
+                    let car = agents
+                        .iter()
+                        .filter(|(_, agent, _, _)| matches!(agent.agent_type, AgentType::Car))
+                        .map(|(entity, ..)| entity)
+                        .choose(&mut rand::thread_rng());
+                    if car.is_none() {
+                        status_events.send(StatusEvent::Error(AppError::MissingData {
+                            message: "there are no cars to follow".to_owned(),
+                        }));
+                    }
+                    car
+                }
+                FollowAgentEvent::Stop => {
+                    // the camera stays where it is
+                    if matches!(player.camera_mode, CameraMode::Follow(_)) {
+                        player.camera_mode = CameraMode::Perspective;
+                    }
+                    continue;
+                }
+            };
+            if let Some(agent) = agent {
+                player.camera_mode = CameraMode::Follow(FollowCamera::new(agent, &scale));
+            }
+        }
+    }
+}
+
+/// A system that moves the camera towards its chase position behind the
+/// followed agent, and looks at a point a bit ahead of the agent. Returns to
+/// free movement when the agent is gone.
+pub fn update_follow_camera(
+    mut players: Query<(&mut Player, &mut Transform), Without<Agent>>,
+    agents: Query<&GlobalTransform, With<Agent>>,
+    time: Res<Time>,
+    scale: Res<WorldScale>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let delta_seconds = time.delta_seconds();
+    for (mut player, mut transform) in &mut players {
+        let CameraMode::Follow(mut follow) = player.camera_mode else {
+            continue;
+        };
+        let Ok(agent_transform) = agents.get(follow.agent) else {
+            player.camera_mode = CameraMode::Perspective;
+            status_events.send(StatusEvent::Update("The followed agent is gone".to_owned()));
+            continue;
+        };
+        let (_, agent_rotation, position) = agent_transform.to_scale_rotation_translation();
+
+        if let Some(last_position) = follow.last_position {
+            let moved = position - last_position;
+            if delta_seconds > 0.0 && moved.length() <= scale.units(MAX_FRAME_DISTANCE) {
+                let t = 1.0 - (-VELOCITY_SMOOTHING * delta_seconds).exp();
+                follow.velocity = follow.velocity.lerp(moved / delta_seconds, t);
+            }
+        }
+        follow.last_position = Some(position);
+
+        // agents face where they drive, along their z-axis
+        let forward = agent_rotation * Vec3::Z;
+        let heading = forward.x.atan2(forward.z);
+        let rotation = Quat::from_euler(EulerRot::YXZ, heading + PI + follow.yaw, follow.pitch, 0.0);
+        let focus = position + Vec3::Y * scale.units(FOCUS_HEIGHT) + follow.velocity * LOOK_AHEAD;
+        let chase_position = focus + rotation * Vec3::Z * follow.distance;
+
+        let t = 1.0 - (-FOLLOW_SMOOTHING * delta_seconds).exp();
+        transform.translation = transform.translation.lerp(chase_position, t);
+        transform.translation.y = transform.translation.y.max(scale.units(1.5));
+        transform.look_at(focus, Vec3::Y);
+
+        player.camera_mode = CameraMode::Follow(follow);
+    }
+}
+
+/// A system that registers the commands of following agents.
+pub fn register_follow_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Follow random car",
+        "Rides along with a random car; the mouse turns the camera around it",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                world.send_event(FollowAgentEvent::RandomCar);
+            })
+        },
+    );
+    registry.register(
+        "Stop following agent",
+        "Returns to the free camera where it is",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                world.send_event(FollowAgentEvent::Stop);
+            })
+        },
+    );
+}
@@ -2,7 +2,45 @@ use bevy::prelude::*;
 
 use std::f32::consts::PI;
 
-use crate::earth::GLOBAL_SCALE_FACTOR;
+use crate::commands::CommandRegistry;
+use crate::data::geography::WorldScale;
+
+use self::follow::FollowCamera;
+
+pub mod follow;
+
+// Lengths are in world units at the default scale, see `WorldScale::units`.
+
+/// The default height of the camera in the top-down map mode.
+const DEFAULT_MAP_HEIGHT: f32 = 2000.0;
+
+/// The orthographic scale the map mode starts with, in world units per pixel.
+const DEFAULT_MAP_SCALE: f32 = 1.0;
+
+/// The range of orthographic scales that can be zoomed to in the map mode.
+const MAP_SCALE_RANGE: std::ops::RangeInclusive<f32> = 0.05..=20.0;
+
+/// How much the orthographic scale changes per line scrolled.
+const MAP_ZOOM_FACTOR: f32 = 1.1;
+
+/// How much the distance to the focus changes per line scrolled, in the orbit
+/// mode.
+const ORBIT_ZOOM_FACTOR: f32 = 1.1;
+
+/// The range of distances between the camera and its focus in the orbit mode.
+const ORBIT_DISTANCE_RANGE: std::ops::RangeInclusive<f32> = 5.0..=5000.0;
+
+/// The distance to the focus when the camera does not look at the ground
+/// while switching to the orbit mode.
+const DEFAULT_ORBIT_DISTANCE: f32 = 200.0;
+
+/// How far the focus moves when panning in the orbit mode, as a fraction of
+/// the distance to the focus per pixel that the mouse was moved.
+const ORBIT_PAN_SPEED: f32 = 0.002;
+
+/// The range of pitches of the camera in the orbit mode, so it stays above
+/// the ground and does not flip over.
+const ORBIT_PITCH_RANGE: std::ops::RangeInclusive<f32> = -0.49 * PI..=-0.02 * PI;
 
 #[derive(Component, Debug)]
 pub struct Player {
@@ -11,6 +49,46 @@ pub struct Player {
 
     /// In radians per pixel that the mouse was moved.
     pub rotation_speed: f32,
+
+    /// How the camera looks at the world.
+    pub camera_mode: CameraMode,
+
+    /// The height of the camera in the top-down map mode.
+    pub map_height: f32,
+
+    /// Where the player was last teleported to when data was loaded. Used as
+    /// the focus of the orbit mode if the camera does not look at the ground.
+    pub teleport_target: Option<Vec3>,
+}
+
+/// How the camera of the player looks at the world.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CameraMode {
+    /// Free movement with a perspective projection.
+    Perspective,
+    /// Looking straight down with an orthographic projection, like a map.
+    /// Remembers the perspective transform to return to.
+    TopDown { previous: Transform },
+    /// Turning around a focus point on the ground, for screenshots.
+    Orbit { focus: Vec3 },
+    /// Riding along with an agent, see `follow`.
+    Follow(FollowCamera),
+}
+
+/// Switches the camera of the player between free movement and one of the
+/// other camera modes.
+#[derive(Clone, Copy, Debug, Event)]
+pub enum ToggleCameraModeEvent {
+    /// Toggles the top-down map mode.
+    Map,
+    /// Toggles the orbit mode.
+    Orbit,
+}
+
+/// Moves the player to a point on the ground, in world coordinates.
+#[derive(Debug, Event)]
+pub struct PlayerTeleportEvent {
+    pub position: Vec2,
 }
 
 #[derive(Debug, Event)]
@@ -18,57 +96,298 @@ pub struct PlayerMoveEvent {
     pub translation: Vec3,
     pub rotation: Vec2,
     pub do_panning: bool,
+    /// The number of lines scrolled, positive when scrolling up.
+    pub scroll: f32,
+    /// Whether the left mouse button is held, which turns the camera around
+    /// the focus in the orbit mode.
+    pub left_drag: bool,
+    /// Whether the middle mouse button is held, which moves the focus in the
+    /// orbit mode.
+    pub middle_drag: bool,
 }
 
 /// Spawns a player.
-pub fn setup_player(mut commands: Commands) {
+pub fn setup_player(mut commands: Commands, scale: Res<WorldScale>) {
     commands.spawn((
         Player {
-            translation_speed: 2.0 * GLOBAL_SCALE_FACTOR,
+            translation_speed: scale.units(200.0),
             rotation_speed: 0.002 * PI,
+            camera_mode: CameraMode::Perspective,
+            map_height: scale.units(DEFAULT_MAP_HEIGHT),
+            teleport_target: None,
         },
         Camera3dBundle {
-            transform: Transform::from_xyz(0.0, 10.0 * GLOBAL_SCALE_FACTOR, 0.0)
+            transform: Transform::from_xyz(0.0, scale.units(1000.0), 0.0)
                 .looking_at(Vec3::ZERO, Vec3::Y),
+            projection: Projection::Perspective(perspective_projection(&scale)),
             ..default()
         },
     ));
 }
 
+/// Returns the perspective projection of the camera, with clipping planes
+/// that are as far away at every scale of the world.
+pub fn perspective_projection(scale: &WorldScale) -> PerspectiveProjection {
+    PerspectiveProjection {
+        near: scale.units(0.1),
+        far: scale.units(1000.0),
+        ..default()
+    }
+}
+
 pub fn update_player(
-    mut query: Query<(&Player, &mut Transform)>,
+    mut query: Query<(&mut Player, &mut Transform, &mut Projection)>,
     mut move_events: EventReader<PlayerMoveEvent>,
     time: Res<Time>,
+    scale: Res<WorldScale>,
 ) {
     for event in move_events.read() {
-        for (player, mut transform) in &mut query {
-            // Multiply the translation by the height factor
-            let height_factor = f32::max(1.0, f32::powf(transform.translation.y / 100.0, 0.8)); // Exponent at the end to make speed increase not exponential the higher you go
-
-            let (yaw, pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
-            let mut new_yaw = yaw - event.rotation.x * player.rotation_speed;
-            let new_pitch =
-                (pitch - event.rotation.y * player.rotation_speed).clamp(-0.5 * PI, 0.5 * PI);
-
-            if event.do_panning {
-                // should pan the camera a bit to the right
-                // add yaw to right
-                new_yaw = yaw - 0.0005;
+        for (mut player, mut transform, mut projection) in &mut query {
+            match player.camera_mode {
+                CameraMode::Perspective => move_free(&player, &mut transform, event, &time, &scale),
+                CameraMode::TopDown { .. } => {
+                    move_map(&player, &mut transform, &mut projection, event, &time, &scale)
+                }
+                CameraMode::Orbit { focus } => {
+                    let focus = move_orbit(&player, &mut transform, focus, event, &time, &scale);
+                    player.camera_mode = CameraMode::Orbit { focus };
+                }
+                // the camera is moved along with the agent instead
+                CameraMode::Follow(mut follow) => {
+                    follow.orbit(&player, event, &scale);
+                    player.camera_mode = CameraMode::Follow(follow);
+                }
+            }
+        }
+    }
+}
+
+/// Moves and turns the camera freely.
+fn move_free(
+    player: &Player,
+    transform: &mut Transform,
+    event: &PlayerMoveEvent,
+    time: &Time,
+    scale: &WorldScale,
+) {
+    // Multiply the translation by the height factor
+    let height_factor = f32::max(1.0, f32::powf(transform.translation.y / scale.units(100.0), 0.8)); // Exponent at the end to make speed increase not exponential the higher you go
+
+    let (yaw, pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
+    let mut new_yaw = yaw - event.rotation.x * player.rotation_speed;
+    let new_pitch = (pitch - event.rotation.y * player.rotation_speed).clamp(-0.5 * PI, 0.5 * PI);
+
+    if event.do_panning {
+        // should pan the camera a bit to the right
+        // add yaw to right
+        new_yaw = yaw - 0.0005;
+    }
+
+    let forward_x = new_yaw.sin();
+    let forward_z = new_yaw.cos();
+    let diff_x = event.translation.x * forward_z + event.translation.z * forward_x;
+    let diff_z = -event.translation.x * forward_x + event.translation.z * forward_z;
+    let diff = Vec3::new(diff_x, event.translation.y, diff_z);
+
+    transform.translation += player.translation_speed * time.delta_seconds() * diff * height_factor;
+    transform.rotation = Quat::from_euler(EulerRot::YXZ, new_yaw, new_pitch, 0.0);
+
+    let min_height = scale.units(1.5);
+    if transform.translation.y < min_height {
+        transform.translation.y = min_height;
+    }
+}
+
+/// Moves the camera over the ground plane in the map mode, faster when zoomed
+/// out, and zooms with the scroll wheel.
+fn move_map(
+    player: &Player,
+    transform: &mut Transform,
+    projection: &mut Projection,
+    event: &PlayerMoveEvent,
+    time: &Time,
+    scale: &WorldScale,
+) {
+    let Projection::Orthographic(ortho) = projection else {
+        return;
+    };
+    if event.scroll != 0.0 {
+        ortho.scale = (ortho.scale * MAP_ZOOM_FACTOR.powf(-event.scroll))
+            .clamp(scale.units(*MAP_SCALE_RANGE.start()), scale.units(*MAP_SCALE_RANGE.end()));
+    }
+    // the speed of the player already scales with the world, the zoom is
+    // relative to the default
+    let zoom = ortho.scale / scale.units(DEFAULT_MAP_SCALE);
+    let diff = Vec3::new(event.translation.x, 0.0, event.translation.z);
+    transform.translation += player.translation_speed * time.delta_seconds() * diff * zoom;
+}
+
+/// Turns the camera around the focus while the left mouse button is held,
+/// moves the focus while the middle mouse button is held or with the movement
+/// keys, and moves closer or further away with the scroll wheel. Returns the
+/// new focus.
+fn move_orbit(
+    player: &Player,
+    transform: &mut Transform,
+    mut focus: Vec3,
+    event: &PlayerMoveEvent,
+    time: &Time,
+    scale: &WorldScale,
+) -> Vec3 {
+    let distance = (transform.translation.distance(focus) * ORBIT_ZOOM_FACTOR.powf(-event.scroll))
+        .clamp(scale.units(*ORBIT_DISTANCE_RANGE.start()), scale.units(*ORBIT_DISTANCE_RANGE.end()));
+
+    let (mut yaw, mut pitch, _) = transform.rotation.to_euler(EulerRot::YXZ);
+    if event.left_drag {
+        yaw -= event.rotation.x * player.rotation_speed;
+        pitch -= event.rotation.y * player.rotation_speed;
+    }
+    pitch = pitch.clamp(*ORBIT_PITCH_RANGE.start(), *ORBIT_PITCH_RANGE.end());
+    let rotation = Quat::from_euler(EulerRot::YXZ, yaw, pitch, 0.0);
+
+    // pan in the ground plane, relative to where the camera looks
+    let right = rotation * Vec3::X;
+    let forward = Vec3::new(-yaw.sin(), 0.0, -yaw.cos());
+    if event.middle_drag {
+        focus +=
+            (-right * event.rotation.x + forward * event.rotation.y) * distance * ORBIT_PAN_SPEED;
+    }
+    let speed = player.translation_speed * time.delta_seconds();
+    focus += (right * event.translation.x - forward * event.translation.z) * speed;
+
+    transform.rotation = rotation;
+    transform.translation = focus - rotation * Vec3::NEG_Z * distance;
+    focus
+}
+
+/// A system that moves the player to where it is teleported. The height of
+/// the camera is kept, unless it is below the ground; the orbit mode keeps
+/// looking at the new position from the same angle, and following an agent
+/// stops.
+pub fn teleport_player(
+    mut query: Query<(&mut Player, &mut Transform)>,
+    mut teleport_events: EventReader<PlayerTeleportEvent>,
+    scale: Res<WorldScale>,
+) {
+    for event in teleport_events.read() {
+        let target = Vec3::new(event.position.x, 0.0, event.position.y);
+        for (mut player, mut transform) in &mut query {
+            player.teleport_target = Some(target);
+            if let CameraMode::Follow(_) = player.camera_mode {
+                player.camera_mode = CameraMode::Perspective;
+            }
+            if let CameraMode::Orbit { focus } = player.camera_mode {
+                transform.translation += target - focus;
+                player.camera_mode = CameraMode::Orbit { focus: target };
+                continue;
+            }
+            transform.translation.x = target.x;
+            transform.translation.z = target.z;
+            // the map mode keeps the camera at its own height
+            if player.camera_mode == CameraMode::Perspective && transform.translation.y <= 0.0 {
+                transform.translation.y = scale.units(5.0);
             }
+        }
+    }
+}
+
+/// A system that registers the commands for the camera modes.
+pub fn register_camera_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle map mode",
+        "Switches between the free camera and a top-down map view",
+        Some(KeyCode::KeyM),
+        |commands| {
+            commands.add(|world: &mut World| {
+                world.send_event(ToggleCameraModeEvent::Map);
+            })
+        },
+    );
+    registry.register(
+        "Toggle orbit camera",
+        "Switches between the free camera and turning around a point on the ground",
+        Some(KeyCode::KeyO),
+        |commands| {
+            commands.add(|world: &mut World| {
+                world.send_event(ToggleCameraModeEvent::Orbit);
+            })
+        },
+    );
+}
 
-            let forward_x = new_yaw.sin();
-            let forward_z = new_yaw.cos();
-            let diff_x = event.translation.x * forward_z + event.translation.z * forward_x;
-            let diff_z = -event.translation.x * forward_x + event.translation.z * forward_z;
-            let diff = Vec3::new(diff_x, event.translation.y, diff_z);
+/// A system that switches the camera of the player between free movement and
+/// the top-down map or orbit mode.
+///
+/// When returning from the map mode, the camera keeps its previous height and
+/// rotation, above the place the map was moved to. Switching between free
+/// movement and the orbit mode keeps the camera where it is; the focus is
+/// where the camera looks at the ground.
+pub fn toggle_camera_mode(
+    mut query: Query<(&mut Player, &mut Transform, &mut Projection)>,
+    mut toggle_events: EventReader<ToggleCameraModeEvent>,
+    scale: Res<WorldScale>,
+) {
+    for event in toggle_events.read() {
+        for (mut player, mut transform, mut projection) in &mut query {
+            let old_mode = player.camera_mode;
 
-            transform.translation +=
-                player.translation_speed * time.delta_seconds() * diff * height_factor;
-            transform.rotation = Quat::from_euler(EulerRot::YXZ, new_yaw, new_pitch, 0.0);
+            // the other modes use the perspective projection of the map mode
+            if let CameraMode::TopDown { previous } = old_mode {
+                player.camera_mode = CameraMode::Perspective;
+                *projection = Projection::Perspective(perspective_projection(&scale));
+                *transform = Transform {
+                    translation: Vec3::new(
+                        transform.translation.x,
+                        previous.translation.y,
+                        transform.translation.z,
+                    ),
+                    ..previous
+                };
+            }
 
-            if transform.translation.y < 1.5 {
-                transform.translation.y = 1.5;
+            match (event, old_mode) {
+                (ToggleCameraModeEvent::Map, CameraMode::TopDown { .. }) => {}
+                (ToggleCameraModeEvent::Map, _) => {
+                    player.camera_mode = CameraMode::TopDown {
+                        previous: *transform,
+                    };
+                    *projection = Projection::Orthographic(OrthographicProjection {
+                        scale: scale.units(DEFAULT_MAP_SCALE),
+                        far: 2.0 * player.map_height,
+                        ..default()
+                    });
+                    *transform = Transform::from_xyz(
+                        transform.translation.x,
+                        player.map_height,
+                        transform.translation.z,
+                    )
+                    .looking_to(Vec3::NEG_Y, Vec3::NEG_Z);
+                }
+                (ToggleCameraModeEvent::Orbit, CameraMode::Orbit { .. }) => {
+                    player.camera_mode = CameraMode::Perspective;
+                }
+                (ToggleCameraModeEvent::Orbit, _) => {
+                    let focus = ground_focus(&transform)
+                        .or(player.teleport_target)
+                        .unwrap_or(
+                            transform.translation + transform.forward() * scale.units(DEFAULT_ORBIT_DISTANCE),
+                        );
+                    // only turns the camera if it did not look at the ground
+                    transform.look_at(focus, Vec3::Y);
+                    player.camera_mode = CameraMode::Orbit { focus };
+                }
             }
         }
     }
 }
+
+/// Returns where the view direction of the camera hits the ground plane, if
+/// the camera looks down.
+fn ground_focus(transform: &Transform) -> Option<Vec3> {
+    let forward = transform.forward();
+    if forward.y >= -f32::EPSILON {
+        return None;
+    }
+    let distance = -transform.translation.y / forward.y;
+    Some(transform.translation + forward * distance)
+}
@@ -1,51 +1,330 @@
-use crate::common::StatusEvent;
-use crate::data::geography::Offset;
-use crate::data::loading::{update_data_queries, update_query_tasks, DataQueryEvent};
+use crate::commands::{
+    register_palette_commands, update_command_keybindings, update_command_palette,
+    CommandPaletteState, CommandRegistry,
+};
+use crate::common::{CancelLoadingEvent, StatusEvent};
+use crate::data::cache::ResponseCache;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::data::city_bin::{register_city_bin_commands, update_geo_data_save_dialog};
+use crate::data::city_bin::{start_geo_data_save, update_geo_data_save, SaveGeoDataEvent};
+use crate::data::geography::{LoadedBounds, Offset, WorldScale};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::data::graph_export::{update_graph_export, update_graph_export_dialog};
+use crate::data::graph_export::{
+    register_graph_export_commands, start_graph_export, ExportGraphEvent, GraphExportState,
+};
+use crate::data::loading::{
+    cancel_data_queries, register_loading_commands, update_data_queries, update_dropped_files, update_osm_conversions,
+    update_overpass_requests, update_query_tasks, update_response_cache_writes, DataAttribution, DataQueryEvent,
+    DroppedFiles, OverpassRequests, OverpassSettings, PlaceMatches,
+};
 use crate::data::traffic_graph::TrafficGraph;
-use crate::earth::agent::update_agents;
+use crate::earth::agent::{
+    register_agent_commands, request_agent_paths, update_agent_route_tasks, update_agents, AgentCommandEvent, AgentSeed,
+    AgentSettings, AgentSpawner,
+};
 use crate::earth::assets::setup_asset_cache;
+use crate::earth::buildings::BuildingFootprints;
+use crate::earth::chunk_stats::{register_chunk_stats_commands, update_chunk_stats_overlay, ChunkStats};
+use crate::earth::day_night::{
+    update_night_materials, update_time_of_day, update_window_patterns, TimeOfDay,
+};
+use crate::earth::floating_origin::{update_floating_origin, OriginShiftEvent};
+use crate::earth::district_stats::{
+    draw_selected_district, register_district_stats_commands, update_district_stats_window,
+    DistrictStats,
+};
+use crate::earth::edge_usage::{
+    decay_edge_usage, register_edge_usage_commands, update_edge_usage_heatmap, EdgeUsage, EdgeUsageHeatmap,
+};
+use crate::earth::generation_settings::{
+    register_generation_settings_commands, setup_generation_settings,
+    update_generation_settings_window, GenerationSettings,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::earth::gltf_export::{register_gltf_export_commands, update_gltf_export_dialog};
+use crate::earth::gltf_export::{
+    start_gltf_export, update_gltf_export, ExportGltfEvent, GltfExportState,
+};
+use crate::earth::layers::{register_layer_commands, update_layer_visibility, LayerVisibility};
+use crate::earth::pipeline_timings::{
+    register_pipeline_timings_commands, update_pipeline_timings_panel, PipelineTimings,
+};
+use crate::earth::roads::update_road_stubs;
+use crate::earth::route::{update_routes, RouteEvent, RoutePlanner};
+use crate::earth::scene_stats::{register_scene_stats_commands, update_scene_stats_window, SceneStats};
+use crate::earth::street_lamps::{setup_street_lamp_lights, update_street_lamps};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::earth::telemetry::{update_telemetry_export, update_telemetry_export_dialog};
+use crate::earth::telemetry::{
+    register_telemetry_commands, start_telemetry_export, update_agent_telemetry, AgentTelemetry,
+    ExportTelemetryEvent,
+};
+use crate::earth::traffic_signals::{update_traffic_signals, TrafficSignals};
+use crate::earth::trees::update_tree_chunks;
 use crate::earth::{
-    setup_earth, update_agent_generation_tasks, update_building_generation_tasks, update_earth, update_river_generation_tasks, update_road_generation_tasks, update_terrain_generation_tasks, GeoDataEvent
+    cancel_generation, clear_world, finish_pipeline_timings, reconcile_agent_count, regenerate_world, register_earth_commands, setup_earth, spawn_mesh_parts, start_queued_generation, update_agent_generation_tasks, update_building_generation_tasks, update_district_stats_tasks, update_earth, update_generation_progress, update_river_generation_tasks, update_road_generation_tasks, update_terrain_generation_tasks, ClearWorldEvent, GeoDataEvent, GenerationProgress, GenerationQueue, LoadedFeatures, LoadedGeoData, RegenerateEvent, MeshPartQueue, SimulationSettings, TunnelSettings
 };
 use crate::lod::lod_system;
-use crate::player::{setup_player, update_player, PlayerMoveEvent};
-use crate::ui::{setup_ui, update_notifications, update_ui, UiState};
+use crate::player::follow::{
+    register_follow_commands, update_follow_camera, update_follow_events, FollowAgentEvent,
+};
+use crate::player::{
+    register_camera_commands, setup_player, teleport_player, toggle_camera_mode, update_player, PlayerMoveEvent,
+    PlayerTeleportEvent, ToggleCameraModeEvent,
+};
+use crate::ui::{
+    setup_ui, update_attribution, update_camera_location, update_file_picker, update_notifications,
+    update_place_picker, update_progress_bar, update_ui, InputMode, UiState,
+};
 
 use crate::fps::{setup_fps, update_fps};
+use crate::screenshot::{
+    register_screenshot_commands, ui_shown, update_saved_screenshots, update_screenshots,
+    Screenshots, TakeScreenshotEvent,
+};
+use crate::startup::{
+    send_startup_query, send_url_query, update_exit_after_load, update_export_after_load,
+    update_page_url, ShareableQuery, StartupArgs,
+};
+use crate::status_log::{register_status_log_commands, update_status_log_window, StatusLog};
+use crate::web_api::{setup_web_api, update_status_callbacks, update_web_api};
+use crate::tutorial::{setup_tutorial, update_tutorial, update_tutorial_card, Tutorial};
+use crate::bookmarks::{
+    register_bookmark_commands, setup_bookmarks, update_bookmark_offsets, update_bookmark_recalls,
+    update_bookmarks_window, Bookmarks, RecallBookmarkEvent,
+};
 
 use bevy::prelude::*;
+use bevy::transform::TransformSystem;
 use bevy_mod_reqwest::ReqwestPlugin;
 
-pub struct CityVisualizerPlugin;
+/// The generation of the world from geographic data and the simulation in it,
+/// without any user interface, input or window. Used by
+/// `CityVisualizerPlugin`, and on its own by headless apps that only need
+/// `MinimalPlugins`, the `AssetPlugin` and the `Mesh`, `Image` and
+/// `StandardMaterial` assets.
+///
+/// Data is added with `GeoDataEvent`s.
+pub struct CityWorldPlugin;
 
-impl Plugin for CityVisualizerPlugin {
+impl Plugin for CityWorldPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins(ReqwestPlugin::default())
-            .add_systems(Startup, setup_asset_cache.before(setup_earth))
+        app.add_systems(Startup, setup_asset_cache.before(setup_earth))
             .add_systems(Startup, setup_earth)
-            .add_systems(Startup, setup_ui)
-            .add_systems(Startup, setup_player)
             .init_resource::<TrafficGraph>()
-            .add_systems(Update, update_data_queries)
-            .add_event::<DataQueryEvent>()
-            .add_systems(Update, update_query_tasks)
+            .init_resource::<AgentSeed>()
+            .init_resource::<AgentSettings>()
+            .init_resource::<BuildingFootprints>()
             .add_systems(Update, update_earth)
+            // kept if the app inserted a queue with another limit
+            .init_resource::<GenerationQueue>()
+            .add_systems(Update, start_queued_generation.after(update_earth))
+            .init_resource::<PipelineTimings>()
+            .init_resource::<SceneStats>()
+            .add_systems(Update, finish_pipeline_timings.before(update_earth))
             .add_event::<GeoDataEvent>()
+            .init_resource::<LoadedFeatures>()
+            .add_systems(Update, clear_world)
+            .add_event::<ClearWorldEvent>()
+            .init_resource::<LoadedGeoData>()
+            .init_resource::<GenerationSettings>()
+            .add_systems(Update, regenerate_world.before(start_queued_generation))
+            .add_event::<RegenerateEvent>()
             .add_systems(Update, update_building_generation_tasks)
             .add_systems(Update, update_road_generation_tasks)
+            .add_systems(Update, update_road_stubs.after(update_road_generation_tasks))
+            .init_resource::<MeshPartQueue>()
+            .add_systems(Update, spawn_mesh_parts)
+            .init_resource::<TunnelSettings>()
+            .init_resource::<LayerVisibility>()
+            .add_systems(Update, update_layer_visibility)
+            .init_resource::<ChunkStats>()
+            .init_resource::<TrafficSignals>()
+            .add_systems(Update, update_traffic_signals)
             .add_systems(Update, update_river_generation_tasks)
             .add_systems(Update, update_terrain_generation_tasks)
             .add_systems(Update, update_agent_generation_tasks)
-            .add_systems(Update, update_ui)
             .add_systems(Update, update_agents)
+            .init_resource::<EdgeUsage>()
+            .init_resource::<AgentSpawner>()
+            .init_resource::<SimulationSettings>()
+            .init_resource::<TimeOfDay>()
+            .add_systems(Update, update_time_of_day)
+            .add_systems(Update, update_night_materials.after(update_time_of_day))
+            .add_systems(Update, update_window_patterns.after(update_time_of_day))
+            .add_systems(Startup, setup_street_lamp_lights)
+            .add_systems(Update, update_street_lamps.after(update_time_of_day))
+            .add_event::<AgentCommandEvent>()
+            .add_systems(Update, reconcile_agent_count)
+            .add_systems(Update, request_agent_paths)
+            .add_systems(Update, update_agent_route_tasks)
+            .init_resource::<AgentTelemetry>()
+            .add_systems(Update, update_agent_telemetry.after(update_agents))
+            .add_event::<ExportTelemetryEvent>()
+            .add_systems(Update, start_telemetry_export)
+            .init_resource::<EdgeUsageHeatmap>()
+            .add_systems(Update, decay_edge_usage.after(update_agents))
             .add_event::<StatusEvent>()
+            .add_event::<CancelLoadingEvent>()
+            .init_resource::<GenerationProgress>()
+            .add_systems(Update, update_generation_progress)
+            .add_systems(Update, cancel_generation)
+            .init_resource::<InputMode>()
+            .init_resource::<DataAttribution>()
+            .add_systems(Update, teleport_player)
+            .add_event::<PlayerTeleportEvent>()
+            .init_resource::<DistrictStats>()
+            .add_systems(Update, update_district_stats_tasks)
+            .add_event::<ExportGltfEvent>()
+            .init_resource::<GltfExportState>()
+            .add_systems(Update, start_gltf_export)
+            .add_systems(Update, update_gltf_export)
+            .add_event::<SaveGeoDataEvent>()
+            .add_systems(Update, start_geo_data_save)
+            .add_systems(Update, update_geo_data_save)
+            .add_event::<ExportGraphEvent>()
+            .init_resource::<GraphExportState>()
+            .add_systems(Update, start_graph_export)
+            // kept if the app inserted a scale before adding the plugin
+            .init_resource::<WorldScale>()
+            .init_resource::<Offset>()
+            .init_resource::<LoadedBounds>()
+            .add_event::<OriginShiftEvent>()
+            .add_systems(
+                PostUpdate,
+                update_floating_origin.before(TransformSystem::TransformPropagate),
+            );
+
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_systems(Update, update_graph_export)
+            .add_systems(Update, update_telemetry_export);
+    }
+}
+
+/// Loading data into the world from files, Overpass and Nominatim, and the
+/// command-line arguments, without any user interface. Used by
+/// `CityVisualizerPlugin`, and on its own by the headless batch mode, see
+/// `headless_app`.
+///
+/// Data is loaded with `DataQueryEvent`s.
+pub struct CityLoaderPlugin;
+
+impl Plugin for CityLoaderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ReqwestPlugin::default())
+            .add_plugins(CityWorldPlugin)
+            .add_systems(Update, update_data_queries)
+            .add_event::<DataQueryEvent>()
+            .add_systems(Update, update_query_tasks)
+            .add_systems(Update, update_response_cache_writes)
+            .add_systems(Update, update_osm_conversions)
+            .init_resource::<OverpassSettings>()
+            .init_resource::<OverpassRequests>()
+            .init_resource::<ResponseCache>()
+            .add_systems(Update, update_overpass_requests)
+            .add_systems(Update, cancel_data_queries)
+            .init_resource::<PlaceMatches>()
+            // kept if the app inserted the parsed arguments
+            .init_resource::<StartupArgs>()
+            .add_systems(PostStartup, send_startup_query)
+            .add_systems(Update, update_exit_after_load)
+            .add_systems(Update, update_export_after_load);
+    }
+}
+
+/// The whole application: the world, loading data, the user interface and
+/// the player. Requires the `DefaultPlugins` and the `EguiPlugin`.
+pub struct CityVisualizerPlugin;
+
+impl Plugin for CityVisualizerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(CityLoaderPlugin)
+            .add_systems(Startup, setup_ui)
+            .add_systems(Startup, setup_player)
+            .init_resource::<CommandRegistry>()
+            .init_resource::<CommandPaletteState>()
+            .add_systems(Startup, register_palette_commands)
+            .add_systems(Startup, register_earth_commands)
+            .add_systems(Startup, register_loading_commands)
+            .add_systems(Startup, register_layer_commands)
+            .add_systems(Startup, register_agent_commands)
+            .add_systems(Update, update_command_palette.run_if(ui_shown))
+            .add_systems(Update, update_command_keybindings)
+            .init_resource::<DroppedFiles>()
+            .add_systems(Update, update_dropped_files)
+            .add_systems(Update, update_place_picker.run_if(ui_shown))
+            .add_systems(Update, update_file_picker)
+            .init_resource::<ShareableQuery>()
+            .add_systems(PostStartup, send_url_query)
+            .add_systems(Update, update_page_url)
+            .add_systems(Startup, setup_web_api.before(setup_ui))
+            .add_systems(Update, update_web_api)
+            .add_systems(Update, update_status_callbacks)
+            .add_systems(Startup, register_pipeline_timings_commands)
+            .add_systems(Update, update_pipeline_timings_panel.run_if(ui_shown))
+            .add_systems(Startup, register_scene_stats_commands)
+            .add_systems(Update, update_scene_stats_window.run_if(ui_shown))
+            .add_systems(Startup, register_chunk_stats_commands)
+            .add_systems(Update, update_chunk_stats_overlay.run_if(ui_shown))
+            .add_systems(Update, update_ui.run_if(ui_shown))
             .init_resource::<UiState>()
             .add_systems(Update, update_notifications)
+            .init_resource::<StatusLog>()
+            .add_systems(Startup, register_status_log_commands)
+            .add_systems(Update, update_status_log_window.run_if(ui_shown))
+            .add_systems(Update, update_progress_bar)
+            .add_systems(Update, update_attribution)
+            .add_systems(Update, update_camera_location)
             .add_systems(Update, update_player)
-            .add_systems(Update, lod_system)
+            .add_systems(Update, lod_system.after(update_layer_visibility).after(update_follow_camera))
+            .add_systems(Update, update_tree_chunks)
             .add_event::<PlayerMoveEvent>()
+            .add_systems(Update, toggle_camera_mode)
+            .add_event::<ToggleCameraModeEvent>()
+            .add_systems(Startup, register_camera_commands)
+            .add_event::<FollowAgentEvent>()
+            .add_systems(Startup, register_follow_commands)
+            .add_systems(Update, update_follow_events.before(update_player))
+            .add_systems(Update, update_follow_camera.after(update_player).after(update_agents))
             .add_systems(Startup, setup_fps)
             .add_systems(Update, update_fps)
-            .init_resource::<Offset>();
+            .init_resource::<Tutorial>()
+            .add_systems(Startup, setup_tutorial)
+            .add_systems(Update, update_tutorial)
+            .add_systems(Update, update_tutorial_card.after(update_tutorial).run_if(ui_shown))
+            .add_systems(Startup, register_district_stats_commands)
+            .add_systems(Update, update_district_stats_window.run_if(ui_shown))
+            .add_systems(Update, draw_selected_district)
+            .init_resource::<Bookmarks>()
+            .add_systems(Startup, setup_bookmarks)
+            .add_systems(Startup, register_bookmark_commands)
+            .add_systems(Update, update_bookmarks_window.run_if(ui_shown))
+            .add_event::<RecallBookmarkEvent>()
+            .add_systems(Update, update_bookmark_recalls.after(update_bookmarks_window))
+            .add_systems(Update, update_bookmark_offsets)
+            .add_systems(Startup, setup_generation_settings)
+            .add_systems(Startup, register_generation_settings_commands)
+            .add_systems(Update, update_generation_settings_window.run_if(ui_shown))
+            .add_systems(Startup, register_graph_export_commands)
+            .init_resource::<Screenshots>()
+            .add_event::<TakeScreenshotEvent>()
+            .add_systems(Startup, register_screenshot_commands)
+            .add_systems(Update, update_screenshots)
+            .add_systems(Update, update_saved_screenshots)
+            .add_systems(Startup, register_telemetry_commands)
+            .add_systems(Update, update_edge_usage_heatmap.after(decay_edge_usage))
+            .init_resource::<RoutePlanner>()
+            .add_event::<RouteEvent>()
+            .add_systems(Update, update_routes)
+            .add_systems(Startup, register_edge_usage_commands);
+
+        #[cfg(not(target_arch = "wasm32"))]
+        app.add_systems(Startup, register_gltf_export_commands)
+            .add_systems(Update, update_gltf_export_dialog)
+            .add_systems(Update, update_graph_export_dialog)
+            .add_systems(Startup, register_city_bin_commands)
+            .add_systems(Update, update_geo_data_save_dialog)
+            .add_systems(Update, update_telemetry_export_dialog);
     }
 }
@@ -0,0 +1,280 @@
+//! Screenshots of the view, taken with F12 or the button in the panel. On
+//! native they are written to `screenshots/city-YYYYMMDD-HHMMSS.png` in the
+//! working directory, with the time in UTC, and in the web version the
+//! browser downloads them.
+//!
+//! Optionally the egui windows, the FPS counter and the notifications are
+//! hidden while the screenshot is taken. The attribution of the data stays,
+//! since it has to be shown with the map.
+
+use crate::commands::CommandRegistry;
+#[cfg(target_arch = "wasm32")]
+use crate::common::download_file;
+use crate::common::{handle_compute_tasks, spawn_compute_task, AppError, AsyncComputation, StatusEvent};
+use crate::fps::FPSContainer;
+use crate::ui::NotificationText;
+
+use bevy::prelude::*;
+use bevy::render::view::screenshot::ScreenshotManager;
+use bevy::window::PrimaryWindow;
+
+use image::ImageOutputFormat;
+
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// The directory that screenshots are written to on native.
+#[cfg(not(target_arch = "wasm32"))]
+const SCREENSHOT_DIR: &str = "screenshots";
+
+/// An event that takes a screenshot of the primary window.
+#[derive(Debug, Event)]
+pub struct TakeScreenshotEvent;
+
+/// The settings and progress of screenshots.
+#[derive(Default, Resource)]
+pub struct Screenshots {
+    /// Whether the UI is hidden while a screenshot is taken.
+    pub hide_ui: bool,
+    stage: ScreenshotStage,
+    /// The visibility of the overlays from before they were hidden.
+    hidden_overlays: Vec<(Entity, Visibility)>,
+}
+
+#[derive(Default)]
+enum ScreenshotStage {
+    #[default]
+    Idle,
+    /// The UI is hidden this frame, and the screenshot is taken next frame,
+    /// once the hidden UI is rendered.
+    HidingUi,
+    /// The screenshot was requested from the renderer, which puts it here
+    /// once the frame is rendered.
+    Capturing(Arc<Mutex<Option<Image>>>),
+}
+
+impl Screenshots {
+    /// Returns whether a screenshot is being taken.
+    pub fn is_busy(&self) -> bool {
+        !matches!(self.stage, ScreenshotStage::Idle)
+    }
+
+    /// Returns whether the UI is hidden for a screenshot.
+    fn is_hiding_ui(&self) -> bool {
+        self.hide_ui && self.is_busy()
+    }
+}
+
+/// A run condition for systems that draw egui windows, which are skipped
+/// while the UI is hidden for a screenshot. The progress bar is not skipped,
+/// since it has to read every `StatusEvent`.
+pub fn ui_shown(screenshots: Res<Screenshots>) -> bool {
+    !screenshots.is_hiding_ui()
+}
+
+/// The result of saving a screenshot: where it was written or downloaded to.
+pub struct SavedScreenshot(Result<PathBuf, AppError>);
+
+/// Returns the name of a screenshot taken at the given Unix time, in seconds,
+/// which is read as UTC.
+///
+/// ```
+/// use city_visualizer::screenshot::screenshot_file_name;
+///
+/// assert_eq!(screenshot_file_name(0), "city-19700101-000000.png");
+/// assert_eq!(screenshot_file_name(1_700_000_000), "city-20231114-221320.png");
+/// assert_eq!(screenshot_file_name(951_782_400), "city-20000229-000000.png");
+/// ```
+pub fn screenshot_file_name(unix_seconds: u64) -> String {
+    // the civil date of the days since 1970, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (unix_seconds / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let seconds = unix_seconds % 86_400;
+    format!(
+        "city-{:04}{:02}{:02}-{:02}{:02}{:02}.png",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+    )
+}
+
+/// Returns the current Unix time in seconds. Browsers do not give the time
+/// to `SystemTime`, so there it is asked from JavaScript.
+fn unix_seconds() -> u64 {
+    #[cfg(not(target_arch = "wasm32"))]
+    return std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+
+    #[cfg(target_arch = "wasm32")]
+    return (js_sys::Date::now() / 1000.0) as u64;
+}
+
+/// A system that takes a screenshot for `TakeScreenshotEvent`s: it hides the
+/// UI first if asked to, requests the screenshot from the renderer, and
+/// saves it once it is rendered.
+pub fn update_screenshots(
+    mut commands: Commands,
+    mut screenshot_events: EventReader<TakeScreenshotEvent>,
+    mut screenshots: ResMut<Screenshots>,
+    mut screenshot_manager: ResMut<ScreenshotManager>,
+    windows: Query<Entity, With<PrimaryWindow>>,
+    mut overlays: Query<(Entity, &mut Visibility), Or<(With<FPSContainer>, With<NotificationText>)>>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let requested = screenshot_events.read().count() > 0;
+    let screenshots = &mut *screenshots;
+
+    match &screenshots.stage {
+        ScreenshotStage::Idle if requested && screenshots.hide_ui => {
+            for (entity, mut visibility) in &mut overlays {
+                screenshots.hidden_overlays.push((entity, *visibility));
+                *visibility = Visibility::Hidden;
+            }
+            screenshots.stage = ScreenshotStage::HidingUi;
+        }
+        ScreenshotStage::Idle if requested => {
+            screenshots.stage = capture(&mut screenshot_manager, &windows, &mut status_events);
+        }
+        ScreenshotStage::Idle => {}
+        ScreenshotStage::HidingUi => {
+            screenshots.stage = capture(&mut screenshot_manager, &windows, &mut status_events);
+        }
+        ScreenshotStage::Capturing(result) => {
+            let Some(image) = result.lock().ok().and_then(|mut result| result.take()) else {
+                return;
+            };
+            screenshots.stage = ScreenshotStage::Idle;
+            let name = screenshot_file_name(unix_seconds());
+            spawn_compute_task(&mut commands, async move { SavedScreenshot(save_screenshot(image, name)) });
+        }
+    }
+
+    // shows the overlays again once the screenshot is taken, or could not be
+    if !screenshots.is_busy() {
+        for (entity, visibility) in screenshots.hidden_overlays.drain(..) {
+            if let Ok((_, mut current)) = overlays.get_mut(entity) {
+                *current = visibility;
+            }
+        }
+    }
+}
+
+/// Requests a screenshot of the primary window from the renderer, and
+/// returns the stage to wait for it, or `Idle` if it cannot be taken.
+fn capture(
+    screenshot_manager: &mut ScreenshotManager,
+    windows: &Query<Entity, With<PrimaryWindow>>,
+    status_events: &mut EventWriter<StatusEvent>,
+) -> ScreenshotStage {
+    let Ok(window) = windows.get_single() else {
+        status_events.send(StatusEvent::Error(AppError::MissingData {
+            message: "there is no window to take a screenshot of".to_owned(),
+        }));
+        return ScreenshotStage::Idle;
+    };
+    let result = Arc::new(Mutex::new(None));
+    let sender = Arc::clone(&result);
+    let requested = screenshot_manager.take_screenshot(window, move |image| {
+        if let Ok(mut result) = sender.lock() {
+            *result = Some(image);
+        }
+    });
+    match requested {
+        Ok(()) => ScreenshotStage::Capturing(result),
+        // another screenshot of the window is taken this frame, which is not
+        // one of ours, so it is not waited for
+        Err(()) => {
+            status_events.send(StatusEvent::Error(AppError::MissingData {
+                message: "a screenshot is being taken already, try again".to_owned(),
+            }));
+            ScreenshotStage::Idle
+        }
+    }
+}
+
+/// Encodes a screenshot as PNG, and writes it to the screenshot directory
+/// or has the browser download it.
+fn save_screenshot(image: Image, name: String) -> Result<PathBuf, AppError> {
+    let encode_error = |message: String| AppError::Io {
+        url: None,
+        status: None,
+        message: format!("could not encode the screenshot: {}", message),
+    };
+    let image = image.try_into_dynamic().map_err(|error| encode_error(error.to_string()))?;
+    let mut bytes = Vec::new();
+    image
+        .to_rgb8()
+        .write_to(&mut Cursor::new(&mut bytes), ImageOutputFormat::Png)
+        .map_err(|error| encode_error(error.to_string()))?;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let dir = PathBuf::from(SCREENSHOT_DIR);
+        std::fs::create_dir_all(&dir).map_err(|error| AppError::from_io_error(error, &dir))?;
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).map_err(|error| AppError::from_io_error(error, &path))?;
+        Ok(path)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    {
+        download_file(&name, "image/png", &bytes);
+        Ok(PathBuf::from(name))
+    }
+}
+
+/// A system that reports where screenshots were saved, or why they could not
+/// be saved.
+pub fn update_saved_screenshots(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<SavedScreenshot>)>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    handle_compute_tasks(&mut commands, query, |_, SavedScreenshot(result)| match result {
+        Ok(path) => {
+            status_events.send(StatusEvent::Update(format!("Saved a screenshot to {}", path.display())));
+        }
+        Err(error) => {
+            status_events.send(StatusEvent::Error(error));
+        }
+    });
+}
+
+/// A system that registers the commands of screenshots.
+pub fn register_screenshot_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Take screenshot",
+        "Saves a screenshot of the view as a PNG file",
+        Some(KeyCode::F12),
+        |commands| {
+            commands.add(|world: &mut World| {
+                world.send_event(TakeScreenshotEvent);
+            })
+        },
+    );
+    registry.register(
+        "Toggle hiding the UI in screenshots",
+        "Hides the panels, the FPS counter and the notifications while a screenshot is taken",
+        None,
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut screenshots = world.resource_mut::<Screenshots>();
+                screenshots.hide_ui = !screenshots.hide_ui;
+            })
+        },
+    );
+}
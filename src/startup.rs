@@ -0,0 +1,477 @@
+//! Command-line arguments, which can load data at startup without using the
+//! UI, for example to test loading a set of files in a script. In the web
+//! version, the parameters of the page URL are used instead, and the URL is
+//! updated with the loaded query so that it can be shared.
+
+use crate::common::{AppError, StatusEvent};
+use crate::data::geography::WorldScale;
+use crate::data::graph_export::{ExportGraphEvent, GraphExportState};
+use crate::data::loading::{DataAttribution, DataQueryEvent};
+use crate::data::query::{city_name_query, parse_data_query, DataQuery, InputQueryType};
+use crate::earth::gltf_export::{ExportGltfEvent, GltfExportState};
+use crate::earth::agent::{AgentSeed, AgentSettings};
+use crate::earth::{is_world_settled, GeoDataEvent};
+use crate::plugin::CityLoaderPlugin;
+
+use bevy::app::AppExit;
+use bevy::ecs::event::ManualEventReader;
+use bevy::prelude::*;
+
+use bevy_mod_reqwest::reqwest::Url;
+
+use std::path::PathBuf;
+
+/// The URL parameters that queries are shared as, with the type of query
+/// that their values are.
+const URL_PARAMS: [(&str, InputQueryType); 2] = [
+    ("city", InputQueryType::City),
+    ("bbox", InputQueryType::BBox),
+];
+
+/// How to use the command-line arguments, printed for `--help` and invalid
+/// arguments.
+pub const USAGE: &str = "\
+Usage: city_visualizer [OPTIONS]
+
+Options:
+  --file <PATH>           Load an OSM JSON or GeoJSON file at startup
+  --city <NAME>           Load the first area with this name from Overpass at startup
+  --overpass-file <PATH>  Load the result of the OverpassQL query in this file at startup
+  --exit-after-load       Exit once the data is in the world, or with a failure if loading fails
+  --headless              Load, generate and export without a window, then exit; implies
+                          --exit-after-load
+  --export-gltf <PATH>    Export the world to a GLB file once the data is in it
+  --export-graph <PATH>   Export the traffic graph once the data is in the world, as DOT for .dot
+                          and .gv files and as GraphML otherwise
+  --scale <UNITS>         World units per meter, 0.25 by default; 1.0 draws the world in meters
+  --seed <NUMBER>         Seed of the agents, so loading the same data spawns the same agents
+  -h, --help              Print this message";
+
+/// The command-line arguments the app was started with.
+#[derive(Clone, Debug, Default, Resource)]
+pub struct StartupArgs {
+    /// The query that is loaded at startup.
+    pub query: Option<DataQuery>,
+    /// Whether the app exits once the data of `query` is in the world.
+    pub exit_after_load: bool,
+    /// Whether the app runs without a window, see `headless_app`.
+    pub headless: bool,
+    /// The GLB file that the world is exported to once the data of `query`
+    /// is in it. The app exits after the export if `exit_after_load` is set.
+    pub export_gltf: Option<PathBuf>,
+    /// The file that the traffic graph is exported to once the data of
+    /// `query` is in the world, like `export_gltf`.
+    pub export_graph: Option<PathBuf>,
+    /// The scale of the world.
+    pub scale: WorldScale,
+    /// The seed that agents are created from, or None for a random seed.
+    pub seed: Option<u64>,
+}
+
+/// Why the command-line arguments could not be used.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ArgsError {
+    /// `--help` was given, so only the usage should be printed.
+    Help,
+    Invalid(String),
+}
+
+/// Parses the command-line arguments, without the name of the program.
+///
+/// ```
+/// use city_visualizer::data::query::DataQuery;
+/// use city_visualizer::startup::{parse_args, ArgsError};
+///
+/// let args = parse_args(["--city", "Delft", "--exit-after-load"].map(String::from)).unwrap();
+/// assert!(matches!(args.query, Some(DataQuery::OverpassQL { .. })));
+/// assert!(args.exit_after_load);
+///
+/// let args = parse_args(["--file", "data/eindhoven.json"].map(String::from)).unwrap();
+/// assert!(matches!(args.query, Some(DataQuery::File { .. })));
+///
+/// let args = parse_args(["--scale", "1.0"].map(String::from)).unwrap();
+/// assert_eq!(args.scale.units_per_meter, 1.0);
+/// assert!(matches!(parse_args(["--scale", "0"].map(String::from)), Err(ArgsError::Invalid(_))));
+///
+/// let args = parse_args(["--seed", "42"].map(String::from)).unwrap();
+/// assert_eq!(args.seed, Some(42));
+/// assert!(parse_args(Vec::new()).unwrap().seed.is_none());
+/// assert!(matches!(parse_args(["--seed", "-1"].map(String::from)), Err(ArgsError::Invalid(_))));
+///
+/// assert!(parse_args(Vec::new()).unwrap().query.is_none());
+/// assert_eq!(parse_args(["--help"].map(String::from)).unwrap_err(), ArgsError::Help);
+/// assert!(matches!(parse_args(["--city"].map(String::from)), Err(ArgsError::Invalid(_))));
+/// assert!(matches!(parse_args(["--exit-after-load"].map(String::from)), Err(ArgsError::Invalid(_))));
+///
+/// let args = parse_args(["--file", "delft.json", "--export-gltf", "delft.glb"].map(String::from));
+/// assert_eq!(args.unwrap().export_gltf.unwrap().to_str(), Some("delft.glb"));
+/// assert!(matches!(parse_args(["--export-gltf", "delft.glb"].map(String::from)), Err(ArgsError::Invalid(_))));
+///
+/// let args = parse_args(["--file", "delft.json", "--export-graph", "delft.graphml"].map(String::from));
+/// assert_eq!(args.unwrap().export_graph.unwrap().to_str(), Some("delft.graphml"));
+/// assert!(matches!(parse_args(["--export-graph", "delft.dot"].map(String::from)), Err(ArgsError::Invalid(_))));
+///
+/// let args = parse_args(["--headless", "--file", "delft.json", "--export-gltf", "delft.glb"].map(String::from)).unwrap();
+/// assert!(args.headless && args.exit_after_load);
+/// assert!(matches!(parse_args(["--headless"].map(String::from)), Err(ArgsError::Invalid(_))));
+/// assert!(matches!(
+///     parse_args(["--city", "Delft", "--file", "delft.json"].map(String::from)),
+///     Err(ArgsError::Invalid(_)),
+/// ));
+/// ```
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<StartupArgs, ArgsError> {
+    let mut result = StartupArgs::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let query = match arg.as_str() {
+            "-h" | "--help" => return Err(ArgsError::Help),
+            "--exit-after-load" => {
+                result.exit_after_load = true;
+                continue;
+            }
+            // without a window, there is nothing to do once the data is
+            // generated and exported
+            "--headless" => {
+                result.headless = true;
+                result.exit_after_load = true;
+                continue;
+            }
+            "--export-gltf" => {
+                result.export_gltf = Some(PathBuf::from(expect_value(&arg, args.next())?));
+                continue;
+            }
+            "--export-graph" => {
+                result.export_graph = Some(PathBuf::from(expect_value(&arg, args.next())?));
+                continue;
+            }
+            "--scale" => {
+                let value = expect_value(&arg, args.next())?;
+                result.scale = parse_scale(&value)?;
+                continue;
+            }
+            "--seed" => {
+                let value = expect_value(&arg, args.next())?;
+                result.seed = Some(value.parse().map_err(|_| {
+                    ArgsError::Invalid(format!("--seed needs a whole number, not {}", value))
+                })?);
+                continue;
+            }
+            "--file" => {
+                let path = expect_value(&arg, args.next())?;
+                parse_data_query(InputQueryType::File, &path)
+            }
+            // without the UI, there is no way to choose between places with
+            // the same name, so the first area with the name is loaded
+            "--city" => {
+                let name = expect_value(&arg, args.next())?;
+                parse_data_query(InputQueryType::City, &name).map(|_| city_name_query(&name))
+            }
+            "--overpass-file" => {
+                let path = expect_value(&arg, args.next())?;
+                let query = std::fs::read_to_string(&path).map_err(|error| {
+                    ArgsError::Invalid(format!("could not read {}: {}", path, error))
+                })?;
+                parse_data_query(InputQueryType::Overpass, query.trim())
+            }
+            _ => return Err(ArgsError::Invalid(format!("unknown argument {}", arg))),
+        };
+        let query = query.map_err(|error| ArgsError::Invalid(format!("{}: {}", arg, error)))?;
+        if result.query.replace(query).is_some() {
+            return Err(ArgsError::Invalid(
+                "only one of --file, --city and --overpass-file can be given".to_owned(),
+            ));
+        }
+    }
+
+    if result.headless && result.query.is_none() {
+        return Err(ArgsError::Invalid(
+            "--headless needs data to load with --file, --city or --overpass-file".to_owned(),
+        ));
+    }
+    if result.exit_after_load && result.query.is_none() {
+        return Err(ArgsError::Invalid(
+            "--exit-after-load needs data to load with --file, --city or --overpass-file"
+                .to_owned(),
+        ));
+    }
+    if result.export_gltf.is_some() && result.query.is_none() {
+        return Err(ArgsError::Invalid(
+            "--export-gltf needs data to load with --file, --city or --overpass-file".to_owned(),
+        ));
+    }
+    if result.export_graph.is_some() && result.query.is_none() {
+        return Err(ArgsError::Invalid(
+            "--export-graph needs data to load with --file, --city or --overpass-file".to_owned(),
+        ));
+    }
+    Ok(result)
+}
+
+fn expect_value(arg: &str, value: Option<String>) -> Result<String, ArgsError> {
+    value.ok_or_else(|| ArgsError::Invalid(format!("{} needs a value", arg)))
+}
+
+/// Parses the value of `--scale`, which must be a positive number.
+fn parse_scale(value: &str) -> Result<WorldScale, ArgsError> {
+    match value.parse::<f32>() {
+        Ok(units_per_meter) if units_per_meter.is_finite() && units_per_meter > 0.0 => {
+            Ok(WorldScale { units_per_meter })
+        }
+        _ => Err(ArgsError::Invalid(format!(
+            "--scale needs a positive number, not {}",
+            value
+        ))),
+    }
+}
+
+/// A system that sends the query of the command-line arguments. It runs
+/// after startup, so the asset cache and the UI are set up by then.
+pub fn send_startup_query(
+    startup_args: Res<StartupArgs>,
+    mut data_query_events: EventWriter<DataQueryEvent>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    if let Some(query) = &startup_args.query {
+        status_events.send(StatusEvent::Update(
+            "Loading the data given on the command line".to_owned(),
+        ));
+        data_query_events.send(DataQueryEvent {
+            query: query.clone(),
+        });
+    }
+}
+
+/// A system that exits the app once the data of the command-line arguments
+/// is in the world, and exported if `--export-gltf` or `--export-graph` was
+/// given, if `--exit-after-load` was given. If loading or exporting fails, the
+/// process exits with a failure right away, since `AppExit` cannot carry an
+/// exit code.
+pub fn update_exit_after_load(
+    world: &mut World,
+    mut status_reader: Local<ManualEventReader<StatusEvent>>,
+    mut loaded: Local<bool>,
+) {
+    let startup_args = world.resource::<StartupArgs>();
+    if !startup_args.exit_after_load {
+        return;
+    }
+    let (export_gltf, export_graph) = (
+        startup_args.export_gltf.is_some(),
+        startup_args.export_graph.is_some(),
+    );
+
+    for event in status_reader.read(world.resource::<Events<StatusEvent>>()) {
+        if let StatusEvent::Error(error) = event {
+            eprintln!("Failed: {}", error);
+            std::process::exit(1);
+        }
+    }
+
+    // the data is shown once it was imported and sent to the world
+    *loaded |= world.resource::<DataAttribution>().shown;
+    let exported = (!export_gltf || world.resource::<GltfExportState>().finished > 0)
+        && (!export_graph || world.resource::<GraphExportState>().finished > 0);
+    if *loaded && exported && is_world_settled(world) {
+        println!("Loaded the data, exiting");
+        world.send_event(AppExit);
+    }
+}
+
+/// A system that exports the world to the file given with `--export-gltf`,
+/// and the traffic graph to the file given with `--export-graph`, once the
+/// data of the command-line arguments is in the world.
+pub fn update_export_after_load(world: &mut World, mut exported: Local<bool>) {
+    if *exported {
+        return;
+    }
+    let startup_args = world.resource::<StartupArgs>();
+    let (gltf_path, graph_path) = (startup_args.export_gltf.clone(), startup_args.export_graph.clone());
+    if gltf_path.is_none() && graph_path.is_none() {
+        return;
+    }
+    if world.resource::<DataAttribution>().shown && is_world_settled(world) {
+        if let Some(path) = gltf_path {
+            world.send_event(ExportGltfEvent { path });
+        }
+        if let Some(path) = graph_path {
+            world.send_event(ExportGraphEvent { path });
+        }
+        *exported = true;
+    }
+}
+
+/// Returns an app that loads the data of the command-line arguments without
+/// a window, for `--headless`: it generates the world, exports it if
+/// `--export-gltf` or `--export-graph` was given, and exits once all tasks
+/// are done. If anything fails, the process exits with a failure.
+///
+/// The app only has the `MinimalPlugins`, the transforms and the assets that
+/// the world keeps its meshes and materials in, so meshes stay on the CPU and
+/// nothing is rendered. Agents are not spawned, since they are not exported.
+pub fn headless_app(startup_args: StartupArgs) -> App {
+    let mut app = App::new();
+    // inserted before the plugins, so they are not replaced by the defaults
+    app.insert_resource(startup_args.scale);
+    if let Some(seed) = startup_args.seed {
+        app.insert_resource(AgentSeed(seed));
+    }
+    app.insert_resource(startup_args)
+        .insert_resource(AgentSettings {
+            enabled: false,
+            ..default()
+        })
+        .add_plugins(MinimalPlugins)
+        .add_plugins((TransformPlugin, HierarchyPlugin))
+        .add_plugins(AssetPlugin::default())
+        .init_asset::<Mesh>()
+        .init_asset::<Image>()
+        .init_asset::<StandardMaterial>()
+        .add_plugins(CityLoaderPlugin)
+        .add_systems(Update, print_status_updates);
+    app
+}
+
+/// A system that prints status updates, since there is no UI to show them
+/// in without a window. Errors are printed by `update_exit_after_load`.
+fn print_status_updates(mut status_events: EventReader<StatusEvent>) {
+    for event in status_events.read() {
+        if let StatusEvent::Update(message) = event {
+            println!("{}", message);
+        }
+    }
+}
+
+/// The query that is being loaded, as a URL parameter, which is written to
+/// the page URL once its data is loaded. `None` if the query cannot be
+/// shared.
+#[derive(Debug, Default, Resource)]
+pub struct ShareableQuery {
+    pub pending: Option<(&'static str, String)>,
+}
+
+/// Returns the URL parameter that a query entered in the loader panel is
+/// shared as, if it can be shared.
+pub fn query_url_param(query_type: InputQueryType, string: &str) -> Option<(&'static str, String)> {
+    URL_PARAMS
+        .iter()
+        .find(|(_, param_type)| *param_type == query_type)
+        .map(|(key, _)| (*key, string.trim().to_owned()))
+}
+
+/// Parses the query in the parameters of a page URL, such as `?city=Utrecht`
+/// or `?bbox=52.08,5.10,52.10,5.13`. Unknown parameters are ignored.
+///
+/// ```
+/// use bevy_mod_reqwest::reqwest::Url;
+/// use city_visualizer::data::query::DataQuery;
+/// use city_visualizer::startup::{parse_url_query, with_url_query};
+///
+/// let url = Url::parse("https://example.com/?lang=nl&city=Den%20Haag").unwrap();
+/// assert!(matches!(parse_url_query(&url), Ok(Some(DataQuery::City { name })) if name == "Den Haag"));
+///
+/// let url = Url::parse("https://example.com/?bbox=52.08,5.10,52.10,5.13").unwrap();
+/// assert!(matches!(parse_url_query(&url), Ok(Some(DataQuery::OverpassQL { .. }))));
+///
+/// let url = Url::parse("https://example.com/?bbox=52.10,5.10,52.08,5.13").unwrap();
+/// assert!(parse_url_query(&url).is_err());
+/// assert!(matches!(parse_url_query(&Url::parse("https://example.com/?lang=nl").unwrap()), Ok(None)));
+///
+/// // the query replaces the previous one, and other parameters are kept
+/// let url = Url::parse("https://example.com/?lang=nl&city=Delft").unwrap();
+/// let url = with_url_query(&url, "bbox", "52.08, 5.10, 52.10, 5.13");
+/// assert_eq!(url.as_str(), "https://example.com/?lang=nl&bbox=52.08%2C+5.10%2C+52.10%2C+5.13");
+/// ```
+pub fn parse_url_query(url: &Url) -> Result<Option<DataQuery>, AppError> {
+    for (key, value) in url.query_pairs() {
+        if let Some((_, query_type)) = URL_PARAMS.iter().find(|(param, _)| *param == key) {
+            return parse_data_query(*query_type, &value).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Returns `url` with its query parameter replaced by `key=value`.
+pub fn with_url_query(url: &Url, key: &str, value: &str) -> Url {
+    let others: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(param, _)| {
+            URL_PARAMS
+                .iter()
+                .all(|(query_param, _)| param != query_param)
+        })
+        .map(|(param, value)| (param.into_owned(), value.into_owned()))
+        .collect();
+    let mut url = url.clone();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(others)
+        .append_pair(key, value);
+    url
+}
+
+/// A system that sends the query in the page URL, in the web version. It
+/// runs after startup, like `send_startup_query`.
+pub fn send_url_query(
+    mut data_query_events: EventWriter<DataQueryEvent>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let Some(url) = page_url() else {
+        return;
+    };
+    match parse_url_query(&url) {
+        Ok(Some(query)) => {
+            status_events.send(StatusEvent::Update(
+                "Loading the data in the page URL".to_owned(),
+            ));
+            data_query_events.send(DataQueryEvent { query });
+        }
+        Ok(None) => {}
+        Err(error) => {
+            status_events.send(StatusEvent::Error(error));
+        }
+    }
+}
+
+/// A system that writes the query of the data that was just loaded to the
+/// page URL, in the web version, so that the view can be shared.
+pub fn update_page_url(
+    mut geo_data_events: EventReader<GeoDataEvent>,
+    mut shareable_query: ResMut<ShareableQuery>,
+) {
+    if geo_data_events.read().count() == 0 {
+        return;
+    }
+    let (Some((key, value)), Some(url)) = (shareable_query.pending.take(), page_url()) else {
+        return;
+    };
+    replace_page_url(&with_url_query(&url, key, &value));
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn page_url() -> Option<Url> {
+    None
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn replace_page_url(_url: &Url) {}
+
+#[cfg(target_arch = "wasm32")]
+fn page_url() -> Option<Url> {
+    let href = web_sys::window()?.location().href().ok()?;
+    Url::parse(&href).ok()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn replace_page_url(url: &Url) {
+    // replacing instead of pushing the state keeps the back button working
+    let replaced = web_sys::window()
+        .and_then(|window| window.history().ok())
+        .map_or(false, |history| {
+            history
+                .replace_state_with_url(&wasm_bindgen::JsValue::NULL, "", Some(url.as_str()))
+                .is_ok()
+        });
+    if !replaced {
+        web_sys::console::warn_1(&"Could not update the page URL".into());
+    }
+}
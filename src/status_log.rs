@@ -0,0 +1,192 @@
+//! A history of the status messages that are shown in the corner of the
+//! screen, so errors can still be read after their notification is gone.
+
+use crate::commands::CommandRegistry;
+
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use bevy_egui::egui;
+use bevy_egui::EguiContexts;
+
+use std::collections::VecDeque;
+
+/// The number of messages that are kept, older messages are dropped.
+pub const MAX_LOG_ENTRIES: usize = 200;
+
+/// How serious a logged message is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Info,
+    Error,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// A logged status message.
+#[derive(Clone, Debug)]
+pub struct LogEntry {
+    /// How long after the start of the app the message was shown.
+    pub time: Duration,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl LogEntry {
+    /// Returns the entry as a line of text, e.g.
+    /// `[00:01:05] error: Could not load`.
+    pub fn format(&self) -> String {
+        let seconds = self.time.as_secs();
+        format!(
+            "[{:02}:{:02}:{:02}] {}: {}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            self.severity.name(),
+            self.message,
+        )
+    }
+}
+
+/// The last `MAX_LOG_ENTRIES` status messages, and which of them are shown
+/// in the log window.
+///
+/// ```
+/// use bevy::utils::Duration;
+/// use city_visualizer::status_log::{Severity, StatusLog, MAX_LOG_ENTRIES};
+///
+/// let mut log = StatusLog::default();
+/// for i in 0..MAX_LOG_ENTRIES + 10 {
+///     log.push(Duration::from_secs(i as u64), Severity::Info, format!("Message {}", i));
+/// }
+/// log.push(Duration::from_secs(3725), Severity::Error, "Could not load".to_owned());
+///
+/// assert_eq!(log.entries().count(), MAX_LOG_ENTRIES);
+/// assert_eq!(log.entries().next().unwrap().message, "Message 11");
+/// log.show_info = false;
+/// assert_eq!(log.shown_text(), "[01:02:05] error: Could not load");
+/// ```
+#[derive(Debug, Resource)]
+pub struct StatusLog {
+    entries: VecDeque<LogEntry>,
+    pub show_info: bool,
+    pub show_errors: bool,
+    /// Whether the log window is shown.
+    pub window_visible: bool,
+}
+
+impl Default for StatusLog {
+    fn default() -> Self {
+        StatusLog {
+            entries: VecDeque::new(),
+            show_info: true,
+            show_errors: true,
+            window_visible: false,
+        }
+    }
+}
+
+impl StatusLog {
+    /// Adds a message, dropping the oldest one if the log is full.
+    pub fn push(&mut self, time: Duration, severity: Severity, message: String) {
+        if self.entries.len() == MAX_LOG_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(LogEntry {
+            time,
+            severity,
+            message,
+        });
+    }
+
+    /// Returns all entries, from old to new.
+    pub fn entries(&self) -> impl Iterator<Item = &LogEntry> {
+        self.entries.iter()
+    }
+
+    /// Returns whether entries of the given severity are shown.
+    pub fn is_shown(&self, severity: Severity) -> bool {
+        match severity {
+            Severity::Info => self.show_info,
+            Severity::Error => self.show_errors,
+        }
+    }
+
+    /// Returns the shown entries as text, one line per entry.
+    pub fn shown_text(&self) -> String {
+        self.entries()
+            .filter(|entry| self.is_shown(entry.severity))
+            .map(LogEntry::format)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Registers the command to show the log in the command palette.
+pub fn register_status_log_commands(mut registry: ResMut<CommandRegistry>) {
+    registry.register(
+        "Toggle log",
+        "Shows the last status messages and errors",
+        Some(KeyCode::KeyL),
+        |commands| {
+            commands.add(|world: &mut World| {
+                let mut log = world.resource_mut::<StatusLog>();
+                log.window_visible = !log.window_visible;
+            })
+        },
+    );
+}
+
+/// A system that shows the log window, with the newest messages at the
+/// bottom.
+pub fn update_status_log_window(mut contexts: EguiContexts, mut log: ResMut<StatusLog>) {
+    if !log.window_visible {
+        return;
+    }
+
+    let mut visible = true;
+    let mut copy = false;
+    egui::Window::new("Log")
+        .open(&mut visible)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut log.show_info, "Info");
+                ui.checkbox(&mut log.show_errors, "Errors");
+                copy = ui.button("Copy all").clicked();
+            });
+            ui.separator();
+
+            if log.entries().next().is_none() {
+                ui.label("No messages yet");
+            }
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .stick_to_bottom(true)
+                .show(ui, |ui| {
+                    for entry in log.entries().filter(|entry| log.is_shown(entry.severity)) {
+                        let text = egui::RichText::new(entry.format());
+                        let text = match entry.severity {
+                            Severity::Info => text,
+                            Severity::Error => text.color(egui::Color32::RED),
+                        };
+                        ui.label(text);
+                    }
+                });
+        });
+
+    if copy {
+        let text = log.shown_text();
+        contexts
+            .ctx_mut()
+            .output_mut(|output| output.copied_text = text);
+    }
+    if !visible {
+        log.window_visible = false;
+    }
+}
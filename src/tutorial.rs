@@ -0,0 +1,178 @@
+//! A short tutorial for first-time users, which explains how to load a city
+//! and move around. Every step is shown on a card, and the tutorial advances
+//! by itself when the user does what the card says. Once finished or skipped,
+//! it is not shown again.
+
+use crate::data::loading::DataQueryEvent;
+use crate::earth::GeoDataEvent;
+use crate::player::PlayerMoveEvent;
+use crate::ui::InputMode;
+
+use bevy::prelude::*;
+use bevy_egui::egui;
+use bevy_egui::EguiContexts;
+
+/// The name of the file that marks the tutorial as completed, on native.
+#[cfg(not(target_arch = "wasm32"))]
+const TUTORIAL_COMPLETED_FILE: &str = ".tutorial_completed";
+
+/// The key in the local storage that marks the tutorial as completed, on the
+/// web.
+#[cfg(target_arch = "wasm32")]
+const TUTORIAL_COMPLETED_KEY: &str = "city_visualizer_tutorial_completed";
+
+/// A step of the tutorial.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TutorialStep {
+    /// Waits for the user to focus the query text box.
+    TypeCity,
+    /// Waits for the user to submit a query.
+    Submit,
+    /// Waits for the data of the query to arrive.
+    Loading,
+    /// Waits for the user to move the camera.
+    Move,
+    /// The tutorial is finished or skipped.
+    Done,
+}
+
+impl TutorialStep {
+    /// Returns the step after this one.
+    pub fn next(self) -> Self {
+        match self {
+            TutorialStep::TypeCity => TutorialStep::Submit,
+            TutorialStep::Submit => TutorialStep::Loading,
+            TutorialStep::Loading => TutorialStep::Move,
+            TutorialStep::Move | TutorialStep::Done => TutorialStep::Done,
+        }
+    }
+
+    /// Returns the text shown on the card of this step.
+    pub fn instruction(self) -> &'static str {
+        match self {
+            TutorialStep::TypeCity => "1. Press Tab and type the name of a small city",
+            TutorialStep::Submit => "2. Press Enter to load the city",
+            TutorialStep::Loading => "Loading the city, this can take a moment...",
+            TutorialStep::Move => "3. Click the view and use WASD to move around",
+            TutorialStep::Done => "",
+        }
+    }
+}
+
+/// The progress of the user through the tutorial.
+#[derive(Debug, Resource)]
+pub struct Tutorial {
+    pub step: TutorialStep,
+}
+
+impl Default for Tutorial {
+    fn default() -> Self {
+        Tutorial {
+            step: TutorialStep::TypeCity,
+        }
+    }
+}
+
+impl Tutorial {
+    /// Moves to the next step. Finishing the tutorial is remembered, so it is
+    /// not shown on the next launch.
+    pub fn advance(&mut self) {
+        self.step = self.step.next();
+        if self.step == TutorialStep::Done {
+            save_tutorial_completed();
+        }
+    }
+
+    /// Ends the tutorial, and remembers that it should not be shown again.
+    pub fn skip(&mut self) {
+        self.step = TutorialStep::Done;
+        save_tutorial_completed();
+    }
+}
+
+/// A system that ends the tutorial right away if it was completed before.
+pub fn setup_tutorial(mut tutorial: ResMut<Tutorial>) {
+    if is_tutorial_completed() {
+        tutorial.step = TutorialStep::Done;
+    }
+}
+
+/// A system that advances the tutorial when the user does what the current
+/// step asks for.
+pub fn update_tutorial(
+    mut tutorial: ResMut<Tutorial>,
+    input_mode: Res<InputMode>,
+    mut data_query_events: EventReader<DataQueryEvent>,
+    mut geo_data_events: EventReader<GeoDataEvent>,
+    mut player_move_events: EventReader<PlayerMoveEvent>,
+) {
+    let submitted = data_query_events.read().count() > 0;
+    let loaded = geo_data_events.read().count() > 0;
+    let moved = player_move_events.read().count() > 0;
+
+    let done = match tutorial.step {
+        TutorialStep::TypeCity => *input_mode == InputMode::TextEntry || submitted,
+        TutorialStep::Submit => submitted,
+        TutorialStep::Loading => loaded,
+        TutorialStep::Move => moved,
+        TutorialStep::Done => false,
+    };
+    if done {
+        tutorial.advance();
+    }
+}
+
+/// A system that shows the card of the current step of the tutorial.
+pub fn update_tutorial_card(mut contexts: EguiContexts, mut tutorial: ResMut<Tutorial>) {
+    if tutorial.step == TutorialStep::Done {
+        return;
+    }
+
+    egui::Window::new("Getting started")
+        .id("tutorial_card".into())
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_BOTTOM, [0.0, -40.0])
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(tutorial.step.instruction());
+            ui.horizontal(|ui| {
+                if ui.button("Next").clicked() {
+                    tutorial.advance();
+                }
+                if ui.button("Skip tutorial").clicked() {
+                    tutorial.skip();
+                }
+            });
+        });
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn is_tutorial_completed() -> bool {
+    std::path::Path::new(TUTORIAL_COMPLETED_FILE).exists()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn save_tutorial_completed() {
+    // not being able to save only means the tutorial is shown again
+    let _ = std::fs::write(TUTORIAL_COMPLETED_FILE, "");
+}
+
+#[cfg(target_arch = "wasm32")]
+fn local_storage() -> Option<web_sys::Storage> {
+    web_sys::window()?.local_storage().ok().flatten()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn is_tutorial_completed() -> bool {
+    local_storage()
+        .and_then(|storage| storage.get_item(TUTORIAL_COMPLETED_KEY).ok().flatten())
+        .is_some()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn save_tutorial_completed() {
+    // not being able to save only means the tutorial is shown again
+    if let Some(storage) = local_storage() {
+        let _ = storage.set_item(TUTORIAL_COMPLETED_KEY, "true");
+    }
+}
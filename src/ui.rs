@@ -1,42 +1,210 @@
-use crate::common::StatusEvent;
-use crate::data::loading::DataQueryEvent;
-use crate::data::query::{parse_data_query, InputQueryType};
-use crate::player::PlayerMoveEvent;
+use crate::common::{
+    handle_compute_tasks, AppError, AsyncComputation, CancelLoadingEvent, StatusEvent,
+};
+use crate::data::cache::ResponseCache;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::data::city_bin::spawn_geo_data_save_dialog;
+use crate::data::loading::{DataAttribution, DataQueryEvent, OverpassSettings, PlaceMatches};
+use crate::data::geography::{GeoLocation, Offset};
+use crate::data::graph_export::request_graph_export;
+use crate::data::query::{
+    bounding_box_query, city_name_query, format_from_extension, parse_coordinates, parse_data_query,
+    DataQuery, InputQueryType,
+};
+use crate::earth::agent::{
+    AgentCommandEvent, AgentMix, AgentType, DEFAULT_MAX_ROUTE_DISTANCE, DEFAULT_TARGET_AGENTS,
+};
+use crate::earth::day_night::TimeOfDay;
+use crate::earth::edge_usage::{reset_edge_usage, ColorRamp, EdgeUsageHeatmap, HALF_LIFE_RANGE};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::earth::gltf_export::spawn_gltf_export_dialog;
+use crate::earth::layers::{FeatureLayer, LayerVisibility};
+use crate::earth::route::{RouteEvent, RoutePlanner};
+use crate::earth::telemetry::{
+    request_telemetry_export, toggle_telemetry_recording, AgentTelemetry, SAMPLE_INTERVAL_RANGE,
+};
+use crate::earth::{RegenerateEvent, SimulationSettings, TIME_SCALE_RANGE};
+use crate::player::follow::FollowAgentEvent;
+use crate::player::{Player, PlayerMoveEvent, PlayerTeleportEvent, ToggleCameraModeEvent};
+use crate::screenshot::{Screenshots, TakeScreenshotEvent};
+use crate::startup::{query_url_param, ShareableQuery};
+use crate::status_log::{Severity, StatusLog};
 use wasm_bindgen::prelude::*;
-use bevy::input::mouse::MouseMotion;
+use bevy::input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel};
+use bevy::math::DVec2;
 use bevy::prelude::*;
 use bevy::text::BreakLineOn;
+use bevy::utils::Instant;
 use bevy::window::{CursorGrabMode, PresentMode, PrimaryWindow};
 
 use bevy_egui::egui;
 use bevy_egui::EguiContexts;
 
 use std::collections::vec_deque::VecDeque;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[wasm_bindgen]
 extern {
     fn setup_finished();
 }
 
+/// How far (in logical pixels) a locked cursor may move before the platform
+/// is assumed not to support locking it.
+const CURSOR_LOCK_TOLERANCE: f32 = 2.0;
+
+/// The highest target number of agents that can be chosen in the UI.
+const MAX_TARGET_AGENTS: usize = 10_000;
+
+/// The maximum route distances of agents that can be chosen in the UI, in
+/// world units at the default scale.
+const MAX_ROUTE_DISTANCE_RANGE: RangeInclusive<f32> = 100.0..=5000.0;
+
+/// The highest speed of the time of day that can be chosen in the UI, in hours
+/// per second.
+const MAX_TIME_OF_DAY_SPEED: f32 = 2.0;
+
+/// The number of frames between updates of the shown camera location, so the
+/// digits can be read while moving.
+const CAMERA_LOCATION_INTERVAL: u32 = 10;
+
+/// The radii that can be chosen for loading more data around the camera, in
+/// meters.
+const LOAD_RADIUS_RANGE: RangeInclusive<f64> = 250.0..=3000.0;
+
+/// The timeouts of Overpass endpoints that can be chosen, in seconds.
+const OVERPASS_TIMEOUT_RANGE: RangeInclusive<u64> = 10..=600;
+
 /// The state of the UI, such as values for input fields, excluding the main
 /// earth panel.
 #[derive(Debug, Resource)]
 pub struct UiState {
     pub cursor_locked: bool,
+    /// Where the cursor was when it was grabbed, used to detect whether the
+    /// platform honours `CursorGrabMode::Locked`.
+    pub grab_position: Option<Vec2>,
+    /// Whether the platform ignored `CursorGrabMode::Locked` before, in which
+    /// case the cursor is confined and recentered every frame instead.
+    pub locked_mode_unsupported: bool,
     pub query: String,
     pub query_type: InputQueryType,
+    /// Whether city queries are looked up with Nominatim first, so the user
+    /// can choose between places with the same name.
+    pub look_up_cities: bool,
+    /// The number of agents to keep in the world.
+    pub agent_target: usize,
+    /// The number of agents added by the spawn button.
+    pub agent_spawn_count: usize,
+    pub agent_mix: AgentMix,
+    pub agent_max_route_distance: f32,
+    /// The coordinates entered in the "Go to" field.
+    pub go_to: String,
+    /// Where the camera is on earth, if data is loaded.
+    pub camera_location: Option<GeoLocation>,
+    /// How far around the camera more data is loaded, in meters.
+    pub load_radius: f64,
 }
 
 impl Default for UiState {
     fn default() -> Self {
         UiState {
             cursor_locked: false,
+            grab_position: None,
+            locked_mode_unsupported: false,
             query: String::new(),
             query_type: InputQueryType::City,
+            look_up_cities: true,
+            agent_target: DEFAULT_TARGET_AGENTS,
+            agent_spawn_count: 100,
+            agent_mix: AgentMix::default(),
+            agent_max_route_distance: DEFAULT_MAX_ROUTE_DISTANCE,
+            go_to: String::new(),
+            camera_location: None,
+            load_radius: 1000.0,
         }
     }
 }
 
+/// A data file that was chosen with the "Browse…" button.
+pub enum PickedFile {
+    /// On native, the path of the file is entered as the query.
+    Path(PathBuf),
+    /// Browsers do not give the paths of files, so their contents are loaded
+    /// right away instead.
+    Contents { name: String, bytes: Vec<u8> },
+}
+
+/// Opens a dialog to choose a data file, without blocking the frame. The
+/// chosen file is handled by `update_file_picker`.
+fn spawn_file_picker(commands: &mut Commands) {
+    let dialog = rfd::AsyncFileDialog::new()
+        .set_title("Load a data file")
+        .add_filter("OSM JSON, GeoJSON or saved data", &["json", "geojson", "gz", "citybin"]);
+
+    #[cfg(not(target_arch = "wasm32"))]
+    crate::common::spawn_compute_task(commands, async move {
+        dialog.pick_file().await.map(|file| PickedFile::Path(file.path().to_owned()))
+    });
+
+    // the dialog cannot be sent to another thread in browsers, but there
+    // tasks run on the main thread anyway
+    #[cfg(target_arch = "wasm32")]
+    {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        bevy::tasks::AsyncComputeTaskPool::get().spawn(async move {
+            let picked = match dialog.pick_file().await {
+                Some(file) => Some(PickedFile::Contents {
+                    name: file.file_name(),
+                    bytes: file.read().await,
+                }),
+                None => None,
+            };
+            let _ = sender.send(picked);
+        }).detach();
+        commands.spawn((AsyncComputation { receiver }, crate::common::PendingComputation));
+    }
+}
+
+/// A system that handles the file that was chosen in the dialog of the
+/// "Browse…" button. Nothing happens if the dialog was cancelled.
+pub fn update_file_picker(
+    mut commands: Commands,
+    query: Query<(Entity, &mut AsyncComputation<Option<PickedFile>>)>,
+    mut ui_state: ResMut<UiState>,
+    mut data_load_events: EventWriter<DataQueryEvent>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    handle_compute_tasks(&mut commands, query, |_, picked| match picked {
+        Some(PickedFile::Path(path)) => {
+            ui_state.query = path.display().to_string();
+        }
+        Some(PickedFile::Contents { name, bytes }) => {
+            status_events.send(StatusEvent::Update(format!("Loading {}", name)));
+            data_load_events.send(DataQueryEvent {
+                query: DataQuery::FileContents {
+                    format: format_from_extension(Path::new(&name)),
+                    name,
+                    bytes: bytes.into(),
+                },
+            });
+        }
+        None => {}
+    });
+}
+
+/// What the input of the user is currently directed at.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Resource)]
+pub enum InputMode {
+    /// The cursor is free and used for the UI.
+    #[default]
+    Interface,
+    /// The cursor is locked and moves the camera.
+    Camera,
+    /// The query text box has focus, and keys are typed into it.
+    TextEntry,
+}
+
 /// A system that sets up the UI and window.
 ///
 /// Right now, it maximizes the window and sets a title, and adds an entity for
@@ -73,6 +241,29 @@ pub fn setup_ui(mut commands: Commands, mut windows: Query<&mut Window, With<Pri
         },
     ));
 
+    // add attribution text entity, which is shown whenever OSM data is shown
+    commands.spawn((
+        AttributionText,
+        TextBundle {
+            text: Text::from_section(
+                "",
+                TextStyle {
+                    color: ATTRIBUTION_COLOR,
+                    font_size: ATTRIBUTION_FONT_SIZE,
+                    ..default()
+                },
+            ),
+            style: Style {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(5.0),
+                right: Val::Px(10.0),
+                ..default()
+            },
+            background_color: BackgroundColor(Color::rgba(0.0, 0.0, 0.0, 0.5)),
+            ..default()
+        },
+    ));
+
     // Communicate with the JavaScript that the setup is finished
     setup_finished();
 }
@@ -86,20 +277,48 @@ pub fn update_ui(
     mut windows: Query<&mut Window, With<PrimaryWindow>>,
     mut contexts: EguiContexts,
     mut ui_state: ResMut<UiState>,
+    mut input_mode: ResMut<InputMode>,
+    mut simulation: ResMut<SimulationSettings>,
+    (mut time_of_day, mut layers, mut screenshots, mut telemetry, mut heatmap, mut route_planner): (
+        ResMut<TimeOfDay>,
+        ResMut<LayerVisibility>,
+        ResMut<Screenshots>,
+        ResMut<AgentTelemetry>,
+        ResMut<EdgeUsageHeatmap>,
+        ResMut<RoutePlanner>,
+    ),
     // input events
     keyboard_input: Res<ButtonInput<KeyCode>>,
     mouse_button_input: Res<ButtonInput<MouseButton>>,
     mut mouse_motion_input: EventReader<MouseMotion>,
+    mut mouse_wheel_input: EventReader<MouseWheel>,
     // generated events
     mut player_move_events: EventWriter<PlayerMoveEvent>,
     mut data_load_events: EventWriter<DataQueryEvent>,
     mut status_events: EventWriter<StatusEvent>,
+    mut agent_command_events: EventWriter<AgentCommandEvent>,
+    (mut camera_mode_events, mut teleport_events, mut regenerate_events, mut screenshot_events, mut follow_events, mut route_events): (
+        EventWriter<ToggleCameraModeEvent>,
+        EventWriter<PlayerTeleportEvent>,
+        EventWriter<RegenerateEvent>,
+        EventWriter<TakeScreenshotEvent>,
+        EventWriter<FollowAgentEvent>,
+        EventWriter<RouteEvent>,
+    ),
+    (offset, mut overpass_settings, mut response_cache, mut commands, mut shareable_query): (
+        Res<Offset>,
+        ResMut<OverpassSettings>,
+        ResMut<ResponseCache>,
+        Commands,
+        ResMut<ShareableQuery>,
+    ),
 ) {
     // we only have one window, so the primary window is always used
     let mut primary_window = windows.single_mut();
     let ctx = contexts.ctx_mut();
 
     let window = egui::Window::new("Earth Loader Panel").id("earth_loader_panel".into());
+    let mut query_focused = false;
 
     window.show(ctx, |ui| {
         ui.label("Enter a query to load it");
@@ -107,22 +326,48 @@ pub fn update_ui(
         egui::ComboBox::from_id_source("query_type")
             .selected_text(match &ui_state.query_type {
                 InputQueryType::City => "City",
+                InputQueryType::BBox => "Bounding box",
                 InputQueryType::File => "File",
                 InputQueryType::Overpass => "Overpass API",
+                InputQueryType::GeoJson => "GeoJSON snippet",
             })
             .show_ui(ui, |ui| {
                 ui.selectable_value(&mut ui_state.query_type, InputQueryType::City, "City");
+                ui.selectable_value(
+                    &mut ui_state.query_type,
+                    InputQueryType::BBox,
+                    "Bounding box",
+                );
                 ui.selectable_value(&mut ui_state.query_type, InputQueryType::File, "File");
                 ui.selectable_value(
                     &mut ui_state.query_type,
                     InputQueryType::Overpass,
                     "Overpass API",
                 );
+                ui.selectable_value(
+                    &mut ui_state.query_type,
+                    InputQueryType::GeoJson,
+                    "GeoJSON snippet",
+                );
             });
 
+        if ui_state.query_type == InputQueryType::City {
+            ui.checkbox(&mut ui_state.look_up_cities, "Look up the city first")
+                .on_hover_text("Finds all places with the name, to choose which one is loaded");
+        }
+
         // Add the multiline text element and capture the response
+        let hint = match ui_state.query_type {
+            InputQueryType::BBox => "Press TAB to enter south, west, north, east...",
+            InputQueryType::File => "Press TAB to enter a file path...",
+            _ => "Press TAB to enter city...",
+        };
         let response = ui.add(egui::TextEdit::multiline(&mut ui_state.query)
-            .hint_text("Press TAB to enter city..."));
+            .hint_text(hint));
+
+        if ui_state.query_type == InputQueryType::File && ui.button("Browse…").clicked() {
+            spawn_file_picker(&mut commands);
+        }
 
         // Set focus to the text edit if the user presses tab
         if ui.input(|i| i.key_pressed(egui::Key::Tab)) {
@@ -132,18 +377,26 @@ pub fn update_ui(
             ui_state.query.clear();
         }
 
+        query_focused = response.has_focus();
+
         // If the user presses enter while the text edit is focused, load the data
         let submit_using_enter: bool = keyboard_input.just_pressed(KeyCode::Enter) && response.has_focus();
         if ui.button("LOAD - press ENTER").clicked() || submit_using_enter {
             // Remove \n (newline) characters from the query
             ui_state.query = ui_state.query.replace("\n", "");
 
-            match parse_data_query(ui_state.query_type, &ui_state.query) {
+            let query = match parse_data_query(ui_state.query_type, &ui_state.query) {
+                // without looking it up, the first area with the name is loaded
+                Ok(DataQuery::City { name }) if !ui_state.look_up_cities => Ok(city_name_query(&name)),
+                query => query,
+            };
+            match query {
                 Ok(query) => {
                     status_events.send(StatusEvent::Update(
                         "Succesfully parsed query, now handling it".to_owned(),
                     ));
                     data_load_events.send(DataQueryEvent { query });
+                    shareable_query.pending = query_url_param(ui_state.query_type, &ui_state.query);
                 }
                 Err(error) => {
                     status_events.send(StatusEvent::Error(error));
@@ -152,8 +405,392 @@ pub fn update_ui(
 
             ui_state.query.clear();
         }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        if ui
+            .button("Save loaded data…")
+            .on_hover_text("Saves the data of the world as a .citybin file, which loads much faster than the original file")
+            .clicked()
+        {
+            spawn_geo_data_save_dialog(&mut commands);
+        }
+
+        // Teleport to coordinates in the loaded data
+        let mut go_to_submitted = false;
+        ui.horizontal(|ui| {
+            let response = ui.add(
+                egui::TextEdit::singleline(&mut ui_state.go_to)
+                    .hint_text("lat, lon or a map link")
+                    .desired_width(160.0),
+            );
+            query_focused |= response.has_focus();
+            let submit_using_enter =
+                response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+            go_to_submitted = ui.button("Go to").clicked() || submit_using_enter;
+        });
+        if go_to_submitted {
+            match parse_coordinates(&ui_state.go_to) {
+                // the offset is only set once data is loaded
+                Ok(_) if offset.x == f64::NEG_INFINITY => {
+                    status_events.send(StatusEvent::Error(AppError::MissingData {
+                        message: "load data before going to coordinates, so they can be \
+                            placed in the world"
+                            .to_owned(),
+                    }));
+                }
+                Ok(location) => {
+                    teleport_events.send(PlayerTeleportEvent {
+                        position: location.project(&offset),
+                    });
+                    ui_state.go_to.clear();
+                }
+                Err(error) => {
+                    status_events.send(StatusEvent::Error(error));
+                }
+            }
+        }
+
+        if let Some(location) = &ui_state.camera_location {
+            let coordinates = format!("{:.6}, {:.6}", location.latitude, location.longitude);
+            ui.horizontal(|ui| {
+                ui.label(format!("Camera at {}", coordinates));
+                if ui.small_button("Copy").clicked() {
+                    ui.output_mut(|output| output.copied_text = coordinates.clone());
+                }
+            });
+        }
+
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(!screenshots.is_busy(), egui::Button::new("Screenshot"))
+                .on_hover_text("Saves a screenshot of the view as a PNG file (F12)")
+                .clicked()
+            {
+                screenshot_events.send(TakeScreenshotEvent);
+            }
+            ui.checkbox(&mut screenshots.hide_ui, "Hide UI")
+                .on_hover_text("Hides the panels, the FPS counter and the notifications in screenshots");
+        });
+
+        // Load the area around the camera, which is added to the loaded data
+        // when it is close enough
+        ui.horizontal(|ui| {
+            let query = ui_state
+                .camera_location
+                .as_ref()
+                .map(|location| bounding_box_query(location, ui_state.load_radius));
+            let button = ui.add_enabled(query.is_some(), egui::Button::new("Load more around me"));
+            let button = match &query {
+                Some(DataQuery::OverpassQL { value }) => button.on_hover_text(value.as_str()),
+                _ => button.on_disabled_hover_text("Load data first"),
+            };
+            match query {
+                Some(query) if button.clicked() => {
+                    status_events.send(StatusEvent::Update(
+                        "Loading the area around the camera".to_owned(),
+                    ));
+                    data_load_events.send(DataQueryEvent { query });
+                }
+                _ => {}
+            }
+            ui.add(egui::Slider::new(&mut ui_state.load_radius, LOAD_RADIUS_RANGE).text("Radius (m)"));
+        });
+
+        ui.collapsing("Overpass settings", |ui| {
+            ui.label("Endpoints, tried in order when one is busy or does not answer");
+            let endpoints = &mut overpass_settings.endpoints;
+            let mut move_up = None;
+            let mut remove = None;
+            for (i, endpoint) in endpoints.iter_mut().enumerate() {
+                ui.horizontal(|ui| {
+                    let response = ui.add(egui::TextEdit::singleline(endpoint).desired_width(240.0));
+                    query_focused |= response.has_focus();
+                    if ui.add_enabled(i > 0, egui::Button::new("Up").small()).clicked() {
+                        move_up = Some(i);
+                    }
+                    if ui.small_button("Remove").clicked() {
+                        remove = Some(i);
+                    }
+                });
+            }
+            if let Some(i) = move_up {
+                endpoints.swap(i - 1, i);
+            }
+            if let Some(i) = remove {
+                endpoints.remove(i);
+            }
+            if ui.button("Add endpoint").clicked() {
+                endpoints.push(String::new());
+            }
+
+            let mut timeout = overpass_settings.timeout.as_secs();
+            let slider = egui::Slider::new(&mut timeout, OVERPASS_TIMEOUT_RANGE).text("Timeout (s)");
+            if ui.add(slider).changed() {
+                overpass_settings.timeout = Duration::from_secs(timeout);
+            }
+
+            // responses are only cached on native
+            ui.add_enabled_ui(response_cache.is_available(), |ui| {
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut response_cache.bypass, "Bypass cache")
+                        .on_hover_text("Downloads the data again, even if the same query was loaded before");
+                    if ui.button("Clear cache").clicked() {
+                        match response_cache.clear() {
+                            Ok(count) => {
+                                status_events.send(StatusEvent::Update(format!(
+                                    "Removed {} cached responses",
+                                    count
+                                )));
+                            }
+                            Err(error) => {
+                                status_events.send(StatusEvent::Error(AppError::Io {
+                                    message: error.to_string(),
+                                    status: None,
+                                    url: None,
+                                }));
+                            }
+                        }
+                    }
+                });
+            });
+        });
+
+        if ui.button("Toggle map view (M)").clicked() {
+            camera_mode_events.send(ToggleCameraModeEvent::Map);
+        }
+        if ui.button("Toggle orbit camera (O)").clicked() {
+            camera_mode_events.send(ToggleCameraModeEvent::Orbit);
+        }
+        if ui
+            .button("Follow random car")
+            .on_hover_text("Rides along with a car, clicking an agent follows that agent; Escape stops following")
+            .clicked()
+        {
+            follow_events.send(FollowAgentEvent::RandomCar);
+        }
+
+        ui.horizontal(|ui| {
+            let label = if simulation.paused { "Resume (F2)" } else { "Pause (F2)" };
+            if ui.button(label).clicked() {
+                simulation.paused = !simulation.paused;
+            }
+            ui.add(
+                egui::Slider::new(&mut simulation.time_scale, TIME_SCALE_RANGE)
+                    .logarithmic(true)
+                    .text("Time scale"),
+            );
+        });
+
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::Slider::new(&mut time_of_day.hours, 0.0..=24.0)
+                    .step_by(0.25)
+                    .text("Time of day"),
+            );
+            ui.add(
+                egui::DragValue::new(&mut time_of_day.speed)
+                    .clamp_range(0.0..=MAX_TIME_OF_DAY_SPEED)
+                    .speed(0.01)
+                    .suffix(" h/s"),
+            );
+        });
+
+        ui.collapsing("Layers", |ui| {
+            // copied, so the layers are only marked as changed when a box is
+            // clicked
+            let mut shown = *layers;
+            ui.horizontal_wrapped(|ui| {
+                for layer in FeatureLayer::ALL {
+                    ui.checkbox(shown.get_mut(layer), layer.name());
+                }
+            });
+            if shown != *layers {
+                *layers = shown;
+            }
+            ui.label("Hidden layers are not generated for newly loaded data");
+            if ui
+                .button("Regenerate")
+                .on_hover_text("Generates the shown layers again from the loaded data, without loading it again")
+                .clicked()
+            {
+                regenerate_events.send(RegenerateEvent::visible(&layers));
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            if ui
+                .button("Export to glTF…")
+                .on_hover_text("Saves the shown layers as a GLB file, for example to open it in Blender")
+                .clicked()
+            {
+                spawn_gltf_export_dialog(&mut commands);
+            }
+        });
+
+        ui.collapsing("Agents", |ui| {
+            let target = egui::Slider::new(&mut ui_state.agent_target, 0..=MAX_TARGET_AGENTS)
+                .text("Target count");
+            if ui.add(target).changed() {
+                agent_command_events.send(AgentCommandEvent::SetTarget(ui_state.agent_target));
+            }
+
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut ui_state.agent_spawn_count).clamp_range(1..=1000));
+                if ui.button("Spawn").clicked() {
+                    let count = ui_state.agent_spawn_count;
+                    ui_state.agent_target += count;
+                    agent_command_events.send(AgentCommandEvent::Spawn(count));
+                }
+                if ui.button("Despawn all").clicked() {
+                    ui_state.agent_target = 0;
+                    agent_command_events.send(AgentCommandEvent::DespawnAll);
+                }
+            });
+
+            let mix = &mut ui_state.agent_mix;
+            let mut mix_changed = false;
+            mix_changed |= ui.add(egui::Slider::new(&mut mix.cars, 0.0..=1.0).text("Cars")).changed();
+            mix_changed |= ui.add(egui::Slider::new(&mut mix.bicycles, 0.0..=1.0).text("Bicycles")).changed();
+            mix_changed |= ui.add(egui::Slider::new(&mut mix.pedestrians, 0.0..=1.0).text("Pedestrians")).changed();
+            if mix_changed {
+                agent_command_events.send(AgentCommandEvent::SetMix(*mix));
+            }
+
+            let max_route_distance = egui::Slider::new(
+                &mut ui_state.agent_max_route_distance,
+                MAX_ROUTE_DISTANCE_RANGE,
+            )
+            .logarithmic(true)
+            .text("Max route distance");
+            if ui.add(max_route_distance).changed() {
+                agent_command_events.send(AgentCommandEvent::SetMaxRouteDistance(
+                    ui_state.agent_max_route_distance,
+                ));
+            }
+
+            ui.horizontal(|ui| {
+                let label = if telemetry.is_recording() { "Stop recording" } else { "Record telemetry" };
+                if ui
+                    .button(label)
+                    .on_hover_text("Samples the location, road type and speed of all agents, and saves them as CSV when recording stops")
+                    .clicked()
+                {
+                    toggle_telemetry_recording(&mut commands);
+                }
+                ui.add_enabled(
+                    !telemetry.is_recording(),
+                    egui::DragValue::new(&mut telemetry.interval)
+                        .clamp_range(SAMPLE_INTERVAL_RANGE)
+                        .speed(0.1)
+                        .prefix("every ")
+                        .suffix(" s"),
+                );
+            });
+            if telemetry.is_recording() || !telemetry.is_empty() {
+                ui.horizontal(|ui| {
+                    let mut samples = format!("{} samples", telemetry.len());
+                    if telemetry.dropped() > 0 {
+                        samples += &format!(", {} oldest dropped", telemetry.dropped());
+                    }
+                    ui.label(samples);
+                    if !telemetry.is_recording() && ui.button("Save telemetry…").clicked() {
+                        request_telemetry_export(&mut commands);
+                    }
+                });
+            }
+
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut heatmap.enabled, "Edge usage heatmap")
+                    .on_hover_text("Colors the roads by how often agents travel over them");
+                egui::ComboBox::from_id_source("heatmap_ramp")
+                    .selected_text(heatmap.ramp.name())
+                    .show_ui(ui, |ui| {
+                        for ramp in ColorRamp::ALL {
+                            ui.selectable_value(&mut heatmap.ramp, ramp, ramp.name());
+                        }
+                    });
+                if ui.button("Reset").clicked() {
+                    reset_edge_usage(&mut commands);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut heatmap.decay, "Fade out")
+                    .on_hover_text("Halves how often roads were used every half-life of simulated time");
+                ui.add_enabled(
+                    heatmap.decay,
+                    egui::DragValue::new(&mut heatmap.half_life)
+                        .clamp_range(HALF_LIFE_RANGE)
+                        .speed(1.0)
+                        .prefix("half-life ")
+                        .suffix(" s"),
+                );
+            });
+
+            if ui
+                .button("Export traffic graph…")
+                .on_hover_text("Saves the road network that agents travel on as GraphML or DOT, for example to analyze it in networkx or Gephi")
+                .clicked()
+            {
+                request_graph_export(&mut commands);
+            }
+        });
+
+        ui.collapsing("Route", |ui| {
+            let planner = &mut *route_planner;
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut planner.picking, "Pick route")
+                    .on_hover_text("Clicking the ground picks the start and then the end of a route, instead of moving the camera");
+                egui::ComboBox::from_id_source("route_agent_type")
+                    .selected_text(planner.agent_type.name())
+                    .show_ui(ui, |ui| {
+                        for agent_type in AgentType::ALL {
+                            ui.selectable_value(&mut planner.agent_type, agent_type, agent_type.name());
+                        }
+                    });
+                if ui.button("Clear").clicked() {
+                    route_events.send(RouteEvent::Clear);
+                }
+            });
+            if planner.picking && planner.has_start() {
+                ui.label("Click the end of the route");
+            }
+
+            ui.horizontal(|ui| {
+                ui.label("From");
+                let response =
+                    ui.add(egui::TextEdit::singleline(&mut planner.start_text).hint_text("latitude, longitude"));
+                query_focused |= response.has_focus();
+            });
+            ui.horizontal(|ui| {
+                ui.label("To");
+                let response =
+                    ui.add(egui::TextEdit::singleline(&mut planner.end_text).hint_text("latitude, longitude"));
+                query_focused |= response.has_focus();
+            });
+            if ui.button("Find route").clicked() {
+                match (parse_coordinates(&planner.start_text), parse_coordinates(&planner.end_text)) {
+                    (Ok(from), Ok(to)) => {
+                        route_events.send(RouteEvent::Between(from, to));
+                    }
+                    (Err(error), _) | (_, Err(error)) => {
+                        status_events.send(StatusEvent::Error(error));
+                    }
+                }
+            }
+
+            if let Some(route) = planner.route() {
+                ui.label(route.summary());
+            }
+        });
     });
 
+    // zooms the map and orbit cameras, in lines
+    let scroll: f32 = mouse_wheel_input
+        .read()
+        .map(|event| match event.unit {
+            MouseScrollUnit::Line => event.y,
+            MouseScrollUnit::Pixel => event.y / 20.0,
+        })
+        .sum();
+
     // see also: https://bevy-cheatbook.github.io/window/mouse-grab.html
     if ui_state.cursor_locked {
         let mut translation = Vec3::ZERO;
@@ -177,26 +814,45 @@ pub fn update_ui(
         }
 
         // `rotation` is a Vec2 but controls rotation of the camera
-        let rotation = if let Some(event) = mouse_motion_input.read().next() {
-            // recenter cursor on Windows, because platform differences :/
-            let center = Vec2::new(primary_window.width() / 2.0, primary_window.height() / 2.0);
-            primary_window.set_cursor_position(Some(center));
+        let rotation: Vec2 = mouse_motion_input.read().map(|event| event.delta).sum();
 
-            event.delta
-        } else {
-            Vec2::ZERO
-        };
+        // Some platforms (e.g. Windows and X11) do not support locking the
+        // cursor, which shows as the cursor moving away from where it was
+        // grabbed. Confine it to the window instead.
+        if primary_window.cursor.grab_mode == CursorGrabMode::Locked && rotation != Vec2::ZERO {
+            let moved = match (ui_state.grab_position, primary_window.cursor_position()) {
+                (Some(grabbed), Some(current)) => grabbed.distance(current) > CURSOR_LOCK_TOLERANCE,
+                _ => false,
+            };
+            if moved {
+                ui_state.locked_mode_unsupported = true;
+                primary_window.cursor.grab_mode = CursorGrabMode::Confined;
+            }
+        }
+
+        // A confined cursor has to be recentered, so it never hits the edge
+        // of the window
+        if primary_window.cursor.grab_mode == CursorGrabMode::Confined && rotation != Vec2::ZERO {
+            let center = DVec2::new(
+                primary_window.physical_width() as f64 / 2.0,
+                primary_window.physical_height() as f64 / 2.0,
+            );
+            primary_window.set_physical_cursor_position(Some(center));
+        }
 
         let do_panning = keyboard_input.pressed(KeyCode::KeyP);
 
         if translation != Vec3::ZERO {
             translation = translation.normalize();
         }
-        if translation != Vec3::ZERO || rotation != Vec2::ZERO || do_panning {
+        if translation != Vec3::ZERO || rotation != Vec2::ZERO || do_panning || scroll != 0.0 {
             player_move_events.send(PlayerMoveEvent {
                 translation,
                 rotation,
                 do_panning,
+                scroll,
+                left_drag: mouse_button_input.pressed(MouseButton::Left),
+                middle_drag: mouse_button_input.pressed(MouseButton::Middle),
             });
         }
 
@@ -206,13 +862,170 @@ pub fn update_ui(
             primary_window.cursor.visible = true;
             ui_state.cursor_locked = false;
         }
+    } else {
+        // motion while the cursor is free should not rotate the camera later
+        mouse_motion_input.clear();
+
+        // scrolling over the view still zooms, but not over the UI
+        if scroll != 0.0 && !ctx.is_pointer_over_area() {
+            player_move_events.send(PlayerMoveEvent {
+                translation: Vec3::ZERO,
+                rotation: Vec2::ZERO,
+                do_panning: false,
+                scroll,
+                left_drag: false,
+                middle_drag: false,
+            });
+        }
     }
 
-    if !ctx.is_pointer_over_area() && mouse_button_input.just_pressed(MouseButton::Left) {
+    let clicked_view = !ctx.is_pointer_over_area() && mouse_button_input.just_pressed(MouseButton::Left);
+    if clicked_view && route_planner.picking && !ui_state.cursor_locked {
+        // picks an end of the route, and leaves the cursor free for the next
+        if let Some(cursor) = primary_window.cursor_position() {
+            route_events.send(RouteEvent::Pick(cursor));
+        }
+    } else if clicked_view {
+        // follows the agent that was clicked, if any
+        if let Some(cursor) = primary_window.cursor_position().filter(|_| !ui_state.cursor_locked) {
+            follow_events.send(FollowAgentEvent::Pick(cursor));
+        }
         // lock cursor, allowing to translate and rotate the camera
-        primary_window.cursor.grab_mode = CursorGrabMode::Locked;
+        primary_window.cursor.grab_mode = if ui_state.locked_mode_unsupported {
+            CursorGrabMode::Confined
+        } else {
+            CursorGrabMode::Locked
+        };
         primary_window.cursor.visible = false;
         ui_state.cursor_locked = true;
+        ui_state.grab_position = primary_window.cursor_position();
+    }
+
+    let new_input_mode = if ui_state.cursor_locked {
+        InputMode::Camera
+    } else if query_focused {
+        InputMode::TextEntry
+    } else {
+        InputMode::Interface
+    };
+    // only touch the resource on changes, so systems can use change detection
+    if *input_mode != new_input_mode {
+        *input_mode = new_input_mode;
+    }
+}
+
+/// A system that shows the places found for a city query, so the user can
+/// choose which one to load.
+pub fn update_place_picker(
+    mut contexts: EguiContexts,
+    mut place_matches: ResMut<PlaceMatches>,
+    mut data_load_events: EventWriter<DataQueryEvent>,
+) {
+    if place_matches.places.is_empty() {
+        return;
+    }
+
+    let mut chosen = None;
+    let mut visible = true;
+    egui::Window::new(format!("Which {}?", place_matches.name))
+        .id("place_picker".into())
+        .open(&mut visible)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::ScrollArea::vertical()
+                .max_height(300.0)
+                .show(ui, |ui| {
+                    for (i, place) in place_matches.places.iter().enumerate() {
+                        let [south, west, north, east] = place.bounding_box;
+                        let label = match &place.country {
+                            Some(country) => format!("{}, {}", place.name, country),
+                            None => place.name.clone(),
+                        };
+                        ui.horizontal(|ui| {
+                            if ui.button(label).on_hover_text(place.display_name.as_str()).clicked() {
+                                chosen = Some(i);
+                            }
+                            ui.weak(format!(
+                                "{:.3}, {:.3}, {:.3}, {:.3}",
+                                south, west, north, east
+                            ));
+                        });
+                    }
+                });
+        });
+
+    if let Some(i) = chosen {
+        data_load_events.send(DataQueryEvent {
+            query: place_matches.places[i].query(),
+        });
+    }
+    if chosen.is_some() || !visible {
+        place_matches.places.clear();
+    }
+}
+
+/// The progress of loading that is shown.
+pub struct LoadingProgress {
+    label: String,
+    fraction: Option<f32>,
+    /// When the label was last changed.
+    since: Instant,
+}
+
+/// A system that shows the progress of loading data as a progress bar, with a
+/// button to cancel loading.
+pub fn update_progress_bar(
+    mut contexts: EguiContexts,
+    mut status_events: EventReader<StatusEvent>,
+    mut cancel_events: EventWriter<CancelLoadingEvent>,
+    mut progress: Local<Option<LoadingProgress>>,
+) {
+    for status_event in status_events.read() {
+        match status_event {
+            StatusEvent::Progress { fraction: Some(fraction), .. } if *fraction >= 1.0 => {
+                *progress = None;
+            }
+            StatusEvent::Progress { label, fraction } => {
+                let since = match &*progress {
+                    Some(current) if current.label == *label => current.since,
+                    _ => Instant::now(),
+                };
+                *progress = Some(LoadingProgress {
+                    label: label.clone(),
+                    fraction: *fraction,
+                    since,
+                });
+            }
+            StatusEvent::Error(_) => *progress = None,
+            StatusEvent::Update(_) => {}
+        }
+    }
+
+    let Some(current) = &*progress else {
+        return;
+    };
+    let mut cancel = false;
+    egui::Window::new("Loading")
+        .anchor(egui::Align2::CENTER_TOP, [0.0, 10.0])
+        .title_bar(false)
+        .resizable(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                // steps of unknown length show how long they take, so it is
+                // clear that something is happening
+                let bar = match current.fraction {
+                    Some(fraction) => egui::ProgressBar::new(fraction).text(current.label.as_str()),
+                    None => egui::ProgressBar::new(0.0)
+                        .text(format!("{} ({}s)", current.label, current.since.elapsed().as_secs()))
+                        .animate(true),
+                };
+                ui.add(bar.desired_width(320.0));
+                cancel = ui.button("Cancel").clicked();
+            });
+        });
+
+    if cancel {
+        cancel_events.send(CancelLoadingEvent);
+        *progress = None;
     }
 }
 
@@ -221,11 +1034,13 @@ pub struct NotificationText {
     pub queue: VecDeque<(String, TextStyle, Timer)>,
 }
 
-/// A system that updates the notification text in the corner
+/// A system that updates the notification text in the corner, and keeps the
+/// notifications in the `StatusLog`.
 pub fn update_notifications(
     mut query: Query<(&mut NotificationText, &mut Text)>,
     time: Res<Time>,
     mut status_events: EventReader<StatusEvent>,
+    mut log: ResMut<StatusLog>,
 ) {
     let (mut notifications, mut text) = query.get_single_mut().unwrap_throw();
 
@@ -248,6 +1063,9 @@ pub fn update_notifications(
     for status_event in status_events.read() {
         let (text, style) = match status_event {
             StatusEvent::Error(error) => {
+                // also in the browser console on the web
+                error!("{}", error);
+                log.push(time.elapsed(), Severity::Error, error.to_string());
                 let style = TextStyle {
                     color: ERROR_COLOR,
                     font_size: NOTIFICATION_FONT_SIZE,
@@ -256,6 +1074,7 @@ pub fn update_notifications(
                 (format!("{}\n\n", error), style)
             }
             StatusEvent::Update(message) => {
+                log.push(time.elapsed(), Severity::Info, message.clone());
                 let style = TextStyle {
                     color: UPDATE_COLOR,
                     font_size: NOTIFICATION_FONT_SIZE,
@@ -263,6 +1082,8 @@ pub fn update_notifications(
                 };
                 (format!("{}\n\n", message), style)
             }
+            // shown by `update_progress_bar`
+            StatusEvent::Progress { .. } => continue,
         };
         notifications.queue.push_back((
             text,
@@ -282,6 +1103,74 @@ pub fn update_notifications(
     }
 }
 
+/// Marker component for the text that attributes the shown data.
+#[derive(Component)]
+pub struct AttributionText;
+
+/// A system that updates the attribution text whenever other data is shown.
+pub fn update_attribution(
+    mut query: Query<(&mut Text, &mut Visibility), With<AttributionText>>,
+    attribution: Res<DataAttribution>,
+) {
+    if !attribution.is_changed() {
+        return;
+    }
+
+    let (mut text, mut visibility) = query.get_single_mut().unwrap_throw();
+    if !attribution.shown {
+        *visibility = Visibility::Hidden;
+        return;
+    }
+
+    let mut value = "Data © OpenStreetMap contributors".to_owned();
+    if let Some(timestamp) = &attribution.snapshot_timestamp {
+        value += &format!(", snapshot {}", timestamp);
+    }
+    if let Some(age) = attribution.cache_age {
+        value += &format!(" (cached {})", format_age(age.as_secs()));
+    }
+    text.sections[0].value = value;
+    *visibility = Visibility::Inherited;
+}
+
+/// A system that keeps track of where the camera is on earth, every few
+/// frames.
+pub fn update_camera_location(
+    mut ui_state: ResMut<UiState>,
+    players: Query<&Transform, With<Player>>,
+    offset: Res<Offset>,
+    mut frames: Local<u32>,
+) {
+    *frames += 1;
+    if *frames < CAMERA_LOCATION_INTERVAL && !offset.is_changed() {
+        return;
+    }
+    *frames = 0;
+
+    // the offset is only set once data is loaded
+    ui_state.camera_location = match players.get_single() {
+        Ok(transform) if offset.x != f64::NEG_INFINITY => Some(GeoLocation::unproject(
+            transform.translation.xz(),
+            &offset,
+        )),
+        _ => None,
+    };
+}
+
+/// Formats an age in seconds in the largest fitting unit, e.g. "3 hours ago".
+fn format_age(seconds: u64) -> String {
+    let (amount, unit) = match seconds {
+        0..=59 => (seconds, "second"),
+        60..=3599 => (seconds / 60, "minute"),
+        3600..=86399 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    let plural = if amount == 1 { "" } else { "s" };
+    format!("{} {}{} ago", amount, unit, plural)
+}
+
+const ATTRIBUTION_COLOR: Color = Color::WHITE;
+const ATTRIBUTION_FONT_SIZE: f32 = 14.0;
 const ERROR_COLOR: Color = Color::RED;
 const UPDATE_COLOR: Color = Color::rgb(0.5, 0.5, 1.0);
 const NOTIFICATION_FONT_SIZE: f32 = 15.0;
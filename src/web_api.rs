@@ -0,0 +1,215 @@
+//! A JavaScript API to control the viewer from the page it is embedded in, in
+//! the web version: loading data, moving the camera and following the status
+//! of loading.
+//!
+//! The page defines `window.setup_finished`, which the viewer calls once it is
+//! set up. The functions of this module can be called from then on; before,
+//! they throw. Calls are queued, and handled at the start of the next frame.
+//!
+//! ```js
+//! import init, { load_city, on_status, set_camera } from "./city_visualizer.js";
+//!
+//! window.setup_finished = () => {
+//!     on_status((kind, message) => {
+//!         if (kind === "error") console.error(message);
+//!     });
+//!     load_city("Utrecht");
+//!     set_camera(52.0907, 5.1214, 300);
+//! };
+//! init();
+//! ```
+
+use crate::common::{AppError, DataFormat, StatusEvent};
+use crate::data::geography::{GeoLocation, Offset};
+use crate::data::loading::DataQueryEvent;
+use crate::data::query::{parse_data_query, DataQuery, InputQueryType};
+use crate::player::{CameraMode, Player, PlayerTeleportEvent};
+
+use bevy::prelude::*;
+use wasm_bindgen::prelude::*;
+
+use std::cell::RefCell;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, OnceLock};
+
+/// The sending half of the channel of `WebApi`. JavaScript calls the API
+/// outside of the app, so it cannot reach a resource.
+static API_SENDER: OnceLock<Sender<ApiCommand>> = OnceLock::new();
+
+thread_local! {
+    /// The functions registered with `on_status`. JavaScript functions can
+    /// only be called on the main thread, so they cannot be in a resource.
+    static STATUS_CALLBACKS: RefCell<Vec<js_sys::Function>> = RefCell::new(Vec::new());
+}
+
+/// A call of the JavaScript API, which is handled by `update_web_api`.
+#[derive(Debug)]
+enum ApiCommand {
+    LoadOsmJson(String),
+    LoadCity(String),
+    SetCamera {
+        latitude: f64,
+        longitude: f64,
+        /// The height above the ground, in meters.
+        height: f32,
+    },
+}
+
+/// The receiving half of the channel that the JavaScript API sends calls
+/// through.
+#[derive(Resource)]
+pub struct WebApi {
+    receiver: Mutex<Receiver<ApiCommand>>,
+}
+
+/// Loads data in OSM JSON, like the response of Overpass, and adds it to the
+/// world.
+#[wasm_bindgen]
+pub fn load_osm_json(json: &str) -> Result<(), JsValue> {
+    send(ApiCommand::LoadOsmJson(json.to_owned()))
+}
+
+/// Loads the city with the given name. If several places have the name, the
+/// user chooses one in the viewer.
+#[wasm_bindgen]
+pub fn load_city(name: &str) -> Result<(), JsValue> {
+    send(ApiCommand::LoadCity(name.to_owned()))
+}
+
+/// Moves the camera above the given coordinates, at `height` meters above the
+/// ground. Data has to be loaded first, since coordinates can only be placed
+/// in the world after that.
+#[wasm_bindgen]
+pub fn set_camera(lat: f64, lon: f64, height: f32) -> Result<(), JsValue> {
+    send(ApiCommand::SetCamera {
+        latitude: lat,
+        longitude: lon,
+        height,
+    })
+}
+
+/// Registers a function that is called with the kind (`"error"`, `"update"`
+/// or `"progress"`) and message of every status update, for example to show
+/// loading errors in the page.
+#[wasm_bindgen]
+pub fn on_status(callback: js_sys::Function) {
+    STATUS_CALLBACKS.with(|callbacks| callbacks.borrow_mut().push(callback));
+}
+
+fn send(command: ApiCommand) -> Result<(), JsValue> {
+    let sent = API_SENDER
+        .get()
+        .map_or(false, |sender| sender.send(command).is_ok());
+    if sent {
+        Ok(())
+    } else {
+        Err(JsValue::from_str(
+            "the viewer is not set up yet, wait for setup_finished()",
+        ))
+    }
+}
+
+/// A system that creates the channel of the JavaScript API. It has to run
+/// before `setup_ui`, which tells the page that the API can be used.
+pub fn setup_web_api(mut commands: Commands) {
+    let (sender, receiver) = mpsc::channel();
+    // only the first app can be controlled, if there are several
+    if API_SENDER.set(sender).is_ok() {
+        commands.insert_resource(WebApi {
+            receiver: Mutex::new(receiver),
+        });
+    }
+}
+
+/// A system that handles the calls of the JavaScript API since the last
+/// frame.
+pub fn update_web_api(
+    web_api: Option<Res<WebApi>>,
+    offset: Res<Offset>,
+    mut players: Query<(&Player, &mut Transform)>,
+    mut data_query_events: EventWriter<DataQueryEvent>,
+    mut teleport_events: EventWriter<PlayerTeleportEvent>,
+    mut status_events: EventWriter<StatusEvent>,
+) {
+    let Some(web_api) = web_api else {
+        return;
+    };
+    let Ok(receiver) = web_api.receiver.lock() else {
+        return;
+    };
+
+    for command in receiver.try_iter() {
+        match command {
+            ApiCommand::LoadOsmJson(json) => {
+                data_query_events.send(DataQueryEvent {
+                    query: DataQuery::FileContents {
+                        format: Some(DataFormat::OsmJson),
+                        name: "data from the page".to_owned(),
+                        bytes: json.into_bytes().into(),
+                    },
+                });
+            }
+            ApiCommand::LoadCity(name) => match parse_data_query(InputQueryType::City, &name) {
+                Ok(query) => {
+                    data_query_events.send(DataQueryEvent { query });
+                }
+                Err(error) => {
+                    status_events.send(StatusEvent::Error(error));
+                }
+            },
+            // the offset is only set once data is loaded
+            ApiCommand::SetCamera { .. } if offset.x == f64::NEG_INFINITY => {
+                status_events.send(StatusEvent::Error(AppError::MissingData {
+                    message:
+                        "load data before setting the camera, so it can be placed in the world"
+                            .to_owned(),
+                }));
+            }
+            ApiCommand::SetCamera {
+                latitude,
+                longitude,
+                height,
+            } => {
+                let location = GeoLocation {
+                    longitude,
+                    latitude,
+                };
+                // the teleport keeps the height of the camera, unless it is
+                // below the ground
+                let height = height / location.meters_per_unit(&offset) as f32;
+                for (player, mut transform) in &mut players {
+                    if player.camera_mode == CameraMode::Perspective {
+                        transform.translation.y = height;
+                    }
+                }
+                teleport_events.send(PlayerTeleportEvent {
+                    position: location.project(&offset),
+                });
+            }
+        }
+    }
+}
+
+/// A system that passes status updates to the functions registered with
+/// `on_status`.
+pub fn update_status_callbacks(mut status_events: EventReader<StatusEvent>) {
+    for event in status_events.read() {
+        let (kind, message) = match event {
+            StatusEvent::Error(error) => ("error", error.to_string()),
+            StatusEvent::Update(message) => ("update", message.clone()),
+            StatusEvent::Progress { label, .. } => ("progress", label.clone()),
+        };
+        STATUS_CALLBACKS.with(|callbacks| {
+            for callback in callbacks.borrow().iter() {
+                let result = callback.call2(
+                    &JsValue::NULL,
+                    &JsValue::from_str(kind),
+                    &JsValue::from_str(&message),
+                );
+                if let Err(thrown) = result {
+                    web_sys::console::error_1(&thrown);
+                }
+            }
+        });
+    }
+}
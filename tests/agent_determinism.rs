@@ -0,0 +1,77 @@
+//! Loads a bundled OSM JSON file twice with the same agent seed, without a
+//! window, and checks that both runs spawn the same agents at the same
+//! places. A third run with another seed should spawn them elsewhere. The
+//! simulation is paused, so agents stay where they were spawned.
+
+mod common;
+
+use common::{add_world_plugins, load, settle, SMALL_TOWN};
+
+use city_visualizer::data::geography::GeoData;
+use city_visualizer::earth::agent::{Agent, AgentSeed, AgentSettings};
+use city_visualizer::earth::{GeoDataEvent, SimulationSettings};
+
+use bevy::prelude::*;
+
+use std::sync::Arc;
+
+const SEED: u64 = 42;
+const OTHER_SEED: u64 = 7;
+/// The number of agents, which are all spawned within a few updates.
+const TARGET_AGENTS: usize = 50;
+
+/// The type and the bits of the translation of an agent, which are compared
+/// exactly.
+type Spawn = (&'static str, [u32; 3]);
+
+#[test]
+fn same_seed_spawns_the_same_agents() {
+    let data = Arc::new(load(SMALL_TOWN).unwrap());
+    let [first, second, other] = [SEED, SEED, OTHER_SEED].map(|seed| spawn_agents(&data, seed));
+
+    assert_eq!(first.len(), TARGET_AGENTS);
+    assert!(first == second, "two runs with the same seed spawned different agents");
+    assert!(first != other, "runs with different seeds spawned the same agents");
+}
+
+/// Loads the data into a new world with the given agent seed, and returns
+/// where the agents were spawned, sorted, since batches of agents may finish
+/// in any order.
+fn spawn_agents(data: &Arc<GeoData>, seed: u64) -> Vec<Spawn> {
+    let mut app = App::new();
+    // Inserted before the plugin, so they are not replaced by the defaults
+    app.insert_resource(AgentSeed(seed))
+        .insert_resource(AgentSettings {
+            target_agents: TARGET_AGENTS,
+            ..default()
+        })
+        .insert_resource(SimulationSettings {
+            paused: true,
+            ..default()
+        });
+    add_world_plugins(&mut app);
+
+    app.world.send_event(GeoDataEvent {
+        data: Arc::clone(data),
+    });
+    // Batches that created fewer agents than requested are made up for
+    // after they finish, so the world is settled until no agents are added
+    let mut spawns = Vec::new();
+    loop {
+        settle(&mut app);
+        let count = spawns.len();
+        spawns = app
+            .world
+            .query::<(&Agent, &Transform)>()
+            .iter(&app.world)
+            .map(|(agent, transform)| {
+                (agent.agent_type.name(), transform.translation.to_array().map(f32::to_bits))
+            })
+            .collect::<Vec<Spawn>>();
+        if spawns.len() == count || spawns.len() >= TARGET_AGENTS {
+            break;
+        }
+    }
+    spawns.sort();
+    spawns
+}
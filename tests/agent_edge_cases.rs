@@ -0,0 +1,98 @@
+//! Runs agents with degenerate paths on tiny traffic graphs, without a
+//! window, and checks that updating them does not panic.
+
+mod common;
+
+use common::agent_app;
+
+use city_visualizer::data::geography::WorldScale;
+use city_visualizer::data::road_type::RoadType;
+use city_visualizer::data::traffic_graph::{Access, OneWay, TrafficGraph};
+use city_visualizer::earth::agent::{create_agents, Agent, AgentSettings, AgentType};
+
+use bevy::prelude::*;
+use petgraph::graph::NodeIndex;
+
+use std::sync::Arc;
+
+const UPDATES: usize = 10;
+const WEST: Vec2 = Vec2::ZERO;
+const EAST: Vec2 = Vec2::new(100.0, 0.0);
+
+#[test]
+fn agents_without_a_valid_path_are_removed() {
+    // a single road between two vertices
+    let mut graph = TrafficGraph::default();
+    graph.add_connection(1, WEST, 2, EAST, OneWay::No, RoadType::Residential, Access::ALL);
+    let (from, to) = (graph.get_index(1).unwrap(), graph.get_index(2).unwrap());
+
+    let mut app = agent_app(graph);
+    let empty = spawn_agent(&mut app, WEST, Vec::new());
+    let single = spawn_agent(&mut app, WEST, vec![from]);
+    let valid = spawn_agent(&mut app, WEST, vec![from, to]);
+    for _ in 0..UPDATES {
+        app.update();
+    }
+
+    assert!(app.world.get_entity(empty).is_none(), "agent with an empty path is not removed");
+    assert!(app.world.get_entity(single).is_none(), "agent with a single node path is not removed");
+    let translation = app.world.get::<Transform>(valid).unwrap().translation;
+    assert!(translation.x > 0.0, "agent with a valid path does not drive");
+}
+
+#[test]
+fn no_agents_are_created_without_edges() {
+    // a vertex without edges: every agent would start at its destination
+    let mut graph = TrafficGraph::default();
+    graph.add_node(1, Vec2::ZERO);
+    let agents = create_agents(10, Arc::new(graph), 0, AgentSettings::default(), WorldScale::default());
+    assert!(agents.is_empty());
+}
+
+/// Agents are created for a graph that is reset and replaced by a smaller one
+/// before their first update, as when data is cleared while agents are being
+/// created.
+#[test]
+fn agents_from_before_a_reset_are_removed() {
+    let mut graph = TrafficGraph::default();
+    for id in 0..10u64 {
+        let from = Vec2::new(id as f32 * 50.0, 0.0);
+        let to = Vec2::new((id + 1) as f32 * 50.0, 0.0);
+        graph.add_connection(id, from, id + 1, to, OneWay::No, RoadType::Residential, Access::ALL);
+    }
+    let agents = create_agents(20, Arc::new(graph.clone()), 0, AgentSettings::default(), WorldScale::default());
+    assert!(!agents.is_empty());
+
+    let mut app = agent_app(graph);
+    let stale: Vec<Entity> = agents
+        .into_iter()
+        .map(|(location, agent)| app.world.spawn((Transform::from_translation(location), agent)).id())
+        .collect();
+    {
+        let mut graph = app.world.resource_mut::<TrafficGraph>();
+        graph.reset();
+        graph.add_connection(100, WEST, 101, EAST, OneWay::No, RoadType::Residential, Access::ALL);
+    }
+    for _ in 0..UPDATES {
+        app.update();
+    }
+
+    for entity in stale {
+        assert!(app.world.get_entity(entity).is_none(), "agent {:?} from before the reset is not removed", entity);
+    }
+}
+
+fn spawn_agent(app: &mut App, location: Vec2, path: Vec<NodeIndex>) -> Entity {
+    let graph = app.world.resource::<TrafficGraph>();
+    let agent = Agent {
+        agent_type: AgentType::Car,
+        destination: path.last().copied().unwrap_or_default(),
+        path: path.into_boxed_slice(),
+        path_index: 0,
+        next_path_location_edge: None,
+        graph_generation: graph.get_generation(),
+    };
+    app.world
+        .spawn((Transform::from_xyz(location.x, 0.0, location.y), agent))
+        .id()
+}
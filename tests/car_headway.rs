@@ -0,0 +1,117 @@
+//! Drives cars over a long one-way road, without a window, and checks after
+//! every update that no car is closer to the car ahead of it than the
+//! headway. The road alternates between fast and slow sections, so cars queue
+//! up where it slows down, also across the vertices between edges.
+//!
+//! The number of cars can be raised for a stress test by setting
+//! `CAR_HEADWAY_CARS`, e.g. `CAR_HEADWAY_CARS=2000 cargo test --release --test car_headway`.
+
+mod common;
+
+use common::agent_app;
+
+use city_visualizer::data::geography::WorldScale;
+use city_visualizer::data::road_type::RoadType;
+use city_visualizer::data::traffic_graph::{Access, OneWay, TrafficGraph};
+use city_visualizer::earth::agent::{Agent, AgentType, CAR_HEADWAY};
+
+use bevy::prelude::*;
+use petgraph::graph::NodeIndex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const DEFAULT_CARS: usize = 200;
+const UPDATES: usize = 2_000;
+/// The length of every edge of the road.
+const EDGE_LENGTH: f32 = 50.0;
+/// The number of edges of every fast or slow section of the road.
+const SECTION_EDGES: usize = 10;
+/// How much closer than the headway cars may be, for rounding errors of
+/// positions kilometers away from the origin.
+const TOLERANCE: f32 = 1e-2;
+
+#[test]
+fn cars_keep_their_headway() {
+    let cars = std::env::var("CAR_HEADWAY_CARS")
+        .ok()
+        .and_then(|cars| cars.parse().ok())
+        .unwrap_or(DEFAULT_CARS);
+    let headway = WorldScale::default().units(CAR_HEADWAY);
+    let mut rng = StdRng::seed_from_u64(2028);
+
+    // Cars start between one and two headways apart, from the start of the
+    // road, and the road is long enough for all of them
+    let mut spawns = Vec::with_capacity(cars);
+    let mut x = 0.0;
+    for _ in 0..cars {
+        spawns.push(x);
+        x += headway * rng.gen_range(1.0..2.0);
+    }
+    let edges = (x / EDGE_LENGTH) as usize + 2 * SECTION_EDGES;
+
+    let mut graph = TrafficGraph::default();
+    for id in 0..edges {
+        let from = Vec2::new(id as f32 * EDGE_LENGTH, 0.0);
+        let to = Vec2::new((id + 1) as f32 * EDGE_LENGTH, 0.0);
+        let road_type = if (id / SECTION_EDGES) % 2 == 0 {
+            RoadType::Motorway
+        } else {
+            RoadType::LivingStreet
+        };
+        graph.add_connection(id as u64, from, id as u64 + 1, to, OneWay::Yes, road_type, Access::ALL);
+    }
+    let generation = graph.get_generation();
+    let mut app = agent_app(graph);
+
+    // Every car drives to the end of the road, starting on the edge it is on
+    for x in spawns {
+        let first = (x / EDGE_LENGTH) as usize;
+        let path: Vec<NodeIndex> = (first..=edges).map(NodeIndex::new).collect();
+        let agent = Agent {
+            agent_type: AgentType::Car,
+            destination: NodeIndex::new(edges),
+            path: path.into_boxed_slice(),
+            path_index: 0,
+            next_path_location_edge: None,
+            graph_generation: generation,
+        };
+        app.world.spawn((Transform::from_xyz(x, 0.0, 0.0), agent));
+    }
+
+    let mut smallest_gap = f32::INFINITY;
+    for update in 1..=UPDATES {
+        app.update();
+        let Some(gap) = smallest_gap_between_cars(&mut app.world) else {
+            continue;
+        };
+        assert!(
+            gap >= headway - TOLERANCE,
+            "after {} updates two cars are {} apart, closer than the headway of {}",
+            update,
+            gap,
+            headway
+        );
+        smallest_gap = smallest_gap.min(gap);
+    }
+
+    // Without queues the headway would not have been tested
+    assert!(smallest_gap <= 1.5 * headway, "cars did not queue up, the smallest gap was {}", smallest_gap);
+}
+
+/// Returns the smallest gap between two consecutive cars that have not
+/// arrived yet, or `None` if fewer than two cars are driving. Arrived cars
+/// wait at the end of the road, on top of each other.
+fn smallest_gap_between_cars(world: &mut World) -> Option<f32> {
+    let mut positions: Vec<f32> = world
+        .query::<(&Agent, &Transform)>()
+        .iter(world)
+        .filter(|(agent, _)| !has_arrived(agent))
+        .map(|(_, transform)| transform.translation.x)
+        .collect();
+    positions.sort_by(f32::total_cmp);
+    positions.windows(2).map(|pair| pair[1] - pair[0]).reduce(f32::min)
+}
+
+fn has_arrived(agent: &Agent) -> bool {
+    agent.path_index + 1 >= agent.path.len()
+}
@@ -0,0 +1,128 @@
+//! Helpers shared by the integration tests. Examples that run agents include
+//! this module with `#[path]`, so not every helper is used by every target.
+
+#![allow(dead_code)]
+
+use city_visualizer::data::geography::{
+    convert_osm_json, GeoData, GeoLocation, LoadedBounds, Offset, WorldScale,
+};
+use city_visualizer::data::traffic_graph::{update_traffic_graph, TrafficGraph};
+use city_visualizer::earth::agent::{update_agents, AgentSettings};
+use city_visualizer::earth::buildings::BuildingFootprints;
+use city_visualizer::earth::edge_usage::EdgeUsage;
+use city_visualizer::earth::traffic_signals::TrafficSignals;
+use city_visualizer::earth::{is_world_settled, SimulationSettings};
+use city_visualizer::plugin::CityWorldPlugin;
+
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+use bevy::utils::Duration;
+
+/// The bundled OSM JSON file of a small town.
+pub const SMALL_TOWN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/fixtures/small_town.json");
+
+/// The simulated time of every update of an `agent_app`.
+pub const FRAME_TIME: Duration = Duration::from_millis(100);
+
+/// Updates before the world must have settled. Generation of the small
+/// fixture takes a handful of updates, so this only catches a hang.
+const MAX_UPDATES: usize = 10_000;
+
+/// Updates that always run after sending data, so the event is read before
+/// the world is checked.
+const MIN_UPDATES: usize = 3;
+
+/// Returns an app without a window that only updates agents, on the given
+/// graph, with every update taking `FRAME_TIME`.
+pub fn agent_app(graph: TrafficGraph) -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .insert_resource(TimeUpdateStrategy::ManualDuration(FRAME_TIME))
+        .insert_resource(graph)
+        .insert_resource(WorldScale::default())
+        .init_resource::<AgentSettings>()
+        .init_resource::<BuildingFootprints>()
+        .init_resource::<TrafficSignals>()
+        .init_resource::<SimulationSettings>()
+        .init_resource::<EdgeUsage>()
+        .add_systems(Update, update_agents);
+    app
+}
+
+/// Adds the world plugin to an app without a window, with the assets it
+/// needs. Resources that should not be replaced by their defaults are
+/// inserted before this is called.
+pub fn add_world_plugins(app: &mut App) {
+    app.add_plugins(MinimalPlugins)
+        .add_plugins(AssetPlugin::default())
+        .init_asset::<Mesh>()
+        .init_asset::<Image>()
+        .init_asset::<StandardMaterial>()
+        .add_plugins(CityWorldPlugin);
+}
+
+/// Updates an app with the world plugin until the world has settled, at most
+/// `MAX_UPDATES` times.
+pub fn settle(app: &mut App) {
+    for updates in 1..=MAX_UPDATES {
+        app.update();
+        if updates >= MIN_UPDATES && is_world_settled(&mut app.world) {
+            return;
+        }
+    }
+    panic!("the world did not settle within {} updates", MAX_UPDATES);
+}
+
+/// Loads and converts an OSM JSON file.
+pub fn load(path: &str) -> Result<GeoData, String> {
+    let contents = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let json = serde_json::from_str(&contents).map_err(|error| error.to_string())?;
+    convert_osm_json(json).map_err(|error| error.to_string())
+}
+
+/// Returns the offset that puts the average of all nodes at the origin.
+pub fn center_offset(data: &GeoData) -> Offset {
+    let count = data.node_locations.len().max(1) as f64;
+    let (sum_longitude, sum_latitude) = data
+        .node_locations
+        .values()
+        .fold((0.0, 0.0), |(longitude, latitude), location| {
+            (longitude + location.longitude, latitude + location.latitude)
+        });
+    Offset::centered_on(
+        &GeoLocation {
+            longitude: sum_longitude / count,
+            latitude: sum_latitude / count,
+        },
+        WorldScale::default(),
+    )
+}
+
+/// Returns the bounds around all nodes of the data.
+pub fn data_bounds(data: &GeoData, offset: &Offset) -> LoadedBounds {
+    let mut bounds = LoadedBounds::default();
+    for location in data.node_locations.values() {
+        let point = location.project(offset);
+        bounds.extend(point, point);
+    }
+    bounds
+}
+
+/// Builds the traffic graph of the data the same way the world does, adding
+/// the chunks in a fixed order.
+pub fn build_graph(data: &GeoData, offset: &Offset, bounds: &LoadedBounds) -> TrafficGraph {
+    let mut graph = TrafficGraph::default();
+    let mut chunk_indices: Vec<_> = data.chunks.keys().collect();
+    chunk_indices.sort();
+    for index in chunk_indices {
+        update_traffic_graph(
+            &data.node_locations,
+            &data.chunks[index].road_features,
+            index,
+            &mut graph,
+            offset,
+            bounds,
+        );
+    }
+    graph
+}
@@ -0,0 +1,58 @@
+//! Builds the traffic graph of a bundled OSM JSON file, without a window, and
+//! checks that `EdgeData::cost_for` finds the same paths as the cost that
+//! path finding computed from the length and road type of an edge before it
+//! was moved into `EdgeData`, between random vertices for every agent type.
+
+mod common;
+
+use common::{build_graph, center_offset, data_bounds, load, SMALL_TOWN};
+
+use city_visualizer::data::traffic_graph::{
+    road_type_allowed_for_agent_type, EdgeData, COST_MULTIPLIER_DISALLOWED,
+};
+use city_visualizer::earth::agent::{agent_speed_on_road_type, AgentType, REFERENCE_SPEED};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// The number of random start and end vertices for every agent type.
+const PAIRS: usize = 500;
+
+#[test]
+fn edge_cost_finds_the_same_paths() {
+    let data = load(SMALL_TOWN).unwrap();
+    let offset = center_offset(&data);
+    let graph = build_graph(&data, &offset, &data_bounds(&data, &offset));
+    assert!(graph.get_size() >= 2, "the traffic graph has fewer than 2 vertices");
+
+    let mut rng = StdRng::seed_from_u64(2031);
+    for agent_type in AgentType::ALL {
+        let mut found = 0;
+        for _ in 0..PAIRS {
+            let from = graph.get_random_node_index(&mut rng);
+            let to = graph.get_random_node_index(&mut rng);
+            let expected = graph.get_shortest_path_with_cost(from, to, agent_type, |edge| {
+                previous_cost(edge, agent_type)
+            });
+            let actual = graph.get_shortest_path(from, to, agent_type);
+            assert_eq!(actual, expected, "{} path from {:?} to {:?} differs", agent_type.name(), from, to);
+            if actual.is_some() {
+                found += 1;
+            }
+        }
+        assert!(found > 0, "no {} paths were found", agent_type.name());
+    }
+}
+
+/// The cost of an edge as path finding computed it before `EdgeData::cost_for`.
+fn previous_cost(edge: &EdgeData, agent_type: AgentType) -> f32 {
+    let road_type = edge.road_type();
+    let mut weight = edge.length(); // Starting weight is the distance
+
+    // See if road type is allowed for agent type
+    if !road_type_allowed_for_agent_type(road_type, agent_type) {
+        weight *= COST_MULTIPLIER_DISALLOWED;
+    }
+
+    // Account for speed multiplier
+    weight / agent_speed_on_road_type(REFERENCE_SPEED, agent_type, road_type)
+}
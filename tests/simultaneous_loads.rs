@@ -0,0 +1,53 @@
+//! Sends two bundled OSM JSON files to the world in the same frame, without a
+//! window, and checks that they are added as a single load: with one ground
+//! plane underneath both and one teleport of the player.
+
+mod common;
+
+use common::{add_world_plugins, load, settle, SMALL_TOWN};
+
+use city_visualizer::earth::agent::AgentSettings;
+use city_visualizer::earth::{GeoDataEvent, GroundPlane};
+use city_visualizer::player::PlayerTeleportEvent;
+
+use bevy::prelude::*;
+
+use std::sync::Arc;
+
+const SHARP_CORNER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/fixtures/sharp_corner.json");
+
+/// The number of teleports of the player that were sent.
+#[derive(Default, Resource)]
+struct Teleports(usize);
+
+#[test]
+fn data_sent_in_one_frame_is_added_as_one_load() {
+    let mut app = App::new();
+    // Inserted before the plugin, so it is not replaced by the default
+    app.insert_resource(AgentSettings {
+        enabled: false,
+        ..default()
+    });
+    add_world_plugins(&mut app);
+    app.init_resource::<Teleports>()
+        .add_systems(Update, count_teleports);
+
+    for fixture in [SMALL_TOWN, SHARP_CORNER] {
+        app.world.send_event(GeoDataEvent {
+            data: Arc::new(load(fixture).unwrap()),
+        });
+    }
+    settle(&mut app);
+
+    let planes = app
+        .world
+        .query_filtered::<(), With<GroundPlane>>()
+        .iter(&app.world)
+        .count();
+    assert_eq!(planes, 1, "ground planes");
+    assert_eq!(app.world.resource::<Teleports>().0, 1, "teleports");
+}
+
+fn count_teleports(mut teleport_events: EventReader<PlayerTeleportEvent>, mut teleports: ResMut<Teleports>) {
+    teleports.0 += teleport_events.read().count();
+}
@@ -0,0 +1,84 @@
+//! Runs the whole generation pipeline without a window: the world plugin is
+//! added to an app with only `MinimalPlugins`, a bundled OSM JSON file is sent
+//! to it, and the app is updated until all work has settled. Then the world
+//! is checked against the data, and the same data is sent again to check that
+//! it is not added twice.
+
+mod common;
+
+use common::{add_world_plugins, build_graph, load, settle, SMALL_TOWN};
+
+use city_visualizer::data::geography::{LoadedBounds, Offset};
+use city_visualizer::data::traffic_graph::TrafficGraph;
+use city_visualizer::earth::agent::AgentSettings;
+use city_visualizer::earth::chunk_stats::ChunkStats;
+use city_visualizer::earth::{GeoDataEvent, GeoFeature};
+
+use bevy::prelude::*;
+
+use std::sync::Arc;
+
+#[test]
+fn world_matches_the_data_and_is_not_added_twice() {
+    let data = Arc::new(load(SMALL_TOWN).unwrap());
+    let buildings: usize = data
+        .chunks
+        .values()
+        .map(|chunk| chunk.building_features.len())
+        .sum();
+
+    let mut app = App::new();
+    // Inserted before the plugin, so it is not replaced by the default
+    app.insert_resource(AgentSettings {
+        enabled: false,
+        ..default()
+    });
+    add_world_plugins(&mut app);
+
+    app.world.send_event(GeoDataEvent {
+        data: Arc::clone(&data),
+    });
+    settle(&mut app);
+
+    let chunk_stats = app.world.resource::<ChunkStats>();
+    let generated_buildings: usize = chunk_stats.chunks.values().map(|stats| stats.buildings).sum();
+    assert_eq!(generated_buildings, buildings, "buildings");
+    let unfinished_chunks = chunk_stats
+        .chunks
+        .values()
+        .filter(|stats| stats.building_time.is_none() || stats.road_time.is_none())
+        .count();
+    assert_eq!(unfinished_chunks, 0, "chunks still generating");
+
+    let features = count_features(&mut app);
+    assert!(features > 0, "no geographic features were spawned");
+
+    // The graph of the world should be the graph that is built from the data
+    // directly, with the same offset and bounds
+    let expected_graph = build_graph(
+        &data,
+        app.world.resource::<Offset>(),
+        app.world.resource::<LoadedBounds>(),
+    );
+    let graph = app.world.resource::<TrafficGraph>();
+    let (nodes, edges) = (graph.get_size(), graph.get_edge_count());
+    assert_eq!(nodes, expected_graph.get_size(), "traffic graph nodes");
+    assert_eq!(edges, expected_graph.get_edge_count(), "traffic graph edges");
+
+    // Loading the same data again should not change the world
+    app.world.send_event(GeoDataEvent {
+        data: Arc::clone(&data),
+    });
+    settle(&mut app);
+    assert_eq!(count_features(&mut app), features, "features after loading again");
+    let graph = app.world.resource::<TrafficGraph>();
+    assert_eq!(graph.get_size(), nodes, "graph nodes after loading again");
+    assert_eq!(graph.get_edge_count(), edges, "graph edges after loading again");
+}
+
+fn count_features(app: &mut App) -> usize {
+    app.world
+        .query_filtered::<(), With<GeoFeature>>()
+        .iter(&app.world)
+        .count()
+}